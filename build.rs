@@ -0,0 +1,70 @@
+//! Bakes what the kernel was built from into it, see `build_info`, and
+//! builds the programs in `user/examples` for the tests to run.
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+
+fn main() {
+    let commit =
+        output("git", &["rev-parse", "--short=12", "HEAD"]).unwrap_or_else(|| "unknown".into());
+    let dirty = output("git", &["status", "--porcelain", "--untracked-files=no"])
+        .map_or(false, |status| !status.is_empty());
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+    let rustc_version = output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".into());
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| {
+            key.strip_prefix("CARGO_FEATURE_")
+                .map(|name| name.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+
+    println!(
+        "cargo:rustc-env=BUILD_GIT_COMMIT={}{}",
+        commit,
+        if dirty { "-dirty" } else { "" }
+    );
+    println!("cargo:rustc-env=BUILD_RUSTC_VERSION={}", rustc_version);
+    println!(
+        "cargo:rustc-env=BUILD_PROFILE={}",
+        env::var("PROFILE").unwrap_or_default()
+    );
+    println!("cargo:rustc-env=BUILD_FEATURES={}", features.join(","));
+    // Pick up new commits, not just changes to this file.
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+
+    build_user_examples();
+}
+
+/// Build `user/examples` as static PIEs, so they load out of the
+/// kernel's way, and say where they are in `USER_EXAMPLES`.
+fn build_user_examples() {
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".into());
+    // A target directory of its own, this build has the main one locked.
+    let target_dir = PathBuf::from(env::var("OUT_DIR").unwrap()).join("user");
+    let status = Command::new(cargo)
+        .args(&["build", "-p", "blog_os_user", "--examples", "--release"])
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .env_remove("CARGO_TARGET_DIR")
+        .env_remove("RUSTFLAGS")
+        .env("CARGO_ENCODED_RUSTFLAGS", "-Clink-arg=-pie")
+        .status()
+        .expect("running cargo to build the user programs");
+    assert!(status.success(), "building the user programs failed");
+    let examples = target_dir.join("x86_64-blog_os/release/examples");
+    println!("cargo:rustc-env=USER_EXAMPLES={}", examples.display());
+    println!("cargo:rerun-if-changed=user");
+}
+
+/// First line of what `program` prints, if it ran fine.
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    Some(stdout.lines().next().unwrap_or("").trim().to_string())
+}
@@ -0,0 +1,22 @@
+#!/bin/sh
+# Runs a kernel ELF file built by cargo straight with QEMU's `-kernel`,
+# no disk image, see src/pvh.rs. Test binaries (they're under deps/)
+# get the same arguments and exit codes as `bootimage runner` gives
+# them, see `package.metadata.bootimage` in Cargo.toml.
+#
+#   CARGO_TARGET_X86_64_BLOG_OS_RUNNER=./pvh-runner.sh cargo test
+kernel="$1"
+shift
+set -- -kernel "$kernel" -device isa-debug-exit,iobase=0xf4,iosize=0x04 -serial stdio "$@"
+case "$kernel" in
+*/deps/*)
+    timeout 150 qemu-system-x86_64 "$@" -display none
+    status=$?
+    # 33 is `platform::exit` with `Success`.
+    [ "$status" -eq 33 ] && exit 0
+    exit "$status"
+    ;;
+*)
+    exec qemu-system-x86_64 "$@"
+    ;;
+esac
@@ -0,0 +1,371 @@
+//! Just enough of the ACPI tables to turn the machine off, and to find
+//! the IO APIC and the other CPUs.
+//!
+//! The firmware leaves the RSDP in the first KiB of the EBDA or in
+//! `0xE0000..0x100000`, 16 byte aligned. It points at the RSDT (or the
+//! XSDT, with 64 bit pointers), a list of every other table. The one
+//! we want is the FADT, which says which I/O ports the power management
+//! registers are at and where the DSDT is.
+//!
+//! Soft off is sleep state 5: write the DSDT's `\_S5` values to the
+//! PM1 control registers along with the sleep enable bit. Reading them
+//! properly needs an AML interpreter, we just look for the bytes of the
+//! `_S5_` package, which is what it looks like wherever it's been
+//! checked.
+//!
+//! The MADT lists the interrupt controllers: a local APIC for each CPU,
+//! where the IO APIC is and
+//! which interrupts it starts at, and ISA IRQs that aren't on the pin
+//! with their number, like the PIT's IRQ 0 on pin 2, or that aren't
+//! the usual edge triggered and active high.
+//!
+//! The HPET table says where the HPET's registers are, for `hpet`.
+//!
+//! Everything is read through the physical memory mapping, so none of
+//! this works before `memory::init`.
+use crate::error::KernelError;
+use crate::memory;
+use crate::trace::MAX_CPUS;
+use x86_64::instructions::port::Port;
+
+const RSDP_SIGNATURE: &[u8] = b"RSD PTR ";
+/// Where the BIOS data area keeps the EBDA's segment.
+const EBDA_SEGMENT: u64 = 0x40E;
+const SCAN_START: u64 = 0xE0000;
+const SCAN_END: u64 = 0x10_0000;
+/// Every table starts with this much header.
+const HEADER_LENGTH: usize = 36;
+
+/// Bits of PM1 control.
+const SCI_ENABLE: u16 = 1;
+const SLEEP_ENABLE: u16 = 1 << 13;
+const SLEEP_TYPE_SHIFT: u16 = 10;
+
+/// `length` bytes of physical memory from `address`.
+unsafe fn physical(address: u64, length: usize) -> Result<&'static [u8], KernelError> {
+    let offset = memory::physical_memory_offset().ok_or(KernelError::NotReady)?;
+    Ok(core::slice::from_raw_parts(
+        (offset + address).as_ptr(),
+        length,
+    ))
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> Option<u64> {
+    let low = u32_at(bytes, offset)?;
+    let high = u32_at(bytes, offset + 4)?;
+    Some(u64::from(high) << 32 | u64::from(low))
+}
+
+/// Where the RSDT or XSDT is, and whether it's the XSDT.
+unsafe fn find_root() -> Result<(u64, bool), KernelError> {
+    let segment = physical(EBDA_SEGMENT, 2)?;
+    let ebda = u64::from(u16::from_le_bytes([segment[0], segment[1]])) << 4;
+    let ranges = [(ebda, ebda + 1024), (SCAN_START, SCAN_END)];
+    for &(start, end) in ranges.iter().filter(|(start, _)| *start != 0) {
+        let area = physical(start, (end - start) as usize)?;
+        for rsdp in (0..area.len()).step_by(16).map(|at| &area[at..]) {
+            if !rsdp.starts_with(RSDP_SIGNATURE) || rsdp.len() < 20 || !checksum_ok(&rsdp[..20]) {
+                continue;
+            }
+            // Revision 2 and up have the XSDT, which is what to use if
+            // it's there.
+            if rsdp[15] >= 2 && rsdp.len() >= 36 && checksum_ok(&rsdp[..36]) {
+                if let Some(xsdt) = u64_at(rsdp, 24).filter(|&xsdt| xsdt != 0) {
+                    return Ok((xsdt, true));
+                }
+            }
+            return Ok((u64::from(u32_at(rsdp, 16).unwrap_or(0)), false));
+        }
+    }
+    Err(KernelError::NotFound)
+}
+
+/// The whole table at `address`, checked.
+unsafe fn table(address: u64) -> Result<&'static [u8], KernelError> {
+    let header = physical(address, HEADER_LENGTH)?;
+    let length = u32_at(header, 4).unwrap_or(0) as usize;
+    if length < HEADER_LENGTH {
+        return Err(KernelError::InvalidData);
+    }
+    let table = physical(address, length)?;
+    if !checksum_ok(table) {
+        return Err(KernelError::InvalidData);
+    }
+    Ok(table)
+}
+
+/// The table with `signature`, from the root table's list.
+unsafe fn find_table(signature: &[u8; 4]) -> Result<&'static [u8], KernelError> {
+    let (root, extended) = find_root()?;
+    let root = table(root)?;
+    let entry_size = if extended { 8 } else { 4 };
+    for entry in root[HEADER_LENGTH..].chunks_exact(entry_size) {
+        let address = if extended {
+            u64_at(entry, 0)
+        } else {
+            u32_at(entry, 0).map(u64::from)
+        };
+        let address = address.unwrap_or(0);
+        if address != 0 && physical(address, 4)? == signature {
+            return table(address);
+        }
+    }
+    Err(KernelError::NotFound)
+}
+
+/// An IO APIC, from the MADT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: u32,
+    /// The global system interrupt its first pin is.
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ that isn't what it would be on the 8259s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Override {
+    pub irq: u8,
+    pub gsi: u32,
+    /// MPS INTI flags: polarity in bits 0-1, trigger mode in bits 2-3.
+    pub flags: u16,
+}
+
+/// What we want out of the MADT. Only the first IO APIC, machines with
+/// more are ones we'll never boot on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Madt {
+    /// The APIC IDs of the CPUs the firmware says can be used, in its
+    /// order, as many as we have room for.
+    pub local_apics: [Option<u8>; MAX_CPUS],
+    pub io_apic: Option<IoApicEntry>,
+    /// By ISA IRQ.
+    pub overrides: [Option<Override>; 16],
+}
+
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_OVERRIDE: u8 = 2;
+/// The local APIC address and flags come before the entries.
+const MADT_ENTRIES: usize = HEADER_LENGTH + 8;
+/// A local APIC entry's flag for a CPU that's there and works.
+const LOCAL_APIC_ENABLED: u8 = 1;
+
+/// Read the entries of a MADT, `table` being all of it.
+pub fn parse_madt(table: &[u8]) -> Madt {
+    let mut madt = Madt {
+        local_apics: [None; MAX_CPUS],
+        io_apic: None,
+        overrides: [None; 16],
+    };
+    let mut entries = table.get(MADT_ENTRIES..).unwrap_or(&[]);
+    while entries.len() >= 2 {
+        let length = usize::from(entries[1]);
+        let entry = match entries.get(..length) {
+            Some(entry) if length >= 2 => entry,
+            _ => break,
+        };
+        match entry[0] {
+            MADT_LOCAL_APIC if length >= 8 && entry[4] & LOCAL_APIC_ENABLED != 0 => {
+                if let Some(slot) = madt.local_apics.iter_mut().find(|slot| slot.is_none()) {
+                    *slot = Some(entry[3]);
+                }
+            }
+            MADT_IO_APIC if length >= 12 && madt.io_apic.is_none() => {
+                madt.io_apic = Some(IoApicEntry {
+                    id: entry[2],
+                    address: u32_at(entry, 4).unwrap_or(0),
+                    gsi_base: u32_at(entry, 8).unwrap_or(0),
+                });
+            }
+            // Bus 0 is ISA, the only one there is.
+            MADT_OVERRIDE if length >= 10 && entry[2] == 0 && entry[3] < 16 => {
+                madt.overrides[usize::from(entry[3])] = Some(Override {
+                    irq: entry[3],
+                    gsi: u32_at(entry, 4).unwrap_or(0),
+                    flags: u16::from_le_bytes([entry[8], entry[9]]),
+                });
+            }
+            _ => {}
+        }
+        entries = &entries[length..];
+    }
+    madt
+}
+
+/// The machine's MADT. `NotFound` if there are no ACPI tables, or no
+/// MADT in them.
+pub fn madt() -> Result<Madt, KernelError> {
+    let table = unsafe { find_table(b"APIC")? };
+    Ok(parse_madt(table))
+}
+
+/// Where the HPET's base address is, a generic address structure.
+const HPET_ADDRESS: usize = HEADER_LENGTH + 4;
+
+/// The physical address of the HPET's registers from its table. `None`
+/// if they aren't in memory, they always are on anything we've seen.
+pub fn parse_hpet(table: &[u8]) -> Option<u64> {
+    // Address space 0 is memory, anything else would be I/O ports.
+    if *table.get(HPET_ADDRESS)? != 0 {
+        return None;
+    }
+    u64_at(table, HPET_ADDRESS + 4).filter(|&address| address != 0)
+}
+
+/// Where the machine's HPET is. `NotFound` if there are no ACPI
+/// tables, or no HPET in them.
+pub fn hpet_address() -> Result<u64, KernelError> {
+    let table = unsafe { find_table(b"HPET")? };
+    parse_hpet(table).ok_or(KernelError::InvalidData)
+}
+
+/// One integer of an AML package, and how many bytes it took.
+fn aml_integer(bytes: &[u8]) -> Option<(u8, usize)> {
+    match *bytes.first()? {
+        // BytePrefix
+        0x0A => Some((*bytes.get(1)?, 2)),
+        // ZeroOp, OneOp
+        value @ 0x00..=0x01 => Some((value, 1)),
+        _ => None,
+    }
+}
+
+/// The SLP_TYPa and SLP_TYPb values in the `\_S5` package of `dsdt`.
+pub fn s5_sleep_types(dsdt: &[u8]) -> Option<(u8, u8)> {
+    let at = dsdt.windows(4).position(|name| name == b"_S5_")?;
+    // A NameOp, maybe with a root prefix in between.
+    let name_op = match at {
+        0 => return None,
+        at if dsdt[at - 1] == b'\\' && at >= 2 => dsdt[at - 2],
+        at => dsdt[at - 1],
+    };
+    let package = dsdt.get(at + 4..)?;
+    if name_op != 0x08 || *package.first()? != 0x12 {
+        return None;
+    }
+    // PkgLength has as many extra bytes as its top two bits say, then
+    // the number of elements.
+    let length_bytes = 1 + usize::from(*package.get(1)? >> 6);
+    let elements = package.get(1 + length_bytes + 1..)?;
+    let (a, used) = aml_integer(elements)?;
+    let (b, _) = aml_integer(&elements[used..])?;
+    Some((a, b))
+}
+
+/// Turn the machine off. Only comes back if that didn't work, saying
+/// why.
+pub fn shutdown() -> KernelError {
+    match unsafe { try_shutdown() } {
+        Ok(()) => KernelError::Timeout,
+        Err(error) => error,
+    }
+}
+
+unsafe fn try_shutdown() -> Result<(), KernelError> {
+    let fadt = find_table(b"FACP")?;
+    let field = |offset| u32_at(fadt, offset).ok_or(KernelError::InvalidData);
+    let dsdt = match u64_at(fadt, 140).filter(|&address| address != 0) {
+        Some(address) => address,
+        None => u64::from(field(40)?),
+    };
+    let (sleep_type_a, sleep_type_b) = s5_sleep_types(table(dsdt)?).ok_or(KernelError::NotFound)?;
+    let smi_command = field(48)? as u16;
+    let acpi_enable = *fadt.get(52).ok_or(KernelError::InvalidData)?;
+    let pm1a = field(64)? as u16;
+    let pm1b = field(68)? as u16;
+    if pm1a == 0 {
+        return Err(KernelError::Unsupported);
+    }
+
+    let mut control_a = Port::<u16>::new(pm1a);
+    // Still in legacy mode, the firmware has to hand over first.
+    if control_a.read() & SCI_ENABLE == 0 && smi_command != 0 && acpi_enable != 0 {
+        Port::<u8>::new(smi_command).write(acpi_enable);
+        for _ in 0..1_000_000 {
+            if control_a.read() & SCI_ENABLE != 0 {
+                break;
+            }
+        }
+    }
+    let control = |sleep_type: u8| u16::from(sleep_type) << SLEEP_TYPE_SHIFT | SLEEP_ENABLE;
+    control_a.write(control(sleep_type_a));
+    if pm1b != 0 {
+        Port::<u16>::new(pm1b).write(control(sleep_type_b));
+    }
+    // It isn't instant everywhere.
+    for _ in 0..1_000_000 {
+        core::sync::atomic::spin_loop_hint();
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_s5_sleep_types() {
+    // Name (\_S5, Package (0x04) { 0x05, 0x05, Zero, Zero }), with the
+    // scope around it.
+    let dsdt = [
+        0x10, 0x0A, b'\\', 0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x0A, 0x04, 0x0A, 0x05, 0x0A,
+        0x05, 0x00, 0x00,
+    ];
+    assert_eq!(s5_sleep_types(&dsdt[4..]), None);
+    assert_eq!(s5_sleep_types(&dsdt), Some((5, 5)));
+    // Name (_S5, Package (0x02) { Zero, One })
+    let short = [0x08, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x02, 0x00, 0x01];
+    assert_eq!(s5_sleep_types(&short), Some((0, 1)));
+    // A method called _S5_, not a name.
+    let method = [0x14, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x02, 0x00, 0x01];
+    assert_eq!(s5_sleep_types(&method), None);
+}
+
+#[test_case]
+fn test_parse_madt() {
+    let mut table = [0; MADT_ENTRIES + 24 + 12 + 10 + 10];
+    let entries = &mut table[MADT_ENTRIES..];
+    // CPUs with APIC IDs 0 and 1, and one that's disabled in between.
+    entries[..8].copy_from_slice(&[0, 8, 0, 0, 1, 0, 0, 0]);
+    entries[8..16].copy_from_slice(&[0, 8, 1, 3, 0, 0, 0, 0]);
+    entries[16..24].copy_from_slice(&[0, 8, 2, 1, 1, 0, 0, 0]);
+    // IO APIC 2 at 0xFEC00000, from GSI 0.
+    entries[24..36].copy_from_slice(&[1, 12, 2, 0, 0, 0, 0xC0, 0xFE, 0, 0, 0, 0]);
+    // The PIT on pin 2, and IRQ 9 level triggered and active high.
+    entries[36..46].copy_from_slice(&[2, 10, 0, 0, 2, 0, 0, 0, 0, 0]);
+    entries[46..56].copy_from_slice(&[2, 10, 0, 9, 9, 0, 0, 0, 0x0D, 0]);
+    let madt = parse_madt(&table);
+    assert_eq!(madt.local_apics[..3], [Some(0), Some(1), None]);
+    assert_eq!(
+        madt.io_apic,
+        Some(IoApicEntry {
+            id: 2,
+            address: 0xFEC0_0000,
+            gsi_base: 0,
+        })
+    );
+    assert_eq!(madt.overrides[0].map(|entry| entry.gsi), Some(2));
+    assert_eq!(madt.overrides[9].map(|entry| entry.flags), Some(0x0D));
+    assert_eq!(madt.overrides[1], None);
+    // Cut short in the middle of an entry.
+    assert_eq!(parse_madt(&table[..MADT_ENTRIES + 31]).io_apic, None);
+}
+
+#[test_case]
+fn test_parse_hpet() {
+    let mut table = [0; HPET_ADDRESS + 12];
+    // In memory at 0xFED00000.
+    table[HPET_ADDRESS + 4..].copy_from_slice(&[0, 0, 0xD0, 0xFE, 0, 0, 0, 0]);
+    assert_eq!(parse_hpet(&table), Some(0xFED0_0000));
+    // Cut short in the middle of the address.
+    assert_eq!(parse_hpet(&table[..HPET_ADDRESS + 8]), None);
+    // In I/O space.
+    table[HPET_ADDRESS] = 1;
+    assert_eq!(parse_hpet(&table), None);
+}
@@ -0,0 +1,377 @@
+//! Heap allocation tracking.
+//!
+//! `TrackingAllocator` wraps another `GlobalAlloc` and keeps count of
+//! what is live, along with the few return addresses of whoever asked
+//! for each block. The test runner uses it to check that every test
+//! gives back everything it allocated.
+//!
+//! The kernel heap is a `LinkedListAllocator` over `HEAP_SIZE` bytes
+//! mapped at `HEAP_START` by `init_heap`, wrapped in one of these as
+//! the `#[global_allocator]`, so `Box`, `Vec` and the rest of `alloc`
+//! work once it has run. Before that every allocation fails. `bump`
+//! and `fixed_size_block` are two other ways of running a heap, only
+//! there for `bench` to compare them with it.
+//!
+//! With the `heap-profile` feature it keeps track of many more blocks
+//! and deeper callers, and `profile` can say where the heap went.
+use crate::error::KernelResult;
+use crate::fault_injection::{self, FaultPoint};
+use crate::ksyms::{self, Demangle};
+use crate::latency;
+use crate::unwind;
+use core::alloc::{GlobalAlloc, Layout};
+use core::{fmt, ptr};
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+pub mod bench;
+pub mod bump;
+pub mod fixed_size_block;
+pub mod linked_list;
+#[cfg(feature = "heap-profile")]
+pub mod profile;
+
+use linked_list::LinkedListAllocator;
+
+/// Where the heap is, well clear of anything the bootloader maps.
+pub const HEAP_START: u64 = 0x4444_4444_0000;
+pub const HEAP_SIZE: u64 = 1024 * 1024;
+
+/// How many live blocks we remember the details of. Past this we
+/// still count them, we just can't say where they came from.
+#[cfg(not(feature = "heap-profile"))]
+pub const TRACKED_BLOCKS: usize = 256;
+/// Enough for most of the heap, for `profile`.
+#[cfg(feature = "heap-profile")]
+pub const TRACKED_BLOCKS: usize = 4096;
+/// Return addresses kept per block.
+#[cfg(not(feature = "heap-profile"))]
+pub const CALLER_DEPTH: usize = 4;
+/// Deep enough to get out of `alloc`'s own functions.
+#[cfg(feature = "heap-profile")]
+pub const CALLER_DEPTH: usize = 8;
+/// Blocks `for_each_block_since` copies out of the table at a time.
+const COPIED_BLOCKS: usize = 32;
+
+/// A live allocation.
+#[derive(Debug, Clone, Copy)]
+pub struct Block {
+    pub address: usize,
+    pub size: usize,
+    /// Allocation number, increasing over time so blocks allocated
+    /// after some point can be told apart from older ones.
+    pub sequence: u64,
+    /// Innermost first, unused slots are 0.
+    pub callers: [u64; CALLER_DEPTH],
+}
+
+/// Totals over all live allocations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HeapStats {
+    pub live_blocks: usize,
+    pub live_bytes: usize,
+    /// Number of allocations ever made, doubles as the sequence
+    /// number of the next one.
+    pub allocations: u64,
+    /// Live blocks we have no details on as the table was full.
+    pub untracked_blocks: usize,
+}
+
+struct Tracker {
+    stats: HeapStats,
+    blocks: [Option<Block>; TRACKED_BLOCKS],
+}
+
+impl Tracker {
+    const fn new() -> Tracker {
+        Tracker {
+            stats: HeapStats {
+                live_blocks: 0,
+                live_bytes: 0,
+                allocations: 0,
+                untracked_blocks: 0,
+            },
+            blocks: [None; TRACKED_BLOCKS],
+        }
+    }
+
+    fn allocated(&mut self, address: usize, size: usize, callers: [u64; CALLER_DEPTH]) {
+        let block = Block {
+            address,
+            size,
+            sequence: self.stats.allocations,
+            callers,
+        };
+        self.stats.allocations += 1;
+        self.stats.live_blocks += 1;
+        self.stats.live_bytes += size;
+        match self.blocks.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(block),
+            None => self.stats.untracked_blocks += 1,
+        }
+    }
+
+    fn freed(&mut self, address: usize, size: usize) {
+        self.stats.live_blocks -= 1;
+        self.stats.live_bytes -= size;
+        let slot = self
+            .blocks
+            .iter_mut()
+            .find(|slot| slot.map_or(false, |block| block.address == address));
+        match slot {
+            Some(slot) => *slot = None,
+            None => self.stats.untracked_blocks -= 1,
+        }
+    }
+}
+
+/// Counts and remembers every allocation made through `inner`.
+pub struct TrackingAllocator<A> {
+    inner: A,
+    tracker: Mutex<Tracker>,
+}
+
+impl<A> TrackingAllocator<A> {
+    pub const fn new(inner: A) -> TrackingAllocator<A> {
+        TrackingAllocator {
+            inner,
+            tracker: Mutex::new(Tracker::new()),
+        }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        latency::without_interrupts(|| self.tracker.lock().stats)
+    }
+
+    /// Call `f` for every block still live that was allocated after
+    /// `stats` was taken.
+    pub fn for_each_block_since(&self, stats: &HeapStats, mut f: impl FnMut(&Block)) {
+        // Copy the table so `f` can print (or even allocate) freely, a
+        // bit at a time as all of it might not fit on the stack.
+        for start in (0..TRACKED_BLOCKS).step_by(COPIED_BLOCKS) {
+            let mut blocks = [None; COPIED_BLOCKS];
+            latency::without_interrupts(|| {
+                blocks.copy_from_slice(&self.tracker.lock().blocks[start..start + COPIED_BLOCKS])
+            });
+            for block in blocks.iter().flatten() {
+                if block.sequence >= stats.allocations {
+                    f(block);
+                }
+            }
+        }
+    }
+
+    /// Return addresses of whoever called into the allocator.
+    #[inline(always)]
+    fn callers() -> [u64; CALLER_DEPTH] {
+        let mut callers = [0; CALLER_DEPTH];
+        let mut depth = 0;
+        unsafe {
+            unwind::walk(unwind::current_frame_pointer(), |address| {
+                callers[depth] = address;
+                depth += 1;
+                depth < CALLER_DEPTH
+            });
+        }
+        callers
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if fault_injection::should_fail(FaultPoint::HeapAlloc) {
+            return ptr::null_mut();
+        }
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            let callers = Self::callers();
+            // Interrupt handlers might allocate too, so make sure we
+            // can't be interrupted while holding the lock.
+            latency::without_interrupts(|| {
+                self.tracker
+                    .lock()
+                    .allocated(ptr as usize, layout.size(), callers)
+            });
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        latency::without_interrupts(|| self.tracker.lock().freed(ptr as usize, layout.size()));
+        self.inner.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: TrackingAllocator<LinkedListAllocator> =
+    TrackingAllocator::new(LinkedListAllocator::empty());
+
+/// Map the heap and hand it to the global allocator.
+///
+/// # Safety
+///
+/// Call once, after `memory::init`.
+pub unsafe fn init_heap() -> KernelResult<()> {
+    crate::memory::paging::map_pages(VirtAddr::new(HEAP_START), HEAP_SIZE / 4096)?;
+    ALLOCATOR
+        .inner()
+        .add(HEAP_START as usize, HEAP_SIZE as usize);
+    Ok(())
+}
+
+/// Bytes of the heap not handed out.
+pub fn heap_free() -> usize {
+    ALLOCATOR.inner().free()
+}
+
+/// What the global allocator has handed out, which the test runner
+/// checks against.
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.stats()
+}
+
+/// See `TrackingAllocator::for_each_block_since`.
+pub fn for_each_block_since(stats: &HeapStats, f: impl FnMut(&Block)) {
+    ALLOCATOR.for_each_block_since(stats, f)
+}
+
+/// Blocks that were allocated after `before` and are still around.
+pub struct LeakReport {
+    before: HeapStats,
+    after: HeapStats,
+}
+
+impl LeakReport {
+    /// `None` if the heap is back to (or below) where `before` left it.
+    pub fn since(before: HeapStats) -> Option<LeakReport> {
+        let after = heap_stats();
+        if after.live_bytes <= before.live_bytes && after.live_blocks <= before.live_blocks {
+            return None;
+        }
+        Some(LeakReport { before, after })
+    }
+}
+
+impl fmt::Display for LeakReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "leaked {} bytes in {} blocks",
+            self.after.live_bytes - self.before.live_bytes,
+            self.after.live_blocks - self.before.live_blocks
+        )?;
+
+        let mut result = Ok(());
+        for_each_block_since(&self.before, |block| {
+            result = result.and_then(|_| {
+                writeln!(f, "  {} bytes at {:#x}, from:", block.size, block.address)?;
+                for &caller in block.callers.iter().filter(|&&caller| caller != 0) {
+                    match ksyms::lookup(caller - 1) {
+                        Some((name, offset)) => writeln!(
+                            f,
+                            "    {:#018x} {}+{:#x}",
+                            caller,
+                            Demangle(name),
+                            offset + 1
+                        )?,
+                        None => writeln!(f, "    {:#018x}", caller)?,
+                    }
+                }
+                Ok(())
+            });
+        });
+        result
+    }
+}
+
+#[test_case]
+fn test_tracking_counts_live_blocks() {
+    use core::cell::UnsafeCell;
+
+    // Just enough of an allocator to have something to wrap.
+    struct Bump {
+        memory: UnsafeCell<[u8; 256]>,
+        next: Mutex<usize>,
+    }
+
+    unsafe impl Sync for Bump {}
+
+    unsafe impl GlobalAlloc for Bump {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let mut next = self.next.lock();
+            let start = (*next + layout.align() - 1) & !(layout.align() - 1);
+            if start + layout.size() > 256 {
+                return ptr::null_mut();
+            }
+            *next = start + layout.size();
+            (self.memory.get() as *mut u8).add(start)
+        }
+
+        unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+    }
+
+    let allocator = TrackingAllocator::new(Bump {
+        memory: UnsafeCell::new([0; 256]),
+        next: Mutex::new(0),
+    });
+    let before = allocator.stats();
+    let layout = Layout::from_size_align(16, 8).unwrap();
+
+    unsafe {
+        let kept = allocator.alloc(layout);
+        let freed = allocator.alloc(layout);
+        allocator.dealloc(freed, layout);
+
+        let stats = allocator.stats();
+        assert_eq!(stats.live_blocks, 1);
+        assert_eq!(stats.live_bytes, 16);
+        assert_eq!(stats.allocations, 2);
+
+        let mut leaked = 0;
+        allocator.for_each_block_since(&before, |block| {
+            assert_eq!(block.address, kept as usize);
+            assert_ne!(block.callers[0], 0);
+            leaked += 1;
+        });
+        assert_eq!(leaked, 1);
+
+        // An injected failure doesn't count as an allocation.
+        fault_injection::fail_nth(FaultPoint::HeapAlloc, 1);
+        assert!(allocator.alloc(layout).is_null());
+        assert_eq!(allocator.stats().allocations, 2);
+        fault_injection::reset();
+    }
+}
+
+#[test_case]
+fn test_heap_collections() {
+    use alloc::boxed::Box;
+    use alloc::collections::BTreeMap;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    let free = heap_free();
+    let boxed = Box::new(41);
+    assert_eq!(*boxed + 1, 42);
+    let numbers: Vec<u64> = (0..1000).collect();
+    assert_eq!(numbers.iter().sum::<u64>(), 999 * 1000 / 2);
+    let mut name = String::from("blog");
+    name.push_str("_os");
+    let mut map = BTreeMap::new();
+    map.insert(name.as_str(), numbers.len());
+    assert_eq!(map.get("blog_os"), Some(&1000));
+    assert!(heap_free() < free);
+    drop(map);
+    drop((boxed, name, numbers));
+    // Merged back together, not just freed.
+    assert_eq!(heap_free(), free);
+    // Not all of `free`, which only fits if nothing else is left in
+    // the middle of the heap.
+    let big = Vec::<u8>::with_capacity(64 * 1024);
+    drop(big);
+    assert_eq!(heap_free(), free);
+}
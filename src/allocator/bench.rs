@@ -0,0 +1,241 @@
+//! The three heap allocators against each other, on the sorts of
+//! things the kernel does with a heap.
+//!
+//! - small: a few hundred blocks of 16 to 64 bytes, then all freed,
+//!   like building a list of nodes and dropping it.
+//! - mixed: 8 bytes to 4 KiB, allocated and freed in random order with
+//!   a few dozen live at once, from a fixed seed so every run is the
+//!   same.
+//! - producer/consumer: a queue of buffers, freed in the order they
+//!   were allocated while more are being allocated.
+//!
+//! Each run gets a fresh allocator over the same arena, which comes
+//! from the kernel heap, and `bench` times it. The figure is cycles per
+//! allocation, its free included, for the fastest run. The bump
+//! allocator reuses nothing until everything has been freed, so it can
+//! run out of arena where the others don't, which is part of the
+//! comparison too. `allocbench` prints the table.
+use super::bump::BumpAllocator;
+use super::fixed_size_block::FixedSizeBlockAllocator;
+use super::linked_list::LinkedListAllocator;
+use crate::bench::{self, Timing};
+use crate::memfuzz::Rng;
+use crate::shell::{self, parse_number, CommandFailed, CommandResult};
+use crate::ui::{Column, Table};
+use alloc::vec;
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::ptr;
+
+pub const ARENA_SIZE: usize = 128 * 1024;
+const SMALL_BLOCKS: usize = 256;
+const MIXED_SLOTS: usize = 32;
+const MIXED_STEPS: usize = 512;
+const QUEUE_DEPTH: usize = 16;
+const QUEUE_ITEMS: usize = 512;
+
+pub const ALLOCATORS: [&str; 3] = ["bump", "linked list", "fixed blocks"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+    Small,
+    Mixed,
+    ProducerConsumer,
+}
+
+pub const PATTERNS: [Pattern; 3] = [Pattern::Small, Pattern::Mixed, Pattern::ProducerConsumer];
+
+impl Pattern {
+    pub fn name(self) -> &'static str {
+        match self {
+            Pattern::Small => "small",
+            Pattern::Mixed => "mixed",
+            Pattern::ProducerConsumer => "producer/consumer",
+        }
+    }
+
+    /// Allocations made, `None` if one of them failed.
+    unsafe fn run(self, allocator: &impl GlobalAlloc) -> Option<usize> {
+        match self {
+            Pattern::Small => small(allocator),
+            Pattern::Mixed => mixed(allocator),
+            Pattern::ProducerConsumer => producer_consumer(allocator),
+        }
+    }
+}
+
+type Block = Option<(*mut u8, Layout)>;
+
+/// Allocate, and touch it like whoever asked would.
+unsafe fn allocate(allocator: &impl GlobalAlloc, size: usize, align: usize) -> Option<Block> {
+    let layout = Layout::from_size_align_unchecked(size, align);
+    let ptr = allocator.alloc(layout);
+    if ptr.is_null() {
+        return None;
+    }
+    ptr::write_volatile(ptr, 0xAA);
+    Some(Some((ptr, layout)))
+}
+
+unsafe fn free(allocator: &impl GlobalAlloc, block: &mut Block) {
+    if let Some((ptr, layout)) = block.take() {
+        allocator.dealloc(ptr, layout);
+    }
+}
+
+unsafe fn small(allocator: &impl GlobalAlloc) -> Option<usize> {
+    const SIZES: [usize; 5] = [16, 24, 32, 48, 64];
+    let mut blocks: [Block; SMALL_BLOCKS] = [None; SMALL_BLOCKS];
+    for (index, block) in blocks.iter_mut().enumerate() {
+        *block = allocate(allocator, SIZES[index % SIZES.len()], 8)?;
+    }
+    for block in blocks.iter_mut() {
+        free(allocator, block);
+    }
+    Some(SMALL_BLOCKS)
+}
+
+unsafe fn mixed(allocator: &impl GlobalAlloc) -> Option<usize> {
+    let mut rng = Rng::new(1);
+    let mut slots: [Block; MIXED_SLOTS] = [None; MIXED_SLOTS];
+    let mut allocations = 0;
+    for _ in 0..MIXED_STEPS {
+        let slot = &mut slots[rng.below(MIXED_SLOTS)];
+        if slot.is_some() {
+            free(allocator, slot);
+        } else {
+            let size = (8 << rng.below(10)) - rng.below(8);
+            *slot = allocate(allocator, size, 8)?;
+            allocations += 1;
+        }
+    }
+    for slot in slots.iter_mut() {
+        free(allocator, slot);
+    }
+    Some(allocations)
+}
+
+unsafe fn producer_consumer(allocator: &impl GlobalAlloc) -> Option<usize> {
+    let mut queue: [Block; QUEUE_DEPTH] = [None; QUEUE_DEPTH];
+    for item in 0..QUEUE_ITEMS {
+        // The oldest one is consumed just before its slot is needed.
+        let slot = &mut queue[item % QUEUE_DEPTH];
+        free(allocator, slot);
+        *slot = allocate(allocator, 64 + item % 4 * 32, 8)?;
+    }
+    for slot in queue.iter_mut() {
+        free(allocator, slot);
+    }
+    Some(QUEUE_ITEMS)
+}
+
+/// The fastest of `runs` results of `run`, `None` if any was.
+fn fastest(runs: usize, mut run: impl FnMut() -> Option<u64>) -> Option<u64> {
+    let mut timing = Timing::new();
+    for _ in 0..runs {
+        timing.record(run()?);
+    }
+    Some(timing.min)
+}
+
+/// One run of `pattern` on `allocator`, in cycles per allocation.
+unsafe fn cycles_per_allocation(pattern: Pattern, allocator: &impl GlobalAlloc) -> Option<u64> {
+    let (allocations, cycles) = bench::cycles(|| pattern.run(allocator));
+    Some(cycles / allocations?.max(1) as u64)
+}
+
+/// Cycles per allocation for each of `ALLOCATORS` (the columns) on
+/// each of `PATTERNS` (the rows), `None` where it ran out of memory.
+pub fn compare(runs: usize) -> [[Option<u64>; 3]; 3] {
+    let mut arena = vec![0u8; ARENA_SIZE];
+    let (start, size) = (arena.as_mut_ptr() as usize, arena.len());
+    let mut results = [[None; 3]; 3];
+    for (row, &pattern) in results.iter_mut().zip(PATTERNS.iter()) {
+        // A new allocator every run, over the same arena.
+        row[0] = fastest(runs, || unsafe {
+            let allocator = BumpAllocator::empty();
+            allocator.add(start, size);
+            cycles_per_allocation(pattern, &allocator)
+        });
+        row[1] = fastest(runs, || unsafe {
+            let allocator = LinkedListAllocator::empty();
+            allocator.add(start, size);
+            cycles_per_allocation(pattern, &allocator)
+        });
+        row[2] = fastest(runs, || unsafe {
+            let allocator = FixedSizeBlockAllocator::empty();
+            allocator.add(start, size);
+            cycles_per_allocation(pattern, &allocator)
+        });
+    }
+    results
+}
+
+/// Adds the `allocbench` shell command.
+pub fn init() {
+    shell::register(
+        "allocbench",
+        "allocbench [RUNS]: compare the heap allocators",
+        allocbench_command,
+    )
+    .expect("allocbench command");
+}
+
+fn allocbench_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    let runs = match args.trim() {
+        "" => Some(16),
+        word => parse_number(word).filter(|&runs| runs > 0),
+    };
+    let runs = match runs {
+        Some(runs) => runs as usize,
+        None => {
+            let _ = writeln!(out, "usage: allocbench [RUNS]");
+            return Err(CommandFailed);
+        }
+    };
+    let results = compare(runs);
+    const COLUMNS: [Column; 4] = [
+        Column::left("pattern", 18),
+        Column::right(ALLOCATORS[0], 12),
+        Column::right(ALLOCATORS[1], 12),
+        Column::right(ALLOCATORS[2], 12),
+    ];
+    let table = Table::new(&COLUMNS);
+    let _ = table.header(out);
+    for (pattern, row) in PATTERNS.iter().zip(results.iter()) {
+        let mut cells: [&dyn fmt::Display; 3] = [&"no memory"; 3];
+        for (cell, cycles) in cells.iter_mut().zip(row.iter()) {
+            if let Some(cycles) = cycles {
+                *cell = cycles;
+            }
+        }
+        let _ = table.row(out, &[&pattern.name(), cells[0], cells[1], cells[2]]);
+    }
+    let _ = table.end(out);
+    let _ = writeln!(
+        out,
+        "cycles per allocation and its free, fastest of {} runs",
+        runs
+    );
+    Ok(())
+}
+
+#[test_case]
+fn test_compare_allocators() {
+    let results = compare(2);
+    for (pattern, row) in PATTERNS.iter().zip(results.iter()) {
+        // Both of the real ones manage everything.
+        assert!(
+            row[1].is_some(),
+            "linked list ran out on {}",
+            pattern.name()
+        );
+        assert!(
+            row[2].is_some(),
+            "fixed blocks ran out on {}",
+            pattern.name()
+        );
+    }
+    // Everything's freed at the end of small, so bump starts over.
+    assert!(results[0][0].is_some());
+}
@@ -0,0 +1,115 @@
+//! An allocator that only ever moves forwards.
+//!
+//! Every allocation comes from `next`, which goes up past it. Nothing
+//! given back is reused until everything is, then it starts again from
+//! the bottom. About as fast as allocating gets and about as wasteful,
+//! it's here to measure the others against, see `bench`.
+//!
+//! The lock is only taken with interrupts off, in case a handler
+//! allocates.
+use crate::latency;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+use spin::Mutex;
+
+struct Bump {
+    start: usize,
+    end: usize,
+    next: usize,
+    /// Live ones, back to the start when it gets to 0.
+    allocations: usize,
+}
+
+pub struct BumpAllocator {
+    bump: Mutex<Bump>,
+}
+
+impl BumpAllocator {
+    /// An allocator with nothing to hand out until `add` is called.
+    pub const fn empty() -> BumpAllocator {
+        BumpAllocator {
+            bump: Mutex::new(Bump {
+                start: 0,
+                end: 0,
+                next: 0,
+                allocations: 0,
+            }),
+        }
+    }
+
+    /// Hand out `size` bytes at `start`, instead of whatever it had.
+    ///
+    /// # Safety
+    ///
+    /// The memory has to be mapped, writable and used for nothing else,
+    /// and nothing from before can still be in use.
+    pub unsafe fn add(&self, start: usize, size: usize) {
+        latency::without_interrupts(|| {
+            *self.bump.lock() = Bump {
+                start,
+                end: start + size,
+                next: start,
+                allocations: 0,
+            }
+        });
+    }
+
+    /// Bytes past `next`.
+    pub fn free(&self) -> usize {
+        latency::without_interrupts(|| {
+            let bump = self.bump.lock();
+            bump.end - bump.next
+        })
+    }
+}
+
+unsafe impl GlobalAlloc for BumpAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        latency::without_interrupts(|| {
+            let mut bump = self.bump.lock();
+            let start = (bump.next + layout.align() - 1) & !(layout.align() - 1);
+            match start.checked_add(layout.size()) {
+                Some(end) if end <= bump.end => {
+                    bump.next = end;
+                    bump.allocations += 1;
+                    start as *mut u8
+                }
+                _ => ptr::null_mut(),
+            }
+        })
+    }
+
+    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
+        latency::without_interrupts(|| {
+            let mut bump = self.bump.lock();
+            bump.allocations -= 1;
+            if bump.allocations == 0 {
+                bump.next = bump.start;
+            }
+        })
+    }
+}
+
+#[test_case]
+fn test_starts_over_once_all_freed() {
+    let mut memory = [0u64; 16];
+    let start = memory.as_mut_ptr() as usize;
+    let allocator = BumpAllocator::empty();
+    unsafe {
+        allocator.add(start, 128);
+        let small = Layout::from_size_align(8, 8).unwrap();
+        let a = allocator.alloc(small);
+        let b = allocator.alloc(Layout::from_size_align(8, 64).unwrap());
+        assert_eq!(a as usize, start);
+        assert_eq!(b as usize % 64, 0);
+        // Freeing one doesn't give anything back.
+        allocator.dealloc(a, small);
+        assert!(allocator
+            .alloc(Layout::from_size_align(128, 8).unwrap())
+            .is_null());
+        // Freeing the last one does.
+        allocator.dealloc(b, small);
+        assert_eq!(allocator.free(), 128);
+        assert_eq!(allocator.alloc(small) as usize, start);
+    }
+}
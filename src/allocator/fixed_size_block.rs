@@ -0,0 +1,128 @@
+//! Free lists of blocks in a few fixed sizes, over a linked list
+//! allocator.
+//!
+//! An allocation is rounded up to the smallest of `BLOCK_SIZES` that
+//! fits both its size and its alignment, and comes off the front of
+//! that size's list. Freed blocks go back on the front and are never
+//! merged, so both are a couple of pointer moves. An empty list gets a
+//! new block from the `LinkedListAllocator` underneath, as does
+//! anything bigger than the biggest size. What goes into a list never
+//! comes back out for another size, so a heap that's been all small
+//! blocks once can't be all big ones.
+//!
+//! The lock is only taken with interrupts off, in case a handler
+//! allocates.
+use super::linked_list::LinkedListAllocator;
+use crate::latency;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+use spin::Mutex;
+
+/// Powers of two, so a block of a size is aligned to it too.
+pub const BLOCK_SIZES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+struct FreeBlock {
+    next: *mut FreeBlock,
+}
+
+/// The first free block of each size, null if there's none.
+struct Lists([*mut FreeBlock; BLOCK_SIZES.len()]);
+
+// Only ever touched with the lock held.
+unsafe impl Send for Lists {}
+
+pub struct FixedSizeBlockAllocator {
+    lists: Mutex<Lists>,
+    fallback: LinkedListAllocator,
+}
+
+/// Which of `BLOCK_SIZES` `layout` goes in, `None` if it's too big.
+fn list_index(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&block| block >= size)
+}
+
+impl FixedSizeBlockAllocator {
+    /// An allocator with nothing to hand out until `add` is called.
+    pub const fn empty() -> FixedSizeBlockAllocator {
+        FixedSizeBlockAllocator {
+            lists: Mutex::new(Lists([ptr::null_mut(); BLOCK_SIZES.len()])),
+            fallback: LinkedListAllocator::empty(),
+        }
+    }
+
+    /// Add `size` bytes at `start` to the allocator underneath.
+    ///
+    /// # Safety
+    ///
+    /// The memory has to be mapped, writable and used for nothing else.
+    pub unsafe fn add(&self, start: usize, size: usize) {
+        self.fallback.add(start, size)
+    }
+}
+
+unsafe impl GlobalAlloc for FixedSizeBlockAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let index = match list_index(layout) {
+            Some(index) => index,
+            None => return self.fallback.alloc(layout),
+        };
+        let block = latency::without_interrupts(|| {
+            let mut lists = self.lists.lock();
+            let block = lists.0[index];
+            if !block.is_null() {
+                lists.0[index] = (*block).next;
+            }
+            block
+        });
+        if !block.is_null() {
+            return block as *mut u8;
+        }
+        let size = BLOCK_SIZES[index];
+        self.fallback
+            .alloc(Layout::from_size_align_unchecked(size, size))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let index = match list_index(layout) {
+            Some(index) => index,
+            None => return self.fallback.dealloc(ptr, layout),
+        };
+        let block = ptr as *mut FreeBlock;
+        latency::without_interrupts(|| {
+            let mut lists = self.lists.lock();
+            block.write(FreeBlock {
+                next: lists.0[index],
+            });
+            lists.0[index] = block;
+        });
+    }
+}
+
+#[test_case]
+fn test_blocks_are_reused() {
+    #[repr(align(4096))]
+    struct Memory([u8; 8192]);
+
+    let mut memory = Memory([0; 8192]);
+    let start = memory.0.as_mut_ptr() as usize;
+    let allocator = FixedSizeBlockAllocator::empty();
+    unsafe {
+        allocator.add(start, 8192);
+        let small = Layout::from_size_align(24, 8).unwrap();
+        let a = allocator.alloc(small);
+        assert_eq!(a as usize % 32, 0);
+        allocator.dealloc(a, small);
+        // Anything else that rounds up to 32 gets the same one back.
+        let other = Layout::from_size_align(32, 16).unwrap();
+        assert_eq!(allocator.alloc(other), a);
+        assert_ne!(allocator.alloc(other), a);
+
+        // Too big for a list, so it goes back where it came from.
+        let big = Layout::from_size_align(4096, 8).unwrap();
+        let b = allocator.alloc(big);
+        assert!(!b.is_null());
+        allocator.dealloc(b, big);
+        assert_eq!(allocator.alloc(big), b);
+    }
+}
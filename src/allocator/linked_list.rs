@@ -0,0 +1,192 @@
+//! A first fit allocator over a list of free holes.
+//!
+//! The holes are kept in address order, each one's size and the next
+//! one living at the start of the hole itself. Freed blocks are merged
+//! back into the holes next to them, so the heap doesn't crumble into
+//! pieces too small to use. Every block and hole is a multiple of
+//! `Hole`'s size, which keeps there always being room for one in what
+//! an allocation leaves over.
+//!
+//! The lock is only taken with interrupts off, in case a handler
+//! allocates.
+use crate::latency;
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem;
+use core::ptr;
+use spin::Mutex;
+
+struct Hole {
+    size: usize,
+    next: *mut Hole,
+}
+
+const HOLE_SIZE: usize = mem::size_of::<Hole>();
+
+struct Holes {
+    /// The lowest one, null if the heap is full.
+    first: *mut Hole,
+    free: usize,
+}
+
+// Only ever touched with the lock held.
+unsafe impl Send for Holes {}
+
+pub struct LinkedListAllocator {
+    holes: Mutex<Holes>,
+}
+
+fn align_up(address: usize, align: usize) -> usize {
+    (address + align - 1) & !(align - 1)
+}
+
+/// What `layout` takes up in the heap.
+fn size_align(layout: Layout) -> (usize, usize) {
+    let size = align_up(layout.size().max(1), HOLE_SIZE);
+    (size, layout.align().max(HOLE_SIZE))
+}
+
+impl LinkedListAllocator {
+    /// An allocator with nothing to hand out until `add` is called.
+    pub const fn empty() -> LinkedListAllocator {
+        LinkedListAllocator {
+            holes: Mutex::new(Holes {
+                first: ptr::null_mut(),
+                free: 0,
+            }),
+        }
+    }
+
+    /// Add `size` bytes at `start` to the heap.
+    ///
+    /// # Safety
+    ///
+    /// The memory has to be mapped, writable and used for nothing else.
+    pub unsafe fn add(&self, start: usize, size: usize) {
+        let aligned = align_up(start, HOLE_SIZE);
+        let size = size.saturating_sub(aligned - start) & !(HOLE_SIZE - 1);
+        if size > 0 {
+            latency::without_interrupts(|| self.holes.lock().free(aligned, size));
+        }
+    }
+
+    /// Bytes not handed out.
+    pub fn free(&self) -> usize {
+        latency::without_interrupts(|| self.holes.lock().free)
+    }
+}
+
+impl Holes {
+    unsafe fn alloc(&mut self, size: usize, align: usize) -> *mut u8 {
+        let mut previous: *mut Hole = ptr::null_mut();
+        let mut hole = self.first;
+        while !hole.is_null() {
+            let start = hole as usize;
+            let end = start + (*hole).size;
+            let address = align_up(start, align);
+            let next = (*hole).next;
+            if address + size <= end {
+                // What's left before and after the block stays free,
+                // both are multiples of `HOLE_SIZE`.
+                let mut link = next;
+                if end > address + size {
+                    let after = (address + size) as *mut Hole;
+                    after.write(Hole {
+                        size: end - address - size,
+                        next: link,
+                    });
+                    link = after;
+                }
+                if address > start {
+                    (*hole).size = address - start;
+                    (*hole).next = link;
+                    link = hole;
+                }
+                if previous.is_null() {
+                    self.first = link;
+                } else {
+                    (*previous).next = link;
+                }
+                self.free -= size;
+                return address as *mut u8;
+            }
+            previous = hole;
+            hole = next;
+        }
+        ptr::null_mut()
+    }
+
+    /// Put `size` bytes at `address` back, merging it with the holes
+    /// on either side if it touches them.
+    unsafe fn free(&mut self, address: usize, size: usize) {
+        self.free += size;
+        let mut previous: *mut Hole = ptr::null_mut();
+        let mut next = self.first;
+        while !next.is_null() && (next as usize) < address {
+            previous = next;
+            next = (*next).next;
+        }
+        let block = address as *mut Hole;
+        block.write(Hole { size, next });
+        if !next.is_null() && address + size == next as usize {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+        if previous.is_null() {
+            self.first = block;
+        } else if previous as usize + (*previous).size == address {
+            (*previous).size += (*block).size;
+            (*previous).next = (*block).next;
+        } else {
+            (*previous).next = block;
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for LinkedListAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (size, align) = size_align(layout);
+        latency::without_interrupts(|| self.holes.lock().alloc(size, align))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = size_align(layout);
+        latency::without_interrupts(|| self.holes.lock().free(ptr as usize, size))
+    }
+}
+
+#[test_case]
+fn test_first_fit_and_merging() {
+    #[repr(align(4096))]
+    struct Memory([u8; 1024]);
+
+    let mut memory = Memory([0; 1024]);
+    let start = memory.0.as_mut_ptr() as usize;
+    let allocator = LinkedListAllocator::empty();
+    unsafe {
+        allocator.add(start, 1024);
+        let small = Layout::from_size_align(24, 8).unwrap();
+        let a = allocator.alloc(small);
+        let b = allocator.alloc(small);
+        assert_eq!(a as usize, start);
+        assert_eq!(b as usize, start + 32);
+        assert_eq!(allocator.free(), 1024 - 64);
+
+        // Aligning leaves a hole in front, which the next small one
+        // fits into.
+        let aligned = allocator.alloc(Layout::from_size_align(16, 256).unwrap());
+        assert_eq!(aligned as usize, start + 256);
+        assert_eq!(allocator.alloc(small) as usize, start + 64);
+        assert!(allocator
+            .alloc(Layout::from_size_align(1024, 8).unwrap())
+            .is_null());
+
+        allocator.dealloc(a, small);
+        allocator.dealloc(b, small);
+        allocator.dealloc((start + 64) as *mut u8, small);
+        allocator.dealloc(aligned, Layout::from_size_align(16, 256).unwrap());
+        // All back in one piece.
+        assert_eq!(allocator.free(), 1024);
+        let all = Layout::from_size_align(1024, 8).unwrap();
+        assert_eq!(allocator.alloc(all) as usize, start);
+    }
+}
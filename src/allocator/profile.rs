@@ -0,0 +1,220 @@
+//! Where the heap went, by who allocated it.
+//!
+//! Every live block the tracker knows about is put down to the first
+//! function among its callers that isn't part of allocating itself
+//! (`alloc`, `core` and this module), and the bytes are added up per
+//! function. `heapprof` shows the biggest:
+//!
+//! ```text
+//! ┌──────────────────────────────────────────┬──────────┬────────┐
+//! │ site                                     │    bytes │ blocks │
+//! ├──────────────────────────────────────────┼──────────┼────────┤
+//! │ blog_os::trace::init                     │  1048576 │      1 │
+//! │ blog_os::shell::register                 │     4096 │      8 │
+//! └──────────────────────────────────────────┴──────────┴────────┘
+//! ```
+//!
+//! Only built with the `heap-profile` feature.
+use super::{for_each_block_since, heap_stats, Block, HeapStats};
+use crate::ksyms::{self, Demangle};
+use crate::shell::{self, parse_number, CommandFailed, CommandResult};
+use crate::ui::{Column, Table};
+use core::fmt;
+
+/// Different sites a profile keeps apart, the rest are added up as
+/// one.
+pub const MAX_SITES: usize = 32;
+
+/// Live heap allocated from one function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Site {
+    /// Where the function starts, or the return address itself if
+    /// there's no symbol for it.
+    pub address: u64,
+    pub name: Option<&'static str>,
+    pub bytes: usize,
+    pub blocks: usize,
+}
+
+pub struct Profile {
+    sites: [Site; MAX_SITES],
+    len: usize,
+    /// Blocks from sites that didn't fit.
+    pub other_bytes: usize,
+    pub other_blocks: usize,
+    /// Blocks the tracker had no room to remember, which have no site.
+    pub untracked_blocks: usize,
+    pub untracked_bytes: usize,
+}
+
+/// Whether a symbol is one of the functions every allocation goes
+/// through, rather than whoever wanted the memory.
+fn is_allocator(name: &str) -> bool {
+    name.starts_with("_ZN5alloc")
+        || name.starts_with("_ZN4core")
+        || name.starts_with("__rust_")
+        || name.starts_with("__rg_")
+        || name.contains("blog_os9allocator")
+        || name.contains("blog_os..allocator")
+}
+
+/// The site `block` is put down to.
+fn site_of(block: &Block) -> (u64, Option<&'static str>) {
+    let callers = block.callers.iter().copied().filter(|&caller| caller != 0);
+    let mut last = (0, None);
+    for caller in callers {
+        // Return addresses are just past the call.
+        match ksyms::lookup(caller - 1) {
+            Some((name, _)) if is_allocator(name) => last = (caller, Some(name)),
+            Some((name, offset)) => return (caller - 1 - offset, Some(name)),
+            None => return (caller, None),
+        }
+    }
+    // All of them were, or there were none: the deepest will do.
+    last
+}
+
+impl Profile {
+    /// Group every live block by site.
+    pub fn take() -> Profile {
+        let empty = Site {
+            address: 0,
+            name: None,
+            bytes: 0,
+            blocks: 0,
+        };
+        let mut profile = Profile {
+            sites: [empty; MAX_SITES],
+            len: 0,
+            other_bytes: 0,
+            other_blocks: 0,
+            untracked_blocks: 0,
+            untracked_bytes: 0,
+        };
+        let mut tracked_bytes = 0;
+        let mut tracked_blocks = 0;
+        for_each_block_since(&HeapStats::default(), |block| {
+            tracked_bytes += block.size;
+            tracked_blocks += 1;
+            let (address, name) = site_of(block);
+            let len = profile.len;
+            let site = match profile.sites[..len]
+                .iter()
+                .position(|site| site.address == address)
+            {
+                Some(index) => &mut profile.sites[index],
+                None if len < MAX_SITES => {
+                    profile.sites[len] = Site {
+                        address,
+                        name,
+                        ..empty
+                    };
+                    profile.len += 1;
+                    &mut profile.sites[len]
+                }
+                None => {
+                    profile.other_bytes += block.size;
+                    profile.other_blocks += 1;
+                    return;
+                }
+            };
+            site.bytes += block.size;
+            site.blocks += 1;
+        });
+        profile.sites[..profile.len].sort_unstable_by(|a, b| b.bytes.cmp(&a.bytes));
+        // The table can change while it's copied out, don't go below 0.
+        let heap = heap_stats();
+        profile.untracked_blocks = heap.live_blocks.saturating_sub(tracked_blocks);
+        profile.untracked_bytes = heap.live_bytes.saturating_sub(tracked_bytes);
+        profile
+    }
+
+    /// Biggest first.
+    pub fn sites(&self) -> &[Site] {
+        &self.sites[..self.len]
+    }
+}
+
+/// Adds the `heapprof` shell command.
+pub fn init() {
+    shell::register(
+        "heapprof",
+        "heapprof [COUNT]: live heap by where it was allocated",
+        heapprof_command,
+    )
+    .expect("heapprof command");
+}
+
+struct SiteName(Site);
+
+impl fmt::Display for SiteName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0.name {
+            Some(name) => write!(f, "{}", Demangle(name)),
+            None => write!(f, "{:#x}", self.0.address),
+        }
+    }
+}
+
+fn heapprof_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    let count = match args.split_whitespace().next().map(parse_number) {
+        None => 10,
+        Some(Some(count)) => count as usize,
+        Some(None) => {
+            let _ = writeln!(out, "usage: heapprof [COUNT]");
+            return Err(CommandFailed);
+        }
+    };
+    let profile = Profile::take();
+    const COLUMNS: [Column; 3] = [
+        Column::left("site", 40),
+        Column::right("bytes", 8),
+        Column::right("blocks", 6),
+    ];
+    let table = Table::new(&COLUMNS);
+    let _ = table.header(out);
+    for site in profile.sites().iter().take(count) {
+        let _ = table.row(out, &[&SiteName(*site), &site.bytes, &site.blocks]);
+    }
+    let rest = profile.sites().iter().skip(count);
+    let (bytes, blocks) = rest.fold((profile.other_bytes, profile.other_blocks), |sum, site| {
+        (sum.0 + site.bytes, sum.1 + site.blocks)
+    });
+    if blocks > 0 {
+        let _ = table.row(out, &[&"(everywhere else)", &bytes, &blocks]);
+    }
+    if profile.untracked_blocks > 0 {
+        let _ = table.row(
+            out,
+            &[
+                &"(not tracked)",
+                &profile.untracked_bytes,
+                &profile.untracked_blocks,
+            ],
+        );
+    }
+    let _ = table.end(out);
+    Ok(())
+}
+
+#[test_case]
+fn test_grouped_by_caller() {
+    use alloc::vec::Vec;
+
+    let buffers: Vec<Vec<u8>> = (0..4).map(|_| Vec::with_capacity(1000)).collect();
+    let profile = Profile::take();
+    let here = profile.sites().iter().find(|site| {
+        site.name
+            .map_or(false, |name| name.contains("test_grouped_by_caller"))
+    });
+    // The four buffers and the vector holding them, if the symbols
+    // are there to tell.
+    if ksyms::lookup(Profile::take as fn() -> Profile as usize as u64).is_some() {
+        let here = here.expect("the test as a site");
+        assert!(here.bytes >= 4000);
+        assert!(here.blocks >= 4);
+    }
+    let total: usize = profile.sites().iter().map(|site| site.bytes).sum();
+    assert!(total + profile.other_bytes + profile.untracked_bytes >= 4000);
+    drop(buffers);
+}
@@ -0,0 +1,476 @@
+//! The local APIC: error and thermal interrupts, its timer, and taking
+//! over from the 8259s.
+//!
+//! Our interrupts come from the 8259s, through the APIC's LINT0 in the
+//! virtual wire mode the firmware left it in, so the APIC stays set up
+//! however the firmware set it up. `enable` only points its error and
+//! thermal sensor entries at handlers of ours, as otherwise a firmware
+//! that unmasked one of them gets us a double fault.
+//!
+//! `take_over` goes further: it masks every line on both PICs and LINT0,
+//! moves the spurious vector to `SPURIOUS_VECTOR` and has the APIC timer
+//! tick periodically on the PIT's vector, as fast as the PIT did, so
+//! `time` doesn't notice. EOIs go to the APIC from then on. Anything
+//! else has to come through the IO APIC then, `interrupts::use_apic`
+//! routes the keyboard and serial lines there and takes over, which is
+//! done at boot with `pic=off` on the command line. `hand_back` undoes
+//! it.
+//!
+//! APIC errors are logged with what the error status register says
+//! went wrong. Thermal interrupts come when the CPU starts or stops
+//! throttling itself for being too hot, `apic` shows how often that
+//! happened and whether it's throttling now.
+//!
+//! The timer is calibrated against the PIT and left off, `idle` arms
+//! it one-shot to wake up from a long sleep, unless it's ticking for
+//! the PIT.
+//!
+//! `send_ipi` goes through the interrupt command register, it's how
+//! `smp` wakes the other CPUs.
+use crate::error::{KernelError, KernelResult};
+use crate::interrupts::{self, InterruptIndex};
+use crate::latency;
+use crate::memory;
+use crate::shell::{self, CommandResult};
+use crate::time;
+use core::arch::x86_64::__cpuid;
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+
+/// What `smp` sends a parked CPU to have it look at what it should be
+/// doing.
+pub const WAKE_VECTOR: u8 = 0xFB;
+/// Right below where a spurious vector usually goes.
+pub const TIMER_VECTOR: u8 = 0xFC;
+pub const THERMAL_VECTOR: u8 = 0xFD;
+pub const ERROR_VECTOR: u8 = 0xFE;
+/// Low four bits all set, which some older APICs insist on.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const IA32_THERM_INTERRUPT: u32 = 0x19B;
+const IA32_THERM_STATUS: u32 = 0x19C;
+
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+/// The CPU is throttling right now.
+const THERM_STATUS_ACTIVE: u64 = 1;
+/// Sticky, it started or stopped throttling since the bit was cleared.
+const THERM_STATUS_LOG: u64 = 1 << 1;
+/// High and low temperature interrupt enables.
+const THERM_INTERRUPT_HIGH_LOW: u64 = 0b11;
+
+const ID: usize = 0x20;
+const VERSION: usize = 0x30;
+const EOI: usize = 0xB0;
+const SPURIOUS: usize = 0xF0;
+const ERROR_STATUS: usize = 0x280;
+const ICR_LOW: usize = 0x300;
+const ICR_HIGH: usize = 0x310;
+const LVT_TIMER: usize = 0x320;
+const LVT_THERMAL: usize = 0x330;
+const LVT_LINT0: usize = 0x350;
+const LVT_ERROR: usize = 0x370;
+const TIMER_INITIAL_COUNT: usize = 0x380;
+const TIMER_CURRENT_COUNT: usize = 0x390;
+const TIMER_DIVIDE: usize = 0x3E0;
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const TIMER_PERIODIC: u32 = 1 << 17;
+/// Set in the ICR until the IPI has gone out.
+const DELIVERY_PENDING: u32 = 1 << 12;
+/// An INIT IPI, asserted, which resets the CPU it's sent to and leaves
+/// it waiting for a startup IPI.
+pub const IPI_INIT: u32 = 0b101 << 8 | 1 << 14;
+/// A startup IPI, for real mode code at the page in the vector bits.
+pub const IPI_STARTUP: u32 = 0b110 << 8 | 1 << 14;
+/// An interrupt at the vector in the low bits.
+pub const IPI_FIXED: u32 = 1 << 14;
+/// Count down at the bus clock over 16.
+const DIVIDE_BY_16: u32 = 0b0011;
+
+/// Names of the error status register bits, lowest first.
+const ERRORS: [&str; 8] = [
+    "send checksum",
+    "receive checksum",
+    "send accept",
+    "receive accept",
+    "redirectable IPI",
+    "send illegal vector",
+    "received illegal vector",
+    "illegal register address",
+];
+
+/// Where the registers are mapped, 0 until `enable`.
+static REGISTERS: AtomicU64 = AtomicU64::new(0);
+static ERROR_COUNT: AtomicU64 = AtomicU64::new(0);
+/// Error status bits seen so far, or-ed together.
+static ERRORS_SEEN: AtomicU64 = AtomicU64::new(0);
+static THROTTLE_EVENTS: AtomicU64 = AtomicU64::new(0);
+/// Set if thermal interrupts are on.
+static THERMAL: AtomicBool = AtomicBool::new(false);
+/// How fast the timer counts down, 0 if it isn't calibrated.
+static TIMER_HZ: AtomicU64 = AtomicU64::new(0);
+/// Set while the APIC has taken over from the PICs.
+static TAKEN_OVER: AtomicBool = AtomicBool::new(false);
+
+/// What `take_over` changed, for `hand_back`.
+struct Legacy {
+    pic_masks: [u8; 2],
+    spurious: u32,
+    lint0: u32,
+}
+
+static LEGACY: spin::Mutex<Option<Legacy>> = spin::Mutex::new(None);
+
+/// Adds the `apic` shell command.
+pub fn init() {
+    shell::register(
+        "apic",
+        "local APIC errors and thermal throttling",
+        apic_command,
+    )
+    .expect("apic command");
+}
+
+fn read(offset: usize) -> u32 {
+    let base = REGISTERS.load(Ordering::Relaxed);
+    unsafe { ptr::read_volatile((base as usize + offset) as *const u32) }
+}
+
+fn write(offset: usize, value: u32) {
+    let base = REGISTERS.load(Ordering::Relaxed);
+    unsafe { ptr::write_volatile((base as usize + offset) as *mut u32, value) }
+}
+
+/// Point the error and thermal LVT entries at our vectors. Needs
+/// `memory::init`, the registers are reached through the physical
+/// memory mapping.
+pub fn enable() -> KernelResult<()> {
+    let features = unsafe { __cpuid(1) };
+    if features.edx & 1 << 9 == 0 {
+        return Err(KernelError::Unsupported);
+    }
+    let base = unsafe { Msr::new(IA32_APIC_BASE).read() };
+    if base & APIC_GLOBAL_ENABLE == 0 {
+        return Err(KernelError::NotReady);
+    }
+    let registers = memory::physical_to_virtual(PhysAddr::new(base & !0xFFF), 4096)?;
+    REGISTERS.store(registers.as_u64(), Ordering::Relaxed);
+    if read(SPURIOUS) & SOFTWARE_ENABLE == 0 {
+        // Turning it on would change how the PIC's interrupts get to us.
+        REGISTERS.store(0, Ordering::Relaxed);
+        return Err(KernelError::NotReady);
+    }
+
+    let max_lvt = (read(VERSION) >> 16) & 0xFF;
+    if max_lvt >= 3 {
+        // Clear anything from before, the register latches on a write.
+        write(ERROR_STATUS, 0);
+        write(LVT_ERROR, u32::from(ERROR_VECTOR));
+    }
+    // Thermal interrupts need the thermal MSRs (the ACPI feature bit)
+    // and an LVT entry for them.
+    if max_lvt >= 5 && features.edx & 1 << 22 != 0 {
+        unsafe {
+            let mut interrupt = Msr::new(IA32_THERM_INTERRUPT);
+            let enables = interrupt.read() | THERM_INTERRUPT_HIGH_LOW;
+            interrupt.write(enables);
+        }
+        write(LVT_THERMAL, u32::from(THERMAL_VECTOR));
+        THERMAL.store(true, Ordering::Relaxed);
+    }
+    calibrate_timer();
+    Ok(())
+}
+
+/// Let the timer count down for 10 ms of PIT time to see how fast it
+/// goes. It's left masked and stopped.
+fn calibrate_timer() {
+    write(LVT_TIMER, LVT_MASKED | u32::from(TIMER_VECTOR));
+    write(TIMER_DIVIDE, DIVIDE_BY_16);
+    write(TIMER_INITIAL_COUNT, u32::MAX);
+    let waited = time::spin_ms(10);
+    let counted = u32::MAX - read(TIMER_CURRENT_COUNT);
+    write(TIMER_INITIAL_COUNT, 0);
+    if waited.is_ok() {
+        TIMER_HZ.store(u64::from(counted) * 100, Ordering::Relaxed);
+    }
+}
+
+/// This CPU's APIC ID, what the IO APIC sends interrupts for it to.
+/// `None` before `enable`.
+pub fn id() -> Option<u8> {
+    if REGISTERS.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+    Some((read(ID) >> 24) as u8)
+}
+
+/// How fast the timer counts, if it's been calibrated.
+pub fn timer_hz() -> Option<u64> {
+    match TIMER_HZ.load(Ordering::Relaxed) {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+/// Get a `TIMER_VECTOR` interrupt in `ns`, or as long as the timer
+/// goes if that's longer. False if there is no timer to do it, or it's
+/// busy ticking for the PIT.
+pub fn arm_timer(ns: u64) -> bool {
+    let hz = match timer_hz() {
+        Some(hz) if !has_taken_over() => hz,
+        _ => return false,
+    };
+    let count = (u128::from(ns) * u128::from(hz) / 1_000_000_000) as u64;
+    write(LVT_TIMER, u32::from(TIMER_VECTOR));
+    write(
+        TIMER_INITIAL_COUNT,
+        count.max(1).min(u64::from(u32::MAX)) as u32,
+    );
+    true
+}
+
+/// Stop the timer before it goes off.
+pub fn disarm_timer() {
+    if timer_hz().is_some() {
+        write(TIMER_INITIAL_COUNT, 0);
+        write(LVT_TIMER, LVT_MASKED | u32::from(TIMER_VECTOR));
+    }
+}
+
+/// Called by the APIC timer interrupt handler. Waking up was all it
+/// was for.
+pub fn handle_timer() {
+    end_of_interrupt();
+}
+
+/// Called by the wake IPI's handler, on a parked CPU. PV EOI is only
+/// set up for the boot CPU, so this always writes the EOI.
+pub fn handle_wake() {
+    write(EOI, 0);
+}
+
+/// Whether interrupts come through the APIC instead of the PICs.
+pub fn has_taken_over() -> bool {
+    TAKEN_OVER.load(Ordering::Relaxed)
+}
+
+/// Mask the PICs and have the APIC tick in place of the PIT. Needs
+/// `enable` to have worked and calibrated the timer, `NotReady` if not.
+pub fn take_over() -> KernelResult<()> {
+    let hz = match timer_hz() {
+        Some(hz) if REGISTERS.load(Ordering::Relaxed) != 0 => hz,
+        _ => return Err(KernelError::NotReady),
+    };
+    let count = u128::from(hz) * u128::from(time::NANOSECONDS_PER_TICK) / 1_000_000_000;
+    latency::without_interrupts(|| {
+        let mut legacy = LEGACY.lock();
+        if legacy.is_some() {
+            return Err(KernelError::AlreadyExists);
+        }
+        *legacy = Some(Legacy {
+            pic_masks: interrupts::set_pic_masks([0xFF, 0xFF]),
+            spurious: read(SPURIOUS),
+            lint0: read(LVT_LINT0),
+        });
+        write(SPURIOUS, SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR));
+        write(LVT_LINT0, LVT_MASKED | read(LVT_LINT0));
+        write(TIMER_DIVIDE, DIVIDE_BY_16);
+        write(
+            LVT_TIMER,
+            TIMER_PERIODIC | u32::from(InterruptIndex::Timer.as_u8()),
+        );
+        write(
+            TIMER_INITIAL_COUNT,
+            count.max(1).min(u128::from(u32::MAX)) as u32,
+        );
+        TAKEN_OVER.store(true, Ordering::Relaxed);
+        Ok(())
+    })
+}
+
+/// Stop the timer and give the PICs back what `take_over` took.
+/// `NotReady` if it hadn't.
+pub fn hand_back() -> KernelResult<()> {
+    latency::without_interrupts(|| {
+        let legacy = LEGACY.lock().take().ok_or(KernelError::NotReady)?;
+        write(TIMER_INITIAL_COUNT, 0);
+        write(LVT_TIMER, LVT_MASKED | u32::from(TIMER_VECTOR));
+        write(LVT_LINT0, legacy.lint0);
+        write(SPURIOUS, legacy.spurious);
+        TAKEN_OVER.store(false, Ordering::Relaxed);
+        interrupts::set_pic_masks(legacy.pic_masks);
+        Ok(())
+    })
+}
+
+/// Signal the end of an interrupt that came through the APIC.
+pub(crate) fn end_of_interrupt() {
+    // KVM may have said we don't need to, see `kvm`.
+    if crate::kvm::skip_eoi() {
+        return;
+    }
+    write(EOI, 0);
+}
+
+/// Send the IPI `command`, what goes in the low half of the ICR, to the
+/// CPU with APIC ID `destination`. `Timeout` if it never went out.
+pub fn send_ipi(destination: u8, command: u32) -> KernelResult<()> {
+    if REGISTERS.load(Ordering::Relaxed) == 0 {
+        return Err(KernelError::NotReady);
+    }
+    latency::without_interrupts(|| {
+        write(ICR_HIGH, u32::from(destination) << 24);
+        // Writing the low half is what sends it.
+        write(ICR_LOW, command);
+        for _ in 0..1_000_000 {
+            if read(ICR_LOW) & DELIVERY_PENDING == 0 {
+                return Ok(());
+            }
+            core::sync::atomic::spin_loop_hint();
+        }
+        Err(KernelError::Timeout)
+    })
+}
+
+/// Turn on the APIC of another CPU, which starts out software disabled,
+/// with the same spurious vector as ours. Everything else stays masked,
+/// nothing's sent to it.
+pub fn enable_on_this_cpu() {
+    write(SPURIOUS, SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR));
+}
+
+/// Called by the APIC error interrupt handler.
+pub fn handle_error() {
+    write(ERROR_STATUS, 0);
+    let status = read(ERROR_STATUS);
+    ERROR_COUNT.fetch_add(1, Ordering::Relaxed);
+    ERRORS_SEEN.fetch_or(u64::from(status), Ordering::Relaxed);
+    crate::klog!(Error, "APIC error {:#04x}: {}", status, ErrorStatus(status));
+    end_of_interrupt();
+}
+
+/// Called by the thermal interrupt handler.
+pub fn handle_thermal() {
+    let mut msr = Msr::new(IA32_THERM_STATUS);
+    let status = unsafe { msr.read() };
+    if status & THERM_STATUS_LOG != 0 {
+        THROTTLE_EVENTS.fetch_add(1, Ordering::Relaxed);
+        let what = if status & THERM_STATUS_ACTIVE != 0 {
+            "started"
+        } else {
+            "stopped"
+        };
+        crate::klog!(Warn, "CPU {} throttling", what);
+        unsafe { msr.write(status & !THERM_STATUS_LOG) };
+    }
+    end_of_interrupt();
+}
+
+/// Times the CPU started or stopped throttling.
+pub fn throttle_events() -> u64 {
+    THROTTLE_EVENTS.load(Ordering::Relaxed)
+}
+
+/// Whether the CPU is throttling right now, `None` if we can't tell.
+pub fn is_throttling() -> Option<bool> {
+    if !THERMAL.load(Ordering::Relaxed) {
+        return None;
+    }
+    let status = unsafe { Msr::new(IA32_THERM_STATUS).read() };
+    Some(status & THERM_STATUS_ACTIVE != 0)
+}
+
+/// Lists the errors set in an error status register value.
+pub struct ErrorStatus(pub u32);
+
+impl fmt::Display for ErrorStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut first = true;
+        for (bit, name) in ERRORS.iter().enumerate() {
+            if self.0 & 1 << bit != 0 {
+                write!(f, "{}{}", if first { "" } else { ", " }, name)?;
+                first = false;
+            }
+        }
+        if first {
+            write!(f, "none")?;
+        }
+        Ok(())
+    }
+}
+
+fn apic_command(out: &mut dyn fmt::Write, _args: &str) -> CommandResult {
+    let registers = REGISTERS.load(Ordering::Relaxed);
+    if registers == 0 {
+        let _ = writeln!(out, "not set up");
+        return Ok(());
+    }
+    let _ = writeln!(
+        out,
+        "registers at {:#x}, version {:#x}",
+        registers,
+        read(VERSION)
+    );
+    if let Some(hz) = timer_hz() {
+        let _ = writeln!(out, "timer at {} kHz", hz / 1000);
+    }
+    if has_taken_over() {
+        let _ = writeln!(out, "in place of the PICs");
+    }
+    let _ = writeln!(
+        out,
+        "{} errors: {}",
+        ERROR_COUNT.load(Ordering::Relaxed),
+        ErrorStatus(ERRORS_SEEN.load(Ordering::Relaxed) as u32)
+    );
+    match is_throttling() {
+        Some(now) => {
+            let _ = writeln!(
+                out,
+                "{} throttle events, {}throttling now",
+                throttle_events(),
+                if now { "" } else { "not " }
+            );
+        }
+        None => {
+            let _ = writeln!(out, "no thermal interrupts");
+        }
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_decodes_error_status() {
+    use alloc::string::String;
+    use core::fmt::Write;
+
+    let mut buffer = String::new();
+    write!(buffer, "{}", ErrorStatus(0b1010_0000)).unwrap();
+    assert_eq!(buffer, "send illegal vector, illegal register address");
+}
+
+#[test_case]
+fn test_takes_over_from_the_pics() {
+    use x86_64::instructions::port::Port;
+
+    if take_over().is_err() {
+        // No APIC, or one that didn't calibrate.
+        return;
+    }
+    let ticks = interrupts::COUNTERS[interrupts::TIMER_COUNTER].count();
+    for _ in 0..3 {
+        x86_64::instructions::hlt();
+    }
+    let masks = unsafe { [Port::<u8>::new(0x21).read(), Port::<u8>::new(0xA1).read()] };
+    let ticked = interrupts::COUNTERS[interrupts::TIMER_COUNTER].count() > ticks;
+    hand_back().unwrap();
+    assert_eq!(masks, [0xFF, 0xFF]);
+    assert!(ticked);
+    assert!(!has_taken_over());
+    assert_eq!(hand_back(), Err(KernelError::NotReady));
+}
@@ -0,0 +1,421 @@
+//! IDE drives, through the legacy ports of the two ATA channels.
+//!
+//! Every PC-compatible IDE controller, and QEMU's PIIX, answers at
+//! `0x1F0` and `0x170` until it's switched to native mode, so there's
+//! no need to go through `pci`. Each channel has a master and a slave.
+//! `probe` sends each one IDENTIFY and registers what answers as a
+//! `block` device: `hd0`, `hd1`, ... for disks and `cd0`, ... for CD
+//! drives. Everything is polled PIO with the channel's interrupt off,
+//! which is slow but simple, and only reads for now.
+//!
+//! A CD drive is ATAPI: it aborts IDENTIFY, leaving a signature that
+//! says so, and takes SCSI commands as a 12 byte packet after the
+//! PACKET command. What it sends back comes in pieces, with how big
+//! each is in the LBA mid and high registers. It reads 2048 byte
+//! sectors, and asks how many there are with READ CAPACITY each time,
+//! since the disc can change. Without one in it, that's 0 and reads
+//! fail with `NotReady`.
+use crate::block::{self, BlockDevice};
+use crate::error::{KernelError, KernelResult};
+use crate::fault_injection::{self, FaultPoint};
+use crate::time;
+use alloc::sync::Arc;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+// From the channel's base port.
+const DATA: u16 = 0;
+const ERROR: u16 = 1;
+const FEATURES: u16 = 1;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DRIVE: u16 = 6;
+const STATUS: u16 = 7;
+const COMMAND: u16 = 7;
+
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+/// In the control register, turns the channel's interrupt off.
+const CONTROL_NIEN: u8 = 1 << 1;
+
+const IDENTIFY: u8 = 0xEC;
+const IDENTIFY_PACKET: u8 = 0xA1;
+const READ_SECTORS: u8 = 0x20;
+const PACKET: u8 = 0xA0;
+
+const READ_CAPACITY: u8 = 0x25;
+const READ_12: u8 = 0xA8;
+
+/// What ATAPI drives leave in LBA mid and high after IDENTIFY.
+const ATAPI_SIGNATURE: (u8, u8) = (0x14, 0xEB);
+const DISK_SECTOR_SIZE: usize = 512;
+const CD_SECTOR_SIZE: usize = 2048;
+/// Sectors in one READ SECTORS, which takes a byte for the count.
+const MAX_DISK_SECTORS: usize = 128;
+/// Sectors in one READ, so one command doesn't hold the channel for
+/// too long.
+const MAX_CD_SECTORS: usize = 16;
+/// A CD drive can take a few seconds to spin up.
+const TIMEOUT_NS: u64 = 5_000_000_000;
+
+const DISK_NAMES: [&str; 4] = ["hd0", "hd1", "hd2", "hd3"];
+const CD_NAMES: [&str; 4] = ["cd0", "cd1", "cd2", "cd3"];
+
+struct Channel {
+    base: u16,
+    control: u16,
+}
+
+/// Primary and secondary. Both drives on one share its registers.
+static CHANNELS: [Mutex<Channel>; 2] = [
+    Mutex::new(Channel {
+        base: 0x1F0,
+        control: 0x3F6,
+    }),
+    Mutex::new(Channel {
+        base: 0x170,
+        control: 0x376,
+    }),
+];
+
+impl Channel {
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.base + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::new(self.base + register).write(value) }
+    }
+
+    fn read_data(&self, buffer: &mut [u8]) {
+        let mut data = Port::<u16>::new(self.base + DATA);
+        for pair in buffer.chunks_exact_mut(2) {
+            pair.copy_from_slice(&unsafe { data.read() }.to_le_bytes());
+        }
+    }
+
+    /// Read and drop `count` bytes the drive has more of than we want.
+    fn skip_data(&self, count: usize) {
+        let mut data = Port::<u16>::new(self.base + DATA);
+        for _ in 0..(count + 1) / 2 {
+            unsafe { data.read() };
+        }
+    }
+
+    fn write_data(&self, bytes: &[u8]) {
+        let mut data = Port::<u16>::new(self.base + DATA);
+        for pair in bytes.chunks_exact(2) {
+            unsafe { data.write(u16::from_le_bytes([pair[0], pair[1]])) };
+        }
+    }
+
+    /// The status without acknowledging anything, from the control
+    /// block. Reading it 4 times is the 400ns a drive needs to put its
+    /// status up after a command or being selected.
+    fn delay(&self) -> u8 {
+        let mut alternate = Port::<u8>::new(self.control);
+        let mut status = 0;
+        for _ in 0..4 {
+            status = unsafe { alternate.read() };
+        }
+        status
+    }
+
+    /// Make the master or slave the one the registers are for, with the
+    /// top 4 bits of an LBA28 address.
+    fn select(&self, slave: bool, lba_top: u8) {
+        self.write(DRIVE, 0xE0 | ((slave as u8) << 4) | (lba_top & 0xF));
+        self.delay();
+    }
+
+    /// Wait for the drive to stop being busy and have all of `ready` in
+    /// its status. `DeviceError` if it failed the command.
+    fn wait(&self, ready: u8) -> KernelResult<()> {
+        let deadline = time::monotonic_ns() + TIMEOUT_NS;
+        loop {
+            let status = self.read(STATUS);
+            if status & STATUS_BSY == 0 {
+                if status & (STATUS_ERR | STATUS_DF) != 0 {
+                    return Err(KernelError::DeviceError);
+                }
+                if status & ready == ready {
+                    return Ok(());
+                }
+            }
+            if time::monotonic_ns() > deadline {
+                return Err(KernelError::Timeout);
+            }
+            core::sync::atomic::spin_loop_hint();
+        }
+    }
+
+    /// What's there as master or slave, if anything.
+    fn identify(&self, slave: bool) -> Option<Kind> {
+        self.select(slave, 0);
+        // Nothing pulls the bus down without a drive on it.
+        if self.read(STATUS) == 0xFF {
+            return None;
+        }
+        for register in [SECTOR_COUNT, LBA_LOW, LBA_MID, LBA_HIGH].iter() {
+            self.write(*register, 0);
+        }
+        self.write(COMMAND, IDENTIFY);
+        if self.delay() == 0 {
+            return None;
+        }
+        let mut identity = [0; 512];
+        match self.wait(STATUS_DRQ) {
+            Ok(()) => {
+                self.read_data(&mut identity);
+                Some(Kind::Disk(disk_sectors(&identity)))
+            }
+            Err(KernelError::DeviceError)
+                if (self.read(LBA_MID), self.read(LBA_HIGH)) == ATAPI_SIGNATURE =>
+            {
+                self.write(COMMAND, IDENTIFY_PACKET);
+                self.delay();
+                self.wait(STATUS_DRQ).ok()?;
+                self.read_data(&mut identity);
+                Some(Kind::Cd)
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// Send the ATAPI `packet` and read what comes back into `buffer`,
+    /// dropping anything past its end. How much there was.
+    fn packet(&self, slave: bool, packet: &[u8; 12], buffer: &mut [u8]) -> KernelResult<usize> {
+        self.select(slave, 0);
+        self.wait(0)?;
+        // PIO, and the most it can send at once.
+        self.write(FEATURES, 0);
+        self.write(LBA_MID, CD_SECTOR_SIZE as u8);
+        self.write(LBA_HIGH, (CD_SECTOR_SIZE >> 8) as u8);
+        self.write(COMMAND, PACKET);
+        self.delay();
+        self.wait(STATUS_DRQ).map_err(|error| self.sense(error))?;
+        self.write_data(packet);
+        self.delay();
+
+        let mut done = 0;
+        loop {
+            self.wait(0).map_err(|error| self.sense(error))?;
+            if self.read(STATUS) & STATUS_DRQ == 0 {
+                return Ok(done);
+            }
+            let count = usize::from(self.read(LBA_MID)) | (usize::from(self.read(LBA_HIGH)) << 8);
+            let wanted = count.min(buffer.len() - done);
+            self.read_data(&mut buffer[done..done + wanted]);
+            self.skip_data(count - wanted);
+            done += wanted;
+            self.delay();
+        }
+    }
+
+    /// What an ATAPI command failing with `error` means, from the sense
+    /// key at the top of the error register.
+    fn sense(&self, error: KernelError) -> KernelError {
+        if error != KernelError::DeviceError {
+            return error;
+        }
+        match self.read(ERROR) >> 4 {
+            // NOT READY, and UNIT ATTENTION for a new disc.
+            0x2 | 0x6 => KernelError::NotReady,
+            // ILLEGAL REQUEST, like reading past the end.
+            0x5 => KernelError::InvalidArgument,
+            _ => KernelError::DeviceError,
+        }
+    }
+}
+
+/// Sectors on a disk, from its IDENTIFY: the LBA28 count, words 60 and
+/// 61. Not more, since that's all that's read with.
+fn disk_sectors(identity: &[u8; 512]) -> u64 {
+    u64::from(u32::from_le_bytes([
+        identity[120],
+        identity[121],
+        identity[122],
+        identity[123],
+    ]))
+}
+
+/// READ (12) of `count` sectors from `lba`.
+fn read_packet(lba: u32, count: u32) -> [u8; 12] {
+    let mut packet = [0; 12];
+    packet[0] = READ_12;
+    packet[2..6].copy_from_slice(&lba.to_be_bytes());
+    packet[6..10].copy_from_slice(&count.to_be_bytes());
+    packet
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// With this many sectors.
+    Disk(u64),
+    Cd,
+}
+
+/// A drive on one of the channels.
+pub struct Drive {
+    channel: &'static Mutex<Channel>,
+    slave: bool,
+    kind: Kind,
+}
+
+impl Drive {
+    fn read_disk(&self, start: u64, buffer: &mut [u8]) -> KernelResult<()> {
+        let channel = self.channel.lock();
+        for (index, chunk) in buffer
+            .chunks_mut(MAX_DISK_SECTORS * DISK_SECTOR_SIZE)
+            .enumerate()
+        {
+            let lba = start + (index * MAX_DISK_SECTORS) as u64;
+            let count = chunk.len() / DISK_SECTOR_SIZE;
+            channel.select(self.slave, (lba >> 24) as u8);
+            channel.wait(0)?;
+            channel.write(SECTOR_COUNT, count as u8);
+            channel.write(LBA_LOW, lba as u8);
+            channel.write(LBA_MID, (lba >> 8) as u8);
+            channel.write(LBA_HIGH, (lba >> 16) as u8);
+            channel.write(COMMAND, READ_SECTORS);
+            channel.delay();
+            for sector in chunk.chunks_exact_mut(DISK_SECTOR_SIZE) {
+                channel.wait(STATUS_DRQ)?;
+                channel.read_data(sector);
+                channel.delay();
+            }
+        }
+        Ok(())
+    }
+
+    fn read_cd(&self, start: u64, buffer: &mut [u8]) -> KernelResult<()> {
+        let channel = self.channel.lock();
+        for (index, chunk) in buffer
+            .chunks_mut(MAX_CD_SECTORS * CD_SECTOR_SIZE)
+            .enumerate()
+        {
+            let lba = start + (index * MAX_CD_SECTORS) as u64;
+            let count = chunk.len() / CD_SECTOR_SIZE;
+            let packet = read_packet(lba as u32, count as u32);
+            if channel.packet(self.slave, &packet, chunk)? != chunk.len() {
+                return Err(KernelError::DeviceError);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sectors on the disc in a CD drive, 0 without one.
+    fn capacity(&self) -> KernelResult<u64> {
+        let mut packet = [0; 12];
+        packet[0] = READ_CAPACITY;
+        let mut reply = [0; 8];
+        self.channel
+            .lock()
+            .packet(self.slave, &packet, &mut reply)?;
+        // The last sector, not how many.
+        let last = u32::from_be_bytes([reply[0], reply[1], reply[2], reply[3]]);
+        Ok(u64::from(last) + 1)
+    }
+}
+
+impl BlockDevice for Drive {
+    fn block_size(&self) -> usize {
+        match self.kind {
+            Kind::Disk(_) => DISK_SECTOR_SIZE,
+            Kind::Cd => CD_SECTOR_SIZE,
+        }
+    }
+
+    fn block_count(&self) -> u64 {
+        match self.kind {
+            Kind::Disk(sectors) => sectors,
+            Kind::Cd => self.capacity().unwrap_or(0),
+        }
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> KernelResult<()> {
+        let block_size = self.block_size() as u64;
+        let count = buffer.len() as u64 / block_size;
+        if buffer.len() as u64 % block_size != 0 {
+            return Err(KernelError::InvalidArgument);
+        }
+        if fault_injection::should_fail(FaultPoint::DiskRead) {
+            return Err(KernelError::DeviceError);
+        }
+        match self.kind {
+            Kind::Disk(sectors) if start.saturating_add(count) > sectors => {
+                Err(KernelError::InvalidArgument)
+            }
+            Kind::Disk(_) => self.read_disk(start, buffer),
+            // The drive says if it's past the end.
+            Kind::Cd if start.saturating_add(count) > u64::from(u32::MAX) => {
+                Err(KernelError::InvalidArgument)
+            }
+            Kind::Cd => self.read_cd(start, buffer),
+        }
+    }
+}
+
+/// Look for drives and register them with `block`. Needs the heap.
+pub fn probe() {
+    let (mut disks, mut cds) = (0, 0);
+    for channel in CHANNELS.iter() {
+        let locked = channel.lock();
+        // Polled, the PICs don't need to hear about it.
+        unsafe { Port::new(locked.control).write(CONTROL_NIEN) };
+        for &slave in [false, true].iter() {
+            let kind = match locked.identify(slave) {
+                Some(kind) => kind,
+                None => continue,
+            };
+            let name = match kind {
+                Kind::Disk(_) => {
+                    disks += 1;
+                    DISK_NAMES[disks - 1]
+                }
+                Kind::Cd => {
+                    cds += 1;
+                    CD_NAMES[cds - 1]
+                }
+            };
+            let drive = Drive {
+                channel,
+                slave,
+                kind,
+            };
+            if let Err(error) = block::register(name, Arc::new(drive)) {
+                crate::klog!(Warn, "ata: {}: {}", name, error.as_str());
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_read_packet() {
+    assert_eq!(
+        read_packet(0x0102_0304, 16),
+        [READ_12, 0, 1, 2, 3, 4, 0, 0, 0, 16, 0, 0]
+    );
+}
+
+#[test_case]
+fn test_boot_disk() {
+    // QEMU boots the tests off the first disk, unless it was `pvh`.
+    let disk = match block::device("hd0") {
+        Ok(disk) => disk,
+        Err(_) => return,
+    };
+    assert_eq!(disk.block_size(), DISK_SECTOR_SIZE);
+    assert!(disk.read_only());
+    let mut sector = [0; DISK_SECTOR_SIZE];
+    disk.read_blocks(0, &mut sector).unwrap();
+    assert_eq!(&sector[510..], &[0x55, 0xAA]);
+    assert_eq!(
+        disk.read_blocks(disk.block_count(), &mut sector),
+        Err(KernelError::InvalidArgument)
+    );
+}
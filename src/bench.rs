@@ -0,0 +1,71 @@
+//! Timing code in TSC cycles, for comparing ways of doing something.
+//!
+//! `cycles` times one call with interrupts off, so a tick doesn't land
+//! in the middle of it. Anything to set up goes outside the call.
+//! `Timing` collects the calls of a few runs, and the fastest is the
+//! one to compare, it's the one with least else going on. Cycles only
+//! turn into time if the TSC rate is known, see `time::tsc_hz`.
+//!
+//! Every run counts as time with interrupts off for `latency`, so keep
+//! them short.
+use crate::boot_timing::read_tsc;
+use crate::latency;
+
+/// Cycles `f` took, and what it returned.
+pub fn cycles<R>(f: impl FnOnce() -> R) -> (R, u64) {
+    latency::without_interrupts(|| {
+        let start = read_tsc();
+        let result = f();
+        (result, read_tsc().wrapping_sub(start))
+    })
+}
+
+/// Cycles over a number of runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    pub runs: u64,
+    pub min: u64,
+    pub max: u64,
+    total: u64,
+}
+
+impl Timing {
+    pub const fn new() -> Timing {
+        Timing {
+            runs: 0,
+            min: u64::MAX,
+            max: 0,
+            total: 0,
+        }
+    }
+
+    pub fn record(&mut self, cycles: u64) {
+        self.runs += 1;
+        self.min = self.min.min(cycles);
+        self.max = self.max.max(cycles);
+        self.total += cycles;
+    }
+
+    pub fn mean(&self) -> u64 {
+        self.total / self.runs.max(1)
+    }
+}
+
+impl Default for Timing {
+    fn default() -> Timing {
+        Timing::new()
+    }
+}
+
+#[test_case]
+fn test_timing_keeps_fastest() {
+    let mut timing = Timing::new();
+    for &run in [30, 10, 20].iter() {
+        timing.record(run);
+    }
+    assert_eq!((timing.runs, timing.min, timing.max), (3, 10, 30));
+    assert_eq!(timing.mean(), 20);
+    let (value, cycles) = cycles(|| 7);
+    assert_eq!(value, 7);
+    assert!(cycles < 1 << 32);
+}
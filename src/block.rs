@@ -0,0 +1,344 @@
+//! Block devices: disks, CDs, and images of them kept in memory.
+//!
+//! A driver `register`s its device under a name, like `cd0`, and file
+//! systems read it through `BlockDevice`, a whole number of blocks at
+//! a time, or through `read_bytes` for any range. `RamDisk` is one in
+//! memory, for an image fetched some other way and for tests.
+//!
+//! Registered devices count what's read from and written to them, on
+//! their own and all together for `stats`. The shell has `lsblk` to
+//! list them with their counts.
+use crate::error::{KernelError, KernelResult};
+use crate::fault_injection::{self, FaultPoint};
+use crate::shell::{self, CommandResult};
+use crate::ui::{Column, Table};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+pub trait BlockDevice: Send + Sync {
+    /// Bytes in a block, 512 for a disk and 2048 for a CD.
+    fn block_size(&self) -> usize;
+
+    fn block_count(&self) -> u64;
+
+    /// Read the blocks from `start` on into `buffer`, which is a whole
+    /// number of them long. `InvalidArgument` if that's past the end.
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> KernelResult<()>;
+
+    /// Write `bytes`, a whole number of blocks, from `start` on.
+    /// Read-only devices don't have to.
+    fn write_blocks(&self, _start: u64, _bytes: &[u8]) -> KernelResult<()> {
+        Err(KernelError::PermissionDenied)
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+}
+
+/// Read `buffer.len()` bytes at byte `offset` of `device`, whatever
+/// blocks they're in.
+pub fn read_bytes(device: &dyn BlockDevice, offset: u64, buffer: &mut [u8]) -> KernelResult<()> {
+    let block_size = device.block_size() as u64;
+    let mut block = Vec::new();
+    let mut done = 0;
+    while done < buffer.len() {
+        let position = offset + done as u64;
+        let within = (position % block_size) as usize;
+        let count = (block_size as usize - within).min(buffer.len() - done);
+        if within == 0 && count == block_size as usize {
+            // Whole blocks can go straight in.
+            let whole = (buffer.len() - done) / block_size as usize * block_size as usize;
+            device.read_blocks(position / block_size, &mut buffer[done..done + whole])?;
+            done += whole;
+            continue;
+        }
+        block.resize(block_size as usize, 0);
+        device.read_blocks(position / block_size, &mut block)?;
+        buffer[done..done + count].copy_from_slice(&block[within..within + count]);
+        done += count;
+    }
+    Ok(())
+}
+
+/// A device that's all in memory, writable.
+pub struct RamDisk {
+    block_size: usize,
+    bytes: Mutex<Vec<u8>>,
+}
+
+impl RamDisk {
+    /// One with `bytes` on it, padded with zeros to a whole number of
+    /// blocks.
+    pub fn new(block_size: usize, mut bytes: Vec<u8>) -> RamDisk {
+        let blocks = (bytes.len() + block_size - 1) / block_size;
+        bytes.resize(blocks * block_size, 0);
+        RamDisk {
+            block_size,
+            bytes: Mutex::new(bytes),
+        }
+    }
+
+    /// Where blocks from `start` on, `len` bytes of them, are.
+    fn range(&self, start: u64, len: usize) -> KernelResult<core::ops::Range<usize>> {
+        if len % self.block_size != 0 {
+            return Err(KernelError::InvalidArgument);
+        }
+        let from = (start as usize)
+            .checked_mul(self.block_size)
+            .ok_or(KernelError::InvalidArgument)?;
+        let to = from.checked_add(len).ok_or(KernelError::InvalidArgument)?;
+        if to > self.bytes.lock().len() {
+            return Err(KernelError::InvalidArgument);
+        }
+        Ok(from..to)
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.bytes.lock().len() / self.block_size) as u64
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> KernelResult<()> {
+        let range = self.range(start, buffer.len())?;
+        if fault_injection::should_fail(FaultPoint::DiskRead) {
+            return Err(KernelError::DeviceError);
+        }
+        buffer.copy_from_slice(&self.bytes.lock()[range]);
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, bytes: &[u8]) -> KernelResult<()> {
+        let range = self.range(start, bytes.len())?;
+        self.bytes.lock()[range].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn read_only(&self) -> bool {
+        false
+    }
+}
+
+/// Reads and writes that worked, and how many bytes they were.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IoStats {
+    pub reads: u64,
+    pub read_bytes: u64,
+    pub writes: u64,
+    pub written_bytes: u64,
+}
+
+struct Counters {
+    reads: AtomicU64,
+    read_bytes: AtomicU64,
+    writes: AtomicU64,
+    written_bytes: AtomicU64,
+}
+
+impl Counters {
+    const fn new() -> Counters {
+        Counters {
+            reads: AtomicU64::new(0),
+            read_bytes: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+            written_bytes: AtomicU64::new(0),
+        }
+    }
+
+    fn count_read(&self, bytes: usize) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.read_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn count_write(&self, bytes: usize) {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.written_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> IoStats {
+        IoStats {
+            reads: self.reads.load(Ordering::Relaxed),
+            read_bytes: self.read_bytes.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            written_bytes: self.written_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Every registered device's counts added up.
+static TOTAL: Counters = Counters::new();
+
+/// A registered device, what `device` hands out so everything going to
+/// it gets counted.
+struct Counted {
+    device: Arc<dyn BlockDevice>,
+    counters: Counters,
+}
+
+impl BlockDevice for Counted {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> KernelResult<()> {
+        self.device.read_blocks(start, buffer)?;
+        self.counters.count_read(buffer.len());
+        TOTAL.count_read(buffer.len());
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, bytes: &[u8]) -> KernelResult<()> {
+        self.device.write_blocks(start, bytes)?;
+        self.counters.count_write(bytes.len());
+        TOTAL.count_write(bytes.len());
+        Ok(())
+    }
+
+    fn read_only(&self) -> bool {
+        self.device.read_only()
+    }
+}
+
+/// In the order they were registered.
+static DEVICES: Mutex<Vec<(&'static str, Arc<Counted>)>> = Mutex::new(Vec::new());
+
+/// Add a device. `AlreadyExists` if there's one called `name` already.
+pub fn register(name: &'static str, device: Arc<dyn BlockDevice>) -> KernelResult<()> {
+    let mut devices = DEVICES.lock();
+    if devices.iter().any(|&(other, _)| other == name) {
+        return Err(KernelError::AlreadyExists);
+    }
+    let counters = Counters::new();
+    devices.push((name, Arc::new(Counted { device, counters })));
+    Ok(())
+}
+
+/// The device called `name`. `NotFound` if there isn't one.
+pub fn device(name: &str) -> KernelResult<Arc<dyn BlockDevice>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|&&(other, _)| other == name)
+        .map(|(_, device)| device.clone() as Arc<dyn BlockDevice>)
+        .ok_or(KernelError::NotFound)
+}
+
+/// Call `f` on each device and its counts, in the order they were
+/// added.
+pub fn for_each(mut f: impl FnMut(&str, &dyn BlockDevice, IoStats)) {
+    for (name, device) in DEVICES.lock().iter() {
+        f(name, &**device, device.counters.get());
+    }
+}
+
+/// Every device's counts added up, since boot.
+pub fn total_io() -> IoStats {
+    TOTAL.get()
+}
+
+/// Adds the `lsblk` shell command.
+pub fn init() {
+    shell::register("lsblk", "list block devices", lsblk_command).expect("lsblk command");
+}
+
+fn lsblk_command(out: &mut dyn fmt::Write, _args: &str) -> CommandResult {
+    const COLUMNS: [Column; 6] = [
+        Column::left("name", 8),
+        Column::right("block", 6),
+        Column::right("size", 12),
+        Column::left("mode", 4),
+        Column::right("read", 12),
+        Column::right("written", 12),
+    ];
+    let table = Table::new(&COLUMNS);
+    let _ = table.header(out);
+    for_each(|name, device, io| {
+        let size = device.block_count() * device.block_size() as u64;
+        let mode = if device.read_only() { "ro" } else { "rw" };
+        let _ = table.row(
+            out,
+            &[
+                &name,
+                &device.block_size(),
+                &size,
+                &mode,
+                &io.read_bytes,
+                &io.written_bytes,
+            ],
+        );
+    });
+    let _ = table.end(out);
+    Ok(())
+}
+
+#[test_case]
+fn test_read_bytes_across_blocks() {
+    let bytes: Vec<u8> = (0..=255).cycle().take(1000).collect();
+    let disk = RamDisk::new(128, bytes.clone());
+    assert_eq!(disk.block_count(), 8);
+
+    // The end of one block, three whole ones, and the start of another.
+    let mut buffer = [0; 500];
+    read_bytes(&disk, 100, &mut buffer).unwrap();
+    assert_eq!(&buffer[..], &bytes[100..600]);
+    assert_eq!(
+        read_bytes(&disk, 1000, &mut buffer),
+        Err(KernelError::InvalidArgument)
+    );
+
+    disk.write_blocks(1, &[7; 128]).unwrap();
+    read_bytes(&disk, 127, &mut buffer[..3]).unwrap();
+    assert_eq!(&buffer[..3], &[bytes[127], 7, 7]);
+
+    fault_injection::fail_nth(FaultPoint::DiskRead, 1);
+    assert_eq!(
+        read_bytes(&disk, 0, &mut buffer),
+        Err(KernelError::DeviceError)
+    );
+    read_bytes(&disk, 0, &mut buffer).unwrap();
+}
+
+#[test_case]
+fn test_counts_io() {
+    let before = total_io();
+    register("count0", Arc::new(RamDisk::new(512, alloc::vec![0; 2048]))).unwrap();
+    let disk = device("count0").unwrap();
+    let mut buffer = [0; 1024];
+    disk.read_blocks(1, &mut buffer).unwrap();
+    disk.write_blocks(0, &buffer[..512]).unwrap();
+    // Past the end, not counted.
+    assert!(disk.read_blocks(4, &mut buffer).is_err());
+
+    let mut counted = None;
+    for_each(|name, _, io| {
+        if name == "count0" {
+            counted = Some(io);
+        }
+    });
+    let expected = IoStats {
+        reads: 1,
+        read_bytes: 1024,
+        writes: 1,
+        written_bytes: 512,
+    };
+    assert_eq!(counted, Some(expected));
+    let after = total_io();
+    assert_eq!(after.reads - before.reads, 1);
+    assert_eq!(after.written_bytes - before.written_bytes, 512);
+    let mut devices = DEVICES.lock();
+    devices.retain(|&(name, _)| name != "count0");
+    devices.shrink_to_fit();
+}
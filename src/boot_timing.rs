@@ -0,0 +1,153 @@
+use crate::shell::{self, CommandResult};
+use core::arch::x86_64::_rdtsc; // Time stamp counter - cycles since reset
+use core::fmt;
+use spin::Mutex; // see Cargo.toml
+
+/// How many init stages we can keep track of. We have no heap
+/// so this has to be a fixed size array.
+pub const MAX_STAGES: usize = 32;
+
+/// A single init stage and the TSC value read when it finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stage {
+    pub name: &'static str,
+    pub tsc: u64,
+}
+
+/// All the stages recorded since `start` was called.
+///
+/// This is `Copy` so that callers can take a snapshot of it
+/// without holding on to the lock while they print.
+#[derive(Debug, Clone, Copy)]
+pub struct BootTimeline {
+    start: u64,
+    stages: [Option<Stage>; MAX_STAGES],
+    len: usize,
+}
+
+impl BootTimeline {
+    const fn new() -> BootTimeline {
+        BootTimeline {
+            start: 0,
+            stages: [None; MAX_STAGES],
+            len: 0,
+        }
+    }
+
+    /// TSC value at the point the timeline was started.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// The recorded stages in the order they finished.
+    pub fn stages(&self) -> impl Iterator<Item = &Stage> {
+        self.stages[..self.len].iter().flatten()
+    }
+
+    /// Cycles between the start and the last recorded stage.
+    pub fn total_cycles(&self) -> u64 {
+        self.stages()
+            .last()
+            .map_or(0, |stage| stage.tsc.saturating_sub(self.start))
+    }
+}
+
+static TIMELINE: Mutex<BootTimeline> = Mutex::new(BootTimeline::new());
+
+/// Read the time stamp counter.
+///
+/// The TSC is not serialising so the value can be off by a few
+/// instructions, but for boot stages that is plenty accurate.
+pub fn read_tsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Reset the timeline and take the reference TSC reading that all
+/// stages are measured against.
+pub fn start() {
+    let mut timeline = TIMELINE.lock();
+    *timeline = BootTimeline::new();
+    timeline.start = read_tsc();
+}
+
+/// Mark the end of an init stage. Stages past `MAX_STAGES` are
+/// silently dropped as we'd rather not panic during boot over it.
+pub fn record(name: &'static str) {
+    let tsc = read_tsc();
+    let mut timeline = TIMELINE.lock();
+    if timeline.len < MAX_STAGES {
+        let index = timeline.len;
+        timeline.stages[index] = Some(Stage { name, tsc });
+        timeline.len += 1;
+    }
+}
+
+/// Take a copy of the timeline recorded so far.
+pub fn timeline() -> BootTimeline {
+    *TIMELINE.lock()
+}
+
+/// Write how many cycles each stage took, along with the total.
+///
+/// We only have cycles as we don't know the TSC frequency at
+/// this point, but they are still good enough to spot regressions.
+pub fn write_summary(out: &mut dyn fmt::Write) -> fmt::Result {
+    let timeline = timeline();
+    let mut previous = timeline.start();
+
+    writeln!(out, "Boot timing (TSC cycles):")?;
+    for stage in timeline.stages() {
+        writeln!(
+            out,
+            "  {:<12} {:>14}",
+            stage.name,
+            stage.tsc.saturating_sub(previous)
+        )?;
+        previous = stage.tsc;
+    }
+    writeln!(out, "  {:<12} {:>14}", "total", timeline.total_cycles())
+}
+
+/// `write_summary` to the screen, once boot is done.
+pub fn print_summary() {
+    let _ = write_summary(&mut crate::ui::Console);
+}
+
+/// Adds the `boottime` shell command, which shows the summary again
+/// after it has scrolled away.
+pub fn init() {
+    shell::register(
+        "boottime",
+        "how long each boot stage took",
+        boottime_command,
+    )
+    .expect("boottime command");
+}
+
+fn boottime_command(out: &mut dyn fmt::Write, _args: &str) -> CommandResult {
+    let _ = write_summary(out);
+    Ok(())
+}
+
+#[test_case]
+fn test_tsc_is_monotonic() {
+    let first = read_tsc();
+    let second = read_tsc();
+    assert!(second >= first);
+}
+
+#[test_case]
+fn test_record_stages_in_order() {
+    start();
+    record("first");
+    record("second");
+
+    let timeline = timeline();
+    let mut stages = timeline.stages();
+    let first = stages.next().unwrap();
+    let second = stages.next().unwrap();
+    assert_eq!(first.name, "first");
+    assert_eq!(second.name, "second");
+    assert!(second.tsc >= first.tsc);
+    assert!(stages.next().is_none());
+}
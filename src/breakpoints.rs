@@ -0,0 +1,274 @@
+//! Software breakpoints that drop into the kernel monitor.
+//!
+//! Setting one swaps the first byte of the instruction at the address
+//! for `int3` (0xCC) and keeps the original. When it's hit, the
+//! breakpoint handler puts the original byte back, rewinds `rip` onto
+//! it and enters the monitor. Continuing from there runs that one
+//! instruction with the trap flag set, and the debug exception that
+//! follows puts the `int3` back in and clears the flag again.
+//!
+//! A breakpoint can also start `step_trace` instead of the monitor.
+//!
+//! Kernel code is mapped read-only, so patching it turns off CR0's
+//! write protection for a moment.
+use crate::error::{KernelError, KernelResult};
+use crate::ksyms;
+use crate::latency;
+use crate::memory;
+use crate::monitor;
+use crate::shell::{self, CommandFailed, CommandResult};
+use crate::step_trace;
+use core::fmt::{self, Write};
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+pub const MAX_BREAKPOINTS: usize = 8;
+
+const INT3: u8 = 0xCC;
+const TRAP_FLAG: u64 = 1 << 8;
+
+/// What happens when a breakpoint is hit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Monitor,
+    /// Trace the function it's on, see `step_trace`.
+    StepTrace,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub address: u64,
+    /// The byte the `int3` replaced.
+    original: u8,
+    pub hits: u64,
+    pub action: Action,
+}
+
+static BREAKPOINTS: Mutex<[Option<Breakpoint>; MAX_BREAKPOINTS]> =
+    Mutex::new([None; MAX_BREAKPOINTS]);
+/// Breakpoint being stepped over, to put back on the next debug
+/// exception. 0 for none.
+static STEPPING_OVER: AtomicU64 = AtomicU64::new(0);
+
+/// Adds the `bp` shell command.
+pub fn init() {
+    shell::register(
+        "bp",
+        "bp [ADDR|-d ADDR]: list, set or delete breakpoints",
+        bp_command,
+    )
+    .expect("bp command");
+}
+
+/// Write `byte` at `address`, read-only or not.
+///
+/// # Safety
+///
+/// `address` has to be a mapped instruction we're allowed to change.
+unsafe fn patch(address: u64, byte: u8) {
+    latency::without_interrupts(|| {
+        let cr0 = Cr0::read();
+        Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+        ptr::write_volatile(address as *mut u8, byte);
+        Cr0::write(cr0);
+    });
+}
+
+/// Break into the monitor at `address`.
+///
+/// # Safety
+///
+/// `address` has to be the first byte of an instruction, or whatever
+/// is there gets mangled.
+pub unsafe fn insert(address: VirtAddr) -> KernelResult<()> {
+    insert_with(address, Action::Monitor)
+}
+
+/// Like `insert`, but do `action` when it's hit.
+///
+/// # Safety
+///
+/// Same as `insert`.
+pub unsafe fn insert_with(address: VirtAddr, action: Action) -> KernelResult<()> {
+    memory::check_range(address, 1, false)?;
+    latency::without_interrupts(|| {
+        let mut breakpoints = BREAKPOINTS.lock();
+        if breakpoints
+            .iter()
+            .flatten()
+            .any(|breakpoint| breakpoint.address == address.as_u64())
+        {
+            return Err(KernelError::AlreadyExists);
+        }
+        let slot = breakpoints
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(KernelError::NoSpace)?;
+        let original = ptr::read_volatile(address.as_ptr::<u8>());
+        patch(address.as_u64(), INT3);
+        *slot = Some(Breakpoint {
+            address: address.as_u64(),
+            original,
+            hits: 0,
+            action,
+        });
+        Ok(())
+    })
+}
+
+/// Take the breakpoint at `address` out again.
+pub fn remove(address: VirtAddr) -> KernelResult<()> {
+    latency::without_interrupts(|| {
+        let mut breakpoints = BREAKPOINTS.lock();
+        let slot = breakpoints
+            .iter_mut()
+            .find(|slot| slot.map_or(false, |breakpoint| breakpoint.address == address.as_u64()))
+            .ok_or(KernelError::NotFound)?;
+        if let Some(breakpoint) = slot.take() {
+            // Already out if we're stepping over it.
+            if STEPPING_OVER.load(Ordering::SeqCst) != breakpoint.address {
+                unsafe { patch(breakpoint.address, breakpoint.original) };
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Call `f` with every breakpoint.
+pub fn for_each(mut f: impl FnMut(&Breakpoint)) {
+    if let Some(breakpoints) = BREAKPOINTS.try_lock() {
+        breakpoints
+            .iter()
+            .flatten()
+            .for_each(|breakpoint| f(breakpoint));
+    }
+}
+
+/// Called by the breakpoint handler. Returns false if the `int3` isn't
+/// one of ours, e.g. one compiled in.
+pub fn hit(stack_frame: &mut InterruptStackFrame) -> bool {
+    // `rip` is already past the `int3`.
+    let address = stack_frame.instruction_pointer.as_u64() - 1;
+    let breakpoint = match BREAKPOINTS.try_lock() {
+        Some(mut breakpoints) => {
+            match breakpoints
+                .iter_mut()
+                .flatten()
+                .find(|breakpoint| breakpoint.address == address)
+            {
+                Some(breakpoint) => {
+                    breakpoint.hits += 1;
+                    *breakpoint
+                }
+                None => return false,
+            }
+        }
+        None => return false,
+    };
+
+    unsafe {
+        patch(address, breakpoint.original);
+        let frame = stack_frame.as_mut();
+        frame.instruction_pointer = VirtAddr::new(address);
+        frame.cpu_flags |= TRAP_FLAG;
+    }
+    STEPPING_OVER.store(address, Ordering::SeqCst);
+    if breakpoint.action == Action::StepTrace {
+        step_trace::start(stack_frame);
+        return true;
+    }
+
+    let mut out = crate::serial::RawSerial::new();
+    let _ = write!(out, "\nbreakpoint at {:#x}", address);
+    if let Some((name, offset)) = ksyms::lookup(address) {
+        let _ = write!(out, " <{}+{:#x}>", ksyms::Demangle(name), offset);
+    }
+    let _ = writeln!(out, ", hit {} times", breakpoint.hits);
+    monitor::enter(stack_frame);
+    true
+}
+
+/// Called by the debug exception handler. Returns false if we weren't
+/// stepping over a breakpoint.
+pub fn single_step(stack_frame: &mut InterruptStackFrame) -> bool {
+    let address = STEPPING_OVER.swap(0, Ordering::SeqCst);
+    if address == 0 {
+        return false;
+    }
+    // Unless it was removed from the monitor in the meantime.
+    let still_set = BREAKPOINTS.try_lock().map_or(false, |breakpoints| {
+        breakpoints
+            .iter()
+            .flatten()
+            .any(|breakpoint| breakpoint.address == address)
+    });
+    unsafe {
+        if still_set {
+            patch(address, INT3);
+        }
+        stack_frame.as_mut().cpu_flags &= !TRAP_FLAG;
+    }
+    true
+}
+
+fn bp_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    let mut words = args.split_whitespace();
+    let (delete, address) = match (words.next(), words.next()) {
+        (None, _) => {
+            for_each(|breakpoint| {
+                let _ = write!(out, "{:#018x}", breakpoint.address);
+                if breakpoint.action == Action::StepTrace {
+                    let _ = write!(out, " (steptrace)");
+                }
+                if let Some((name, offset)) = ksyms::lookup(breakpoint.address) {
+                    let _ = write!(out, " <{}+{:#x}>", ksyms::Demangle(name), offset);
+                }
+                let _ = writeln!(out, "  {} hits", breakpoint.hits);
+            });
+            return Ok(());
+        }
+        (Some("-d"), Some(address)) => (true, ksyms::parse_address(address)),
+        (Some(address), None) => (false, ksyms::parse_address(address)),
+        _ => (false, None),
+    };
+    let address = match address.and_then(|address| VirtAddr::try_new(address).ok()) {
+        Some(address) => address,
+        None => {
+            let _ = writeln!(out, "usage: bp [ADDR|-d ADDR]");
+            return Err(CommandFailed);
+        }
+    };
+    let result = if delete {
+        remove(address)
+    } else {
+        unsafe { insert(address) }
+    };
+    result.map_err(|error| {
+        let _ = writeln!(out, "{:#x}: {}", address.as_u64(), error);
+        CommandFailed
+    })
+}
+
+#[test_case]
+fn test_insert_and_remove() {
+    #[inline(never)]
+    fn target() -> u64 {
+        42
+    }
+    let address = VirtAddr::new(target as usize as u64);
+    let original = unsafe { ptr::read_volatile(address.as_ptr::<u8>()) };
+
+    assert_eq!(unsafe { insert(address) }, Ok(()));
+    assert_eq!(unsafe { ptr::read_volatile(address.as_ptr::<u8>()) }, INT3);
+    assert_eq!(unsafe { insert(address) }, Err(KernelError::AlreadyExists));
+    assert_eq!(remove(address), Ok(()));
+    assert_eq!(
+        unsafe { ptr::read_volatile(address.as_ptr::<u8>()) },
+        original
+    );
+    assert_eq!(target(), 42);
+}
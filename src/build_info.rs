@@ -0,0 +1,53 @@
+//! What this kernel was built from, filled in by `build.rs`.
+//!
+//! The banner goes out first thing on boot, so a log pasted into a bug
+//! report says which build it came from.
+use crate::shell::{self, CommandResult};
+use core::fmt;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Short hash of `HEAD`, `-dirty` if there were changes on top of it.
+pub const GIT_COMMIT: &str = env!("BUILD_GIT_COMMIT");
+pub const RUSTC_VERSION: &str = env!("BUILD_RUSTC_VERSION");
+/// `debug` or `release`.
+pub const PROFILE: &str = env!("BUILD_PROFILE");
+/// Cargo features that were on, comma separated.
+pub const FEATURES: &str = env!("BUILD_FEATURES");
+
+/// Adds the `version` shell command.
+pub fn init() {
+    shell::register(
+        "version",
+        "what this kernel was built from",
+        version_command,
+    )
+    .expect("version command");
+}
+
+/// Formats as the one line boot banner.
+pub struct Banner;
+
+impl fmt::Display for Banner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "blog_os {} ({}, {} build, {}",
+            VERSION, GIT_COMMIT, PROFILE, RUSTC_VERSION
+        )?;
+        if !FEATURES.is_empty() {
+            write!(f, ", features {}", FEATURES)?;
+        }
+        write!(f, ")")
+    }
+}
+
+fn version_command(out: &mut dyn fmt::Write, _args: &str) -> CommandResult {
+    let _ = writeln!(out, "{}", Banner);
+    Ok(())
+}
+
+#[test_case]
+fn test_banner_has_build() {
+    assert!(!GIT_COMMIT.is_empty());
+    assert!(PROFILE == "debug" || PROFILE == "release");
+}
@@ -0,0 +1,164 @@
+//! A text clipboard, for getting things off the screen and typed
+//! back in.
+//!
+//! `select` picks a range of rows, `copy` takes what's on them, with
+//! the blanks at the end of each row left off, and `paste` types it
+//! into the console's input: every `keyboard::Mode::Chars` subscriber
+//! gets it as if it had been typed. The last line doesn't get a
+//! newline, so pasting half a command doesn't run it.
+//!
+//! On the keyboard, Ctrl-Insert copies and Shift-Insert pastes.
+//!
+//! There's only the one console so far, so this copies from it and
+//! pastes into it. With virtual consoles the selection would be on
+//! whichever one is shown and the paste would go to the one switched
+//! to after.
+use crate::error::{KernelError, KernelResult};
+use crate::keyboard;
+use crate::latency;
+use crate::shell::{self, CommandFailed, CommandResult};
+use crate::vga_buffer::{BUFFER_HEIGHT, BUFFER_WIDTH, WRITER};
+use core::fmt;
+use spin::Mutex;
+
+/// The whole screen, with a newline after every row.
+pub const MAX_LENGTH: usize = BUFFER_HEIGHT * (BUFFER_WIDTH + 1);
+
+struct Clipboard {
+    /// First and last row to copy.
+    selection: (usize, usize),
+    bytes: [u8; MAX_LENGTH],
+    len: usize,
+}
+
+static CLIPBOARD: Mutex<Clipboard> = Mutex::new(Clipboard {
+    // The row the cursor is on.
+    selection: (BUFFER_HEIGHT - 1, BUFFER_HEIGHT - 1),
+    bytes: [0; MAX_LENGTH],
+    len: 0,
+});
+
+/// Adds the `clip` shell command.
+pub fn init() {
+    shell::register(
+        "clip",
+        "clip [select FIRST LAST|copy|paste]: the text clipboard",
+        clip_command,
+    )
+    .expect("clip command");
+}
+
+/// Copy rows `first` to `last` next time, 0 being the top one.
+pub fn select(first: usize, last: usize) -> KernelResult<()> {
+    if first > last || last >= BUFFER_HEIGHT {
+        return Err(KernelError::InvalidArgument);
+    }
+    latency::without_interrupts(|| CLIPBOARD.lock().selection = (first, last));
+    Ok(())
+}
+
+/// Replace what's on the clipboard with the selected rows.
+/// `WouldBlock` if something is printing, the keyboard interrupt
+/// could have come in the middle of it.
+pub fn copy() -> KernelResult<()> {
+    latency::without_interrupts(|| {
+        let mut clipboard = CLIPBOARD.lock();
+        let (first, last) = clipboard.selection;
+        let screen = WRITER.try_lock().ok_or(KernelError::WouldBlock)?;
+        let mut len = 0;
+        for row in first..=last {
+            let bytes = screen.read_row(row);
+            let end = bytes
+                .iter()
+                .rposition(|&byte| byte != b' ')
+                .map_or(0, |i| i + 1);
+            for &byte in &bytes[..end] {
+                // The VGA placeholder for anything it can't show isn't
+                // ASCII.
+                clipboard.bytes[len] = if byte.is_ascii() { byte } else { b'?' };
+                len += 1;
+            }
+            if row != last {
+                clipboard.bytes[len] = b'\n';
+                len += 1;
+            }
+        }
+        clipboard.len = len;
+        Ok(())
+    })
+}
+
+/// Type what's on the clipboard into the console.
+pub fn paste() {
+    latency::without_interrupts(|| {
+        let clipboard = CLIPBOARD.lock();
+        for &byte in &clipboard.bytes[..clipboard.len] {
+            keyboard::type_char(char::from(byte));
+        }
+    });
+}
+
+/// Call `f` with what's on the clipboard.
+pub fn with_contents<R>(f: impl FnOnce(&str) -> R) -> R {
+    latency::without_interrupts(|| {
+        let clipboard = CLIPBOARD.lock();
+        // `copy` only puts ASCII there.
+        let text = core::str::from_utf8(&clipboard.bytes[..clipboard.len]).unwrap_or("");
+        f(text)
+    })
+}
+
+fn clip_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    let mut words = args.split_whitespace();
+    match words.next() {
+        None => with_contents(|text| {
+            let _ = writeln!(out, "{}", text);
+        }),
+        Some("select") => {
+            let first = words.next().and_then(|word| word.parse().ok());
+            let last = words.next().and_then(|word| word.parse().ok());
+            let result = match (first, last) {
+                (Some(first), Some(last)) => select(first, last),
+                _ => return usage(out),
+            };
+            return result.map_err(|error| {
+                let _ = writeln!(out, "clip: {}", error);
+                CommandFailed
+            });
+        }
+        Some("copy") => {
+            return copy().map_err(|error| {
+                let _ = writeln!(out, "clip: {}", error);
+                CommandFailed
+            })
+        }
+        Some("paste") => paste(),
+        Some(_) => return usage(out),
+    }
+    Ok(())
+}
+
+fn usage(out: &mut dyn fmt::Write) -> CommandResult {
+    let _ = writeln!(out, "usage: clip [select FIRST LAST|copy|paste]");
+    Err(CommandFailed)
+}
+
+#[test_case]
+fn test_copy_and_paste() {
+    use crate::println;
+    use keyboard::Mode;
+
+    println!("clipboard test  ");
+    // The newline moved the text up one.
+    select(BUFFER_HEIGHT - 2, BUFFER_HEIGHT - 2).unwrap();
+    copy().unwrap();
+    with_contents(|text| assert_eq!(text, "clipboard test"));
+
+    let input = keyboard::subscribe(Mode::Chars).unwrap();
+    paste();
+    for expected in "clipboard test".chars() {
+        assert_eq!(input.next_char(), Some(expected));
+    }
+    assert_eq!(input.next_char(), None);
+    assert_eq!(select(3, 2), Err(KernelError::InvalidArgument));
+}
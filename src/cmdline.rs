@@ -0,0 +1,163 @@
+//! The kernel command line.
+//!
+//! The bootloader doesn't hand us one, so it comes from QEMU's
+//! firmware config device instead, as the file `opt/blog_os/cmdline`:
+//!
+//! ```text
+//! qemu-system-x86_64 ... -fw_cfg name=opt/blog_os/cmdline,string="test=vga shard=0/4"
+//! ```
+//!
+//! It's words separated by spaces, `key=value` or just `key`. On
+//! anything that isn't QEMU there's no such device and the command
+//! line is empty. Booted with `-kernel`, see `pvh`, `-append` works
+//! too.
+use spin::Once;
+use x86_64::instructions::port::Port;
+
+/// Longer command lines get cut.
+pub const MAX_LENGTH: usize = 256;
+
+const FW_CFG_SELECTOR: u16 = 0x510;
+const FW_CFG_DATA: u16 = 0x511;
+const FW_CFG_SIGNATURE: u16 = 0x0000;
+const FW_CFG_FILE_DIR: u16 = 0x0019;
+const FILE_NAME: &[u8] = b"opt/blog_os/cmdline";
+
+struct CommandLine {
+    bytes: [u8; MAX_LENGTH],
+    len: usize,
+}
+
+static COMMAND_LINE: Once<CommandLine> = Once::new();
+
+struct FwCfg {
+    selector: Port<u16>,
+    data: Port<u8>,
+}
+
+impl FwCfg {
+    fn select(&mut self, key: u16) {
+        unsafe { self.selector.write(key) };
+    }
+
+    fn read(&mut self, bytes: &mut [u8]) {
+        for byte in bytes {
+            *byte = unsafe { self.data.read() };
+        }
+    }
+
+    /// Everything in the device is big endian.
+    fn read_u32(&mut self) -> u32 {
+        let mut bytes = [0; 4];
+        self.read(&mut bytes);
+        u32::from_be_bytes(bytes)
+    }
+
+    fn read_u16(&mut self) -> u16 {
+        let mut bytes = [0; 2];
+        self.read(&mut bytes);
+        u16::from_be_bytes(bytes)
+    }
+
+    /// The device, if it's there.
+    fn open() -> Option<FwCfg> {
+        let mut fw_cfg = FwCfg {
+            selector: Port::new(FW_CFG_SELECTOR),
+            data: Port::new(FW_CFG_DATA),
+        };
+        let mut signature = [0; 4];
+        fw_cfg.select(FW_CFG_SIGNATURE);
+        fw_cfg.read(&mut signature);
+        if &signature == b"QEMU" {
+            Some(fw_cfg)
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether QEMU's firmware config device is there, which nothing but
+/// QEMU has.
+pub fn has_fw_cfg() -> bool {
+    FwCfg::open().is_some()
+}
+
+fn read_from_qemu() -> CommandLine {
+    let mut line = CommandLine {
+        bytes: [0; MAX_LENGTH],
+        len: 0,
+    };
+    let mut fw_cfg = match FwCfg::open() {
+        Some(fw_cfg) => fw_cfg,
+        None => return line,
+    };
+    fw_cfg.select(FW_CFG_FILE_DIR);
+    for _ in 0..fw_cfg.read_u32() {
+        let size = fw_cfg.read_u32() as usize;
+        let key = fw_cfg.read_u16();
+        let _reserved = fw_cfg.read_u16();
+        let mut name = [0; 56];
+        fw_cfg.read(&mut name);
+        if name.starts_with(FILE_NAME) && name[FILE_NAME.len()] == 0 {
+            line.len = size.min(MAX_LENGTH);
+            fw_cfg.select(key);
+            fw_cfg.read(&mut line.bytes[..line.len]);
+            break;
+        }
+    }
+    // `string=` doesn't add a NUL, a file from the host might.
+    while line.len > 0 && matches!(line.bytes[line.len - 1], 0 | b'\n') {
+        line.len -= 1;
+    }
+    line
+}
+
+/// Use `line` instead of asking QEMU, like `pvh` does with what it
+/// was booted with. Only before anything has read it, and only if
+/// there's something in it.
+pub(crate) fn set(line: &[u8]) {
+    if line.is_empty() {
+        return;
+    }
+    COMMAND_LINE.call_once(|| {
+        let mut copy = CommandLine {
+            bytes: [0; MAX_LENGTH],
+            len: line.len().min(MAX_LENGTH),
+        };
+        copy.bytes[..copy.len].copy_from_slice(&line[..copy.len]);
+        copy
+    });
+}
+
+/// The whole command line.
+pub fn get() -> &'static str {
+    let line = COMMAND_LINE.call_once(read_from_qemu);
+    core::str::from_utf8(&line.bytes[..line.len]).unwrap_or("")
+}
+
+/// What `key` is set to in `line`: `Some("")` for a bare `key`.
+pub fn value_in<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    line.split_whitespace().find_map(|word| {
+        let mut parts = word.splitn(2, '=');
+        if parts.next() != Some(key) {
+            return None;
+        }
+        Some(parts.next().unwrap_or(""))
+    })
+}
+
+/// What `key` is set to on the command line.
+pub fn value(key: &str) -> Option<&'static str> {
+    value_in(get(), key)
+}
+
+#[test_case]
+fn test_values() {
+    let line = "test=vga  shard=1/4 quiet";
+    assert_eq!(value_in(line, "test"), Some("vga"));
+    assert_eq!(value_in(line, "shard"), Some("1/4"));
+    assert_eq!(value_in(line, "quiet"), Some(""));
+    assert_eq!(value_in(line, "te"), None);
+    // Reading it twice has to give the same thing.
+    assert_eq!(get(), get());
+}
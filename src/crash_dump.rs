@@ -0,0 +1,164 @@
+//! A crash dump a program on the other end of the serial line can pick
+//! out of the log and check.
+//!
+//! The dump is plain text, so it's still readable by eye:
+//!
+//! ```text
+//! === crash dump v2 ===
+//! @panic
+//! panicked at 'oh no', src/main.rs:12:5
+//! @registers
+//! ...
+//! === end of crash dump, 1234 bytes, crc32 89abcdef ===
+//! ```
+//!
+//! Every section starts with an `@name` line. The byte count and CRC
+//! (the usual zlib one) cover everything after the first line up to
+//! the start of the last one, so the reader can tell a dump that got
+//! cut off or garbled from a good one.
+//!
+//! A dump goes to serial: the panic handler writes one there when the
+//! panic policy is `dump`, `crashdump` to wherever the shell's output
+//! goes, and `sz crashdump` sends one as a file over YMODEM. Nothing
+//! keeps one on disk, a panic is no time to trust the block layer.
+use crate::kassert;
+use crate::scheduler::{self, State};
+use crate::shell::{self, CommandResult};
+use crate::stats;
+use crate::time::{self, Duration};
+use crate::trace;
+use crate::unwind::Backtrace;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Adds the `crashdump` shell command.
+pub fn init() {
+    shell::register(
+        "crashdump",
+        "write a crash dump of the running system",
+        crashdump_command,
+    )
+    .expect("crashdump command");
+}
+
+/// Passes everything on to `out`, keeping count and a CRC on the way.
+struct Framed<'a> {
+    out: &'a mut dyn Write,
+    crc: u32,
+    bytes: usize,
+}
+
+impl Write for Framed<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.crc = crc32(self.crc, s.as_bytes());
+        self.bytes += s.len();
+        self.out.write_str(s)
+    }
+}
+
+/// Continue a CRC-32 over `bytes`, start with 0. Bit by bit, as a
+/// table is 1K of memory and this only runs once we're going down.
+pub fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Write the whole dump to `out`. `info` is the panic, if there is one.
+///
+/// Nothing here waits on a lock, whatever is busy gets skipped.
+pub fn write(out: &mut dyn Write, info: Option<&PanicInfo>, backtrace: &Backtrace) -> fmt::Result {
+    writeln!(out, "=== crash dump v{} ===", FORMAT_VERSION)?;
+    let mut framed = Framed {
+        out,
+        crc: 0,
+        bytes: 0,
+    };
+    write_sections(&mut framed, info, backtrace)?;
+    let (bytes, crc) = (framed.bytes, framed.crc);
+    writeln!(
+        out,
+        "=== end of crash dump, {} bytes, crc32 {:08x} ===",
+        bytes, crc
+    )
+}
+
+fn write_sections(
+    out: &mut dyn Write,
+    info: Option<&PanicInfo>,
+    backtrace: &Backtrace,
+) -> fmt::Result {
+    writeln!(out, "@panic")?;
+    match info {
+        Some(info) => writeln!(out, "{}", info)?,
+        None => writeln!(out, "(none, dumped on request)")?,
+    }
+    writeln!(out, "@uptime")?;
+    writeln!(
+        out,
+        "{} ({} ms)",
+        Duration(time::uptime_ms()),
+        time::uptime_ms()
+    )?;
+    writeln!(out, "@registers")?;
+    kassert::write_state(out)?;
+    writeln!(out, "@backtrace")?;
+    write!(out, "{}", backtrace)?;
+    writeln!(out, "@stats")?;
+    write!(out, "{}", stats::Snapshot::take())?;
+    writeln!(out, "@tasks")?;
+    let mut result = Ok(());
+    let listed = scheduler::for_each_thread(|thread| {
+        result = result.and_then(|()| {
+            write!(
+                out,
+                "{} {} {}",
+                thread.id,
+                thread.name,
+                thread.state.as_str()
+            )?;
+            if thread.state == State::Running {
+                write!(out, " cpu {}", trace::current_cpu())?;
+            }
+            writeln!(out)
+        });
+    });
+    result?;
+    if listed.is_err() {
+        writeln!(out, "(scheduler locked)")?;
+    }
+    // The trace ring is the closest thing to a kernel log we have.
+    writeln!(out, "@log")?;
+    trace::dump(out)
+}
+
+fn crashdump_command(out: &mut dyn fmt::Write, _args: &str) -> CommandResult {
+    let _ = write(out, None, &Backtrace::capture());
+    Ok(())
+}
+
+#[test_case]
+fn test_crc32_and_framing() {
+    assert_eq!(crc32(0, b"123456789"), 0xCBF4_3926);
+
+    // Split over several writes it has to come out the same.
+    let mut framed = Framed {
+        out: &mut alloc::string::String::new(),
+        crc: 0,
+        bytes: 0,
+    };
+    let _ = framed.write_str("1234");
+    let _ = framed.write_str("56789");
+    assert_eq!((framed.bytes, framed.crc), (9, 0xCBF4_3926));
+}
@@ -0,0 +1,80 @@
+//! The error type shared by the whole kernel.
+//!
+//! Subsystems are free to have their own error types with more detail
+//! (see `memory::AccessError`), as long as those convert into a
+//! `KernelError` so callers a few layers up can just use `?`. Panics
+//! are for bugs, anything the hardware or a caller can cause should
+//! come back as one of these instead.
+use core::fmt;
+
+pub type KernelResult<T> = Result<T, KernelError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelError {
+    /// No memory left to satisfy the request.
+    OutOfMemory,
+    /// The address isn't mapped, or isn't the kind of address expected.
+    InvalidAddress,
+    /// Not allowed, e.g. writing to a read-only page.
+    PermissionDenied,
+    /// The hardware answered with something we didn't expect.
+    DeviceError,
+    /// The hardware didn't answer in time.
+    Timeout,
+    NotFound,
+    AlreadyExists,
+    /// A fixed size table is full.
+    NoSpace,
+    /// Can't be done right now without waiting, try again later.
+    WouldBlock,
+    /// Still in use, e.g. a file system with another mounted in it.
+    Busy,
+    /// What's needed for this hasn't been initialised yet.
+    NotReady,
+    InvalidArgument,
+    /// Malformed data, e.g. a file in the wrong format.
+    InvalidData,
+    /// This machine or this kernel doesn't support it.
+    Unsupported,
+}
+
+impl KernelError {
+    /// A short description, for logs and the shell.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KernelError::OutOfMemory => "out of memory",
+            KernelError::InvalidAddress => "invalid address",
+            KernelError::PermissionDenied => "permission denied",
+            KernelError::DeviceError => "device error",
+            KernelError::Timeout => "timed out",
+            KernelError::NotFound => "not found",
+            KernelError::AlreadyExists => "already exists",
+            KernelError::NoSpace => "no space left",
+            KernelError::WouldBlock => "would block",
+            KernelError::Busy => "busy",
+            KernelError::NotReady => "not initialised yet",
+            KernelError::InvalidArgument => "invalid argument",
+            KernelError::InvalidData => "invalid data",
+            KernelError::Unsupported => "not supported",
+        }
+    }
+}
+
+impl fmt::Display for KernelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[test_case]
+fn test_converts_from_subsystem_errors() {
+    use crate::memory::AccessError;
+    use x86_64::VirtAddr;
+
+    let unmapped = AccessError::Unmapped(VirtAddr::new(0x1000));
+    assert_eq!(KernelError::from(unmapped), KernelError::InvalidAddress);
+    assert_eq!(
+        KernelError::from(crate::shell::RegisterError::Full),
+        KernelError::NoSpace
+    );
+}
@@ -0,0 +1,104 @@
+//! Deterministic fault injection for exercising error paths in tests.
+//!
+//! Code that can fail asks `should_fail` at the point where it would
+//! fail for real, and bails out the same way it would if the hardware
+//! (or the heap) had said no. A test picks which call fails with
+//! `fail_nth`, so the same failure happens on every run:
+//!
+//! ```ignore
+//! fault_injection::fail_nth(FaultPoint::HeapAlloc, 3);
+//! // the third allocation from here on returns null
+//! ```
+//!
+//! The test runner turns everything off again after each test.
+//!
+//! The allocator asks for `HeapAlloc`, `RamDisk` and the ATA driver
+//! for `DiskRead` before reading, and `net::count_rx` for
+//! `NetworkPacket` on every packet a driver receives.
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Places where a fault can be injected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultPoint {
+    /// `TrackingAllocator::alloc` returns null.
+    HeapAlloc,
+    /// A received network packet is dropped.
+    NetworkPacket,
+    /// A disk read returns an error.
+    DiskRead,
+}
+
+impl FaultPoint {
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+const FAULT_POINTS: usize = 3;
+
+/// Calls left until the next injected fault, 0 when off.
+static COUNTDOWN: [AtomicU64; FAULT_POINTS] =
+    [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+/// How many faults were injected since the last `reset`.
+static INJECTED: [AtomicU64; FAULT_POINTS] =
+    [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+/// Make the `n`th call to `should_fail(point)` from now on fail, with
+/// `n` starting at 1. Only that one call fails, after it the point is
+/// off again. `n == 0` turns the point off.
+pub fn fail_nth(point: FaultPoint, n: u64) {
+    COUNTDOWN[point.index()].store(n, Ordering::SeqCst);
+}
+
+/// Asked by the code at `point` before doing the real work. Returns
+/// `true` if it should pretend it failed.
+pub fn should_fail(point: FaultPoint) -> bool {
+    let countdown = &COUNTDOWN[point.index()];
+    // A plain load first, so the usual case of nothing being armed
+    // stays cheap for hot paths like the allocator.
+    if countdown.load(Ordering::Relaxed) == 0 {
+        return false;
+    }
+    let previous = countdown.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+        if left == 0 {
+            None
+        } else {
+            Some(left - 1)
+        }
+    });
+    if previous == Ok(1) {
+        INJECTED[point.index()].fetch_add(1, Ordering::SeqCst);
+        true
+    } else {
+        false
+    }
+}
+
+/// Number of faults injected at `point` since the last `reset`.
+pub fn injected(point: FaultPoint) -> u64 {
+    INJECTED[point.index()].load(Ordering::SeqCst)
+}
+
+/// Turn every point off and forget what was injected. The test runner
+/// calls this after each test so one test's faults don't leak into
+/// the next.
+pub fn reset() {
+    for (countdown, injected) in COUNTDOWN.iter().zip(INJECTED.iter()) {
+        countdown.store(0, Ordering::SeqCst);
+        injected.store(0, Ordering::SeqCst);
+    }
+}
+
+#[test_case]
+fn test_fail_nth() {
+    fail_nth(FaultPoint::DiskRead, 3);
+    assert!(!should_fail(FaultPoint::DiskRead));
+    assert!(!should_fail(FaultPoint::NetworkPacket));
+    assert!(!should_fail(FaultPoint::DiskRead));
+    assert!(should_fail(FaultPoint::DiskRead));
+    assert!(!should_fail(FaultPoint::DiskRead));
+    assert_eq!(injected(FaultPoint::DiskRead), 1);
+    assert_eq!(injected(FaultPoint::NetworkPacket), 0);
+    reset();
+    assert_eq!(injected(FaultPoint::DiskRead), 0);
+}
@@ -0,0 +1,841 @@
+//! Files, and the one tree they're all in.
+//!
+//! A file system implements `FileSystem`, which is handed paths from its
+//! own root: `a/b`, without the leading `/`, and `` for the root itself.
+//! The tree is file systems mounted on each other. `mount` puts one on
+//! a directory of the tree there is so far, and going to a path finds
+//! the mount with the longest point that's a prefix of it, then asks
+//! that file system for the rest. A read-only mount refuses writes
+//! whatever its file system would do.
+//!
+//! `stat` says what a path is, `read_dir` lists a directory, and
+//! `mkdir`, `unlink`, `rmdir` and `rename` change the tree. None of
+//! them goes through a mount point: it can't be removed or renamed
+//! while something's mounted on it, and renaming from one file system
+//! to another is a copy, which is up to the caller. `syscall` has them
+//! too, for user programs.
+//!
+//! Reads and writes go through `cache`, the page cache, unless the file
+//! system says not to. What's written there stays there until it's
+//! written back: `sync` does all of it, and unmounting, renaming or
+//! removing does what's on the way.
+//!
+//! `fsck` checks a file system's tree, from tests or the shell.
+//!
+//! `mmap` maps files into user memory, a page at a time out of the
+//! page cache as they're touched.
+//!
+//! `/` is a `ramfs`, mounted as soon as there's a heap. `iso9660` reads
+//! CDs and images of them, from a `block` device. The kernel doesn't
+//! boot from one itself, that's still GRUB or the bootloader, but it
+//! can mount one once it's up. The shell has `mount` to list the table
+//! and mount another ramfs or a CD, `umount`, and `ls`, `stat`, `cat`,
+//! `mkdir`, `rm`, `mv`, `sync` and `fsck`, and its `run` reads scripts
+//! from here. Boot runs `/init.rc` with `run_init_script` once `/` is
+//! mounted, if there is one.
+use crate::block;
+use crate::error::{KernelError, KernelResult};
+use crate::shell::{self, CommandFailed, CommandResult};
+use crate::time;
+use crate::ui::{Column, Table};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::{self, Vec};
+use core::fmt;
+use spin::Mutex;
+
+pub mod cache;
+pub mod fsck;
+pub mod iso9660;
+pub mod mmap;
+pub mod ramfs;
+
+pub const MAX_MOUNTS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Directory,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: Kind,
+    /// Which file it is on its file system, the same one whatever it's
+    /// renamed to. 0 if the file system doesn't have them.
+    pub inode: u64,
+    /// Bytes for a file, entries for a directory.
+    pub size: u64,
+    /// Unix time, 0 if the file system doesn't know.
+    pub created_ns: u64,
+    pub modified_ns: u64,
+}
+
+/// What `read_dir` lists.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub metadata: Metadata,
+}
+
+pub trait FileSystem: Send + Sync {
+    /// What sort it is, e.g. `ramfs`, for the mount table.
+    fn name(&self) -> &'static str;
+
+    fn metadata(&self, path: &str) -> KernelResult<Metadata>;
+
+    /// Read what's at `offset` on into `buffer`, as much as fits. How
+    /// much that was, 0 at the end of the file.
+    fn read(&self, path: &str, offset: u64, buffer: &mut [u8]) -> KernelResult<usize>;
+
+    /// Write `bytes` at `offset`, growing the file if that's past its
+    /// end. Read-only file systems don't have to.
+    fn write(&self, _path: &str, _offset: u64, _bytes: &[u8]) -> KernelResult<usize> {
+        Err(KernelError::PermissionDenied)
+    }
+
+    /// Add an empty file or directory. `AlreadyExists` if there's one
+    /// at `path`, `NotFound` if its directory isn't there.
+    fn create(&self, _path: &str, _kind: Kind) -> KernelResult<()> {
+        Err(KernelError::PermissionDenied)
+    }
+
+    /// What's in the directory at `path`. `InvalidArgument` if it's a
+    /// file.
+    fn read_dir(&self, path: &str) -> KernelResult<Vec<DirEntry>>;
+
+    /// Take away the file or empty directory at `path`. `Busy` if
+    /// it's a directory with something in it, or the root.
+    fn remove(&self, _path: &str) -> KernelResult<()> {
+        Err(KernelError::PermissionDenied)
+    }
+
+    /// Move what's at `from` to `to`, replacing a file that's there.
+    /// `AlreadyExists` if a directory is, `InvalidArgument` if `to` is
+    /// under `from`.
+    fn rename(&self, _from: &str, _to: &str) -> KernelResult<()> {
+        Err(KernelError::PermissionDenied)
+    }
+
+    /// Whether its files should go through the page cache. One that's
+    /// in memory anyway would only have them twice.
+    fn cached(&self) -> bool {
+        true
+    }
+
+    /// Anything wrong with how it keeps its files that the calls above
+    /// wouldn't show, for `fsck`.
+    fn check(&self) -> Vec<fsck::Problem> {
+        Vec::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MountOptions {
+    pub read_only: bool,
+}
+
+impl MountOptions {
+    /// `ro` or `rw`, comma separated. The last one wins.
+    pub fn parse(text: &str) -> Option<MountOptions> {
+        let mut options = MountOptions::default();
+        for option in text.split(',').filter(|option| !option.is_empty()) {
+            match option {
+                "ro" => options.read_only = true,
+                "rw" => options.read_only = false,
+                _ => return None,
+            }
+        }
+        Some(options)
+    }
+}
+
+impl fmt::Display for MountOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(if self.read_only { "ro" } else { "rw" })
+    }
+}
+
+struct Mount {
+    /// Where it's mounted, as `normalize` has it.
+    point: String,
+    fs: Arc<dyn FileSystem>,
+    options: MountOptions,
+}
+
+/// In the order they were mounted, so `/` is first.
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+/// The names in a path, from the top.
+pub fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|name| !name.is_empty())
+}
+
+/// `path` with no `.`, `..`, doubled or trailing slashes left, e.g.
+/// `/a/./b/../c/` is `/a/c`. `InvalidArgument` if it isn't absolute.
+pub fn normalize(path: &str) -> KernelResult<String> {
+    if !path.starts_with('/') {
+        return Err(KernelError::InvalidArgument);
+    }
+    let mut names = Vec::new();
+    for name in components(path) {
+        match name {
+            "." => {}
+            // Going up from `/` stays there.
+            ".." => {
+                names.pop();
+            }
+            name => names.push(name),
+        }
+    }
+    let mut normal = String::with_capacity(path.len());
+    for name in names {
+        normal.push('/');
+        normal.push_str(name);
+    }
+    if normal.is_empty() {
+        normal.push('/');
+    }
+    Ok(normal)
+}
+
+/// Whether `path` is `point` or under it, both normalized.
+fn is_under(path: &str, point: &str) -> bool {
+    point == "/"
+        || path == point
+        || path.starts_with(point) && path.as_bytes().get(point.len()) == Some(&b'/')
+}
+
+/// The file system `path` is on, `path` on it, and how it's mounted.
+/// `NotReady` before `/` is mounted.
+fn resolve(path: &str) -> KernelResult<(Arc<dyn FileSystem>, String, MountOptions)> {
+    let path = normalize(path)?;
+    let mounts = MOUNTS.lock();
+    let mount = mounts
+        .iter()
+        .filter(|mount| is_under(&path, &mount.point))
+        .max_by_key(|mount| mount.point.len())
+        .ok_or(KernelError::NotReady)?;
+    let rest = path[mount.point.len()..].trim_start_matches('/').into();
+    Ok((mount.fs.clone(), rest, mount.options))
+}
+
+/// `resolve` for something that's about to change, `PermissionDenied`
+/// if it's on a read-only mount, and `Busy` if a mount point is `path`
+/// or under it.
+fn resolve_for_change(path: &str) -> KernelResult<(Arc<dyn FileSystem>, String)> {
+    let normal = normalize(path)?;
+    if MOUNTS
+        .lock()
+        .iter()
+        .any(|mount| is_under(&mount.point, &normal))
+    {
+        return Err(KernelError::Busy);
+    }
+    let (fs, path, options) = resolve(&normal)?;
+    if options.read_only {
+        return Err(KernelError::PermissionDenied);
+    }
+    Ok((fs, path))
+}
+
+/// Tells file systems apart, it's their data pointer: the same one can
+/// have more than one vtable.
+fn fs_id(fs: &Arc<dyn FileSystem>) -> usize {
+    &**fs as *const dyn FileSystem as *const () as usize
+}
+
+/// Put `fs` on the directory at `path`, which hides whatever was in
+/// it until `umount`. The first one has to go on `/`.
+pub fn mount(fs: Arc<dyn FileSystem>, path: &str, options: MountOptions) -> KernelResult<()> {
+    let point = normalize(path)?;
+    let is_root = point == "/";
+    if !is_root && stat(&point)?.kind != Kind::Directory {
+        return Err(KernelError::InvalidArgument);
+    }
+    let mut mounts = MOUNTS.lock();
+    if mounts.is_empty() != is_root {
+        return Err(if is_root {
+            KernelError::AlreadyExists
+        } else {
+            KernelError::NotReady
+        });
+    }
+    if mounts.iter().any(|mount| mount.point == point) {
+        return Err(KernelError::AlreadyExists);
+    }
+    if mounts.len() == MAX_MOUNTS {
+        return Err(KernelError::NoSpace);
+    }
+    mounts.push(Mount { point, fs, options });
+    Ok(())
+}
+
+/// Take off what's mounted at `path`. `Busy` if something else is
+/// mounted in it, and `/` always is. What's cached from it is written
+/// back first, and if that fails it stays mounted.
+pub fn umount(path: &str) -> KernelResult<()> {
+    let point = normalize(path)?;
+    let mut mounts = MOUNTS.lock();
+    let index = mounts
+        .iter()
+        .position(|mount| mount.point == point)
+        .ok_or(KernelError::NotFound)?;
+    if point == "/"
+        || mounts
+            .iter()
+            .any(|mount| mount.point != point && is_under(&mount.point, &point))
+    {
+        return Err(KernelError::Busy);
+    }
+    cache::forget_fs(&mounts[index].fs)?;
+    mounts.remove(index);
+    Ok(())
+}
+
+/// Mount an empty ramfs on `/`. Needs the heap.
+pub fn mount_root() -> KernelResult<()> {
+    mount(Arc::new(ramfs::RamFs::new()), "/", MountOptions::default())
+}
+
+/// Call `f` with each mount's point, file system name and options, in
+/// the order they were mounted.
+pub fn for_each_mount(mut f: impl FnMut(&str, &'static str, MountOptions)) {
+    for mount in MOUNTS.lock().iter() {
+        f(&mount.point, mount.fs.name(), mount.options);
+    }
+}
+
+pub fn stat(path: &str) -> KernelResult<Metadata> {
+    let (fs, path, _) = resolve(path)?;
+    cache::metadata(&fs, &path)
+}
+
+/// The entries of a directory, sorted the way its file system keeps
+/// them.
+pub struct ReadDir(vec::IntoIter<DirEntry>);
+
+impl Iterator for ReadDir {
+    type Item = DirEntry;
+
+    fn next(&mut self) -> Option<DirEntry> {
+        self.0.next()
+    }
+}
+
+/// What's in the directory at `path`. A mount point in it is listed
+/// as what it hides, not what's mounted there.
+pub fn read_dir(path: &str) -> KernelResult<ReadDir> {
+    let (fs, path, _) = resolve(path)?;
+    Ok(ReadDir(fs.read_dir(&path)?.into_iter()))
+}
+
+pub fn read(path: &str, offset: u64, buffer: &mut [u8]) -> KernelResult<usize> {
+    let (fs, path, _) = resolve(path)?;
+    read_from(&fs, &path, offset, buffer)
+}
+
+/// `read`, with the path resolved already.
+fn read_from(
+    fs: &Arc<dyn FileSystem>,
+    path: &str,
+    offset: u64,
+    buffer: &mut [u8],
+) -> KernelResult<usize> {
+    let metadata = cache::metadata(fs, path)?;
+    if cache::caches(fs, &metadata) {
+        cache::read(fs, path, metadata, offset, buffer)
+    } else {
+        fs.read(path, offset, buffer)
+    }
+}
+
+pub fn write(path: &str, offset: u64, bytes: &[u8]) -> KernelResult<usize> {
+    let (fs, path, options) = resolve(path)?;
+    if options.read_only {
+        return Err(KernelError::PermissionDenied);
+    }
+    let metadata = cache::metadata(&fs, &path)?;
+    if cache::caches(&fs, &metadata) {
+        cache::write(&fs, &path, metadata, offset, bytes)
+    } else {
+        fs.write(&path, offset, bytes)
+    }
+}
+
+pub fn create(path: &str, kind: Kind) -> KernelResult<()> {
+    let (fs, path, options) = resolve(path)?;
+    if options.read_only {
+        return Err(KernelError::PermissionDenied);
+    }
+    fs.create(&path, kind)
+}
+
+pub fn mkdir(path: &str) -> KernelResult<()> {
+    create(path, Kind::Directory)
+}
+
+/// Remove the file at `path`. `InvalidArgument` for a directory,
+/// `Busy` while some of it is mapped. Whatever of it hadn't been
+/// written back is thrown away.
+pub fn unlink(path: &str) -> KernelResult<()> {
+    let (fs, path) = resolve_for_change(path)?;
+    let metadata = fs.metadata(&path)?;
+    if metadata.kind != Kind::File {
+        return Err(KernelError::InvalidArgument);
+    }
+    if cache::mapped(&fs, metadata.inode) {
+        return Err(KernelError::Busy);
+    }
+    fs.remove(&path)?;
+    cache::forget_file(&fs, metadata.inode, true)
+}
+
+/// Remove the empty directory at `path`. `InvalidArgument` for a file.
+pub fn rmdir(path: &str) -> KernelResult<()> {
+    let (fs, path) = resolve_for_change(path)?;
+    if fs.metadata(&path)?.kind != Kind::Directory {
+        return Err(KernelError::InvalidArgument);
+    }
+    fs.remove(&path)
+}
+
+/// Move `from` to `to`, see `FileSystem::rename`. `Unsupported` if
+/// they're on different file systems.
+pub fn rename(from: &str, to: &str) -> KernelResult<()> {
+    let (from_fs, from) = resolve_for_change(from)?;
+    let (to_fs, to) = resolve_for_change(to)?;
+    if fs_id(&from_fs) != fs_id(&to_fs) {
+        return Err(KernelError::Unsupported);
+    }
+    // The cache has files by inode but writes them back by path, which
+    // is about to change. A file it replaces goes too.
+    let metadata = from_fs.metadata(&from)?;
+    match metadata.kind {
+        Kind::File => cache::forget_file(&from_fs, metadata.inode, false)?,
+        Kind::Directory => cache::forget_fs(&from_fs)?,
+    }
+    match from_fs.metadata(&to) {
+        Ok(replaced) if replaced.kind == Kind::File && replaced.inode != metadata.inode => {
+            cache::forget_file(&from_fs, replaced.inode, true)?
+        }
+        _ => {}
+    }
+    from_fs.rename(&from, &to)
+}
+
+/// Adds the `mount`, `umount`, `ls`, `stat`, `cat`, `mkdir`, `rm`, `mv`,
+/// `sync` and `fsck` shell commands.
+pub fn init() {
+    shell::register(
+        "mount",
+        "mount [ramfs PATH [ro|rw] | iso9660 DEVICE PATH]: list mounts, mount a new ramfs or a CD",
+        mount_command,
+    )
+    .expect("mount command");
+    shell::register(
+        "umount",
+        "umount PATH: unmount a file system",
+        umount_command,
+    )
+    .expect("umount command");
+    shell::register("ls", "ls [PATH]: list a directory", ls_command).expect("ls command");
+    shell::register("stat", "stat PATH: size, type and times", stat_command).expect("stat command");
+    shell::register("cat", "cat PATH: print a file", cat_command).expect("cat command");
+    shell::register("mkdir", "mkdir PATH: make a directory", mkdir_command).expect("mkdir command");
+    shell::register(
+        "rm",
+        "rm PATH: remove a file or an empty directory",
+        rm_command,
+    )
+    .expect("rm command");
+    shell::register("mv", "mv FROM TO: rename", mv_command).expect("mv command");
+    shell::register("sync", "write back the page cache", sync_command).expect("sync command");
+    shell::register(
+        "fsck",
+        "fsck [PATH]: check the file system PATH is on, from there down",
+        fsck::fsck_command,
+    )
+    .expect("fsck command");
+    shell::set_file_source(read_script);
+}
+
+/// The script boot runs, for configuration that doesn't belong in code.
+pub const INIT_SCRIPT: &str = "/init.rc";
+
+/// Run `INIT_SCRIPT` through the shell, printing to the console. Not
+/// having one is fine; one that can't be read or fails is logged, and
+/// boot goes on either way.
+pub fn run_init_script() {
+    match stat(INIT_SCRIPT) {
+        Ok(_) => {}
+        Err(KernelError::NotFound) => return,
+        Err(error) => {
+            crate::klog!(Warn, "{}: {}", INIT_SCRIPT, error);
+            return;
+        }
+    }
+    let script = match read_script(INIT_SCRIPT) {
+        Some(script) => script,
+        None => {
+            crate::klog!(Warn, "{}: can't be read as text", INIT_SCRIPT);
+            return;
+        }
+    };
+    if shell::run_script(&mut crate::ui::Console, &script).is_err() {
+        crate::klog!(Warn, "{} failed", INIT_SCRIPT);
+    }
+}
+
+/// The whole file at `path`, for the shell's `run`. `None` if it can't
+/// be read, or isn't text.
+fn read_script(path: &str) -> Option<String> {
+    let path = absolute(path);
+    let mut contents = Vec::new();
+    let mut buffer = [0; 256];
+    loop {
+        match read(&path, contents.len() as u64, &mut buffer).ok()? {
+            0 => return String::from_utf8(contents).ok(),
+            count => contents.extend_from_slice(&buffer[..count]),
+        }
+    }
+}
+
+/// For the shell, which hands over whatever was typed.
+fn absolute(path: &str) -> String {
+    if path.starts_with('/') {
+        path.into()
+    } else {
+        alloc::format!("/{}", path)
+    }
+}
+
+/// How `ls` and `stat` show a time.
+struct Time(u64);
+
+impl fmt::Display for Time {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            0 => f.write_str("-"),
+            ns => write!(f, "{}", time::DateTime::from_unix(ns / 1_000_000_000)),
+        }
+    }
+}
+
+fn kind_name(kind: Kind) -> &'static str {
+    match kind {
+        Kind::File => "file",
+        Kind::Directory => "dir",
+    }
+}
+
+fn ls_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    const COLUMNS: [Column; 4] = [
+        Column::left("name", 24),
+        Column::left("type", 4),
+        Column::right("size", 10),
+        Column::left("modified", 19),
+    ];
+    let path = absolute(if args.is_empty() { "/" } else { args });
+    let entries = read_dir(&path).map_err(|error| {
+        let _ = writeln!(out, "ls: {}: {}", path, error.as_str());
+        CommandFailed
+    })?;
+    let table = Table::new(&COLUMNS);
+    let _ = table.header(out);
+    for entry in entries {
+        let metadata = entry.metadata;
+        let _ = table.row(
+            out,
+            &[
+                &entry.name,
+                &kind_name(metadata.kind),
+                &metadata.size,
+                &Time(metadata.modified_ns),
+            ],
+        );
+    }
+    let _ = table.end(out);
+    Ok(())
+}
+
+fn stat_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    if args.is_empty() {
+        let _ = writeln!(out, "usage: stat PATH");
+        return Err(CommandFailed);
+    }
+    let path = absolute(args);
+    let metadata = stat(&path).map_err(|error| {
+        let _ = writeln!(out, "stat: {}: {}", path, error.as_str());
+        CommandFailed
+    })?;
+    let _ = writeln!(out, "{}: {}", path, kind_name(metadata.kind));
+    let _ = writeln!(out, "  size:     {}", metadata.size);
+    let _ = writeln!(out, "  created:  {}", Time(metadata.created_ns));
+    let _ = writeln!(out, "  modified: {}", Time(metadata.modified_ns));
+    Ok(())
+}
+
+fn cat_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    if args.is_empty() {
+        let _ = writeln!(out, "usage: cat PATH");
+        return Err(CommandFailed);
+    }
+    let path = absolute(args);
+    let mut buffer = [0; 256];
+    let mut offset = 0;
+    loop {
+        let count = read(&path, offset, &mut buffer).map_err(|error| {
+            let _ = writeln!(out, "cat: {}: {}", path, error.as_str());
+            CommandFailed
+        })?;
+        if count == 0 {
+            return Ok(());
+        }
+        // Good enough for text, anything else wants `hexdump`.
+        for &byte in &buffer[..count] {
+            let _ = out.write_char(if byte == b'\n' || (0x20..0x7F).contains(&byte) {
+                char::from(byte)
+            } else {
+                '.'
+            });
+        }
+        offset += count as u64;
+    }
+}
+
+fn rm_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    if args.is_empty() {
+        let _ = writeln!(out, "usage: rm PATH");
+        return Err(CommandFailed);
+    }
+    let path = absolute(args);
+    let result = match stat(&path) {
+        Ok(metadata) if metadata.kind == Kind::Directory => rmdir(&path),
+        Ok(_) => unlink(&path),
+        Err(error) => Err(error),
+    };
+    result.map_err(|error| {
+        let _ = writeln!(out, "rm: {}: {}", path, error.as_str());
+        CommandFailed
+    })
+}
+
+fn mv_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    let mut words = args.split_whitespace();
+    let (from, to) = match (words.next(), words.next(), words.next()) {
+        (Some(from), Some(to), None) => (absolute(from), absolute(to)),
+        _ => {
+            let _ = writeln!(out, "usage: mv FROM TO");
+            return Err(CommandFailed);
+        }
+    };
+    rename(&from, &to).map_err(|error| {
+        let _ = writeln!(out, "mv: {}: {}", from, error.as_str());
+        CommandFailed
+    })
+}
+
+fn sync_command(out: &mut dyn fmt::Write, _args: &str) -> CommandResult {
+    match cache::sync() {
+        Ok(pages) => {
+            let _ = writeln!(out, "{} pages written back", pages);
+            Ok(())
+        }
+        Err(error) => {
+            let _ = writeln!(out, "sync: {}", error.as_str());
+            Err(CommandFailed)
+        }
+    }
+}
+
+fn mount_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    const COLUMNS: [Column; 3] = [
+        Column::left("point", 24),
+        Column::left("type", 8),
+        Column::left("options", 7),
+    ];
+    let mut words = args.split_whitespace();
+    let result = match (words.next(), words.next(), words.next(), words.next()) {
+        (None, _, _, _) => {
+            let table = Table::new(&COLUMNS);
+            let _ = table.header(out);
+            for_each_mount(|point, name, options| {
+                let _ = table.row(out, &[&point, &name, &options]);
+            });
+            let _ = table.end(out);
+            return Ok(());
+        }
+        (Some("ramfs"), Some(path), options, None) => {
+            match MountOptions::parse(options.unwrap_or("")) {
+                Some(options) => mount(Arc::new(ramfs::RamFs::new()), path, options),
+                None => Err(KernelError::InvalidArgument),
+            }
+        }
+        // CDs are always read-only.
+        (Some("iso9660"), Some(device), Some(path), None) => block::device(device)
+            .and_then(iso9660::Iso9660::new)
+            .and_then(|fs| mount(Arc::new(fs), path, MountOptions { read_only: true })),
+        _ => Err(KernelError::InvalidArgument),
+    };
+    match result {
+        Ok(()) => Ok(()),
+        Err(KernelError::InvalidArgument) => {
+            let _ = writeln!(
+                out,
+                "usage: mount [ramfs PATH [ro|rw] | iso9660 DEVICE PATH]"
+            );
+            Err(CommandFailed)
+        }
+        Err(error) => {
+            let _ = writeln!(out, "mount: {}", error.as_str());
+            Err(CommandFailed)
+        }
+    }
+}
+
+fn umount_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    if args.is_empty() {
+        let _ = writeln!(out, "usage: umount PATH");
+        return Err(CommandFailed);
+    }
+    umount(args).map_err(|error| {
+        let _ = writeln!(out, "umount: {}: {}", args, error.as_str());
+        CommandFailed
+    })
+}
+
+fn mkdir_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    if args.is_empty() {
+        let _ = writeln!(out, "usage: mkdir PATH");
+        return Err(CommandFailed);
+    }
+    mkdir(&absolute(args)).map_err(|error| {
+        let _ = writeln!(out, "mkdir: {}: {}", args, error.as_str());
+        CommandFailed
+    })
+}
+
+#[test_case]
+fn test_mounts_in_one_tree() {
+    assert_eq!(normalize("/a/./b/../c//").unwrap(), "/a/c");
+    assert_eq!(normalize("/..").unwrap(), "/");
+    assert_eq!(normalize("a"), Err(KernelError::InvalidArgument));
+
+    create("/fs-test", Kind::Directory).unwrap();
+    create("/fs-test/mnt", Kind::Directory).unwrap();
+    create("/fs-test/file", Kind::File).unwrap();
+    assert_eq!(
+        mount(
+            Arc::new(ramfs::RamFs::new()),
+            "/fs-test/file",
+            MountOptions::default()
+        ),
+        Err(KernelError::InvalidArgument)
+    );
+    let read_only = MountOptions::parse("rw,ro").unwrap();
+    mount(
+        Arc::new(ramfs::RamFs::new()),
+        "/fs-test/mnt",
+        MountOptions::default(),
+    )
+    .unwrap();
+    // Made on the new one, so not there once it's gone.
+    create("/fs-test/mnt/inner", Kind::Directory).unwrap();
+    mount(
+        Arc::new(ramfs::RamFs::new()),
+        "/fs-test/mnt/inner",
+        read_only,
+    )
+    .unwrap();
+    assert_eq!(
+        create("/fs-test/mnt/inner/x", Kind::File),
+        Err(KernelError::PermissionDenied)
+    );
+    assert_eq!(umount("/fs-test/mnt"), Err(KernelError::Busy));
+    assert_eq!(umount("/"), Err(KernelError::Busy));
+
+    write("/fs-test/mnt/../file", 2, b"hi").unwrap();
+    let mut buffer = [0xFF; 8];
+    assert_eq!(read("/fs-test/file", 0, &mut buffer), Ok(4));
+    assert_eq!(&buffer[..4], b"\0\0hi");
+
+    umount("/fs-test/mnt/inner/").unwrap();
+    umount("/fs-test/mnt").unwrap();
+    assert_eq!(stat("/fs-test/mnt/inner"), Err(KernelError::NotFound));
+    assert_eq!(umount("/fs-test/mnt"), Err(KernelError::NotFound));
+}
+
+#[test_case]
+fn test_run_reads_scripts() {
+    let script = "/run-test";
+    create(script, Kind::File).unwrap();
+    write(script, 0, b"mkdir /run-test-dir # from a file\n").unwrap();
+    let mut out = String::new();
+    assert_eq!(shell::execute(&mut out, "run run-test"), Ok(()));
+    assert_eq!(
+        stat("/run-test-dir").map(|stat| stat.kind),
+        Ok(Kind::Directory)
+    );
+    assert_eq!(
+        shell::execute(&mut out, "run /no-such-script"),
+        Err(CommandFailed)
+    );
+    rmdir("/run-test-dir").unwrap();
+    unlink(script).unwrap();
+}
+
+#[test_case]
+fn test_stat_list_remove_rename() {
+    use alloc::vec;
+
+    let names = |path| {
+        read_dir(path)
+            .unwrap()
+            .map(|entry| entry.name)
+            .collect::<Vec<_>>()
+    };
+    mkdir("/fs-ops").unwrap();
+    create("/fs-ops/b", Kind::File).unwrap();
+    create("/fs-ops/a", Kind::File).unwrap();
+    write("/fs-ops/a", 0, b"abc").unwrap();
+    let a = stat("/fs-ops/a").unwrap();
+    assert_eq!((a.kind, a.size), (Kind::File, 3));
+    assert!(a.created_ns <= a.modified_ns);
+    assert_eq!(names("/fs-ops"), vec!["a", "b"]);
+    assert_eq!(
+        read_dir("/fs-ops/a").err(),
+        Some(KernelError::InvalidArgument)
+    );
+
+    rename("/fs-ops/a", "/fs-ops/c").unwrap();
+    assert_eq!(names("/fs-ops"), vec!["b", "c"]);
+    assert_eq!(stat("/fs-ops/c").unwrap().size, 3);
+    assert_eq!(
+        rename("/fs-ops", "/fs-ops/d"),
+        Err(KernelError::InvalidArgument)
+    );
+    assert_eq!(unlink("/fs-ops"), Err(KernelError::InvalidArgument));
+    assert_eq!(rmdir("/fs-ops"), Err(KernelError::Busy));
+
+    mkdir("/fs-ops/mnt").unwrap();
+    mount(
+        Arc::new(ramfs::RamFs::new()),
+        "/fs-ops/mnt",
+        MountOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(rmdir("/fs-ops/mnt"), Err(KernelError::Busy));
+    assert_eq!(
+        rename("/fs-ops/b", "/fs-ops/mnt/b"),
+        Err(KernelError::Unsupported)
+    );
+    umount("/fs-ops/mnt").unwrap();
+
+    rmdir("/fs-ops/mnt").unwrap();
+    unlink("/fs-ops/b").unwrap();
+    unlink("/fs-ops/c").unwrap();
+    assert_eq!(unlink("/fs-ops/c"), Err(KernelError::NotFound));
+    rmdir("/fs-ops").unwrap();
+    assert_eq!(rmdir("/"), Err(KernelError::Busy));
+}
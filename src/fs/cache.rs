@@ -0,0 +1,532 @@
+//! The page cache: what's in files, a page at a time, so reading the
+//! same part of a file again doesn't go back to its file system, which
+//! for one on a disk is a walk through its metadata and then the disk.
+//!
+//! Pages are frames from `frame_allocator`, for mapping a file to be
+//! able to map the same ones. They're keyed by which file system, the
+//! file's inode there and which page of the file it is. A write only
+//! changes the cached pages and marks them dirty, they're written back
+//! when they're evicted, before the file is renamed or removed, on
+//! `umount` and on `sync`. At most `MAX_PAGES` are kept, the one used
+//! longest ago goes first.
+//!
+//! `mmap` maps the frames themselves. A page that's mapped somewhere
+//! stays until it's unmapped, it isn't evicted and the file can't be
+//! renamed, removed or unmounted, that's `Busy`. Writing it back
+//! doesn't make it clean either, whoever has it mapped can write to it
+//! again without the cache knowing.
+//!
+//! File systems that are in memory anyway say so with
+//! `FileSystem::cached` and are left alone, unless one of their files
+//! is mapped: then that file goes through the cache until it isn't
+//! anymore. Files without an inode are always left alone.
+use super::{FileSystem, Metadata};
+use crate::error::{KernelError, KernelResult};
+use crate::memory::{self, frame_allocator};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::slice;
+use spin::Mutex;
+use x86_64::structures::paging::PhysFrame;
+
+pub const PAGE_SIZE: u64 = 4096;
+pub const MAX_PAGES: usize = 256;
+
+/// A page of a file: the file system, by `super::fs_id`, the inode
+/// and the page's index.
+type Key = (usize, u64, u64);
+
+struct Page {
+    frame: PhysFrame,
+    dirty: bool,
+    /// `Cache::clock` when it was last used.
+    used: u64,
+    /// How many places it's mapped at.
+    mapped: usize,
+}
+
+/// What writing back a file's pages needs.
+struct File {
+    fs: Arc<dyn FileSystem>,
+    path: String,
+    /// With whatever the dirty pages added.
+    size: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    pub pages: usize,
+    pub dirty: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub writebacks: u64,
+}
+
+struct Cache {
+    pages: BTreeMap<Key, Page>,
+    files: BTreeMap<(usize, u64), File>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+static CACHE: Mutex<Cache> = Mutex::new(Cache {
+    pages: BTreeMap::new(),
+    files: BTreeMap::new(),
+    clock: 0,
+    stats: CacheStats {
+        pages: 0,
+        dirty: 0,
+        hits: 0,
+        misses: 0,
+        writebacks: 0,
+    },
+});
+
+fn bytes(frame: PhysFrame) -> KernelResult<&'static mut [u8]> {
+    let address = memory::physical_to_virtual(frame.start_address(), PAGE_SIZE)?;
+    Ok(unsafe { slice::from_raw_parts_mut(address.as_mut_ptr(), PAGE_SIZE as usize) })
+}
+
+impl Cache {
+    /// Write `key` back if it's dirty.
+    fn write_back(&mut self, key: Key) -> KernelResult<()> {
+        let page = match self.pages.get_mut(&key) {
+            Some(page) if page.dirty => page,
+            _ => return Ok(()),
+        };
+        let (fs, inode, index) = key;
+        let file = self.files.get(&(fs, inode)).ok_or(KernelError::NotFound)?;
+        let start = index * PAGE_SIZE;
+        let len = file.size.saturating_sub(start).min(PAGE_SIZE) as usize;
+        let contents = &bytes(page.frame)?[..len];
+        let mut written = 0;
+        while written < len {
+            written += file
+                .fs
+                .write(&file.path, start + written as u64, &contents[written..])?;
+        }
+        if page.mapped == 0 {
+            page.dirty = false;
+            self.stats.dirty -= 1;
+        }
+        self.stats.writebacks += 1;
+        Ok(())
+    }
+
+    /// Write `key` back and drop it.
+    fn evict(&mut self, key: Key) -> KernelResult<()> {
+        self.write_back(key)?;
+        if let Some(page) = self.pages.remove(&key) {
+            self.stats.pages -= 1;
+            unsafe { frame_allocator::free_frame(page.frame)? };
+        }
+        Ok(())
+    }
+
+    /// The page at `key`, read in if it isn't cached yet.
+    fn page(&mut self, key: Key) -> KernelResult<&'static mut [u8]> {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(page) = self.pages.get_mut(&key) {
+            page.used = clock;
+            self.stats.hits += 1;
+            return bytes(page.frame);
+        }
+        if self.pages.len() >= MAX_PAGES {
+            // If they're all mapped there's nothing to do but grow.
+            let oldest = self
+                .pages
+                .iter()
+                .filter(|(_, page)| page.mapped == 0)
+                .min_by_key(|(_, page)| page.used)
+                .map(|(&key, _)| key);
+            if let Some(oldest) = oldest {
+                self.evict(oldest)?;
+            }
+        }
+
+        let frame = frame_allocator::allocate_frame()?;
+        let contents = bytes(frame)?;
+        let (fs, inode, index) = key;
+        let file = self.files.get(&(fs, inode)).ok_or(KernelError::NotFound)?;
+        contents.iter_mut().for_each(|byte| *byte = 0);
+        let mut read = 0;
+        while read < contents.len() {
+            let offset = index * PAGE_SIZE + read as u64;
+            match file.fs.read(&file.path, offset, &mut contents[read..]) {
+                Ok(0) => break,
+                Ok(count) => read += count,
+                Err(error) => {
+                    unsafe { frame_allocator::free_frame(frame)? };
+                    return Err(error);
+                }
+            }
+        }
+        self.pages.insert(
+            key,
+            Page {
+                frame,
+                dirty: false,
+                used: clock,
+                mapped: 0,
+            },
+        );
+        self.stats.pages += 1;
+        self.stats.misses += 1;
+        Ok(contents)
+    }
+
+    /// Start keeping track of the file at `path`, or update where it
+    /// is. Its size.
+    fn file(&mut self, fs: &Arc<dyn FileSystem>, path: &str, metadata: Metadata) -> u64 {
+        let file = self
+            .files
+            .entry((super::fs_id(fs), metadata.inode))
+            .or_insert_with(|| File {
+                fs: fs.clone(),
+                path: path.into(),
+                size: metadata.size,
+            });
+        if file.path != path {
+            file.path = path.into();
+        }
+        file.size = file.size.max(metadata.size);
+        file.size
+    }
+
+    /// Drop every page `keep` says no to, writing dirty ones back
+    /// first unless `discard`. The files go with them. `Busy`, and
+    /// nothing is dropped, if one of the pages is mapped.
+    fn flush(&mut self, keep: impl Fn(usize, u64) -> bool, discard: bool) -> KernelResult<()> {
+        let keys: alloc::vec::Vec<Key> = self
+            .pages
+            .keys()
+            .filter(|&&(fs, inode, _)| !keep(fs, inode))
+            .copied()
+            .collect();
+        if keys.iter().any(|key| self.pages[key].mapped > 0) {
+            return Err(KernelError::Busy);
+        }
+        for key in keys {
+            if discard {
+                if let Some(page) = self.pages.get_mut(&key) {
+                    if page.dirty {
+                        page.dirty = false;
+                        self.stats.dirty -= 1;
+                    }
+                }
+            }
+            self.evict(key)?;
+        }
+        self.files.retain(|&(fs, inode), _| keep(fs, inode));
+        // An empty map still holds on to a node, give it back so an
+        // idle cache has nothing on the heap.
+        if self.pages.is_empty() {
+            self.pages = BTreeMap::new();
+        }
+        if self.files.is_empty() {
+            self.files = BTreeMap::new();
+        }
+        Ok(())
+    }
+}
+
+/// `fs.metadata(path)`, with the size of what's been written to the
+/// file and not written back.
+pub(super) fn metadata(fs: &Arc<dyn FileSystem>, path: &str) -> KernelResult<Metadata> {
+    let mut metadata = fs.metadata(path)?;
+    let cache = CACHE.lock();
+    if let Some(file) = cache.files.get(&(super::fs_id(fs), metadata.inode)) {
+        metadata.size = metadata.size.max(file.size);
+    }
+    Ok(metadata)
+}
+
+/// Whether `fs` goes through the cache for the file `metadata` is of.
+pub(super) fn caches(fs: &Arc<dyn FileSystem>, metadata: &Metadata) -> bool {
+    if metadata.inode == 0 || metadata.kind != super::Kind::File {
+        return false;
+    }
+    fs.cached()
+        || CACHE
+            .lock()
+            .files
+            .contains_key(&(super::fs_id(fs), metadata.inode))
+}
+
+/// Whether any of the file with `inode` on `fs` is mapped.
+pub(super) fn mapped(fs: &Arc<dyn FileSystem>, inode: u64) -> bool {
+    let id = super::fs_id(fs);
+    CACHE
+        .lock()
+        .pages
+        .iter()
+        .any(|(&(fs, file, _), page)| fs == id && file == inode && page.mapped > 0)
+}
+
+/// The frame page `index` of the file at `path` is in, read in if it
+/// isn't cached yet, for `mmap` to map. It stays cached until
+/// `unmap_page`. With `write` it's dirty already.
+pub(super) fn map_page(
+    fs: &Arc<dyn FileSystem>,
+    path: &str,
+    metadata: Metadata,
+    index: u64,
+    write: bool,
+) -> KernelResult<PhysFrame> {
+    let mut cache = CACHE.lock();
+    // Through the guard only one field at a time can be borrowed.
+    let cache = &mut *cache;
+    cache.file(fs, path, metadata);
+    let key = (super::fs_id(fs), metadata.inode, index);
+    cache.page(key)?;
+    let page = cache.pages.get_mut(&key).ok_or(KernelError::NotFound)?;
+    page.mapped += 1;
+    let frame = page.frame;
+    if write && !page.dirty {
+        page.dirty = true;
+        cache.stats.dirty += 1;
+    }
+    Ok(frame)
+}
+
+/// A page `map_page` handed out was written to where it's mapped.
+pub(super) fn mark_dirty(fs: &Arc<dyn FileSystem>, inode: u64, index: u64) -> KernelResult<()> {
+    let mut cache = CACHE.lock();
+    let cache = &mut *cache;
+    let page = cache
+        .pages
+        .get_mut(&(super::fs_id(fs), inode, index))
+        .ok_or(KernelError::NotFound)?;
+    if !page.dirty {
+        page.dirty = true;
+        cache.stats.dirty += 1;
+    }
+    Ok(())
+}
+
+/// A page `map_page` handed out isn't mapped there anymore. Once none
+/// of a file is mapped, a file system that stays out of the cache gets
+/// it written back and dropped.
+pub(super) fn unmap_page(fs: &Arc<dyn FileSystem>, inode: u64, index: u64) -> KernelResult<()> {
+    let id = super::fs_id(fs);
+    let mut cache = CACHE.lock();
+    if let Some(page) = cache.pages.get_mut(&(id, inode, index)) {
+        page.mapped = page.mapped.saturating_sub(1);
+    }
+    let still_mapped = cache
+        .pages
+        .iter()
+        .any(|(&(fs, file, _), page)| fs == id && file == inode && page.mapped > 0);
+    if fs.cached() || still_mapped {
+        return Ok(());
+    }
+    cache.flush(|fs, file| fs != id || file != inode, false)
+}
+
+/// `FileSystem::read` through the cache.
+pub(super) fn read(
+    fs: &Arc<dyn FileSystem>,
+    path: &str,
+    metadata: Metadata,
+    offset: u64,
+    buffer: &mut [u8],
+) -> KernelResult<usize> {
+    let mut cache = CACHE.lock();
+    let size = cache.file(fs, path, metadata);
+    let end = size.min(offset.saturating_add(buffer.len() as u64));
+    let mut position = offset;
+    while position < end {
+        let within = (position % PAGE_SIZE) as usize;
+        let count = (PAGE_SIZE - within as u64).min(end - position) as usize;
+        let key = (super::fs_id(fs), metadata.inode, position / PAGE_SIZE);
+        let page = cache.page(key)?;
+        let done = (position - offset) as usize;
+        buffer[done..done + count].copy_from_slice(&page[within..within + count]);
+        position += count as u64;
+    }
+    Ok(end.saturating_sub(offset) as usize)
+}
+
+/// `FileSystem::write` into the cache, left there until it's written
+/// back.
+pub(super) fn write(
+    fs: &Arc<dyn FileSystem>,
+    path: &str,
+    metadata: Metadata,
+    offset: u64,
+    bytes: &[u8],
+) -> KernelResult<usize> {
+    let end = offset
+        .checked_add(bytes.len() as u64)
+        .ok_or(KernelError::NoSpace)?;
+    let mut cache = CACHE.lock();
+    let cache = &mut *cache;
+    cache.file(fs, path, metadata);
+    let mut position = offset;
+    while position < end {
+        let within = (position % PAGE_SIZE) as usize;
+        let count = (PAGE_SIZE - within as u64).min(end - position) as usize;
+        let key = (super::fs_id(fs), metadata.inode, position / PAGE_SIZE);
+        let page = cache.page(key)?;
+        let done = (position - offset) as usize;
+        page[within..within + count].copy_from_slice(&bytes[done..done + count]);
+        let page = cache.pages.get_mut(&key).ok_or(KernelError::NotFound)?;
+        if !page.dirty {
+            page.dirty = true;
+            cache.stats.dirty += 1;
+        }
+        position += count as u64;
+    }
+    let file = cache
+        .files
+        .get_mut(&(super::fs_id(fs), metadata.inode))
+        .ok_or(KernelError::NotFound)?;
+    file.size = file.size.max(end);
+    Ok(bytes.len())
+}
+
+/// Write back and drop what's cached of one file, before it's renamed.
+/// With `discard`, what wasn't written back is lost, for a file that's
+/// being removed.
+pub(super) fn forget_file(fs: &Arc<dyn FileSystem>, inode: u64, discard: bool) -> KernelResult<()> {
+    let id = super::fs_id(fs);
+    CACHE
+        .lock()
+        .flush(|fs, file| fs != id || file != inode, discard)
+}
+
+/// Write back and drop everything cached from `fs`, before it's
+/// unmounted or a directory on it is renamed.
+pub(super) fn forget_fs(fs: &Arc<dyn FileSystem>) -> KernelResult<()> {
+    let id = super::fs_id(fs);
+    CACHE.lock().flush(|fs, _| fs != id, false)
+}
+
+/// Write back every dirty page. How many there were.
+pub fn sync() -> KernelResult<usize> {
+    let mut cache = CACHE.lock();
+    let dirty: alloc::vec::Vec<Key> = cache
+        .pages
+        .iter()
+        .filter(|(_, page)| page.dirty)
+        .map(|(&key, _)| key)
+        .collect();
+    for &key in &dirty {
+        cache.write_back(key)?;
+    }
+    Ok(dirty.len())
+}
+
+pub fn stats() -> CacheStats {
+    CACHE.lock().stats
+}
+
+#[test_case]
+fn test_caches_and_writes_back() {
+    use super::{DirEntry, Kind, MountOptions};
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    /// One file, `data`, with its reads and writes counted.
+    struct Disk {
+        data: Mutex<Vec<u8>>,
+        reads: AtomicUsize,
+        writes: AtomicUsize,
+    }
+
+    impl FileSystem for Disk {
+        fn name(&self) -> &'static str {
+            "disk"
+        }
+
+        fn metadata(&self, path: &str) -> KernelResult<Metadata> {
+            let (kind, inode, size) = match path {
+                "" => (Kind::Directory, 1, 1),
+                "data" => (Kind::File, 2, self.data.lock().len() as u64),
+                _ => return Err(KernelError::NotFound),
+            };
+            Ok(Metadata {
+                kind,
+                inode,
+                size,
+                created_ns: 0,
+                modified_ns: 0,
+            })
+        }
+
+        fn read(&self, _path: &str, offset: u64, buffer: &mut [u8]) -> KernelResult<usize> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            let data = self.data.lock();
+            let start = (offset as usize).min(data.len());
+            let count = buffer.len().min(data.len() - start);
+            buffer[..count].copy_from_slice(&data[start..start + count]);
+            Ok(count)
+        }
+
+        fn write(&self, _path: &str, offset: u64, bytes: &[u8]) -> KernelResult<usize> {
+            self.writes.fetch_add(1, Ordering::Relaxed);
+            let mut data = self.data.lock();
+            let end = offset as usize + bytes.len();
+            if data.len() < end {
+                data.resize(end, 0);
+            }
+            data[offset as usize..end].copy_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn read_dir(&self, _path: &str) -> KernelResult<Vec<DirEntry>> {
+            Ok(Vec::new())
+        }
+    }
+
+    let disk = Arc::new(Disk {
+        data: Mutex::new(alloc::vec![b'x'; PAGE_SIZE as usize + 10]),
+        reads: AtomicUsize::new(0),
+        writes: AtomicUsize::new(0),
+    });
+    super::mkdir("/cache-test").unwrap();
+    super::mount(disk.clone(), "/cache-test", MountOptions::default()).unwrap();
+
+    // Across the page boundary, then again from the cache.
+    let mut buffer = [0; 8];
+    assert_eq!(
+        super::read("/cache-test/data", PAGE_SIZE - 4, &mut buffer),
+        Ok(8)
+    );
+    assert_eq!(disk.reads.load(Ordering::Relaxed), 2);
+    assert_eq!(
+        super::read("/cache-test/data", PAGE_SIZE - 4, &mut buffer),
+        Ok(8)
+    );
+    assert_eq!(disk.reads.load(Ordering::Relaxed), 2);
+    assert_eq!(&buffer, b"xxxxxxxx");
+
+    // Past the end, nothing reaches the disk until it's written back.
+    super::write("/cache-test/data", PAGE_SIZE + 8, b"hello").unwrap();
+    assert_eq!(disk.writes.load(Ordering::Relaxed), 0);
+    assert_eq!(
+        super::stat("/cache-test/data").unwrap().size,
+        PAGE_SIZE + 13
+    );
+    assert_eq!(
+        super::read("/cache-test/data", PAGE_SIZE + 8, &mut buffer),
+        Ok(5)
+    );
+    assert_eq!(&buffer[..5], b"hello");
+    assert_eq!(sync(), Ok(1));
+    assert_eq!(disk.writes.load(Ordering::Relaxed), 1);
+    assert_eq!(&disk.data.lock()[PAGE_SIZE as usize + 6..], b"xxhello");
+
+    super::write("/cache-test/data", 0, b"y").unwrap();
+    super::umount("/cache-test").unwrap();
+    assert_eq!(disk.data.lock()[0], b'y');
+    let owned = |fs, _| fs == super::fs_id(&(disk.clone() as Arc<dyn FileSystem>));
+    assert!(!CACHE
+        .lock()
+        .pages
+        .keys()
+        .any(|&(fs, inode, _)| owned(fs, inode)));
+    super::rmdir("/cache-test").unwrap();
+}
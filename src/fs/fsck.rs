@@ -0,0 +1,185 @@
+//! Checking a file system for things that can't be right, the way
+//! `fsck` does, so a bug in a write path shows up in a test rather than
+//! as a mangled file much later.
+//!
+//! `check` goes through everything under a directory with nothing but
+//! `FileSystem` calls, so it works on any of them:
+//!
+//! - every name is one a path can get to: not empty, `.` or `..`, no
+//!   `/` in it, and only once in its directory,
+//! - what a directory lists for an entry is what `metadata` says,
+//! - a directory's size is how many entries it has, and reading a file
+//!   gives back its size in bytes,
+//! - no two files have the same inode,
+//! - nothing was modified before it was created.
+//!
+//! From the root, the file system then gets to look at its insides
+//! with `FileSystem::check`. `ramfs` checks its inode numbers. There's
+//! no FAT driver yet, whose check would be where lost and cross-linked
+//! cluster chains turn up.
+//!
+//! The shell has `fsck [PATH]`, which writes back the page cache first
+//! so the file system has everything.
+use super::{cache, FileSystem, Kind, Metadata};
+use crate::error::{KernelError, KernelResult};
+use crate::shell::{CommandFailed, CommandResult};
+use alloc::collections::BTreeSet;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    /// From the file system's root, like the paths it's handed.
+    pub path: String,
+    pub what: &'static str,
+}
+
+impl Problem {
+    pub fn new(path: &str, what: &'static str) -> Problem {
+        Problem {
+            path: path.into(),
+            what,
+        }
+    }
+}
+
+/// `name` in the directory at `directory`, both from the root.
+pub fn join(directory: &str, name: &str) -> String {
+    if directory.is_empty() {
+        name.into()
+    } else {
+        alloc::format!("{}/{}", directory, name)
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains('/')
+}
+
+/// Everything wrong under `path` on `fs`, and in `fs` itself if that's
+/// its root. Errors are only for `path` not being there.
+pub fn check(fs: &dyn FileSystem, path: &str) -> KernelResult<Vec<Problem>> {
+    let mut problems = Vec::new();
+    let mut inodes = BTreeSet::new();
+    let metadata = fs.metadata(path)?;
+    check_one(fs, path, &metadata, &mut inodes, &mut problems);
+
+    let mut directories = Vec::new();
+    if metadata.kind == Kind::Directory {
+        directories.push((String::from(path), metadata.size));
+    }
+    while let Some((directory, size)) = directories.pop() {
+        let entries = match fs.read_dir(&directory) {
+            Ok(entries) => entries,
+            Err(_) => {
+                problems.push(Problem::new(&directory, "can't be listed"));
+                continue;
+            }
+        };
+        if entries.len() as u64 != size {
+            problems.push(Problem::new(
+                &directory,
+                "size isn't how many entries it has",
+            ));
+        }
+        let twice = {
+            let mut names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+            names.sort_unstable();
+            names.windows(2).any(|pair| pair[0] == pair[1])
+        };
+        if twice {
+            problems.push(Problem::new(&directory, "has the same name twice"));
+        }
+
+        for entry in entries {
+            let path = join(&directory, &entry.name);
+            if !valid_name(&entry.name) {
+                problems.push(Problem::new(&path, "has a name no path gets to"));
+                continue;
+            }
+            match fs.metadata(&path) {
+                Ok(metadata) if metadata == entry.metadata => {}
+                Ok(_) => problems.push(Problem::new(&path, "isn't what its directory lists")),
+                Err(_) => {
+                    problems.push(Problem::new(&path, "is listed but isn't there"));
+                    continue;
+                }
+            }
+            check_one(fs, &path, &entry.metadata, &mut inodes, &mut problems);
+            if entry.metadata.kind == Kind::Directory {
+                directories.push((path, entry.metadata.size));
+            }
+        }
+    }
+
+    if path.is_empty() {
+        problems.extend(fs.check());
+    }
+    Ok(problems)
+}
+
+/// What there is to check about `path` by itself.
+fn check_one(
+    fs: &dyn FileSystem,
+    path: &str,
+    metadata: &Metadata,
+    inodes: &mut BTreeSet<u64>,
+    problems: &mut Vec<Problem>,
+) {
+    if metadata.inode != 0 && !inodes.insert(metadata.inode) {
+        problems.push(Problem::new(path, "has an inode something else has"));
+    }
+    if metadata.created_ns != 0 && metadata.modified_ns < metadata.created_ns {
+        problems.push(Problem::new(path, "was modified before it was created"));
+    }
+    if metadata.kind == Kind::File {
+        match readable_bytes(fs, path, metadata.size) {
+            Ok(size) if size == metadata.size => {}
+            Ok(_) => problems.push(Problem::new(path, "reads back a different size")),
+            Err(_) => problems.push(Problem::new(path, "can't be read")),
+        }
+    }
+}
+
+/// How much reading the file at `path` gives, up to a little past
+/// `size`.
+fn readable_bytes(fs: &dyn FileSystem, path: &str, size: u64) -> KernelResult<u64> {
+    let mut buffer = vec![0; 4096];
+    let mut total = 0;
+    while total <= size {
+        match fs.read(path, total, &mut buffer)? {
+            0 => break,
+            count => total += count as u64,
+        }
+    }
+    Ok(total)
+}
+
+/// `fsck [PATH]`, on the file system `PATH` is in, from there down.
+pub(super) fn fsck_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    let path = super::absolute(if args.is_empty() { "/" } else { args });
+    let path = super::normalize(&path).map_err(|error| fail(out, error))?;
+    cache::sync().map_err(|error| fail(out, error))?;
+    let (fs, rest, _) = super::resolve(&path).map_err(|error| fail(out, error))?;
+    let problems = check(&*fs, &rest).map_err(|error| fail(out, error))?;
+
+    // Where `fs` is mounted, to show whole paths.
+    let point = path[..path.len() - rest.len()].trim_end_matches('/');
+    for problem in &problems {
+        let _ = writeln!(out, "{}/{}: {}", point, problem.path, problem.what);
+    }
+    if problems.is_empty() {
+        let _ = writeln!(out, "{}: clean", path);
+        Ok(())
+    } else {
+        let _ = writeln!(out, "{}: {} problems", path, problems.len());
+        Err(CommandFailed)
+    }
+}
+
+fn fail(out: &mut dyn fmt::Write, error: KernelError) -> CommandFailed {
+    let _ = writeln!(out, "fsck: {}", error.as_str());
+    CommandFailed
+}
@@ -0,0 +1,470 @@
+//! ISO 9660, what CDs are formatted with, read-only.
+//!
+//! The primary volume descriptor, in the 2048 byte sector 16 on, has
+//! the logical block size and the root directory's record. A directory
+//! is an extent of records, one per entry, that don't cross logical
+//! blocks: a record length of 0 means the rest of the block is empty.
+//! Names are upper case with a `;1` version on the end, which is taken
+//! off and the rest lower cased, like Linux does.
+//!
+//! With the Rock Ridge extensions, which `mkisofs -R` and friends add,
+//! records carry more in their system use area, as SUSP entries:
+//! `NM` for the real name, and `TF` for when it was made and changed.
+//! `CE` continues the area elsewhere on the disc. The root's `.`
+//! record starts with `SP` if they're there. Relocated deep directories
+//! (`CL`, `RE`) aren't followed.
+//!
+//! A file's inode is its extent's first block, 0 for an empty one.
+use super::{DirEntry, FileSystem, Kind, Metadata};
+use crate::block::{self, BlockDevice};
+use crate::error::{KernelError, KernelResult};
+use crate::time::DateTime;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const SECTOR_SIZE: u64 = 2048;
+/// Where the volume descriptors start, in sectors.
+const FIRST_DESCRIPTOR: u64 = 16;
+const PRIMARY_DESCRIPTOR: u8 = 1;
+const TERMINATOR: u8 = 255;
+/// Descriptors to look through before giving up on a terminator.
+const MAX_DESCRIPTORS: u64 = 32;
+
+const FLAG_DIRECTORY: u8 = 1 << 1;
+/// Continuation areas to follow for one record, in case they loop.
+const MAX_CONTINUATIONS: usize = 8;
+
+/// What we use of a directory record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Record {
+    name: String,
+    /// Logical block its contents start at.
+    extent: u32,
+    size: u32,
+    directory: bool,
+    created_ns: u64,
+    modified_ns: u64,
+    /// A directory Rock Ridge moved here from deeper down, `RE`.
+    relocated: bool,
+}
+
+pub struct Iso9660 {
+    device: Arc<dyn BlockDevice>,
+    block_size: u64,
+    root: Record,
+    /// Bytes at the start of every system use area to skip, if there
+    /// are Rock Ridge entries.
+    rock_ridge: Option<usize>,
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    let mut le = [0; 4];
+    le.copy_from_slice(&bytes[at..at + 4]);
+    u32::from_le_bytes(le)
+}
+
+/// The 7 byte form of a date: years since 1900, month, day, hour,
+/// minute, second, and the time zone in quarter hours east of UTC.
+/// 0 if it isn't one.
+fn short_date(bytes: &[u8]) -> u64 {
+    let date = DateTime {
+        year: 1900 + u16::from(bytes[0]),
+        month: bytes[1],
+        day: bytes[2],
+        hour: bytes[3],
+        minute: bytes[4],
+        second: bytes[5],
+    };
+    if !(1..=12).contains(&date.month) || date.day == 0 {
+        return 0;
+    }
+    let zone = i64::from(bytes[6] as i8) * 15 * 60;
+    (date.unix_time() as i64 - zone).max(0) as u64 * 1_000_000_000
+}
+
+/// `FILE.TXT;1` as `file.txt`.
+fn plain_name(identifier: &[u8]) -> String {
+    let name = match identifier.iter().position(|&byte| byte == b';') {
+        Some(version) => &identifier[..version],
+        None => identifier,
+    };
+    let name = name.strip_suffix(b".").unwrap_or(name);
+    name.iter()
+        .map(|&byte| char::from(byte.to_ascii_lowercase()))
+        .collect()
+}
+
+impl Iso9660 {
+    /// The file system on `device`. `InvalidData` if there isn't a
+    /// primary volume descriptor where there should be.
+    pub fn new(device: Arc<dyn BlockDevice>) -> KernelResult<Iso9660> {
+        let mut descriptor = vec![0; SECTOR_SIZE as usize];
+        let mut sector = FIRST_DESCRIPTOR;
+        loop {
+            block::read_bytes(&*device, sector * SECTOR_SIZE, &mut descriptor)?;
+            if &descriptor[1..6] != b"CD001" || descriptor[0] == TERMINATOR {
+                return Err(KernelError::InvalidData);
+            }
+            if descriptor[0] == PRIMARY_DESCRIPTOR {
+                break;
+            }
+            sector += 1;
+            if sector == FIRST_DESCRIPTOR + MAX_DESCRIPTORS {
+                return Err(KernelError::InvalidData);
+            }
+        }
+        let block_size = u64::from(u16_at(&descriptor, 128));
+        if !block_size.is_power_of_two() || block_size < 512 {
+            return Err(KernelError::InvalidData);
+        }
+        let mut fs = Iso9660 {
+            device,
+            block_size,
+            root: Record {
+                name: String::new(),
+                extent: 0,
+                size: 0,
+                directory: true,
+                created_ns: 0,
+                modified_ns: 0,
+                relocated: false,
+            },
+            rock_ridge: None,
+        };
+        fs.root = fs.record(&descriptor[156..190])?;
+        fs.root.name = String::new();
+
+        // `SP` in the root's `.` says there's Rock Ridge, and how much
+        // to skip.
+        let mut first = vec![0; 255];
+        let start = u64::from(fs.root.extent) * block_size;
+        block::read_bytes(&*fs.device, start, &mut first[..1])?;
+        let len = usize::from(first[0]);
+        if len < 34 {
+            return Err(KernelError::InvalidData);
+        }
+        block::read_bytes(&*fs.device, start, &mut first[..len])?;
+        let system_use = &first[34..len];
+        if system_use.len() >= 7 && &system_use[..2] == b"SP" && system_use[4..6] == [0xBE, 0xEF] {
+            fs.rock_ridge = Some(usize::from(system_use[6]));
+        }
+        Ok(fs)
+    }
+
+    /// Parse the directory record `bytes`, with its Rock Ridge entries
+    /// if there are any.
+    fn record(&self, bytes: &[u8]) -> KernelResult<Record> {
+        if bytes.len() < 34 || bytes.len() < 33 + usize::from(bytes[32]) {
+            return Err(KernelError::InvalidData);
+        }
+        let name_len = usize::from(bytes[32]);
+        let identifier = &bytes[33..33 + name_len];
+        let recorded = short_date(&bytes[18..25]);
+        let mut record = Record {
+            name: plain_name(identifier),
+            extent: u32_at(bytes, 2),
+            size: u32_at(bytes, 10),
+            directory: bytes[25] & FLAG_DIRECTORY != 0,
+            created_ns: recorded,
+            modified_ns: recorded,
+            relocated: false,
+        };
+        if let Some(skip) = self.rock_ridge {
+            // Padded to an even length before it.
+            let start = 33 + name_len + (1 - name_len % 2) + skip;
+            if start < bytes.len() {
+                self.rock_ridge_entries(&bytes[start..], &mut record)?;
+            }
+        }
+        Ok(record)
+    }
+
+    /// Go through the SUSP entries in `area` and any areas it continues
+    /// in, for what `record` can use.
+    fn rock_ridge_entries(&self, area: &[u8], record: &mut Record) -> KernelResult<()> {
+        let mut area = area.to_vec();
+        let mut name: Option<String> = None;
+        for _ in 0..MAX_CONTINUATIONS {
+            let mut continuation = None;
+            let mut at = 0;
+            while at + 4 <= area.len() {
+                let len = usize::from(area[at + 2]);
+                if len < 4 || at + len > area.len() {
+                    break;
+                }
+                let data = &area[at + 4..at + len];
+                match &area[at..at + 2] {
+                    b"NM" if !data.is_empty() => {
+                        // `.` and `..` have flags and no name.
+                        if data[0] & 0b110 == 0 {
+                            let part = data[1..].iter().map(|&byte| char::from(byte));
+                            name.get_or_insert_with(String::new).extend(part);
+                        }
+                    }
+                    b"TF" if !data.is_empty() => self.timestamps(data, record),
+                    b"RE" => record.relocated = true,
+                    b"CE" if data.len() >= 24 => {
+                        let block = u64::from(u32_at(data, 0));
+                        let offset = u64::from(u32_at(data, 8));
+                        let size = u32_at(data, 16) as usize;
+                        continuation = Some((block * self.block_size + offset, size));
+                    }
+                    b"ST" => break,
+                    _ => {}
+                }
+                at += len;
+            }
+            match continuation {
+                Some((position, size)) => {
+                    area = vec![0; size];
+                    block::read_bytes(&*self.device, position, &mut area)?;
+                }
+                None => break,
+            }
+        }
+        if let Some(name) = name {
+            record.name = name;
+        }
+        Ok(())
+    }
+
+    /// `TF`: the times it has are in order, creation first, each 7 or
+    /// 17 bytes.
+    fn timestamps(&self, data: &[u8], record: &mut Record) {
+        const CREATION: u8 = 1 << 0;
+        const MODIFY: u8 = 1 << 1;
+        const LONG_FORM: u8 = 1 << 7;
+        let flags = data[0];
+        // The 17 byte form is text, and isn't what anyone writes.
+        if flags & LONG_FORM != 0 {
+            return;
+        }
+        let mut times = data[1..].chunks_exact(7);
+        if flags & CREATION != 0 {
+            if let Some(time) = times.next() {
+                record.created_ns = short_date(time);
+            }
+        }
+        if flags & MODIFY != 0 {
+            if let Some(time) = times.next() {
+                record.modified_ns = short_date(time);
+            }
+        }
+    }
+
+    /// What's in `directory`, without `.` and `..`.
+    fn entries(&self, directory: &Record) -> KernelResult<Vec<Record>> {
+        let mut contents = vec![0; directory.size as usize];
+        let start = u64::from(directory.extent) * self.block_size;
+        block::read_bytes(&*self.device, start, &mut contents)?;
+        let mut entries = Vec::new();
+        let mut at = 0;
+        while at < contents.len() {
+            let len = usize::from(contents[at]);
+            if len == 0 {
+                // The rest of the block is padding.
+                let block_size = self.block_size as usize;
+                at = (at / block_size + 1) * block_size;
+                continue;
+            }
+            if at + len > contents.len() {
+                return Err(KernelError::InvalidData);
+            }
+            let bytes = &contents[at..at + len];
+            at += len;
+            // `.` and `..` are the one byte names 0 and 1.
+            if bytes.len() > 33 && bytes[32] == 1 && bytes[33] <= 1 {
+                continue;
+            }
+            let record = self.record(bytes)?;
+            if !record.relocated {
+                entries.push(record);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// The record for `path`, from the root.
+    fn lookup(&self, path: &str) -> KernelResult<Record> {
+        let mut record = self.root.clone();
+        for name in super::components(path) {
+            if !record.directory {
+                return Err(KernelError::NotFound);
+            }
+            record = self
+                .entries(&record)?
+                .into_iter()
+                .find(|entry| entry.name == name)
+                .ok_or(KernelError::NotFound)?;
+        }
+        Ok(record)
+    }
+
+    fn metadata_of(&self, record: &Record) -> KernelResult<Metadata> {
+        let (kind, size) = if record.directory {
+            (Kind::Directory, self.entries(record)?.len() as u64)
+        } else {
+            (Kind::File, u64::from(record.size))
+        };
+        Ok(Metadata {
+            kind,
+            inode: if record.size == 0 {
+                0
+            } else {
+                u64::from(record.extent)
+            },
+            size,
+            created_ns: record.created_ns,
+            modified_ns: record.modified_ns,
+        })
+    }
+}
+
+impl FileSystem for Iso9660 {
+    fn name(&self) -> &'static str {
+        "iso9660"
+    }
+
+    fn metadata(&self, path: &str) -> KernelResult<Metadata> {
+        self.metadata_of(&self.lookup(path)?)
+    }
+
+    fn read(&self, path: &str, offset: u64, buffer: &mut [u8]) -> KernelResult<usize> {
+        let record = self.lookup(path)?;
+        if record.directory {
+            return Err(KernelError::InvalidArgument);
+        }
+        let size = u64::from(record.size);
+        let count = size.saturating_sub(offset).min(buffer.len() as u64) as usize;
+        let start = u64::from(record.extent) * self.block_size + offset;
+        if count > 0 {
+            block::read_bytes(&*self.device, start, &mut buffer[..count])?;
+        }
+        Ok(count)
+    }
+
+    fn read_dir(&self, path: &str) -> KernelResult<Vec<DirEntry>> {
+        let record = self.lookup(path)?;
+        if !record.directory {
+            return Err(KernelError::InvalidArgument);
+        }
+        self.entries(&record)?
+            .into_iter()
+            .map(|entry| {
+                Ok(DirEntry {
+                    metadata: self.metadata_of(&entry)?,
+                    name: entry.name,
+                })
+            })
+            .collect()
+    }
+}
+
+#[test_case]
+fn test_reads_rock_ridge_image() {
+    use crate::block::RamDisk;
+
+    /// A directory record, with `system_use` after the name.
+    fn record(extent: u32, size: u32, directory: bool, name: &[u8], system_use: &[u8]) -> Vec<u8> {
+        let start = 33 + name.len() + (1 - name.len() % 2);
+        let mut bytes = vec![0; start + system_use.len()];
+        bytes[0] = bytes.len() as u8;
+        bytes[2..6].copy_from_slice(&extent.to_le_bytes());
+        bytes[6..10].copy_from_slice(&extent.to_be_bytes());
+        bytes[10..14].copy_from_slice(&size.to_le_bytes());
+        bytes[14..18].copy_from_slice(&size.to_be_bytes());
+        // 2020-01-02 03:04:05, an hour east of UTC.
+        bytes[18..25].copy_from_slice(&[120, 1, 2, 3, 4, 5, 4]);
+        bytes[25] = if directory { FLAG_DIRECTORY } else { 0 };
+        bytes[32] = name.len() as u8;
+        bytes[33..33 + name.len()].copy_from_slice(name);
+        bytes[start..].copy_from_slice(system_use);
+        bytes
+    }
+    fn sector(image: &mut [u8], index: usize) -> &mut [u8] {
+        &mut image[index * 2048..(index + 1) * 2048]
+    }
+    fn directory(image: &mut [u8], index: usize, records: &[Vec<u8>]) {
+        let mut at = 0;
+        for record in records {
+            sector(image, index)[at..at + record.len()].copy_from_slice(record);
+            at += record.len();
+        }
+    }
+
+    let mut image = vec![0; 24 * 2048];
+    let primary = sector(&mut image, 16);
+    primary[..7].copy_from_slice(b"\x01CD001\x01");
+    primary[128..130].copy_from_slice(&2048u16.to_le_bytes());
+    primary[156..190].copy_from_slice(&record(18, 2048, true, &[0], &[]));
+    sector(&mut image, 17)[..7].copy_from_slice(b"\xffCD001\x01");
+
+    let sp = b"SP\x07\x01\xbe\xef\x00";
+    let mut hello_su = b"NM\x0e\x01\x00Hello.txt".to_vec();
+    // Changed 2021-06-07 08:09:10 UTC.
+    hello_su.extend_from_slice(b"TF\x0c\x01\x02\x79\x06\x07\x08\x09\x0a\x00");
+    directory(
+        &mut image,
+        18,
+        &[
+            record(18, 2048, true, &[0], sp),
+            record(18, 2048, true, &[1], &[]),
+            record(20, 2048 + 5, false, b"HELLO.TXT;1", &hello_su),
+            record(19, 2048, true, b"SUB", &[]),
+        ],
+    );
+    directory(
+        &mut image,
+        19,
+        &[
+            record(19, 2048, true, &[0], &[]),
+            record(18, 2048, true, &[1], &[]),
+            record(22, 3, false, b"A.TXT;1", &[]),
+        ],
+    );
+    sector(&mut image, 20)
+        .iter_mut()
+        .for_each(|byte| *byte = b'x');
+    sector(&mut image, 21)[..5].copy_from_slice(b"hello");
+    sector(&mut image, 22)[..3].copy_from_slice(b"abc");
+
+    // Blocks smaller than the sectors, like an image on a disk.
+    let fs = Iso9660::new(Arc::new(RamDisk::new(512, image))).unwrap();
+    assert_eq!(fs.rock_ridge, Some(0));
+    let names: Vec<String> = fs
+        .read_dir("")
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    assert_eq!(names, ["Hello.txt", "sub"]);
+
+    let hello = fs.metadata("Hello.txt").unwrap();
+    assert_eq!(
+        (hello.kind, hello.size, hello.inode),
+        (Kind::File, 2048 + 5, 20)
+    );
+    let recorded = DateTime::from_unix(hello.created_ns / 1_000_000_000);
+    assert_eq!((recorded.year, recorded.hour), (2020, 2));
+    let changed = DateTime::from_unix(hello.modified_ns / 1_000_000_000);
+    assert_eq!((changed.year, changed.month, changed.second), (2021, 6, 10));
+
+    let mut buffer = [0; 8];
+    assert_eq!(fs.read("Hello.txt", 2048 - 3, &mut buffer), Ok(8));
+    assert_eq!(&buffer, b"xxxhello");
+    assert_eq!(fs.read("Hello.txt", 2048 + 5, &mut buffer), Ok(0));
+    assert_eq!(fs.read("sub/a.txt", 0, &mut buffer), Ok(3));
+    assert_eq!(&buffer[..3], b"abc");
+    assert_eq!(fs.metadata("sub").map(|sub| sub.size), Ok(1));
+    assert_eq!(fs.metadata("HELLO.TXT;1"), Err(KernelError::NotFound));
+    assert_eq!(
+        fs.write("sub/a.txt", 0, b"x"),
+        Err(KernelError::PermissionDenied)
+    );
+}
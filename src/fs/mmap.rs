@@ -0,0 +1,492 @@
+//! Files mapped into user memory.
+//!
+//! `map` finds room for a file between `MAP_START` and `MAP_END` and
+//! remembers it, but maps nothing yet. The first time a page of it is
+//! touched the page fault handler calls `fault`, which maps it in from
+//! the page cache:
+//!
+//! - a shared mapping gets the cache's own frame, read-only until it's
+//!   written to. Then the cache page is marked dirty and is written
+//!   back like any other.
+//! - a private one gets the cache's frame read-only too, and the first
+//!   write to a page copies it to a frame of its own, which the file
+//!   never sees.
+//!
+//! Past the end of the file it's zeros. While any of a file is mapped
+//! in it can't be removed, renamed or unmounted, see `cache`.
+//!
+//! `map_anonymous` is memory that isn't any file's, a zeroed frame of
+//! its own for each page as it's touched.
+//!
+//! There's the one address space, so a `Mapping` is for whoever holds
+//! it, and dropping it unmaps it. The `mmap` syscall only maps
+//! anonymous memory, there are no file descriptors to map files by.
+//! It hands the mapping to the thread running the program with
+//! `Mapping::give_to`, so it lasts until `munmap`, or until the program
+//! is done and `unmap_thread` gets what's left.
+use super::cache::{self, PAGE_SIZE};
+use super::{FileSystem, Kind};
+use crate::error::{KernelError, KernelResult};
+use crate::memory::{self, frame_allocator, paging};
+use crate::scheduler::ThreadId;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::{mem, ptr, slice};
+use spin::Mutex;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+
+/// Where mappings go, above where `program` puts programs and below
+/// the top of user memory.
+pub const MAP_START: u64 = 0x7000_0000_0000;
+pub const MAP_END: u64 = 0x7f00_0000_0000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MapOptions {
+    pub writable: bool,
+    pub executable: bool,
+    /// Writes go to the file, rather than to copies only this mapping
+    /// sees.
+    pub shared: bool,
+}
+
+/// The file an area maps, see `map`.
+struct File {
+    fs: Arc<dyn FileSystem>,
+    path: String,
+    inode: u64,
+    /// The page of the file it starts at.
+    first: u64,
+}
+
+struct Area {
+    /// `None` for anonymous memory.
+    file: Option<File>,
+    pages: u64,
+    options: MapOptions,
+    /// Pages, counted from the start of the area, that are private
+    /// copies rather than the cache's. All of them, for anonymous
+    /// memory.
+    copies: BTreeSet<u64>,
+    /// Set by `Mapping::give_to`.
+    owner: Option<ThreadId>,
+}
+
+/// By start address.
+static AREAS: Mutex<BTreeMap<u64, Area>> = Mutex::new(BTreeMap::new());
+
+/// A file mapped by `map`, unmapped again when dropped.
+#[derive(Debug)]
+pub struct Mapping {
+    start: u64,
+    size: u64,
+}
+
+impl Mapping {
+    pub fn start(&self) -> VirtAddr {
+        VirtAddr::new(self.start)
+    }
+
+    /// In bytes, as asked for.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// What's mapped, for the kernel to read. Pages come in as they're
+    /// touched.
+    ///
+    /// # Safety
+    ///
+    /// Nothing can write to it while the slice is around, through this
+    /// mapping or a shared one of the same file.
+    pub unsafe fn as_slice(&self) -> &[u8] {
+        slice::from_raw_parts(self.start as *const u8, self.size as usize)
+    }
+}
+
+impl Mapping {
+    /// Leave it mapped for `thread`'s program, which only has its
+    /// address to go by: until `unmap`, or `unmap_thread` once the
+    /// program's done.
+    pub fn give_to(self, thread: ThreadId) -> VirtAddr {
+        if let Some(area) = AREAS.lock().get_mut(&self.start) {
+            area.owner = Some(thread);
+        }
+        let start = self.start();
+        mem::forget(self);
+        start
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if let Some(area) = remove(self.start) {
+            unsafe { unmap_area(self.start, &area) };
+        }
+    }
+}
+
+fn remove(start: u64) -> Option<Area> {
+    let mut areas = AREAS.lock();
+    let area = areas.remove(&start);
+    // Empty, it would still have a node on the heap.
+    if areas.is_empty() {
+        *areas = BTreeMap::new();
+    }
+    area
+}
+
+/// Map `size` bytes of the file at `path` from `offset` on, which has
+/// to be a whole number of pages in. Nothing is read until it's used.
+/// `PermissionDenied` for a shared writable mapping of a file on a
+/// read-only mount, `NoSpace` if there's no room left between
+/// `MAP_START` and `MAP_END`.
+pub fn map(path: &str, offset: u64, size: u64, options: MapOptions) -> KernelResult<Mapping> {
+    if offset % PAGE_SIZE != 0 || size == 0 {
+        return Err(KernelError::InvalidArgument);
+    }
+    let (fs, path, mount) = super::resolve(path)?;
+    if options.shared && options.writable && mount.read_only {
+        return Err(KernelError::PermissionDenied);
+    }
+    let metadata = fs.metadata(&path)?;
+    if metadata.kind != Kind::File {
+        return Err(KernelError::InvalidArgument);
+    }
+    // The page cache only has files it can tell apart.
+    if metadata.inode == 0 {
+        return Err(KernelError::Unsupported);
+    }
+    let file = File {
+        fs,
+        path,
+        inode: metadata.inode,
+        first: offset / PAGE_SIZE,
+    };
+    insert(Some(file), size, options)
+}
+
+/// Map `size` bytes of zeroed memory. `shared` makes no difference,
+/// nothing else can see it.
+pub fn map_anonymous(size: u64, options: MapOptions) -> KernelResult<Mapping> {
+    if size == 0 {
+        return Err(KernelError::InvalidArgument);
+    }
+    insert(None, size, options)
+}
+
+fn insert(file: Option<File>, size: u64, options: MapOptions) -> KernelResult<Mapping> {
+    let pages = size
+        .checked_add(PAGE_SIZE - 1)
+        .ok_or(KernelError::NoSpace)?
+        / PAGE_SIZE;
+    let mut areas = AREAS.lock();
+    let start = find_room(&areas, pages)?;
+    areas.insert(
+        start,
+        Area {
+            file,
+            pages,
+            options,
+            copies: BTreeSet::new(),
+            owner: None,
+        },
+    );
+    Ok(Mapping { start, size })
+}
+
+/// Unmap what `thread` was given at `start`, which has to be all of
+/// it, `size` bytes rounded up to pages. `NotFound` if it wasn't given
+/// anything there, `InvalidArgument` for only some of it.
+pub fn unmap(start: VirtAddr, size: u64, thread: ThreadId) -> KernelResult<()> {
+    let start = start.as_u64();
+    {
+        let areas = AREAS.lock();
+        let area = areas
+            .get(&start)
+            .filter(|area| area.owner == Some(thread))
+            .ok_or(KernelError::NotFound)?;
+        if size.saturating_add(PAGE_SIZE - 1) / PAGE_SIZE != area.pages {
+            return Err(KernelError::InvalidArgument);
+        }
+    }
+    if let Some(area) = remove(start) {
+        unsafe { unmap_area(start, &area) };
+    }
+    Ok(())
+}
+
+/// Unmap everything `thread` was given, once its program is done.
+pub fn unmap_thread(thread: ThreadId) {
+    let given: Vec<u64> = AREAS
+        .lock()
+        .iter()
+        .filter(|(_, area)| area.owner == Some(thread))
+        .map(|(&start, _)| start)
+        .collect();
+    for start in given {
+        if let Some(area) = remove(start) {
+            unsafe { unmap_area(start, &area) };
+        }
+    }
+}
+
+/// The lowest address `pages` pages fit at, with an unmapped page
+/// after each area so running off the end of one faults.
+fn find_room(areas: &BTreeMap<u64, Area>, pages: u64) -> KernelResult<u64> {
+    let len = pages.checked_mul(PAGE_SIZE).ok_or(KernelError::NoSpace)?;
+    let mut candidate = MAP_START;
+    for (&start, area) in areas.iter() {
+        if candidate.saturating_add(len) <= start {
+            break;
+        }
+        candidate = start + (area.pages + 1) * PAGE_SIZE;
+    }
+    match candidate.checked_add(len) {
+        Some(end) if end <= MAP_END => Ok(candidate),
+        _ => Err(KernelError::NoSpace),
+    }
+}
+
+fn page_flags(options: MapOptions, writable: bool) -> PageTableFlags {
+    let mut flags = if options.executable {
+        PageTableFlags::PRESENT
+    } else {
+        paging::data_flags(false)
+    };
+    if writable {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    flags | PageTableFlags::USER_ACCESSIBLE
+}
+
+/// A fresh frame with a copy of page `index` of `file`'s area, which
+/// is at `page`. If that's mapped it's copied from there, it may have
+/// been written to, otherwise it's read from the file. All zeros
+/// without a file.
+unsafe fn copy_of(file: Option<&File>, index: u64, page: Page) -> KernelResult<PhysFrame> {
+    let frame = frame_allocator::allocate_frame()?;
+    let result = memory::physical_to_virtual(frame.start_address(), PAGE_SIZE)
+        .map_err(KernelError::from)
+        .and_then(|address| {
+            let contents = slice::from_raw_parts_mut(address.as_mut_ptr(), PAGE_SIZE as usize);
+            if memory::translate(page.start_address()).is_some() {
+                let from = page.start_address().as_ptr::<u8>();
+                ptr::copy_nonoverlapping(from, contents.as_mut_ptr(), contents.len());
+                return Ok(());
+            }
+            contents.iter_mut().for_each(|byte| *byte = 0);
+            match file {
+                Some(file) => {
+                    let offset = (file.first + index) * PAGE_SIZE;
+                    super::read_from(&file.fs, &file.path, offset, contents).map(|_| ())
+                }
+                None => Ok(()),
+            }
+        });
+    if let Err(error) = result {
+        frame_allocator::free_frame(frame)?;
+        return Err(error);
+    }
+    Ok(frame)
+}
+
+/// Map in the page at `address` if it's in a mapping, for the page
+/// fault handler and for `user` checking memory a syscall was handed.
+/// With `write` it's made writable too, copied first if the mapping's
+/// private. `NotFound` if `address` isn't in a mapping,
+/// `PermissionDenied` for a write to a read-only one.
+///
+/// It waits on locks and on file systems, so it mustn't be called
+/// from an interrupt handler, nor by anything holding the page cache
+/// or a file system's lock.
+pub fn fault(address: VirtAddr, write: bool) -> KernelResult<()> {
+    let mut areas = AREAS.lock();
+    let (&start, area) = areas
+        .range_mut(..=address.as_u64())
+        .next_back()
+        .ok_or(KernelError::NotFound)?;
+    let index = (address.as_u64() - start) / PAGE_SIZE;
+    if index >= area.pages {
+        return Err(KernelError::NotFound);
+    }
+    if write && !area.options.writable {
+        return Err(KernelError::PermissionDenied);
+    }
+    let page = Page::containing_address(VirtAddr::new(start + index * PAGE_SIZE));
+    let private = !area.options.shared;
+
+    unsafe {
+        let mapped = memory::translate(page.start_address());
+        let file = match (&area.file, mapped) {
+            // Someone else got to it first.
+            (_, Some(mapping)) if mapping.writable || !write => return Ok(()),
+            (Some(file), _) => file,
+            // Anonymous memory is mapped as writable as it'll get.
+            (None, _) => {
+                let zeros = copy_of(None, index, page)?;
+                let flags = page_flags(area.options, area.options.writable);
+                if let Err(error) = paging::map_to(page, zeros, flags) {
+                    frame_allocator::free_frame(zeros)?;
+                    return Err(error);
+                }
+                area.copies.insert(index);
+                return Ok(());
+            }
+        };
+        let file_page = file.first + index;
+        match mapped {
+            Some(_) if private => {
+                let copy = copy_of(Some(file), index, page)?;
+                paging::unmap(page)?;
+                cache::unmap_page(&file.fs, file.inode, file_page)?;
+                area.copies.insert(index);
+                paging::map_to(page, copy, page_flags(area.options, true))
+            }
+            Some(_) => {
+                cache::mark_dirty(&file.fs, file.inode, file_page)?;
+                paging::update_flags(page, page_flags(area.options, true))
+            }
+            None if write && private => {
+                let copy = copy_of(Some(file), index, page)?;
+                if let Err(error) = paging::map_to(page, copy, page_flags(area.options, true)) {
+                    frame_allocator::free_frame(copy)?;
+                    return Err(error);
+                }
+                area.copies.insert(index);
+                Ok(())
+            }
+            None => {
+                let metadata = cache::metadata(&file.fs, &file.path)?;
+                let frame = cache::map_page(&file.fs, &file.path, metadata, file_page, write)?;
+                let result = paging::map_to(page, frame, page_flags(area.options, write));
+                if result.is_err() {
+                    cache::unmap_page(&file.fs, file.inode, file_page)?;
+                }
+                result
+            }
+        }
+    }
+}
+
+/// Unmap what's mapped in of `area`, which is at `start`: copies are
+/// freed, the cache's pages are handed back.
+///
+/// # Safety
+///
+/// Nothing can be using the area anymore.
+unsafe fn unmap_area(start: u64, area: &Area) {
+    for index in 0..area.pages {
+        let page = Page::containing_address(VirtAddr::new(start + index * PAGE_SIZE));
+        let frame = match paging::unmap(page) {
+            Ok(frame) => frame,
+            Err(_) => continue,
+        };
+        let result = match &area.file {
+            Some(file) if !area.copies.contains(&index) => {
+                cache::unmap_page(&file.fs, file.inode, file.first + index)
+            }
+            _ => frame_allocator::free_frame(frame),
+        };
+        if let Err(error) = result {
+            crate::klog!(
+                Warn,
+                "unmapping {:#x}: {}",
+                page.start_address().as_u64(),
+                error.as_str()
+            );
+        }
+    }
+}
+
+#[test_case]
+fn test_private_and_shared_mappings() {
+    super::create("/mmap-test", Kind::File).unwrap();
+    let mut contents = alloc::vec![b'a'; PAGE_SIZE as usize];
+    contents.extend_from_slice(b"bcd");
+    super::write("/mmap-test", 0, &contents).unwrap();
+
+    // Read-only, and only from the second page on.
+    let read_only = map("/mmap-test", PAGE_SIZE, 8, MapOptions::default()).unwrap();
+    assert_eq!(read_only.start().as_u64() % PAGE_SIZE, 0);
+    assert_eq!(unsafe { read_only.as_slice() }, b"bcd\0\0\0\0\0");
+    assert_eq!(
+        fault(read_only.start(), true),
+        Err(KernelError::PermissionDenied)
+    );
+    // Mapped in, so it stays put.
+    assert_eq!(super::unlink("/mmap-test"), Err(KernelError::Busy));
+
+    let private = MapOptions {
+        writable: true,
+        ..MapOptions::default()
+    };
+    let copy = map("/mmap-test", 0, PAGE_SIZE + 3, private).unwrap();
+    let shared = MapOptions {
+        shared: true,
+        ..private
+    };
+    let file = map("/mmap-test", 0, PAGE_SIZE + 3, shared).unwrap();
+    assert!(file.start().as_u64() >= copy.start().as_u64() + 3 * PAGE_SIZE);
+    unsafe {
+        *copy.start().as_mut_ptr::<u8>() = b'x';
+        *file.start().as_mut_ptr::<u8>().add(PAGE_SIZE as usize) = b'y';
+        assert_eq!(copy.as_slice()[0], b'x');
+        assert_eq!(file.as_slice()[0], b'a');
+        // The same cache page as the shared mapping's.
+        assert_eq!(read_only.as_slice()[0], b'y');
+    }
+
+    // What the shared mapping wrote gets to the file, the copy doesn't.
+    drop((read_only, copy, file));
+    let mut buffer = [0; 2];
+    assert_eq!(super::read("/mmap-test", PAGE_SIZE - 1, &mut buffer), Ok(2));
+    assert_eq!(&buffer, b"ay");
+    assert_eq!(
+        fault(VirtAddr::new(MAP_START), false),
+        Err(KernelError::NotFound)
+    );
+    super::unlink("/mmap-test").unwrap();
+}
+
+#[test_case]
+fn test_anonymous_mappings() {
+    use crate::scheduler;
+
+    let options = MapOptions {
+        writable: true,
+        ..MapOptions::default()
+    };
+    let mapping = map_anonymous(PAGE_SIZE + 1, options).unwrap();
+    unsafe {
+        assert!(mapping.as_slice().iter().all(|&byte| byte == 0));
+        *mapping.start().as_mut_ptr::<u8>().add(PAGE_SIZE as usize) = b'z';
+        assert_eq!(mapping.as_slice()[PAGE_SIZE as usize], b'z');
+    }
+    let thread = scheduler::current();
+    let start = mapping.give_to(thread);
+    // Still there, until it's unmapped all at once by whoever has it.
+    assert_eq!(
+        unsafe { *start.as_ptr::<u8>().add(PAGE_SIZE as usize) },
+        b'z'
+    );
+    assert_eq!(
+        unmap(start, PAGE_SIZE, thread),
+        Err(KernelError::InvalidArgument)
+    );
+    assert_eq!(
+        unmap(start + PAGE_SIZE, PAGE_SIZE, thread),
+        Err(KernelError::NotFound)
+    );
+    unmap(start, 2 * PAGE_SIZE, thread).unwrap();
+    assert_eq!(memory::translate(start + PAGE_SIZE), None);
+
+    // And what's left over when it's done.
+    let given = map_anonymous(1, options).unwrap().give_to(thread);
+    unsafe { *given.as_mut_ptr::<u8>() = 1 };
+    unmap_thread(thread);
+    assert_eq!(memory::translate(given), None);
+    assert_eq!(fault(given, false), Err(KernelError::NotFound));
+}
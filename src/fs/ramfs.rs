@@ -0,0 +1,296 @@
+//! A file system that's only in memory, what `/` is until there's a
+//! disk.
+//!
+//! It's a tree of nodes behind one lock, directories keeping their
+//! entries sorted by name. Files grow as they're written, with zeros
+//! in any gap a write past the end leaves. A directory counts as
+//! modified when something is added to it or taken out. Inodes are
+//! handed out in order, the root's is 1.
+//!
+//! It's all in memory already, so it stays out of the page cache.
+//!
+//! `fsck` looks at the inodes too: each one has to have been handed
+//! out, and the root has to have 1.
+use super::fsck::{self, Problem};
+use super::{DirEntry, FileSystem, Kind, Metadata};
+use crate::error::{KernelError, KernelResult};
+use crate::time;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+enum Contents {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Node>),
+}
+
+struct Node {
+    contents: Contents,
+    inode: u64,
+    created_ns: u64,
+    modified_ns: u64,
+}
+
+impl Node {
+    fn new(kind: Kind, inode: u64) -> Node {
+        let now = time::unix_time_ns();
+        Node {
+            contents: match kind {
+                Kind::File => Contents::File(Vec::new()),
+                Kind::Directory => Contents::Directory(BTreeMap::new()),
+            },
+            inode,
+            created_ns: now,
+            modified_ns: now,
+        }
+    }
+
+    fn metadata(&self) -> Metadata {
+        let (kind, size) = match &self.contents {
+            Contents::File(bytes) => (Kind::File, bytes.len() as u64),
+            Contents::Directory(entries) => (Kind::Directory, entries.len() as u64),
+        };
+        Metadata {
+            kind,
+            inode: self.inode,
+            size,
+            created_ns: self.created_ns,
+            modified_ns: self.modified_ns,
+        }
+    }
+
+    fn entries(&mut self) -> KernelResult<&mut BTreeMap<String, Node>> {
+        match &mut self.contents {
+            Contents::Directory(entries) => Ok(entries),
+            Contents::File(_) => Err(KernelError::InvalidArgument),
+        }
+    }
+}
+
+pub struct RamFs {
+    root: Mutex<Node>,
+    next_inode: AtomicU64,
+}
+
+impl RamFs {
+    /// An empty one, just the root directory.
+    pub fn new() -> RamFs {
+        RamFs {
+            root: Mutex::new(Node::new(Kind::Directory, 1)),
+            next_inode: AtomicU64::new(2),
+        }
+    }
+}
+
+impl Default for RamFs {
+    fn default() -> RamFs {
+        RamFs::new()
+    }
+}
+
+/// The node at `path` under `node`. `NotFound` if there's nothing
+/// there, or something on the way isn't a directory.
+fn walk<'a>(mut node: &'a mut Node, path: &str) -> KernelResult<&'a mut Node> {
+    for name in super::components(path) {
+        node = match &mut node.contents {
+            Contents::Directory(entries) => entries.get_mut(name).ok_or(KernelError::NotFound)?,
+            Contents::File(_) => return Err(KernelError::NotFound),
+        };
+    }
+    Ok(node)
+}
+
+/// `path` split into its directory and its name in there. The name is
+/// empty for the root.
+fn split(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(slash) => (&path[..slash], &path[slash + 1..]),
+        None => ("", path),
+    }
+}
+
+/// The directory `path` is in, as a directory, and its name there.
+/// `Busy` for the root, which can't be taken away or replaced.
+fn parent<'a, 'b>(
+    root: &'a mut Node,
+    path: &'b str,
+) -> KernelResult<(&'a mut BTreeMap<String, Node>, &'b str)> {
+    let (parent, name) = split(path);
+    if name.is_empty() {
+        return Err(KernelError::Busy);
+    }
+    let entries = walk(root, parent)?
+        .entries()
+        .map_err(|_| KernelError::NotFound)?;
+    Ok((entries, name))
+}
+
+impl FileSystem for RamFs {
+    fn name(&self) -> &'static str {
+        "ramfs"
+    }
+
+    fn cached(&self) -> bool {
+        false
+    }
+
+    fn check(&self) -> Vec<Problem> {
+        let root = self.root.lock();
+        let next_inode = self.next_inode.load(Ordering::Relaxed);
+        let mut problems = Vec::new();
+        if root.inode != 1 {
+            problems.push(Problem::new("", "isn't inode 1"));
+        }
+        let mut nodes = vec![(String::new(), &*root)];
+        while let Some((path, node)) = nodes.pop() {
+            if node.inode == 0 || node.inode >= next_inode {
+                problems.push(Problem::new(&path, "has an inode that wasn't handed out"));
+            }
+            if let Contents::Directory(entries) = &node.contents {
+                for (name, child) in entries {
+                    nodes.push((fsck::join(&path, name), child));
+                }
+            }
+        }
+        problems
+    }
+
+    fn metadata(&self, path: &str) -> KernelResult<Metadata> {
+        Ok(walk(&mut self.root.lock(), path)?.metadata())
+    }
+
+    fn read(&self, path: &str, offset: u64, buffer: &mut [u8]) -> KernelResult<usize> {
+        match &walk(&mut self.root.lock(), path)?.contents {
+            Contents::File(bytes) => {
+                let start = (offset as usize).min(bytes.len());
+                let count = buffer.len().min(bytes.len() - start);
+                buffer[..count].copy_from_slice(&bytes[start..start + count]);
+                Ok(count)
+            }
+            Contents::Directory(_) => Err(KernelError::InvalidArgument),
+        }
+    }
+
+    fn write(&self, path: &str, offset: u64, bytes: &[u8]) -> KernelResult<usize> {
+        let mut root = self.root.lock();
+        let node = walk(&mut root, path)?;
+        match &mut node.contents {
+            Contents::File(file) => {
+                let start = offset as usize;
+                let end = start.checked_add(bytes.len()).ok_or(KernelError::NoSpace)?;
+                if file.len() < end {
+                    file.resize(end, 0);
+                }
+                file[start..end].copy_from_slice(bytes);
+            }
+            Contents::Directory(_) => return Err(KernelError::InvalidArgument),
+        }
+        node.modified_ns = time::unix_time_ns();
+        Ok(bytes.len())
+    }
+
+    fn create(&self, path: &str, kind: Kind) -> KernelResult<()> {
+        let mut root = self.root.lock();
+        let (entries, name) = parent(&mut root, path).map_err(|error| match error {
+            // The root, which is always there.
+            KernelError::Busy => KernelError::AlreadyExists,
+            error => error,
+        })?;
+        if entries.contains_key(name) {
+            return Err(KernelError::AlreadyExists);
+        }
+        let inode = self.next_inode.fetch_add(1, Ordering::Relaxed);
+        entries.insert(name.to_string(), Node::new(kind, inode));
+        let (directory, _) = split(path);
+        walk(&mut root, directory)?.modified_ns = time::unix_time_ns();
+        Ok(())
+    }
+
+    fn read_dir(&self, path: &str) -> KernelResult<Vec<DirEntry>> {
+        let mut root = self.root.lock();
+        let entries = walk(&mut root, path)?.entries()?;
+        Ok(entries
+            .iter()
+            .map(|(name, node)| DirEntry {
+                name: name.clone(),
+                metadata: node.metadata(),
+            })
+            .collect())
+    }
+
+    fn remove(&self, path: &str) -> KernelResult<()> {
+        let mut root = self.root.lock();
+        let (entries, name) = parent(&mut root, path)?;
+        match entries
+            .get_mut(name)
+            .ok_or(KernelError::NotFound)?
+            .entries()
+        {
+            Ok(children) if !children.is_empty() => return Err(KernelError::Busy),
+            _ => {}
+        }
+        entries.remove(name);
+        let (directory, _) = split(path);
+        walk(&mut root, directory)?.modified_ns = time::unix_time_ns();
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> KernelResult<()> {
+        if from == to {
+            return Ok(());
+        }
+        if to.starts_with(from) && to.as_bytes().get(from.len()) == Some(&b'/') {
+            // Into itself.
+            return Err(KernelError::InvalidArgument);
+        }
+        let mut root = self.root.lock();
+        // Going by `to` first, nothing's been taken out yet if it's no
+        // good.
+        let (entries, name) = parent(&mut root, to)?;
+        if let Some(node) = entries.get(name) {
+            if let Contents::Directory(_) = node.contents {
+                return Err(KernelError::AlreadyExists);
+            }
+        }
+        let (entries, name) = parent(&mut root, from)?;
+        let node = entries.remove(name).ok_or(KernelError::NotFound)?;
+        let (entries, name) = parent(&mut root, to)?;
+        entries.insert(name.to_string(), node);
+        let now = time::unix_time_ns();
+        for path in [from, to].iter() {
+            walk(&mut root, split(path).0)?.modified_ns = now;
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_fsck() {
+    let fs = RamFs::new();
+    fs.create("a", Kind::File).unwrap();
+    fs.create("d", Kind::Directory).unwrap();
+    fs.create("d/b", Kind::File).unwrap();
+    fs.write("d/b", 3, b"xyz").unwrap();
+    assert_eq!(fsck::check(&fs, ""), Ok(Vec::new()));
+
+    let paths = |fs: &RamFs| -> Vec<String> {
+        let problems = fsck::check(fs, "").unwrap();
+        problems.into_iter().map(|problem| problem.path).collect()
+    };
+    // Two files with one inode, as a bad rename could leave.
+    let inode = walk(&mut fs.root.lock(), "a").unwrap().inode;
+    walk(&mut fs.root.lock(), "d/b").unwrap().inode = inode;
+    assert_eq!(paths(&fs), ["d/b"]);
+    // Only the file system knows this one's wrong.
+    walk(&mut fs.root.lock(), "d/b").unwrap().inode = 1000;
+    assert_eq!(paths(&fs), ["d/b"]);
+    // From further down, only what's there.
+    assert_eq!(
+        fsck::check(&fs, "d/b").map(|problems| problems.len()),
+        Ok(0)
+    );
+    assert_eq!(fsck::check(&fs, "e").err(), Some(KernelError::NotFound));
+}
@@ -0,0 +1,267 @@
+//! Futexes, waiting on a word of user memory.
+//!
+//! A user space mutex only needs the kernel when it's contended: the
+//! loser calls `FUTEX_WAIT` with the value it saw, and sleeps if the
+//! word still holds it. Whoever unlocks calls `FUTEX_WAKE` on the same
+//! address. Checking the word and queueing up happen with interrupts
+//! off, so a wake can't slip in between and get lost.
+//!
+//! Waiters are kept in one fixed size table, keyed by address and
+//! woken in the order they came. A waiter's thread blocks, see
+//! `scheduler::block`, and a wake unblocks it. One with a timeout is
+//! unblocked by the timer tick its deadline comes at.
+//!
+//! Reading the word can fault a page of an `mmap` in, which can't
+//! happen with the queue locked and interrupts off. So it's read once
+//! before, to fault it in, and again under the lock without faulting.
+use crate::error::{KernelError, KernelResult};
+use crate::scheduler::{self, ThreadId};
+use crate::time::{self, MILLISECONDS_PER_TICK};
+use crate::{latency, user};
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+/// Sleep if the word at the address still holds the value.
+pub const FUTEX_WAIT: u64 = 0;
+/// Wake up to the value's worth of waiters on the address.
+pub const FUTEX_WAKE: u64 = 1;
+
+const MAX_WAITERS: usize = 32;
+
+#[derive(Clone, Copy)]
+struct Waiter {
+    address: u64,
+    thread: ThreadId,
+    /// Order it came in, to wake the oldest first.
+    ticket: u64,
+    /// Tick count to give up at.
+    deadline: Option<u64>,
+    woken: bool,
+}
+
+struct Queue {
+    waiters: [Option<Waiter>; MAX_WAITERS],
+    next_ticket: u64,
+}
+
+static QUEUE: Mutex<Queue> = Mutex::new(Queue {
+    waiters: [None; MAX_WAITERS],
+    next_ticket: 0,
+});
+
+/// The futex syscall. `timeout_ms` is only for `FUTEX_WAIT`, 0 waits
+/// forever. Returns how many waiters got woken for `FUTEX_WAKE`, 0
+/// otherwise.
+pub fn sys_futex(address: VirtAddr, op: u64, value: u32, timeout_ms: u64) -> KernelResult<usize> {
+    match op {
+        FUTEX_WAIT => {
+            let timeout = if timeout_ms == 0 {
+                None
+            } else {
+                Some(timeout_ms)
+            };
+            wait(address, value, timeout).map(|()| 0)
+        }
+        FUTEX_WAKE => Ok(wake(address, value as usize)),
+        _ => Err(KernelError::InvalidArgument),
+    }
+}
+
+/// Sleep until woken, if the word at `address` is `expected`.
+/// `WouldBlock` if it isn't, the caller should look at it again.
+pub fn wait(address: VirtAddr, expected: u32, timeout_ms: Option<u64>) -> KernelResult<()> {
+    if !address.is_aligned(4u64) {
+        return Err(KernelError::InvalidArgument);
+    }
+    // Rounded up, like the watchdog.
+    let deadline = timeout_ms.map(|ms| {
+        time::ticks() + ((ms + MILLISECONDS_PER_TICK - 1) / MILLISECONDS_PER_TICK).max(1)
+    });
+    loop {
+        let mut word = [0; 4];
+        user::copy_in(&mut word, address)?;
+        let queued = enqueue(address.as_u64(), expected, deadline, || {
+            user::copy_in_resident(&mut word, address)?;
+            Ok(u32::from_ne_bytes(word))
+        });
+        match queued {
+            // Unmapped since, the next `copy_in` says if it's gone.
+            Err(KernelError::InvalidAddress) => continue,
+            queued => return sleep(queued?),
+        }
+    }
+}
+
+/// Queue up on `address` if `read` says it holds `expected`. Returns
+/// the slot to sleep on.
+fn enqueue(
+    address: u64,
+    expected: u32,
+    deadline: Option<u64>,
+    read: impl FnOnce() -> KernelResult<u32>,
+) -> KernelResult<usize> {
+    let thread = scheduler::current();
+    latency::without_interrupts(|| {
+        let mut queue = QUEUE.lock();
+        if read()? != expected {
+            return Err(KernelError::WouldBlock);
+        }
+        let slot = queue
+            .waiters
+            .iter()
+            .position(Option::is_none)
+            .ok_or(KernelError::NoSpace)?;
+        let ticket = queue.next_ticket;
+        queue.next_ticket += 1;
+        queue.waiters[slot] = Some(Waiter {
+            address,
+            thread,
+            ticket,
+            deadline,
+            woken: false,
+        });
+        Ok(slot)
+    })
+}
+
+/// Block until `slot` is woken or its deadline comes, then give the
+/// slot back.
+fn sleep(slot: usize) -> KernelResult<()> {
+    loop {
+        let done = latency::without_interrupts(|| {
+            let mut queue = QUEUE.lock();
+            let waiter = queue.waiters[slot];
+            let woken = waiter.map_or(true, |waiter| waiter.woken);
+            let expired = waiter
+                .and_then(|waiter| waiter.deadline)
+                .map_or(false, |deadline| time::ticks() >= deadline);
+            if woken || expired {
+                queue.waiters[slot] = None;
+                Some(if woken {
+                    Ok(())
+                } else {
+                    Err(KernelError::Timeout)
+                })
+            } else {
+                None
+            }
+        });
+        if let Some(result) = done {
+            return result;
+        }
+        // A wake since the check makes this come straight back.
+        scheduler::block();
+    }
+}
+
+/// Unblock the waiters whose deadline has come, so they see it. Called
+/// by the timer handler, and by `idle` for the ticks it slept through.
+pub(crate) fn expire() {
+    let now = time::ticks();
+    latency::without_interrupts(|| {
+        let queue = QUEUE.lock();
+        let expired = queue
+            .waiters
+            .iter()
+            .flatten()
+            .filter(|waiter| !waiter.woken)
+            .filter(|waiter| waiter.deadline.map_or(false, |deadline| now >= deadline));
+        for waiter in expired {
+            scheduler::unblock(waiter.thread);
+        }
+    })
+}
+
+/// The earliest tick count a waiter gives up at, for `idle`.
+pub fn next_deadline() -> Option<u64> {
+    latency::without_interrupts(|| {
+        QUEUE
+            .lock()
+            .waiters
+            .iter()
+            .flatten()
+            .filter(|waiter| !waiter.woken)
+            .filter_map(|waiter| waiter.deadline)
+            .min()
+    })
+}
+
+/// Wake up to `count` of the waiters on `address`, oldest first.
+/// Returns how many there were.
+pub fn wake(address: VirtAddr, count: usize) -> usize {
+    let address = address.as_u64();
+    latency::without_interrupts(|| {
+        let mut queue = QUEUE.lock();
+        let mut woken = 0;
+        while woken < count {
+            let oldest = queue
+                .waiters
+                .iter_mut()
+                .flatten()
+                .filter(|waiter| waiter.address == address && !waiter.woken)
+                .min_by_key(|waiter| waiter.ticket);
+            match oldest {
+                Some(waiter) => {
+                    waiter.woken = true;
+                    scheduler::unblock(waiter.thread);
+                }
+                None => break,
+            }
+            woken += 1;
+        }
+        woken
+    })
+}
+
+#[test_case]
+fn test_wait_and_wake() {
+    let address = 0x1000;
+    // Kernel memory can't be waited on.
+    let word = 0u32;
+    assert_eq!(
+        wait(VirtAddr::from_ptr(&word), 0, None),
+        Err(KernelError::InvalidAddress)
+    );
+    assert_eq!(
+        enqueue(address, 1, None, || Ok(2)),
+        Err(KernelError::WouldBlock)
+    );
+
+    let first = enqueue(address, 1, None, || Ok(1)).unwrap();
+    let second = enqueue(address, 1, Some(0), || Ok(1)).unwrap();
+    assert_eq!(next_deadline(), Some(0));
+    assert_eq!(wake(VirtAddr::new(address), 1), 1);
+    assert_eq!(sleep(first), Ok(()));
+    // Still queued, so it times out.
+    assert_eq!(sleep(second), Err(KernelError::Timeout));
+    assert_eq!(wake(VirtAddr::new(address), 1), 0);
+}
+
+#[test_case]
+fn test_waiter_blocks() {
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    let address = 0x2000;
+    let done = Arc::new(AtomicBool::new(false));
+    {
+        let done = done.clone();
+        scheduler::spawn("waiter", move || {
+            let slot = enqueue(address, 0, None, || Ok(0)).unwrap();
+            assert_eq!(sleep(slot), Ok(()));
+            done.store(true, Ordering::SeqCst);
+        });
+    }
+    scheduler::without_preemption(|| {
+        scheduler::yield_now();
+        // Off the ready queue until it's woken.
+        assert_eq!(scheduler::ready_count(), 0);
+        assert!(!done.load(Ordering::SeqCst));
+        assert_eq!(wake(VirtAddr::new(address), 1), 1);
+        assert_eq!(scheduler::ready_count(), 1);
+    });
+    while scheduler::ready_count() > 0 {
+        scheduler::yield_now();
+    }
+    assert!(done.load(Ordering::SeqCst));
+}
@@ -1,3 +1,21 @@
+//! The GDT and the TSS.
+//!
+//! The segments are laid out the way `syscall` and `sysret` want them:
+//! kernel code, kernel data, then user data before user code. Only the
+//! privilege levels in them matter in long mode, the bases and limits
+//! are ignored.
+//!
+//! The TSS has the stacks the CPU switches to by itself: the double
+//! fault one, and `rsp0`, the one for interrupts that come in while
+//! ring 3 is running. `rsp0` changes with whichever thread is running,
+//! see `usermode`.
+//!
+//! Other CPUs get tables of their own from `cpu_tables`, the same
+//! segments with a TSS each.
+use alloc::boxed::Box;
+use alloc::vec;
+use core::cell::UnsafeCell;
+use core::ptr;
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::SegmentSelector;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
@@ -6,50 +24,155 @@ use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+pub const DOUBLE_FAULT_STACK_SIZE: usize = 4096;
+static mut DOUBLE_FAULT_STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+
+/// Present, writable, ring 0. x86_64 only has the user one.
+const KERNEL_DATA_SEGMENT: u64 = 0x00cf_9200_0000_ffff;
+/// `privilege_stack_table` is after a `u32`, the TSS is packed.
+const RSP0_OFFSET: usize = 4;
+
+/// The CPU reads the TSS whenever it likes, and we change `rsp0` in it
+/// while it's loaded.
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+// Only `rsp0` is written after loading, with interrupts off.
+unsafe impl Sync for Tss {}
+
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
+    static ref TSS: Tss = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            stack_start + STACK_SIZE
-        };
-        tss
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+            double_fault_stack_bottom() + DOUBLE_FAULT_STACK_SIZE;
+        Tss(UnsafeCell::new(tss))
     };
 }
 
+/// Lowest address of the stack double faults run on.
+pub fn double_fault_stack_bottom() -> VirtAddr {
+    VirtAddr::from_ptr(unsafe { &DOUBLE_FAULT_STACK })
+}
+
+/// Where `rsp0` is, for `usermode` to set from assembly. It's not
+/// aligned.
+pub(crate) fn kernel_stack_slot() -> *mut u64 {
+    unsafe { (TSS.0.get() as *mut u8).add(RSP0_OFFSET) as *mut u64 }
+}
+
+/// Where interrupts from ring 3 start their stack.
+pub fn kernel_stack() -> VirtAddr {
+    VirtAddr::new(unsafe { ptr::read_unaligned(kernel_stack_slot()) })
+}
+
+/// Make interrupts from ring 3 start their stack at `top`.
+///
+/// # Safety
+///
+/// Everything above `top` has to stay put while ring 3 can be
+/// interrupted, and there has to be room below it.
+pub unsafe fn set_kernel_stack(top: VirtAddr) {
+    ptr::write_unaligned(kernel_stack_slot(), top.as_u64())
+}
+
+/// A GDT laid out the same for every CPU, with `tss` as its TSS.
+fn build(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::UserSegment(KERNEL_DATA_SEGMENT));
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    (
+        gdt,
+        Selectors {
+            code_selector,
+            data_selector,
+            user_code_selector,
+            user_data_selector,
+            tss_selector,
+        },
+    )
+}
+
 lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
-    };
+    static ref GDT: (GlobalDescriptorTable, Selectors) = build(unsafe { &*TSS.0.get() });
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+/// The segments, with the privilege level they're for in the RPL.
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub tss_selector: SegmentSelector,
 }
-pub fn init() {
-    use x86_64::instructions::segmentation::set_cs;
+
+pub fn selectors() -> Selectors {
+    GDT.1
+}
+
+/// Load `gdt` and the segments in it on this CPU.
+unsafe fn load(gdt: &'static GlobalDescriptorTable, selectors: &Selectors) {
+    use x86_64::instructions::segmentation::{load_ss, set_cs};
     use x86_64::instructions::tables::load_tss;
 
-    // After loading our GDT we need to specify
-    // the CS and TSS things to it as the processor
-    // will still use the old ones.
-    // We force this through the unsafe block
-    GDT.0.load();
+    gdt.load();
+    // The processor keeps using the old segments until they're loaded
+    // again.
+    set_cs(selectors.code_selector);
+    // `iretq` back to ring 0 checks whatever is in SS against the new
+    // table.
+    load_ss(selectors.data_selector);
+    load_tss(selectors.tss_selector);
+}
+
+pub fn init() {
+    unsafe { load(&GDT.0, &GDT.1) };
+}
+
+/// The GDT and TSS of a CPU other than the boot one, which has its own
+/// double fault stack. Only the boot CPU runs ring 3, so `rsp0` is
+/// never set in it.
+pub struct CpuTables {
+    gdt: GlobalDescriptorTable,
+    selectors: Selectors,
+}
+
+/// Tables for another CPU to load with `load_cpu_tables`. They're
+/// never freed, CPUs don't go away.
+pub fn cpu_tables() -> &'static CpuTables {
+    let stack = Box::leak(vec![0u8; DOUBLE_FAULT_STACK_SIZE].into_boxed_slice());
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        VirtAddr::from_ptr(stack.as_ptr()) + DOUBLE_FAULT_STACK_SIZE;
+    let (gdt, selectors) = build(Box::leak(Box::new(tss)));
+    Box::leak(Box::new(CpuTables { gdt, selectors }))
+}
+
+/// Load `tables` on this CPU.
+///
+/// # Safety
+///
+/// Only once and only on one CPU, a TSS is busy once it's loaded.
+pub unsafe fn load_cpu_tables(tables: &'static CpuTables) {
+    load(&tables.gdt, &tables.selectors);
+}
+
+#[test_case]
+fn test_segments_for_sysret() {
+    let selectors = selectors();
+    let code = selectors.code_selector.0;
+    // `syscall` wants kernel data right after kernel code, `sysret`
+    // user data and then user code after that.
+    assert_eq!(selectors.data_selector.0, code + 8);
+    assert_eq!(selectors.user_data_selector.0, (code + 16) | 3);
+    assert_eq!(selectors.user_code_selector.0, (code + 24) | 3);
+
+    let before = kernel_stack();
     unsafe {
-        set_cs(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        set_kernel_stack(VirtAddr::new(0x1234_5670));
+        assert_eq!(kernel_stack(), VirtAddr::new(0x1234_5670));
+        set_kernel_stack(before);
     }
 }
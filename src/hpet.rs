@@ -0,0 +1,104 @@
+//! The HPET, a counter that goes up at a fixed rate of at least 10 MHz
+//! whatever the CPU is doing. We only use its main counter, as a clock
+//! source for `time`; its timers could stand in for the PIT, but the
+//! local APIC timer does that already.
+//!
+//! The ACPI HPET table says where the registers are, and the general
+//! capabilities register how long a tick is in femtoseconds. The counter
+//! only runs once it's enabled in the general configuration register.
+//! It's reached through the physical memory mapping, so `enable` needs
+//! `memory::init`, and is called after it like `kvm::enable`.
+use crate::acpi;
+use crate::error::{KernelError, KernelResult};
+use crate::memory;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::PhysAddr;
+
+/// Offsets of the registers we use.
+const CAPABILITIES: usize = 0x00;
+const CONFIGURATION: usize = 0x10;
+const MAIN_COUNTER: usize = 0xF0;
+
+/// Bits of the general capabilities register.
+const COUNTER_64_BIT: u64 = 1 << 13;
+const PERIOD_SHIFT: u64 = 32;
+/// The spec's longest tick, 100 ns. Anything more is a broken table.
+const MAX_PERIOD_FS: u64 = 100_000_000;
+/// Bit of the general configuration register that starts the counter.
+const ENABLE: u64 = 1;
+
+/// Where the registers are mapped, 0 until `enable`.
+static REGISTERS: AtomicU64 = AtomicU64::new(0);
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+
+fn read(offset: usize) -> u64 {
+    let base = REGISTERS.load(Ordering::Relaxed);
+    unsafe { ptr::read_volatile((base as usize + offset) as *const u64) }
+}
+
+fn write(offset: usize, value: u64) {
+    let base = REGISTERS.load(Ordering::Relaxed);
+    unsafe { ptr::write_volatile((base as usize + offset) as *mut u64, value) }
+}
+
+/// Find the HPET and start its counter. `Unsupported` if it only has a
+/// 32 bit one, which at 100 MHz wraps every 43 seconds.
+pub fn enable() -> KernelResult<()> {
+    let address = acpi::hpet_address()?;
+    let registers = memory::physical_to_virtual(PhysAddr::new(address), 1024)?;
+    REGISTERS.store(registers.as_u64(), Ordering::Relaxed);
+    let capabilities = read(CAPABILITIES);
+    let period = capabilities >> PERIOD_SHIFT;
+    let error = if period == 0 || period > MAX_PERIOD_FS {
+        Some(KernelError::InvalidData)
+    } else if capabilities & COUNTER_64_BIT == 0 {
+        Some(KernelError::Unsupported)
+    } else {
+        None
+    };
+    if let Some(error) = error {
+        REGISTERS.store(0, Ordering::Relaxed);
+        return Err(error);
+    }
+    PERIOD_FS.store(period, Ordering::Relaxed);
+    write(CONFIGURATION, read(CONFIGURATION) | ENABLE);
+    crate::klog!(
+        Info,
+        "hpet at {:#x}, {} MHz",
+        address,
+        1_000_000_000 / period
+    );
+    // It wasn't there when `time::init` looked.
+    crate::time::probe_again();
+    Ok(())
+}
+
+pub fn is_on() -> bool {
+    PERIOD_FS.load(Ordering::Relaxed) != 0
+}
+
+/// For `time`: whether `enable` got the counter going.
+pub(crate) fn clock_probe() -> bool {
+    is_on()
+}
+
+/// For `time`: nanoseconds since the counter was reset, which the
+/// firmware did some time before boot.
+pub(crate) fn clock_read_ns() -> u64 {
+    let ticks = read(MAIN_COUNTER);
+    (u128::from(ticks) * u128::from(PERIOD_FS.load(Ordering::Relaxed)) / 1_000_000) as u64
+}
+
+#[test_case]
+fn test_hpet_goes_forwards() {
+    if !is_on() {
+        // No HPET table, or `enable` said no.
+        assert!(!clock_probe());
+        return;
+    }
+    let first = clock_read_ns();
+    crate::time::spin_ms(1).expect("spin");
+    let second = clock_read_ns();
+    assert!(second > first);
+}
@@ -0,0 +1,164 @@
+//! What the CPU does when there's nothing to do.
+//!
+//! `idle` halts until the next interrupt. Left alone the PIT wakes it
+//! 18 times a second whether anything is due or not, which under QEMU
+//! keeps a host CPU busy for nothing. So when nothing needs the ticks,
+//! `idle` masks the PIT and arms the APIC timer one-shot for the
+//! nearest deadline instead, at most `MAX_SLEEP_TICKS` away. The ticks
+//! it slept through get added to `time::ticks` when it wakes up, by
+//! whatever woke it.
+//!
+//! Something needs the ticks if
+//!
+//! - the PIT is the clock source, the ticks are the time then,
+//! - the watchdog or the profiler is counting them.
+//!
+//! There's no timer wheel to ask for the nearest deadline yet, the
+//! only timeouts are futex ones.
+use crate::interrupts::{self, COUNTERS, TIMER_COUNTER};
+use crate::shell::{self, CommandFailed, CommandResult};
+use crate::time::{self, NANOSECONDS_PER_TICK};
+use crate::{apic, futex, latency, profiler, scheduler, watchdog};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Wake up at least this often anyway, about once a second.
+const MAX_SLEEP_TICKS: u64 = 18;
+/// Not worth reprogramming anything for less.
+const MIN_SLEEP_TICKS: u64 = 2;
+
+static TICKLESS: AtomicBool = AtomicBool::new(true);
+/// Sleeps without the PIT, and how long they added up to.
+static SLEEPS: AtomicU64 = AtomicU64::new(0);
+static SLEPT_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Adds the `idle` shell command.
+pub fn init() {
+    shell::register(
+        "idle",
+        "idle [on|off]: tickless idle stats, or turn it on and off",
+        idle_command,
+    )
+    .expect("idle command");
+}
+
+/// How long to sleep without the PIT, `None` if it has to keep going.
+fn sleep_ns() -> Option<u64> {
+    if !TICKLESS.load(Ordering::Relaxed)
+        || time::clock_source().name == "pit"
+        || watchdog::is_armed()
+        || profiler::is_running()
+    {
+        return None;
+    }
+    let now = time::ticks();
+    let ticks = futex::next_deadline()
+        .map_or(MAX_SLEEP_TICKS, |deadline| deadline.saturating_sub(now))
+        .min(MAX_SLEEP_TICKS);
+    if ticks < MIN_SLEEP_TICKS {
+        None
+    } else {
+        Some(ticks * NANOSECONDS_PER_TICK)
+    }
+}
+
+/// Halt until an interrupt has been handled. Interrupts have to be
+/// on, or this never comes back.
+pub fn idle() {
+    idle_unless(|| false)
+}
+
+/// `idle`, unless `busy` says there's something to do. It's asked with
+/// interrupts off, so nothing a handler does can make it true after
+/// it's said no and before the `hlt`.
+pub fn idle_unless(busy: impl FnOnce() -> bool) {
+    // Not through `latency::without_interrupts`, the `sti` has to come
+    // right before the `hlt`: anything that came in between would have
+    // its interrupt handled and then wait for the next one.
+    x86_64::instructions::interrupts::disable();
+    if busy() {
+        x86_64::instructions::interrupts::enable();
+        return;
+    }
+    let start = sleep_ns().filter(|&ns| apic::arm_timer(ns)).map(|_| {
+        interrupts::mask_timer(true);
+        time::monotonic_ns()
+    });
+    // `sti` only takes effect after the next instruction, so nothing
+    // can get in before the `hlt`.
+    unsafe { asm!("sti", "hlt", options(nomem, nostack)) };
+    if let Some(start) = start {
+        latency::without_interrupts(|| {
+            apic::disarm_timer();
+            interrupts::mask_timer(false);
+            let slept = time::monotonic_ns().saturating_sub(start);
+            time::skip_ticks(slept);
+            futex::expire();
+            SLEEPS.fetch_add(1, Ordering::Relaxed);
+            SLEPT_NS.fetch_add(slept, Ordering::Relaxed);
+        });
+    }
+}
+
+/// Sleeps without the PIT so far.
+pub fn sleeps() -> u64 {
+    SLEEPS.load(Ordering::Relaxed)
+}
+
+/// How long those sleeps added up to.
+pub fn slept_ns() -> u64 {
+    SLEPT_NS.load(Ordering::Relaxed)
+}
+
+/// Idle forever, or rather let the kernel threads run and halt when
+/// there aren't any.
+pub fn idle_loop() -> ! {
+    loop {
+        scheduler::yield_now();
+        idle_unless(|| scheduler::ready_count() > 0);
+    }
+}
+
+fn idle_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    match args {
+        "" => {
+            let _ = writeln!(
+                out,
+                "tickless idle {}, {}",
+                if TICKLESS.load(Ordering::Relaxed) {
+                    "on"
+                } else {
+                    "off"
+                },
+                match sleep_ns() {
+                    Some(_) => "nothing needs the tick now",
+                    None => "ticking",
+                }
+            );
+            let _ = writeln!(
+                out,
+                "{} sleeps, {} ms in all, {} timer interrupts",
+                sleeps(),
+                slept_ns() / 1_000_000,
+                COUNTERS[TIMER_COUNTER].count()
+            );
+        }
+        "on" => TICKLESS.store(true, Ordering::Relaxed),
+        "off" => TICKLESS.store(false, Ordering::Relaxed),
+        _ => {
+            let _ = writeln!(out, "usage: idle [on|off]");
+            return Err(CommandFailed);
+        }
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_idle_keeps_ticking_for_the_watchdog() {
+    // Every test runs with the watchdog armed.
+    assert_eq!(sleep_ns(), None);
+    let before = time::ticks();
+    while time::ticks() < before + 2 {
+        idle();
+    }
+}
@@ -1,37 +1,612 @@
+use crate::boot_timing::read_tsc;
+use crate::error::KernelResult;
 use crate::gdt;
+use crate::latency::{self, Histogram};
 use crate::println;
+use crate::usermode::{self, Exit, Fault};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use pic8259_simple::ChainedPics;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::registers::control::Cr2;
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::PrivilegeLevel;
+use x86_64::VirtAddr;
+
+// The first 32 vectors are taken by CPU exceptions, so the
+// hardware interrupts from the PICs get moved to right after them.
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+pub static PICS: Mutex<ChainedPics> =
+    Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+/// Vectors of the hardware interrupts we handle.
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+pub enum InterruptIndex {
+    Timer = PIC_1_OFFSET,
+    Keyboard = PIC_1_OFFSET + 1,
+    Serial = PIC_1_OFFSET + 4, // COM1
+}
+
+impl InterruptIndex {
+    pub fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    fn as_usize(self) -> usize {
+        usize::from(self.as_u8())
+    }
+}
+
+/// How many times an interrupt fired since boot, and how long its
+/// handler took.
+pub struct InterruptCounter {
+    pub name: &'static str,
+    count: AtomicU64,
+    /// TSC cycles from entering the handler to leaving it.
+    pub time: Histogram,
+}
+
+impl InterruptCounter {
+    const fn new(name: &'static str) -> InterruptCounter {
+        InterruptCounter {
+            name,
+            count: AtomicU64::new(0),
+            time: Histogram::new(),
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn increment(&self) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub const COUNTER_COUNT: usize = 15;
+
+/// Counters for every interrupt we have a handler for.
+pub static COUNTERS: [InterruptCounter; COUNTER_COUNT] = [
+    InterruptCounter::new("breakpoint"),
+    InterruptCounter::new("double fault"),
+    InterruptCounter::new("timer"),
+    InterruptCounter::new("serial"),
+    InterruptCounter::new("debug"),
+    InterruptCounter::new("apic error"),
+    InterruptCounter::new("thermal"),
+    InterruptCounter::new("machine check"),
+    InterruptCounter::new("page fault"),
+    InterruptCounter::new("apic timer"),
+    InterruptCounter::new("keyboard"),
+    InterruptCounter::new("divide error"),
+    InterruptCounter::new("invalid opcode"),
+    InterruptCounter::new("gp fault"),
+    InterruptCounter::new("apic spurious"),
+];
+
+pub const BREAKPOINT_COUNTER: usize = 0;
+pub const DOUBLE_FAULT_COUNTER: usize = 1;
+pub const TIMER_COUNTER: usize = 2;
+pub const SERIAL_COUNTER: usize = 3;
+pub const DEBUG_COUNTER: usize = 4;
+pub const APIC_ERROR_COUNTER: usize = 5;
+pub const THERMAL_COUNTER: usize = 6;
+pub const MACHINE_CHECK_COUNTER: usize = 7;
+pub const PAGE_FAULT_COUNTER: usize = 8;
+pub const APIC_TIMER_COUNTER: usize = 9;
+pub const KEYBOARD_COUNTER: usize = 10;
+pub const DIVIDE_ERROR_COUNTER: usize = 11;
+pub const INVALID_OPCODE_COUNTER: usize = 12;
+pub const GENERAL_PROTECTION_COUNTER: usize = 13;
+pub const APIC_SPURIOUS_COUNTER: usize = 14;
+
+/// How many interrupt handlers we are nested in right now.
+static DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether we are running inside an interrupt or exception handler.
+/// Anything the interrupted code was doing, holding a lock included,
+/// might be half done.
+pub fn in_interrupt() -> bool {
+    nesting_depth() != 0
+}
+
+/// How many handlers deep we are, 0 outside of any.
+pub fn nesting_depth() -> usize {
+    DEPTH.load(Ordering::SeqCst)
+}
+
+/// Marks a handler as running until dropped, and times it. Every
+/// handler takes one of these first thing.
+struct HandlerGuard {
+    counter: &'static InterruptCounter,
+    start: u64,
+}
+
+impl HandlerGuard {
+    fn enter(counter: &'static InterruptCounter) -> HandlerGuard {
+        DEPTH.fetch_add(1, Ordering::SeqCst);
+        HandlerGuard {
+            counter,
+            start: read_tsc(),
+        }
+    }
+
+    /// Run `f` as if we'd never come into the handler: not counted in
+    /// `nesting_depth`, so it can be preempted, and with interrupts on.
+    /// For an exception that has to wait on locks. Only if the code it
+    /// came from could have been preempted itself, so isn't in another
+    /// handler and had interrupts on, otherwise `None`.
+    fn outside<R>(&self, stack_frame: &InterruptStackFrame, f: impl FnOnce() -> R) -> Option<R> {
+        let interruptible = stack_frame.cpu_flags & RFlags::INTERRUPT_FLAG.bits() != 0;
+        if !interruptible || nesting_depth() != 1 {
+            return None;
+        }
+        DEPTH.fetch_sub(1, Ordering::SeqCst);
+        x86_64::instructions::interrupts::enable();
+        let result = f();
+        x86_64::instructions::interrupts::disable();
+        DEPTH.fetch_add(1, Ordering::SeqCst);
+        Some(result)
+    }
+}
+
+/// Stop running ring 3 code that faulted, out of the handler.
+fn leave_user_mode(guard: HandlerGuard, stack_frame: &InterruptStackFrame, fault: Fault) -> ! {
+    let rip = stack_frame.instruction_pointer.as_u64();
+    // `leave` never comes back to drop it.
+    drop(guard);
+    crate::usermode::leave(Exit::Fault { fault, rip })
+}
+
+impl Drop for HandlerGuard {
+    fn drop(&mut self) {
+        self.counter
+            .time
+            .record(read_tsc().wrapping_sub(self.start));
+        DEPTH.fetch_sub(1, Ordering::SeqCst);
+    }
+}
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler); // Handle breakpoints
+        idt.debug.set_handler_fn(debug_handler); // Single steps and watchpoints
+        idt.machine_check.set_handler_fn(machine_check_handler);
+        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_handler);
         unsafe {
             idt.double_fault.set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX); // new
         };
+        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
+        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Serial.as_usize()].set_handler_fn(serial_interrupt_handler);
+        idt[usize::from(crate::apic::ERROR_VECTOR)].set_handler_fn(apic_error_handler);
+        idt[usize::from(crate::apic::THERMAL_VECTOR)].set_handler_fn(thermal_handler);
+        idt[usize::from(crate::apic::TIMER_VECTOR)].set_handler_fn(apic_timer_handler);
+        idt[usize::from(crate::apic::WAKE_VECTOR)].set_handler_fn(wake_handler);
+        idt[usize::from(crate::apic::SPURIOUS_VECTOR)].set_handler_fn(apic_spurious_handler);
+        // The one gate ring 3 may use.
+        idt[usize::from(usermode::RETURN_VECTOR)]
+            .set_handler_fn(usermode::return_gate())
+            .set_privilege_level(PrivilegeLevel::Ring3);
         idt
     };
 }
 
-pub fn init_idt() {
+/// Load the IDT on this CPU, which other CPUs do too when they start.
+pub fn load_idt() {
     IDT.load();
 }
 
+pub fn init_idt() {
+    load_idt();
+    crate::shell::register("irqstats", "interrupt counts and rates", irqstats_command)
+        .expect("irqstats command");
+}
+
+fn irqstats_command(out: &mut dyn core::fmt::Write, _args: &str) -> crate::shell::CommandResult {
+    let seconds = (crate::time::uptime_ms() / 1000).max(1);
+    let _ = writeln!(out, "  {:<14} {:>10} {:>8}", "", "count", "per s");
+    for counter in COUNTERS.iter() {
+        let count = counter.count();
+        let _ = writeln!(
+            out,
+            "  {:<14} {:>10} {:>8}",
+            counter.name,
+            count,
+            count / seconds
+        );
+    }
+    Ok(())
+}
+
+/// Remap the PICs and unmask the lines we have handlers for.
+pub fn init_pics() {
+    unsafe {
+        PICS.lock().initialize();
+        // Anything without a handler would end up as a double
+        // fault, so only the timer (IRQ0), the keyboard (IRQ1) and
+        // COM1 (IRQ4) are let through for now.
+        Port::<u8>::new(0x21).write(0xEC);
+        Port::<u8>::new(0xA1).write(0xFF);
+    }
+}
+
+/// Have the local APIC take over from the PICs, with the keyboard and
+/// COM1 routed through the IO APIC to this CPU. Left to the PICs if
+/// either APIC isn't there or won't do it.
+pub fn use_apic() -> KernelResult<()> {
+    crate::ioapic::enable()?;
+    latency::without_interrupts(|| {
+        crate::apic::take_over()?;
+        let routed = [(1, InterruptIndex::Keyboard), (4, InterruptIndex::Serial)]
+            .iter()
+            .try_for_each(|&(irq, index)| {
+                crate::ioapic::route(irq, index.as_u8(), crate::smp::BOOT_CPU)
+            });
+        if routed.is_err() {
+            let _ = crate::apic::hand_back();
+            for &irq in [1, 4].iter() {
+                let _ = crate::ioapic::mask(irq);
+            }
+        }
+        routed
+    })
+}
+
+/// Set which lines of the two PICs are masked, a bit each, and return
+/// what they were. For `apic` taking over from them and handing back.
+pub(crate) fn set_pic_masks(masks: [u8; 2]) -> [u8; 2] {
+    let mut first = Port::<u8>::new(0x21);
+    let mut second = Port::<u8>::new(0xA1);
+    unsafe {
+        let old = [first.read(), second.read()];
+        first.write(masks[0]);
+        second.write(masks[1]);
+        old
+    }
+}
+
+/// Tell whoever sent the interrupt it has been handled: the PICs, or
+/// the local APIC once it has taken over from them.
+fn end_of_interrupt(index: InterruptIndex) {
+    if crate::apic::has_taken_over() {
+        crate::apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(index.as_u8()) };
+    }
+}
+
+/// Stop or restart timer interrupts from the PIT, for `idle`. The
+/// PIT's masked for good once the APIC has taken over.
+pub fn mask_timer(masked: bool) {
+    if crate::apic::has_taken_over() {
+        return;
+    }
+    let mut mask = Port::<u8>::new(0x21);
+    unsafe {
+        let lines = mask.read();
+        mask.write(if masked { lines | 1 } else { lines & !1 });
+    }
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    let _guard = HandlerGuard::enter(&COUNTERS[BREAKPOINT_COUNTER]);
+    COUNTERS[BREAKPOINT_COUNTER].increment();
+    if !crate::breakpoints::hit(stack_frame) {
+        println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    }
+}
+
+extern "x86-interrupt" fn debug_handler(stack_frame: &mut InterruptStackFrame) {
+    let _guard = HandlerGuard::enter(&COUNTERS[DEBUG_COUNTER]);
+    COUNTERS[DEBUG_COUNTER].increment();
+    // A watchpoint can go off on the step over a breakpoint, so ask both.
+    let watched = crate::watchpoints::hit(stack_frame);
+    let stepped = crate::breakpoints::single_step(stack_frame);
+    // After the breakpoint, which clears the trap flag.
+    let traced = crate::step_trace::step(stack_frame);
+    if !watched && !stepped && !traced {
+        println!("EXCEPTION: DEBUG\n{:#?}", stack_frame);
+    }
 }
 
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: &mut InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    // Never returns, so this stays in interrupt context for good.
+    let _guard = HandlerGuard::enter(&COUNTERS[DOUBLE_FAULT_COUNTER]);
+    COUNTERS[DOUBLE_FAULT_COUNTER].increment();
+    let _ = write_double_fault_report(&mut crate::serial::panic_writer(), stack_frame);
+    let overflow = looks_like_stack_overflow(Cr2::read(), stack_frame.stack_pointer);
+    panic!(
+        "EXCEPTION: DOUBLE FAULT{}\n{:#?}",
+        if overflow { " (stack overflow)" } else { "" },
+        stack_frame
+    );
+}
+
+/// Quad words shown from the top of the interrupted stack.
+const SAVED_STACK_WORDS: u64 = 8;
+
+/// What was going on when the double fault hit: the top of the stack
+/// that was in use, how much of the double fault stack we're using and
+/// whether it looks like that stack overflowed.
+fn write_double_fault_report(
+    out: &mut dyn fmt::Write,
+    stack_frame: &InterruptStackFrame,
+) -> fmt::Result {
+    let fault_address = Cr2::read();
+    let stack_pointer = stack_frame.stack_pointer;
+    writeln!(out, "double fault:")?;
+    writeln!(
+        out,
+        "  rip {:#018x}  rsp {:#018x}  cr2 {:#018x}",
+        stack_frame.instruction_pointer.as_u64(),
+        stack_pointer.as_u64(),
+        fault_address.as_u64()
+    )?;
+    if looks_like_stack_overflow(fault_address, stack_pointer) {
+        writeln!(
+            out,
+            "  cr2 is in the guard page below rsp, the stack overflowed"
+        )?;
+    }
+
+    // Only read the old stack if the page tables say it's there.
+    let len = SAVED_STACK_WORDS * 8;
+    match crate::memory::check_range(stack_pointer, len, false) {
+        Ok(()) => {
+            writeln!(out, "  top of the interrupted stack:")?;
+            for i in 0..SAVED_STACK_WORDS {
+                let address = stack_pointer + i * 8;
+                let value = unsafe { core::ptr::read_volatile(address.as_ptr::<u64>()) };
+                writeln!(out, "    {:#018x}: {:#018x}", address.as_u64(), value)?;
+            }
+        }
+        Err(error) => writeln!(out, "  interrupted stack not shown: {}", error)?,
+    }
+
+    let (used, size) = double_fault_stack_usage();
+    writeln!(out, "  double fault stack: {} of {} bytes used", used, size)
+}
+
+/// Whether a fault at `fault_address` (CR2) with the stack pointer at
+/// `stack_pointer` was most likely a push running off the bottom of the
+/// stack into its guard page.
+pub fn looks_like_stack_overflow(fault_address: VirtAddr, stack_pointer: VirtAddr) -> bool {
+    // A push writes just below the stack pointer, a local can be just
+    // above it if the stack pointer already moved into the guard page.
+    let (fault, stack) = (fault_address.as_u64(), stack_pointer.as_u64());
+    let distance = fault.max(stack) - fault.min(stack);
+    if distance > 4096 {
+        return false;
+    }
+    // And a guard page isn't mapped. Without page tables to look at,
+    // the distance has to do.
+    crate::memory::physical_memory_offset().is_none()
+        || crate::memory::translate(fault_address).is_none()
+}
+
+/// Bytes of the double fault stack in use right now and its size.
+pub fn double_fault_stack_usage() -> (usize, usize) {
+    let rsp: u64;
+    unsafe {
+        asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack));
+    }
+    let bottom = gdt::double_fault_stack_bottom().as_u64();
+    let top = bottom + gdt::DOUBLE_FAULT_STACK_SIZE as u64;
+    let used = if (bottom..top).contains(&rsp) {
+        (top - rsp) as usize
+    } else {
+        0
+    };
+    (used, gdt::DOUBLE_FAULT_STACK_SIZE)
+}
+
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: &mut InterruptStackFrame) {
+    {
+        let _guard = HandlerGuard::enter(&COUNTERS[TIMER_COUNTER]);
+        crate::profiler::tick(stack_frame);
+        crate::replay::record(crate::replay::Input::Ticks(1));
+        timer_tick();
+
+        // No other one comes until we acknowledge this one.
+        end_of_interrupt(InterruptIndex::Timer);
+    }
+    // Out of the handler but for the `iretq`, which might not be for a
+    // while if this switches to another thread.
+    crate::scheduler::preempt();
+}
+
+/// Everything the timer interrupt does but sample the profiler, which
+/// needs to know what it interrupted. `replay` injects ticks here.
+pub(crate) fn timer_tick() {
+    COUNTERS[TIMER_COUNTER].increment();
+    crate::random::add_interrupt_timing(crate::random::Source::Timer);
+    crate::trace_event!(Interrupts, "timer tick {}", COUNTERS[TIMER_COUNTER].count());
+    crate::watchdog::tick();
+    crate::rcu::tick();
+    crate::futex::expire();
+    crate::stack_canary::check_all();
+}
+
+extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    let _guard = HandlerGuard::enter(&COUNTERS[KEYBOARD_COUNTER]);
+    COUNTERS[KEYBOARD_COUNTER].increment();
+    crate::random::add_interrupt_timing(crate::random::Source::Keyboard);
+    crate::keyboard::handle_interrupt();
+
+    end_of_interrupt(InterruptIndex::Keyboard);
+}
+
+extern "x86-interrupt" fn serial_interrupt_handler(stack_frame: &mut InterruptStackFrame) {
+    let _guard = HandlerGuard::enter(&COUNTERS[SERIAL_COUNTER]);
+    use crate::serial::{self, Received};
+
+    COUNTERS[SERIAL_COUNTER].increment();
+    crate::random::add_interrupt_timing(crate::random::Source::Serial);
+    while let Some(received) = serial::receive_raw() {
+        match received {
+            Received::Break | Received::Byte(crate::monitor::MAGIC_BYTE) => {
+                crate::monitor::enter(stack_frame)
+            }
+            Received::Byte(byte) => {
+                crate::replay::record(crate::replay::Input::Serial(byte));
+                serial_byte(byte);
+            }
+        }
+    }
+
+    end_of_interrupt(InterruptIndex::Serial);
+}
+
+/// A byte from serial that isn't for the monitor. `replay` injects
+/// serial input here.
+pub(crate) fn serial_byte(byte: u8) {
+    crate::trace_event!(Serial, "received {:#04x}", byte);
+    if !crate::serial::handle_flow_byte(byte) {
+        crate::line_discipline::feed(crate::line_discipline::Source::Serial, byte);
+    }
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: &mut InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let guard = HandlerGuard::enter(&COUNTERS[PAGE_FAULT_COUNTER]);
+    COUNTERS[PAGE_FAULT_COUNTER].increment();
+    // A page of a mapped file that isn't in yet, or a private one being
+    // written to for the first time. Mapping it in can wait on locks.
+    let address = Cr2::read();
+    if address.as_u64() < crate::user::USER_END {
+        let write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+        let mapped = guard.outside(stack_frame, || crate::fs::mmap::fault(address, write));
+        if mapped == Some(Ok(())) {
+            return;
+        }
+    }
+    // A user memory copy that's allowed to fault.
+    if let Some(fixup) = crate::user::fixup(stack_frame.instruction_pointer) {
+        unsafe { stack_frame.as_mut().instruction_pointer = fixup };
+        return;
+    }
+    if usermode::is_user(stack_frame) {
+        let fault = Fault::PageFault {
+            address: address.as_u64(),
+            error_code: error_code.bits(),
+        };
+        leave_user_mode(guard, stack_frame, fault);
+    }
+    panic!(
+        "EXCEPTION: PAGE FAULT at {:#x}, {:?}\n{:#?}",
+        address.as_u64(),
+        error_code,
+        stack_frame
+    );
+}
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: &mut InterruptStackFrame) {
+    let guard = HandlerGuard::enter(&COUNTERS[DIVIDE_ERROR_COUNTER]);
+    COUNTERS[DIVIDE_ERROR_COUNTER].increment();
+    if usermode::is_user(stack_frame) {
+        leave_user_mode(guard, stack_frame, Fault::DivideError);
+    }
+    panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: &mut InterruptStackFrame) {
+    let guard = HandlerGuard::enter(&COUNTERS[INVALID_OPCODE_COUNTER]);
+    COUNTERS[INVALID_OPCODE_COUNTER].increment();
+    if usermode::is_user(stack_frame) {
+        leave_user_mode(guard, stack_frame, Fault::InvalidOpcode);
+    }
+    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn general_protection_handler(
+    stack_frame: &mut InterruptStackFrame,
+    error_code: u64,
+) {
+    let guard = HandlerGuard::enter(&COUNTERS[GENERAL_PROTECTION_COUNTER]);
+    COUNTERS[GENERAL_PROTECTION_COUNTER].increment();
+    if usermode::is_user(stack_frame) {
+        leave_user_mode(guard, stack_frame, Fault::GeneralProtection { error_code });
+    }
+    panic!(
+        "EXCEPTION: GENERAL PROTECTION FAULT ({:#x})\n{:#?}",
+        error_code, stack_frame
+    );
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: &mut InterruptStackFrame) -> ! {
+    let _guard = HandlerGuard::enter(&COUNTERS[MACHINE_CHECK_COUNTER]);
+    COUNTERS[MACHINE_CHECK_COUNTER].increment();
+    let severity = crate::machine_check::report(&mut crate::serial::panic_writer());
+    panic!(
+        "EXCEPTION: MACHINE CHECK ({})\n{:#?}",
+        severity.name(),
+        stack_frame
+    );
+}
+
+extern "x86-interrupt" fn apic_error_handler(_stack_frame: &mut InterruptStackFrame) {
+    let _guard = HandlerGuard::enter(&COUNTERS[APIC_ERROR_COUNTER]);
+    COUNTERS[APIC_ERROR_COUNTER].increment();
+    crate::apic::handle_error();
+}
+
+extern "x86-interrupt" fn thermal_handler(_stack_frame: &mut InterruptStackFrame) {
+    let _guard = HandlerGuard::enter(&COUNTERS[THERMAL_COUNTER]);
+    COUNTERS[THERMAL_COUNTER].increment();
+    crate::apic::handle_thermal();
+}
+
+/// Nothing to do, spurious interrupts don't even get an EOI.
+extern "x86-interrupt" fn apic_spurious_handler(_stack_frame: &mut InterruptStackFrame) {
+    let _guard = HandlerGuard::enter(&COUNTERS[APIC_SPURIOUS_COUNTER]);
+    COUNTERS[APIC_SPURIOUS_COUNTER].increment();
+}
+
+extern "x86-interrupt" fn apic_timer_handler(_stack_frame: &mut InterruptStackFrame) {
+    let _guard = HandlerGuard::enter(&COUNTERS[APIC_TIMER_COUNTER]);
+    COUNTERS[APIC_TIMER_COUNTER].increment();
+    crate::apic::handle_timer();
+}
+
+/// Only ever on a parked CPU, which runs nothing a `HandlerGuard`
+/// would have to account for. Waking up was all it was for.
+extern "x86-interrupt" fn wake_handler(_stack_frame: &mut InterruptStackFrame) {
+    crate::apic::handle_wake();
 }
 
 #[test_case]
 fn test_breakpoint_exception() {
     // invoke a breakpoint exception
+    let before = COUNTERS[BREAKPOINT_COUNTER].count();
     x86_64::instructions::interrupts::int3();
+    assert_eq!(COUNTERS[BREAKPOINT_COUNTER].count(), before + 1);
+}
+
+#[test_case]
+fn test_stack_overflow_detection() {
+    // Just below the stack pointer, in an unmapped page.
+    let rsp = VirtAddr::new(0x1000);
+    assert!(looks_like_stack_overflow(VirtAddr::new(0xff8), rsp));
+    // Far from the stack.
+    assert!(!looks_like_stack_overflow(VirtAddr::new(0xdead_0000), rsp));
+    // Not in a handler, so no double fault stack in use.
+    assert_eq!(double_fault_stack_usage().0, 0);
 }
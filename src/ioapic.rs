@@ -0,0 +1,246 @@
+//! The IO APIC, for routing legacy IRQs once the local APIC has taken
+//! over from the 8259s.
+//!
+//! Each of its pins has a redirection entry saying which vector to send
+//! and to which CPU, and whether the pin is masked. `enable` finds it in
+//! the MADT and masks every pin; `route` gives an ISA IRQ a vector and a
+//! CPU, going by the MADT's overrides for the pin it's on and whether
+//! it's level triggered or active low. `ioapic` lists what's routed.
+//!
+//! Its registers are behind an index and a data register, so every
+//! access holds the lock, with interrupts off.
+use crate::acpi::{self, Override};
+use crate::error::{KernelError, KernelResult};
+use crate::latency;
+use crate::memory;
+use crate::shell::{self, CommandResult};
+use crate::smp;
+use crate::ui::{Column, Table};
+use core::fmt;
+use core::ptr;
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+const SELECT: usize = 0x00;
+const WINDOW: usize = 0x10;
+const VERSION: u32 = 0x01;
+const REDIRECTION: u32 = 0x10;
+
+const ACTIVE_LOW: u64 = 1 << 13;
+const LEVEL_TRIGGERED: u64 = 1 << 15;
+const MASKED: u64 = 1 << 16;
+
+/// MPS INTI flags, as in the MADT's overrides.
+const POLARITY_ACTIVE_LOW: u16 = 0b11;
+const TRIGGER_LEVEL: u16 = 0b11 << 2;
+
+struct IoApic {
+    /// Where the registers are mapped.
+    registers: u64,
+    gsi_base: u32,
+    /// How many pins it has.
+    pins: u32,
+    overrides: [Option<Override>; 16],
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        let base = self.registers as usize;
+        unsafe {
+            ptr::write_volatile((base + SELECT) as *mut u32, register);
+            ptr::read_volatile((base + WINDOW) as *const u32)
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        let base = self.registers as usize;
+        unsafe {
+            ptr::write_volatile((base + SELECT) as *mut u32, register);
+            ptr::write_volatile((base + WINDOW) as *mut u32, value);
+        }
+    }
+
+    fn entry(&self, pin: u32) -> u64 {
+        let low = self.read(REDIRECTION + 2 * pin);
+        let high = self.read(REDIRECTION + 2 * pin + 1);
+        u64::from(high) << 32 | u64::from(low)
+    }
+
+    fn set_entry(&self, pin: u32, entry: u64) {
+        // Masked while it's half written.
+        self.write(REDIRECTION + 2 * pin, MASKED as u32);
+        self.write(REDIRECTION + 2 * pin + 1, (entry >> 32) as u32);
+        self.write(REDIRECTION + 2 * pin, entry as u32);
+    }
+
+    /// The pin `irq` is on, and the flags it needs.
+    fn pin(&self, irq: u8) -> KernelResult<(u32, u64)> {
+        let (gsi, flags) = match self.overrides.get(usize::from(irq)) {
+            Some(Some(entry)) => (entry.gsi, entry.flags),
+            Some(None) => (u32::from(irq), 0),
+            None => return Err(KernelError::InvalidArgument),
+        };
+        let pin = gsi
+            .checked_sub(self.gsi_base)
+            .filter(|&pin| pin < self.pins)
+            .ok_or(KernelError::NotFound)?;
+        // 0 is whatever the bus does, for ISA edge triggered and high.
+        let mut bits = 0;
+        if flags & POLARITY_ACTIVE_LOW == POLARITY_ACTIVE_LOW {
+            bits |= ACTIVE_LOW;
+        }
+        if flags & TRIGGER_LEVEL == TRIGGER_LEVEL {
+            bits |= LEVEL_TRIGGERED;
+        }
+        Ok((pin, bits))
+    }
+}
+
+static IO_APIC: Mutex<Option<IoApic>> = Mutex::new(None);
+
+/// Adds the `ioapic` shell command.
+pub fn init() {
+    shell::register("ioapic", "list routed IO APIC interrupts", ioapic_command)
+        .expect("ioapic command");
+}
+
+/// Find the IO APIC and mask all its pins. Needs `memory::init`, and
+/// `apic::enable` for `route` to know where to send things.
+pub fn enable() -> KernelResult<()> {
+    let madt = acpi::madt()?;
+    let entry = madt.io_apic.ok_or(KernelError::NotFound)?;
+    let registers = memory::physical_to_virtual(PhysAddr::new(u64::from(entry.address)), 4096)?;
+    let mut io_apic = IoApic {
+        registers: registers.as_u64(),
+        gsi_base: entry.gsi_base,
+        pins: 0,
+        overrides: madt.overrides,
+    };
+    io_apic.pins = (io_apic.read(VERSION) >> 16 & 0xFF) + 1;
+    latency::without_interrupts(|| {
+        for pin in 0..io_apic.pins {
+            io_apic.set_entry(pin, MASKED);
+        }
+        *IO_APIC.lock() = Some(io_apic);
+    });
+    Ok(())
+}
+
+/// Have ISA IRQ `irq` come in as `vector` on `cpu`. `NotReady` before
+/// `enable`, `InvalidArgument` for a CPU that isn't online.
+pub fn route(irq: u8, vector: u8, cpu: u32) -> KernelResult<()> {
+    if !smp::is_online(cpu) {
+        return Err(KernelError::InvalidArgument);
+    }
+    let destination = smp::apic_id(cpu).ok_or(KernelError::NotReady)?;
+    latency::without_interrupts(|| {
+        let io_apic = IO_APIC.lock();
+        let io_apic = io_apic.as_ref().ok_or(KernelError::NotReady)?;
+        let (pin, bits) = io_apic.pin(irq)?;
+        // Fixed delivery to a physical APIC ID.
+        io_apic.set_entry(pin, u64::from(destination) << 56 | bits | u64::from(vector));
+        Ok(())
+    })
+}
+
+/// Stop `irq` coming in, wherever it was routed.
+pub fn mask(irq: u8) -> KernelResult<()> {
+    latency::without_interrupts(|| {
+        let io_apic = IO_APIC.lock();
+        let io_apic = io_apic.as_ref().ok_or(KernelError::NotReady)?;
+        let (pin, _) = io_apic.pin(irq)?;
+        io_apic.set_entry(pin, io_apic.entry(pin) | MASKED);
+        Ok(())
+    })
+}
+
+/// Where `irq` goes: its vector and the APIC ID it's sent to, `None`
+/// if it's masked.
+pub fn routing(irq: u8) -> KernelResult<Option<(u8, u8)>> {
+    latency::without_interrupts(|| {
+        let io_apic = IO_APIC.lock();
+        let io_apic = io_apic.as_ref().ok_or(KernelError::NotReady)?;
+        let (pin, _) = io_apic.pin(irq)?;
+        let entry = io_apic.entry(pin);
+        if entry & MASKED != 0 {
+            return Ok(None);
+        }
+        Ok(Some((entry as u8, (entry >> 56) as u8)))
+    })
+}
+
+fn ioapic_command(out: &mut dyn fmt::Write, _args: &str) -> CommandResult {
+    const COLUMNS: [Column; 5] = [
+        Column::right("irq", 3),
+        Column::right("pin", 3),
+        Column::right("vector", 6),
+        Column::right("apic", 4),
+        Column::left("mode", 10),
+    ];
+    let entries = latency::without_interrupts(|| {
+        let io_apic = IO_APIC.lock();
+        let io_apic = io_apic.as_ref()?;
+        let mut entries = [None; 16];
+        for (irq, slot) in entries.iter_mut().enumerate() {
+            if let Ok((pin, _)) = io_apic.pin(irq as u8) {
+                *slot = Some((pin, io_apic.entry(pin)));
+            }
+        }
+        Some(entries)
+    });
+    let entries = match entries {
+        Some(entries) => entries,
+        None => {
+            let _ = writeln!(out, "not set up");
+            return Ok(());
+        }
+    };
+    let table = Table::new(&COLUMNS);
+    let _ = table.header(out);
+    for (irq, entry) in entries.iter().enumerate() {
+        let (pin, entry) = match *entry {
+            Some((pin, entry)) if entry & MASKED == 0 => (pin, entry),
+            _ => continue,
+        };
+        let mode = match (entry & LEVEL_TRIGGERED != 0, entry & ACTIVE_LOW != 0) {
+            (false, false) => "edge high",
+            (false, true) => "edge low",
+            (true, false) => "level high",
+            (true, true) => "level low",
+        };
+        let _ = table.row(
+            out,
+            &[&irq, &pin, &(entry as u8), &((entry >> 56) as u8), &mode],
+        );
+    }
+    let _ = table.end(out);
+    Ok(())
+}
+
+#[test_case]
+fn test_routes_and_masks() {
+    // The RTC, which nothing else wants.
+    const IRQ: u8 = 8;
+    const VECTOR: u8 = 0x48;
+
+    if IO_APIC.lock().is_none() && enable().is_err() {
+        // No ACPI tables, or no IO APIC in them.
+        return;
+    }
+    assert_eq!(route(IRQ, VECTOR, 7), Err(KernelError::InvalidArgument));
+    assert_eq!(routing(16), Err(KernelError::InvalidArgument));
+    // There's no handler for it, it's masked again before anything can
+    // come in.
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if route(IRQ, VECTOR, smp::BOOT_CPU).is_err() {
+            // No local APIC to send it to.
+            return;
+        }
+        assert_eq!(
+            routing(IRQ),
+            Ok(Some((VECTOR, smp::apic_id(smp::BOOT_CPU).unwrap())))
+        );
+        mask(IRQ).unwrap();
+        assert_eq!(routing(IRQ), Ok(None));
+    });
+}
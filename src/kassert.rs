@@ -0,0 +1,132 @@
+//! Assertions that say more about the machine when they fail.
+//!
+//! `kassert!` and `kassert_eq!` work like `assert!` and `assert_eq!`,
+//! but before panicking they print what we were running, how deep in
+//! interrupt handlers we were, a few registers and the last entries in
+//! the trace buffer. A CI log then has something to go on besides the
+//! line number.
+use crate::interrupts;
+use crate::scheduler::{self, State};
+use crate::trace;
+use core::fmt::{self, Write};
+use x86_64::registers::control::{Cr2, Cr3};
+use x86_64::registers::rflags;
+
+/// Trace records included in the dump.
+pub const RECENT_TRACE_EVENTS: usize = 8;
+
+/// Write the machine state shown by failing assertions to `out`.
+pub fn write_state(out: &mut dyn Write) -> fmt::Result {
+    let (rsp, rbp): (u64, u64);
+    unsafe {
+        asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack));
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack));
+    }
+    let (level_4_table, _) = Cr3::read();
+
+    writeln!(out, "machine state:")?;
+    let mut running = None;
+    let _ = scheduler::for_each_thread(|thread| {
+        if thread.state == State::Running {
+            running = Some(*thread);
+        }
+    });
+    match running {
+        Some(thread) => writeln!(
+            out,
+            "  thread    {} {} (cpu {})",
+            thread.id,
+            thread.name,
+            trace::current_cpu()
+        )?,
+        // Failed with the scheduler locked.
+        None => writeln!(out, "  thread    unknown (cpu {})", trace::current_cpu())?,
+    }
+    writeln!(out, "  interrupt nesting {}", interrupts::nesting_depth())?;
+    writeln!(
+        out,
+        "  rsp {:#018x}  rbp {:#018x}  rflags {:#x}",
+        rsp,
+        rbp,
+        rflags::read_raw()
+    )?;
+    writeln!(
+        out,
+        "  cr2 {:#018x}  cr3 {:#018x}",
+        Cr2::read().as_u64(),
+        level_4_table.start_address().as_u64()
+    )?;
+
+    let mut total = 0;
+    trace::for_each_record(|_| total += 1);
+    writeln!(out, "  last trace events:")?;
+    let mut seen = 0;
+    let mut result = Ok(());
+    trace::for_each_record(|record| {
+        seen += 1;
+        if seen + RECENT_TRACE_EVENTS > total {
+            result = result.and_then(|_| writeln!(out, "    {}", record));
+        }
+    });
+    if total == 0 {
+        writeln!(out, "    (none, see `trace::enable`)")?;
+    }
+    result
+}
+
+/// Print the machine state without risking a deadlock on the console.
+/// Use `kassert!` instead.
+#[doc(hidden)]
+pub fn dump_state() {
+    let _ = write_state(&mut crate::serial::panic_writer());
+}
+
+/// Like `assert!`, but dumps the machine state before panicking.
+#[macro_export]
+macro_rules! kassert {
+    ($cond:expr $(,)?) => {
+        $crate::kassert!($cond, concat!("assertion failed: ", stringify!($cond)))
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            $crate::kassert::dump_state();
+            panic!($($arg)+);
+        }
+    };
+}
+
+/// Like `assert_eq!`, but dumps the machine state before panicking.
+#[macro_export]
+macro_rules! kassert_eq {
+    ($left:expr, $right:expr $(,)?) => {
+        $crate::kassert_eq!($left, $right, "")
+    };
+    ($left:expr, $right:expr, $($arg:tt)+) => {
+        match (&$left, &$right) {
+            (left, right) => {
+                if !(*left == *right) {
+                    $crate::kassert::dump_state();
+                    panic!(
+                        "assertion failed: `(left == right)`\n  left: `{:?}`,\n right: `{:?}`: {}",
+                        left,
+                        right,
+                        format_args!($($arg)+)
+                    );
+                }
+            }
+        }
+    };
+}
+
+#[test_case]
+fn test_kassert_passes() {
+    let ticks = crate::interrupts::COUNTERS[crate::interrupts::TIMER_COUNTER].count();
+    kassert!(ticks < u64::MAX);
+    kassert!(
+        !trace::is_enabled(trace::Subsystem::Scheduler),
+        "with a message {}",
+        ticks
+    );
+    kassert_eq!(interrupts::nesting_depth(), 0);
+    kassert_eq!(trace::current_cpu(), 0, "with a message");
+}
@@ -0,0 +1,599 @@
+//! The PS/2 keyboard: what it types, its lock LEDs and how fast held
+//! keys repeat.
+//!
+//! Every scancode from the keyboard interrupt goes through
+//! `handle_scancode`, which hands it to whoever subscribed, in one of
+//! two ways:
+//!
+//! - `Mode::Chars` gets what was typed as `char`s, with shift, ctrl
+//!   and caps lock applied, which is what a shell wants,
+//! - `Mode::Events` gets every key going down and coming up as a
+//!   `KeyEvent`, which is what a game or a console switcher wants.
+//!
+//! Each subscriber has its own queue, so one that doesn't keep up only
+//! loses its own keys (the oldest ones), never anybody else's. Only a
+//! US layout and scancode set 1, which is what the 8042 translates
+//! everything to.
+//!
+//! Commands go to the keyboard through the 8042's data port, and it
+//! answers every byte with an ACK, or RESEND if it wants it again. The
+//! answers are polled for with interrupts off, so the interrupt
+//! handler doesn't take them for keys. The lock keys put the LEDs
+//! right themselves, `kbd` can do it by hand as well.
+//!
+//! Ctrl-Insert and Shift-Insert are the clipboard's, see `clipboard`,
+//! and PageUp and PageDown scroll the screen, see `vga_buffer`. The
+//! arrows, Home, End and Delete go to the line discipline as the escape
+//! sequences a terminal would send, so the console's line editor gets
+//! them the same from either.
+use crate::boot_timing::read_tsc;
+use crate::error::{KernelError, KernelResult};
+use crate::latency;
+use crate::line_discipline;
+use crate::shell::{self, CommandFailed, CommandResult};
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+const DATA: u16 = 0x60;
+const STATUS: u16 = 0x64;
+const OUTPUT_FULL: u8 = 1;
+const INPUT_FULL: u8 = 1 << 1;
+
+const SET_LEDS: u8 = 0xED;
+const SET_TYPEMATIC: u8 = 0xF3;
+const ACK: u8 = 0xFA;
+const RESEND: u8 = 0xFE;
+const RETRIES: usize = 3;
+/// A second or so, the same as `selftest` gives the controller.
+const TIMEOUT_CYCLES: u64 = 1 << 31;
+
+/// Subscribers there can be at once.
+pub const MAX_SUBSCRIBERS: usize = 4;
+/// Keys each subscriber's queue holds before the oldest get dropped.
+pub const QUEUE_LENGTH: usize = 32;
+
+/// Comes before the scancode of the keys the original PC keyboard
+/// didn't have, the arrows and such.
+const EXTENDED: u8 = 0xE0;
+/// Set in the scancode of a key coming up.
+const BREAK: u8 = 0x80;
+const LEFT_SHIFT: u8 = 0x2A;
+const RIGHT_SHIFT: u8 = 0x36;
+const CTRL: u8 = 0x1D;
+const CAPS_LOCK: u8 = 0x3A;
+const NUM_LOCK: u8 = 0x45;
+const SCROLL_LOCK: u8 = 0x46;
+/// Extended, so `0xE052`.
+const INSERT: u8 = 0x52;
+/// Also extended, they scroll the console.
+const PAGE_UP: u8 = 0x49;
+const PAGE_DOWN: u8 = 0x51;
+/// The rest of the extended keys the line editor knows.
+const UP: u8 = 0x48;
+const DOWN: u8 = 0x50;
+const LEFT: u8 = 0x4B;
+const RIGHT: u8 = 0x4D;
+const HOME: u8 = 0x47;
+const END: u8 = 0x4F;
+const DELETE: u8 = 0x53;
+
+/// What each scancode types, by itself and with shift. 0 is nothing.
+const PLAIN: &[u8] = b"\0\x1b1234567890-=\x08\tqwertyuiop[]\n\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+const SHIFTED: &[u8] = b"\0\x1b!@#$%^&*()_+\x08\tQWERTYUIOP{}\n\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ";
+
+/// The three lock keys, as bits of the set LEDs command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Lock {
+    Scroll = 1,
+    Num = 1 << 1,
+    Caps = 1 << 2,
+}
+
+impl Lock {
+    const ALL: [Lock; 3] = [Lock::Caps, Lock::Num, Lock::Scroll];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Lock::Scroll => "scroll",
+            Lock::Num => "num",
+            Lock::Caps => "caps",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Lock> {
+        Lock::ALL.iter().copied().find(|lock| lock.name() == name)
+    }
+}
+
+/// Locks that are on, as `Lock` bits.
+static LOCKS: AtomicU8 = AtomicU8::new(0);
+/// The typematic byte last sent, the keyboard's default until then.
+static TYPEMATIC: AtomicU8 = AtomicU8::new(0x2B);
+
+/// Adds the `kbd` shell command.
+pub fn init() {
+    shell::register(
+        "kbd",
+        "kbd [caps|num|scroll|rate HZ [DELAY_MS]]: lock LEDs and key repeat",
+        kbd_command,
+    )
+    .expect("kbd command");
+}
+
+fn wait_for(status: &mut Port<u8>, ready: impl Fn(u8) -> bool) -> KernelResult<()> {
+    let start = read_tsc();
+    while !ready(unsafe { status.read() }) {
+        if read_tsc() - start > TIMEOUT_CYCLES {
+            return Err(KernelError::Timeout);
+        }
+    }
+    Ok(())
+}
+
+/// Send the bytes of one command, each of which has to be ACKed.
+fn send(command: &[u8]) -> KernelResult<()> {
+    let mut data = Port::<u8>::new(DATA);
+    let mut status = Port::<u8>::new(STATUS);
+    latency::without_interrupts(|| {
+        // Whatever is still in the buffer isn't an answer to us.
+        while unsafe { status.read() } & OUTPUT_FULL != 0 {
+            unsafe { data.read() };
+        }
+        for &byte in command {
+            let mut tries = 0;
+            loop {
+                wait_for(&mut status, |s| s & INPUT_FULL == 0)?;
+                unsafe { data.write(byte) };
+                wait_for(&mut status, |s| s & OUTPUT_FULL != 0)?;
+                match unsafe { data.read() } {
+                    ACK => break,
+                    RESEND if tries < RETRIES => tries += 1,
+                    _ => return Err(KernelError::DeviceError),
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+/// How a subscriber wants its keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Chars,
+    Events,
+}
+
+/// A key going down or coming up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    /// The set 1 make code, with `0xE0` in the top byte for extended
+    /// keys: the up arrow is `0xE048`.
+    pub code: u16,
+    pub pressed: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Input {
+    Char(char),
+    Event(KeyEvent),
+}
+
+#[derive(Clone, Copy)]
+struct Queue {
+    mode: Mode,
+    inputs: [Option<Input>; QUEUE_LENGTH],
+    /// Where the oldest input is.
+    first: usize,
+    len: usize,
+    dropped: u64,
+}
+
+impl Queue {
+    fn push(&mut self, input: Input) {
+        if self.len == QUEUE_LENGTH {
+            self.first = (self.first + 1) % QUEUE_LENGTH;
+            self.len -= 1;
+            self.dropped += 1;
+        }
+        self.inputs[(self.first + self.len) % QUEUE_LENGTH] = Some(input);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<Input> {
+        if self.len == 0 {
+            return None;
+        }
+        let first = self.first;
+        self.first = (first + 1) % QUEUE_LENGTH;
+        self.len -= 1;
+        self.inputs[first].take()
+    }
+}
+
+/// The modifiers held down and what came before this scancode.
+struct Decoder {
+    extended: bool,
+    shift: bool,
+    ctrl: bool,
+}
+
+static DECODER: Mutex<Decoder> = Mutex::new(Decoder {
+    extended: false,
+    shift: false,
+    ctrl: false,
+});
+static QUEUES: Mutex<[Option<Queue>; MAX_SUBSCRIBERS]> = Mutex::new([None; MAX_SUBSCRIBERS]);
+
+/// Keys for one consumer, until it's dropped.
+pub struct Subscription {
+    slot: usize,
+}
+
+/// Start getting keys in `mode`. `NoSpace` if there are
+/// `MAX_SUBSCRIBERS` already.
+pub fn subscribe(mode: Mode) -> KernelResult<Subscription> {
+    latency::without_interrupts(|| {
+        let mut queues = QUEUES.lock();
+        let slot = queues
+            .iter()
+            .position(Option::is_none)
+            .ok_or(KernelError::NoSpace)?;
+        queues[slot] = Some(Queue {
+            mode,
+            inputs: [None; QUEUE_LENGTH],
+            first: 0,
+            len: 0,
+            dropped: 0,
+        });
+        Ok(Subscription { slot })
+    })
+}
+
+impl Subscription {
+    fn pop(&self) -> Option<Input> {
+        latency::without_interrupts(|| QUEUES.lock()[self.slot].as_mut().and_then(Queue::pop))
+    }
+
+    /// The oldest character not picked up yet, for `Mode::Chars`.
+    pub fn next_char(&self) -> Option<char> {
+        match self.pop()? {
+            Input::Char(c) => Some(c),
+            Input::Event(_) => None,
+        }
+    }
+
+    /// The oldest key event not picked up yet, for `Mode::Events`.
+    pub fn next_event(&self) -> Option<KeyEvent> {
+        match self.pop()? {
+            Input::Event(event) => Some(event),
+            Input::Char(_) => None,
+        }
+    }
+
+    /// Keys this subscriber lost to a full queue.
+    pub fn dropped(&self) -> u64 {
+        latency::without_interrupts(|| QUEUES.lock()[self.slot].map_or(0, |queue| queue.dropped))
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        latency::without_interrupts(|| QUEUES.lock()[self.slot] = None);
+    }
+}
+
+/// What pressing `code` types, if anything.
+fn translate(code: u8, shift: bool, ctrl: bool) -> Option<char> {
+    let table = if shift { SHIFTED } else { PLAIN };
+    let mut byte = *table.get(usize::from(code))?;
+    if byte == 0 {
+        return None;
+    }
+    if byte.is_ascii_alphabetic() {
+        if is_locked(Lock::Caps) {
+            byte ^= 0x20;
+        }
+        if ctrl {
+            byte &= 0x1F;
+        }
+    }
+    Some(char::from(byte))
+}
+
+/// Called by the keyboard interrupt with whatever the data port has.
+pub fn handle_interrupt() {
+    let mut data = Port::<u8>::new(DATA);
+    let mut status = Port::<u8>::new(STATUS);
+    // A command's ACK raises it too, but `send` already took that.
+    if unsafe { status.read() } & OUTPUT_FULL != 0 {
+        let byte = unsafe { data.read() };
+        crate::replay::record(crate::replay::Input::Scancode(byte));
+        handle_scancode(byte);
+    }
+}
+
+/// Decode one byte from the keyboard and queue it for every
+/// subscriber.
+pub fn handle_scancode(byte: u8) {
+    latency::without_interrupts(|| decode(byte))
+}
+
+fn decode(byte: u8) {
+    if byte == EXTENDED {
+        DECODER.lock().extended = true;
+        return;
+    }
+    let pressed = byte & BREAK == 0;
+    let make = byte & !BREAK;
+    let (extended, c, chord, page, sequence) = {
+        let mut decoder = DECODER.lock();
+        let extended = core::mem::replace(&mut decoder.extended, false);
+        match make {
+            LEFT_SHIFT | RIGHT_SHIFT if !extended => decoder.shift = pressed,
+            CTRL => decoder.ctrl = pressed,
+            _ => {}
+        }
+        let c = if pressed && !extended {
+            translate(make, decoder.shift, decoder.ctrl)
+        } else {
+            None
+        };
+        let chord = if pressed && extended && make == INSERT {
+            Some((decoder.ctrl, decoder.shift))
+        } else {
+            None
+        };
+        let page = match make {
+            PAGE_UP if pressed && extended => Some(true),
+            PAGE_DOWN if pressed && extended => Some(false),
+            _ => None,
+        };
+        let sequence = if pressed && extended {
+            escape_sequence(make)
+        } else {
+            None
+        };
+        (extended, c, chord, page, sequence)
+    };
+    let lock = match make {
+        CAPS_LOCK => Some(Lock::Caps),
+        NUM_LOCK => Some(Lock::Num),
+        SCROLL_LOCK => Some(Lock::Scroll),
+        _ => None,
+    };
+    if let (Some(lock), true, false) = (lock, pressed, extended) {
+        // The lock is on either way, only the LED would be wrong.
+        let _ = toggle(lock);
+    }
+    #[cfg(not(feature = "no-vga"))]
+    match chord {
+        // Nowhere to say the screen was busy, the copy can be tried
+        // again.
+        Some((true, _)) => {
+            let _ = crate::clipboard::copy();
+        }
+        Some((false, true)) => crate::clipboard::paste(),
+        _ => {}
+    }
+    #[cfg(not(feature = "no-vga"))]
+    if let Some(up) = page {
+        crate::vga_buffer::scroll_page(up);
+    }
+    #[cfg(feature = "no-vga")]
+    let _ = (chord, page);
+    let event = KeyEvent {
+        code: if extended {
+            u16::from(EXTENDED) << 8 | u16::from(make)
+        } else {
+            u16::from(make)
+        },
+        pressed,
+    };
+    for queue in QUEUES.lock().iter_mut().flatten() {
+        if queue.mode == Mode::Events {
+            queue.push(Input::Event(event));
+        }
+    }
+    if let Some(c) = c {
+        type_char(c);
+    }
+    for &byte in sequence.unwrap_or(b"") {
+        line_discipline::feed(line_discipline::Source::Keyboard, byte);
+    }
+}
+
+/// What a VT100 sends for the extended key `code`, if the line editor
+/// knows it.
+fn escape_sequence(code: u8) -> Option<&'static [u8]> {
+    match code {
+        UP => Some(b"\x1b[A"),
+        DOWN => Some(b"\x1b[B"),
+        RIGHT => Some(b"\x1b[C"),
+        LEFT => Some(b"\x1b[D"),
+        HOME => Some(b"\x1b[H"),
+        END => Some(b"\x1b[F"),
+        DELETE => Some(b"\x1b[3~"),
+        _ => None,
+    }
+}
+
+/// Hand `c` to every `Mode::Chars` subscriber and the console's line
+/// discipline as if it had been typed.
+pub fn type_char(c: char) {
+    latency::without_interrupts(|| {
+        for queue in QUEUES.lock().iter_mut().flatten() {
+            if queue.mode == Mode::Chars {
+                queue.push(Input::Char(c));
+            }
+        }
+    });
+    if c.is_ascii() {
+        line_discipline::feed(line_discipline::Source::Keyboard, c as u8);
+    }
+}
+
+/// Press and let go of the key that types `c`, with shift if it
+/// needs it, as scancodes through `handle_scancode`. False if no key
+/// types it. Caps lock still applies to letters.
+pub fn press(c: char) -> bool {
+    if c == '\0' || !c.is_ascii() {
+        return false;
+    }
+    let byte = c as u8;
+    let (code, shift) = match PLAIN.iter().position(|&plain| plain == byte) {
+        Some(code) => (code as u8, false),
+        None => match SHIFTED.iter().position(|&shifted| shifted == byte) {
+            Some(code) => (code as u8, true),
+            None => return false,
+        },
+    };
+    if shift {
+        handle_scancode(LEFT_SHIFT);
+    }
+    handle_scancode(code);
+    handle_scancode(code | BREAK);
+    if shift {
+        handle_scancode(LEFT_SHIFT | BREAK);
+    }
+    true
+}
+
+/// Whether `lock` is on.
+pub fn is_locked(lock: Lock) -> bool {
+    LOCKS.load(Ordering::Relaxed) & lock as u8 != 0
+}
+
+/// Turn `lock` on or off, and its LED with it.
+pub fn set_lock(lock: Lock, on: bool) -> KernelResult<()> {
+    let locks = if on {
+        LOCKS.fetch_or(lock as u8, Ordering::Relaxed) | lock as u8
+    } else {
+        LOCKS.fetch_and(!(lock as u8), Ordering::Relaxed) & !(lock as u8)
+    };
+    send(&[SET_LEDS, locks])
+}
+
+/// Flip `lock`, for when its key is pressed.
+pub fn toggle(lock: Lock) -> KernelResult<()> {
+    set_lock(lock, !is_locked(lock))
+}
+
+/// Time between repeats of typematic rate `code`, in microseconds.
+/// Goes from about 30 Hz at 0 down to 2 Hz at 31.
+fn repeat_period_us(code: u8) -> u32 {
+    let mantissa = 8 + u32::from(code & 0b111);
+    let exponent = u32::from(code >> 3 & 0b11);
+    mantissa * (1 << exponent) * 4170
+}
+
+/// The rate code closest to `hz` repeats a second.
+pub fn rate_code(hz: u32) -> u8 {
+    let wanted = 1_000_000 / hz.max(1);
+    (0..32u8)
+        .min_by_key(|&code| (i64::from(repeat_period_us(code)) - i64::from(wanted)).abs())
+        .unwrap_or(0)
+}
+
+/// The delay code closest to `ms` before a held key starts repeating.
+/// The keyboard can do 250, 500, 750 or 1000.
+pub fn delay_code(ms: u32) -> u8 {
+    ((ms.max(250).min(1000) + 125) / 250 - 1) as u8
+}
+
+/// Repeat held keys about `hz` times a second, after `delay_ms`.
+pub fn set_typematic(hz: u32, delay_ms: u32) -> KernelResult<()> {
+    let byte = delay_code(delay_ms) << 5 | rate_code(hz);
+    send(&[SET_TYPEMATIC, byte])?;
+    TYPEMATIC.store(byte, Ordering::Relaxed);
+    Ok(())
+}
+
+fn kbd_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    let mut words = args.split_whitespace();
+    let result = match words.next() {
+        None => {
+            for &lock in Lock::ALL.iter() {
+                let state = if is_locked(lock) { "on" } else { "off" };
+                let _ = writeln!(out, "{} lock {}", lock.name(), state);
+            }
+            let typematic = TYPEMATIC.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "repeat every {} ms after {} ms",
+                repeat_period_us(typematic & 0x1F) / 1000,
+                (u32::from(typematic >> 5) + 1) * 250
+            );
+            return Ok(());
+        }
+        Some("rate") => {
+            let hz = words.next().and_then(|word| word.parse().ok());
+            let delay = words.next().map_or(Some(500), |word| word.parse().ok());
+            match (hz, delay) {
+                (Some(hz), Some(delay)) => set_typematic(hz, delay),
+                _ => return usage(out),
+            }
+        }
+        Some(name) => match Lock::from_name(name) {
+            Some(lock) => toggle(lock),
+            None => return usage(out),
+        },
+    };
+    result.map_err(|error| {
+        let _ = writeln!(out, "keyboard: {}", error);
+        CommandFailed
+    })
+}
+
+fn usage(out: &mut dyn fmt::Write) -> CommandResult {
+    let _ = writeln!(out, "usage: kbd [caps|num|scroll|rate HZ [DELAY_MS]]");
+    Err(CommandFailed)
+}
+
+#[test_case]
+fn test_typematic_codes() {
+    assert_eq!(rate_code(30), 0);
+    assert_eq!(rate_code(2), 31);
+    assert_eq!(rate_code(10), 0x0C);
+    assert_eq!(delay_code(0), 0);
+    assert_eq!(delay_code(500), 1);
+    assert_eq!(delay_code(5000), 3);
+    // QEMU's keyboard ACKs these like a real one.
+    set_lock(Lock::Num, true).expect("set LEDs");
+    assert!(is_locked(Lock::Num));
+    set_lock(Lock::Num, false).expect("set LEDs");
+}
+
+#[test_case]
+fn test_chars_and_events() {
+    let chars = subscribe(Mode::Chars).unwrap();
+    let events = subscribe(Mode::Events).unwrap();
+    // Shift, h, up arrow.
+    for &byte in &[0x2A, 0x23, 0xA3, 0xAA, 0xE0, 0x48, 0xE0, 0xC8] {
+        handle_scancode(byte);
+    }
+    assert_eq!(chars.next_char(), Some('H'));
+    assert_eq!(chars.next_char(), None);
+    let codes = [
+        (0x2A, true),
+        (0x23, true),
+        (0x23, false),
+        (0x2A, false),
+        (0xE048, true),
+        (0xE048, false),
+    ];
+    for &(code, pressed) in codes.iter() {
+        assert_eq!(events.next_event(), Some(KeyEvent { code, pressed }));
+    }
+
+    // Events nobody reads only fill their own queue.
+    for _ in 0..QUEUE_LENGTH {
+        handle_scancode(0x1E);
+        handle_scancode(0x9E);
+        assert_eq!(chars.next_char(), Some('a'));
+    }
+    assert_eq!(chars.dropped(), 0);
+    assert_eq!(events.dropped(), QUEUE_LENGTH as u64);
+}
@@ -0,0 +1,298 @@
+//! Kernel symbol table. `lookup` puts names on addresses for
+//! backtraces, the profiler and tracepoints, `resolve` goes the other
+//! way for the debugging commands and the module linker.
+//!
+//! The bootloader only strips debug info from the kernel before
+//! putting it in the boot image, so the ELF `.symtab` is still there.
+//! The whole ELF file also stays where stage 2 loaded it in physical
+//! memory (marked as `Kernel` in the memory map, never handed out).
+//! So instead of generating a separate table at build time we just
+//! read the one the linker already made, through the physical memory
+//! mapping.
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::{fmt, slice, str};
+use spin::Once;
+use x86_64::VirtAddr;
+
+const SHT_SYMTAB: u32 = 2;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const SYMBOL_SIZE: usize = 24;
+
+/// The kernel's `.symtab` and the string table its names live in.
+#[derive(Debug, Clone, Copy)]
+pub struct SymbolTable {
+    symbols: &'static [u8],
+    strings: &'static [u8],
+}
+
+static SYMBOLS: Once<SymbolTable> = Once::new();
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    let b = bytes.get(offset..offset + 2)?;
+    Some(u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let b = bytes.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    let b = bytes.get(offset..offset + 8)?;
+    let mut word = [0; 8];
+    word.copy_from_slice(b);
+    Some(u64::from_le_bytes(word))
+}
+
+impl SymbolTable {
+    /// Find `.symtab` (and the `.strtab` it links to) in an ELF image.
+    pub fn from_elf(elf: &'static [u8]) -> Option<SymbolTable> {
+        if elf.get(..4)? != b"\x7fELF" {
+            return None;
+        }
+        let section_offset = read_u64(elf, 0x28)? as usize;
+        let section_size = usize::from(read_u16(elf, 0x3A)?);
+        let section_count = usize::from(read_u16(elf, 0x3C)?);
+
+        let section = |index: usize| -> Option<&'static [u8]> {
+            let header = section_offset + index * section_size;
+            let offset = read_u64(elf, header + 24)? as usize;
+            let size = read_u64(elf, header + 32)? as usize;
+            elf.get(offset..offset + size)
+        };
+
+        (0..section_count).find_map(|index| {
+            let header = section_offset + index * section_size;
+            if read_u32(elf, header + 4)? != SHT_SYMTAB {
+                return None;
+            }
+            let link = read_u32(elf, header + 40)? as usize;
+            Some(SymbolTable {
+                symbols: section(index)?,
+                strings: section(link)?,
+            })
+        })
+    }
+
+    fn name(&self, offset: usize) -> Option<&'static str> {
+        let rest = self.strings.get(offset..)?;
+        let end = rest.iter().position(|&b| b == 0)?;
+        str::from_utf8(&rest[..end]).ok()
+    }
+
+    /// The function containing `address`, with the offset into it.
+    pub fn lookup(&self, address: u64) -> Option<(&'static str, u64)> {
+        self.symbols
+            .chunks_exact(SYMBOL_SIZE)
+            .filter(|symbol| symbol[4] & 0xf == STT_FUNC)
+            .find_map(|symbol| {
+                let start = read_u64(symbol, 8)?;
+                let size = read_u64(symbol, 16)?;
+                if address >= start && address < start + size.max(1) {
+                    let name = self.name(read_u32(symbol, 0)? as usize)?;
+                    Some((name, address - start))
+                } else {
+                    None
+                }
+            })
+    }
+
+    /// Address of the function or static called `name`, either mangled
+    /// or the way `Demangle` shows it.
+    pub fn resolve(&self, name: &str) -> Option<u64> {
+        self.symbols
+            .chunks_exact(SYMBOL_SIZE)
+            .filter(|symbol| matches!(symbol[4] & 0xf, STT_FUNC | STT_OBJECT))
+            .find_map(|symbol| {
+                let address = read_u64(symbol, 8)?;
+                let symbol_name = self.name(read_u32(symbol, 0)? as usize)?;
+                if address != 0 && (symbol_name == name || demangles_to(symbol_name, name)) {
+                    Some(address)
+                } else {
+                    None
+                }
+            })
+    }
+}
+
+/// Whether `mangled` shows up as `name` demangled, without needing a
+/// buffer to demangle it into.
+fn demangles_to(mangled: &str, name: &str) -> bool {
+    struct Compare<'a>(&'a str);
+
+    impl fmt::Write for Compare<'_> {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.0 = self.0.strip_prefix(s).ok_or(fmt::Error)?;
+            Ok(())
+        }
+    }
+
+    let mut compare = Compare(name);
+    fmt::write(&mut compare, format_args!("{}", Demangle(mangled))).is_ok() && compare.0.is_empty()
+}
+
+/// Pick up the symbol table from the kernel ELF file.
+///
+/// # Safety
+///
+/// All of physical memory must be mapped at `physical_memory_offset`.
+pub unsafe fn init(memory_map: &MemoryMap, physical_memory_offset: VirtAddr) {
+    let kernel = memory_map
+        .iter()
+        .find(|region| region.region_type == MemoryRegionType::Kernel);
+
+    if let Some(region) = kernel {
+        let start = physical_memory_offset + region.range.start_addr();
+        let len = region.range.end_addr() - region.range.start_addr();
+        let elf = slice::from_raw_parts(start.as_ptr::<u8>(), len as usize);
+        if let Some(table) = SymbolTable::from_elf(elf) {
+            SYMBOLS.call_once(|| table);
+        }
+    }
+}
+
+/// The function containing `address` and how far into it the address
+/// is, if we have a symbol table and the address is in one.
+pub fn lookup(address: u64) -> Option<(&'static str, u64)> {
+    SYMBOLS.r#try()?.lookup(address)
+}
+
+/// Address of the function or static called `name`, if we have a
+/// symbol table and it's in there. See `SymbolTable::resolve`.
+pub fn resolve(name: &str) -> Option<u64> {
+    SYMBOLS.r#try()?.resolve(name)
+}
+
+/// An address given to a shell command: a number like `parse_number`
+/// takes, or the name of a function or static.
+pub fn parse_address(word: &str) -> Option<u64> {
+    crate::shell::parse_number(word).or_else(|| resolve(word))
+}
+
+/// Displays a mangled Rust symbol name the way it was written, minus
+/// the trailing hash: `_ZN7blog_os4init17h0123456789abcdefE` comes out
+/// as `blog_os::init`. Anything that isn't a legacy mangled name is
+/// shown as is.
+pub struct Demangle<'a>(pub &'a str);
+
+impl fmt::Display for Demangle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut rest = match self
+            .0
+            .strip_prefix("_ZN")
+            .and_then(|inner| inner.strip_suffix('E'))
+        {
+            Some(rest) => rest,
+            None => return f.write_str(self.0),
+        };
+
+        let mut first = true;
+        while !rest.is_empty() {
+            let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+            let len: usize = match rest[..digits].parse() {
+                Ok(len) if digits + len <= rest.len() => len,
+                _ => return f.write_str(self.0),
+            };
+            let part = &rest[digits..digits + len];
+            rest = &rest[digits + len..];
+
+            // The last part is a hash that only makes the name unique.
+            if rest.is_empty() && is_hash(part) {
+                break;
+            }
+            if !first {
+                f.write_str("::")?;
+            }
+            first = false;
+            write_unescaped(f, part)?;
+        }
+        Ok(())
+    }
+}
+
+fn is_hash(part: &str) -> bool {
+    part.len() == 17 && part.starts_with('h') && part[1..].bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Undo the `$LT$`-style escapes used for characters that aren't
+/// allowed in symbol names.
+fn write_unescaped(f: &mut fmt::Formatter, mut part: &str) -> fmt::Result {
+    if part.starts_with("_$") {
+        part = &part[1..];
+    }
+    while !part.is_empty() {
+        if part.starts_with("..") {
+            f.write_str("::")?;
+            part = &part[2..];
+        } else if part.starts_with('$') {
+            let end = match part[1..].find('$') {
+                Some(end) => end + 1,
+                None => return f.write_str(part),
+            };
+            let replacement = match &part[1..end] {
+                "SP" => "@",
+                "BP" => "*",
+                "RF" => "&",
+                "LT" => "<",
+                "GT" => ">",
+                "LP" => "(",
+                "RP" => ")",
+                "C" => ",",
+                "u20" => " ",
+                "u27" => "'",
+                "u5b" => "[",
+                "u5d" => "]",
+                "u7b" => "{",
+                "u7d" => "}",
+                "u7e" => "~",
+                _ => &part[..=end],
+            };
+            f.write_str(replacement)?;
+            part = &part[end + 1..];
+        } else {
+            let end = part
+                .find(|c| c == '$' || c == '.')
+                .map_or(part.len(), |end| end.max(1));
+            f.write_str(&part[..end])?;
+            part = &part[end..];
+        }
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_demangle() {
+    use alloc::string::String;
+    use core::fmt::Write;
+
+    let check = |mangled: &str, expected: &str| {
+        let mut buffer = String::new();
+        write!(buffer, "{}", Demangle(mangled)).unwrap();
+        assert_eq!(buffer, expected);
+    };
+
+    check("_ZN7blog_os4init17h0123456789abcdefE", "blog_os::init");
+    check(
+        "_ZN39_$LT$T$u20$as$u20$blog_os..Testable$GT$3run17h0123456789abcdefE",
+        "<T as blog_os::Testable>::run",
+    );
+    check("rust_begin_unwind", "rust_begin_unwind");
+}
+
+#[test_case]
+fn test_resolve() {
+    assert!(demangles_to(
+        "_ZN7blog_os4init17h0123456789abcdefE",
+        "blog_os::init"
+    ));
+    assert!(!demangles_to(
+        "_ZN7blog_os4init17h0123456789abcdefE",
+        "blog_os::ini"
+    ));
+    // The test kernel reads the symbol table before running tests.
+    let address = resolve as usize as u64;
+    assert_eq!(resolve("blog_os::ksyms::resolve"), Some(address));
+    assert_eq!(parse_address("blog_os::ksyms::resolve"), Some(address));
+    assert_eq!(resolve("blog_os::no_such_function"), None);
+}
@@ -0,0 +1,174 @@
+//! What KVM does for a guest that knows it's one.
+//!
+//! - kvmclock: the host keeps a structure of ours up to date with the
+//!   guest's time at some TSC reading and how fast the TSC goes, so
+//!   reading the time is a `rdtsc` and some maths, with none of the
+//!   drift of calibrating the TSC against an emulated PIT ourselves.
+//!   It's a clock source like any other, see `time`.
+//! - PV EOI: before injecting an interrupt the host can set a bit in a
+//!   word of ours, meaning the EOI can be skipped. Clearing the bit is
+//!   all `apic` has to do then, and the write to the APIC that would
+//!   have exited to the host doesn't happen.
+//!
+//! Both are handed to the host by physical address, so `enable` needs
+//! `memory::init`, and is called after it like `apic::enable`.
+use crate::error::{KernelError, KernelResult};
+use crate::memory;
+use crate::platform::{self, Hypervisor};
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use x86_64::registers::model_specific::Msr;
+use x86_64::VirtAddr;
+
+const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b56_4d01;
+const MSR_KVM_PV_EOI_EN: u32 = 0x4b56_4d04;
+/// Bits of the feature leaf's `eax`.
+const FEATURE_CLOCKSOURCE2: u32 = 1 << 3;
+const FEATURE_PV_EOI: u32 = 1 << 6;
+/// Bit 0 of both MSRs turns the thing on.
+const MSR_ENABLE: u64 = 1;
+
+/// What the host writes for kvmclock, `pvclock_vcpu_time_info`.
+#[repr(C)]
+struct PvClock {
+    /// Odd while the host is in the middle of an update.
+    version: u32,
+    _pad: u32,
+    tsc_timestamp: u64,
+    system_time: u64,
+    tsc_to_system_mul: u32,
+    tsc_shift: i8,
+    flags: u8,
+    _pad2: [u8; 2],
+}
+
+/// Aligned so it's never split across pages.
+#[repr(C, align(32))]
+struct Shared<T>(UnsafeCell<T>);
+
+// The host writes it, we only ever read it with volatile reads.
+unsafe impl<T> Sync for Shared<T> {}
+
+static CLOCK: Shared<PvClock> = Shared(UnsafeCell::new(PvClock {
+    version: 0,
+    _pad: 0,
+    tsc_timestamp: 0,
+    system_time: 0,
+    tsc_to_system_mul: 0,
+    tsc_shift: 0,
+    flags: 0,
+    _pad2: [0; 2],
+}));
+static CLOCK_ON: AtomicBool = AtomicBool::new(false);
+
+#[repr(align(4))]
+struct EoiWord(AtomicU32);
+
+static PV_EOI: EoiWord = EoiWord(AtomicU32::new(0));
+static PV_EOI_ON: AtomicBool = AtomicBool::new(false);
+
+/// KVM's feature bits, `None` if this isn't KVM.
+fn features() -> Option<u32> {
+    match platform::hypervisor() {
+        Some((Hypervisor::Kvm, max_leaf)) if max_leaf > platform::HYPERVISOR_LEAF => {
+            Some(unsafe { __cpuid(platform::HYPERVISOR_LEAF + 1) }.eax)
+        }
+        _ => None,
+    }
+}
+
+fn physical_address<T>(value: &T) -> KernelResult<u64> {
+    let address = VirtAddr::new(value as *const T as u64);
+    let mapping = memory::translate(address).ok_or(KernelError::NotReady)?;
+    Ok(mapping.address.as_u64())
+}
+
+/// Turn on kvmclock and PV EOI, whichever KVM has. `Unsupported` if it
+/// isn't KVM or has neither.
+pub fn enable() -> KernelResult<()> {
+    let features = features().ok_or(KernelError::Unsupported)?;
+    if features & (FEATURE_CLOCKSOURCE2 | FEATURE_PV_EOI) == 0 {
+        return Err(KernelError::Unsupported);
+    }
+    if features & FEATURE_CLOCKSOURCE2 != 0 {
+        let address = physical_address(&CLOCK)?;
+        unsafe { Msr::new(MSR_KVM_SYSTEM_TIME_NEW).write(address | MSR_ENABLE) };
+        CLOCK_ON.store(true, Ordering::Release);
+        // It wasn't there when `time::init` looked.
+        crate::time::probe_again();
+    }
+    if features & FEATURE_PV_EOI != 0 {
+        let address = physical_address(&PV_EOI)?;
+        unsafe { Msr::new(MSR_KVM_PV_EOI_EN).write(address | MSR_ENABLE) };
+        PV_EOI_ON.store(true, Ordering::Release);
+    }
+    crate::klog!(
+        Info,
+        "kvmclock {}, pv eoi {}",
+        if is_clock_on() { "on" } else { "off" },
+        if PV_EOI_ON.load(Ordering::Relaxed) {
+            "on"
+        } else {
+            "off"
+        }
+    );
+    Ok(())
+}
+
+pub fn is_clock_on() -> bool {
+    CLOCK_ON.load(Ordering::Acquire)
+}
+
+/// For `time`: whether kvmclock can be the clock source.
+pub(crate) fn clock_probe() -> bool {
+    is_clock_on()
+}
+
+/// For `time`: nanoseconds since the guest started, going by the host.
+pub(crate) fn clock_read_ns() -> u64 {
+    let clock = CLOCK.0.get();
+    loop {
+        unsafe {
+            let version = ptr::read_volatile(&(*clock).version);
+            if version & 1 != 0 {
+                continue;
+            }
+            let timestamp = ptr::read_volatile(&(*clock).tsc_timestamp);
+            let system_time = ptr::read_volatile(&(*clock).system_time);
+            let multiplier = ptr::read_volatile(&(*clock).tsc_to_system_mul);
+            let shift = ptr::read_volatile(&(*clock).tsc_shift);
+            let mut delta = _rdtsc().wrapping_sub(timestamp);
+            if shift < 0 {
+                delta >>= -shift;
+            } else {
+                delta <<= shift;
+            }
+            let ns = system_time + ((u128::from(delta) * u128::from(multiplier)) >> 32) as u64;
+            // Updated while we were reading, take it again.
+            if ptr::read_volatile(&(*clock).version) == version {
+                return ns;
+            }
+        }
+    }
+}
+
+/// For `apic`: whether the host said this interrupt's EOI can be
+/// skipped. Says so only once per interrupt.
+pub(crate) fn skip_eoi() -> bool {
+    PV_EOI_ON.load(Ordering::Relaxed) && PV_EOI.0.fetch_and(!1, Ordering::AcqRel) & 1 != 0
+}
+
+#[test_case]
+fn test_kvmclock_goes_forwards() {
+    if !is_clock_on() {
+        // Not KVM, or `enable` said no.
+        assert!(!clock_probe());
+        return;
+    }
+    let first = clock_read_ns();
+    let second = clock_read_ns();
+    assert!(second >= first);
+    assert!(first > 0);
+}
@@ -0,0 +1,202 @@
+//! How long interrupt handlers run and how long interrupts stay off.
+//!
+//! Every handler reads the TSC on the way in and out, and the time in
+//! between goes into a histogram of its own (`InterruptCounter::time`).
+//! Code that needs interrupts off goes through `without_interrupts`
+//! here instead of x86_64's, which does the same for the time they were
+//! off. An interrupt that comes in during one of those has to wait for
+//! the rest of it, so the two together bound how late a keypress or a
+//! tick gets handled.
+//!
+//! Times are in TSC cycles. `latency` also shows them in microseconds
+//! if the TSC is the clock source and so has a known rate.
+use crate::boot_timing::read_tsc;
+use crate::interrupts::COUNTERS;
+use crate::shell::{self, CommandFailed, CommandResult};
+use crate::time;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+
+/// Bucket `n` counts times of `2^n` up to `2^(n + 1)` cycles, the last
+/// one anything longer.
+pub const BUCKETS: usize = 32;
+
+/// Counts times by powers of two.
+pub struct Histogram {
+    count: AtomicU64,
+    total: AtomicU64,
+    max: AtomicU64,
+    buckets: [AtomicU64; BUCKETS],
+}
+
+// Atomics aren't `Copy`, a const is the only way to fill an array of them.
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+impl Histogram {
+    pub const fn new() -> Histogram {
+        Histogram {
+            count: ZERO,
+            total: ZERO,
+            max: ZERO,
+            buckets: [ZERO; BUCKETS],
+        }
+    }
+
+    pub fn record(&self, cycles: u64) {
+        let bucket = (63 - cycles.max(1).leading_zeros() as usize).min(BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total.fetch_add(cycles, Ordering::Relaxed);
+        self.max.fetch_max(cycles, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn mean(&self) -> u64 {
+        self.total.load(Ordering::Relaxed) / self.count().max(1)
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max.load(Ordering::Relaxed)
+    }
+
+    /// How many times fell in bucket `n`.
+    pub fn bucket(&self, n: usize) -> u64 {
+        self.buckets[n].load(Ordering::Relaxed)
+    }
+
+    pub fn reset(&self) {
+        self.count.store(0, Ordering::Relaxed);
+        self.total.store(0, Ordering::Relaxed);
+        self.max.store(0, Ordering::Relaxed);
+        self.buckets
+            .iter()
+            .for_each(|bucket| bucket.store(0, Ordering::Relaxed));
+    }
+}
+
+/// Time spent in `without_interrupts`.
+pub static INTERRUPTS_OFF: Histogram = Histogram::new();
+
+/// Adds the `latency` shell command.
+pub fn init() {
+    shell::register(
+        "latency",
+        "latency [NAME|off|reset]: interrupt handler and interrupts off times",
+        latency_command,
+    )
+    .expect("latency command");
+}
+
+/// Run `f` with interrupts off, like x86_64's `without_interrupts`, and
+/// count how long they were off for. Only the outermost call counts,
+/// inside a handler or another one of these they already were.
+pub fn without_interrupts<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    if !interrupts::are_enabled() {
+        return f();
+    }
+    interrupts::disable();
+    let start = read_tsc();
+    let result = f();
+    INTERRUPTS_OFF.record(read_tsc().wrapping_sub(start));
+    interrupts::enable();
+    result
+}
+
+/// Shows a number of cycles, and microseconds if we know the TSC rate.
+struct Cycles(u64);
+
+impl fmt::Display for Cycles {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match time::tsc_hz() {
+            Some(hz) => write!(f, "{:>10} ({}us)", self.0, self.0 * 1_000_000 / hz),
+            None => write!(f, "{:>10}", self.0),
+        }
+    }
+}
+
+fn write_histogram(out: &mut dyn fmt::Write, histogram: &Histogram) -> fmt::Result {
+    let most = (0..BUCKETS).map(|n| histogram.bucket(n)).max().unwrap_or(0);
+    for n in (0..BUCKETS).filter(|&n| histogram.bucket(n) != 0) {
+        let count = histogram.bucket(n);
+        let bar = (count * 40 / most.max(1)) as usize;
+        write!(out, "  >= {} ", Cycles(1 << n))?;
+        for _ in 0..bar.max(1) {
+            write!(out, "#")?;
+        }
+        writeln!(out, " {}", count)?;
+    }
+    Ok(())
+}
+
+fn latency_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    match args {
+        "" => {
+            let _ = writeln!(
+                out,
+                "  {:<14} {:>8} {:>10} {:>10}",
+                "", "count", "mean", "max"
+            );
+            let summary = |out: &mut dyn fmt::Write, name: &str, histogram: &Histogram| {
+                let _ = writeln!(
+                    out,
+                    "  {:<14} {:>8} {} {}",
+                    name,
+                    histogram.count(),
+                    Cycles(histogram.mean()),
+                    Cycles(histogram.max())
+                );
+            };
+            for counter in COUNTERS.iter() {
+                summary(out, counter.name, &counter.time);
+            }
+            summary(out, "interrupts off", &INTERRUPTS_OFF);
+        }
+        "reset" => {
+            COUNTERS.iter().for_each(|counter| counter.time.reset());
+            INTERRUPTS_OFF.reset();
+        }
+        "off" => {
+            let _ = write_histogram(out, &INTERRUPTS_OFF);
+        }
+        name => match COUNTERS.iter().find(|counter| counter.name == name) {
+            Some(counter) => {
+                let _ = write_histogram(out, &counter.time);
+            }
+            None => {
+                let _ = write!(out, "usage: latency [NAME|off|reset], names:");
+                for counter in COUNTERS.iter() {
+                    let _ = write!(out, " `{}`", counter.name);
+                }
+                let _ = writeln!(out);
+                return Err(CommandFailed);
+            }
+        },
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_histogram() {
+    let histogram = Histogram::new();
+    histogram.record(0);
+    histogram.record(3);
+    histogram.record(1000);
+    assert_eq!(histogram.count(), 3);
+    assert_eq!(histogram.max(), 1000);
+    assert_eq!(histogram.mean(), 334);
+    assert_eq!(histogram.bucket(0), 1);
+    assert_eq!(histogram.bucket(1), 1);
+    assert_eq!(histogram.bucket(9), 1);
+
+    let off = INTERRUPTS_OFF.count();
+    without_interrupts(|| without_interrupts(|| ()));
+    assert_eq!(INTERRUPTS_OFF.count(), off + 1);
+}
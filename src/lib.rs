@@ -2,27 +2,149 @@
 #![cfg_attr(test, no_main)] // Define that we have no main and test
 #![feature(custom_test_frameworks)] // Allow custom testing framework interface
 #![feature(abi_x86_interrupt)] // Required as the extern x86_interrupt convention is unstable
+#![feature(asm)] // Inline assembly for reading registers
+#![feature(global_asm)] // Switching stacks between kernel threads
+#![feature(alloc_error_handler)] // Running out of heap has to go somewhere
+#![feature(wake_trait)] // `Waker`s from an `Arc`, for the executor
 #![test_runner(crate::test_runner)] // Define what runs a test
 #![reexport_test_harness_main = "test_main"] // Avoid name clashes with normal main for the runner
 
 // Unsure why we import this
 extern crate rlibc;
+// `Box`, `Vec` and friends, on the heap from `allocator`
+extern crate alloc;
 
 // Required for panic handling
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
+pub mod acpi;
+pub mod allocator;
+pub mod apic;
+pub mod ata;
+pub mod bench;
+pub mod block;
+pub mod boot_timing;
+pub mod breakpoints;
+pub mod build_info;
+#[cfg(not(feature = "no-vga"))]
+pub mod clipboard;
+pub mod cmdline;
+pub mod crash_dump;
+pub mod error;
+pub mod fault_injection;
+pub mod fs;
+pub mod futex;
 pub mod gdt;
+pub mod hpet;
+pub mod idle;
 pub mod interrupts;
+pub mod ioapic;
+pub mod kassert;
+pub mod keyboard;
+pub mod ksyms;
+pub mod kvm;
+pub mod latency;
+pub mod line_discipline;
+pub mod line_editor;
+pub mod log;
+pub mod machine_check;
+pub mod mbuf;
+pub mod memaudit;
+pub mod memfuzz;
+pub mod memory;
+pub mod module;
+pub mod monitor;
+#[cfg(not(feature = "no-vga"))]
+pub mod mouse_pointer;
+pub mod net;
+pub mod panic_policy;
+pub mod pci;
+pub mod platform;
+pub mod profiler;
+pub mod program;
+pub mod pvh;
+pub mod random;
+pub mod rcu;
+pub mod replay;
+pub mod scheduler;
+pub mod selftest;
 pub mod serial;
+pub mod shell;
+pub mod smbios;
+pub mod smp;
+pub mod stack_canary;
+pub mod stats;
+pub mod step_trace;
+pub mod syscall;
+pub mod task;
+pub mod test_report;
+pub mod time;
+pub mod trace;
+pub mod ui;
+pub mod unwind;
+pub mod user;
+pub mod usermode;
+#[cfg(not(feature = "no-vga"))]
 pub mod vga_buffer;
+pub mod watchdog;
+pub mod watchpoints;
 
 pub fn init() {
+    boot_timing::start(); // everything below is measured from here
     gdt::init(); // initialise the global descriptor table
+    boot_timing::record("gdt");
     interrupts::init_idt(); // interrupt descriptor table
+    syscall::init(); // and the way in from ring 3 that isn't an interrupt
+    boot_timing::record("idt");
+    interrupts::init_pics(); // hardware interrupts from the 8259s
+    x86_64::instructions::interrupts::enable();
+    boot_timing::record("pic");
+    time::init(); // pick a clock source, needs the timer going
+    boot_timing::record("time");
+    // The rest only adds shell commands
+    shell::init();
+    #[cfg(feature = "heap-profile")]
+    allocator::profile::init();
+    allocator::bench::init();
+    apic::init();
+    block::init();
+    boot_timing::init();
+    breakpoints::init();
+    build_info::init();
+    #[cfg(not(feature = "no-vga"))]
+    clipboard::init();
+    fs::init();
+    idle::init();
+    ioapic::init();
+    keyboard::init();
+    log::init();
+    machine_check::init();
+    memaudit::init();
+    memfuzz::init();
+    net::init();
+    crash_dump::init();
+    latency::init();
+    panic_policy::init();
+    pci::init();
+    platform::init();
+    profiler::init();
+    random::init();
+    replay::init();
+    scheduler::init();
+    serial::init();
+    smp::init();
+    stack_canary::init();
+    stats::init();
+    step_trace::init();
+    trace::init();
+    watchpoints::init();
 }
 
 // Define a more explicit type for testing
 pub trait Testable {
+    fn name(&self) -> &'static str;
     fn run(&self);
 }
 
@@ -32,57 +154,155 @@ impl<T> Testable for T
 where
     T: Fn(),
 {
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+
     fn run(&self) {
-        serial_print!("{}...\t", core::any::type_name::<T>());
+        test_report::begin_test(self.name());
+        let heap = allocator::heap_stats();
         self();
-        serial_println!("[ok]");
+        // Every test has to give back what it allocated.
+        if let Some(leaks) = allocator::LeakReport::since(heap) {
+            test_report::fail(&leaks);
+        }
+        test_report::pass();
     }
 }
 
+// How long a single test gets before the watchdog fails it.
+// Should stay well under `test-timeout` in Cargo.toml.
+pub const DEFAULT_TEST_TIMEOUT_MS: u64 = 10_000;
+
+static TEST_TIMEOUT_MS: AtomicU64 = AtomicU64::new(DEFAULT_TEST_TIMEOUT_MS);
+
+/// Change how long each test is allowed to run for.
+pub fn set_test_timeout_ms(timeout_ms: u64) {
+    TEST_TIMEOUT_MS.store(timeout_ms, Ordering::SeqCst);
+}
+
+pub fn test_timeout_ms() -> u64 {
+    TEST_TIMEOUT_MS.load(Ordering::SeqCst)
+}
+
 // This is what handles the tests being run.
 pub fn test_runner(tests: &[&dyn Testable]) {
     // Prints to the original caller of the qemu test
-    serial_println!("Running {} tests", tests.len());
-    for test in tests {
+    // `test=` and `shard=` on the kernel command line, see `test_report`
+    let filter = test_report::filter();
+    let selected = |&(index, test): &(usize, &&dyn Testable)| filter.selects(index, test.name());
+    let count = tests.iter().enumerate().filter(selected).count();
+    test_report::begin_suite(count, tests.len() - count);
+    for (_, test) in tests.iter().enumerate().filter(selected) {
+        // Hung tests get caught by the timer interrupt, see `watchdog`
+        watchdog::arm(test.name(), test_timeout_ms());
         test.run();
+        watchdog::disarm();
+        // Faults a test set up are only meant for that test
+        fault_injection::reset();
     }
-    exit_qemu(QemuExitCode::Success);
+    test_report::finish();
 }
 
 // Panic handler for the case where tests fail.
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
-    serial_println!("[failed]\n");
-    serial_println!("Error: {}\n", info);
-    exit_qemu(QemuExitCode::Failed);
-    loop {}
+    if !panic_policy::enter() {
+        nested_test_panic(info)
+    }
+    test_report::fail(info)
 }
 
-// Exit codes for QEMU - required for smoother testing
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u32)]
-pub enum QemuExitCode {
-    Success = 0x10,
-    Failed = 0x11,
+// A panic while reporting one, likely from inside `test_report`, so
+// don't go back in there.
+fn nested_test_panic(info: &PanicInfo) -> ! {
+    panic_policy::report_nested(info);
+    platform::exit(platform::ExitStatus::Failed)
 }
 
-// We write out to the exit device specified in the `test-args`
-// in Cargo.toml
-pub fn exit_qemu(exit_code: QemuExitCode) {
-    use x86_64::instructions::port::Port;
+// Tests that are expected to panic can't live in the normal
+// `test_runner` as we abort on panic - there is no way to carry
+// on with the next test. Instead each of those gets its own test
+// binary (`harness = false` in Cargo.toml) that calls
+// `run_should_panic` from `_start` and uses `should_panic_handler`
+// as its panic handler.
 
+/// Run a test that has to panic. If it returns instead we report
+/// the failure and exit QEMU.
+pub fn run_should_panic(name: &'static str, test: fn()) -> ! {
+    test_report::begin_test(name);
+    test();
+    test_report::fail(&"test did not panic")
+}
+
+// Panic handler for the expected-to-panic tests, the panic
+// is the success case here.
+pub fn should_panic_handler(info: &PanicInfo) -> ! {
+    if !panic_policy::enter() {
+        nested_test_panic(info)
+    }
+    test_report::pass();
+    test_report::finish()
+}
+
+// Integration tests that expect a CPU exception (e.g. a stack
+// overflow turning into a double fault) can't use our IDT, as its
+// handlers panic. They bring their own instead and these helpers
+// do the fiddly bits of setting one up.
+
+/// Double fault handler for tests where the double fault is the
+/// expected outcome.
+pub extern "x86-interrupt" fn test_double_fault_handler(
+    _stack_frame: &mut InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    test_report::pass();
+    test_report::finish()
+}
+
+/// Make a double fault the success case for this IDT.
+///
+/// The handler runs on the double fault IST stack from `gdt`, so
+/// it works even when the fault was caused by a blown kernel stack.
+/// Requires `gdt::init` to have been called before the IDT is loaded.
+pub fn expect_double_fault(idt: &mut InterruptDescriptorTable) {
     unsafe {
-        let mut port = Port::new(0xf4);
-        port.write(exit_code as u32);
+        idt.double_fault
+            .set_handler_fn(test_double_fault_handler)
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
     }
 }
 
+#[cfg(test)]
+bootloader::entry_point!(test_kernel_main);
+
 /// Entry point for `cargo xtest`
 #[cfg(test)]
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+fn test_kernel_main(boot_info: &'static bootloader::BootInfo) -> ! {
+    serial_println!("{}", build_info::Banner);
     init();
+    let physical_memory_offset = x86_64::VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(&boot_info.memory_map, physical_memory_offset);
+        allocator::init_heap().expect("heap");
+        fs::mount_root().expect("root file system");
+        ata::probe();
+        // Names in backtraces of failing tests
+        ksyms::init(&boot_info.memory_map, physical_memory_offset);
+    }
+    stack_canary::protect_boot_stack().expect("boot stack canary");
+    // Not there on every machine, the tests don't need it.
+    let _ = apic::enable();
+    let _ = hpet::enable();
+    let _ = kvm::enable();
     test_main();
-    loop {}
+    idle::idle_loop()
+}
+
+// Called when the heap can't fit an allocation that isn't allowed to
+// fail, like a `Box::new`
+#[alloc_error_handler]
+fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    panic!("out of heap allocating {:?}", layout)
 }
 
 #[cfg(test)]
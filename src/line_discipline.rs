@@ -0,0 +1,301 @@
+//! Turning what comes in from the keyboard or serial into lines.
+//!
+//! Both go through the same `LineDiscipline`, so typing at the shell
+//! works the same on the screen and through `-serial stdio`:
+//!
+//! - bytes go through a `KeyDecoder` into a `LineEditor`, so the
+//!   arrows, Home, End, Delete, the `Ctrl` shortcuts and the history
+//!   on up/down all work; the keyboard sends its extended keys as the
+//!   same escape sequences a terminal does,
+//! - what's typed is echoed back, serial terminals don't do that
+//!   themselves when QEMU puts them in raw mode. Typing at the end of
+//!   the line just echoes the character; anything else redraws the
+//!   line with ANSI cursor movement, which `vga_buffer`'s writer turns
+//!   into moving its cursor,
+//! - `\r`, `\n` and `\r\n` all end a line, terminals differ on what
+//!   Enter sends,
+//! - `Ctrl-C` throws away the line and raises an interrupt, which a
+//!   long running command can check for with `interrupted`.
+//!
+//! `shell::start_console` picks up finished lines with `read_line`,
+//! from both sources, and `feed` wakes it when there's a new one. The
+//! kernel monitor keeps its own editor, it runs with everything else
+//! stopped.
+use crate::latency;
+use crate::line_editor::{Key, KeyDecoder, LineEditor, LINE_LENGTH};
+use crate::scheduler::{self, ThreadId};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+
+/// Finished lines kept until they're read, newer ones get dropped.
+pub const MAX_LINES: usize = 4;
+
+const CTRL_C: u8 = 0x03;
+
+/// Where input came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Keyboard,
+    Serial,
+}
+
+/// What a byte did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// Nothing to act on yet.
+    None,
+    /// A line is ready for `read_line`.
+    Line,
+    /// `Ctrl-C`.
+    Interrupt,
+}
+
+#[derive(Clone, Copy)]
+struct Line {
+    bytes: [u8; LINE_LENGTH],
+    len: usize,
+}
+
+const EMPTY: Line = Line {
+    bytes: [0; LINE_LENGTH],
+    len: 0,
+};
+
+pub struct LineDiscipline {
+    decoder: KeyDecoder,
+    /// The line being typed.
+    editor: LineEditor,
+    /// The last byte ended a line with `\r`, so a `\n` right after
+    /// it is the same Enter.
+    after_cr: bool,
+    lines: [Line; MAX_LINES],
+    /// Where the oldest finished line is.
+    first: usize,
+    len: usize,
+}
+
+impl LineDiscipline {
+    pub const fn new() -> LineDiscipline {
+        LineDiscipline {
+            decoder: KeyDecoder::new(),
+            editor: LineEditor::new(),
+            after_cr: false,
+            lines: [EMPTY; MAX_LINES],
+            first: 0,
+            len: 0,
+        }
+    }
+
+    /// Take one byte of input, echoing to `echo`.
+    pub fn feed(&mut self, byte: u8, echo: &mut dyn fmt::Write) -> Event {
+        let after_cr = core::mem::replace(&mut self.after_cr, false);
+        if byte == b'\n' && after_cr {
+            return Event::None;
+        }
+        if byte == CTRL_C {
+            self.decoder = KeyDecoder::new();
+            self.editor.clear();
+            let _ = echo.write_str("^C\n");
+            return Event::Interrupt;
+        }
+        match self.decoder.feed(byte) {
+            Some(Key::Enter) => {
+                self.after_cr = byte == b'\r';
+                self.editor.key(Key::Enter);
+                let _ = echo.write_char('\n');
+                if self.len < MAX_LINES {
+                    let line = &mut self.lines[(self.first + self.len) % MAX_LINES];
+                    line.len = self.editor.line().len();
+                    line.bytes[..line.len].copy_from_slice(self.editor.line().as_bytes());
+                    self.len += 1;
+                }
+                self.editor.clear();
+                Event::Line
+            }
+            // Completing needs the shell's commands, and the shell isn't
+            // ours to ask from an interrupt handler.
+            Some(Key::Tab) | None => Event::None,
+            Some(key) => {
+                self.edit(key, echo);
+                Event::None
+            }
+        }
+    }
+
+    /// Apply `key` to the line and show what it did.
+    fn edit(&mut self, key: Key, echo: &mut dyn fmt::Write) {
+        let (was_at, was_len) = (self.editor.cursor(), self.editor.line().len());
+        self.editor.key(key);
+        let line = self.editor.line();
+        let at_end = was_at == was_len && self.editor.cursor() == line.len();
+        let _ = match key {
+            Key::Char(byte) if at_end && line.len() == was_len + 1 => {
+                echo.write_char(char::from(byte))
+            }
+            Key::Backspace if at_end && line.len() + 1 == was_len => echo.write_str("\x08 \x08"),
+            _ => redraw(echo, was_at, line, self.editor.cursor()),
+        };
+    }
+
+    /// Copy the oldest finished line into `buffer`, returning its
+    /// length.
+    pub fn read_line(&mut self, buffer: &mut [u8; LINE_LENGTH]) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+        let line = &self.lines[self.first];
+        buffer[..line.len].copy_from_slice(&line.bytes[..line.len]);
+        self.first = (self.first + 1) % MAX_LINES;
+        self.len -= 1;
+        Some(line.len)
+    }
+}
+
+/// Go back `from` columns to where the line starts, write it out over
+/// what was there and put the cursor at `cursor`.
+fn redraw(echo: &mut dyn fmt::Write, from: usize, line: &str, cursor: usize) -> fmt::Result {
+    if from > 0 {
+        write!(echo, "\x1b[{}D", from)?;
+    }
+    write!(echo, "{}\x1b[K", line)?;
+    let behind = line.len() - cursor;
+    if behind > 0 {
+        write!(echo, "\x1b[{}D", behind)?;
+    }
+    Ok(())
+}
+
+static KEYBOARD: Mutex<LineDiscipline> = Mutex::new(LineDiscipline::new());
+static SERIAL: Mutex<LineDiscipline> = Mutex::new(LineDiscipline::new());
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+/// Who to wake when a line is finished.
+static READER: Mutex<Option<ThreadId>> = Mutex::new(None);
+
+fn discipline(source: Source) -> &'static Mutex<LineDiscipline> {
+    match source {
+        Source::Keyboard => &KEYBOARD,
+        Source::Serial => &SERIAL,
+    }
+}
+
+/// Echoes back to where the input came from, without waiting on a
+/// lock: this runs in interrupt handlers.
+struct Echo(Source);
+
+impl fmt::Write for Echo {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Without a screen the keyboard's echo goes to serial too.
+        #[cfg(not(feature = "no-vga"))]
+        {
+            if self.0 == Source::Keyboard {
+                if let Some(mut screen) = crate::vga_buffer::WRITER.try_lock() {
+                    screen.write_str(s)?;
+                }
+                return Ok(());
+            }
+        }
+        crate::serial::panic_writer().write_str(s)
+    }
+}
+
+/// Prints back to where the input came from, for what a command has to
+/// say about a line. This one waits for the screen or the port, so not
+/// from an interrupt handler.
+pub struct Output(pub Source);
+
+impl fmt::Write for Output {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match self.0 {
+            Source::Keyboard => crate::print!("{}", s),
+            Source::Serial => crate::serial_print!("{}", s),
+        }
+        Ok(())
+    }
+}
+
+/// Take one byte of input from `source`. Called from the keyboard and
+/// serial interrupts.
+pub fn feed(source: Source, byte: u8) -> Event {
+    let event =
+        latency::without_interrupts(|| discipline(source).lock().feed(byte, &mut Echo(source)));
+    match event {
+        Event::Interrupt => {
+            INTERRUPTED.store(true, Ordering::SeqCst);
+            INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+        }
+        Event::Line => {
+            if let Some(reader) = latency::without_interrupts(|| *READER.lock()) {
+                scheduler::unblock(reader);
+            }
+        }
+        Event::None => {}
+    }
+    event
+}
+
+/// Have `feed` unblock `thread` whenever a line is finished on either
+/// source. There's one reader, this replaces the last one.
+pub fn set_reader(thread: ThreadId) {
+    latency::without_interrupts(|| *READER.lock() = Some(thread));
+}
+
+/// The oldest line typed on `source` that hasn't been read yet.
+pub fn read_line(source: Source, buffer: &mut [u8; LINE_LENGTH]) -> Option<usize> {
+    latency::without_interrupts(|| discipline(source).lock().read_line(buffer))
+}
+
+/// Whether `Ctrl-C` was pressed since the last time this was asked.
+pub fn interrupted() -> bool {
+    INTERRUPTED.swap(false, Ordering::SeqCst)
+}
+
+/// `Ctrl-C`s since boot.
+pub fn interrupts() -> u64 {
+    INTERRUPTS.load(Ordering::Relaxed)
+}
+
+#[test_case]
+fn test_echo_erase_and_line_endings() {
+    let mut echo = alloc::string::String::new();
+    let mut discipline = LineDiscipline::new();
+    let mut events = [Event::None; 16];
+    for (event, &byte) in events.iter_mut().zip(b"ls\x7f\x7fhelp\r\nx\x03a\nb\r") {
+        *event = discipline.feed(byte, &mut echo);
+    }
+    assert_eq!(events[8], Event::Line);
+    // The `\n` of `\r\n` isn't another line.
+    assert_eq!(events[9], Event::None);
+    assert_eq!(events[11], Event::Interrupt);
+    assert_eq!(
+        echo.as_bytes(),
+        &b"ls\x08 \x08\x08 \x08help\nx^C\na\nb\n"[..]
+    );
+
+    let mut line = [0; LINE_LENGTH];
+    for &expected in &["help", "a", "b"] {
+        let len = discipline.read_line(&mut line).unwrap();
+        assert_eq!(&line[..len], expected.as_bytes());
+    }
+    assert_eq!(discipline.read_line(&mut line), None);
+}
+
+#[test_case]
+fn test_editing_in_the_middle_and_history() {
+    let mut echo = alloc::string::String::new();
+    let mut discipline = LineDiscipline::new();
+    for &byte in b"hlp\x1b[D\x1b[De\r\x1b[A\r" {
+        discipline.feed(byte, &mut echo);
+    }
+    assert_eq!(
+        echo,
+        "hlp\x1b[3Dhlp\x1b[K\x1b[1D\x1b[2Dhlp\x1b[K\x1b[2D\x1b[1Dhelp\x1b[K\x1b[2D\nhelp\x1b[K\n"
+    );
+
+    let mut line = [0; LINE_LENGTH];
+    for _ in 0..2 {
+        let len = discipline.read_line(&mut line).unwrap();
+        assert_eq!(&line[..len], b"help");
+    }
+}
@@ -0,0 +1,417 @@
+//! A small line editor for the shell.
+//!
+//! Knows about moving the cursor, inserting and deleting in the middle
+//! of the line, the usual `Ctrl-A`/`Ctrl-E`/`Ctrl-K` shortcuts and a
+//! history of earlier lines on up/down. It doesn't do any I/O: bytes
+//! from the terminal go through `KeyDecoder` and into `LineEditor::key`,
+//! and whoever owns the terminal redraws the line from `line` and
+//! `cursor` afterwards.
+//!
+//! Tab completion needs to know what can be completed, so `Key::Tab`
+//! is left to the caller, which hands `complete` a `Completer`.
+
+/// Longest line we edit.
+pub const LINE_LENGTH: usize = 80;
+/// Earlier lines kept for up/down.
+pub const HISTORY_LENGTH: usize = 16;
+
+/// What a key press means to the editor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Char(u8),
+    Left,
+    Right,
+    Home,
+    End,
+    /// Delete the character before the cursor.
+    Backspace,
+    /// Delete the character under the cursor.
+    Delete,
+    /// Delete from the cursor to the end of the line.
+    KillToEnd,
+    Up,
+    Down,
+    /// Ignored by `LineEditor::key`, see `LineEditor::complete`.
+    Tab,
+    Enter,
+}
+
+/// Calls `f` with everything that could go in word number `word` of
+/// the line (0 being the command), given it starts with `prefix`.
+/// Candidates not starting with `prefix` are fine, they get skipped.
+pub type Completer = fn(word: usize, prefix: &str, f: &mut dyn FnMut(&str));
+
+/// What `LineEditor::complete` did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Completion {
+    /// Nothing starts with what's under the cursor.
+    NoMatch,
+    /// There was one candidate and it has been filled in.
+    Unique,
+    /// Several candidates, filled in as far as they agree. `list` is
+    /// set when this was the second tab in a row, i.e. the user wants
+    /// to see them (`for_each_candidate`).
+    Ambiguous { list: bool },
+}
+
+/// Turns the bytes a VT100-style terminal sends into `Key`s, escape
+/// sequences for the arrow keys included.
+#[derive(Debug)]
+pub struct KeyDecoder {
+    state: Escape,
+}
+
+/// How far into an escape sequence we are.
+#[derive(Debug, Clone, Copy)]
+enum Escape {
+    None,
+    /// Got `ESC`.
+    Started,
+    /// Got `ESC [` (or `ESC O`).
+    Bracket,
+    /// Got `ESC [` and a digit, waiting for the `~`.
+    Number(u8),
+}
+
+impl KeyDecoder {
+    pub const fn new() -> KeyDecoder {
+        KeyDecoder {
+            state: Escape::None,
+        }
+    }
+
+    /// Feed one byte, get a key once there is a whole one.
+    pub fn feed(&mut self, byte: u8) -> Option<Key> {
+        let (state, key) = match (self.state, byte) {
+            (Escape::None, 0x1B) => (Escape::Started, None),
+            (Escape::None, byte) => (Escape::None, control_key(byte)),
+            (Escape::Started, b'[') | (Escape::Started, b'O') => (Escape::Bracket, None),
+            (Escape::Bracket, b'0'..=b'9') => (Escape::Number(byte), None),
+            (Escape::Bracket, byte) => {
+                let key = match byte {
+                    b'A' => Some(Key::Up),
+                    b'B' => Some(Key::Down),
+                    b'C' => Some(Key::Right),
+                    b'D' => Some(Key::Left),
+                    b'H' => Some(Key::Home),
+                    b'F' => Some(Key::End),
+                    _ => None,
+                };
+                (Escape::None, key)
+            }
+            (Escape::Number(number), b'~') => {
+                let key = match number {
+                    b'1' | b'7' => Some(Key::Home),
+                    b'3' => Some(Key::Delete),
+                    b'4' | b'8' => Some(Key::End),
+                    _ => None,
+                };
+                (Escape::None, key)
+            }
+            // Anything we don't know gets dropped.
+            _ => (Escape::None, None),
+        };
+        self.state = state;
+        key
+    }
+}
+
+impl Default for KeyDecoder {
+    fn default() -> KeyDecoder {
+        KeyDecoder::new()
+    }
+}
+
+/// A byte on its own, outside of an escape sequence.
+fn control_key(byte: u8) -> Option<Key> {
+    match byte {
+        b'\r' | b'\n' => Some(Key::Enter),
+        b'\t' => Some(Key::Tab),
+        0x01 => Some(Key::Home),      // Ctrl-A
+        0x02 => Some(Key::Left),      // Ctrl-B
+        0x04 => Some(Key::Delete),    // Ctrl-D
+        0x05 => Some(Key::End),       // Ctrl-E
+        0x06 => Some(Key::Right),     // Ctrl-F
+        0x0B => Some(Key::KillToEnd), // Ctrl-K
+        0x0E => Some(Key::Down),      // Ctrl-N
+        0x10 => Some(Key::Up),        // Ctrl-P
+        0x08 | 0x7F => Some(Key::Backspace),
+        0x20..=0x7E => Some(Key::Char(byte)),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Line {
+    bytes: [u8; LINE_LENGTH],
+    len: usize,
+}
+
+impl Line {
+    const EMPTY: Line = Line {
+        bytes: [0; LINE_LENGTH],
+        len: 0,
+    };
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+/// The line being edited plus the history of earlier ones.
+pub struct LineEditor {
+    line: Line,
+    cursor: usize,
+    history: [Line; HISTORY_LENGTH],
+    /// How many entries of `history` are in use, newest last.
+    history_len: usize,
+    /// Which history entry up/down got us to, `history_len` for the
+    /// line being typed.
+    browsing: usize,
+    /// What was typed before we started going through the history.
+    draft: Line,
+    /// The last key was a tab that found several candidates.
+    tabbed: bool,
+}
+
+impl LineEditor {
+    pub const fn new() -> LineEditor {
+        LineEditor {
+            line: Line::EMPTY,
+            cursor: 0,
+            history: [Line::EMPTY; HISTORY_LENGTH],
+            history_len: 0,
+            browsing: 0,
+            draft: Line::EMPTY,
+            tabbed: false,
+        }
+    }
+
+    /// The line as it is right now. Only printable ASCII gets in, so
+    /// it is always valid UTF-8.
+    pub fn line(&self) -> &str {
+        core::str::from_utf8(self.line.as_bytes()).unwrap_or("")
+    }
+
+    /// Position of the cursor in `line`.
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Forget the current line and start a new one.
+    pub fn clear(&mut self) {
+        self.line = Line::EMPTY;
+        self.cursor = 0;
+        self.browsing = self.history_len;
+    }
+
+    /// Apply a key. Returns `true` on `Enter`, at which point `line`
+    /// is the finished line and it has been added to the history.
+    /// Call `clear` before editing the next one.
+    pub fn key(&mut self, key: Key) -> bool {
+        let len = self.line.len;
+        self.tabbed = false;
+        match key {
+            Key::Char(byte) if len < LINE_LENGTH => {
+                self.line
+                    .bytes
+                    .copy_within(self.cursor..len, self.cursor + 1);
+                self.line.bytes[self.cursor] = byte;
+                self.line.len += 1;
+                self.cursor += 1;
+            }
+            Key::Char(_) => {}
+            Key::Left => self.cursor = self.cursor.saturating_sub(1),
+            Key::Right => self.cursor = (self.cursor + 1).min(len),
+            Key::Home => self.cursor = 0,
+            Key::End => self.cursor = len,
+            Key::Backspace if self.cursor > 0 => {
+                self.line
+                    .bytes
+                    .copy_within(self.cursor..len, self.cursor - 1);
+                self.line.len -= 1;
+                self.cursor -= 1;
+            }
+            Key::Delete if self.cursor < len => {
+                self.line
+                    .bytes
+                    .copy_within(self.cursor + 1..len, self.cursor);
+                self.line.len -= 1;
+            }
+            Key::Backspace | Key::Delete => {}
+            Key::KillToEnd => self.line.len = self.cursor,
+            Key::Up if self.browsing > 0 => {
+                if self.browsing == self.history_len {
+                    self.draft = self.line;
+                }
+                self.browsing -= 1;
+                self.show(self.history[self.browsing]);
+            }
+            Key::Down if self.browsing < self.history_len => {
+                self.browsing += 1;
+                let line = if self.browsing == self.history_len {
+                    self.draft
+                } else {
+                    self.history[self.browsing]
+                };
+                self.show(line);
+            }
+            Key::Up | Key::Down | Key::Tab => {}
+            Key::Enter => {
+                self.remember();
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Complete the word before the cursor with what `completer` comes
+    /// up with.
+    pub fn complete(&mut self, completer: Completer) -> Completion {
+        let prefix_len = self.word_before_cursor().1.len();
+
+        // The longest start all the candidates share.
+        let mut common = Line::EMPTY;
+        let mut count = 0;
+        self.for_each_candidate(completer, |candidate| {
+            let candidate = candidate.as_bytes();
+            if count == 0 {
+                common.len = candidate.len().min(LINE_LENGTH);
+                common.bytes[..common.len].copy_from_slice(&candidate[..common.len]);
+            } else {
+                common.len = common
+                    .as_bytes()
+                    .iter()
+                    .zip(candidate)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+            }
+            count += 1;
+        });
+        let rest = &common.as_bytes()[prefix_len..];
+        match count {
+            0 => Completion::NoMatch,
+            1 => {
+                self.insert(rest);
+                // A directory, which the path can go on into.
+                if !common.as_bytes().ends_with(b"/") {
+                    self.insert(b" ");
+                }
+                Completion::Unique
+            }
+            _ => {
+                let list = self.tabbed;
+                self.insert(rest);
+                self.tabbed = true;
+                Completion::Ambiguous { list }
+            }
+        }
+    }
+
+    /// Call `f` with the candidates `complete` would pick from.
+    pub fn for_each_candidate(&self, completer: Completer, mut f: impl FnMut(&str)) {
+        let (word, prefix) = self.word_before_cursor();
+        completer(word, prefix, &mut |candidate| {
+            if candidate.starts_with(prefix) {
+                f(candidate)
+            }
+        });
+    }
+
+    /// Which word the cursor is at the end of, and that word up to the
+    /// cursor.
+    fn word_before_cursor(&self) -> (usize, &str) {
+        let before = &self.line()[..self.cursor];
+        let start = before.rfind(' ').map_or(0, |space| space + 1);
+        let word = before[..start].split_whitespace().count();
+        (word, &before[start..])
+    }
+
+    fn insert(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.key(Key::Char(byte));
+        }
+    }
+
+    fn show(&mut self, line: Line) {
+        self.line = line;
+        self.cursor = line.len;
+    }
+
+    /// Add the current line to the history, unless it's empty or the
+    /// same as the last one.
+    fn remember(&mut self) {
+        if self.line.len == 0 {
+            return;
+        }
+        if self.history_len > 0
+            && self.history[self.history_len - 1].as_bytes() == self.line.as_bytes()
+        {
+            return;
+        }
+        if self.history_len == HISTORY_LENGTH {
+            self.history.copy_within(1.., 0);
+            self.history_len -= 1;
+        }
+        self.history[self.history_len] = self.line;
+        self.history_len += 1;
+    }
+}
+
+#[test_case]
+fn test_editing_and_history() {
+    let mut decoder = KeyDecoder::new();
+    let mut editor = LineEditor::new();
+    let mut type_bytes = |editor: &mut LineEditor, bytes: &[u8]| {
+        bytes
+            .iter()
+            .filter_map(|&byte| decoder.feed(byte))
+            .fold(false, |_, key| editor.key(key))
+    };
+
+    // "bt" typed as "t", home, "b", then a stray char deleted again
+    assert!(type_bytes(&mut editor, b"t\x01bx\x1b[D\x1b[3~\r"));
+    assert_eq!(editor.line(), "bt");
+
+    editor.clear();
+    assert!(type_bytes(&mut editor, b"irq 12\x1b[D\x1b[D\x0b\r"));
+    assert_eq!(editor.line(), "irq ");
+
+    editor.clear();
+    type_bytes(&mut editor, b"x");
+    type_bytes(&mut editor, b"\x1b[A");
+    assert_eq!(editor.line(), "irq ");
+    type_bytes(&mut editor, b"\x1b[A\x1b[A");
+    assert_eq!(editor.line(), "bt");
+    type_bytes(&mut editor, b"\x1b[B\x1b[B");
+    assert_eq!(editor.line(), "x");
+}
+
+#[test_case]
+fn test_completion() {
+    fn completer(word: usize, _prefix: &str, f: &mut dyn FnMut(&str)) {
+        if word == 0 {
+            ["trace", "tasks", "bt"].iter().for_each(|name| f(name));
+        }
+    }
+
+    let mut editor = LineEditor::new();
+    editor.insert(b"b");
+    assert_eq!(editor.complete(completer), Completion::Unique);
+    assert_eq!(editor.line(), "bt ");
+    assert_eq!(editor.complete(completer), Completion::NoMatch);
+
+    editor.clear();
+    editor.insert(b"t");
+    assert_eq!(
+        editor.complete(completer),
+        Completion::Ambiguous { list: false }
+    );
+    assert_eq!(
+        editor.complete(completer),
+        Completion::Ambiguous { list: true }
+    );
+    editor.insert(b"r");
+    assert_eq!(editor.complete(completer), Completion::Unique);
+    assert_eq!(editor.line(), "trace ");
+}
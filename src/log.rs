@@ -0,0 +1,354 @@
+//! Log messages with a level, filtered per module.
+//!
+//! ```ignore
+//! klog!(Info, "using {} as clock source", source.name);
+//! ```
+//!
+//! A message goes to serial if its level is at or above the one set
+//! for the module it came from, the last part of its `module_path!`
+//! (`time`, `pci`, ...). Modules without a level of their own use the
+//! default, `Info` unless changed.
+//!
+//! Levels are changed with directives like `pci=debug,time=off` or a
+//! bare `warn` for the default, from the `loglevel` shell command or
+//! the kernel command line's `loglevel=`, which `init` applies. Both go
+//! through `apply`.
+//!
+//! Whatever the levels, only `BURST` messages go out back to back and
+//! `RATE` a second after that, the rest get dropped. A driver logging
+//! in a tight loop would otherwise have the machine doing nothing but
+//! write to a 38400 baud port. The next message that does go out
+//! after some were dropped comes with an `N messages suppressed` line
+//! before it, so it's clear something is missing.
+use crate::cmdline;
+use crate::error::{KernelError, KernelResult};
+use crate::latency;
+use crate::shell::{self, CommandFailed, CommandResult};
+use crate::time::{self, Duration};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Mutex;
+
+/// Modules that can have a level of their own.
+pub const MAX_FILTERS: usize = 16;
+/// Longest module name a filter can be for.
+pub const MAX_NAME: usize = 16;
+/// Messages that can go out back to back.
+pub const BURST: u64 = 32;
+/// Messages a second that go out once the burst is used up.
+pub const RATE: u64 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+    /// Nothing gets through. Not something to log at.
+    Off,
+}
+
+impl Level {
+    const ALL: [Level; 6] = [
+        Level::Trace,
+        Level::Debug,
+        Level::Info,
+        Level::Warn,
+        Level::Error,
+        Level::Off,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Trace => "trace",
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+            Level::Off => "off",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Level> {
+        Level::ALL
+            .iter()
+            .copied()
+            .find(|level| level.name() == name)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Filter {
+    name: [u8; MAX_NAME],
+    len: usize,
+    level: Level,
+}
+
+impl Filter {
+    fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.len]).unwrap_or("")
+    }
+}
+
+/// A token bucket, one token per message.
+struct Limiter {
+    tokens: u64,
+    /// When `tokens` was last topped up.
+    refilled_ms: u64,
+    /// Dropped since the last message that went out.
+    suppressed: u64,
+    total_suppressed: u64,
+}
+
+impl Limiter {
+    const fn new() -> Limiter {
+        Limiter {
+            tokens: BURST,
+            refilled_ms: 0,
+            suppressed: 0,
+            total_suppressed: 0,
+        }
+    }
+
+    /// Whether a message can go out at `now_ms`. If it can, also how
+    /// many were dropped before it.
+    fn admit(&mut self, now_ms: u64) -> Option<u64> {
+        let added = now_ms.saturating_sub(self.refilled_ms) * RATE / 1000;
+        if added > 0 {
+            // Only move on by the time the tokens took, so a bit of a
+            // second isn't lost every time.
+            self.refilled_ms += added * 1000 / RATE;
+            self.tokens = (self.tokens + added).min(BURST);
+        }
+        if self.tokens == 0 {
+            self.suppressed += 1;
+            self.total_suppressed += 1;
+            return None;
+        }
+        self.tokens -= 1;
+        Some(core::mem::replace(&mut self.suppressed, 0))
+    }
+}
+
+static LIMITER: Mutex<Limiter> = Mutex::new(Limiter::new());
+
+static DEFAULT_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static FILTERS: Mutex<[Option<Filter>; MAX_FILTERS]> = Mutex::new([None; MAX_FILTERS]);
+
+/// Adds the `loglevel` shell command and applies `loglevel=` from the
+/// kernel command line.
+pub fn init() {
+    shell::register(
+        "loglevel",
+        "loglevel [LEVEL] [MODULE=LEVEL...]: show or set log levels",
+        loglevel_command,
+    )
+    .expect("loglevel command");
+    if let Some(directives) = cmdline::value("loglevel") {
+        if let Err(error) = apply(directives) {
+            crate::klog!(Warn, "loglevel={}: {:?}", directives, error);
+        }
+    }
+}
+
+/// The last part of a module path, what filters go by.
+pub fn module_name(path: &'static str) -> &'static str {
+    path.rsplit("::").next().unwrap_or(path)
+}
+
+/// The level `module` logs at.
+pub fn level(module: &str) -> Level {
+    let default = Level::ALL[usize::from(DEFAULT_LEVEL.load(Ordering::Relaxed))];
+    // Logging from an interrupt while the filters are being changed
+    // falls back on the default rather than waiting.
+    FILTERS.try_lock().map_or(default, |filters| {
+        filters
+            .iter()
+            .flatten()
+            .find(|filter| filter.name() == module)
+            .map_or(default, |filter| filter.level)
+    })
+}
+
+pub fn set_default_level(level: Level) {
+    DEFAULT_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Give `module` its own level, `None` to have it use the default again.
+pub fn set_level(module: &str, level: Option<Level>) -> Result<(), LevelError> {
+    if module.len() > MAX_NAME {
+        return Err(LevelError::NameTooLong);
+    }
+    latency::without_interrupts(|| {
+        let mut filters = FILTERS.lock();
+        let existing = filters
+            .iter()
+            .position(|slot| slot.map_or(false, |filter| filter.name() == module));
+        let index = match (existing, level) {
+            (Some(index), _) => index,
+            (None, None) => return Ok(()),
+            (None, Some(_)) => filters
+                .iter()
+                .position(|slot| slot.is_none())
+                .ok_or(LevelError::Full)?,
+        };
+        filters[index] = level.map(|level| {
+            let mut name = [0; MAX_NAME];
+            name[..module.len()].copy_from_slice(module.as_bytes());
+            Filter {
+                name,
+                len: module.len(),
+                level,
+            }
+        });
+        Ok(())
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LevelError {
+    /// Not a `Level` name, or not `module=level`.
+    Invalid,
+    NameTooLong,
+    /// All `MAX_FILTERS` are taken.
+    Full,
+}
+
+impl From<LevelError> for KernelError {
+    fn from(error: LevelError) -> KernelError {
+        match error {
+            LevelError::Invalid | LevelError::NameTooLong => KernelError::InvalidArgument,
+            LevelError::Full => KernelError::NoSpace,
+        }
+    }
+}
+
+/// Apply comma or space separated directives: `module=level` or a bare
+/// `level` for the default. Stops at the first bad one.
+pub fn apply(directives: &str) -> Result<(), LevelError> {
+    for directive in directives.split(|c: char| c == ',' || c.is_whitespace()) {
+        let mut parts = directive.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(""), None) => {}
+            (Some(level), None) => {
+                set_default_level(Level::from_name(level).ok_or(LevelError::Invalid)?)
+            }
+            (Some(module), Some("default")) => set_level(module, None)?,
+            (Some(module), Some(level)) => set_level(
+                module,
+                Some(Level::from_name(level).ok_or(LevelError::Invalid)?),
+            )?,
+            _ => return Err(LevelError::Invalid),
+        }
+    }
+    Ok(())
+}
+
+/// Messages dropped by the rate limit since boot. `Busy` if a message
+/// is going through the limiter right now, this doesn't wait for it.
+pub fn suppressed() -> KernelResult<u64> {
+    latency::without_interrupts(|| LIMITER.try_lock().map(|limiter| limiter.total_suppressed))
+        .ok_or(KernelError::Busy)
+}
+
+/// What `klog!` calls once the level let the message through.
+#[doc(hidden)]
+pub fn write(level: Level, module: &str, args: fmt::Arguments) {
+    let now = time::uptime_ms();
+    let suppressed = match latency::without_interrupts(|| LIMITER.lock().admit(now)) {
+        Some(suppressed) => suppressed,
+        None => return,
+    };
+    // Goes around the serial lock if it's taken or we're in an
+    // interrupt, so logging can't deadlock.
+    let mut serial = crate::serial::panic_writer();
+    if suppressed > 0 {
+        let _ = writeln!(
+            serial,
+            "[{}] {:<5} log: {} messages suppressed",
+            Duration(now),
+            Level::Warn.name(),
+            suppressed
+        );
+    }
+    let _ = writeln!(
+        serial,
+        "[{}] {:<5} {}: {}",
+        Duration(now),
+        level.name(),
+        module,
+        args
+    );
+}
+
+/// Log a message at a `Level`, like `println!` otherwise.
+#[macro_export]
+macro_rules! klog {
+    ($level:ident, $($arg:tt)+) => {{
+        let module = $crate::log::module_name(module_path!());
+        let level = $crate::log::Level::$level;
+        if level >= $crate::log::level(module) {
+            $crate::log::write(level, module, format_args!($($arg)+));
+        }
+    }};
+}
+
+fn loglevel_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    if !args.is_empty() {
+        if let Err(error) = apply(args) {
+            let _ = writeln!(
+                out,
+                "{:?}, usage: loglevel [LEVEL] [MODULE=LEVEL|default...]",
+                error
+            );
+            return Err(CommandFailed);
+        }
+    }
+    let default = Level::ALL[usize::from(DEFAULT_LEVEL.load(Ordering::Relaxed))];
+    let _ = writeln!(out, "default {}", default.name());
+    match suppressed() {
+        Ok(suppressed) => {
+            let _ = writeln!(out, "{} messages suppressed", suppressed);
+        }
+        Err(error) => {
+            let _ = writeln!(out, "messages suppressed: {}", error);
+        }
+    }
+    if let Some(filters) = FILTERS.try_lock() {
+        for filter in filters.iter().flatten() {
+            let _ = writeln!(out, "{:<8} {}", filter.name(), filter.level.name());
+        }
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_directives() {
+    assert_eq!(apply("log_test=debug, other_test=off"), Ok(()));
+    assert_eq!(level("log_test"), Level::Debug);
+    assert_eq!(level("other_test"), Level::Off);
+    assert_eq!(apply("log_test=default other_test=default"), Ok(()));
+    assert_eq!(level("log_test"), level("no_such_module"));
+    assert_eq!(apply("log_test=loud"), Err(LevelError::Invalid));
+    assert_eq!(module_name(module_path!()), "log");
+}
+
+#[test_case]
+fn test_rate_limit() {
+    let mut limiter = Limiter::new();
+    for _ in 0..BURST {
+        assert_eq!(limiter.admit(0), Some(0));
+    }
+    assert_eq!(limiter.admit(0), None);
+    assert_eq!(limiter.admit(1), None);
+    // A token every 1000 / RATE ms, with what was dropped in between.
+    let next = (1000 + RATE - 1) / RATE;
+    assert_eq!(limiter.admit(next), Some(2));
+    assert_eq!(limiter.admit(next), None);
+    assert_eq!(limiter.total_suppressed, 3);
+    // And a full burst again after being quiet for long enough.
+    for _ in 0..BURST {
+        assert!(limiter.admit(10_000).is_some());
+    }
+}
@@ -0,0 +1,269 @@
+//! Machine check reporting.
+//!
+//! The CPU keeps a few banks of MSRs that hardware errors get logged
+//! in, each bank covering some part of it (a cache, the memory
+//! controller, the bus...). Errors it could fix are only logged there.
+//! Ones it couldn't raise #MC, whose handler prints every bank with an
+//! error, decoded, and whether the interrupted code could have gone
+//! on. The handler can't return either way, so then it panics.
+//!
+//! Without `init` turning on CR4.MCE a machine check shuts the CPU
+//! down, which on real hardware looks just like a triple fault.
+//! Corrected errors are listed by `mce`, along with anything left in
+//! the banks from before a warm reboot.
+use crate::shell::{self, CommandFailed, CommandResult};
+use core::arch::x86_64::__cpuid;
+use core::fmt;
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::registers::model_specific::Msr;
+
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17A;
+/// `IA32_MC0_CTL`, every bank has 4 MSRs from here: CTL, STATUS, ADDR
+/// and MISC.
+const IA32_MC0_CTL: u32 = 0x400;
+
+/// Restart IP valid, the interrupted code can be gone back to.
+const MCG_STATUS_RIPV: u64 = 1;
+/// Error IP valid, `rip` is where the error happened.
+const MCG_STATUS_EIPV: u64 = 1 << 1;
+
+const STATUS_VALID: u64 = 1 << 63;
+/// Another error came in while this one was still logged.
+const STATUS_OVERFLOW: u64 = 1 << 62;
+const STATUS_UNCORRECTED: u64 = 1 << 61;
+const STATUS_MISC_VALID: u64 = 1 << 59;
+const STATUS_ADDRESS_VALID: u64 = 1 << 58;
+/// Processor context corrupt.
+const STATUS_PCC: u64 = 1 << 57;
+
+/// How bad an error is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// The hardware fixed it.
+    Corrected,
+    /// Not fixed, but the CPU state is fine and the interrupted code
+    /// could carry on if it doesn't need whatever was lost.
+    Recoverable,
+    Fatal,
+}
+
+impl Severity {
+    /// Severity of an error with bank status `status`, while
+    /// `IA32_MCG_STATUS` was `mcg_status`.
+    pub fn of(status: u64, mcg_status: u64) -> Severity {
+        if status & STATUS_UNCORRECTED == 0 {
+            Severity::Corrected
+        } else if status & STATUS_PCC != 0 || mcg_status & MCG_STATUS_RIPV == 0 {
+            Severity::Fatal
+        } else {
+            Severity::Recoverable
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Severity::Corrected => "corrected",
+            Severity::Recoverable => "recoverable",
+            Severity::Fatal => "fatal",
+        }
+    }
+}
+
+/// What kind of error the low 16 bits of a bank status, the MCA error
+/// code, are for.
+pub fn error_kind(code: u16) -> &'static str {
+    match code {
+        0x0000 => "no error",
+        0x0001 => "unclassified",
+        0x0002 => "microcode ROM parity",
+        0x0003 => "external",
+        0x0004 => "functional redundancy check",
+        0x0005 => "internal parity",
+        0x0400 => "internal timer",
+        0x0401..=0x04FF => "internal unclassified",
+        _ if code & 0xFFFC == 0x000C => "generic cache hierarchy",
+        _ if code & 0xEFF0 == 0x0010 => "TLB",
+        _ if code & 0xEF80 == 0x0080 => "memory controller",
+        _ if code & 0xEF00 == 0x0100 => "cache",
+        _ if code & 0xE800 == 0x0800 => "bus or interconnect",
+        _ => "unknown",
+    }
+}
+
+/// An error logged in one of the banks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BankError {
+    pub bank: u32,
+    pub status: u64,
+    /// Where it happened, if the bank knows.
+    pub address: Option<u64>,
+    pub misc: Option<u64>,
+}
+
+impl fmt::Display for BankError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.status as u16;
+        write!(
+            f,
+            "bank {}: {} error {:#06x}, model specific {:#06x}, status {:#018x}",
+            self.bank,
+            error_kind(code),
+            code,
+            (self.status >> 16) as u16,
+            self.status
+        )?;
+        if let Some(address) = self.address {
+            write!(f, ", address {:#x}", address)?;
+        }
+        if let Some(misc) = self.misc {
+            write!(f, ", misc {:#x}", misc)?;
+        }
+        if self.status & STATUS_OVERFLOW != 0 {
+            write!(f, " (overflowed, earlier errors lost)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the CPU has machine checks and the banks to go with them.
+fn supported() -> bool {
+    let edx = unsafe { __cpuid(1) }.edx;
+    // MCE and MCA.
+    edx & 1 << 7 != 0 && edx & 1 << 14 != 0
+}
+
+fn bank_count() -> u32 {
+    if !supported() {
+        return 0;
+    }
+    (unsafe { Msr::new(IA32_MCG_CAP).read() } & 0xFF) as u32
+}
+
+fn bank_msr(bank: u32, register: u32) -> Msr {
+    Msr::new(IA32_MC0_CTL + 4 * bank + register)
+}
+
+/// Call `f` with the error in every bank that has one.
+pub fn for_each_error(mut f: impl FnMut(&BankError)) {
+    for bank in 0..bank_count() {
+        let status = unsafe { bank_msr(bank, 1).read() };
+        if status & STATUS_VALID == 0 {
+            continue;
+        }
+        let optional = |valid: u64, register: u32| {
+            if status & valid != 0 {
+                Some(unsafe { bank_msr(bank, register).read() })
+            } else {
+                None
+            }
+        };
+        f(&BankError {
+            bank,
+            status,
+            address: optional(STATUS_ADDRESS_VALID, 2),
+            misc: optional(STATUS_MISC_VALID, 3),
+        });
+    }
+}
+
+/// Forget every logged error.
+pub fn clear() {
+    for bank in 0..bank_count() {
+        unsafe { bank_msr(bank, 1).write(0) };
+    }
+}
+
+/// Report anything left over, turn on every bank and then machine
+/// checks, and add the `mce` shell command.
+pub fn init() {
+    shell::register(
+        "mce",
+        "mce [clear]: hardware errors logged in the machine check banks",
+        mce_command,
+    )
+    .expect("mce command");
+    if !supported() {
+        return;
+    }
+    for_each_error(|error| crate::klog!(Warn, "left over from before boot: {}", error));
+    clear();
+    for bank in 0..bank_count() {
+        unsafe { bank_msr(bank, 0).write(!0) };
+    }
+    unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::MACHINE_CHECK_EXCEPTION)) };
+}
+
+/// Write out what the banks say happened, for the #MC handler. Returns
+/// how bad the worst of it was.
+pub fn report(out: &mut dyn fmt::Write) -> Severity {
+    let mcg_status = unsafe { Msr::new(IA32_MCG_STATUS).read() };
+    let mut worst = None;
+    for_each_error(|error| {
+        let severity = Severity::of(error.status, mcg_status);
+        let _ = writeln!(out, "{} ({})", error, severity.name());
+        worst = worst.max(Some(severity));
+    });
+    let valid = |bit: u64| if mcg_status & bit != 0 { "" } else { "not " };
+    let _ = writeln!(
+        out,
+        "restart rip {}valid, error rip {}valid",
+        valid(MCG_STATUS_RIPV),
+        valid(MCG_STATUS_EIPV)
+    );
+    worst.unwrap_or_else(|| {
+        let _ = writeln!(out, "no bank says what happened");
+        Severity::Fatal
+    })
+}
+
+fn mce_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    match args {
+        "" if !supported() => {
+            let _ = writeln!(out, "no machine check architecture");
+        }
+        "" => {
+            let mcg_status = unsafe { Msr::new(IA32_MCG_STATUS).read() };
+            let mut any = false;
+            for_each_error(|error| {
+                let severity = Severity::of(error.status, mcg_status);
+                let _ = writeln!(out, "{} ({})", error, severity.name());
+                any = true;
+            });
+            let _ = writeln!(
+                out,
+                "{} banks{}",
+                bank_count(),
+                if any { "" } else { ", no errors" }
+            );
+        }
+        "clear" => clear(),
+        _ => {
+            let _ = writeln!(out, "usage: mce [clear]");
+            return Err(CommandFailed);
+        }
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_decodes_errors() {
+    assert_eq!(error_kind(0x0005), "internal parity");
+    // Memory controller, read, channel 1.
+    assert_eq!(error_kind(0x0091), "memory controller");
+    // Data cache, level 1, data read.
+    assert_eq!(error_kind(0x0135), "cache");
+
+    let corrected = STATUS_VALID | 0x0135;
+    let uncorrected = corrected | STATUS_UNCORRECTED;
+    assert_eq!(Severity::of(corrected, 0), Severity::Corrected);
+    assert_eq!(
+        Severity::of(uncorrected, MCG_STATUS_RIPV),
+        Severity::Recoverable
+    );
+    assert_eq!(Severity::of(uncorrected, 0), Severity::Fatal);
+    assert_eq!(
+        Severity::of(uncorrected | STATUS_PCC, MCG_STATUS_RIPV),
+        Severity::Fatal
+    );
+}
@@ -4,19 +4,114 @@
 #![test_runner(blog_os::test_runner)] // Define the test running funtion
 #![reexport_test_harness_main = "test_main"] // Avoid name clashes
 
+use blog_os::boot_timing;
+use blog_os::error::KernelError;
 use blog_os::println;
+use blog_os::ui::{Console, ProgressBar};
+use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo; // Required as we need to get deets on the panic.
+use x86_64::VirtAddr;
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+// Type checked way of defining our `_start` - the bootloader
+// now hands us the boot info (memory map, physical memory offset).
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    // Before anything else, so even a log of a crash during init says
+    // which build it was
+    println!("{}", blog_os::build_info::Banner);
+    #[cfg(not(feature = "no-vga"))]
+    blog_os::serial_println!("{}", blog_os::build_info::Banner);
     println!("Hello World{}", "!");
 
     blog_os::init();
 
+    // Nothing can print while the bar is up, errors wait until after.
+    let mut console = Console;
+    let mut progress = ProgressBar::new("boot", 8);
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let heap = unsafe {
+        blog_os::memory::init(&boot_info.memory_map, physical_memory_offset);
+        boot_timing::record("memory");
+        let _ = progress.advance(&mut console, 1);
+        let heap = blog_os::allocator::init_heap();
+        boot_timing::record("heap");
+        let _ = progress.advance(&mut console, 1);
+        blog_os::ksyms::init(&boot_info.memory_map, physical_memory_offset);
+        boot_timing::record("ksyms");
+        let _ = progress.advance(&mut console, 1);
+        heap
+    };
+    let root = heap.and_then(|()| blog_os::fs::mount_root());
+    boot_timing::record("fs");
+    if heap.is_ok() {
+        blog_os::ata::probe();
+    }
+    boot_timing::record("ata");
+    let _ = progress.advance(&mut console, 1);
+    let canary = blog_os::stack_canary::protect_boot_stack();
+    let _ = progress.advance(&mut console, 1);
+    let apic = blog_os::apic::enable();
+    boot_timing::record("apic");
+    let _ = progress.advance(&mut console, 1);
+    let hpet = blog_os::hpet::enable();
+    boot_timing::record("hpet");
+    let _ = progress.advance(&mut console, 1);
+    let kvm = blog_os::kvm::enable();
+    boot_timing::record("kvm");
+    let _ = progress.finish(&mut console);
+    if let Err(error) = heap {
+        println!("heap: {}", error);
+    } else if let Err(error) = root {
+        println!("root file system: {}", error);
+    }
+    if let Err(error) = canary {
+        println!("boot stack canary: {}", error);
+    }
+    if let Err(error) = apic {
+        println!("apic: {}", error);
+    } else if blog_os::cmdline::value("pic") == Some("off") {
+        if let Err(error) = blog_os::interrupts::use_apic() {
+            println!("apic: taking over from the PIC: {}", error);
+        }
+    }
+    if let Err(error) = blog_os::smp::start_all() {
+        println!("smp: {}", error);
+    }
+    boot_timing::record("smp");
+    // Most machines aren't KVM, that's not worth saying.
+    match kvm {
+        Ok(()) | Err(KernelError::Unsupported) => {}
+        Err(error) => println!("kvm: {}", error),
+    }
+    // Nor is not having an HPET.
+    match hpet {
+        Ok(()) | Err(KernelError::NotFound) => {}
+        Err(error) => println!("hpet: {}", error),
+    }
+    if let Some(smbios) = unsafe { blog_os::smbios::find(physical_memory_offset) } {
+        smbios.print_summary();
+    }
+
+    if root.is_ok() {
+        blog_os::fs::run_init_script();
+        boot_timing::record("init.rc");
+    }
+
+    boot_timing::print_summary();
+
+    // For real hardware, where the QEMU test suite can't run
+    #[cfg(feature = "selftest")]
+    blog_os::selftest::run();
+
     #[cfg(test)]
     test_main();
 
-    loop {}
+    // Tests feed their own lines through `line_discipline`.
+    #[cfg(not(test))]
+    blog_os::shell::start_console();
+
+    blog_os::idle::idle_loop()
 }
 
 /// This function is called on panic.
@@ -24,12 +119,36 @@ pub extern "C" fn _start() -> ! {
 /// We use the abort strategy - we don't do unwinding.
 ///
 /// After making the println! macro we added the printout
-/// of the panic info.
+/// of the panic info, followed by where we were called from.
+///
+/// The panic could have come from inside `println!` itself or from
+/// an interrupt handler, so nothing here waits on a lock: the screen
+/// is skipped if it's busy and serial goes around its lock instead.
+/// On the screen it's in red, to stand out from the boot log.
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
-    loop {}
+    use core::fmt::Write;
+
+    // Nothing is going to resume, don't let interrupts in either.
+    x86_64::instructions::interrupts::disable();
+    // Whatever panicked the second time, the steps below could again.
+    if !blog_os::panic_policy::enter() {
+        blog_os::panic_policy::report_nested(info);
+        blog_os::panic_policy::give_up()
+    }
+    let backtrace = blog_os::unwind::Backtrace::capture();
+
+    #[cfg(not(feature = "no-vga"))]
+    if let Some(mut screen) = blog_os::vga_buffer::WRITER.try_lock() {
+        use blog_os::vga_buffer::Color;
+        screen.with_color(Color::Red, Color::Black, |screen| {
+            let _ = writeln!(screen, "{}\n{}", info, backtrace);
+        });
+    }
+    let mut serial = blog_os::serial::panic_writer();
+    let _ = writeln!(serial, "{}\n{}", info, backtrace);
+    blog_os::panic_policy::finish(&mut serial, info, &backtrace)
 }
 
 // Called when we're testing as we want to close out our
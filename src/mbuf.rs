@@ -0,0 +1,331 @@
+//! Packet buffers, for moving packets up and down a network stack
+//! without copying them.
+//!
+//! An `Mbuf` is a chain of segments, each a window on a reference
+//! counted buffer. On the way down every layer puts its header in front
+//! with `prepend`: if the first buffer has room before the window and
+//! nothing else shares it, the window just grows into it, otherwise a
+//! new segment goes at the front of the chain. Either way the payload
+//! stays where it is. On the way up `pull_up` gets a header into one
+//! piece so it can be parsed and `trim_front` takes it off again.
+//!
+//! `clone` only shares the buffers, so a driver can be sending a packet
+//! while a retransmit queue keeps it. Shared buffers are never written
+//! to, writing to a packet that shares its ends with another starts a
+//! new segment instead. Drivers that do scatter-gather go through
+//! `segments`, the rest copy the packet out with `copy_out`.
+//!
+//! How many buffers are around and how big they are together is in the
+//! `stats`, as `mbuf.live_buffers` and `mbuf.live_bytes`.
+use crate::error::{KernelError, KernelResult};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Room left in front by default: Ethernet, IPv6 and TCP with all its
+/// options fit.
+pub const HEADROOM: usize = 128;
+/// What a segment added by `append` holds at least.
+const SEGMENT_SIZE: usize = 2048;
+
+static LIVE_BUFFERS: AtomicUsize = AtomicUsize::new(0);
+static LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// What segments point into.
+struct Buffer(Box<[u8]>);
+
+impl Buffer {
+    fn new(size: usize) -> Arc<Buffer> {
+        LIVE_BUFFERS.fetch_add(1, Ordering::Relaxed);
+        LIVE_BYTES.fetch_add(size, Ordering::Relaxed);
+        Arc::new(Buffer(vec![0; size].into_boxed_slice()))
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        LIVE_BUFFERS.fetch_sub(1, Ordering::Relaxed);
+        LIVE_BYTES.fetch_sub(self.0.len(), Ordering::Relaxed);
+    }
+}
+
+/// `start..end` of `buffer` is packet, the rest is free to grow into
+/// if nothing else has the buffer.
+#[derive(Clone)]
+struct Segment {
+    buffer: Arc<Buffer>,
+    start: usize,
+    end: usize,
+}
+
+impl Segment {
+    /// An empty one in a new buffer of `size` bytes, at `at`.
+    fn new(size: usize, at: usize) -> Segment {
+        Segment {
+            buffer: Buffer::new(size),
+            start: at,
+            end: at,
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        &self.buffer.0[self.start..self.end]
+    }
+
+    fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// No `Weak`s are ever made, so this is the only one.
+    fn is_unique(&self) -> bool {
+        Arc::strong_count(&self.buffer) == 1
+    }
+
+    fn headroom(&self) -> usize {
+        if self.is_unique() {
+            self.start
+        } else {
+            0
+        }
+    }
+
+    fn tailroom(&self) -> usize {
+        if self.is_unique() {
+            self.buffer.0.len() - self.end
+        } else {
+            0
+        }
+    }
+
+    /// `start..end` of the whole buffer, which mustn't be shared.
+    fn buffer_mut(&mut self, start: usize, end: usize) -> &mut [u8] {
+        let buffer = Arc::get_mut(&mut self.buffer).expect("writing a shared mbuf");
+        &mut buffer.0[start..end]
+    }
+}
+
+/// A packet, as a chain of segments.
+#[derive(Clone, Default)]
+pub struct Mbuf {
+    segments: VecDeque<Segment>,
+    len: usize,
+}
+
+impl Mbuf {
+    /// An empty packet with no buffer yet.
+    pub fn new() -> Mbuf {
+        Mbuf::default()
+    }
+
+    /// An empty packet in one buffer, with `headroom` bytes for headers
+    /// in front and room for `capacity` bytes after. What a driver
+    /// receives into, or a socket puts a payload in.
+    pub fn with_capacity(headroom: usize, capacity: usize) -> Mbuf {
+        let mut segments = VecDeque::new();
+        segments.push_back(Segment::new(headroom + capacity, headroom));
+        Mbuf { segments, len: 0 }
+    }
+
+    /// A copy of `bytes`, with `HEADROOM` in front.
+    pub fn from_slice(bytes: &[u8]) -> Mbuf {
+        let mut mbuf = Mbuf::with_capacity(HEADROOM, bytes.len());
+        mbuf.extend_from_slice(bytes);
+        mbuf
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Bytes `prepend` can have without a new segment.
+    pub fn headroom(&self) -> usize {
+        self.segments.front().map_or(0, Segment::headroom)
+    }
+
+    /// Bytes `append` can have without a new segment.
+    pub fn tailroom(&self) -> usize {
+        self.segments.back().map_or(0, Segment::tailroom)
+    }
+
+    /// The packet's pieces in order, none of them empty.
+    pub fn segments(&self) -> impl Iterator<Item = &[u8]> {
+        self.segments
+            .iter()
+            .map(Segment::bytes)
+            .filter(|bytes| !bytes.is_empty())
+    }
+
+    /// Put `len` bytes in front of the packet, for the caller to fill
+    /// in with a header.
+    pub fn prepend(&mut self, len: usize) -> &mut [u8] {
+        if self.headroom() < len {
+            // At the end of its buffer, with the usual headroom left
+            // for the next layer down.
+            let size = HEADROOM + len;
+            self.segments.push_front(Segment::new(size, size));
+        }
+        let first = self.segments.front_mut().unwrap();
+        first.start -= len;
+        self.len += len;
+        let start = first.start;
+        first.buffer_mut(start, start + len)
+    }
+
+    /// Put `len` bytes at the end of the packet, for the caller to fill
+    /// in.
+    pub fn append(&mut self, len: usize) -> &mut [u8] {
+        if self.tailroom() < len {
+            self.segments
+                .push_back(Segment::new(len.max(SEGMENT_SIZE), 0));
+        }
+        let last = self.segments.back_mut().unwrap();
+        let end = last.end;
+        last.end += len;
+        self.len += len;
+        last.buffer_mut(end, end + len)
+    }
+
+    pub fn extend_from_slice(&mut self, bytes: &[u8]) {
+        self.append(bytes.len()).copy_from_slice(bytes);
+    }
+
+    /// Add `other` to the end, without copying it.
+    pub fn chain(&mut self, mut other: Mbuf) {
+        self.len += other.len;
+        self.segments.append(&mut other.segments);
+    }
+
+    /// Take `len` bytes off the front, a header that's been dealt with.
+    /// `InvalidArgument` if the packet isn't that long, and then it's
+    /// left alone.
+    pub fn trim_front(&mut self, mut len: usize) -> KernelResult<()> {
+        if len > self.len {
+            return Err(KernelError::InvalidArgument);
+        }
+        self.len -= len;
+        while len > 0 {
+            let first = self.segments.front_mut().unwrap();
+            let taken = len.min(first.len());
+            first.start += taken;
+            len -= taken;
+            if first.len() == 0 && self.segments.len() > 1 {
+                self.segments.pop_front();
+            }
+        }
+        Ok(())
+    }
+
+    /// Take `len` bytes off the end, e.g. an Ethernet frame's padding or
+    /// checksum.
+    pub fn trim_back(&mut self, mut len: usize) -> KernelResult<()> {
+        if len > self.len {
+            return Err(KernelError::InvalidArgument);
+        }
+        self.len -= len;
+        while len > 0 {
+            let last = self.segments.back_mut().unwrap();
+            let taken = len.min(last.len());
+            last.end -= taken;
+            len -= taken;
+            if last.len() == 0 && self.segments.len() > 1 {
+                self.segments.pop_back();
+            }
+        }
+        Ok(())
+    }
+
+    /// The first `len` bytes in one piece, for parsing a header. Copies
+    /// them into a segment of their own if they're spread over more than
+    /// one, leaving `HEADROOM` in front of it. `InvalidData` if the
+    /// packet is shorter than that.
+    pub fn pull_up(&mut self, len: usize) -> KernelResult<&[u8]> {
+        if len > self.len {
+            return Err(KernelError::InvalidData);
+        }
+        // Empty segments at the front don't count.
+        while self.segments.len() > 1 && self.segments[0].len() == 0 {
+            self.segments.pop_front();
+        }
+        if self.segments.front().map_or(0, Segment::len) < len {
+            let mut first = Segment::new(HEADROOM + len, HEADROOM);
+            first.end += len;
+            self.copy_out(0, first.buffer_mut(HEADROOM, HEADROOM + len));
+            self.trim_front(len)?;
+            self.segments.push_front(first);
+            self.len += len;
+        }
+        Ok(&self.segments[0].bytes()[..len])
+    }
+
+    /// Copy what's at `offset` on into `out`, as much as fits. How much
+    /// that was.
+    pub fn copy_out(&self, mut offset: usize, out: &mut [u8]) -> usize {
+        let mut copied = 0;
+        for bytes in self.segments() {
+            if offset >= bytes.len() {
+                offset -= bytes.len();
+                continue;
+            }
+            let bytes = &bytes[offset..];
+            offset = 0;
+            let count = bytes.len().min(out.len() - copied);
+            out[copied..copied + count].copy_from_slice(&bytes[..count]);
+            copied += count;
+            if copied == out.len() {
+                break;
+            }
+        }
+        copied
+    }
+}
+
+/// Buffers that segments point into, shared ones once.
+pub fn live_buffers() -> usize {
+    LIVE_BUFFERS.load(Ordering::Relaxed)
+}
+
+pub fn live_bytes() -> usize {
+    LIVE_BYTES.load(Ordering::Relaxed)
+}
+
+#[test_case]
+fn test_headers_go_in_front_without_copying() {
+    let buffers = live_buffers();
+    let mut packet = Mbuf::from_slice(b"payload");
+    packet.prepend(4).copy_from_slice(b"tcp ");
+    packet.prepend(3).copy_from_slice(b"ip ");
+    // All in the headroom.
+    assert_eq!(packet.segments().count(), 1);
+    assert_eq!(packet.headroom(), HEADROOM - 7);
+
+    // Shared now, so the next header can't go in the same buffer.
+    let queued = packet.clone();
+    assert_eq!(packet.headroom(), 0);
+    packet.prepend(4).copy_from_slice(b"eth ");
+    assert_eq!(packet.segments().count(), 2);
+    assert_eq!(live_buffers(), buffers + 2);
+    let mut bytes = [0; 32];
+    let len = packet.copy_out(0, &mut bytes);
+    assert_eq!(&bytes[..len], b"eth ip tcp payload");
+    let len = queued.copy_out(3, &mut bytes);
+    assert_eq!(&bytes[..len], b"tcp payload");
+
+    // And back up: the header spans both segments.
+    assert_eq!(packet.pull_up(7), Ok(&b"eth ip "[..]));
+    packet.trim_front(7).unwrap();
+    packet.trim_back(4).unwrap();
+    assert_eq!(packet.len(), 7);
+    assert_eq!(packet.pull_up(7), Ok(&b"tcp pay"[..]));
+    assert_eq!(packet.trim_front(8), Err(KernelError::InvalidArgument));
+    assert_eq!(packet.pull_up(8), Err(KernelError::InvalidData));
+
+    drop(packet);
+    drop(queued);
+    assert_eq!(live_buffers(), buffers);
+}
@@ -0,0 +1,259 @@
+//! Looking for mappings that shouldn't be there.
+//!
+//! `audit` walks every level of the active page tables and reports
+//! runs of pages that are
+//!
+//! - writable and executable, so anything that can write there can
+//!   run code,
+//! - user accessible. `program::run` maps a program's image and stack
+//!   that way and `mmap` does for its anonymous memory, but it's all
+//!   unmapped when the program exits, so with nothing in ring 3 any
+//!   that are left were missed,
+//! - identity mapped above the first MiB, where the BIOS and VGA bits
+//!   the bootloader maps one to one are.
+//!
+//! Adjacent pages with the same problem come out as one range. The
+//! `memaudit` shell command prints them.
+use crate::error::{KernelError, KernelResult};
+use crate::memory;
+use crate::shell::{self, CommandResult};
+use core::fmt;
+use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use x86_64::PhysAddr;
+
+/// Identity mappings below this are expected.
+const LOW_MEMORY: u64 = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Issue {
+    WritableExecutable,
+    UserAccessible,
+    IdentityMapped,
+}
+
+impl Issue {
+    const ALL: [Issue; 3] = [
+        Issue::WritableExecutable,
+        Issue::UserAccessible,
+        Issue::IdentityMapped,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Issue::WritableExecutable => "writable and executable",
+            Issue::UserAccessible => "user accessible",
+            Issue::IdentityMapped => "identity mapped",
+        }
+    }
+}
+
+/// A range of virtual memory with the same problem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Finding {
+    pub issue: Issue,
+    pub start: u64,
+    /// Exclusive.
+    pub end: u64,
+    /// Where `start` is mapped to.
+    pub physical: u64,
+}
+
+impl Finding {
+    pub fn contains(&self, address: u64) -> bool {
+        (self.start..self.end).contains(&address)
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#018x}-{:#018x} -> {:#x}  {} ({} KiB)",
+            self.start,
+            self.end,
+            self.physical,
+            self.issue.name(),
+            (self.end - self.start) / 1024
+        )
+    }
+}
+
+/// Adds the `memaudit` shell command.
+pub fn init() {
+    shell::register(
+        "memaudit",
+        "look for suspicious page mappings",
+        memaudit_command,
+    )
+    .expect("memaudit command");
+}
+
+/// What a mapping ends up allowing, with the flags of every level on
+/// the way to it taken into account.
+#[derive(Clone, Copy)]
+struct Access {
+    writable: bool,
+    user: bool,
+    executable: bool,
+}
+
+impl Access {
+    fn through(self, flags: PageTableFlags, no_execute: bool) -> Access {
+        Access {
+            writable: self.writable && flags.contains(PageTableFlags::WRITABLE),
+            user: self.user && flags.contains(PageTableFlags::USER_ACCESSIBLE),
+            executable: self.executable
+                && !(no_execute && flags.contains(PageTableFlags::NO_EXECUTE)),
+        }
+    }
+
+    fn has(self, issue: Issue, virtual_address: u64, physical: u64) -> bool {
+        match issue {
+            Issue::WritableExecutable => self.writable && self.executable,
+            Issue::UserAccessible => self.user,
+            Issue::IdentityMapped => virtual_address == physical && physical >= LOW_MEMORY,
+        }
+    }
+}
+
+/// Joins up findings for adjacent pages before handing them to `f`.
+struct Collector<F: FnMut(&Finding)> {
+    pending: [Option<Finding>; 3],
+    f: F,
+}
+
+impl<F: FnMut(&Finding)> Collector<F> {
+    fn page(&mut self, virtual_address: u64, size: u64, physical: u64, access: Access) {
+        for (index, &issue) in Issue::ALL.iter().enumerate() {
+            let has = access.has(issue, virtual_address, physical);
+            match &mut self.pending[index] {
+                Some(finding) if has && finding.end == virtual_address => {
+                    finding.end += size;
+                    continue;
+                }
+                _ => self.flush(index),
+            }
+            if has {
+                self.pending[index] = Some(Finding {
+                    issue,
+                    start: virtual_address,
+                    end: virtual_address + size,
+                    physical,
+                });
+            }
+        }
+    }
+
+    fn flush(&mut self, index: usize) {
+        if let Some(finding) = self.pending[index].take() {
+            (self.f)(&finding);
+        }
+    }
+}
+
+/// Call `f` with every run of suspicious pages in the active page
+/// tables, in address order for each kind of issue.
+pub fn audit(f: impl FnMut(&Finding)) -> KernelResult<()> {
+    let offset = memory::physical_memory_offset().ok_or(KernelError::NotReady)?;
+    let no_execute = Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE);
+    let table = |address: PhysAddr| unsafe { &*(offset + address.as_u64()).as_ptr::<PageTable>() };
+    let mut collector = Collector {
+        pending: [None; 3],
+        f,
+    };
+    let everything = Access {
+        writable: true,
+        user: true,
+        executable: true,
+    };
+
+    let (level_4_frame, _) = Cr3::read();
+    for (i4, entry4) in table(level_4_frame.start_address()).iter().enumerate() {
+        if !entry4.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let access4 = everything.through(entry4.flags(), no_execute);
+        // Sign extend, the upper half starts at index 256.
+        let base4 = ((i4 as u64) << 39) | if i4 >= 256 { 0xFFFF << 48 } else { 0 };
+        for (i3, entry3) in table(entry4.addr()).iter().enumerate() {
+            let flags3 = entry3.flags();
+            if !flags3.contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+            let access3 = access4.through(flags3, no_execute);
+            let base3 = base4 | (i3 as u64) << 30;
+            if flags3.contains(PageTableFlags::HUGE_PAGE) {
+                collector.page(base3, 1 << 30, entry3.addr().as_u64(), access3);
+                continue;
+            }
+            for (i2, entry2) in table(entry3.addr()).iter().enumerate() {
+                let flags2 = entry2.flags();
+                if !flags2.contains(PageTableFlags::PRESENT) {
+                    continue;
+                }
+                let access2 = access3.through(flags2, no_execute);
+                let base2 = base3 | (i2 as u64) << 21;
+                if flags2.contains(PageTableFlags::HUGE_PAGE) {
+                    collector.page(base2, 1 << 21, entry2.addr().as_u64(), access2);
+                    continue;
+                }
+                for (i1, entry1) in table(entry2.addr()).iter().enumerate() {
+                    let flags1 = entry1.flags();
+                    if flags1.contains(PageTableFlags::PRESENT) {
+                        let access1 = access2.through(flags1, no_execute);
+                        let base1 = base2 | (i1 as u64) << 12;
+                        collector.page(base1, 4096, entry1.addr().as_u64(), access1);
+                    }
+                }
+            }
+        }
+    }
+    for index in 0..Issue::ALL.len() {
+        collector.flush(index);
+    }
+    Ok(())
+}
+
+fn memaudit_command(out: &mut dyn fmt::Write, _args: &str) -> CommandResult {
+    if !Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) {
+        let _ = writeln!(out, "no-execute is off, everything mapped is executable");
+    }
+    let mut count = 0;
+    let result = audit(|finding| {
+        let _ = writeln!(out, "{}", finding);
+        count += 1;
+    });
+    match result {
+        Ok(()) => {
+            let _ = writeln!(out, "{} suspicious ranges", count);
+            Ok(())
+        }
+        Err(error) => {
+            let _ = writeln!(out, "{}", error);
+            Err(shell::CommandFailed)
+        }
+    }
+}
+
+#[test_case]
+fn test_kernel_image_is_clean() {
+    use core::sync::atomic::AtomicU64;
+    static WRITABLE: AtomicU64 = AtomicU64::new(0);
+    fn code() {}
+    let code = code as usize as u64;
+    let data = &WRITABLE as *const AtomicU64 as u64;
+    let mut user = 0;
+    audit(|finding| match finding.issue {
+        Issue::WritableExecutable => {
+            assert!(!finding.contains(code), "{}", finding);
+            assert!(!finding.contains(data), "{}", finding);
+        }
+        Issue::UserAccessible => user += 1,
+        // Where the bootloader put the kernel is up to it.
+        Issue::IdentityMapped => {}
+    })
+    .expect("page tables readable");
+    assert_eq!(user, 0);
+}
@@ -0,0 +1,354 @@
+//! Fuzzing the page table code against a model of what it should do.
+//!
+//! A run does random `map_pages`, `unmap_pages` and `protect_pages`
+//! calls over a small region nothing else uses, keeping track of what
+//! each page should look like afterwards. After every call each page
+//! of the region is checked with `translate`: mapped or not as it
+//! should be, writable or not, still on the same frame as when it was
+//! mapped, no two pages on the same frame, and still holding what was
+//! written to it.
+//!
+//! The same seed always makes the same calls, and every failure says
+//! what seed it was. A test picks the seed up from `seed=N` on the
+//! kernel command line, see `cmdline`, or takes the TSC if it isn't
+//! there. `memfuzz SEED` runs it from the shell.
+use crate::boot_timing::read_tsc;
+use crate::error::KernelError;
+use crate::memory;
+use crate::shell::{self, parse_number, CommandFailed, CommandResult};
+use core::fmt;
+use core::ptr;
+use x86_64::VirtAddr;
+
+/// Where the region is, somewhere the heap and the bootloader aren't.
+pub const REGION_START: u64 = 0x5555_0000_0000;
+pub const REGION_PAGES: usize = 64;
+/// Pages a single call covers at most.
+const MAX_RUN: usize = 8;
+
+/// A deterministic xorshift64* generator. Nothing like `random`, it's
+/// meant to be predictable.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // It gets stuck at 0.
+        Rng(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// A number up to but not including `bound`.
+    pub fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Map,
+    Unmap,
+    Protect { writable: bool },
+}
+
+/// One call to the paging code, on `count` pages from `first` on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Call {
+    pub operation: Operation,
+    pub first: usize,
+    pub count: usize,
+}
+
+impl fmt::Display for Call {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.operation {
+            Operation::Map => write!(f, "map")?,
+            Operation::Unmap => write!(f, "unmap")?,
+            Operation::Protect { writable: true } => write!(f, "protect rw")?,
+            Operation::Protect { writable: false } => write!(f, "protect ro")?,
+        }
+        write!(f, " pages {}..{}", self.first, self.first + self.count)
+    }
+}
+
+/// What went wrong, and where.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Problem {
+    /// The call returned something else than the model says it should.
+    Result {
+        expected: Result<(), KernelError>,
+        got: Result<(), KernelError>,
+    },
+    Mapped,
+    NotMapped,
+    Writable(bool),
+    /// Moved to another frame while mapped.
+    Frame {
+        expected: u64,
+        got: u64,
+    },
+    /// On the same frame as another page.
+    SharedFrame {
+        with: usize,
+    },
+    /// What was written to it when it was mapped isn't there anymore.
+    Contents,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mismatch {
+    pub seed: u64,
+    /// How many calls in.
+    pub step: usize,
+    pub call: Call,
+    /// `None` for a wrong result.
+    pub page: Option<usize>,
+    pub problem: Problem,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "seed {}, call {} ({})", self.seed, self.step, self.call)?;
+        if let Some(page) = self.page {
+            write!(f, ", page {}", page)?;
+        }
+        match self.problem {
+            Problem::Result { expected, got } => {
+                write!(f, ": expected {:?}, got {:?}", expected, got)
+            }
+            Problem::Mapped => write!(f, ": mapped and shouldn't be"),
+            Problem::NotMapped => write!(f, ": not mapped and should be"),
+            Problem::Writable(writable) => write!(f, ": writable is {}", writable),
+            Problem::Frame { expected, got } => {
+                write!(f, ": moved from frame {:#x} to {:#x}", expected, got)
+            }
+            Problem::SharedFrame { with } => write!(f, ": same frame as page {}", with),
+            Problem::Contents => write!(f, ": contents changed"),
+        }
+    }
+}
+
+/// What a page of the region should look like.
+#[derive(Debug, Clone, Copy)]
+struct ModelPage {
+    frame: u64,
+    writable: bool,
+}
+
+fn address(page: usize) -> VirtAddr {
+    VirtAddr::new(REGION_START + page as u64 * 4096)
+}
+
+/// What goes at the start of a page when it's mapped.
+fn tag(seed: u64, page: usize, frame: u64) -> u64 {
+    seed ^ frame ^ ((page as u64) << 48)
+}
+
+struct Fuzzer {
+    seed: u64,
+    model: [Option<ModelPage>; REGION_PAGES],
+}
+
+impl Fuzzer {
+    /// What `call` should return. Every page until the first one it
+    /// fails on is done anyway.
+    fn predict(&self, call: Call) -> Result<(), KernelError> {
+        for page in call.first..call.first + call.count {
+            match (call.operation, self.model[page]) {
+                (Operation::Map, Some(_)) => return Err(KernelError::AlreadyExists),
+                (Operation::Unmap, None) | (Operation::Protect { .. }, None) => {
+                    return Err(KernelError::NotFound)
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    unsafe fn perform(&mut self, step: usize, call: Call) -> Result<(), Mismatch> {
+        let seed = self.seed;
+        let mismatch = |page, problem| Mismatch {
+            seed,
+            step,
+            call,
+            page,
+            problem,
+        };
+        let expected = self.predict(call);
+        let start = address(call.first);
+        let count = call.count as u64;
+        let got = match call.operation {
+            Operation::Map => memory::paging::map_pages(start, count),
+            Operation::Unmap => memory::paging::unmap_pages(start, count),
+            Operation::Protect { writable } => {
+                memory::paging::protect_pages(start, count, writable)
+            }
+        };
+        if got != expected {
+            return Err(mismatch(None, Problem::Result { expected, got }));
+        }
+
+        let mut page = call.first;
+        while page < call.first + call.count {
+            match (call.operation, self.model[page]) {
+                (Operation::Map, None) => {
+                    // Pages don't move once mapped, so whatever it got
+                    // is what it has to keep.
+                    let frame = memory::translate(address(page))
+                        .ok_or_else(|| mismatch(Some(page), Problem::NotMapped))?
+                        .address
+                        .as_u64();
+                    ptr::write_volatile(address(page).as_mut_ptr(), tag(self.seed, page, frame));
+                    self.model[page] = Some(ModelPage {
+                        frame,
+                        writable: true,
+                    });
+                }
+                (Operation::Unmap, Some(_)) => self.model[page] = None,
+                (Operation::Protect { writable }, Some(mut model)) => {
+                    model.writable = writable;
+                    self.model[page] = Some(model);
+                }
+                // Where it failed.
+                _ => break,
+            }
+            page += 1;
+        }
+        self.check()
+            .map_err(|(page, problem)| mismatch(Some(page), problem))
+    }
+
+    /// Compare every page with the model.
+    fn check(&self) -> Result<(), (usize, Problem)> {
+        for (page, &model) in self.model.iter().enumerate() {
+            let (model, mapping) = match (model, memory::translate(address(page))) {
+                (None, None) => continue,
+                (None, Some(_)) => return Err((page, Problem::Mapped)),
+                (Some(_), None) => return Err((page, Problem::NotMapped)),
+                (Some(model), Some(mapping)) => (model, mapping),
+            };
+            if mapping.writable != model.writable {
+                return Err((page, Problem::Writable(mapping.writable)));
+            }
+            let frame = mapping.address.as_u64();
+            if frame != model.frame {
+                return Err((
+                    page,
+                    Problem::Frame {
+                        expected: model.frame,
+                        got: frame,
+                    },
+                ));
+            }
+            let shared = self.model[..page]
+                .iter()
+                .position(|other| other.map_or(false, |other| other.frame == frame));
+            if let Some(with) = shared {
+                return Err((page, Problem::SharedFrame { with }));
+            }
+            let contents = unsafe { ptr::read_volatile(address(page).as_ptr::<u64>()) };
+            if contents != tag(self.seed, page, frame) {
+                return Err((page, Problem::Contents));
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmap whatever is still mapped.
+    unsafe fn clean_up(&mut self) {
+        for page in 0..REGION_PAGES {
+            if self.model[page].take().is_some() {
+                let _ = memory::paging::unmap_pages(address(page), 1);
+            }
+        }
+    }
+}
+
+/// The seed on the kernel command line, or else a new one.
+pub fn seed() -> u64 {
+    crate::cmdline::value("seed")
+        .and_then(parse_number)
+        .unwrap_or_else(read_tsc)
+}
+
+/// Make `calls` random calls from `seed`, checking each against the
+/// model. Everything is unmapped again at the end, whatever happens.
+pub fn run(seed: u64, calls: usize) -> Result<(), Mismatch> {
+    let mut rng = Rng::new(seed);
+    let mut fuzzer = Fuzzer {
+        seed,
+        model: [None; REGION_PAGES],
+    };
+    let mut result = Ok(());
+    for step in 0..calls {
+        let first = rng.below(REGION_PAGES);
+        let count = 1 + rng.below(MAX_RUN.min(REGION_PAGES - first));
+        let operation = match rng.below(4) {
+            0 | 1 => Operation::Map,
+            2 => Operation::Unmap,
+            _ => Operation::Protect {
+                writable: rng.below(2) == 0,
+            },
+        };
+        let call = Call {
+            operation,
+            first,
+            count,
+        };
+        result = unsafe { fuzzer.perform(step, call) };
+        if result.is_err() {
+            break;
+        }
+    }
+    unsafe { fuzzer.clean_up() };
+    result
+}
+
+/// Adds the `memfuzz` shell command.
+pub fn init() {
+    shell::register(
+        "memfuzz",
+        "memfuzz [SEED [CALLS]]: check the page table code against a model",
+        memfuzz_command,
+    )
+    .expect("memfuzz command");
+}
+
+fn memfuzz_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    let mut words = args.split_whitespace();
+    let seed = words.next().map_or(Some(seed()), parse_number);
+    let calls = words.next().map_or(Some(1000), parse_number);
+    let (seed, calls) = match (seed, calls) {
+        (Some(seed), Some(calls)) => (seed, calls as usize),
+        _ => {
+            let _ = writeln!(out, "usage: memfuzz [SEED [CALLS]]");
+            return Err(CommandFailed);
+        }
+    };
+    match run(seed, calls) {
+        Ok(()) => {
+            let _ = writeln!(out, "seed {}: {} calls, no mismatches", seed, calls);
+            Ok(())
+        }
+        Err(mismatch) => {
+            let _ = writeln!(out, "memfuzz: {}", mismatch);
+            Err(CommandFailed)
+        }
+    }
+}
+
+#[test_case]
+fn test_paging_against_model() {
+    if let Err(mismatch) = run(seed(), 300) {
+        panic!("{}", mismatch);
+    }
+    // Nothing left behind.
+    for page in 0..REGION_PAGES {
+        assert_eq!(memory::translate(address(page)), None);
+    }
+}
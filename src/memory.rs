@@ -0,0 +1,444 @@
+//! Looking at memory through the page tables.
+//!
+//! The bootloader maps all of physical memory at `physical_memory_offset`,
+//! so the page tables themselves can be read from there. `translate`
+//! walks them by hand rather than through `OffsetPageTable`, as that
+//! wants a `&mut` to the level 4 table and doesn't tell us the flags.
+//! Nothing here takes a lock, so the monitor can use it too.
+//!
+//! Changing the page tables is `paging`'s business, it takes a lock to
+//! do it. Frames for new pages come from `frame_allocator`.
+//!
+//! It also adds the `peek`, `poke` and `hexdump` shell commands, which
+//! check an address is mapped before touching it.
+use crate::allocator;
+use crate::error::KernelError;
+use crate::shell::{self, parse_number, CommandFailed, CommandResult};
+use crate::ui::{Column, Table};
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::fmt;
+use core::ptr;
+use spin::Once;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use x86_64::{PhysAddr, VirtAddr};
+
+pub mod frame_allocator;
+pub mod paging;
+
+/// Upper bound on how much `hexdump` shows in one go.
+const MAX_DUMP: u64 = 4096;
+
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
+static MEMORY_MAP: Once<&'static MemoryMap> = Once::new();
+
+/// Where a virtual address ends up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub address: PhysAddr,
+    /// Flags of the page itself.
+    pub flags: PageTableFlags,
+    /// Whether every level allows writes, not just the page.
+    pub writable: bool,
+    /// Same for user mode access.
+    pub user_accessible: bool,
+}
+
+/// Why a range of memory can't be accessed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessError {
+    /// `memory::init` hasn't been called.
+    NoPageTableAccess,
+    /// The page with this address isn't mapped.
+    Unmapped(VirtAddr),
+    /// The page with this address can't be written to.
+    ReadOnly(VirtAddr),
+    /// The physical address isn't covered by the physical memory mapping.
+    NotInPhysicalMapping(PhysAddr),
+}
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AccessError::NoPageTableAccess => write!(f, "page tables aren't accessible yet"),
+            AccessError::Unmapped(address) => write!(f, "{:#x} is not mapped", address.as_u64()),
+            AccessError::ReadOnly(address) => write!(f, "{:#x} is read-only", address.as_u64()),
+            AccessError::NotInPhysicalMapping(address) => {
+                write!(f, "physical {:#x} is not mapped", address.as_u64())
+            }
+        }
+    }
+}
+
+impl From<AccessError> for KernelError {
+    fn from(error: AccessError) -> KernelError {
+        match error {
+            AccessError::NoPageTableAccess => KernelError::NotReady,
+            AccessError::Unmapped(_) | AccessError::NotInPhysicalMapping(_) => {
+                KernelError::InvalidAddress
+            }
+            AccessError::ReadOnly(_) => KernelError::PermissionDenied,
+        }
+    }
+}
+
+/// Remember where physical memory is mapped and what's in it, and add
+/// the shell commands.
+///
+/// # Safety
+///
+/// All of physical memory must be mapped at `physical_memory_offset`.
+pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) {
+    PHYSICAL_MEMORY_OFFSET.call_once(|| physical_memory_offset);
+    MEMORY_MAP.call_once(|| memory_map);
+    frame_allocator::init(memory_map, physical_memory_offset);
+
+    shell::register(
+        "peek",
+        "[-p] ADDR [SIZE]  read a 1, 2, 4 or 8 (default) byte value",
+        peek_command,
+    )
+    .expect("peek command");
+    shell::register(
+        "poke",
+        "[-p] ADDR VALUE [SIZE]  write a 1, 2, 4 or 8 (default) byte value",
+        poke_command,
+    )
+    .expect("poke command");
+    shell::register(
+        "hexdump",
+        "[-p] ADDR [LEN]  dump LEN (default 64) bytes, -p for physical",
+        hexdump_command,
+    )
+    .expect("hexdump command");
+    shell::register("meminfo", "physical memory and heap usage", meminfo_command)
+        .expect("meminfo command");
+}
+
+pub fn physical_memory_offset() -> Option<VirtAddr> {
+    PHYSICAL_MEMORY_OFFSET.r#try().copied()
+}
+
+/// Find out where `address` is mapped to in the active page tables.
+pub fn translate(address: VirtAddr) -> Option<Mapping> {
+    let offset = physical_memory_offset()?;
+    let (level_4_table, _) = Cr3::read();
+    let mut table_address = level_4_table.start_address();
+    let mut writable = true;
+    let mut user_accessible = true;
+
+    let indexes = [
+        address.p4_index(),
+        address.p3_index(),
+        address.p2_index(),
+        address.p1_index(),
+    ];
+    for (level, &index) in indexes.iter().enumerate() {
+        let table = unsafe { &*(offset + table_address.as_u64()).as_ptr::<PageTable>() };
+        let entry = &table[index];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        writable &= flags.contains(PageTableFlags::WRITABLE);
+        user_accessible &= flags.contains(PageTableFlags::USER_ACCESSIBLE);
+
+        // 1 GiB pages end the walk at level 3, 2 MiB ones at level 2
+        let page_size: u64 = match level {
+            1 if flags.contains(PageTableFlags::HUGE_PAGE) => 1 << 30,
+            2 if flags.contains(PageTableFlags::HUGE_PAGE) => 1 << 21,
+            3 => 1 << 12,
+            _ => {
+                table_address = entry.addr();
+                continue;
+            }
+        };
+        return Some(Mapping {
+            address: entry.addr() + (address.as_u64() & (page_size - 1)),
+            flags,
+            writable,
+            user_accessible,
+        });
+    }
+    None
+}
+
+/// Check that every page in `start..start + len` is mapped, and
+/// writable as well if `write` is set.
+pub fn check_range(start: VirtAddr, len: u64, write: bool) -> Result<(), AccessError> {
+    physical_memory_offset().ok_or(AccessError::NoPageTableAccess)?;
+    if len == 0 {
+        return Ok(());
+    }
+    let last = start.as_u64().saturating_add(len - 1);
+    let mut page = start.align_down(4096u64).as_u64();
+    while page <= last {
+        // The first page starts at `start` itself, for the error.
+        let address = VirtAddr::new(page.max(start.as_u64()));
+        let mapping = translate(address).ok_or(AccessError::Unmapped(address))?;
+        if write && !mapping.writable {
+            return Err(AccessError::ReadOnly(address));
+        }
+        page = match page.checked_add(4096) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    Ok(())
+}
+
+/// Where physical `address` can be accessed through the physical
+/// memory mapping, if it can.
+pub fn physical_to_virtual(address: PhysAddr, len: u64) -> Result<VirtAddr, AccessError> {
+    let offset = physical_memory_offset().ok_or(AccessError::NoPageTableAccess)?;
+    let not_mapped = AccessError::NotInPhysicalMapping(address);
+    let virtual_address = offset
+        .as_u64()
+        .checked_add(address.as_u64())
+        .and_then(|virtual_address| VirtAddr::try_new(virtual_address).ok())
+        .ok_or(not_mapped)?;
+    check_range(virtual_address, len, false).map_err(|_| not_mapped)?;
+    Ok(virtual_address)
+}
+
+/// Print `len` bytes at `address` as hex and ASCII, 16 to a line.
+///
+/// # Safety
+///
+/// The whole range has to be readable, see `check_range`.
+pub unsafe fn hexdump(out: &mut dyn fmt::Write, address: u64, len: u64) -> fmt::Result {
+    let end = address.saturating_add(len);
+    for line_start in (address..end).step_by(16) {
+        let line_len = (end - line_start).min(16) as usize;
+        let bytes = core::slice::from_raw_parts(line_start as *const u8, line_len);
+
+        write!(out, "{:016x}  ", line_start)?;
+        for index in 0..16 {
+            match bytes.get(index) {
+                Some(byte) => write!(out, "{:02x} ", byte)?,
+                None => write!(out, "   ")?,
+            }
+        }
+        write!(out, " |")?;
+        for &byte in bytes {
+            let shown = if (0x20..0x7F).contains(&byte) {
+                byte as char
+            } else {
+                '.'
+            };
+            out.write_char(shown)?;
+        }
+        writeln!(out, "|")?;
+    }
+    Ok(())
+}
+
+/// Take the address argument, which can have a `-p` in front to
+/// make it physical.
+fn parse_address<'a>(words: &mut impl Iterator<Item = &'a str>) -> Option<(bool, u64)> {
+    let mut word = words.next()?;
+    let physical = word == "-p";
+    if physical {
+        word = words.next()?;
+    }
+    Some((physical, parse_number(word)?))
+}
+
+/// Check `len` bytes at `address` can be accessed and return where
+/// to access them.
+fn resolve(physical: bool, address: u64, len: u64, write: bool) -> Result<VirtAddr, AccessError> {
+    let virtual_address = if physical {
+        let address = PhysAddr::try_new(address)
+            .map_err(|_| AccessError::NotInPhysicalMapping(PhysAddr::new_truncate(address)))?;
+        physical_to_virtual(address, len)?
+    } else {
+        VirtAddr::try_new(address)
+            .map_err(|_| AccessError::Unmapped(VirtAddr::new_truncate(address)))?
+    };
+    check_range(virtual_address, len, write)?;
+    Ok(virtual_address)
+}
+
+/// A value size given to `peek`/`poke`, which has to be a power of two
+/// up to 8.
+fn parse_size(word: Option<&str>) -> Option<u64> {
+    match word.map_or(Some(8), parse_number)? {
+        size @ 1 | size @ 2 | size @ 4 | size @ 8 => Some(size),
+        _ => None,
+    }
+}
+
+fn peek_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    let mut words = args.split_whitespace();
+    let (physical, address) = match parse_address(&mut words) {
+        Some(parsed) => parsed,
+        None => {
+            let _ = writeln!(out, "usage: peek [-p] ADDR [SIZE]");
+            return Err(CommandFailed);
+        }
+    };
+    let size = match parse_size(words.next()) {
+        Some(size) if address % size == 0 => size,
+        _ => {
+            let _ = writeln!(out, "size has to be 1, 2, 4 or 8 and ADDR aligned to it");
+            return Err(CommandFailed);
+        }
+    };
+    let pointer = match resolve(physical, address, size, false) {
+        Ok(virtual_address) => virtual_address.as_u64(),
+        Err(error) => {
+            let _ = writeln!(out, "{}", error);
+            return Err(CommandFailed);
+        }
+    };
+    // Volatile and exactly `size` wide, as this is meant for MMIO too.
+    let value = unsafe {
+        match size {
+            1 => u64::from(ptr::read_volatile(pointer as *const u8)),
+            2 => u64::from(ptr::read_volatile(pointer as *const u16)),
+            4 => u64::from(ptr::read_volatile(pointer as *const u32)),
+            _ => ptr::read_volatile(pointer as *const u64),
+        }
+    };
+    let _ = writeln!(
+        out,
+        "{:#x}: {:#0width$x}",
+        address,
+        value,
+        width = size as usize * 2 + 2
+    );
+    Ok(())
+}
+
+fn poke_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    let mut words = args.split_whitespace();
+    let parsed = parse_address(&mut words)
+        .and_then(|(physical, address)| Some((physical, address, parse_number(words.next()?)?)));
+    let (physical, address, value) = match parsed {
+        Some(parsed) => parsed,
+        None => {
+            let _ = writeln!(out, "usage: poke [-p] ADDR VALUE [SIZE]");
+            return Err(CommandFailed);
+        }
+    };
+    let size = match parse_size(words.next()) {
+        Some(size) if address % size == 0 && (size == 8 || value >> (size * 8) == 0) => size,
+        _ => {
+            let _ = writeln!(out, "size has to be 1, 2, 4 or 8, fit VALUE and align ADDR");
+            return Err(CommandFailed);
+        }
+    };
+    let pointer = match resolve(physical, address, size, true) {
+        Ok(virtual_address) => virtual_address.as_u64(),
+        Err(error) => {
+            let _ = writeln!(out, "{}", error);
+            return Err(CommandFailed);
+        }
+    };
+    unsafe {
+        match size {
+            1 => ptr::write_volatile(pointer as *mut u8, value as u8),
+            2 => ptr::write_volatile(pointer as *mut u16, value as u16),
+            4 => ptr::write_volatile(pointer as *mut u32, value as u32),
+            _ => ptr::write_volatile(pointer as *mut u64, value),
+        }
+    }
+    Ok(())
+}
+
+fn hexdump_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    let mut words = args.split_whitespace();
+    let address = parse_address(&mut words);
+    let len = words.next().map_or(Some(64), parse_number);
+    let (physical, address, len) = match (address, len) {
+        (Some((physical, address)), Some(len)) => (physical, address, len.min(MAX_DUMP)),
+        _ => {
+            let _ = writeln!(out, "usage: hexdump [-p] ADDR [LEN]");
+            return Err(CommandFailed);
+        }
+    };
+    match resolve(physical, address, len, false) {
+        Ok(virtual_address) => {
+            let _ = unsafe { hexdump(out, virtual_address.as_u64(), len) };
+            Ok(())
+        }
+        Err(error) => {
+            let _ = writeln!(out, "{}", error);
+            Err(CommandFailed)
+        }
+    }
+}
+
+fn meminfo_command(out: &mut dyn fmt::Write, _args: &str) -> CommandResult {
+    if let Some(memory_map) = MEMORY_MAP.r#try() {
+        let kib = |region_type: MemoryRegionType| -> u64 {
+            memory_map
+                .iter()
+                .filter(|region| region.region_type == region_type)
+                .map(|region| region.range.end_addr() - region.range.start_addr())
+                .sum::<u64>()
+                / 1024
+        };
+        let total: u64 = memory_map
+            .iter()
+            .map(|region| region.range.end_addr() - region.range.start_addr())
+            .sum();
+        const COLUMNS: [Column; 2] = [Column::left("memory", 10), Column::right("KiB", 10)];
+        let table = Table::new(&COLUMNS);
+        let _ = table.header(out);
+        let _ = table.row(out, &[&"physical", &(total / 1024)]);
+        for &(name, region_type) in &[
+            ("  usable", MemoryRegionType::Usable),
+            ("  kernel", MemoryRegionType::Kernel),
+            ("  tables", MemoryRegionType::PageTable),
+            ("  reserved", MemoryRegionType::Reserved),
+        ] {
+            let _ = table.row(out, &[&name, &kib(region_type)]);
+        }
+        if let Ok(frames) = frame_allocator::stats() {
+            let kib = frame_allocator::FRAME_SIZE / 1024;
+            let _ = table.row(out, &[&"  allocated", &(frames.allocated * kib)]);
+            let _ = table.row(out, &[&"  available", &(frames.available() * kib)]);
+        }
+        let _ = table.end(out);
+    }
+
+    let heap = allocator::heap_stats();
+    let _ = writeln!(
+        out,
+        "heap       {:>10} bytes in {} blocks, {} allocations so far, {} bytes free",
+        heap.live_bytes,
+        heap.live_blocks,
+        heap.allocations,
+        allocator::heap_free()
+    );
+    let cache = crate::fs::cache::stats();
+    let _ = writeln!(
+        out,
+        "page cache {:>10} pages, {} dirty, {} hits, {} misses, {} written back",
+        cache.pages, cache.dirty, cache.hits, cache.misses, cache.writebacks
+    );
+    Ok(())
+}
+
+#[test_case]
+fn test_translate_and_check_range() {
+    let local = 0u64;
+    let address = VirtAddr::new(&local as *const u64 as u64);
+    let mapping = translate(address).expect("stack is mapped");
+    assert!(mapping.writable);
+    // The physical memory mapping should lead back to the same memory.
+    let offset = physical_memory_offset().unwrap();
+    assert_eq!(
+        translate(offset + mapping.address.as_u64()).map(|m| m.address),
+        Some(mapping.address)
+    );
+
+    assert_eq!(check_range(address, 8, true), Ok(()));
+    // Same address tests/double_fault.rs relies on being unmapped
+    let unmapped = VirtAddr::new(0xdeadbeef);
+    assert_eq!(
+        check_range(unmapped, 8, false),
+        Err(AccessError::Unmapped(unmapped))
+    );
+}
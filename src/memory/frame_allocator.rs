@@ -0,0 +1,232 @@
+//! Physical memory, a 4 KiB frame at a time.
+//!
+//! Frames come from the regions the bootloader's memory map says are
+//! usable, lowest first. Freed ones go on a list and get handed out
+//! again before anything new: each one on the list has the address of
+//! the next at its start, written through the physical memory mapping,
+//! so the list costs nothing however long it gets.
+//!
+//! `allocate_frame` and `free_frame` work on the one allocator for all
+//! of memory, which `memory::init` sets up. `paging` gets its frames
+//! from it too.
+//!
+//! One frame below 1 MiB is kept back, for code that has to run in real
+//! mode: `low_frame` is where `smp` puts what other CPUs start in.
+use crate::error::{KernelError, KernelResult};
+use crate::latency;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+pub const FRAME_SIZE: u64 = 4096;
+/// As high as real mode goes.
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
+static FRAMES: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+
+/// Counts of frames, not bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FrameStats {
+    /// Frames in the usable regions.
+    pub usable: u64,
+    /// Handed out and not freed.
+    pub allocated: u64,
+    /// On the free list, waiting to be handed out again.
+    pub freed: u64,
+}
+
+impl FrameStats {
+    /// Frames that can still be handed out.
+    pub fn available(&self) -> u64 {
+        self.usable - self.allocated
+    }
+}
+
+pub struct BootInfoFrameAllocator {
+    memory_map: &'static MemoryMap,
+    /// The region new frames come out of, an index into the map.
+    region: usize,
+    /// The next new frame in it.
+    next: u64,
+    /// The last frame freed, 0 if there isn't one: frame 0 is never
+    /// usable.
+    freed: u64,
+    /// The frame kept back below 1 MiB, 0 if there wasn't one.
+    low: u64,
+    offset: VirtAddr,
+    stats: FrameStats,
+}
+
+fn usable(memory_map: &MemoryMap) -> impl Iterator<Item = (u64, u64)> + '_ {
+    memory_map
+        .iter()
+        .filter(|region| region.region_type == MemoryRegionType::Usable)
+        .map(|region| {
+            let start = (region.range.start_addr() + FRAME_SIZE - 1) & !(FRAME_SIZE - 1);
+            (start, region.range.end_addr() & !(FRAME_SIZE - 1))
+        })
+}
+
+impl BootInfoFrameAllocator {
+    /// # Safety
+    ///
+    /// Nothing can be using the frames the map says are usable, and all
+    /// of physical memory has to be mapped at `offset`.
+    pub unsafe fn new(memory_map: &'static MemoryMap, offset: VirtAddr) -> BootInfoFrameAllocator {
+        let low = usable(memory_map)
+            .find(|&(start, end)| start + FRAME_SIZE <= end.min(LOW_MEMORY_END))
+            .map_or(0, |(start, _)| start);
+        let usable: u64 = usable(memory_map)
+            .map(|(start, end)| end.saturating_sub(start) / FRAME_SIZE)
+            .sum();
+        BootInfoFrameAllocator {
+            memory_map,
+            region: 0,
+            next: 0,
+            freed: 0,
+            low,
+            offset,
+            stats: FrameStats {
+                usable: usable - u64::from(low != 0),
+                ..FrameStats::default()
+            },
+        }
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    /// Whether `frame` is one this could have handed out.
+    pub fn owns(&self, frame: PhysFrame) -> bool {
+        let address = frame.start_address().as_u64();
+        address != self.low
+            && usable(self.memory_map).any(|(start, end)| (start..end).contains(&address))
+    }
+
+    fn link(&self, address: u64) -> *mut u64 {
+        (self.offset + address).as_mut_ptr()
+    }
+
+    fn new_frame(&mut self) -> Option<u64> {
+        while let Some(region) = self.memory_map.get(self.region) {
+            let start = (region.range.start_addr() + FRAME_SIZE - 1) & !(FRAME_SIZE - 1);
+            let end = region.range.end_addr() & !(FRAME_SIZE - 1);
+            let address = self.next.max(start);
+            if region.region_type == MemoryRegionType::Usable && address < end {
+                self.next = address + FRAME_SIZE;
+                if address == self.low {
+                    continue;
+                }
+                return Some(address);
+            }
+            self.region += 1;
+        }
+        None
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let address = if self.freed != 0 {
+            let address = self.freed;
+            self.freed = unsafe { self.link(address).read() };
+            self.stats.freed -= 1;
+            address
+        } else {
+            self.new_frame()?
+        };
+        self.stats.allocated += 1;
+        Some(PhysFrame::containing_address(PhysAddr::new(address)))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let address = frame.start_address().as_u64();
+        self.link(address).write(self.freed);
+        self.freed = address;
+        self.stats.allocated -= 1;
+        self.stats.freed += 1;
+    }
+}
+
+/// Hand the usable parts of `memory_map` to the allocator.
+///
+/// # Safety
+///
+/// See `BootInfoFrameAllocator::new`.
+pub(crate) unsafe fn init(memory_map: &'static MemoryMap, offset: VirtAddr) {
+    let allocator = BootInfoFrameAllocator::new(memory_map, offset);
+    latency::without_interrupts(|| *FRAMES.lock() = Some(allocator));
+}
+
+/// Run `f` with the allocator, with interrupts off. `NotReady` before
+/// `memory::init`.
+pub(crate) fn with<R>(f: impl FnOnce(&mut BootInfoFrameAllocator) -> R) -> KernelResult<R> {
+    latency::without_interrupts(|| {
+        let mut frames = FRAMES.lock();
+        frames.as_mut().map(f).ok_or(KernelError::NotReady)
+    })
+}
+
+/// A frame nothing else is using. Its contents are whatever were
+/// there last.
+pub fn allocate_frame() -> KernelResult<PhysFrame> {
+    with(|frames| frames.allocate_frame())?.ok_or(KernelError::OutOfMemory)
+}
+
+/// Give back a frame from `allocate_frame`. `InvalidAddress` if it
+/// isn't in usable memory at all.
+///
+/// # Safety
+///
+/// Nothing can be using it anymore, and it can't be freed twice.
+pub unsafe fn free_frame(frame: PhysFrame) -> KernelResult<()> {
+    with(|frames| {
+        if !frames.owns(frame) {
+            return Err(KernelError::InvalidAddress);
+        }
+        frames.deallocate_frame(frame);
+        Ok(())
+    })?
+}
+
+/// The frame kept back below 1 MiB. It's never handed out or freed,
+/// whoever uses it has to make sure only one thing does at a time.
+/// `NotFound` if none of low memory was usable.
+pub fn low_frame() -> KernelResult<PhysFrame> {
+    match with(|frames| frames.low)? {
+        0 => Err(KernelError::NotFound),
+        low => Ok(PhysFrame::containing_address(PhysAddr::new(low))),
+    }
+}
+
+pub fn stats() -> KernelResult<FrameStats> {
+    with(|frames| frames.stats())
+}
+
+#[test_case]
+fn test_free_and_reuse() {
+    let before = stats().unwrap();
+    let first = allocate_frame().unwrap();
+    let second = allocate_frame().unwrap();
+    assert_ne!(first, second);
+    assert_eq!(stats().unwrap().allocated, before.allocated + 2);
+    unsafe {
+        free_frame(first).unwrap();
+        // Freed last, handed out first.
+        free_frame(second).unwrap();
+        assert_eq!(allocate_frame().unwrap(), second);
+        assert_eq!(allocate_frame().unwrap(), first);
+        free_frame(first).unwrap();
+        free_frame(second).unwrap();
+        // Frame 0 never is usable.
+        let zero = PhysFrame::containing_address(PhysAddr::new(0));
+        assert_eq!(free_frame(zero), Err(KernelError::InvalidAddress));
+    }
+    let after = stats().unwrap();
+    assert_eq!(after.allocated, before.allocated);
+    assert_eq!(after.available(), before.available());
+}
@@ -0,0 +1,232 @@
+//! Changing the page tables.
+//!
+//! Everything goes through the active level 4 table, reached through
+//! the physical memory mapping, as an `OffsetPageTable`. It's made
+//! afresh for every call with the frame allocator's lock held and
+//! interrupts off, which is what keeps two changes from racing, so
+//! nobody has to hold on to a `&mut` to the tables.
+//!
+//! `map_to`, `unmap` and `update_flags` do one page, with frames the
+//! caller picks: MMIO outside of physical memory, or frames from
+//! `frame_allocator`. `map_pages`, `unmap_pages` and `protect_pages`
+//! do runs of pages with frames from the allocator, which is what the
+//! heap wants.
+//!
+//! Looking at the page tables doesn't need any of this, see
+//! `memory::translate`.
+use super::frame_allocator::{self, BootInfoFrameAllocator};
+use super::physical_memory_offset;
+use crate::error::{KernelError, KernelResult};
+use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::mapper::{FlagUpdateError, MapToError, UnmapError};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// The active level 4 table.
+///
+/// # Safety
+///
+/// All of physical memory has to be mapped at `offset`. Nothing else
+/// can be using the table while the reference is around.
+pub unsafe fn active_level_4_table(offset: VirtAddr) -> &'static mut PageTable {
+    let (level_4_table, _) = Cr3::read();
+    &mut *(offset + level_4_table.start_address().as_u64()).as_mut_ptr()
+}
+
+/// Run `f` with the active page tables and the frame allocator, with
+/// interrupts off. `NotReady` before `memory::init`.
+///
+/// # Safety
+///
+/// Whatever `f` maps or unmaps mustn't break anything still using it.
+pub unsafe fn with_page_table<R>(
+    f: impl FnOnce(&mut OffsetPageTable, &mut BootInfoFrameAllocator) -> KernelResult<R>,
+) -> KernelResult<R> {
+    let offset = physical_memory_offset().ok_or(KernelError::NotReady)?;
+    frame_allocator::with(|frames| {
+        let mut table = OffsetPageTable::new(active_level_4_table(offset), offset);
+        f(&mut table, frames)
+    })?
+}
+
+/// Where `address` ends up, if it's mapped.
+pub fn translate_addr(address: VirtAddr) -> Option<PhysAddr> {
+    super::translate(address).map(|mapping| mapping.address)
+}
+
+/// Present, writable if asked for and if the CPU is checking, not
+/// executable.
+pub fn data_flags(writable: bool) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT;
+    if writable {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    // The bit is reserved, and faults, unless it's turned on.
+    if Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+fn map_error(error: MapToError<Size4KiB>) -> KernelError {
+    match error {
+        MapToError::FrameAllocationFailed => KernelError::OutOfMemory,
+        MapToError::PageAlreadyMapped(_) | MapToError::ParentEntryHugePage => {
+            KernelError::AlreadyExists
+        }
+    }
+}
+
+fn unmap_error(error: UnmapError) -> KernelError {
+    match error {
+        UnmapError::PageNotMapped => KernelError::NotFound,
+        UnmapError::ParentEntryHugePage | UnmapError::InvalidFrameAddress(_) => {
+            KernelError::InvalidAddress
+        }
+    }
+}
+
+fn flag_error(error: FlagUpdateError) -> KernelError {
+    match error {
+        FlagUpdateError::PageNotMapped => KernelError::NotFound,
+        FlagUpdateError::ParentEntryHugePage => KernelError::InvalidAddress,
+    }
+}
+
+/// Map `page` to `frame`. Tables in between come from the frame
+/// allocator. `AlreadyExists` if `page` is mapped already.
+///
+/// # Safety
+///
+/// Nothing else can be using `page`'s address, nor `frame` unless it's
+/// meant to be shared, like MMIO.
+pub unsafe fn map_to(page: Page, frame: PhysFrame, flags: PageTableFlags) -> KernelResult<()> {
+    with_page_table(|table, frames| {
+        table
+            .map_to(page, frame, flags, frames)
+            .map(|flush| flush.flush())
+            .map_err(map_error)
+    })
+}
+
+/// Unmap `page`, returning the frame it was on, which the caller gets
+/// to free if it came from `frame_allocator`. `NotFound` if it wasn't
+/// mapped.
+///
+/// # Safety
+///
+/// Nothing can be using `page` anymore.
+pub unsafe fn unmap(page: Page) -> KernelResult<PhysFrame> {
+    with_page_table(|table, _| {
+        let (frame, flush) = table.unmap(page).map_err(unmap_error)?;
+        flush.flush();
+        Ok(frame)
+    })
+}
+
+/// Change the flags `page` is mapped with. `NotFound` if it isn't.
+///
+/// # Safety
+///
+/// Nothing can be about to use `page` in a way the new flags don't
+/// allow.
+pub unsafe fn update_flags(page: Page, flags: PageTableFlags) -> KernelResult<()> {
+    with_page_table(|table, _| {
+        table
+            .update_flags(page, flags)
+            .map(|flush| flush.flush())
+            .map_err(flag_error)
+    })
+}
+
+fn pages(start: VirtAddr, count: u64) -> impl Iterator<Item = Page> {
+    let first = Page::<Size4KiB>::containing_address(start);
+    Page::range(first, first + count)
+}
+
+/// Map `count` pages from `start` on to fresh frames, writable.
+/// `OutOfMemory` if we run out of frames, `AlreadyExists` if one of
+/// the pages was mapped already. The pages before the one that failed
+/// stay mapped.
+///
+/// # Safety
+///
+/// Nothing else may be using the pages' addresses.
+pub unsafe fn map_pages(start: VirtAddr, count: u64) -> KernelResult<()> {
+    let flags = data_flags(true);
+    with_page_table(|table, frames| {
+        for page in pages(start, count) {
+            let frame = frames.allocate_frame().ok_or(KernelError::OutOfMemory)?;
+            match table.map_to(page, frame, flags, frames) {
+                Ok(flush) => flush.flush(),
+                Err(error) => {
+                    frames.deallocate_frame(frame);
+                    return Err(map_error(error));
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Unmap `count` pages from `start` on and give their frames back.
+/// `NotFound` if one of them wasn't mapped, the pages before it are
+/// unmapped anyway.
+///
+/// # Safety
+///
+/// The pages have to have been mapped by `map_pages`, and nothing can
+/// be using them anymore.
+pub unsafe fn unmap_pages(start: VirtAddr, count: u64) -> KernelResult<()> {
+    with_page_table(|table, frames| {
+        for page in pages(start, count) {
+            let (frame, flush) = table.unmap(page).map_err(unmap_error)?;
+            flush.flush();
+            frames.deallocate_frame(frame);
+        }
+        Ok(())
+    })
+}
+
+/// Make `count` pages from `start` on writable or read-only. `NotFound`
+/// if one of them isn't mapped, the pages before it are changed anyway.
+///
+/// # Safety
+///
+/// Nothing can be about to write to pages that become read-only.
+pub unsafe fn protect_pages(start: VirtAddr, count: u64, writable: bool) -> KernelResult<()> {
+    let flags = data_flags(writable);
+    with_page_table(|table, _| {
+        for page in pages(start, count) {
+            table.update_flags(page, flags).map_err(flag_error)?.flush();
+        }
+        Ok(())
+    })
+}
+
+#[test_case]
+fn test_map_protect_unmap() {
+    let page = Page::containing_address(VirtAddr::new(0x5556_0000_0000));
+    let frame = frame_allocator::allocate_frame().unwrap();
+    unsafe {
+        map_to(page, frame, data_flags(true)).unwrap();
+        assert_eq!(
+            map_to(page, frame, data_flags(true)),
+            Err(KernelError::AlreadyExists)
+        );
+        assert_eq!(
+            translate_addr(page.start_address() + 8u64),
+            Some(frame.start_address() + 8u64)
+        );
+        update_flags(page, data_flags(false)).unwrap();
+        assert!(!super::translate(page.start_address()).unwrap().writable);
+        assert_eq!(unmap(page), Ok(frame));
+        assert_eq!(translate_addr(page.start_address()), None);
+        assert_eq!(unmap(page), Err(KernelError::NotFound));
+        frame_allocator::free_frame(frame).unwrap();
+    }
+}
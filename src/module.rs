@@ -0,0 +1,633 @@
+//! Loadable kernel modules.
+//!
+//! A module is a relocatable ELF object file (`ET_REL`, what `rustc
+//! --emit=obj` or `cc -c` gives you). Loading one means:
+//!
+//! 1. laying out its allocatable sections in a region of memory,
+//! 2. resolving the symbols it needs against the symbols the kernel
+//!    exported with `export_symbol!`,
+//! 3. applying the relocations so the code points at the right places,
+//! 4. calling its `module_init`.
+//!
+//! `load` and `unload` don't hold `MODULES` while the module's own code
+//! runs, so its init and exit can look at `loaded` like anyone else.
+//!
+//! Exported functions are called with the C ABI, Rust's isn't stable
+//! across compilers. The kernel's print functions have shims for that,
+//! `vga_print` and `serial_print`, `extern "C" fn(text: *const u8, len:
+//! usize)` with `len` bytes of UTF-8 at `text`.
+//!
+//! We don't have an initrd or a heap yet, so the caller has to hand us
+//! both the object file bytes (`include_bytes!` works for now) and the
+//! (executable) memory to load it into.
+use crate::error::KernelError;
+use crate::println;
+use core::{mem, ptr, slice, str};
+use spin::Mutex;
+
+/// How many modules can be loaded at once.
+pub const MAX_MODULES: usize = 8;
+/// Upper bound on the number of sections in a module object file.
+const MAX_SECTIONS: usize = 64;
+
+/// Name of the function called right after a module is loaded.
+/// Signature: `extern "C" fn() -> i32`, anything but 0 is a failure.
+pub const INIT_SYMBOL: &str = "module_init";
+/// Name of the (optional) function called on unload.
+/// Signature: `extern "C" fn()`.
+pub const EXIT_SYMBOL: &str = "module_exit";
+
+/// A symbol the kernel makes available to modules.
+#[repr(C)]
+pub struct KernelSymbol {
+    pub name: &'static str,
+    pub address: *const (),
+}
+
+// The addresses are only ever read, never written through.
+unsafe impl Sync for KernelSymbol {}
+
+/// Export a kernel function or static so modules can link against it.
+///
+/// Each exported symbol ends up in the `ksymtab` linker section. The
+/// linker gives us `__start_ksymtab` and `__stop_ksymtab` for free
+/// as the section name is a valid C identifier.
+#[macro_export]
+macro_rules! export_symbol {
+    ($name:expr, $item:path) => {
+        const _: () = {
+            #[used]
+            #[link_section = "ksymtab"]
+            static SYMBOL: $crate::module::KernelSymbol = $crate::module::KernelSymbol {
+                name: $name,
+                address: $item as *const (),
+            };
+        };
+    };
+}
+
+// Only ever used for their addresses.
+extern "C" {
+    static __start_ksymtab: u8;
+    static __stop_ksymtab: u8;
+}
+
+/// All the symbols exported by the kernel.
+pub fn kernel_symbols() -> &'static [KernelSymbol] {
+    unsafe {
+        let start = &__start_ksymtab as *const u8 as usize;
+        let end = &__stop_ksymtab as *const u8 as usize;
+        let len = (end - start) / mem::size_of::<KernelSymbol>();
+        slice::from_raw_parts(start as *const KernelSymbol, len)
+    }
+}
+
+/// Find the address of an exported kernel symbol.
+pub fn find_kernel_symbol(name: &str) -> Option<*const ()> {
+    kernel_symbols()
+        .iter()
+        .find(|symbol| symbol.name == name)
+        .map(|symbol| symbol.address)
+}
+
+/// The `len` bytes at `text` a module passed one of the print shims,
+/// up to where they stop being UTF-8.
+///
+/// # Safety
+///
+/// `text` has to point at `len` readable bytes, unless `len` is 0.
+pub(crate) unsafe fn text<'a>(text: *const u8, len: usize) -> &'a str {
+    if len == 0 {
+        return "";
+    }
+    let bytes = slice::from_raw_parts(text, len);
+    str::from_utf8(bytes)
+        .unwrap_or_else(|error| str::from_utf8(&bytes[..error.valid_up_to()]).unwrap_or_default())
+}
+
+/// Everything that can go wrong while loading a module, or a program
+/// (see `program`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    /// Not an x86_64 relocatable ELF object.
+    NotRelocatableElf,
+    /// Not an x86_64 executable, static or position independent.
+    NotExecutable,
+    /// Dynamically linked, it wants a `PT_INTERP` we don't have.
+    NeedsInterpreter,
+    /// Some offset or index in the file points outside of it.
+    Malformed,
+    TooManySections,
+    TooManySegments,
+    /// It doesn't fit in the region we were given.
+    RegionTooSmall,
+    UnresolvedSymbol(&'static str),
+    /// The kernel has the symbol, it's just not exported.
+    NotExported(&'static str),
+    UnsupportedRelocation(u32),
+    /// The relocated value doesn't fit in the relocation field.
+    RelocationOverflow,
+    MissingInit,
+    InitFailed(i32),
+    TooManyModules,
+    AlreadyLoaded,
+}
+
+impl From<LoadError> for KernelError {
+    fn from(error: LoadError) -> KernelError {
+        match error {
+            LoadError::NotRelocatableElf | LoadError::NotExecutable | LoadError::Malformed => {
+                KernelError::InvalidData
+            }
+            LoadError::TooManySections
+            | LoadError::TooManySegments
+            | LoadError::NeedsInterpreter
+            | LoadError::UnsupportedRelocation(_) => KernelError::Unsupported,
+            LoadError::RegionTooSmall | LoadError::TooManyModules => KernelError::NoSpace,
+            LoadError::UnresolvedSymbol(_) | LoadError::MissingInit => KernelError::NotFound,
+            LoadError::NotExported(_) => KernelError::PermissionDenied,
+            LoadError::RelocationOverflow => KernelError::InvalidAddress,
+            LoadError::InitFailed(_) => KernelError::DeviceError,
+            LoadError::AlreadyLoaded => KernelError::AlreadyExists,
+        }
+    }
+}
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+const SHF_ALLOC: u64 = 0x2;
+
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xfff1;
+
+const R_X86_64_64: u32 = 1;
+const R_X86_64_PC32: u32 = 2;
+const R_X86_64_PLT32: u32 = 4;
+const R_X86_64_32: u32 = 10;
+const R_X86_64_32S: u32 = 11;
+const R_X86_64_PC64: u32 = 24;
+
+pub(crate) fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, LoadError> {
+    let b = bytes.get(offset..offset + 2).ok_or(LoadError::Malformed)?;
+    Ok(u16::from_le_bytes([b[0], b[1]]))
+}
+
+pub(crate) fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, LoadError> {
+    let b = bytes.get(offset..offset + 4).ok_or(LoadError::Malformed)?;
+    Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+pub(crate) fn read_u64(bytes: &[u8], offset: usize) -> Result<u64, LoadError> {
+    let b = bytes.get(offset..offset + 8).ok_or(LoadError::Malformed)?;
+    let mut word = [0; 8];
+    word.copy_from_slice(b);
+    Ok(u64::from_le_bytes(word))
+}
+
+/// The parts of a section header we care about.
+#[derive(Debug, Clone, Copy)]
+struct Section {
+    kind: u32,
+    flags: u64,
+    offset: usize,
+    size: usize,
+    link: u32,
+    info: u32,
+    align: usize,
+}
+
+/// A parsed relocatable ELF object.
+struct Object<'a> {
+    bytes: &'a [u8],
+    section_offset: usize,
+    section_count: usize,
+}
+
+impl<'a> Object<'a> {
+    fn parse(bytes: &'a [u8]) -> Result<Object<'a>, LoadError> {
+        // magic, 64-bit, little endian, ET_REL, EM_X86_64
+        if bytes.get(..6) != Some(b"\x7fELF\x02\x01")
+            || read_u16(bytes, 0x10)? != 1
+            || read_u16(bytes, 0x12)? != 62
+        {
+            return Err(LoadError::NotRelocatableElf);
+        }
+
+        let section_offset = read_u64(bytes, 0x28)? as usize;
+        let section_count = usize::from(read_u16(bytes, 0x3C)?);
+        if read_u16(bytes, 0x3A)? != 64 {
+            return Err(LoadError::Malformed);
+        }
+        if section_count > MAX_SECTIONS {
+            return Err(LoadError::TooManySections);
+        }
+
+        Ok(Object {
+            bytes,
+            section_offset,
+            section_count,
+        })
+    }
+
+    fn section(&self, index: usize) -> Result<Section, LoadError> {
+        if index >= self.section_count {
+            return Err(LoadError::Malformed);
+        }
+        let header = self.section_offset + index * 64;
+        Ok(Section {
+            kind: read_u32(self.bytes, header + 4)?,
+            flags: read_u64(self.bytes, header + 8)?,
+            offset: read_u64(self.bytes, header + 24)? as usize,
+            size: read_u64(self.bytes, header + 32)? as usize,
+            link: read_u32(self.bytes, header + 40)?,
+            info: read_u32(self.bytes, header + 44)?,
+            align: read_u64(self.bytes, header + 48)? as usize,
+        })
+    }
+
+    fn section_data(&self, section: &Section) -> Result<&'a [u8], LoadError> {
+        self.bytes
+            .get(section.offset..section.offset + section.size)
+            .ok_or(LoadError::Malformed)
+    }
+
+    /// NUL terminated string at `offset` in a string table section.
+    fn string(&self, table: &Section, offset: usize) -> Result<&'a str, LoadError> {
+        let data = self.section_data(table)?;
+        let rest = data.get(offset..).ok_or(LoadError::Malformed)?;
+        let end = rest
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or(LoadError::Malformed)?;
+        str::from_utf8(&rest[..end]).map_err(|_| LoadError::Malformed)
+    }
+}
+
+/// A module that made it through `load`.
+#[derive(Debug, Clone, Copy)]
+pub struct LoadedModule {
+    pub name: &'static str,
+    /// Where in memory the module lives.
+    pub base: usize,
+    pub size: usize,
+    exit: Option<extern "C" fn()>,
+}
+
+static MODULES: Mutex<[Option<LoadedModule>; MAX_MODULES]> = Mutex::new([None; MAX_MODULES]);
+
+/// Lays out, links and relocates `image` into `region`. Returns the
+/// module along with the address of its init function.
+fn link(
+    name: &'static str,
+    image: &'static [u8],
+    region: &mut [u8],
+) -> Result<(LoadedModule, usize), LoadError> {
+    let object = Object::parse(image)?;
+    let base = region.as_mut_ptr() as usize;
+
+    // Place every allocatable section, remembering where it went.
+    let mut addresses = [0usize; MAX_SECTIONS];
+    let mut used = 0;
+    for (index, address) in addresses.iter_mut().enumerate().take(object.section_count) {
+        let section = object.section(index)?;
+        if section.flags & SHF_ALLOC == 0 {
+            continue;
+        }
+        let align = section.align.max(1);
+        let start = (base + used + align - 1) / align * align - base;
+        let end = start + section.size;
+        if end > region.len() {
+            return Err(LoadError::RegionTooSmall);
+        }
+
+        let target = &mut region[start..end];
+        if section.kind == SHT_NOBITS {
+            for byte in target.iter_mut() {
+                *byte = 0;
+            }
+        } else {
+            target.copy_from_slice(object.section_data(&section)?);
+        }
+        *address = base + start;
+        used = end;
+    }
+
+    // There is only ever one symbol table in an object file.
+    let mut symtab = None;
+    for index in 0..object.section_count {
+        let section = object.section(index)?;
+        if section.kind == SHT_SYMTAB {
+            symtab = Some(section);
+            break;
+        }
+    }
+    let symtab = symtab.ok_or(LoadError::Malformed)?;
+    let strtab = object.section(symtab.link as usize)?;
+    let symbols = object.section_data(&symtab)?;
+
+    // Value of symbol number `index` once loaded, with its name.
+    let symbol = |index: usize| -> Result<(&'static str, usize), LoadError> {
+        let entry = index * 24;
+        let name = object.string(&strtab, read_u32(symbols, entry)? as usize)?;
+        let section = read_u16(symbols, entry + 6)?;
+        let value = read_u64(symbols, entry + 8)? as usize;
+        let address = match section {
+            SHN_UNDEF => find_kernel_symbol(name).map(|address| address as usize),
+            SHN_ABS => Some(value),
+            section => addresses
+                .get(usize::from(section))
+                .filter(|&&address| address != 0)
+                .map(|address| address + value),
+        };
+        match address {
+            Some(address) => Ok((name, address)),
+            // Worth telling apart from a typo.
+            None if section == SHN_UNDEF && crate::ksyms::resolve(name).is_some() => {
+                Err(LoadError::NotExported(name))
+            }
+            None => Err(LoadError::UnresolvedSymbol(name)),
+        }
+    };
+
+    // Apply the relocations of every section we loaded.
+    for index in 0..object.section_count {
+        let section = object.section(index)?;
+        if section.kind != SHT_RELA {
+            continue;
+        }
+        // Relocations for sections we didn't load (debug info) are skipped.
+        let target_base = match addresses.get(section.info as usize) {
+            Some(&address) if address != 0 => address,
+            _ => continue,
+        };
+        let target_start = target_base - base;
+        let relocations = object.section_data(&section)?;
+
+        for entry in relocations.chunks_exact(24) {
+            let offset = read_u64(entry, 0)? as usize;
+            let info = read_u64(entry, 8)?;
+            let addend = read_u64(entry, 16)? as i64;
+            let (_, symbol) = symbol((info >> 32) as usize)?;
+
+            let place = target_base + offset;
+            let value = (symbol as i64).wrapping_add(addend);
+            let field = region
+                .get_mut(target_start + offset..)
+                .ok_or(LoadError::Malformed)?;
+
+            match info as u32 {
+                R_X86_64_64 => write_field(field, &(value as u64).to_le_bytes())?,
+                R_X86_64_PC64 => {
+                    write_field(field, &(value.wrapping_sub(place as i64)).to_le_bytes())?
+                }
+                R_X86_64_PC32 | R_X86_64_PLT32 => {
+                    let relative = value.wrapping_sub(place as i64);
+                    write_field(field, &fit_i32(relative)?.to_le_bytes())?
+                }
+                R_X86_64_32 => {
+                    let value = if value >= 0 && value <= i64::from(u32::MAX) {
+                        value as u32
+                    } else {
+                        return Err(LoadError::RelocationOverflow);
+                    };
+                    write_field(field, &value.to_le_bytes())?
+                }
+                R_X86_64_32S => write_field(field, &fit_i32(value)?.to_le_bytes())?,
+                other => return Err(LoadError::UnsupportedRelocation(other)),
+            }
+        }
+    }
+
+    // Now find the entry points.
+    let symbol_count = symbols.len() / 24;
+    let mut init = None;
+    let mut exit = None;
+    for index in 1..symbol_count {
+        if let Ok((name, address)) = symbol(index) {
+            if name == INIT_SYMBOL {
+                init = Some(address);
+            } else if name == EXIT_SYMBOL {
+                exit = Some(unsafe { mem::transmute::<usize, extern "C" fn()>(address) });
+            }
+        }
+    }
+
+    let module = LoadedModule {
+        name,
+        base,
+        size: used,
+        exit,
+    };
+    Ok((module, init.ok_or(LoadError::MissingInit)?))
+}
+
+fn fit_i32(value: i64) -> Result<i32, LoadError> {
+    if value >= i64::from(i32::MIN) && value <= i64::from(i32::MAX) {
+        Ok(value as i32)
+    } else {
+        Err(LoadError::RelocationOverflow)
+    }
+}
+
+pub(crate) fn write_field(field: &mut [u8], bytes: &[u8]) -> Result<(), LoadError> {
+    field
+        .get_mut(..bytes.len())
+        .ok_or(LoadError::Malformed)?
+        .copy_from_slice(bytes);
+    Ok(())
+}
+
+/// Load a module and run its `module_init`.
+///
+/// # Safety
+///
+/// `region` must be mapped executable and must not be used for
+/// anything else until the module is unloaded. Loading a module runs
+/// arbitrary code with kernel privileges.
+pub unsafe fn load(
+    name: &'static str,
+    image: &'static [u8],
+    region: &'static mut [u8],
+) -> Result<LoadedModule, LoadError> {
+    let (module, init) = {
+        let mut modules = MODULES.lock();
+        if modules.iter().flatten().any(|module| module.name == name) {
+            return Err(LoadError::AlreadyLoaded);
+        }
+        let slot = modules
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(LoadError::TooManyModules)?;
+        let (module, init) = link(name, image, region)?;
+        // Taken now, so nobody loads the same name while init runs.
+        *slot = Some(module);
+        (module, init)
+    };
+    let init = mem::transmute::<usize, extern "C" fn() -> i32>(init);
+    match init() {
+        0 => {
+            println!("module: loaded {} at {:#x}", name, module.base);
+            Ok(module)
+        }
+        status => {
+            if let Some(module) = take(name) {
+                scrub(&module);
+            }
+            Err(LoadError::InitFailed(status))
+        }
+    }
+}
+
+fn take(name: &str) -> Option<LoadedModule> {
+    MODULES
+        .lock()
+        .iter_mut()
+        .find(|slot| slot.map_or(false, |module| module.name == name))?
+        .take()
+}
+
+/// Zero the module's region, so stale code can't be jumped into.
+unsafe fn scrub(module: &LoadedModule) {
+    ptr::write_bytes(module.base as *mut u8, 0, module.size);
+}
+
+/// Run the module's `module_exit` and forget about it. Gives back
+/// `false` if no module with that name is loaded.
+///
+/// # Safety
+///
+/// Nothing may still be referencing code or data from the module.
+pub unsafe fn unload(name: &str) -> bool {
+    let module = match take(name) {
+        Some(module) => module,
+        None => return false,
+    };
+    if let Some(exit) = module.exit {
+        exit();
+    }
+    scrub(&module);
+    true
+}
+
+/// A copy of the currently loaded modules.
+pub fn loaded() -> [Option<LoadedModule>; MAX_MODULES] {
+    *MODULES.lock()
+}
+
+#[test_case]
+fn test_exported_symbols_resolve() {
+    let address = find_kernel_symbol("vga_print").expect("vga_print not exported");
+    #[cfg(not(feature = "no-vga"))]
+    assert_eq!(address, crate::vga_buffer::module_print as *const ());
+    #[cfg(feature = "no-vga")]
+    assert_eq!(address, crate::serial::module_print as *const ());
+    assert!(find_kernel_symbol("definitely_not_exported").is_none());
+}
+
+#[test_case]
+fn test_rejects_non_elf() {
+    let mut region = [0u8; 16];
+    let result = link("bogus", b"not an elf file at all", &mut region);
+    assert_eq!(result.err(), Some(LoadError::NotRelocatableElf));
+}
+
+/// What the fixture module's init passed to `module_test_hook`.
+#[cfg(test)]
+static HOOKED: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(0);
+
+#[cfg(test)]
+extern "C" fn module_test_hook(value: u64) {
+    HOOKED.store(value, core::sync::atomic::Ordering::SeqCst);
+}
+
+#[cfg(test)]
+crate::export_symbol!("module_test_hook", module_test_hook);
+
+#[test_case]
+fn test_loads_relocatable_object() {
+    use crate::memory::paging;
+    use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+    use core::sync::atomic::Ordering;
+    use x86_64::structures::paging::{Page, PageTableFlags};
+    use x86_64::VirtAddr;
+
+    // .text, .symtab, .rela.text and .strtab, then the section headers.
+    static mut IMAGE: [u8; 0x218] = [0; 0x218];
+    let image = unsafe { &mut IMAGE };
+    image[..6].copy_from_slice(b"\x7fELF\x02\x01");
+    let mut put = |offset: usize, value: u64, len: usize| {
+        image[offset..offset + len].copy_from_slice(&value.to_le_bytes()[..len]);
+    };
+    put(0x10, 1, 2); // ET_REL
+    put(0x12, 62, 2);
+    put(0x28, 0xD8, 8); // section headers
+    put(0x3A, 64, 2);
+    put(0x3C, 5, 2);
+    // sub rsp, 8; mov edi, 42; call module_test_hook; add rsp, 8;
+    // xor eax, eax; ret
+    let code = [
+        0x48, 0x83, 0xec, 0x08, 0xbf, 42, 0, 0, 0, 0xe8, 0, 0, 0, 0, 0x48, 0x83, 0xc4, 0x08, 0x31,
+        0xc0, 0xc3,
+    ];
+    for (index, &byte) in code.iter().enumerate() {
+        put(0x40 + index, byte, 1);
+    }
+    // Symbol 1 is `module_init` at the start of .text, 2 the hook.
+    put(0x58 + 24, 1, 4);
+    put(0x58 + 24 + 4, 0x12, 1); // global function
+    put(0x58 + 24 + 6, 1, 2);
+    put(0x58 + 48, 13, 4);
+    put(0x58 + 48 + 4, 0x10, 1); // global, undefined
+                                 // The call's operand, relative to its end.
+    put(0xA0, 10, 8);
+    put(0xA8, (2 << 32) | u64::from(R_X86_64_PLT32), 8);
+    put(0xB0, -4i64 as u64, 8);
+    for (index, &byte) in b"\0module_init\0module_test_hook\0".iter().enumerate() {
+        put(0xB8 + index, u64::from(byte), 1);
+    }
+    // (kind, flags, offset, size, link, info, align)
+    let sections = [
+        (1, SHF_ALLOC | 0x4, 0x40, code.len(), 0, 0, 16),
+        (SHT_SYMTAB, 0, 0x58, 72, 3, 1, 8),
+        (3, 0, 0xB8, 30, 0, 0, 1),
+        (SHT_RELA, 0, 0xA0, 24, 2, 1, 8),
+    ];
+    for (index, &(kind, flags, offset, size, link, info, align)) in sections.iter().enumerate() {
+        let header = 0xD8 + (index + 1) * 64;
+        put(header + 4, u64::from(kind), 4);
+        put(header + 8, flags, 8);
+        put(header + 24, offset, 8);
+        put(header + 32, size as u64, 8);
+        put(header + 40, link, 4);
+        put(header + 44, info, 4);
+        put(header + 48, align, 8);
+    }
+    let image: &'static [u8] = unsafe { &IMAGE };
+
+    // Heap memory is never executable, this page has to be.
+    let layout = Layout::from_size_align(4096, 4096).unwrap();
+    let start = unsafe { alloc_zeroed(layout) };
+    let page = Page::containing_address(VirtAddr::from_ptr(start));
+    let writable = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe { paging::update_flags(page, writable) }.unwrap();
+    let region = unsafe { slice::from_raw_parts_mut(start, 4096) };
+
+    let module = unsafe { load("fixture", image, region) }.unwrap();
+    assert_eq!(HOOKED.load(Ordering::SeqCst), 42);
+    assert_eq!(module.base, start as usize);
+    assert!(loaded()
+        .iter()
+        .flatten()
+        .any(|module| module.name == "fixture"));
+    assert!(unsafe { unload("fixture") });
+    assert!(loaded()
+        .iter()
+        .flatten()
+        .all(|module| module.name != "fixture"));
+
+    unsafe {
+        paging::update_flags(page, paging::data_flags(true)).unwrap();
+        dealloc(start, layout);
+    }
+}
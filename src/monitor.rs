@@ -0,0 +1,193 @@
+//! A tiny kernel debugger that talks over serial.
+//!
+//! Send a serial break, or press `Ctrl-]` in the terminal QEMU's
+//! `-serial stdio` is attached to, and the serial interrupt drops us in
+//! here. So does hitting a breakpoint set with `bp`. Everything runs
+//! inside that interrupt handler with interrupts off, polling the UART
+//! directly and never touching a lock, so it still works when the rest
+//! of the kernel is stuck.
+//!
+//! Besides the few commands here that need the interrupted state,
+//! everything registered with `shell` can be run. Lines can be edited
+//! and earlier ones brought back with up/down, see `line_editor`.
+//! Tab completes command names, and paths after them, a second tab
+//! lists the candidates.
+use crate::fs::{self, Kind};
+use crate::line_editor::{Completion, Key, KeyDecoder, LineEditor, LINE_LENGTH};
+use crate::scheduler::State;
+use crate::serial::{receive_raw, RawSerial, Received};
+use crate::shell;
+use crate::unwind::Backtrace;
+use core::fmt::Write;
+use core::str;
+use spin::Mutex;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::InterruptStackFrame;
+
+/// `Ctrl-]`, same escape character telnet uses.
+pub const MAGIC_BYTE: u8 = 0x1D;
+
+const PROMPT: &str = "mon> ";
+
+/// Kept between visits so the history survives. Only ever used from
+/// here, and we can't be in here twice.
+static EDITOR: Mutex<LineEditor> = Mutex::new(LineEditor::new());
+
+/// Run the monitor until the user asks to continue.
+pub fn enter(stack_frame: &InterruptStackFrame) {
+    let mut out = RawSerial::new();
+    let _ = writeln!(out, "\n-- kernel monitor, `help` for commands --");
+
+    let mut editor = match EDITOR.try_lock() {
+        Some(editor) => editor,
+        None => return,
+    };
+    let mut line = [0u8; LINE_LENGTH];
+    loop {
+        let len = read_line(&mut out, &mut editor);
+        line[..len].copy_from_slice(editor.line().as_bytes());
+        let line = str::from_utf8(&line[..len]).unwrap_or("");
+
+        let mut words = line.split_whitespace();
+        match words.next() {
+            None => {}
+            Some("help") => {
+                let _ = writeln!(
+                    out,
+                    "regs            interrupted registers\n\
+                     bt              backtrace\n\
+                     tasks           list tasks\n\
+                     c               continue"
+                );
+                shell::for_each(|command| {
+                    let _ = writeln!(out, "{:<15} {}", command.name, command.help);
+                });
+            }
+            Some("regs") => print_registers(&mut out, stack_frame),
+            Some("bt") => {
+                let _ = write!(out, "{}", Backtrace::capture());
+            }
+            Some("tasks") => {
+                let listed = crate::scheduler::for_each_thread(|thread| {
+                    let _ = write!(
+                        out,
+                        "{:>3}  {} {}",
+                        thread.id,
+                        thread.name,
+                        thread.state.as_str()
+                    );
+                    if thread.state == State::Running {
+                        let _ = write!(
+                            out,
+                            " (interrupted at {:?})",
+                            stack_frame.instruction_pointer
+                        );
+                    }
+                    let _ = writeln!(out);
+                });
+                if listed.is_err() {
+                    let _ = writeln!(out, "the scheduler was interrupted with its lock held");
+                }
+            }
+            Some("c") | Some("continue") => break,
+            Some(_) => {
+                // Handlers say what went wrong themselves.
+                let _ = shell::execute(&mut out, line);
+            }
+        }
+    }
+    let _ = writeln!(out, "-- continuing --");
+}
+
+/// Commands handled here rather than through `shell`.
+const BUILTINS: &[&str] = &["help", "regs", "bt", "tasks", "c", "continue"];
+
+fn complete(word: usize, prefix: &str, f: &mut dyn FnMut(&str)) {
+    if word == 0 {
+        BUILTINS.iter().for_each(|name| f(name));
+        shell::for_each(|command| f(command.name));
+    } else {
+        complete_path(prefix, f);
+    }
+}
+
+/// What's in the directory `prefix` is in, as paths that start like it
+/// does. Relative ones are from the root, like the shell takes them.
+/// It waits on the file system's locks, like running `ls` from here.
+fn complete_path(prefix: &str, f: &mut dyn FnMut(&str)) {
+    let directory = &prefix[..prefix.rfind('/').map_or(0, |slash| slash + 1)];
+    let entries =
+        fs::normalize(&alloc::format!("/{}", directory)).and_then(|path| fs::read_dir(&path));
+    for entry in entries.into_iter().flatten() {
+        let slash = if entry.metadata.kind == Kind::Directory {
+            "/"
+        } else {
+            ""
+        };
+        f(&alloc::format!("{}{}{}", directory, entry.name, slash));
+    }
+}
+
+/// Read a line, polling the UART. Returns its length, the line itself
+/// is left in `editor`.
+fn read_line(out: &mut RawSerial, editor: &mut LineEditor) -> usize {
+    let mut decoder = KeyDecoder::new();
+    editor.clear();
+    let _ = write!(out, "{}", PROMPT);
+    loop {
+        let byte = match receive_raw() {
+            Some(Received::Byte(byte)) => byte,
+            Some(Received::Break) => continue,
+            None => {
+                core::sync::atomic::spin_loop_hint();
+                continue;
+            }
+        };
+        let key = match decoder.feed(byte) {
+            Some(key) => key,
+            None => continue,
+        };
+        if key == Key::Tab {
+            if editor.complete(complete) == (Completion::Ambiguous { list: true }) {
+                let _ = writeln!(out);
+                editor.for_each_candidate(complete, |candidate| {
+                    let _ = write!(out, "{}  ", candidate);
+                });
+                let _ = writeln!(out);
+            }
+        } else if editor.key(key) {
+            let _ = writeln!(out);
+            return editor.line().len();
+        }
+        // Redraw the whole line, clear whatever is left after it and
+        // step back to where the cursor is.
+        let _ = write!(out, "\r{}{}\x1b[K", PROMPT, editor.line());
+        let behind = editor.line().len() - editor.cursor();
+        if behind > 0 {
+            let _ = write!(out, "\x1b[{}D", behind);
+        }
+    }
+}
+
+fn print_registers(out: &mut RawSerial, stack_frame: &InterruptStackFrame) {
+    let _ = writeln!(
+        out,
+        "rip    {:#018x}  cs {:#06x}\n\
+         rsp    {:#018x}  ss {:#06x}\n\
+         rflags {:#018x}",
+        stack_frame.instruction_pointer.as_u64(),
+        stack_frame.code_segment,
+        stack_frame.stack_pointer.as_u64(),
+        stack_frame.stack_segment,
+        stack_frame.cpu_flags,
+    );
+    let (level_4_table, _) = Cr3::read();
+    let _ = writeln!(
+        out,
+        "cr0    {:#018x}\ncr2    {:#018x}\ncr3    {:#018x}\ncr4    {:#018x}",
+        Cr0::read_raw(),
+        Cr2::read().as_u64(),
+        level_4_table.start_address().as_u64(),
+        Cr4::read_raw(),
+    );
+}
@@ -0,0 +1,167 @@
+//! A mouse pointer on the text screen.
+//!
+//! A mouse driver hands every movement/button packet to `handle`. That
+//! moves the pointer, a cell with its colours swapped, and turns
+//! button presses into `Click`s with the cell they happened on, which
+//! whatever draws the UI picks up with `next_click`.
+//!
+//! There is no PS/2 mouse driver yet, so nothing calls `handle` apart
+//! from the tests.
+use crate::latency;
+use crate::vga_buffer::{BUFFER_HEIGHT, BUFFER_WIDTH, WRITER};
+use spin::Mutex;
+
+/// Mouse counts it takes to move one column. Rows are about twice as
+/// tall as columns are wide, so a row takes twice as many.
+pub const COUNTS_PER_COLUMN: i32 = 8;
+
+/// Clicks kept for `next_click`, older ones get dropped.
+pub const MAX_CLICKS: usize = 16;
+
+pub const LEFT_BUTTON: u8 = 1;
+pub const RIGHT_BUTTON: u8 = 1 << 1;
+pub const MIDDLE_BUTTON: u8 = 1 << 2;
+
+/// One packet from the mouse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    /// Positive is to the right.
+    pub dx: i16,
+    /// Positive is down the screen. PS/2 counts up, the driver flips it.
+    pub dy: i16,
+    /// The `*_BUTTON` bits of the buttons held down.
+    pub buttons: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Click {
+    pub row: usize,
+    pub column: usize,
+    /// Which one of the `*_BUTTON`s.
+    pub button: u8,
+}
+
+struct Pointer {
+    /// In mouse counts, so slow movements add up.
+    x: i32,
+    y: i32,
+    buttons: u8,
+    clicks: [Option<Click>; MAX_CLICKS],
+    /// Where the oldest click is.
+    first: usize,
+    len: usize,
+}
+
+impl Pointer {
+    fn cell(&self) -> (usize, usize) {
+        (
+            (self.y / (2 * COUNTS_PER_COLUMN)) as usize,
+            (self.x / COUNTS_PER_COLUMN) as usize,
+        )
+    }
+
+    fn push(&mut self, click: Click) {
+        if self.len == MAX_CLICKS {
+            self.first = (self.first + 1) % MAX_CLICKS;
+            self.len -= 1;
+        }
+        self.clicks[(self.first + self.len) % MAX_CLICKS] = Some(click);
+        self.len += 1;
+    }
+}
+
+static POINTER: Mutex<Pointer> = Mutex::new(Pointer {
+    x: BUFFER_WIDTH as i32 / 2 * COUNTS_PER_COLUMN,
+    y: BUFFER_HEIGHT as i32 / 2 * 2 * COUNTS_PER_COLUMN,
+    buttons: 0,
+    clicks: [None; MAX_CLICKS],
+    first: 0,
+    len: 0,
+});
+
+/// Move the pointer and note any button that went down. Meant to be
+/// called from the mouse interrupt.
+pub fn handle(event: MouseEvent) {
+    latency::without_interrupts(|| {
+        let mut pointer = POINTER.lock();
+        let width = BUFFER_WIDTH as i32 * COUNTS_PER_COLUMN;
+        let height = BUFFER_HEIGHT as i32 * 2 * COUNTS_PER_COLUMN;
+        pointer.x = (pointer.x + i32::from(event.dx)).max(0).min(width - 1);
+        pointer.y = (pointer.y + i32::from(event.dy)).max(0).min(height - 1);
+
+        let (row, column) = pointer.cell();
+        let pressed = event.buttons & !pointer.buttons;
+        for &button in &[LEFT_BUTTON, RIGHT_BUTTON, MIDDLE_BUTTON] {
+            if pressed & button != 0 {
+                pointer.push(Click {
+                    row,
+                    column,
+                    button,
+                });
+            }
+        }
+        pointer.buttons = event.buttons;
+
+        // If the screen is busy the pointer catches up on the next
+        // packet.
+        if let Some(mut screen) = WRITER.try_lock() {
+            screen.set_pointer(Some((row, column)));
+        }
+    });
+}
+
+/// Where the pointer is, as `(row, column)`.
+pub fn position() -> (usize, usize) {
+    latency::without_interrupts(|| POINTER.lock().cell())
+}
+
+/// The oldest click not picked up yet.
+pub fn next_click() -> Option<Click> {
+    latency::without_interrupts(|| {
+        let mut pointer = POINTER.lock();
+        if pointer.len == 0 {
+            return None;
+        }
+        let first = pointer.first;
+        pointer.first = (first + 1) % MAX_CLICKS;
+        pointer.len -= 1;
+        pointer.clicks[first].take()
+    })
+}
+
+#[test_case]
+fn test_moves_and_clicks() {
+    while next_click().is_some() {}
+    // Far enough to hit the top left corner from anywhere.
+    handle(MouseEvent {
+        dx: -10_000,
+        dy: -10_000,
+        buttons: 0,
+    });
+    assert_eq!(position(), (0, 0));
+    handle(MouseEvent {
+        dx: 3 * COUNTS_PER_COLUMN as i16,
+        dy: 2 * 2 * COUNTS_PER_COLUMN as i16,
+        buttons: LEFT_BUTTON,
+    });
+    // Held down isn't another click.
+    handle(MouseEvent {
+        dx: 0,
+        dy: 0,
+        buttons: LEFT_BUTTON,
+    });
+    assert_eq!(
+        next_click(),
+        Some(Click {
+            row: 2,
+            column: 3,
+            button: LEFT_BUTTON
+        })
+    );
+    assert_eq!(next_click(), None);
+    handle(MouseEvent {
+        dx: 0,
+        dy: 0,
+        buttons: 0,
+    });
+}
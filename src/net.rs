@@ -0,0 +1,274 @@
+//! Network interfaces.
+//!
+//! A driver `register`s its interface under a name, and counts what it
+//! sends and receives on it with `count_tx` and `count_rx`. How the
+//! interface is set up, its IPv4 address, netmask and gateway and
+//! whether it's up, is `ifconfig`'s, for when there's no DHCP to do it.
+//! `lo` is always there, up as `127.0.0.1/8`.
+//!
+//! There are no drivers or IP yet, so nothing moves packets over any
+//! of them but `socket`, whose datagrams only go from one port of this
+//! machine to another; `mbuf` is what real packets will be in. `tftp`,
+//! `sntp` and `http` are only what goes over the wire until then.
+use crate::error::{KernelError, KernelResult};
+use crate::fault_injection::{self, FaultPoint};
+use crate::latency;
+use core::fmt;
+use spin::Mutex;
+
+pub mod http;
+pub mod ifconfig;
+pub mod sntp;
+pub mod socket;
+pub mod tftp;
+
+pub const MAX_INTERFACES: usize = 8;
+pub const LOOPBACK: &str = "lo";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    pub const UNSPECIFIED: Ipv4Addr = Ipv4Addr([0; 4]);
+
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    pub fn from_u32(value: u32) -> Ipv4Addr {
+        Ipv4Addr(value.to_be_bytes())
+    }
+
+    /// `a.b.c.d`.
+    pub fn parse(text: &str) -> Option<Ipv4Addr> {
+        let mut bytes = [0; 4];
+        let mut parts = text.split('.');
+        for byte in bytes.iter_mut() {
+            let part = parts.next()?;
+            // No signs, and nothing parse would take for hex.
+            if part.is_empty() || part.len() > 3 || !part.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            *byte = part.parse().ok()?;
+        }
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Ipv4Addr(bytes))
+    }
+
+    /// The netmask with the top `bits` bits set.
+    pub fn netmask(bits: u32) -> Option<Ipv4Addr> {
+        match bits {
+            0 => Some(Ipv4Addr::UNSPECIFIED),
+            1..=32 => Some(Ipv4Addr::from_u32(!0 << (32 - bits))),
+            _ => None,
+        }
+    }
+
+    /// How many bits are set, if this is a netmask: all the ones before
+    /// all the zeros.
+    pub fn prefix_len(self) -> Option<u32> {
+        let mask = self.to_u32();
+        if mask.leading_ones() + mask.trailing_zeros() == 32 {
+            Some(mask.leading_ones())
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, byte) in self.0.iter().enumerate() {
+            if index > 0 {
+                f.write_str(".")?;
+            }
+            write!(f, "{}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, byte) in self.0.iter().enumerate() {
+            if index > 0 {
+                f.write_str(":")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InterfaceStats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    /// Received and thrown away, only by fault injection so far.
+    pub rx_dropped: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+}
+
+/// An interface's address, set with `ifconfig::set_address`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Address {
+    pub address: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+}
+
+impl Address {
+    /// Whether `other` is on the same subnet.
+    pub fn contains(&self, other: Ipv4Addr) -> bool {
+        let mask = self.netmask.to_u32();
+        self.address.to_u32() & mask == other.to_u32() & mask
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interface {
+    pub name: &'static str,
+    pub mac: MacAddr,
+    pub up: bool,
+    pub address: Option<Address>,
+    pub gateway: Option<Ipv4Addr>,
+    pub stats: InterfaceStats,
+}
+
+static INTERFACES: Mutex<[Option<Interface>; MAX_INTERFACES]> = Mutex::new([None; MAX_INTERFACES]);
+
+/// Add an interface, down and without an address. `AlreadyExists` if
+/// there's one called `name` already, `NoSpace` if there are
+/// `MAX_INTERFACES`.
+pub fn register(name: &'static str, mac: MacAddr) -> KernelResult<()> {
+    latency::without_interrupts(|| {
+        let mut interfaces = INTERFACES.lock();
+        if interfaces
+            .iter()
+            .flatten()
+            .any(|interface| interface.name == name)
+        {
+            return Err(KernelError::AlreadyExists);
+        }
+        let slot = interfaces
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(KernelError::NoSpace)?;
+        *slot = Some(Interface {
+            name,
+            mac,
+            up: false,
+            address: None,
+            gateway: None,
+            stats: InterfaceStats::default(),
+        });
+        Ok(())
+    })
+}
+
+/// Run `f` on the interface called `name`. `NotFound` if there isn't
+/// one.
+fn with_interface<R>(name: &str, f: impl FnOnce(&mut Interface) -> R) -> KernelResult<R> {
+    latency::without_interrupts(|| {
+        INTERFACES
+            .lock()
+            .iter_mut()
+            .flatten()
+            .find(|interface| interface.name == name)
+            .map(f)
+            .ok_or(KernelError::NotFound)
+    })
+}
+
+/// What the interface called `name` looks like right now.
+pub fn interface(name: &str) -> KernelResult<Interface> {
+    with_interface(name, |interface| *interface)
+}
+
+/// Call `f` on a copy of each interface, in the order they were added.
+/// `Busy` if they were locked, rather than waiting: a crash dump can
+/// come in while a driver holds them.
+pub fn for_each(mut f: impl FnMut(&Interface)) -> KernelResult<()> {
+    let interfaces =
+        latency::without_interrupts(|| INTERFACES.try_lock().map(|interfaces| *interfaces))
+            .ok_or(KernelError::Busy)?;
+    for interface in interfaces.iter().flatten() {
+        f(interface);
+    }
+    Ok(())
+}
+
+/// A copy of the first interface `f` says yes to, in the order they
+/// were added.
+pub fn find(mut f: impl FnMut(&Interface) -> bool) -> Option<Interface> {
+    latency::without_interrupts(|| {
+        INTERFACES
+            .lock()
+            .iter()
+            .flatten()
+            .find(|interface| f(interface))
+            .copied()
+    })
+}
+
+/// For drivers: a packet of `bytes` bytes came in on `name`.
+/// `DeviceError` if it's to be dropped, as if it never arrived, which
+/// `fault_injection` can ask for.
+pub fn count_rx(name: &str, bytes: usize) -> KernelResult<()> {
+    let dropped = fault_injection::should_fail(FaultPoint::NetworkPacket);
+    with_interface(name, |interface| {
+        if dropped {
+            interface.stats.rx_dropped += 1;
+        } else {
+            interface.stats.rx_packets += 1;
+            interface.stats.rx_bytes += bytes as u64;
+        }
+    })?;
+    if dropped {
+        return Err(KernelError::DeviceError);
+    }
+    Ok(())
+}
+
+/// For drivers: a packet of `bytes` bytes went out on `name`.
+pub fn count_tx(name: &str, bytes: usize) -> KernelResult<()> {
+    with_interface(name, |interface| {
+        interface.stats.tx_packets += 1;
+        interface.stats.tx_bytes += bytes as u64;
+    })
+}
+
+/// Adds the loopback interface and the `ifconfig` shell command.
+pub fn init() {
+    register(LOOPBACK, MacAddr([0; 6])).expect("loopback interface");
+    let address = Ipv4Addr([127, 0, 0, 1]);
+    ifconfig::set_address(LOOPBACK, address, Ipv4Addr::netmask(8).unwrap())
+        .and_then(|()| ifconfig::set_up(LOOPBACK, true))
+        .expect("loopback interface");
+    ifconfig::init();
+}
+
+#[test_case]
+fn test_ipv4_addresses() {
+    assert_eq!(Ipv4Addr::parse("10.0.2.15"), Some(Ipv4Addr([10, 0, 2, 15])));
+    for bad in ["10.0.2", "10.0.2.15.1", "10.0.2.256", "10.0..1", "+1.0.0.0"].iter() {
+        assert_eq!(Ipv4Addr::parse(bad), None, "{}", bad);
+    }
+    let mask = Ipv4Addr::netmask(24).unwrap();
+    assert_eq!(mask, Ipv4Addr([255, 255, 255, 0]));
+    assert_eq!(mask.prefix_len(), Some(24));
+    assert_eq!(Ipv4Addr::netmask(0).unwrap().prefix_len(), Some(0));
+    assert_eq!(Ipv4Addr([255, 0, 255, 0]).prefix_len(), None);
+    assert_eq!(Ipv4Addr::netmask(33), None);
+    let address = Address {
+        address: Ipv4Addr([10, 0, 2, 15]),
+        netmask: mask,
+    };
+    assert!(address.contains(Ipv4Addr([10, 0, 2, 2])));
+    assert!(!address.contains(Ipv4Addr([10, 0, 3, 2])));
+}
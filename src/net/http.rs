@@ -0,0 +1,215 @@
+//! Fetching pages with HTTP/1.0.
+//!
+//! A fetch is one request and the answer, read until the server closes
+//! the connection. HTTP/1.0 servers just send the body, but plenty
+//! answer as 1.1 anyway, so a `chunked` body is put back together too.
+//!
+//! There's no TCP yet, or DNS, so this is only the two ends of it:
+//! `request` is what to send, and `parse_response` makes sense of what
+//! came back. A URL's host has to be an address, `Url::address` is
+//! what to connect to.
+use super::Ipv4Addr;
+use crate::error::KernelError;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+pub const DEFAULT_PORT: u16 = 80;
+/// The status line and headers can't be longer than this.
+pub const MAX_HEADER: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpError {
+    /// Not an `http://` URL, or one whose host isn't an address.
+    BadUrl,
+    /// The server sent something that isn't HTTP.
+    Malformed,
+    /// Bigger than the caller wanted.
+    TooBig,
+}
+
+impl From<HttpError> for KernelError {
+    fn from(error: HttpError) -> KernelError {
+        match error {
+            HttpError::BadUrl => KernelError::InvalidArgument,
+            HttpError::Malformed => KernelError::InvalidData,
+            HttpError::TooBig => KernelError::NoSpace,
+        }
+    }
+}
+
+/// `http://HOST[:PORT][/PATH]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Url<'a> {
+    pub host: &'a str,
+    pub port: u16,
+    /// With its `/`, and the query if there is one.
+    pub path: &'a str,
+}
+
+impl<'a> Url<'a> {
+    pub fn parse(text: &'a str) -> Result<Url<'a>, HttpError> {
+        let rest = text.strip_prefix("http://").ok_or(HttpError::BadUrl)?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.find(':') {
+            Some(colon) => (
+                &authority[..colon],
+                authority[colon + 1..]
+                    .parse()
+                    .map_err(|_| HttpError::BadUrl)?,
+            ),
+            None => (authority, DEFAULT_PORT),
+        };
+        if host.is_empty() {
+            return Err(HttpError::BadUrl);
+        }
+        Ok(Url { host, port, path })
+    }
+
+    /// Where to connect to, if the host is an address. Names need DNS.
+    pub fn address(&self) -> Option<Ipv4Addr> {
+        Ipv4Addr::parse(self.host)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+/// Where the headers end and the body starts, if they have yet.
+fn header_end(bytes: &[u8]) -> Option<usize> {
+    bytes
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|at| at + 4)
+}
+
+/// What `name` is set to in `headers`, ignoring case.
+fn header<'a>(headers: &'a str, name: &str) -> Option<&'a str> {
+    headers.split("\r\n").skip(1).find_map(|line| {
+        let colon = line.find(':')?;
+        if line[..colon].eq_ignore_ascii_case(name) {
+            Some(line[colon + 1..].trim())
+        } else {
+            None
+        }
+    })
+}
+
+/// `HTTP/1.x NNN reason`.
+fn status(headers: &str) -> Option<u16> {
+    let mut words = headers.split("\r\n").next()?.split(' ');
+    if !words.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+    let code = words.next()?;
+    if code.len() != 3 {
+        return None;
+    }
+    code.parse().ok()
+}
+
+/// Put a `chunked` body back together: each chunk its size in hex and
+/// `\r\n`, the chunk and `\r\n` again, until one of size 0.
+fn dechunk(mut bytes: &[u8]) -> Result<Vec<u8>, HttpError> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = bytes
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .ok_or(HttpError::Malformed)?;
+        let line = core::str::from_utf8(&bytes[..line_end]).map_err(|_| HttpError::Malformed)?;
+        // Anything after a `;` is an extension, nobody uses them.
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| HttpError::Malformed)?;
+        bytes = &bytes[line_end + 2..];
+        if size == 0 {
+            // Trailers, if any, don't matter.
+            return Ok(body);
+        }
+        if bytes.len() < size + 2 || &bytes[size..size + 2] != b"\r\n" {
+            return Err(HttpError::Malformed);
+        }
+        body.extend_from_slice(&bytes[..size]);
+        bytes = &bytes[size + 2..];
+    }
+}
+
+/// The request for `url`, and the URL it's for.
+pub fn request(url: &str) -> Result<(Url, String), HttpError> {
+    let url = Url::parse(url)?;
+    let mut request = String::new();
+    let _ = write!(
+        request,
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: blog_os\r\nConnection: close\r\n\r\n",
+        url.path, url.host
+    );
+    Ok((url, request))
+}
+
+/// The response in `bytes`, everything the server sent, with a body of
+/// up to `max_size` bytes. Any status is a response, it's for the
+/// caller to say what's an error.
+pub fn parse_response(bytes: &[u8], max_size: usize) -> Result<Response, HttpError> {
+    let start =
+        header_end(&bytes[..bytes.len().min(MAX_HEADER + 4)]).ok_or(HttpError::Malformed)?;
+    let headers = core::str::from_utf8(&bytes[..start]).map_err(|_| HttpError::Malformed)?;
+    let status = status(headers).ok_or(HttpError::Malformed)?;
+    let length: Option<usize> = match header(headers, "content-length") {
+        Some(length) => Some(length.parse().map_err(|_| HttpError::Malformed)?),
+        None => None,
+    };
+    let raw = &bytes[start..];
+    let body = match header(headers, "transfer-encoding") {
+        Some(encoding) if encoding.eq_ignore_ascii_case("chunked") => dechunk(raw)?,
+        Some(encoding) if !encoding.eq_ignore_ascii_case("identity") => {
+            return Err(HttpError::Malformed)
+        }
+        _ => match length {
+            Some(length) if raw.len() < length => return Err(HttpError::Malformed),
+            Some(length) => raw[..length].to_vec(),
+            None => raw.to_vec(),
+        },
+    };
+    if body.len() > max_size {
+        return Err(HttpError::TooBig);
+    }
+    Ok(Response { status, body })
+}
+
+#[test_case]
+fn test_parses_identity_and_chunked_bodies() {
+    let (url, text) = request("http://10.0.2.2:8000/index.html").unwrap();
+    assert_eq!(url.address(), Some(Ipv4Addr([10, 0, 2, 2])));
+    assert_eq!(url.port, 8000);
+    assert!(text.starts_with("GET /index.html HTTP/1.0\r\nHost: 10.0.2.2\r\n"));
+    assert!(text.ends_with("\r\n\r\n"));
+
+    let identity = b"HTTP/1.0 200 OK\r\nContent-Length: 5\r\n\r\nhello and then junk";
+    let response = parse_response(identity, 64).unwrap();
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, b"hello");
+
+    let chunked = b"HTTP/1.1 404 Not Found\r\ntransfer-encoding: Chunked\r\n\r\n\
+                    4\r\nnot \r\n5;x=y\r\nfound\r\n0\r\n\r\n";
+    let response = parse_response(chunked, 64).unwrap();
+    assert_eq!(response.status, 404);
+    assert_eq!(response.body, b"not found");
+    assert_eq!(parse_response(chunked, 4), Err(HttpError::TooBig));
+    // Cut off before the headers were done.
+    assert_eq!(
+        parse_response(&chunked[..20], 64),
+        Err(HttpError::Malformed)
+    );
+    assert_eq!(request("ftp://10.0.2.2").err(), Some(HttpError::BadUrl));
+
+    let url = Url::parse("http://example.com").unwrap();
+    assert_eq!(url.path, "/");
+    assert_eq!(url.port, DEFAULT_PORT);
+    assert_eq!(url.address(), None);
+}
@@ -0,0 +1,209 @@
+//! Setting interfaces up by hand.
+//!
+//! An address is set along with its netmask, and the gateway has to be
+//! on that subnet, so changing the address to another one drops the
+//! gateway. The shell command takes an address as `ADDRESS/BITS`:
+//!
+//! ```text
+//! ifconfig eth0 10.0.2.15/24
+//! ifconfig eth0 gateway 10.0.2.2
+//! ifconfig eth0 up
+//! ```
+use super::{with_interface, Address, Interface, Ipv4Addr, MAX_INTERFACES};
+use crate::error::{KernelError, KernelResult};
+use crate::shell::{self, CommandFailed, CommandResult};
+use crate::ui::{Column, Table};
+use core::fmt;
+
+/// Give `name` `address` on the subnet `netmask` says.
+/// `InvalidArgument` if `netmask` isn't one.
+pub fn set_address(name: &str, address: Ipv4Addr, netmask: Ipv4Addr) -> KernelResult<()> {
+    netmask.prefix_len().ok_or(KernelError::InvalidArgument)?;
+    let address = Address { address, netmask };
+    with_interface(name, |interface| {
+        if interface
+            .gateway
+            .map_or(false, |gateway| !address.contains(gateway))
+        {
+            interface.gateway = None;
+        }
+        interface.address = Some(address);
+    })
+}
+
+/// Use `gateway` for anything off the subnet, or nothing if it's
+/// `None`. `InvalidArgument` if it isn't on `name`'s subnet, or `name`
+/// has no address yet.
+pub fn set_gateway(name: &str, gateway: Option<Ipv4Addr>) -> KernelResult<()> {
+    with_interface(name, |interface| match (gateway, interface.address) {
+        (Some(router), Some(address)) if !address.contains(router) => {
+            Err(KernelError::InvalidArgument)
+        }
+        (Some(_), None) => Err(KernelError::InvalidArgument),
+        _ => {
+            interface.gateway = gateway;
+            Ok(())
+        }
+    })?
+}
+
+/// Bring `name` up or down. It keeps its address either way.
+pub fn set_up(name: &str, up: bool) -> KernelResult<()> {
+    with_interface(name, |interface| interface.up = up)
+}
+
+/// Adds the `ifconfig` shell command.
+pub fn init() {
+    shell::register(
+        "ifconfig",
+        "ifconfig [IF [up|down|ADDR/BITS|gateway ADDR|none]]: show or set up interfaces",
+        ifconfig_command,
+    )
+    .expect("ifconfig command");
+}
+
+/// `ADDRESS/BITS`.
+fn parse_address(word: &str) -> Option<(Ipv4Addr, Ipv4Addr)> {
+    let mut parts = word.splitn(2, '/');
+    let address = Ipv4Addr::parse(parts.next()?)?;
+    let netmask = Ipv4Addr::netmask(parts.next()?.parse().ok()?)?;
+    Some((address, netmask))
+}
+
+/// `ADDRESS/BITS`, or `-` for none.
+struct ShownAddress(Option<Address>);
+
+impl fmt::Display for ShownAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(address) => write!(
+                f,
+                "{}/{}",
+                address.address,
+                address.netmask.prefix_len().unwrap_or(0)
+            ),
+            None => f.write_str("-"),
+        }
+    }
+}
+
+fn show(out: &mut dyn fmt::Write, interfaces: &[Option<Interface>]) {
+    const COLUMNS: [Column; 7] = [
+        Column::left("if", 6),
+        Column::left("state", 5),
+        Column::left("address", 18),
+        Column::left("gateway", 15),
+        Column::left("mac", 17),
+        Column::right("rx pkts", 8),
+        Column::right("tx pkts", 8),
+    ];
+    let table = Table::new(&COLUMNS);
+    let _ = table.header(out);
+    for interface in interfaces.iter().flatten() {
+        let state = if interface.up { "up" } else { "down" };
+        let gateway: &dyn fmt::Display = match &interface.gateway {
+            Some(gateway) => gateway,
+            None => &"-",
+        };
+        let _ = table.row(
+            out,
+            &[
+                &interface.name,
+                &state,
+                &ShownAddress(interface.address),
+                gateway,
+                &interface.mac,
+                &interface.stats.rx_packets,
+                &interface.stats.tx_packets,
+            ],
+        );
+    }
+    let _ = table.end(out);
+}
+
+fn ifconfig_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    let mut words = args.split_whitespace();
+    let name = match words.next() {
+        Some(name) => name,
+        None => {
+            let mut interfaces = [None; MAX_INTERFACES];
+            let mut slots = interfaces.iter_mut();
+            super::for_each(|interface| {
+                if let Some(slot) = slots.next() {
+                    *slot = Some(*interface);
+                }
+            });
+            show(out, &interfaces);
+            return Ok(());
+        }
+    };
+    let result = match (words.next(), words.next(), words.next()) {
+        (None, _, _) => super::interface(name).map(|interface| show(out, &[Some(interface)])),
+        (Some("up"), None, _) => set_up(name, true),
+        (Some("down"), None, _) => set_up(name, false),
+        (Some("gateway"), Some("none"), None) => set_gateway(name, None),
+        (Some("gateway"), Some(gateway), None) => match Ipv4Addr::parse(gateway) {
+            Some(gateway) => set_gateway(name, Some(gateway)),
+            None => Err(KernelError::InvalidArgument),
+        },
+        (Some(address), None, _) => match parse_address(address) {
+            Some((address, netmask)) => set_address(name, address, netmask),
+            None => Err(KernelError::InvalidArgument),
+        },
+        _ => Err(KernelError::InvalidArgument),
+    };
+    match result {
+        Ok(()) => Ok(()),
+        Err(KernelError::InvalidArgument) => {
+            let _ = writeln!(
+                out,
+                "usage: ifconfig [IF [up|down|ADDR/BITS|gateway ADDR|none]]"
+            );
+            Err(CommandFailed)
+        }
+        Err(error) => {
+            let _ = writeln!(out, "ifconfig: {}: {}", name, error.as_str());
+            Err(CommandFailed)
+        }
+    }
+}
+
+#[test_case]
+fn test_static_configuration() {
+    const NAME: &str = "test0";
+
+    if super::interface(NAME).is_err() {
+        super::register(NAME, super::MacAddr([0x52, 0x54, 0, 0x12, 0x34, 0x56])).unwrap();
+    }
+    let address = |text| Ipv4Addr::parse(text).unwrap();
+    let (ip, netmask) = parse_address("10.0.2.15/24").unwrap();
+    assert_eq!(
+        set_gateway(NAME, Some(address("10.0.2.2"))),
+        Err(KernelError::InvalidArgument)
+    );
+    set_address(NAME, ip, netmask).unwrap();
+    assert_eq!(
+        set_gateway(NAME, Some(address("10.0.3.2"))),
+        Err(KernelError::InvalidArgument)
+    );
+    set_gateway(NAME, Some(address("10.0.2.2"))).unwrap();
+    set_up(NAME, true).unwrap();
+    super::count_rx(NAME, 60).unwrap();
+    crate::fault_injection::fail_nth(crate::fault_injection::FaultPoint::NetworkPacket, 1);
+    assert_eq!(super::count_rx(NAME, 60), Err(KernelError::DeviceError));
+    let interface = super::interface(NAME).unwrap();
+    assert!(interface.up);
+    assert_eq!(interface.gateway, Some(address("10.0.2.2")));
+    assert_eq!(interface.stats.rx_bytes, 60);
+    assert_eq!(interface.stats.rx_dropped, 1);
+
+    // Somewhere else, so the gateway goes.
+    set_address(NAME, address("192.168.1.5"), netmask).unwrap();
+    assert_eq!(super::interface(NAME).unwrap().gateway, None);
+    assert_eq!(
+        set_address(NAME, ip, address("255.0.255.0")),
+        Err(KernelError::InvalidArgument)
+    );
+    assert_eq!(set_up("nope0", true), Err(KernelError::NotFound));
+    assert_eq!(parse_address("10.0.2.15/33"), None);
+}
@@ -0,0 +1,171 @@
+//! Setting the clock with SNTP, RFC 4330.
+//!
+//! A request to an NTP server on port 123 gets back when the server got
+//! it and when it sent the answer, by its clock. With when we sent and
+//! got them by ours, that's what the time was there when the answer
+//! got here, give or take half the round trip less however long the
+//! server sat on it. That goes to `time::discipline`, which after a
+//! couple of samples `MIN_DRIFT_INTERVAL_NS` apart knows how fast our
+//! clock drifts as well. Once at boot and every `POLL_INTERVAL_S` after
+//! is plenty.
+//!
+//! Like `tftp` there's no driver to get packets to a server, so this is
+//! only the two ends of it: `request` is what to send, and `parse`
+//! makes a `Sample` of what came back. Whoever sends it sends it again
+//! if nothing comes for `TIMEOUT_MS`.
+use crate::error::KernelError;
+use crate::time;
+
+pub const SERVER_PORT: u16 = 123;
+/// What RFC 4330 says a client mustn't poll more often than, roughly.
+pub const POLL_INTERVAL_S: u64 = 1024;
+pub const TIMEOUT_MS: u64 = 1000;
+
+pub const PACKET_SIZE: usize = 48;
+const VERSION: u8 = 4;
+const MODE_CLIENT: u8 = 3;
+const MODE_SERVER: u8 = 4;
+/// The leap indicator saying the server's clock isn't set.
+const LEAP_UNSYNCHRONIZED: u8 = 3;
+/// NTP counts seconds from 1900.
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SntpError {
+    /// Stratum 0, the server telling us to go away with a code like
+    /// `RATE` or `DENY`.
+    KissOfDeath([u8; 4]),
+    /// The server doesn't know the time either.
+    Unsynchronized,
+    /// The server sent something that isn't an NTP answer.
+    Malformed,
+    /// An answer, but to some other request, maybe one sent before.
+    /// Ours might still come.
+    Stale,
+}
+
+impl From<SntpError> for KernelError {
+    fn from(error: SntpError) -> KernelError {
+        match error {
+            SntpError::KissOfDeath(_) => KernelError::PermissionDenied,
+            SntpError::Unsynchronized => KernelError::NotReady,
+            SntpError::Malformed | SntpError::Stale => KernelError::InvalidData,
+        }
+    }
+}
+
+/// What a server said the time was.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample {
+    /// `time::monotonic_ns` when the answer came.
+    pub mono_ns: u64,
+    /// The time then.
+    pub unix_ns: u64,
+    /// The round trip, less the time spent in the server.
+    pub delay_ns: u64,
+    pub stratum: u8,
+}
+
+/// Seconds since 1900 and the fraction of one in 32 bits each.
+fn to_ntp(unix_ns: u64) -> u64 {
+    let seconds = unix_ns / 1_000_000_000 + NTP_UNIX_OFFSET;
+    let fraction = ((unix_ns % 1_000_000_000) << 32) / 1_000_000_000;
+    seconds << 32 | fraction
+}
+
+/// `None` for 0, a timestamp that isn't set, and anything before 1970.
+/// The seconds run out in 2036, after that this will need to know
+/// about NTP eras.
+fn from_ntp(timestamp: u64) -> Option<u64> {
+    let seconds = (timestamp >> 32).checked_sub(NTP_UNIX_OFFSET)?;
+    // Rounded, so what `to_ntp` rounded down comes back the same.
+    let fraction = ((timestamp & 0xFFFF_FFFF) * 1_000_000_000 + (1 << 31)) >> 32;
+    Some(seconds * 1_000_000_000 + fraction)
+}
+
+fn u64_at(packet: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&packet[offset..offset + 8]);
+    u64::from_be_bytes(bytes)
+}
+
+/// A request, sent at `time::unix_time_ns`. That goes in it too,
+/// which is how `parse` knows an answer is to this one.
+pub fn request() -> [u8; PACKET_SIZE] {
+    let mut request = [0; PACKET_SIZE];
+    request[0] = VERSION << 3 | MODE_CLIENT;
+    request[40..48].copy_from_slice(&to_ntp(time::unix_time_ns()).to_be_bytes());
+    request
+}
+
+/// The sample in `reply` to `request`, sent at `start` by
+/// `time::monotonic_ns`, which came back at `end`.
+pub fn parse(
+    request: &[u8; PACKET_SIZE],
+    reply: &[u8],
+    start: u64,
+    end: u64,
+) -> Result<Sample, SntpError> {
+    if reply.len() < PACKET_SIZE || reply[0] & 0b111 != MODE_SERVER {
+        return Err(SntpError::Malformed);
+    }
+    // Our transmit timestamp is copied back as the originate one.
+    if reply[24..32] != request[40..48] {
+        return Err(SntpError::Stale);
+    }
+    let stratum = reply[1];
+    if stratum == 0 {
+        let mut code = [0; 4];
+        code.copy_from_slice(&reply[12..16]);
+        return Err(SntpError::KissOfDeath(code));
+    }
+    if reply[0] >> 6 == LEAP_UNSYNCHRONIZED {
+        return Err(SntpError::Unsynchronized);
+    }
+    let received = from_ntp(u64_at(reply, 32)).ok_or(SntpError::Malformed)?;
+    let transmitted = from_ntp(u64_at(reply, 40)).ok_or(SntpError::Malformed)?;
+    let delay_ns = (end - start).saturating_sub(transmitted.saturating_sub(received));
+    Ok(Sample {
+        mono_ns: end,
+        unix_ns: transmitted + delay_ns / 2,
+        delay_ns,
+        stratum,
+    })
+}
+
+#[test_case]
+fn test_parses_server_time() {
+    /// 2024-01-01 00:00:00.
+    const NOW: u64 = 1_704_067_200 * 1_000_000_000;
+
+    /// What a server that sat on `request` for 1 ms would say.
+    fn reply(request: &[u8; PACKET_SIZE], stratum: u8) -> [u8; PACKET_SIZE] {
+        let mut reply = [0; PACKET_SIZE];
+        reply[0] = VERSION << 3 | MODE_SERVER;
+        reply[1] = stratum;
+        reply[12..16].copy_from_slice(b"RATE");
+        reply[24..32].copy_from_slice(&request[40..48]);
+        reply[32..40].copy_from_slice(&to_ntp(NOW).to_be_bytes());
+        reply[40..48].copy_from_slice(&to_ntp(NOW + 1_000_000).to_be_bytes());
+        reply
+    }
+
+    let request = request();
+    assert_eq!(request[0], VERSION << 3 | MODE_CLIENT);
+    // A 3 ms round trip, 1 ms of it in the server.
+    let sample = parse(&request, &reply(&request, 2), 5_000_000, 8_000_000).unwrap();
+    assert_eq!(sample.stratum, 2);
+    assert_eq!(sample.delay_ns, 2_000_000);
+    assert_eq!(sample.unix_ns, NOW + 2_000_000);
+    assert_eq!(sample.mono_ns, 8_000_000);
+
+    let mut stale = reply(&request, 2);
+    stale[31] ^= 1;
+    assert_eq!(parse(&request, &stale, 0, 0), Err(SntpError::Stale));
+    assert_eq!(
+        parse(&request, &reply(&request, 0), 0, 0),
+        Err(SntpError::KissOfDeath(*b"RATE"))
+    );
+    assert_eq!(parse(&request, &request, 0, 0), Err(SntpError::Malformed));
+    assert_eq!(from_ntp(to_ntp(NOW + 123_456_789)), Some(NOW + 123_456_789));
+}
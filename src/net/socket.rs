@@ -0,0 +1,391 @@
+//! UDP-style datagram sockets, what the socket syscalls hand out.
+//!
+//! A socket is `bind`ed to a local port, or gets one of its own on its
+//! first send, and can be `connect`ed to one peer, after which it only
+//! takes datagrams from there and `send_to` can leave out where to.
+//!
+//! There are no NIC drivers, so datagrams only go to addresses of this
+//! machine: `lo`'s `127.0.0.0/8`, or whatever `ifconfig` gave an
+//! interface that's up. They go straight into the queue of the socket
+//! bound to the port they're for, counted as a packet out and a packet
+//! in on the interface, which `fault_injection` can drop like the wire
+//! would. One for a port nobody's on, or a socket with `MAX_QUEUED`
+//! waiting already, is dropped too, as UDP does. Anywhere else is
+//! `InvalidAddress`, there's no route there.
+//!
+//! `receive_from` blocks the calling thread until a datagram comes, if
+//! it's asked to wait: each socket has a queue of waiting threads, and
+//! whoever queues a datagram wakes all of them.
+//!
+//! Sockets opened for a user program are given to its thread with
+//! `give_to`, and the ones it doesn't close are closed with
+//! `close_thread` when it's done, like its `mmap`s.
+use super::{Ipv4Addr, LOOPBACK};
+use crate::error::{KernelError, KernelResult};
+use crate::latency;
+use crate::scheduler::{self, ThreadId};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+
+/// Open at once, for everyone together.
+pub const MAX_SOCKETS: usize = 16;
+/// Datagrams a socket keeps until they're read.
+pub const MAX_QUEUED: usize = 8;
+/// The most a datagram can carry, what fits in an Ethernet frame.
+pub const MAX_DATAGRAM: usize = 1472;
+/// Threads that can wait on one socket at once.
+const MAX_WAITERS: usize = 4;
+/// Where ports picked for sockets that didn't bind start.
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SocketId(pub u32);
+
+impl fmt::Display for SocketId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// An address and a port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Endpoint {
+    pub address: Ipv4Addr,
+    pub port: u16,
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.address, self.port)
+    }
+}
+
+struct Datagram {
+    from: Endpoint,
+    bytes: Vec<u8>,
+}
+
+struct Socket {
+    /// Set by `give_to`.
+    owner: Option<ThreadId>,
+    local: Option<Endpoint>,
+    peer: Option<Endpoint>,
+    queue: VecDeque<Datagram>,
+    waiters: [Option<ThreadId>; MAX_WAITERS],
+}
+
+impl Socket {
+    /// Whether a datagram from `from` to `to` is this socket's.
+    fn takes(&self, from: Endpoint, to: Endpoint) -> bool {
+        let bound = match self.local {
+            Some(local) => local,
+            None => return false,
+        };
+        bound.port == to.port
+            && (bound.address == Ipv4Addr::UNSPECIFIED || bound.address == to.address)
+            && self.peer.map_or(true, |peer| peer == from)
+    }
+
+    fn wake_all(&mut self) {
+        for waiter in self.waiters.iter_mut() {
+            if let Some(thread) = waiter.take() {
+                scheduler::unblock(thread);
+            }
+        }
+    }
+}
+
+static SOCKETS: Mutex<BTreeMap<SocketId, Socket>> = Mutex::new(BTreeMap::new());
+
+fn with_socket<R>(id: SocketId, f: impl FnOnce(&mut Socket) -> KernelResult<R>) -> KernelResult<R> {
+    latency::without_interrupts(|| {
+        let mut sockets = SOCKETS.lock();
+        f(sockets.get_mut(&id).ok_or(KernelError::NotFound)?)
+    })
+}
+
+/// A new socket, unbound and unconnected. `NoSpace` if there are
+/// `MAX_SOCKETS` already.
+pub fn open() -> KernelResult<SocketId> {
+    latency::without_interrupts(|| {
+        let mut sockets = SOCKETS.lock();
+        if sockets.len() >= MAX_SOCKETS {
+            return Err(KernelError::NoSpace);
+        }
+        // The lowest free one, like file descriptors.
+        let id = (0..)
+            .map(SocketId)
+            .find(|id| !sockets.contains_key(id))
+            .unwrap();
+        sockets.insert(
+            id,
+            Socket {
+                owner: None,
+                local: None,
+                peer: None,
+                queue: VecDeque::new(),
+                waiters: [None; MAX_WAITERS],
+            },
+        );
+        Ok(id)
+    })
+}
+
+/// Close `id`, throwing away what it had queued. Threads waiting on it
+/// get `NotFound`.
+pub fn close(id: SocketId) -> KernelResult<()> {
+    latency::without_interrupts(|| {
+        let mut sockets = SOCKETS.lock();
+        let mut socket = sockets.remove(&id).ok_or(KernelError::NotFound)?;
+        // Empty, it would still have a node on the heap.
+        if sockets.is_empty() {
+            *sockets = BTreeMap::new();
+        }
+        socket.wake_all();
+        Ok(())
+    })
+}
+
+/// Leave `id` to `thread`'s program, for `close_thread` to close.
+pub fn give_to(id: SocketId, thread: ThreadId) -> KernelResult<()> {
+    with_socket(id, |socket| {
+        socket.owner = Some(thread);
+        Ok(())
+    })
+}
+
+/// Who `id` was given to, `None` if it's the kernel's.
+pub fn owner(id: SocketId) -> KernelResult<Option<ThreadId>> {
+    with_socket(id, |socket| Ok(socket.owner))
+}
+
+/// Close what `thread`'s program didn't.
+pub fn close_thread(thread: ThreadId) {
+    let given: Vec<SocketId> = latency::without_interrupts(|| {
+        SOCKETS
+            .lock()
+            .iter()
+            .filter(|(_, socket)| socket.owner == Some(thread))
+            .map(|(&id, _)| id)
+            .collect()
+    });
+    for id in given {
+        let _ = close(id);
+    }
+}
+
+/// The interface datagrams for `address` go to, if it's this machine's.
+fn local_interface(address: Ipv4Addr) -> KernelResult<&'static str> {
+    super::find(|interface| {
+        interface.up
+            && interface.address.map_or(false, |own| {
+                own.address == address || (interface.name == LOOPBACK && own.contains(address))
+            })
+    })
+    .map(|interface| interface.name)
+    .ok_or(KernelError::InvalidAddress)
+}
+
+/// Take `local` for `id`. Port 0 is any free port, the unspecified
+/// address any of this machine's. `InvalidAddress` if the address
+/// isn't one of this machine's, `AlreadyExists` if another socket has
+/// the port, `InvalidArgument` if `id` is bound already.
+pub fn bind(id: SocketId, local: Endpoint) -> KernelResult<Endpoint> {
+    if local.address != Ipv4Addr::UNSPECIFIED {
+        local_interface(local.address)?;
+    }
+    latency::without_interrupts(|| {
+        let mut sockets = SOCKETS.lock();
+        if sockets
+            .get(&id)
+            .ok_or(KernelError::NotFound)?
+            .local
+            .is_some()
+        {
+            return Err(KernelError::InvalidArgument);
+        }
+        let taken = |port: u16| {
+            sockets.values().any(|socket| {
+                socket.local.map_or(false, |bound| {
+                    bound.port == port
+                        && (bound.address == local.address
+                            || bound.address == Ipv4Addr::UNSPECIFIED
+                            || local.address == Ipv4Addr::UNSPECIFIED)
+                })
+            })
+        };
+        let port = match local.port {
+            0 => (FIRST_EPHEMERAL_PORT..=u16::MAX)
+                .find(|&port| !taken(port))
+                .ok_or(KernelError::NoSpace)?,
+            port if taken(port) => return Err(KernelError::AlreadyExists),
+            port => port,
+        };
+        let bound = Endpoint {
+            address: local.address,
+            port,
+        };
+        sockets.get_mut(&id).unwrap().local = Some(bound);
+        Ok(bound)
+    })
+}
+
+/// Send to `peer` when `send_to` isn't told where, and only take
+/// datagrams from there.
+pub fn connect(id: SocketId, peer: Endpoint) -> KernelResult<()> {
+    with_socket(id, |socket| {
+        socket.peer = Some(peer);
+        Ok(())
+    })
+}
+
+/// Send `bytes` as one datagram to `to`, or to the connected peer.
+/// `NotReady` if it's neither, `InvalidArgument` if it's more than
+/// `MAX_DATAGRAM`, `InvalidAddress` if there's no route to it. A
+/// datagram that gets dropped on the way was still sent.
+pub fn send_to(id: SocketId, bytes: &[u8], to: Option<Endpoint>) -> KernelResult<usize> {
+    if bytes.len() > MAX_DATAGRAM {
+        return Err(KernelError::InvalidArgument);
+    }
+    let (local, peer) = with_socket(id, |socket| Ok((socket.local, socket.peer)))?;
+    let to = to.or(peer).ok_or(KernelError::NotReady)?;
+    let interface = local_interface(to.address)?;
+    let local = match local {
+        Some(local) => local,
+        None => bind(
+            id,
+            Endpoint {
+                address: Ipv4Addr::UNSPECIFIED,
+                port: 0,
+            },
+        )?,
+    };
+    // Everything is local, so it's from where it's going.
+    let from = Endpoint {
+        address: if local.address == Ipv4Addr::UNSPECIFIED {
+            to.address
+        } else {
+            local.address
+        },
+        port: local.port,
+    };
+    super::count_tx(interface, bytes.len())?;
+    if super::count_rx(interface, bytes.len()).is_err() {
+        return Ok(bytes.len());
+    }
+    let datagram = Datagram {
+        from,
+        bytes: bytes.into(),
+    };
+    latency::without_interrupts(|| {
+        let mut sockets = SOCKETS.lock();
+        let receiver = sockets.values_mut().find(|socket| socket.takes(from, to));
+        if let Some(receiver) = receiver {
+            if receiver.queue.len() < MAX_QUEUED {
+                receiver.queue.push_back(datagram);
+                receiver.wake_all();
+            }
+        }
+    });
+    Ok(bytes.len())
+}
+
+/// Take the oldest datagram queued for `id` into `buffer`, giving back
+/// how much of it fit, the rest is lost, and who sent it. If there's
+/// none it waits for one if `wait` is set, else it's `WouldBlock`.
+pub fn receive_from(
+    id: SocketId,
+    buffer: &mut [u8],
+    wait: bool,
+) -> KernelResult<(usize, Endpoint)> {
+    let thread = scheduler::current();
+    loop {
+        let datagram = with_socket(id, |socket| {
+            if let Some(datagram) = socket.queue.pop_front() {
+                return Ok(Some(datagram));
+            }
+            if !wait {
+                return Err(KernelError::WouldBlock);
+            }
+            if !socket.waiters.contains(&Some(thread)) {
+                let slot = socket
+                    .waiters
+                    .iter_mut()
+                    .find(|waiter| waiter.is_none())
+                    .ok_or(KernelError::Busy)?;
+                *slot = Some(thread);
+            }
+            Ok(None)
+        })?;
+        match datagram {
+            Some(datagram) => {
+                let len = datagram.bytes.len().min(buffer.len());
+                buffer[..len].copy_from_slice(&datagram.bytes[..len]);
+                return Ok((len, datagram.from));
+            }
+            // A datagram queued since then makes this come straight back.
+            None => scheduler::block(),
+        }
+    }
+}
+
+#[test_case]
+fn test_loopback_datagrams() {
+    let localhost = |port| Endpoint {
+        address: Ipv4Addr([127, 0, 0, 1]),
+        port,
+    };
+    let server = open().unwrap();
+    let client = open().unwrap();
+    assert_eq!(bind(server, localhost(7)), Ok(localhost(7)));
+    let other = open().unwrap();
+    assert_eq!(bind(other, localhost(7)), Err(KernelError::AlreadyExists));
+    let away = Endpoint {
+        address: Ipv4Addr([10, 0, 2, 2]),
+        port: 7,
+    };
+    assert_eq!(bind(other, away), Err(KernelError::InvalidAddress));
+    assert_eq!(
+        send_to(client, b"ping", Some(away)),
+        Err(KernelError::InvalidAddress)
+    );
+    assert_eq!(send_to(client, b"ping", None), Err(KernelError::NotReady));
+
+    let mut buffer = [0; 16];
+    assert_eq!(
+        receive_from(server, &mut buffer, false),
+        Err(KernelError::WouldBlock)
+    );
+    assert_eq!(send_to(client, b"ping", Some(localhost(7))), Ok(4));
+    let (len, from) = receive_from(server, &mut buffer, false).unwrap();
+    assert_eq!(&buffer[..len], b"ping");
+    assert!(from.port >= FIRST_EPHEMERAL_PORT);
+
+    // Connected, it only takes from its peer.
+    connect(server, from).unwrap();
+    send_to(other, b"not you", Some(localhost(7))).unwrap();
+    send_to(client, b"pong", Some(localhost(7))).unwrap();
+    let (len, _) = receive_from(server, &mut buffer, false).unwrap();
+    assert_eq!(&buffer[..len], b"pong");
+    assert_eq!(
+        receive_from(server, &mut buffer, false),
+        Err(KernelError::WouldBlock)
+    );
+
+    // Waiting, for a thread that sends once it gets to run.
+    scheduler::spawn("sender", move || {
+        send_to(client, b"later", Some(localhost(7))).unwrap();
+    });
+    let (len, _) = receive_from(server, &mut buffer, true).unwrap();
+    assert_eq!(&buffer[..len], b"later");
+    while scheduler::ready_count() > 0 {
+        scheduler::yield_now();
+    }
+
+    for &id in &[server, client, other] {
+        close(id).unwrap();
+    }
+    assert_eq!(close(server), Err(KernelError::NotFound));
+}
@@ -0,0 +1,166 @@
+//! Fetching files with TFTP, RFC 1350, the way QEMU's user networking
+//! serves them with `-netdev user,tftp=DIR`.
+//!
+//! A read request goes to port 69, the file comes back in 512 byte
+//! blocks from whatever port the server picked for the transfer, each
+//! one acknowledged before the next is sent, and a short block is the
+//! last.
+//!
+//! There's no driver to get packets to a server, so this is only the
+//! packets and what to do with them: `read_request` is the first one to
+//! send, and `Fetch` takes what comes back and says what to answer.
+//! Whoever sends them sends the last one again if nothing comes for
+//! `TIMEOUT_MS`, and sticks to the port the server's first answer came
+//! from afterwards.
+use crate::error::KernelError;
+use alloc::vec::Vec;
+
+pub const SERVER_PORT: u16 = 69;
+pub const BLOCK_SIZE: usize = 512;
+pub const TIMEOUT_MS: u64 = 1000;
+
+const RRQ: u16 = 1;
+const DATA: u16 = 3;
+const ACK: u16 = 4;
+const ERROR: u16 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TftpError {
+    /// The server said no, e.g. 1 for file not found.
+    Server(u16),
+    /// The server sent something that isn't TFTP.
+    Malformed,
+    /// Bigger than the caller wanted.
+    TooBig,
+}
+
+impl From<TftpError> for KernelError {
+    fn from(error: TftpError) -> KernelError {
+        match error {
+            TftpError::Server(1) => KernelError::NotFound,
+            TftpError::Server(2) => KernelError::PermissionDenied,
+            TftpError::Server(_) => KernelError::DeviceError,
+            TftpError::Malformed => KernelError::InvalidData,
+            TftpError::TooBig => KernelError::NoSpace,
+        }
+    }
+}
+
+fn u16_at(packet: &[u8], offset: usize) -> Option<u16> {
+    let bytes = packet.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// A read request for `name`, in binary mode.
+pub fn read_request(name: &str) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(2 + name.len() + 1 + 6);
+    packet.extend_from_slice(&RRQ.to_be_bytes());
+    packet.extend_from_slice(name.as_bytes());
+    packet.push(0);
+    packet.extend_from_slice(b"octet\0");
+    packet
+}
+
+/// Acknowledges `block`.
+pub fn ack(block: u16) -> [u8; 4] {
+    let [high, low] = block.to_be_bytes();
+    [0, ACK as u8, high, low]
+}
+
+/// What to send back for a packet `Fetch` took.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Next {
+    /// Acknowledge a block and wait for the next one.
+    Ack([u8; 4]),
+    /// Acknowledge the last block. Nothing waits for this one, if it's
+    /// lost the server gives up on its own.
+    Done([u8; 4]),
+}
+
+/// A file coming in, up to `max_size` bytes of it.
+pub struct Fetch {
+    file: Vec<u8>,
+    /// The block we want next.
+    block: u16,
+    max_size: usize,
+}
+
+impl Fetch {
+    pub fn new(max_size: usize) -> Fetch {
+        Fetch {
+            file: Vec::new(),
+            block: 1,
+            max_size,
+        }
+    }
+
+    /// Take a packet from the server.
+    pub fn receive(&mut self, packet: &[u8]) -> Result<Next, TftpError> {
+        match u16_at(packet, 0) {
+            Some(DATA) => {}
+            Some(ERROR) => {
+                return Err(TftpError::Server(
+                    u16_at(packet, 2).ok_or(TftpError::Malformed)?,
+                ))
+            }
+            _ => return Err(TftpError::Malformed),
+        }
+        let number = u16_at(packet, 2).ok_or(TftpError::Malformed)?;
+        if number != self.block {
+            // Our last ack got lost and the server sent the block again,
+            // sending the ack again will do.
+            return Ok(Next::Ack(ack(self.block.wrapping_sub(1))));
+        }
+        let data = &packet[4..];
+        if self.file.len() + data.len() > self.max_size {
+            return Err(TftpError::TooBig);
+        }
+        self.file.extend_from_slice(data);
+        let reply = ack(self.block);
+        self.block = self.block.wrapping_add(1);
+        if data.len() < BLOCK_SIZE {
+            Ok(Next::Done(reply))
+        } else {
+            Ok(Next::Ack(reply))
+        }
+    }
+
+    /// What came, all of it once `receive` has said `Done`.
+    pub fn into_file(self) -> Vec<u8> {
+        self.file
+    }
+}
+
+#[test_case]
+fn test_fetches_in_blocks() {
+    let data = |block: u16, bytes: &[u8]| {
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&DATA.to_be_bytes());
+        packet.extend_from_slice(&block.to_be_bytes());
+        packet.extend_from_slice(bytes);
+        packet
+    };
+    let file: Vec<u8> = (0..1000).map(|index| index as u8).collect();
+    assert!(read_request("hello.elf")[2..].starts_with(b"hello.elf\0octet\0"));
+
+    let mut fetch = Fetch::new(4096);
+    let first = data(1, &file[..BLOCK_SIZE]);
+    assert_eq!(fetch.receive(&first), Ok(Next::Ack(ack(1))));
+    // Our ack got lost, so the block came again.
+    assert_eq!(fetch.receive(&first), Ok(Next::Ack(ack(1))));
+    let last = data(2, &file[BLOCK_SIZE..]);
+    assert_eq!(fetch.receive(&last), Ok(Next::Done(ack(2))));
+    assert_eq!(fetch.into_file(), file);
+
+    let mut fetch = Fetch::new(600);
+    fetch.receive(&first).unwrap();
+    assert_eq!(fetch.receive(&last), Err(TftpError::TooBig));
+    assert_eq!(
+        Fetch::new(4096).receive(b"\0\x05\0\x01File not found\0"),
+        Err(TftpError::Server(1))
+    );
+    assert_eq!(
+        Fetch::new(4096).receive(b"\0\x04\0\x01"),
+        Err(TftpError::Malformed)
+    );
+}
@@ -0,0 +1,222 @@
+//! What to do once a panic has been reported.
+//!
+//! Halting (the default) keeps everything on screen for whoever is
+//! looking at it. Unattended machines are better off rebooting, and
+//! `Dump` also writes a `crash_dump` to serial first, so the log has
+//! more than the panic message to go on.
+//!
+//! Set it with `set`, with the `panicpolicy` command, which a boot
+//! script can run as well, or on the kernel command line, with the
+//! command's words separated by commas: `panic=reboot,10`. `init` reads
+//! that one.
+//!
+//! A panic from inside the panic handler, say from a console lock that
+//! was left held or a `Display` impl that panics, mustn't go through
+//! the same steps again. The handler calls `enter` first, and if it
+//! says there's a panic on already reports it with `report_nested`,
+//! which only writes to the UART, and `give_up`s.
+use crate::cmdline;
+use crate::crash_dump;
+use crate::serial::RawSerial;
+use crate::shell::{self, CommandFailed, CommandResult};
+use crate::time::{self, Duration};
+use crate::unwind::Backtrace;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use x86_64::instructions::port::Port;
+
+/// How long to wait before rebooting wasn't said.
+pub const DEFAULT_REBOOT_DELAY_MS: u64 = 5000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicPolicy {
+    /// Stop and wait for someone to power cycle us.
+    Halt,
+    /// Reboot after waiting this many milliseconds.
+    Reboot(u64),
+    /// Write a crash dump to serial, then reboot after this many
+    /// milliseconds.
+    Dump(u64),
+}
+
+impl fmt::Display for PanicPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PanicPolicy::Halt => write!(f, "halt"),
+            PanicPolicy::Reboot(delay) => write!(f, "reboot after {}", Duration(*delay)),
+            PanicPolicy::Dump(delay) => write!(f, "dump, reboot after {}", Duration(*delay)),
+        }
+    }
+}
+
+// The panic handler reads these, so no lock.
+static KIND: AtomicU8 = AtomicU8::new(0);
+static DELAY_MS: AtomicU64 = AtomicU64::new(0);
+/// Panics so far, the nested ones included.
+static PANICS: AtomicUsize = AtomicUsize::new(0);
+
+/// Adds the `panicpolicy` shell command and sets the policy from
+/// `panic=` on the kernel command line.
+pub fn init() {
+    shell::register(
+        "panicpolicy",
+        "panicpolicy [halt|reboot [SECS]|dump [SECS]]: show or set what panics do",
+        panicpolicy_command,
+    )
+    .expect("panicpolicy command");
+    if let Some(value) = cmdline::value("panic") {
+        match parse(value.split(',')) {
+            Some(policy) => set(policy),
+            None => crate::klog!(
+                Warn,
+                "panic={}: want halt, reboot[,SECS] or dump[,SECS]",
+                value
+            ),
+        }
+    }
+}
+
+/// `halt`, `reboot [SECS]` or `dump [SECS]`, one word at a time.
+fn parse<'a>(mut words: impl Iterator<Item = &'a str>) -> Option<PanicPolicy> {
+    let delay = |word: Option<&str>| match word {
+        None => Some(DEFAULT_REBOOT_DELAY_MS),
+        Some(word) => word.parse().ok().map(|seconds: u64| seconds * 1000),
+    };
+    match words.next()? {
+        "halt" => Some(PanicPolicy::Halt),
+        "reboot" => delay(words.next()).map(PanicPolicy::Reboot),
+        "dump" => delay(words.next()).map(PanicPolicy::Dump),
+        _ => None,
+    }
+}
+
+pub fn set(policy: PanicPolicy) {
+    let (kind, delay) = match policy {
+        PanicPolicy::Halt => (0, 0),
+        PanicPolicy::Reboot(delay) => (1, delay),
+        PanicPolicy::Dump(delay) => (2, delay),
+    };
+    DELAY_MS.store(delay, Ordering::SeqCst);
+    KIND.store(kind, Ordering::SeqCst);
+}
+
+pub fn get() -> PanicPolicy {
+    let delay = DELAY_MS.load(Ordering::SeqCst);
+    match KIND.load(Ordering::SeqCst) {
+        1 => PanicPolicy::Reboot(delay),
+        2 => PanicPolicy::Dump(delay),
+        _ => PanicPolicy::Halt,
+    }
+}
+
+/// Called first thing by the panic handler. `false` if a panic is
+/// being handled already, and this one came out of handling it.
+pub fn enter() -> bool {
+    PANICS.fetch_add(1, Ordering::SeqCst) == 0
+}
+
+/// Report a panic from inside the panic handler, with nothing the
+/// first one could have broken: no locks, no backtrace. If printing
+/// the message panicked too only say that, and after that nothing.
+pub fn report_nested(info: &PanicInfo) {
+    let mut serial = RawSerial::new();
+    match PANICS.load(Ordering::SeqCst) {
+        0..=2 => {
+            let _ = writeln!(serial, "\npanicked while panicking: {}", info);
+        }
+        3 => {
+            let _ = serial.write_str("\npanicked while panicking, again\n");
+        }
+        _ => {}
+    }
+}
+
+/// Halt or reboot as the policy says, straight away: waiting needs the
+/// timer and a dump needs the unwinder, either could be what panicked.
+pub fn give_up() -> ! {
+    x86_64::instructions::interrupts::disable();
+    match get() {
+        PanicPolicy::Halt => halt(),
+        PanicPolicy::Reboot(_) | PanicPolicy::Dump(_) => reboot(),
+    }
+}
+
+/// Called by the panic handler once the panic has been printed to
+/// `out`. Interrupts have to be off already.
+pub fn finish(out: &mut dyn Write, info: &PanicInfo, backtrace: &Backtrace) -> ! {
+    let delay = match get() {
+        PanicPolicy::Halt => halt(),
+        PanicPolicy::Reboot(delay) => delay,
+        PanicPolicy::Dump(delay) => {
+            let _ = crash_dump::write(out, Some(info), backtrace);
+            delay
+        }
+    };
+    let _ = writeln!(out, "rebooting in {}", Duration(delay));
+    // Without a PIT to time it we reboot straight away, which still
+    // beats sitting there.
+    let _ = time::spin_ms(delay);
+    reboot()
+}
+
+fn halt() -> ! {
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Reset the machine through the keyboard controller, or by triple
+/// faulting if that doesn't work.
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+    unsafe {
+        Port::<u8>::new(0x64).write(0xFE);
+    }
+    // With an empty IDT the breakpoint below can't be delivered,
+    // neither can the double fault that follows.
+    let empty = x86_64::structures::DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe {
+        x86_64::instructions::tables::lidt(&empty);
+    }
+    x86_64::instructions::interrupts::int3();
+    halt()
+}
+
+fn panicpolicy_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    if args.is_empty() {
+        let _ = writeln!(out, "panics: {}", get());
+        return Ok(());
+    }
+    match parse(args.split_whitespace()) {
+        Some(policy) => {
+            set(policy);
+            let _ = writeln!(out, "panics: {}", policy);
+            Ok(())
+        }
+        None => {
+            let _ = writeln!(out, "usage: panicpolicy [halt|reboot [SECS]|dump [SECS]]");
+            Err(CommandFailed)
+        }
+    }
+}
+
+#[test_case]
+fn test_set_and_get() {
+    set(PanicPolicy::Dump(2000));
+    assert_eq!(get(), PanicPolicy::Dump(2000));
+    set(PanicPolicy::Halt);
+    assert_eq!(get(), PanicPolicy::Halt);
+}
+
+#[test_case]
+fn test_parse() {
+    assert_eq!(parse("halt".split(',')), Some(PanicPolicy::Halt));
+    assert_eq!(
+        parse("reboot".split(',')),
+        Some(PanicPolicy::Reboot(DEFAULT_REBOOT_DELAY_MS))
+    );
+    assert_eq!(parse("dump,10".split(',')), Some(PanicPolicy::Dump(10_000)));
+    assert_eq!(parse("reboot,soon".split(',')), None);
+    assert_eq!(parse("explode".split(',')), None);
+}
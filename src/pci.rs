@@ -0,0 +1,424 @@
+//! PCI devices, found through the legacy configuration ports.
+//!
+//! Writing a bus/device/function/register address to `0xCF8` makes
+//! that register readable at `0xCFC`. Every function answers to that,
+//! so there is no need to keep a list around: `devices` just asks
+//! every possible address and skips those reading back all ones.
+//! Drivers look for theirs with `find` by vendor and device ID, or
+//! filter `devices` by class.
+//!
+//! `Device::bar` says where a function's registers are and how big
+//! they are. The size is what's left of the BAR after writing all ones
+//! to it, which has the function's decoding turned off for a moment,
+//! so nothing else should be using it then.
+use crate::latency;
+use crate::ui::{Column, Table};
+use core::fmt;
+use x86_64::instructions::port::Port;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+/// The command register, in the low half, and the status register,
+/// whose bits are cleared by writing ones, in the high half.
+const COMMAND: u8 = 0x04;
+const COMMAND_IO: u32 = 1 << 0;
+const COMMAND_MEMORY: u32 = 1 << 1;
+const FIRST_BAR: u8 = 0x10;
+
+/// Where a function lives on the bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Location {
+    /// Read the 32 bit register at `offset` (rounded down to 4) of
+    /// this function's configuration space.
+    pub fn read_config(self, offset: u8) -> u32 {
+        let address = self.config_address(offset);
+        // Address and data have to be used as a pair, don't let an
+        // interrupt handler get in between.
+        latency::without_interrupts(|| unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(address);
+            Port::<u32>::new(CONFIG_DATA).read()
+        })
+    }
+
+    /// Write the 32 bit register at `offset` (rounded down to 4).
+    ///
+    /// # Safety
+    ///
+    /// Configuration space decides where the function's registers are
+    /// and whether it can do DMA, among other things.
+    pub unsafe fn write_config(self, offset: u8, value: u32) {
+        let address = self.config_address(offset);
+        latency::without_interrupts(|| {
+            Port::<u32>::new(CONFIG_ADDRESS).write(address);
+            Port::<u32>::new(CONFIG_DATA).write(value);
+        })
+    }
+
+    fn config_address(self, offset: u8) -> u32 {
+        1 << 31
+            | u32::from(self.bus) << 16
+            | u32::from(self.device) << 11
+            | u32::from(self.function) << 8
+            | u32::from(offset & 0xFC)
+    }
+
+    /// The one after this in the order `devices` goes in: the next
+    /// function if there might be one, or else the next device.
+    fn next(self, multifunction: bool) -> Option<Location> {
+        if multifunction && self.function < 7 {
+            Some(Location {
+                function: self.function + 1,
+                ..self
+            })
+        } else if self.device < 31 {
+            Some(Location {
+                bus: self.bus,
+                device: self.device + 1,
+                function: 0,
+            })
+        } else if self.bus < 255 {
+            Some(Location {
+                bus: self.bus + 1,
+                device: 0,
+                function: 0,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// Where a base address register says a function's registers are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        /// Takes up this BAR and the next one.
+        wide: bool,
+    },
+    Io {
+        port: u16,
+        size: u16,
+    },
+}
+
+impl fmt::Display for Bar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Bar::Memory {
+                address,
+                size,
+                prefetchable,
+                wide,
+            } => {
+                write!(f, "memory at {:#x}, {} bytes", address, size)?;
+                if wide {
+                    write!(f, ", 64 bit")?;
+                }
+                if prefetchable {
+                    write!(f, ", prefetchable")?;
+                }
+                Ok(())
+            }
+            Bar::Io { port, size } => write!(f, "ports at {:#x}, {} bytes", port, size),
+        }
+    }
+}
+
+/// The identifying parts of a function's configuration header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Device {
+    pub location: Location,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub header_type: u8,
+}
+
+impl Device {
+    fn read(location: Location) -> Option<Device> {
+        let id = location.read_config(0x00);
+        // Nothing there reads back as all ones.
+        if id & 0xFFFF == 0xFFFF {
+            return None;
+        }
+        let class = location.read_config(0x08);
+        let header = location.read_config(0x0C);
+        Some(Device {
+            location,
+            vendor_id: id as u16,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            header_type: (header >> 16) as u8,
+        })
+    }
+
+    /// Whether functions other than 0 need looking at.
+    fn is_multifunction(&self) -> bool {
+        self.header_type & 0x80 != 0
+    }
+
+    /// How many BARs its kind of header has.
+    fn bar_count(&self) -> u8 {
+        match self.header_type & 0x7F {
+            0 => 6,
+            // PCI to PCI bridge
+            1 => 2,
+            _ => 0,
+        }
+    }
+
+    /// Read the BAR register at `offset`, and what sticks of all ones
+    /// written to it.
+    ///
+    /// # Safety
+    ///
+    /// Decoding has to be off.
+    unsafe fn probe_bar(&self, offset: u8) -> (u32, u32) {
+        let original = self.location.read_config(offset);
+        self.location.write_config(offset, !0);
+        let mask = self.location.read_config(offset);
+        self.location.write_config(offset, original);
+        (original, mask)
+    }
+
+    /// BAR `index` and how big it is. `None` if it's past the last one,
+    /// not in use, or the top half of the one before it.
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        if index >= self.bar_count() {
+            return None;
+        }
+        let mut at = 0;
+        while at < index {
+            at += if self.is_wide_bar(at) { 2 } else { 1 };
+        }
+        if at != index {
+            return None;
+        }
+        let offset = FIRST_BAR + index * 4;
+        let location = self.location;
+        latency::without_interrupts(|| unsafe {
+            // Only the command half, writing the status bits back
+            // would clear them.
+            let command = location.read_config(COMMAND) & 0xFFFF;
+            location.write_config(COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY));
+            let (low, low_mask) = self.probe_bar(offset);
+            let wide = low & 1 == 0 && (low >> 1) & 3 == 2 && index + 1 < self.bar_count();
+            let (high, high_mask) = if wide {
+                self.probe_bar(offset + 4)
+            } else {
+                (0, !0)
+            };
+            location.write_config(COMMAND, command);
+
+            if low & 1 != 0 {
+                // Ports only go up to 16 bits, the top can read back
+                // as zeros.
+                let mask = low_mask & !3 | 0xFFFF_0000;
+                if low_mask & !3 == 0 {
+                    return None;
+                }
+                return Some(Bar::Io {
+                    port: (low & !3) as u16,
+                    size: (!mask).wrapping_add(1) as u16,
+                });
+            }
+            let mask = u64::from(high_mask) << 32 | u64::from(low_mask & !0xF);
+            if low_mask & !0xF == 0 && (!wide || high_mask == 0) {
+                return None;
+            }
+            Some(Bar::Memory {
+                address: u64::from(high) << 32 | u64::from(low & !0xF),
+                size: (!mask).wrapping_add(1),
+                prefetchable: low & 0x8 != 0,
+                wide,
+            })
+        })
+    }
+
+    /// Every BAR in use, with its index.
+    pub fn bars(&self) -> impl Iterator<Item = (u8, Bar)> + '_ {
+        (0..self.bar_count()).filter_map(move |index| self.bar(index).map(|bar| (index, bar)))
+    }
+
+    /// Whether BAR `index` is a 64 bit memory one, going by its type
+    /// bits.
+    fn is_wide_bar(&self, index: u8) -> bool {
+        let bar = self.location.read_config(FIRST_BAR + index * 4);
+        bar & 1 == 0 && (bar >> 1) & 3 == 2
+    }
+
+    /// A rough name for the class, for listings.
+    pub fn class_name(&self) -> &'static str {
+        match (self.class, self.subclass) {
+            (0x01, 0x01) => "IDE controller",
+            (0x01, 0x06) => "SATA controller",
+            (0x01, 0x08) => "NVMe controller",
+            (0x01, _) => "storage controller",
+            (0x02, 0x00) => "ethernet controller",
+            (0x02, _) => "network controller",
+            (0x03, _) => "display controller",
+            (0x04, _) => "multimedia controller",
+            (0x06, 0x00) => "host bridge",
+            (0x06, 0x01) => "ISA bridge",
+            (0x06, 0x04) => "PCI bridge",
+            (0x06, _) => "bridge",
+            (0x0C, 0x03) => "USB controller",
+            (0x0C, 0x05) => "SMBus controller",
+            (0x0C, _) => "serial bus controller",
+            _ => "device",
+        }
+    }
+}
+
+/// Adds the `lspci` shell command.
+pub fn init() {
+    crate::shell::register(
+        "lspci",
+        "lspci [-v]: list PCI devices, with their BARs",
+        lspci_command,
+    )
+    .expect("lspci command");
+}
+
+/// Every PCI function there is, bus by bus.
+pub struct Devices {
+    next: Option<Location>,
+    /// Whether function 0 of the device it's on said there are more.
+    multifunction: bool,
+}
+
+impl Iterator for Devices {
+    type Item = Device;
+
+    fn next(&mut self) -> Option<Device> {
+        while let Some(location) = self.next {
+            let device = Device::read(location);
+            if location.function == 0 {
+                self.multifunction = device.map_or(false, |device| device.is_multifunction());
+            }
+            self.next = location.next(self.multifunction);
+            if device.is_some() {
+                return device;
+            }
+        }
+        None
+    }
+}
+
+pub fn devices() -> Devices {
+    Devices {
+        next: Some(Location {
+            bus: 0,
+            device: 0,
+            function: 0,
+        }),
+        multifunction: false,
+    }
+}
+
+/// Call `f` with every PCI function there is.
+pub fn for_each_device(mut f: impl FnMut(&Device)) {
+    for device in devices() {
+        f(&device);
+    }
+}
+
+/// The first function with this vendor and device ID.
+pub fn find(vendor_id: u16, device_id: u16) -> Option<Device> {
+    devices().find(|device| device.vendor_id == vendor_id && device.device_id == device_id)
+}
+
+fn lspci_command(out: &mut dyn fmt::Write, args: &str) -> crate::shell::CommandResult {
+    let verbose = match args {
+        "" => false,
+        "-v" => true,
+        _ => {
+            let _ = writeln!(out, "usage: lspci [-v]");
+            return Err(crate::shell::CommandFailed);
+        }
+    };
+    const COLUMNS: [Column; 4] = [
+        Column::left("slot", 7),
+        Column::left("id", 9),
+        Column::left("class", 6),
+        Column::left("kind", 22),
+    ];
+    let table = Table::new(&COLUMNS);
+    let _ = table.header(out);
+    for_each_device(|device| {
+        let _ = table.row(
+            out,
+            &[
+                &device.location,
+                &format_args!("{:04x}:{:04x}", device.vendor_id, device.device_id),
+                &format_args!(
+                    "{:02x}{:02x}{:02x}",
+                    device.class, device.subclass, device.prog_if
+                ),
+                &device.class_name(),
+            ],
+        );
+        if verbose {
+            for (index, bar) in device.bars() {
+                let _ = table.row(out, &[&"", &format_args!("BAR{}", index), &"", &bar]);
+            }
+        }
+    });
+    let _ = table.end(out);
+    Ok(())
+}
+
+#[test_case]
+fn test_finds_host_bridge() {
+    // QEMU always has one at 00:00.0
+    let mut host_bridge = None;
+    for_each_device(|device| {
+        if device.location.bus == 0 && device.location.device == 0 {
+            host_bridge = Some(*device);
+        }
+    });
+    let host_bridge = host_bridge.expect("no device at 00:00.0");
+    assert_eq!((host_bridge.class, host_bridge.subclass), (0x06, 0x00));
+}
+
+#[test_case]
+fn test_bars() {
+    let mut count = 0;
+    for_each_device(|_| count += 1);
+    assert_eq!(devices().count(), count);
+    // QEMU's standard VGA, a 16 MiB framebuffer.
+    let vga = match find(0x1234, 0x1111) {
+        Some(vga) => vga,
+        None => return,
+    };
+    let framebuffer = vga.bar(0);
+    match framebuffer {
+        Some(Bar::Memory {
+            size, prefetchable, ..
+        }) => assert!(size == 16 << 20 && prefetchable),
+        other => panic!("BAR0 is {:?}", other),
+    }
+    // Sizing it put it back.
+    assert_eq!(vga.bar(0), framebuffer);
+    assert_eq!(vga.bar(6), None);
+}
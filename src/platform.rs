@@ -0,0 +1,183 @@
+//! What we're running on, and how to stop running.
+//!
+//! `exit` is how a test run ends, and it has to end on real hardware
+//! as well as under QEMU. So it tries, in turn:
+//!
+//! - the isa-debug-exit device, if this is QEMU. It's only there if
+//!   QEMU was started with it (see `test-args` in Cargo.toml), and then
+//!   QEMU exits with `status` straight away. Writing to it does nothing
+//!   otherwise.
+//! - ACPI soft off, see `acpi`, which works on most machines and under
+//!   QEMU without the device too, but loses the status.
+//! - halting, if neither did anything.
+//!
+//! What it's running on is found out when asked, from the firmware
+//! config device and CPUID, rather than built in. Which hypervisor it
+//! is comes from the signature in CPUID leaf `0x4000_0000`, under KVM
+//! `kvm` uses what it offers.
+use crate::acpi;
+use crate::serial::RawSerial;
+use crate::shell::{self, CommandFailed, CommandResult};
+use core::arch::x86_64::__cpuid;
+use core::fmt::{self, Write};
+use spin::Once;
+use x86_64::instructions::port::Port;
+
+/// Where `test-args` puts the isa-debug-exit device.
+const DEBUG_EXIT_PORT: u16 = 0xf4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    Qemu,
+    /// Some other virtual machine.
+    Hypervisor,
+    BareMetal,
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Platform::Qemu => write!(f, "qemu"),
+            Platform::Hypervisor => write!(f, "virtual machine"),
+            Platform::BareMetal => write!(f, "bare metal"),
+        }
+    }
+}
+
+/// Leaf the hypervisor leaves start at.
+pub const HYPERVISOR_LEAF: u32 = 0x4000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hypervisor {
+    Kvm,
+    /// QEMU on its own, without KVM.
+    Tcg,
+    VMware,
+    HyperV,
+    Xen,
+    Other([u8; 12]),
+}
+
+impl Hypervisor {
+    pub fn from_signature(signature: [u8; 12]) -> Hypervisor {
+        match &signature {
+            b"KVMKVMKVM\0\0\0" => Hypervisor::Kvm,
+            b"TCGTCGTCGTCG" => Hypervisor::Tcg,
+            b"VMwareVMware" => Hypervisor::VMware,
+            b"Microsoft Hv" => Hypervisor::HyperV,
+            b"XenVMMXenVMM" => Hypervisor::Xen,
+            _ => Hypervisor::Other(signature),
+        }
+    }
+}
+
+impl fmt::Display for Hypervisor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Hypervisor::Kvm => write!(f, "kvm"),
+            Hypervisor::Tcg => write!(f, "qemu tcg"),
+            Hypervisor::VMware => write!(f, "vmware"),
+            Hypervisor::HyperV => write!(f, "hyper-v"),
+            Hypervisor::Xen => write!(f, "xen"),
+            Hypervisor::Other(signature) => {
+                let end = signature.iter().position(|&byte| byte == 0);
+                let name = &signature[..end.unwrap_or(signature.len())];
+                write!(f, "{}", core::str::from_utf8(name).unwrap_or("unknown"))
+            }
+        }
+    }
+}
+
+/// The hypervisor we're running under, and the highest hypervisor leaf
+/// it has. `None` on bare metal.
+pub fn hypervisor() -> Option<(Hypervisor, u32)> {
+    if unsafe { __cpuid(1) }.ecx & 1 << 31 == 0 {
+        return None;
+    }
+    let leaf = unsafe { __cpuid(HYPERVISOR_LEAF) };
+    let mut signature = [0; 12];
+    signature[..4].copy_from_slice(&leaf.ebx.to_le_bytes());
+    signature[4..8].copy_from_slice(&leaf.ecx.to_le_bytes());
+    signature[8..].copy_from_slice(&leaf.edx.to_le_bytes());
+    Some((Hypervisor::from_signature(signature), leaf.eax))
+}
+
+static PLATFORM: Once<Platform> = Once::new();
+
+fn detect() -> Platform {
+    if crate::cmdline::has_fw_cfg() {
+        Platform::Qemu
+    } else if hypervisor().is_some() {
+        Platform::Hypervisor
+    } else {
+        Platform::BareMetal
+    }
+}
+
+pub fn get() -> Platform {
+    *PLATFORM.call_once(detect)
+}
+
+/// How a run ended. The values are what QEMU gets, it exits with
+/// `(value << 1) | 1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ExitStatus {
+    Success = 0x10,
+    Failed = 0x11,
+    /// A test hung and was stopped by the watchdog
+    Timeout = 0x12,
+}
+
+/// Stop the machine, with `status` if there's anyone to tell.
+pub fn exit(status: ExitStatus) -> ! {
+    x86_64::instructions::interrupts::disable();
+    if get() == Platform::Qemu {
+        unsafe { Port::<u32>::new(DEBUG_EXIT_PORT).write(status as u32) };
+    }
+    let error = acpi::shutdown();
+    // This could be the end of a panic, don't wait for the lock.
+    let _ = writeln!(
+        RawSerial::new(),
+        "can't turn off ({}), halting: {:?}",
+        error,
+        status
+    );
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Adds the `poweroff` shell command.
+pub fn init() {
+    shell::register(
+        "poweroff",
+        "poweroff: turn the machine off",
+        poweroff_command,
+    )
+    .expect("poweroff command");
+}
+
+fn poweroff_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    if !args.is_empty() {
+        let _ = writeln!(out, "usage: poweroff");
+        return Err(CommandFailed);
+    }
+    let _ = writeln!(out, "turning off {}", get());
+    exit(ExitStatus::Success)
+}
+
+#[test_case]
+fn test_detected_qemu() {
+    // The tests only run under QEMU.
+    assert_eq!(get(), Platform::Qemu);
+    assert_eq!(get(), detect());
+    // `test-success-exit-code` in Cargo.toml.
+    assert_eq!((ExitStatus::Success as u32) << 1 | 1, 33);
+    assert_eq!(
+        Hypervisor::from_signature(*b"KVMKVMKVM\0\0\0"),
+        Hypervisor::Kvm
+    );
+    let other = Hypervisor::from_signature(*b"bhyve bhyve ");
+    assert_eq!(other, Hypervisor::Other(*b"bhyve bhyve "));
+}
@@ -0,0 +1,302 @@
+//! Sampling profiler driven by the timer interrupt.
+//!
+//! While running, every `every`th timer tick records where the
+//! interrupted code was (`rip`) and a few of its callers. `report`
+//! then adds the samples up by function: "self" counts samples taken
+//! in the function itself, "total" also counts the ones taken in
+//! anything it called.
+//!
+//! The timer still runs at the PIT default of ~18 Hz, so it takes a
+//! while to collect a useful number of samples.
+use crate::ksyms::{self, Demangle};
+use crate::shell::{parse_number, CommandFailed, CommandResult};
+use crate::unwind;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::structures::idt::InterruptStackFrame;
+
+/// Samples kept, later ones are dropped once this is full.
+pub const MAX_SAMPLES: usize = 2048;
+/// Callers kept per sample, besides `rip` itself.
+pub const STACK_DEPTH: usize = 4;
+/// Distinct functions `report` can tell apart, the rest are lumped
+/// together as `<other>`.
+const REPORT_SYMBOLS: usize = 64;
+
+/// Where the CPU was when the timer went off.
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub rip: u64,
+    /// Return addresses of the interrupted code, innermost first.
+    /// Unused slots are 0.
+    pub stack: [u64; STACK_DEPTH],
+}
+
+struct Samples {
+    samples: [Sample; MAX_SAMPLES],
+    len: usize,
+}
+
+static SAMPLES: Mutex<Samples> = Mutex::new(Samples {
+    samples: [Sample {
+        rip: 0,
+        stack: [0; STACK_DEPTH],
+    }; MAX_SAMPLES],
+    len: 0,
+});
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+/// Sample one in this many ticks.
+static EVERY: AtomicU64 = AtomicU64::new(1);
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// Samples we couldn't store, either as the buffer was full or busy.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Add the `prof` shell command.
+pub fn init() {
+    crate::shell::register(
+        "prof",
+        "report, or `start [N]` sampling every Nth tick, or `stop`",
+        prof_command,
+    )
+    .expect("prof command");
+}
+
+fn prof_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    let mut words = args.split_whitespace();
+    match words.next() {
+        None => {
+            let _ = report(out);
+        }
+        Some("start") => start(words.next().and_then(parse_number).unwrap_or(1)),
+        Some("stop") => stop(),
+        Some(_) => {
+            let _ = writeln!(out, "usage: prof [start [N]|stop]");
+            return Err(CommandFailed);
+        }
+    }
+    Ok(())
+}
+
+/// Throw away old samples and start taking one every `every` ticks.
+pub fn start(every: u64) {
+    stop();
+    crate::latency::without_interrupts(|| SAMPLES.lock().len = 0);
+    DROPPED.store(0, Ordering::SeqCst);
+    TICKS.store(0, Ordering::SeqCst);
+    EVERY.store(every.max(1), Ordering::SeqCst);
+    RUNNING.store(true, Ordering::SeqCst);
+}
+
+pub fn stop() {
+    RUNNING.store(false, Ordering::SeqCst);
+}
+
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::SeqCst)
+}
+
+/// How many samples there are so far.
+pub fn sample_count() -> usize {
+    SAMPLES.try_lock().map_or(0, |samples| samples.len)
+}
+
+/// Called from the timer interrupt handler on every tick.
+pub fn tick(stack_frame: &InterruptStackFrame) {
+    if !is_running() {
+        return;
+    }
+    if TICKS.fetch_add(1, Ordering::Relaxed) % EVERY.load(Ordering::Relaxed) != 0 {
+        return;
+    }
+
+    let rip = stack_frame.instruction_pointer.as_u64();
+    let mut stack = [0; STACK_DEPTH];
+    let mut depth = 0;
+    let mut found = false;
+    // The chain starts in the interrupt handler. The handler's own frame
+    // sits right on top of the interrupt stack frame, so its "return
+    // address" is `rip`, everything after that is the interrupted code.
+    unsafe {
+        unwind::walk(unwind::current_frame_pointer(), |address| {
+            if found {
+                stack[depth] = address;
+                depth += 1;
+            }
+            found = found || address == rip;
+            depth < STACK_DEPTH
+        });
+    }
+
+    // Interrupted code might hold the lock if it was reading samples.
+    match SAMPLES.try_lock() {
+        Some(mut samples) if samples.len < MAX_SAMPLES => {
+            let len = samples.len;
+            samples.samples[len] = Sample { rip, stack };
+            samples.len += 1;
+        }
+        _ => {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Name of the function containing a code address.
+fn function(address: u64) -> Option<&'static str> {
+    ksyms::lookup(address).map(|(name, _)| name)
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    name: Option<&'static str>,
+    self_samples: usize,
+    total_samples: usize,
+}
+
+/// `samples` added up by function.
+struct Tally {
+    entries: [Entry; REPORT_SYMBOLS],
+    used: usize,
+    /// Samples taken in functions there was no room for.
+    other: usize,
+}
+
+fn add_up(samples: &[Sample]) -> Tally {
+    let mut entries = [Entry {
+        name: None,
+        self_samples: 0,
+        total_samples: 0,
+    }; REPORT_SYMBOLS];
+    let mut used = 0;
+    let mut other = 0;
+
+    for sample in samples {
+        // The return addresses point past the call, look up the byte
+        // before them like the backtraces do.
+        let callers = sample.stack.iter().filter(|&&address| address != 0);
+        let addresses = Some(sample.rip).into_iter().chain(callers.map(|a| a - 1));
+        // Names counted for this sample so far, `None` being unknown.
+        let mut counted: [Option<&str>; STACK_DEPTH + 1] = [None; STACK_DEPTH + 1];
+        let mut seen = 0;
+
+        for (index, address) in addresses.enumerate() {
+            let name = function(address);
+            // Recursion shouldn't count a function twice in one sample.
+            if counted[..seen].contains(&name) {
+                continue;
+            }
+            counted[seen] = name;
+            seen += 1;
+
+            let entry = match entries[..used].iter().position(|e| e.name == name) {
+                Some(position) => &mut entries[position],
+                None if used < REPORT_SYMBOLS => {
+                    entries[used].name = name;
+                    used += 1;
+                    &mut entries[used - 1]
+                }
+                None => {
+                    if index == 0 {
+                        other += 1;
+                    }
+                    continue;
+                }
+            };
+            entry.total_samples += 1;
+            if index == 0 {
+                entry.self_samples += 1;
+            }
+        }
+    }
+    Tally {
+        entries,
+        used,
+        other,
+    }
+}
+
+/// Print the samples taken so far, added up by function, busiest first.
+pub fn report(out: &mut dyn fmt::Write) -> fmt::Result {
+    let guard = match SAMPLES.try_lock() {
+        Some(guard) => guard,
+        None => return writeln!(out, "samples are busy, try again"),
+    };
+    let samples = &guard.samples[..guard.len];
+    let Tally {
+        mut entries,
+        used,
+        other,
+    } = add_up(samples);
+
+    let entries = &mut entries[..used];
+    entries.sort_unstable_by(|a, b| b.self_samples.cmp(&a.self_samples));
+
+    let total = samples.len().max(1);
+    writeln!(
+        out,
+        "{} samples, {} dropped\n  self%  total%  function",
+        samples.len(),
+        DROPPED.load(Ordering::Relaxed)
+    )?;
+    for entry in entries.iter() {
+        write!(
+            out,
+            "  {:>5}  {:>6}  ",
+            entry.self_samples * 100 / total,
+            entry.total_samples * 100 / total
+        )?;
+        match entry.name {
+            Some(name) => writeln!(out, "{}", Demangle(name))?,
+            None => writeln!(out, "<unknown>")?,
+        }
+    }
+    if other > 0 {
+        writeln!(out, "  {:>5}          <other>", other * 100 / total)?;
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_profiler_takes_samples() {
+    start(1);
+    while sample_count() < 2 {
+        x86_64::instructions::hlt();
+    }
+    stop();
+    let taken = sample_count();
+    x86_64::instructions::hlt();
+    assert_eq!(sample_count(), taken);
+}
+
+#[test_case]
+fn test_add_up_counts_each_function_once_a_sample() {
+    let resolve = ksyms::resolve as usize as u64;
+    let lookup = ksyms::lookup as usize as u64;
+    // The test kernel reads the symbol table before running tests.
+    assert!(function(resolve).is_some() && function(lookup).is_some());
+    // Nothing's down at 0x10, those frames are unknown.
+    let samples = [
+        Sample {
+            rip: resolve,
+            stack: [resolve + 1, 0x11, 0, 0],
+        },
+        Sample {
+            rip: 0x10,
+            stack: [0x11, lookup + 1, 0, 0],
+        },
+    ];
+    let tally = add_up(&samples);
+    assert_eq!(tally.used, 3);
+    assert_eq!(tally.other, 0);
+    let counts = |name| {
+        let entry = tally.entries[..tally.used]
+            .iter()
+            .find(|entry| entry.name == name)
+            .unwrap();
+        (entry.self_samples, entry.total_samples)
+    };
+    assert_eq!(counts(function(resolve)), (1, 1));
+    assert_eq!(counts(None), (1, 2));
+    assert_eq!(counts(function(lookup)), (0, 1));
+}
@@ -0,0 +1,620 @@
+//! Loading user programs from ELF executables.
+//!
+//! Both kinds a static Rust program comes out as work:
+//!
+//! - `ET_EXEC`, linked to run at a fixed address, is copied there as is.
+//! - `ET_DYN` without a `PT_INTERP`, a static PIE (what the
+//!   `x86_64-unknown-linux-musl` target and friends build by default),
+//!   gets a random load base. The `R_X86_64_RELATIVE` relocations in
+//!   its dynamic section are then applied, since it has no startup code
+//!   of its own to do it.
+//!
+//! Anything wanting a dynamic linker is turned down. Like `module`, the
+//! caller can hand `load` the file and the memory to lay it out in.
+//! Where that memory gets mapped in the program's address space is
+//! `base`, and `segments` says with what permissions.
+//!
+//! Or `map` does all of that in the one address space there is: fresh
+//! pages at `base` for ring 3, with the segments' permissions once
+//! they're laid out, and a stack after them past an unmapped page.
+//! `run` then runs it with `usermode::run` until it exits and unmaps
+//! it again, and `spawn` does that on a thread of its own. `map_file`
+//! and `run_file` take the program from a file, through `fs::mmap`.
+use crate::error::{KernelError, KernelResult};
+use crate::fs::{self, mmap};
+use crate::memory::{self, frame_allocator, paging};
+use crate::module::{read_u16, read_u32, read_u64, write_field, LoadError};
+use crate::random;
+use crate::scheduler::{self, ThreadId};
+use crate::user::USER_END;
+use crate::usermode::{self, Exit};
+use core::ptr;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+pub const MAX_SEGMENTS: usize = 16;
+
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_RELATIVE: u32 = 8;
+
+/// Where position independent programs go, give or take the random
+/// part. Well clear of null and of the top of user memory.
+const PIE_BASE: u64 = 0x5555_0000_0000;
+/// Random bits in the load base, in pages.
+const PIE_RANDOM_BITS: u32 = 28;
+
+const PAGE_SIZE: u64 = 4096;
+/// What a program `map` maps gets for a stack.
+pub const STACK_SIZE: u64 = 64 * 1024;
+
+/// A `PT_LOAD` segment, once loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    /// Where it goes in the program's address space.
+    pub start: u64,
+    pub size: u64,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+/// A program that made it through `load`.
+#[derive(Debug, Clone, Copy)]
+pub struct Program {
+    /// Where the start of the region has to be mapped.
+    pub base: u64,
+    /// How much of the region it uses, a whole number of pages.
+    pub size: u64,
+    pub entry: u64,
+    pub segments: [Option<Segment>; MAX_SEGMENTS],
+    /// Relocations applied, 0 for a fixed address program.
+    pub relocations: usize,
+}
+
+/// The parts of a program header we care about.
+#[derive(Debug, Clone, Copy)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: usize,
+    address: u64,
+    file_size: usize,
+    memory_size: u64,
+    align: u64,
+}
+
+/// A parsed ELF executable.
+struct Executable<'a> {
+    bytes: &'a [u8],
+    position_independent: bool,
+    entry: u64,
+    header_offset: usize,
+    header_count: usize,
+}
+
+impl<'a> Executable<'a> {
+    fn parse(bytes: &'a [u8]) -> Result<Executable<'a>, LoadError> {
+        // magic, 64-bit, little endian, EM_X86_64
+        if bytes.get(..6) != Some(b"\x7fELF\x02\x01") || read_u16(bytes, 0x12)? != 62 {
+            return Err(LoadError::NotExecutable);
+        }
+        let position_independent = match read_u16(bytes, 0x10)? {
+            ET_EXEC => false,
+            ET_DYN => true,
+            _ => return Err(LoadError::NotExecutable),
+        };
+        if read_u16(bytes, 0x36)? != 56 {
+            return Err(LoadError::Malformed);
+        }
+        let header_count = usize::from(read_u16(bytes, 0x38)?);
+        if header_count > MAX_SEGMENTS {
+            return Err(LoadError::TooManySegments);
+        }
+        Ok(Executable {
+            bytes,
+            position_independent,
+            entry: read_u64(bytes, 0x18)?,
+            header_offset: read_u64(bytes, 0x20)? as usize,
+            header_count,
+        })
+    }
+
+    fn header(&self, index: usize) -> Result<ProgramHeader, LoadError> {
+        let header = self.header_offset + index * 56;
+        Ok(ProgramHeader {
+            kind: read_u32(self.bytes, header)?,
+            flags: read_u32(self.bytes, header + 4)?,
+            offset: read_u64(self.bytes, header + 8)? as usize,
+            address: read_u64(self.bytes, header + 16)?,
+            file_size: read_u64(self.bytes, header + 32)? as usize,
+            memory_size: read_u64(self.bytes, header + 40)?,
+            align: read_u64(self.bytes, header + 48)?,
+        })
+    }
+
+    fn headers(&self) -> impl Iterator<Item = Result<ProgramHeader, LoadError>> + '_ {
+        (0..self.header_count).map(move |index| self.header(index))
+    }
+
+    /// The page aligned range of link time addresses the loadable
+    /// segments cover, and the biggest alignment any of them wants.
+    fn span(&self) -> Result<(u64, u64, u64), LoadError> {
+        let mut low = u64::MAX;
+        let mut high = 0;
+        let mut align = PAGE_SIZE;
+        for header in self.headers() {
+            let header = header?;
+            if header.kind != PT_LOAD {
+                continue;
+            }
+            let end = header
+                .address
+                .checked_add(header.memory_size)
+                .ok_or(LoadError::Malformed)?;
+            low = low.min(header.address);
+            high = high.max(end);
+            align = align.max(header.align);
+        }
+        if low > high || !align.is_power_of_two() {
+            return Err(LoadError::Malformed);
+        }
+        Ok((low & !(PAGE_SIZE - 1), align_up(high, PAGE_SIZE), align))
+    }
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) & !(align - 1)
+}
+
+/// A random load base for a program whose segments want `align`.
+fn random_base(align: u64) -> u64 {
+    let pages = random::u64() & ((1 << PIE_RANDOM_BITS) - 1);
+    (PIE_BASE + pages * PAGE_SIZE) & !(align - 1)
+}
+
+/// Lay out `image` in `region`, the start of which will be mapped at a
+/// random address if it is position independent.
+pub fn load(image: &[u8], region: &mut [u8]) -> Result<Program, LoadError> {
+    let executable = Executable::parse(image)?;
+    let (_, _, align) = executable.span()?;
+    load_at(&executable, region, random_base(align))
+}
+
+/// Lay out `executable` in `region`, to be mapped at `base` if that's
+/// up to us.
+fn load_at(executable: &Executable, region: &mut [u8], base: u64) -> Result<Program, LoadError> {
+    let (low, high, align) = executable.span()?;
+    let base = if executable.position_independent {
+        if base & (align - 1) != 0 {
+            return Err(LoadError::Malformed);
+        }
+        base
+    } else {
+        low
+    };
+    let size = high - low;
+    if base.checked_add(size).map_or(true, |end| end > USER_END) {
+        return Err(LoadError::Malformed);
+    }
+    let region = region
+        .get_mut(..size as usize)
+        .ok_or(LoadError::RegionTooSmall)?;
+    // Whatever isn't in the file is zeroed, including .bss.
+    for byte in region.iter_mut() {
+        *byte = 0;
+    }
+
+    let mut segments = [None; MAX_SEGMENTS];
+    let mut dynamic = None;
+    for (index, header) in executable.headers().enumerate() {
+        let header = header?;
+        match header.kind {
+            PT_INTERP => return Err(LoadError::NeedsInterpreter),
+            PT_DYNAMIC => dynamic = Some(header),
+            PT_LOAD => {
+                if header.file_size as u64 > header.memory_size {
+                    return Err(LoadError::Malformed);
+                }
+                let data = image_bytes(executable, header.offset, header.file_size)?;
+                let start = (header.address - low) as usize;
+                region[start..start + data.len()].copy_from_slice(data);
+                segments[index] = Some(Segment {
+                    start: base + header.address - low,
+                    size: header.memory_size,
+                    writable: header.flags & PF_W != 0,
+                    executable: header.flags & PF_X != 0,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    let relocations = match dynamic {
+        Some(dynamic) if executable.position_independent => {
+            relocate(region, dynamic, low, base.wrapping_sub(low))?
+        }
+        // A fixed address program doesn't need relocating.
+        _ => 0,
+    };
+
+    Ok(Program {
+        base,
+        size,
+        entry: base + executable.entry.wrapping_sub(low),
+        segments,
+        relocations,
+    })
+}
+
+fn image_bytes<'a>(
+    executable: &Executable<'a>,
+    offset: usize,
+    len: usize,
+) -> Result<&'a [u8], LoadError> {
+    executable
+        .bytes
+        .get(offset..offset.checked_add(len).ok_or(LoadError::Malformed)?)
+        .ok_or(LoadError::Malformed)
+}
+
+/// Apply the relocations the dynamic section lists to the loaded
+/// `region`, which starts at link time address `low` and gets moved by
+/// `bias`. Returns how many there were.
+fn relocate(
+    region: &mut [u8],
+    dynamic: ProgramHeader,
+    low: u64,
+    bias: u64,
+) -> Result<usize, LoadError> {
+    // Read from the loaded copy, that's where the addresses in it are
+    // relative to.
+    let offset = |address: u64| address.checked_sub(low).ok_or(LoadError::Malformed);
+    let start = offset(dynamic.address)? as usize;
+    let entries = region
+        .get(start..start + dynamic.memory_size as usize)
+        .ok_or(LoadError::Malformed)?;
+
+    let mut rela = None;
+    let mut rela_size = 0;
+    let mut rela_entry = 24;
+    for entry in entries.chunks_exact(16) {
+        let value = read_u64(entry, 8)?;
+        match read_u64(entry, 0)? {
+            DT_NULL => break,
+            DT_RELA => rela = Some(offset(value)? as usize),
+            DT_RELASZ => rela_size = value as usize,
+            DT_RELAENT => rela_entry = value as usize,
+            _ => {}
+        }
+    }
+    let rela = match rela {
+        Some(rela) => rela,
+        None => return Ok(0),
+    };
+    if rela_entry != 24 {
+        return Err(LoadError::Malformed);
+    }
+    let mut count = 0;
+    while count * 24 < rela_size {
+        let entry = rela + count * 24;
+        let table = region.get(entry..entry + 24).ok_or(LoadError::Malformed)?;
+        let place = read_u64(table, 0)?;
+        let info = read_u64(table, 8)?;
+        let addend = read_u64(table, 16)?;
+        match info as u32 {
+            R_X86_64_NONE => {}
+            R_X86_64_RELATIVE => {
+                let field = region
+                    .get_mut(offset(place)? as usize..)
+                    .ok_or(LoadError::Malformed)?;
+                write_field(field, &bias.wrapping_add(addend).to_le_bytes())?;
+            }
+            // Anything with a symbol needs a dynamic linker to look it up.
+            other => return Err(LoadError::UnsupportedRelocation(other)),
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// A program mapped for ring 3 with its stack, see `map`. Unmapped
+/// again when dropped.
+pub struct Mapped {
+    base: u64,
+    /// Mapped so far from `base` on.
+    pages: u64,
+    stack: u64,
+    stack_pages: u64,
+    entry: u64,
+}
+
+impl Mapped {
+    pub fn entry(&self) -> VirtAddr {
+        VirtAddr::new(self.entry)
+    }
+
+    /// Where `rsp` starts: at an `argc` of 0, then empty `argv`, `envp`
+    /// and auxiliary vector, and 16 byte aligned.
+    pub fn stack_pointer(&self) -> VirtAddr {
+        VirtAddr::new(self.stack + STACK_SIZE - 6 * 8)
+    }
+}
+
+impl Drop for Mapped {
+    fn drop(&mut self) {
+        unsafe {
+            unmap_user(self.base, self.pages);
+            unmap_user(self.stack, self.stack_pages);
+        }
+    }
+}
+
+/// Map pages from `start` on to fresh zeroed frames, writable and for
+/// ring 3, until `mapped` gets to `count`. Counting them as it goes
+/// means whatever it got to is unmapped again if one fails.
+unsafe fn map_user(start: u64, count: u64, mapped: &mut u64) -> KernelResult<()> {
+    let offset = memory::physical_memory_offset().ok_or(KernelError::NotReady)?;
+    let flags = paging::data_flags(true) | PageTableFlags::USER_ACCESSIBLE;
+    while *mapped < count {
+        let page = Page::containing_address(VirtAddr::new(start + *mapped * PAGE_SIZE));
+        let frame = frame_allocator::allocate_frame()?;
+        let contents = offset + frame.start_address().as_u64();
+        ptr::write_bytes(contents.as_mut_ptr::<u8>(), 0, PAGE_SIZE as usize);
+        if let Err(error) = paging::map_to(page, frame, flags) {
+            let _ = frame_allocator::free_frame(frame);
+            return Err(error);
+        }
+        *mapped += 1;
+    }
+    Ok(())
+}
+
+unsafe fn unmap_user(start: u64, count: u64) {
+    for index in 0..count {
+        let page = Page::containing_address(VirtAddr::new(start + index * PAGE_SIZE));
+        if let Ok(frame) = paging::unmap(page) {
+            let _ = frame_allocator::free_frame(frame);
+        }
+    }
+}
+
+/// What the page at `address` ends up mapped with: whatever the
+/// segments in it need between them, or kernel only and read-only if
+/// none of them are.
+fn page_flags(program: &Program, address: u64) -> PageTableFlags {
+    let mut used = false;
+    let mut writable = false;
+    let mut executable = false;
+    for segment in program.segments.iter().flatten() {
+        if segment.start < address + PAGE_SIZE && address < segment.start + segment.size {
+            used = true;
+            writable |= segment.writable;
+            executable |= segment.executable;
+        }
+    }
+    if !used {
+        return paging::data_flags(false);
+    }
+    let flags = if executable && writable {
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+    } else if executable {
+        PageTableFlags::PRESENT
+    } else {
+        paging::data_flags(writable)
+    };
+    flags | PageTableFlags::USER_ACCESSIBLE
+}
+
+/// Load `image` into fresh pages for ring 3, at a random address if
+/// it's position independent, and map it a stack. `AlreadyExists` if
+/// something is in the way, which a fixed address program can run into.
+pub fn map(image: &[u8]) -> KernelResult<Mapped> {
+    let executable = Executable::parse(image)?;
+    let (low, high, align) = executable.span()?;
+    let base = if executable.position_independent {
+        random_base(align)
+    } else {
+        low
+    };
+    let size = high - low;
+    let stack = base
+        .checked_add(size + PAGE_SIZE)
+        .filter(|&stack| stack + STACK_SIZE <= USER_END)
+        .ok_or(KernelError::InvalidAddress)?;
+    let mut mapped = Mapped {
+        base,
+        pages: 0,
+        stack,
+        stack_pages: 0,
+        entry: 0,
+    };
+    unsafe {
+        map_user(base, size / PAGE_SIZE, &mut mapped.pages)?;
+        map_user(stack, STACK_SIZE / PAGE_SIZE, &mut mapped.stack_pages)?;
+        let region = core::slice::from_raw_parts_mut(base as *mut u8, size as usize);
+        let program = load_at(&executable, region, base)?;
+        for index in 0..mapped.pages {
+            let address = base + index * PAGE_SIZE;
+            let page = Page::containing_address(VirtAddr::new(address));
+            paging::update_flags(page, page_flags(&program, address))?;
+        }
+        mapped.entry = program.entry;
+    }
+    Ok(mapped)
+}
+
+/// `map`, with the executable in the file at `path`. It's mapped
+/// privately while it's loaded, so only the pages of it that are used
+/// are read.
+pub fn map_file(path: &str) -> KernelResult<Mapped> {
+    let size = fs::stat(path)?.size;
+    let file = mmap::map(path, 0, size, mmap::MapOptions::default())?;
+    // Read-only and private, nothing writes to it.
+    map(unsafe { file.as_slice() })
+}
+
+/// Map `image` and run it until it exits or faults, then unmap it.
+///
+/// # Safety
+///
+/// Like `usermode::run`: it gets at everything else mapped for ring 3
+/// too.
+pub unsafe fn run(image: &[u8]) -> KernelResult<Exit> {
+    let mapped = map(image)?;
+    usermode::run(mapped.entry(), mapped.stack_pointer())
+}
+
+/// `run`, with the program in the file at `path`, see `map_file`.
+///
+/// # Safety
+///
+/// See `run`.
+pub unsafe fn run_file(path: &str) -> KernelResult<Exit> {
+    let mapped = map_file(path)?;
+    usermode::run(mapped.entry(), mapped.stack_pointer())
+}
+
+/// Map `image` and run it on a new thread, which logs how it ended.
+/// Anything wrong with `image` comes back before the thread starts.
+///
+/// # Safety
+///
+/// See `run`.
+pub unsafe fn spawn(name: &'static str, image: &[u8]) -> KernelResult<ThreadId> {
+    let mapped = map(image)?;
+    Ok(scheduler::spawn(name, move || {
+        let exit = usermode::run(mapped.entry(), mapped.stack_pointer());
+        crate::klog!(Info, "{}: {:?}", name, exit);
+        drop(mapped);
+    }))
+}
+
+#[test_case]
+fn test_loads_static_pie() {
+    let mut image = [0u8; 0x100];
+    image[..6].copy_from_slice(b"\x7fELF\x02\x01");
+    let mut put = |offset: usize, value: u64, len: usize| {
+        image[offset..offset + len].copy_from_slice(&value.to_le_bytes()[..len]);
+    };
+    put(0x10, u64::from(ET_DYN), 2);
+    put(0x12, 62, 2);
+    put(0x18, 0x10, 8); // entry
+    put(0x20, 0x40, 8); // program headers
+    put(0x36, 56, 2);
+    put(0x38, 2, 2);
+    // Everything, plus 0x100 bytes of .bss.
+    put(0x40, u64::from(PT_LOAD), 4);
+    put(0x44, u64::from(PF_W), 4);
+    put(0x60, 0x100, 8);
+    put(0x68, 0x200, 8);
+    put(0x70, 0x1000, 8);
+    put(0x78, u64::from(PT_DYNAMIC), 4);
+    put(0x88, 0xB0, 8);
+    put(0x90, 0xB0, 8);
+    put(0xA0, 0x30, 8);
+    put(0xA8, 0x30, 8);
+    // Dynamic section: one relocation table, one entry long.
+    put(0xB0, DT_RELA, 8);
+    put(0xB8, 0xE0, 8);
+    put(0xC0, DT_RELASZ, 8);
+    put(0xC8, 24, 8);
+    // A pointer to 0x40 goes at 0xF8.
+    put(0xE0, 0xF8, 8);
+    put(0xE8, u64::from(R_X86_64_RELATIVE), 8);
+    put(0xF0, 0x40, 8);
+
+    let mut region = [0xFFu8; 0x1000];
+    let executable = Executable::parse(&image).unwrap();
+    let base = 0x4000_0000;
+    let program = load_at(&executable, &mut region, base).unwrap();
+    assert_eq!(program.size, 0x1000);
+    assert_eq!(program.entry, base + 0x10);
+    assert_eq!(program.relocations, 1);
+    assert_eq!(region[0xF8..0x100], (base + 0x40).to_le_bytes());
+    assert!(region[0x100..0x200].iter().all(|&byte| byte == 0));
+    let segment = program.segments[0].unwrap();
+    assert!(segment.writable && !segment.executable);
+
+    assert_eq!(
+        load(b"not an elf file at all", &mut region).err(),
+        Some(LoadError::NotExecutable)
+    );
+}
+
+#[test_case]
+fn test_runs_fixed_address_program() {
+    const BASE: u64 = 0x3000_0000_0000;
+
+    let mut image = [0u8; 0x78 + 15];
+    image[..6].copy_from_slice(b"\x7fELF\x02\x01");
+    let mut put = |offset: usize, value: u64, len: usize| {
+        image[offset..offset + len].copy_from_slice(&value.to_le_bytes()[..len]);
+    };
+    put(0x10, u64::from(ET_EXEC), 2);
+    put(0x12, 62, 2);
+    put(0x18, BASE + 0x78, 8); // entry
+    put(0x20, 0x40, 8); // program headers
+    put(0x36, 56, 2);
+    put(0x38, 1, 2);
+    // The whole file, executable.
+    put(0x40, u64::from(PT_LOAD), 4);
+    put(0x44, u64::from(PF_X), 4);
+    put(0x50, BASE, 8);
+    put(0x60, 0x78 + 15, 8);
+    put(0x68, 0x78 + 15, 8);
+    put(0x70, 0x1000, 8);
+    // mov rdi, [rsp]; add edi, 7; mov eax, 60; syscall: exit(argc + 7)
+    image[0x78..].copy_from_slice(&[
+        0x48, 0x8b, 0x3c, 0x24, 0x83, 0xc7, 0x07, 0xb8, 60, 0, 0, 0, 0x0f, 0x05, 0x90,
+    ]);
+
+    let mapping = |address| memory::translate(VirtAddr::new(address));
+    {
+        let mapped = map(&image).unwrap();
+        let code = mapping(BASE).unwrap();
+        assert!(code.user_accessible && !code.writable);
+        // Another one can't go in the same place.
+        assert_eq!(map(&image).err(), Some(KernelError::AlreadyExists));
+        let stack = mapping(mapped.stack_pointer().as_u64()).unwrap();
+        assert!(stack.user_accessible && stack.writable);
+        // The page in between.
+        assert_eq!(mapping(BASE + PAGE_SIZE), None);
+    }
+    assert_eq!(mapping(BASE), None);
+    assert_eq!(unsafe { run(&image) }, Ok(Exit::Exited(7)));
+
+    unsafe { spawn("exits", &image) }.unwrap();
+    while scheduler::ready_count() > 0 {
+        scheduler::yield_now();
+    }
+    assert_eq!(mapping(BASE), None);
+
+    fs::create("/exits", fs::Kind::File).unwrap();
+    fs::write("/exits", 0, &image).unwrap();
+    assert_eq!(unsafe { run_file("/exits") }, Ok(Exit::Exited(7)));
+    assert_eq!(mapping(BASE), None);
+    fs::unlink("/exits").unwrap();
+}
+
+#[test_case]
+fn test_runs_user_example() {
+    static HELLO: &[u8] = include_bytes!(concat!(env!("USER_EXAMPLES"), "/hello"));
+
+    let (exit, output) = crate::syscall::capture_output(|| unsafe { run(HELLO) }).unwrap();
+    assert_eq!(exit, Ok(Exit::Exited(0)));
+    assert_eq!(output, "hello from user space\n");
+}
@@ -0,0 +1,268 @@
+//! Booting straight from the kernel's ELF file, with QEMU's `-kernel`.
+//!
+//! Building a disk image with the bootloader for every test binary
+//! takes longer than running it. QEMU can load an ELF file itself
+//! instead if it has a PVH note, saying where to start it in 32 bit
+//! protected mode with paging off. `pvh_start32` does the rest of what
+//! the bootloader would have:
+//!
+//! - identity maps the first 4 GiB with 2 MiB pages, the kernel is
+//!   linked at its physical address so it keeps running where it is,
+//! - turns on long mode and jumps to 64 bit code on a stack of ours,
+//! - and `pvh_main` then puts a guard page under that stack, turns the
+//!   memory map in QEMU's `hvm_start_info` into a `BootInfo` and calls
+//!   `_start` with it, like the bootloader does.
+//!
+//! So nothing after `_start` knows the difference. Physical memory is
+//! mapped at offset 0 and only up to 4 GiB, anything above that isn't
+//! in the memory map. The loaded kernel isn't the whole ELF file, so
+//! `ksyms` has no symbols. The command line comes from `-append`, if
+//! there is one, see `cmdline`.
+//!
+//! `pvh-runner.sh` runs a kernel built by cargo this way, see the
+//! comment next to `test-args` in Cargo.toml.
+use bootloader::bootinfo::{BootInfo, FrameRange, MemoryMap, MemoryRegion, MemoryRegionType};
+use core::mem::MaybeUninit;
+use core::ptr;
+
+/// What `hvm_start_info` starts with.
+const START_INFO_MAGIC: u32 = 0x336e_c578;
+/// Everything above this isn't mapped.
+const MAPPED_END: u64 = 4 << 30;
+const PAGE_SIZE: u64 = 4096;
+
+#[repr(C)]
+struct StartInfo {
+    magic: u32,
+    version: u32,
+    flags: u32,
+    nr_modules: u32,
+    modlist_paddr: u64,
+    cmdline_paddr: u64,
+    rsdp_paddr: u64,
+    // Version 1 and up.
+    memmap_paddr: u64,
+    memmap_entries: u32,
+    reserved: u32,
+}
+
+#[repr(C)]
+struct MemmapEntry {
+    address: u64,
+    size: u64,
+    kind: u32,
+    reserved: u32,
+}
+
+global_asm!(
+    r#"
+    .section .note.Xen, "a", @note
+    .balign 4
+    .long 4
+    .long 4
+    .long 18 # XEN_ELFNOTE_PHYS32_ENTRY
+    .asciz "Xen"
+    .balign 4
+    .long pvh_start32
+    .balign 4
+
+    .section .text.pvh, "ax", @progbits
+    .code32
+    .global pvh_start32
+pvh_start32:
+    cli
+    # Where QEMU put hvm_start_info, for pvh_main.
+    mov %ebx, %edi
+
+    # 2048 2 MiB pages make 4 GiB.
+    xor %ecx, %ecx
+1:
+    mov %ecx, %eax
+    shl $21, %eax
+    or $0x83, %eax # present, writable, huge
+    mov %eax, pvh_page_directories(, %ecx, 8)
+    inc %ecx
+    cmp $2048, %ecx
+    jne 1b
+
+    # One directory for each GiB.
+    xor %ecx, %ecx
+2:
+    mov %ecx, %eax
+    shl $12, %eax
+    add $pvh_page_directories, %eax
+    or $0x3, %eax
+    mov %eax, pvh_pdpt(, %ecx, 8)
+    inc %ecx
+    cmp $4, %ecx
+    jne 2b
+    mov $pvh_pdpt, %eax
+    or $0x3, %eax
+    mov %eax, pvh_pml4
+
+    # PAE, the tables, long mode, then paging.
+    mov %cr4, %eax
+    or $0x20, %eax
+    mov %eax, %cr4
+    mov $pvh_pml4, %eax
+    mov %eax, %cr3
+    mov $0xC0000080, %ecx
+    rdmsr
+    or $0x100, %eax
+    wrmsr
+    mov %cr0, %eax
+    or $0x80000001, %eax
+    mov %eax, %cr0
+
+    lgdt pvh_gdt_pointer
+    ljmp $0x08, $pvh_start64
+
+    .code64
+pvh_start64:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    mov $pvh_stack_top, %rsp
+    # Only the low half is the address.
+    mov %edi, %edi
+    call pvh_main
+    ud2
+
+    .section .rodata.pvh, "a", @progbits
+    .balign 8
+pvh_gdt:
+    .quad 0
+    .quad 0x00af9a000000ffff # 64 bit code
+    .quad 0x00cf92000000ffff # data
+pvh_gdt_pointer:
+    .word pvh_gdt_pointer - pvh_gdt - 1
+    .long pvh_gdt
+
+    .section .bss.pvh, "aw", @nobits
+    .balign 4096
+pvh_pml4:
+    .skip 4096
+pvh_pdpt:
+    .skip 4096
+    .global pvh_page_directories
+pvh_page_directories:
+    .skip 4 * 4096
+    .global pvh_page_table
+pvh_page_table:
+    .skip 4096
+    .global pvh_stack_guard
+pvh_stack_guard:
+    .skip 4096
+    .skip 80 * 1024
+pvh_stack_top:
+"#
+);
+
+extern "C" {
+    static mut pvh_page_directories: [u64; 4 * 512];
+    static mut pvh_page_table: [u64; 512];
+    static pvh_stack_guard: u8;
+    static __ehdr_start: u8;
+    static _end: u8;
+    fn _start(boot_info: &'static BootInfo) -> !;
+}
+
+static mut BOOT_INFO: MaybeUninit<BootInfo> = MaybeUninit::uninit();
+
+/// Unmap the page under the stack, so running off the end faults like
+/// it does with the bootloader's. The 2 MiB page it's in becomes 4 KiB
+/// ones.
+unsafe fn map_stack_guard() {
+    let guard = &pvh_stack_guard as *const u8 as u64;
+    let huge = guard & !((1 << 21) - 1);
+    for (index, entry) in pvh_page_table.iter_mut().enumerate() {
+        let address = huge + index as u64 * PAGE_SIZE;
+        *entry = if address == guard { 0 } else { address | 0x3 };
+    }
+    pvh_page_directories[(huge >> 21) as usize] = &pvh_page_table as *const _ as u64 | 0x3;
+    let (table, flags) = x86_64::registers::control::Cr3::read();
+    x86_64::registers::control::Cr3::write(table, flags);
+}
+
+fn region(start: u64, end: u64, region_type: MemoryRegionType) -> MemoryRegion {
+    MemoryRegion {
+        range: FrameRange::new(start, end),
+        region_type,
+    }
+}
+
+/// Where the loaded kernel is, in whole frames. Our page tables and
+/// stack are in there too.
+fn kernel_range() -> (u64, u64) {
+    unsafe {
+        (
+            &__ehdr_start as *const u8 as u64 & !(PAGE_SIZE - 1),
+            (&_end as *const u8 as u64 + PAGE_SIZE - 1) & !(PAGE_SIZE - 1),
+        )
+    }
+}
+
+/// Add `start..end` as `region_type`, but with the kernel cut out of
+/// usable memory, and the frames shrunk to whole ones inside it.
+fn add_region(map: &mut MemoryMap, start: u64, end: u64, region_type: MemoryRegionType) {
+    let (kernel_start, kernel_end) = kernel_range();
+    if region_type != MemoryRegionType::Usable {
+        map.add_region(region(start, end, region_type));
+        return;
+    }
+    // Frame 0 never is, `frame_allocator` counts on that.
+    let start = ((start + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)).max(PAGE_SIZE);
+    let end = end.min(MAPPED_END) & !(PAGE_SIZE - 1);
+    for &(start, end) in [(start, end.min(kernel_start)), (start.max(kernel_end), end)].iter() {
+        if start < end {
+            map.add_region(region(start, end, MemoryRegionType::Usable));
+        }
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn pvh_main(start_info: u32) -> ! {
+    let info = &*(start_info as u64 as *const StartInfo);
+    if info.magic != START_INFO_MAGIC || info.version < 1 {
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+    map_stack_guard();
+
+    let mut map = MemoryMap::new();
+    let entries = info.memmap_paddr as *const MemmapEntry;
+    for index in 0..info.memmap_entries as usize {
+        let entry = &*entries.add(index);
+        let region_type = match entry.kind {
+            1 => MemoryRegionType::Usable,
+            3 => MemoryRegionType::AcpiReclaimable,
+            4 => MemoryRegionType::AcpiNvs,
+            5 => MemoryRegionType::BadMemory,
+            _ => MemoryRegionType::Reserved,
+        };
+        add_region(
+            &mut map,
+            entry.address,
+            entry.address + entry.size,
+            region_type,
+        );
+    }
+    // Not `Kernel`: that's meant to be the whole ELF file, and `ksyms`
+    // would go looking for sections in it.
+    let (kernel_start, kernel_end) = kernel_range();
+    add_region(&mut map, kernel_start, kernel_end, MemoryRegionType::InUse);
+
+    if info.cmdline_paddr != 0 {
+        let line = info.cmdline_paddr as *const u8;
+        let len = (0..crate::cmdline::MAX_LENGTH)
+            .find(|&at| ptr::read(line.add(at)) == 0)
+            .unwrap_or(crate::cmdline::MAX_LENGTH);
+        crate::cmdline::set(core::slice::from_raw_parts(line, len));
+    }
+
+    // Identity mapped.
+    BOOT_INFO = MaybeUninit::new(BootInfo::new(map, None, 0, 0));
+    _start(&*BOOT_INFO.as_ptr())
+}
@@ -0,0 +1,248 @@
+//! Random numbers that are hard to guess.
+//!
+//! Interrupt handlers call `add_interrupt_timing`, which mixes the TSC
+//! into a small pool. The exact cycle an interrupt arrives on depends
+//! on things like serial line timing and the memory bus, so the low
+//! bits of it are reasonably unpredictable. We only count one
+//! bit of entropy per interrupt, which is on the careful side.
+//!
+//! Numbers come out of ChaCha20 (the RFC 8439 block function) keyed
+//! from the pool. Once the pool has collected `RESEED_BITS` it gets
+//! folded into the key again. Where the CPU has RDRAND, that goes in
+//! as well, but we don't rely on it.
+use crate::latency;
+use crate::shell::{self, CommandResult};
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::fmt;
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// How much entropy the pool needs before it's used to reseed.
+pub const RESEED_BITS: u32 = 256;
+
+const POOL_WORDS: usize = 8;
+const BLOCK_BYTES: usize = 64;
+
+/// Where timings get mixed in. Atomics, as interrupt handlers write
+/// to it and can't wait on a lock.
+static POOL: [AtomicU64; POOL_WORDS] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+static POOL_INDEX: AtomicUsize = AtomicUsize::new(0);
+static POOL_BITS: AtomicU32 = AtomicU32::new(0);
+static LAST_TSC: AtomicU64 = AtomicU64::new(0);
+/// Bits of entropy that have gone into the generator's key so far.
+static SEEDED_BITS: AtomicU64 = AtomicU64::new(0);
+
+static GENERATOR: Mutex<ChaCha20> = Mutex::new(ChaCha20 {
+    key: [0; 8],
+    block: 0,
+    buffer: [0; 16],
+    used: BLOCK_BYTES,
+});
+
+/// Where an interrupt came from, mixed in with its timing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Timer,
+    Serial,
+    Keyboard,
+    Disk,
+}
+
+/// Adds the `entropy` shell command and seeds the generator with
+/// what there is so far.
+pub fn init() {
+    reseed();
+    shell::register("entropy", "entropy pool status", entropy_command).expect("entropy command");
+}
+
+/// Mix the time of an interrupt from `source` into the pool. Cheap, and
+/// safe to call from any interrupt handler.
+pub fn add_interrupt_timing(source: Source) {
+    let tsc = unsafe { _rdtsc() };
+    let delta = tsc.wrapping_sub(LAST_TSC.swap(tsc, Ordering::Relaxed));
+    let index = POOL_INDEX.fetch_add(1, Ordering::Relaxed) % POOL_WORDS;
+    let value = (tsc ^ delta.rotate_left(32) ^ source as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    let old = POOL[index].load(Ordering::Relaxed);
+    POOL[index].store(old.rotate_left(7) ^ value, Ordering::Relaxed);
+    // Timer interrupts that arrive a fixed number of cycles apart
+    // don't tell us anything.
+    if delta & 0xFF != 0 {
+        POOL_BITS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Whether the generator has had `RESEED_BITS` of entropy yet. Before
+/// that its output is only as good as whatever RDRAND gave.
+pub fn is_seeded() -> bool {
+    SEEDED_BITS.load(Ordering::Relaxed) >= u64::from(RESEED_BITS) || has_rdrand()
+}
+
+/// Fill `buffer` with random bytes.
+pub fn fill(buffer: &mut [u8]) {
+    if POOL_BITS.load(Ordering::Relaxed) >= RESEED_BITS {
+        reseed();
+    }
+    latency::without_interrupts(|| {
+        let mut generator = GENERATOR.lock();
+        for byte in buffer.iter_mut() {
+            *byte = generator.next_byte();
+        }
+    });
+}
+
+pub fn u64() -> u64 {
+    let mut bytes = [0; 8];
+    fill(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+/// Fold the pool into the key.
+fn reseed() {
+    let bits = POOL_BITS.swap(0, Ordering::Relaxed);
+    let mut words = [0u32; 16];
+    for (i, word) in POOL.iter().enumerate() {
+        let value = word.load(Ordering::Relaxed);
+        words[2 * i] = value as u32;
+        words[2 * i + 1] = (value >> 32) as u32;
+    }
+    if let Some(value) = rdrand() {
+        words[0] ^= value as u32;
+        words[1] ^= (value >> 32) as u32;
+    }
+    latency::without_interrupts(|| {
+        let mut generator = GENERATOR.lock();
+        // Mix each half of the pool into the key and run it through
+        // ChaCha20, so earlier seeds still count for something.
+        let mut key = generator.key;
+        for half in words.chunks(8) {
+            for (key, word) in key.iter_mut().zip(half) {
+                *key ^= word;
+            }
+            let output = chacha20_block(&key, 0, &[0; 3]);
+            key.copy_from_slice(&output[..8]);
+        }
+        generator.key = key;
+        generator.block = 0;
+        generator.used = BLOCK_BYTES;
+    });
+    SEEDED_BITS.fetch_add(u64::from(bits), Ordering::Relaxed);
+}
+
+fn has_rdrand() -> bool {
+    unsafe { __cpuid(1).ecx & 1 << 30 != 0 }
+}
+
+fn rdrand() -> Option<u64> {
+    if !has_rdrand() {
+        return None;
+    }
+    // It can run dry for a moment, Intel suggests trying ten times.
+    for _ in 0..10 {
+        let (value, ok): (u64, u8);
+        unsafe {
+            asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+struct ChaCha20 {
+    key: [u32; 8],
+    block: u64,
+    buffer: [u32; 16],
+    /// Bytes of `buffer` handed out already.
+    used: usize,
+}
+
+impl ChaCha20 {
+    fn next_byte(&mut self) -> u8 {
+        if self.used == BLOCK_BYTES {
+            let nonce = [(self.block >> 32) as u32, 0, 0];
+            self.buffer = chacha20_block(&self.key, self.block as u32, &nonce);
+            self.block += 1;
+            self.used = 0;
+        }
+        let byte = (self.buffer[self.used / 4] >> (8 * (self.used % 4))) as u8;
+        self.used += 1;
+        byte
+    }
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+fn chacha20_block(key: &[u32; 8], counter: u32, nonce: &[u32; 3]) -> [u32; 16] {
+    let mut initial = [0u32; 16];
+    // "expand 32-byte k"
+    initial[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    initial[4..12].copy_from_slice(key);
+    initial[12] = counter;
+    initial[13..].copy_from_slice(nonce);
+    let mut state = initial;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for (word, initial) in state.iter_mut().zip(&initial) {
+        *word = word.wrapping_add(*initial);
+    }
+    state
+}
+
+fn entropy_command(out: &mut dyn fmt::Write, _args: &str) -> CommandResult {
+    let _ = writeln!(
+        out,
+        "pool {} bits, {} bits seeded, rdrand {}, {}",
+        POOL_BITS.load(Ordering::Relaxed),
+        SEEDED_BITS.load(Ordering::Relaxed),
+        if has_rdrand() { "yes" } else { "no" },
+        if is_seeded() {
+            "seeded"
+        } else {
+            "not seeded yet"
+        }
+    );
+    Ok(())
+}
+
+#[test_case]
+fn test_chacha20_block() {
+    // RFC 8439 section 2.3.2
+    let mut key = [0u32; 8];
+    for (i, word) in key.iter_mut().enumerate() {
+        let i = i as u32 * 4;
+        *word = u32::from_le_bytes([i as u8, i as u8 + 1, i as u8 + 2, i as u8 + 3]);
+    }
+    let block = chacha20_block(&key, 1, &[0x0900_0000, 0x4a00_0000, 0]);
+    assert_eq!(
+        block[..4],
+        [0xe4e7_f110, 0x1559_3bd1, 0x1fdd_0f50, 0xc471_20a3]
+    );
+    assert_ne!(u64(), u64());
+}
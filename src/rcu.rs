@@ -0,0 +1,121 @@
+//! Read-copy-update, for data that is read all the time and hardly
+//! ever changed.
+//!
+//! Readers go through `Rcu::read` and never wait: they get whichever
+//! version was current when they started. A writer builds a new
+//! version on the side, publishes it with `Rcu::replace`, and then
+//! calls `synchronize` before reusing the old one, which waits out a
+//! grace period: every reader that could still have the old version
+//! has finished.
+//!
+//! Readers can't sleep, so a CPU that isn't inside any `read` is done
+//! with whatever it read before. The timer tick reports that for its
+//! CPU (see `tick`), and a grace period is over once every CPU that
+//! was running at its start has reported in, parked ones don't read. The CPU calling
+//! `synchronize` counts as reported, it's not reading. With only the
+//! boot CPU online that makes grace periods instant.
+//!
+//! There is no heap to free old versions to, so they are `'static`
+//! and the writer owns where they live, e.g. two statics it flips
+//! between.
+use crate::interrupts;
+use crate::smp;
+use crate::trace::{current_cpu, MAX_CPUS};
+use crate::{idle, kassert};
+use core::sync::atomic::{AtomicPtr, AtomicU32, AtomicU64, Ordering};
+use spin::Mutex;
+
+/// How many `read`s each CPU is inside of.
+static READ_DEPTH: [AtomicU32; MAX_CPUS] = [
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+];
+/// CPUs that still have to report in for the current grace period.
+static PENDING: AtomicU32 = AtomicU32::new(0);
+static GRACE_PERIODS: AtomicU64 = AtomicU64::new(0);
+/// One grace period at a time.
+static WRITER: Mutex<()> = Mutex::new(());
+
+/// A pointer readers follow without locking.
+pub struct Rcu<T: 'static> {
+    current: AtomicPtr<T>,
+}
+
+impl<T: 'static> Rcu<T> {
+    pub const fn new(initial: &'static T) -> Rcu<T> {
+        Rcu {
+            current: AtomicPtr::new(initial as *const T as *mut T),
+        }
+    }
+
+    /// Run `f` on the current version. It mustn't sleep or wait for a
+    /// grace period.
+    pub fn read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        let depth = &READ_DEPTH[current_cpu() as usize];
+        depth.fetch_add(1, Ordering::SeqCst);
+        let result = f(unsafe { &*self.current.load(Ordering::Acquire) });
+        depth.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+
+    /// Make `new` the current version. Returns the old one, which
+    /// readers may still be looking at until `synchronize`.
+    pub fn replace(&self, new: &'static T) -> &'static T {
+        let old = self
+            .current
+            .swap(new as *const T as *mut T, Ordering::AcqRel);
+        unsafe { &*old }
+    }
+}
+
+// Readers on any CPU only ever get shared references.
+unsafe impl<T: Sync> Sync for Rcu<T> {}
+
+/// Wait until every reader that started before this has finished.
+/// Can't be called from inside `read` or an interrupt handler, a
+/// reader it interrupted would never finish.
+pub fn synchronize() {
+    let cpu = current_cpu();
+    kassert!(READ_DEPTH[cpu as usize].load(Ordering::SeqCst) == 0);
+    kassert!(!interrupts::in_interrupt());
+    let _writer = WRITER.lock();
+    PENDING.store(smp::running_mask() & !(1 << cpu), Ordering::SeqCst);
+    while PENDING.load(Ordering::SeqCst) != 0 {
+        idle::idle();
+    }
+    GRACE_PERIODS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Called from the timer interrupt handler. Reports this CPU done if
+/// the code it interrupted isn't reading.
+pub fn tick() {
+    let cpu = current_cpu();
+    if READ_DEPTH[cpu as usize].load(Ordering::SeqCst) == 0 {
+        PENDING.fetch_and(!(1 << cpu), Ordering::SeqCst);
+    }
+}
+
+/// Grace periods waited out since boot.
+pub fn grace_periods() -> u64 {
+    GRACE_PERIODS.load(Ordering::Relaxed)
+}
+
+#[test_case]
+fn test_replace_and_synchronize() {
+    static OLD: u32 = 1;
+    static NEW: u32 = 2;
+    static VALUE: Rcu<u32> = Rcu::new(&OLD);
+
+    assert_eq!(VALUE.read(|value| *value), 1);
+    let before = grace_periods();
+    VALUE.read(|value| {
+        // A reader keeps what it started with.
+        assert_eq!(*VALUE.replace(&NEW), 1);
+        assert_eq!(*value, 1);
+    });
+    synchronize();
+    assert_eq!(grace_periods(), before + 1);
+    assert_eq!(VALUE.read(|value| *value), 2);
+}
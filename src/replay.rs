@@ -0,0 +1,265 @@
+//! Feeding recorded or scripted input through the interrupt paths.
+//!
+//! Tests can't press keys or wait for the timer, and if they could the
+//! results would depend on when things happened to arrive. Instead a
+//! script of inputs goes through the same code the keyboard, timer
+//! and serial interrupts call, with interrupts off so nothing real
+//! comes in between:
+//!
+//! ```text
+//! k2a k23 kaa k1c t3 s71
+//! ```
+//!
+//! is left shift down, `h` down, left shift up, Enter down, three
+//! timer ticks and a `q` on serial. Scancodes and serial bytes are in
+//! hex, tick counts in decimal and `t` by itself is one tick.
+//!
+//! `replay record` records real input in the same format, so a session
+//! that went wrong can be pasted into a test. Bytes for the monitor
+//! aren't recorded, they stop everything anyway.
+//!
+//! There's only the one console so far. Switching between them would
+//! be key events like any other, so a script would test that too.
+use crate::error::{KernelError, KernelResult};
+use crate::interrupts;
+use crate::keyboard;
+use crate::latency;
+use crate::shell::{self, CommandFailed, CommandResult};
+use core::fmt;
+use spin::Mutex;
+
+/// Inputs a recording holds, the rest are dropped.
+pub const MAX_RECORDED: usize = 256;
+
+/// One thing an interrupt would have brought in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    /// A byte from the keyboard's data port.
+    Scancode(u8),
+    /// This many timer interrupts.
+    Ticks(u32),
+    /// A byte from COM1.
+    Serial(u8),
+}
+
+impl Input {
+    /// One word of a script.
+    pub fn parse(word: &str) -> KernelResult<Input> {
+        let mut chars = word.chars();
+        let kind = chars.next().ok_or(KernelError::InvalidArgument)?;
+        let rest = chars.as_str();
+        let byte = || u8::from_str_radix(rest, 16).map_err(|_| KernelError::InvalidArgument);
+        match kind {
+            'k' => Ok(Input::Scancode(byte()?)),
+            's' => Ok(Input::Serial(byte()?)),
+            't' if rest.is_empty() => Ok(Input::Ticks(1)),
+            't' => rest
+                .parse()
+                .map(Input::Ticks)
+                .map_err(|_| KernelError::InvalidArgument),
+            _ => Err(KernelError::InvalidArgument),
+        }
+    }
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Input::Scancode(byte) => write!(f, "k{:02x}", byte),
+            Input::Ticks(1) => f.write_str("t"),
+            Input::Ticks(count) => write!(f, "t{}", count),
+            Input::Serial(byte) => write!(f, "s{:02x}", byte),
+        }
+    }
+}
+
+struct Recording {
+    on: bool,
+    inputs: [Input; MAX_RECORDED],
+    len: usize,
+    dropped: usize,
+}
+
+static RECORDING: Mutex<Recording> = Mutex::new(Recording {
+    on: false,
+    inputs: [Input::Ticks(0); MAX_RECORDED],
+    len: 0,
+    dropped: 0,
+});
+
+/// Adds the `replay` shell command.
+pub fn init() {
+    shell::register(
+        "replay",
+        "replay [record|stop|run SCRIPT]: record input or play it back",
+        replay_command,
+    )
+    .expect("replay command");
+}
+
+/// Called by the interrupt handlers with what came in. Ticks in a row
+/// count as one input, there are a lot of them.
+pub fn record(input: Input) {
+    let mut recording = match RECORDING.try_lock() {
+        Some(recording) => recording,
+        None => return,
+    };
+    if !recording.on {
+        return;
+    }
+    let len = recording.len;
+    if let (Input::Ticks(more), Some(Input::Ticks(count))) =
+        (input, recording.inputs[..len].last_mut())
+    {
+        *count = count.saturating_add(more);
+        return;
+    }
+    if len == MAX_RECORDED {
+        recording.dropped += 1;
+        return;
+    }
+    recording.inputs[len] = input;
+    recording.len += 1;
+}
+
+/// Throw away what was recorded and start again.
+pub fn start_recording() {
+    latency::without_interrupts(|| {
+        let mut recording = RECORDING.lock();
+        recording.on = true;
+        recording.len = 0;
+        recording.dropped = 0;
+    });
+}
+
+pub fn stop_recording() {
+    latency::without_interrupts(|| RECORDING.lock().on = false);
+}
+
+/// Write what was recorded as a script.
+pub fn write_recording(out: &mut dyn fmt::Write) -> fmt::Result {
+    let (inputs, len, dropped) = latency::without_interrupts(|| {
+        let recording = RECORDING.lock();
+        (recording.inputs, recording.len, recording.dropped)
+    });
+    for (index, input) in inputs[..len].iter().enumerate() {
+        // Short lines, so they paste into a test.
+        let separator = match index {
+            0 => "",
+            _ if index % 16 == 0 => "\n",
+            _ => " ",
+        };
+        write!(out, "{}{}", separator, input)?;
+    }
+    writeln!(out)?;
+    if dropped > 0 {
+        writeln!(out, "({} more didn't fit)", dropped)?;
+    }
+    Ok(())
+}
+
+/// Feed one input through the path its interrupt takes.
+pub fn inject(input: Input) {
+    latency::without_interrupts(|| match input {
+        Input::Scancode(byte) => keyboard::handle_scancode(byte),
+        Input::Ticks(count) => {
+            for _ in 0..count {
+                interrupts::timer_tick();
+            }
+        }
+        Input::Serial(byte) => interrupts::serial_byte(byte),
+    })
+}
+
+/// Play `script` back, all of it with interrupts off. Checked before
+/// anything is played, so a typo doesn't leave it half done.
+pub fn run(script: &str) -> KernelResult<()> {
+    for word in script.split_whitespace() {
+        Input::parse(word)?;
+    }
+    latency::without_interrupts(|| {
+        for word in script.split_whitespace() {
+            // Parsed fine above.
+            if let Ok(input) = Input::parse(word) {
+                inject(input);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Type `text` on the keyboard, as scancodes. `InvalidArgument` if
+/// there's a char no key types, before any of it is typed.
+pub fn type_text(text: &str) -> KernelResult<()> {
+    if text.chars().any(|c| c == '\0' || !c.is_ascii()) {
+        return Err(KernelError::InvalidArgument);
+    }
+    latency::without_interrupts(|| {
+        for c in text.chars() {
+            if !keyboard::press(c) {
+                return Err(KernelError::InvalidArgument);
+            }
+        }
+        Ok(())
+    })
+}
+
+fn replay_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    let args = args.trim();
+    let (word, rest) = match args.find(' ') {
+        Some(space) => (&args[..space], &args[space..]),
+        None => (args, ""),
+    };
+    match word {
+        "" => {
+            let _ = write_recording(out);
+        }
+        "record" => start_recording(),
+        "stop" => {
+            stop_recording();
+            let _ = write_recording(out);
+        }
+        "run" if !rest.trim().is_empty() => {
+            return run(rest).map_err(|error| {
+                let _ = writeln!(out, "replay: {}", error);
+                CommandFailed
+            })
+        }
+        _ => return usage(out),
+    }
+    Ok(())
+}
+
+fn usage(out: &mut dyn fmt::Write) -> CommandResult {
+    let _ = writeln!(out, "usage: replay [record|stop|run SCRIPT]");
+    Err(CommandFailed)
+}
+
+#[test_case]
+fn test_replay() {
+    use crate::line_discipline::{self, Source};
+    use crate::line_editor::LINE_LENGTH;
+
+    // Finish and throw away whatever earlier tests typed.
+    run("k1c k9c").unwrap();
+    let mut line = [0; LINE_LENGTH];
+    while line_discipline::read_line(Source::Keyboard, &mut line).is_some() {}
+
+    let ticks = crate::time::ticks();
+    run("k2a k23 kaa k17 k97 t3 k1c k9c").unwrap();
+    let len = line_discipline::read_line(Source::Keyboard, &mut line).unwrap();
+    assert_eq!(&line[..len], b"Hi");
+    assert!(crate::time::ticks() >= ticks + 3);
+
+    type_text("ls -l\n").unwrap();
+    let len = line_discipline::read_line(Source::Keyboard, &mut line).unwrap();
+    assert_eq!(&line[..len], b"ls -l");
+
+    assert_eq!(run("k1c x1"), Err(KernelError::InvalidArgument));
+    assert_eq!(Input::parse("t12"), Ok(Input::Ticks(12)));
+    // Nothing of a bad script gets played.
+    assert_eq!(
+        line_discipline::read_line(Source::Keyboard, &mut line),
+        None
+    );
+}
@@ -0,0 +1,714 @@
+//! Kernel threads, switched between round robin.
+//!
+//! `spawn` starts a closure on a stack of its own, and it runs when
+//! whatever is running calls `yield_now`, until it calls `yield_now`
+//! itself, and so on round the ready queue. A thread that doesn't
+//! yield gets preempted once it has had `TIME_SLICE_TICKS` timer
+//! ticks, and goes to the back of the queue like it had yielded.
+//! Code that mustn't be switched away from in the middle, like a
+//! spin lock held without interrupts off that other threads want, runs
+//! in `without_preemption`.
+//!
+//! Threads, not tasks, to keep them apart from `task`'s futures, which
+//! share one stack. The code that booted is a thread too, the only one
+//! without a stack of its own, and the only one that can't `exit`. The
+//! idle loop yields to the others before it halts.
+//!
+//! A switch pushes the callee-saved registers and `rflags` (so every
+//! thread keeps its own interrupt flag) onto the stack being left and
+//! pops them off the one being switched to. A new stack is set up to
+//! look like it was switched away from just before `thread_start`.
+//! Preempting is the same switch, from the end of the timer handler:
+//! the CPU has pushed `rip`, `rsp` and `rflags` by then and the
+//! handler every scratch register, so with the rest pushed by the
+//! switch all of the thread's registers are on its stack, and come
+//! back off it with the `iretq` once it's switched back to. There's no
+//! SSE state to save, the kernel is built without it.
+//!
+//! A thread waiting for something calls `block`, which takes it off
+//! the ready queue until whoever it's waiting on calls `unblock`. An
+//! `unblock` that comes before the `block` isn't lost, the `block`
+//! returns straight away, so checking for what's being waited on and
+//! then blocking doesn't need a lock held across both. With every
+//! thread blocked, the one that blocked last idles until an interrupt
+//! handler unblocks something.
+//!
+//! `for_each_thread` lists them, for `threads`, `ps` and crash dumps.
+//!
+//! Stacks come from the heap, with the page below each one unmapped
+//! as a guard page while it's in use. Their bottoms are registered
+//! with `stack_canary`, for what jumps the guard page, and checked on
+//! every switch away as well as on timer ticks.
+use crate::error::{KernelError, KernelResult};
+use crate::gdt;
+use crate::memory::paging;
+use crate::shell::{self, CommandFailed, CommandResult};
+use crate::stack_canary;
+use crate::ui::{Column, Table};
+use crate::{idle, latency};
+use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::fmt;
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::{Page, PhysFrame};
+use x86_64::VirtAddr;
+
+pub const STACK_SIZE: usize = 16 * 1024;
+/// How long a thread runs before it's preempted, about 110 ms.
+pub const TIME_SLICE_TICKS: u64 = 2;
+const GUARD_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The thread that booted.
+pub const BOOT_THREAD: ThreadId = ThreadId(0);
+
+struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    /// `None` for the boot thread, which has the bootloader's.
+    stack: Option<Stack>,
+    /// Where its registers are, while it isn't running.
+    rsp: u64,
+    /// What it runs, until it's started.
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    /// How deep in `without_preemption` it is, while it isn't running.
+    preempt_disabled: usize,
+    /// Its `rsp0`, while it isn't running, see `usermode`.
+    kernel_stack: VirtAddr,
+    /// Unblocked while it wasn't blocked, so its next `block` returns.
+    wake_pending: bool,
+}
+
+/// `STACK_SIZE` bytes from the heap, after a guard page.
+struct Stack {
+    /// Where the guard page is, the start of the allocation.
+    start: *mut u8,
+    /// The guard page's frame while it's unmapped, to put back before
+    /// the heap has the page again. `None` without page tables.
+    guard: Option<PhysFrame>,
+}
+
+// Only ever touched by the thread it's for, and by the scheduler once
+// that thread has finished.
+unsafe impl Send for Stack {}
+
+impl Stack {
+    fn layout() -> Layout {
+        Layout::from_size_align(GUARD_SIZE + STACK_SIZE, GUARD_SIZE).expect("stack layout")
+    }
+
+    /// A stack for the thread called `name`, canary and all.
+    fn new(name: &'static str) -> Stack {
+        let start = unsafe { alloc_zeroed(Stack::layout()) };
+        if start.is_null() {
+            handle_alloc_error(Stack::layout());
+        }
+        let page = Page::containing_address(VirtAddr::from_ptr(start));
+        let guard = unsafe { paging::unmap(page) }.ok();
+        let stack = Stack { start, guard };
+        if let Err(error) = unsafe { stack_canary::register(name, stack.bottom()) } {
+            crate::klog!(Warn, "no canary for thread {}'s stack: {}", name, error);
+        }
+        stack
+    }
+
+    /// The lowest address of the stack itself, where the canary is.
+    fn bottom(&self) -> VirtAddr {
+        VirtAddr::from_ptr(self.start) + GUARD_SIZE
+    }
+
+    fn words(&mut self) -> &mut [u64] {
+        let bottom = self.bottom().as_mut_ptr::<u64>();
+        unsafe { core::slice::from_raw_parts_mut(bottom, STACK_SIZE / 8) }
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        let _ = stack_canary::unregister(self.bottom());
+        if let Some(frame) = self.guard {
+            let page = Page::containing_address(VirtAddr::from_ptr(self.start));
+            unsafe { paging::map_to(page, frame, paging::data_flags(true)) }
+                .expect("guard page back");
+        }
+        unsafe { dealloc(self.start, Stack::layout()) };
+    }
+}
+
+impl Thread {
+    fn check_stack(&self) {
+        if let Some(stack) = &self.stack {
+            stack_canary::check(stack.bottom());
+        }
+    }
+}
+
+struct Scheduler {
+    current: Box<Thread>,
+    ready: VecDeque<Box<Thread>>,
+    /// Waiting for an `unblock`.
+    blocked: Vec<Box<Thread>>,
+    /// Exited, and freed once we're off its stack.
+    finished: Option<Box<Thread>>,
+}
+
+/// `None` while the boot thread is the only one.
+static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+/// How deep in `without_preemption` the running thread is. Swapped
+/// with the thread's own count on a switch, so yielding inside is fine.
+static PREEMPT_DISABLED: AtomicUsize = AtomicUsize::new(0);
+/// Ticks left of the running thread's time slice.
+static SLICE_LEFT: AtomicU64 = AtomicU64::new(TIME_SLICE_TICKS);
+static PREEMPTIONS: AtomicU64 = AtomicU64::new(0);
+static SWITCHES: AtomicU64 = AtomicU64::new(0);
+/// The boot thread's `wake_pending`, while there's no `SCHEDULER`.
+static BOOT_WAKE: AtomicBool = AtomicBool::new(false);
+
+/// What happens to the thread a switch leaves.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Leaving {
+    Ready,
+    Blocked,
+    Exited,
+}
+
+extern "C" {
+    /// Save the registers on the current stack and its `rsp` in `old`,
+    /// then carry on with the registers on the stack at `new`.
+    fn switch_stacks(old: *mut u64, new: u64);
+}
+
+global_asm!(
+    "
+    .intel_syntax noprefix
+    .global switch_stacks
+    switch_stacks:
+        push rbp
+        push rbx
+        push r12
+        push r13
+        push r14
+        push r15
+        pushfq
+        mov [rdi], rsp
+        mov rsp, rsi
+        popfq
+        pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop rbx
+        pop rbp
+        ret
+    .att_syntax
+    "
+);
+
+/// Free what's finished, and forget about the boot thread once it's
+/// all there is again. Only once we're off the finished one's stack.
+fn clean_up(scheduler: &mut Option<Scheduler>) {
+    if let Some(state) = scheduler {
+        state.finished = None;
+        if state.ready.is_empty() && state.blocked.is_empty() && state.current.id == BOOT_THREAD {
+            BOOT_WAKE.store(state.current.wake_pending, Ordering::Relaxed);
+            *scheduler = None;
+        }
+    }
+}
+
+extern "C" fn thread_start() -> ! {
+    // Switched to with interrupts off, like every switch.
+    let entry = {
+        let mut scheduler = SCHEDULER.lock();
+        clean_up(&mut scheduler);
+        scheduler
+            .as_mut()
+            .and_then(|state| state.current.entry.take())
+    };
+    x86_64::instructions::interrupts::enable();
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit()
+}
+
+/// Start `entry` on a new thread, which runs once the threads ahead of
+/// it in the queue have yielded.
+pub fn spawn(name: &'static str, entry: impl FnOnce() + Send + 'static) -> ThreadId {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+    let id = ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    let mut stack = Stack::new(name);
+    // What `switch_stacks` pops: rflags with interrupts off, the six
+    // registers, then `thread_start` to return into. Above that a
+    // return address for `thread_start` it never uses, which also
+    // leaves the stack aligned the way a call would, the top being
+    // page aligned.
+    let words = stack.words();
+    let top = words.len();
+    words[top - 1] = 0;
+    words[top - 2] = thread_start as usize as u64;
+    words[top - 9] = 0x2;
+    let rsp = &words[top - 9] as *const u64 as u64;
+    let thread = Box::new(Thread {
+        id,
+        name,
+        stack: Some(stack),
+        rsp,
+        entry: Some(Box::new(entry)),
+        preempt_disabled: 0,
+        kernel_stack: VirtAddr::zero(),
+        wake_pending: false,
+    });
+    latency::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let state = scheduler.get_or_insert_with(|| Scheduler {
+            current: Box::new(Thread {
+                id: BOOT_THREAD,
+                name: "boot",
+                stack: None,
+                rsp: 0,
+                entry: None,
+                preempt_disabled: 0,
+                kernel_stack: VirtAddr::zero(),
+                wake_pending: BOOT_WAKE.swap(false, Ordering::Relaxed),
+            }),
+            ready: VecDeque::new(),
+            blocked: Vec::new(),
+            finished: None,
+        });
+        state.ready.push_back(thread);
+    });
+    id
+}
+
+/// Whether it's done: switched away and back, or not blocking because
+/// of a pending wake. Not if there was nothing else to run.
+fn switch(leaving: Leaving) -> bool {
+    latency::without_interrupts(|| {
+        let (old, new) = {
+            let mut scheduler = SCHEDULER.lock();
+            let current = scheduler
+                .as_ref()
+                .map_or(BOOT_THREAD, |state| state.current.id);
+            assert!(
+                leaving != Leaving::Exited || current != BOOT_THREAD,
+                "the boot thread can't exit"
+            );
+            if leaving == Leaving::Blocked {
+                let woken = match scheduler.as_mut() {
+                    Some(state) => mem::replace(&mut state.current.wake_pending, false),
+                    None => BOOT_WAKE.swap(false, Ordering::Relaxed),
+                };
+                if woken {
+                    return true;
+                }
+            }
+            let state = match scheduler.as_mut() {
+                Some(state) if !state.ready.is_empty() => state,
+                _ => return false,
+            };
+            let next = state.ready.pop_front().unwrap();
+            SWITCHES.fetch_add(1, Ordering::Relaxed);
+            let mut previous = mem::replace(&mut state.current, next);
+            previous.check_stack();
+            previous.preempt_disabled =
+                PREEMPT_DISABLED.swap(state.current.preempt_disabled, Ordering::Relaxed);
+            SLICE_LEFT.store(TIME_SLICE_TICKS, Ordering::Relaxed);
+            previous.kernel_stack = gdt::kernel_stack();
+            unsafe { gdt::set_kernel_stack(state.current.kernel_stack) };
+            // It's boxed, so this stays put wherever it's moved.
+            let old: *mut u64 = &mut previous.rsp;
+            match leaving {
+                Leaving::Ready => state.ready.push_back(previous),
+                Leaving::Blocked => state.blocked.push(previous),
+                Leaving::Exited => state.finished = Some(previous),
+            }
+            (old, state.current.rsp)
+        };
+        unsafe { switch_stacks(old, new) };
+        clean_up(&mut SCHEDULER.lock());
+        true
+    })
+}
+
+/// Whether the current thread has been unblocked or another is ready,
+/// for idling until one of them is. Interrupts have to be off.
+fn anything_to_do() -> bool {
+    match SCHEDULER.lock().as_ref() {
+        Some(state) => state.current.wake_pending || !state.ready.is_empty(),
+        None => BOOT_WAKE.load(Ordering::Relaxed),
+    }
+}
+
+/// Let the next ready thread run, and carry on once everything else
+/// has had a go. Does nothing if there aren't any other threads.
+pub fn yield_now() {
+    switch(Leaving::Ready);
+}
+
+/// Stop running until something calls `unblock` for this thread, or
+/// return straight away if something has since it last blocked. It can
+/// come back without whatever it's waiting for having happened, so
+/// check again and block again.
+pub fn block() {
+    while !switch(Leaving::Blocked) {
+        // Nothing else to run.
+        idle::idle_unless(anything_to_do);
+    }
+}
+
+/// Make `thread` ready to run again if it's blocked, or have its next
+/// `block` come straight back if it isn't. Fine from an interrupt
+/// handler.
+pub fn unblock(thread: ThreadId) {
+    latency::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let state = match scheduler.as_mut() {
+            Some(state) => state,
+            None => {
+                if thread == BOOT_THREAD {
+                    BOOT_WAKE.store(true, Ordering::Relaxed);
+                }
+                return;
+            }
+        };
+        if let Some(index) = state.blocked.iter().position(|t| t.id == thread) {
+            let woken = state.blocked.swap_remove(index);
+            state.ready.push_back(woken);
+        } else if state.current.id == thread {
+            state.current.wake_pending = true;
+        } else if let Some(ready) = state.ready.iter_mut().find(|t| t.id == thread) {
+            ready.wake_pending = true;
+        }
+    })
+}
+
+/// Called last thing by the timer handler, after the EOI: count the
+/// tick against the running thread's slice, and switch to the next
+/// ready one if it's used up. Not from inside another handler, or in
+/// `without_preemption`, that waits for the next tick after.
+pub(crate) fn preempt() {
+    if crate::interrupts::in_interrupt() || PREEMPT_DISABLED.load(Ordering::Relaxed) != 0 {
+        return;
+    }
+    if SLICE_LEFT.load(Ordering::Relaxed) > 1 {
+        SLICE_LEFT.fetch_sub(1, Ordering::Relaxed);
+        return;
+    }
+    let ready = match SCHEDULER.try_lock() {
+        Some(scheduler) => scheduler
+            .as_ref()
+            .map_or(false, |state| !state.ready.is_empty()),
+        None => false,
+    };
+    if !ready {
+        // Nothing else to run, it can keep going.
+        SLICE_LEFT.store(TIME_SLICE_TICKS, Ordering::Relaxed);
+        return;
+    }
+    PREEMPTIONS.fetch_add(1, Ordering::Relaxed);
+    crate::trace_event!(Scheduler, "preempting thread {}", current().0);
+    switch(Leaving::Ready);
+}
+
+/// Run `f` without being preempted. Interrupts still come in, and
+/// `yield_now` still switches, unlike `latency::without_interrupts`.
+/// Nests.
+pub fn without_preemption<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    PREEMPT_DISABLED.fetch_add(1, Ordering::Relaxed);
+    let result = f();
+    PREEMPT_DISABLED.fetch_sub(1, Ordering::Relaxed);
+    result
+}
+
+/// Times a thread was switched away from by the timer.
+pub fn preemptions() -> u64 {
+    PREEMPTIONS.load(Ordering::Relaxed)
+}
+
+/// Times one thread was switched to another, for any reason.
+pub fn switches() -> u64 {
+    SWITCHES.load(Ordering::Relaxed)
+}
+
+/// Stop the current thread for good. Returning from its closure does
+/// the same.
+pub fn exit() -> ! {
+    while !switch(Leaving::Exited) {
+        // Everything else is blocked, nothing to switch to yet.
+        idle::idle_unless(|| {
+            SCHEDULER
+                .lock()
+                .as_ref()
+                .map_or(false, |state| !state.ready.is_empty())
+        });
+    }
+    unreachable!("an exited thread was switched back to")
+}
+
+/// The thread that's running.
+pub fn current() -> ThreadId {
+    latency::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_ref()
+            .map_or(BOOT_THREAD, |state| state.current.id)
+    })
+}
+
+/// Threads waiting for a turn.
+pub fn ready_count() -> usize {
+    latency::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_ref()
+            .map_or(0, |state| state.ready.len())
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    Ready,
+    Blocked,
+}
+
+impl State {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            State::Running => "running",
+            State::Ready => "ready",
+            State::Blocked => "blocked",
+        }
+    }
+}
+
+/// A thread, as `for_each_thread` shows it.
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: &'static str,
+    pub state: State,
+    /// In bytes, 0 for the boot thread's.
+    pub stack: usize,
+}
+
+/// Call `f` with every thread, the running one first. It's called with
+/// the scheduler locked and interrupts off, so it shouldn't take long.
+/// `Busy` if the scheduler was locked already, for a panic or an
+/// exception that came in while it was.
+pub fn for_each_thread(mut f: impl FnMut(&ThreadInfo)) -> KernelResult<()> {
+    latency::without_interrupts(|| {
+        let scheduler = SCHEDULER.try_lock().ok_or(KernelError::Busy)?;
+        let state = match scheduler.as_ref() {
+            Some(state) => state,
+            None => {
+                f(&ThreadInfo {
+                    id: BOOT_THREAD,
+                    name: "boot",
+                    state: State::Running,
+                    stack: 0,
+                });
+                return Ok(());
+            }
+        };
+        let threads = Some((&state.current, State::Running))
+            .into_iter()
+            .chain(state.ready.iter().map(|thread| (thread, State::Ready)))
+            .chain(state.blocked.iter().map(|thread| (thread, State::Blocked)));
+        for (thread, state) in threads {
+            f(&ThreadInfo {
+                id: thread.id,
+                name: thread.name,
+                state,
+                stack: thread.stack.as_ref().map_or(0, |_| STACK_SIZE),
+            });
+        }
+        Ok(())
+    })
+}
+
+/// Adds the `threads` shell command.
+pub fn init() {
+    shell::register("threads", "threads: list kernel threads", threads_command)
+        .expect("threads command");
+}
+
+fn threads_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    if !args.is_empty() {
+        let _ = writeln!(out, "usage: threads");
+        return Err(CommandFailed);
+    }
+    // Copied out first, printing could take a while.
+    let mut rows = [None; 16];
+    let mut total = 0;
+    let listed = for_each_thread(|thread| {
+        if let Some(slot) = rows.get_mut(total) {
+            *slot = Some(*thread);
+        }
+        total += 1;
+    });
+    if listed.is_err() {
+        let _ = writeln!(out, "threads: the scheduler is busy");
+        return Err(CommandFailed);
+    }
+    const COLUMNS: [Column; 4] = [
+        Column::right("id", 4),
+        Column::left("name", 16),
+        Column::left("state", 8),
+        Column::right("stack", 8),
+    ];
+    let table = Table::new(&COLUMNS);
+    let _ = table.header(out);
+    for row in rows.iter().flatten() {
+        let stack: &dyn fmt::Display = if row.stack == 0 { &"boot" } else { &row.stack };
+        let _ = table.row(out, &[&row.id, &row.name, &row.state.as_str(), stack]);
+    }
+    let _ = table.end(out);
+    if total > rows.len() {
+        let _ = writeln!(out, "and {} more", total - rows.len());
+    }
+    let _ = writeln!(
+        out,
+        "{} preemptions, {} tick time slices",
+        preemptions(),
+        TIME_SLICE_TICKS
+    );
+    Ok(())
+}
+
+#[test_case]
+fn test_stack_has_guard_page() {
+    use crate::memory;
+
+    let stack = Stack::new("guard test");
+    let guard = stack.bottom() - GUARD_SIZE as u64;
+    if memory::physical_memory_offset().is_some() {
+        assert!(memory::translate(guard).is_none());
+        assert!(memory::translate(stack.bottom()).is_some());
+    }
+    stack_canary::check(stack.bottom());
+    drop(stack);
+    if memory::physical_memory_offset().is_some() {
+        assert!(memory::translate(guard).is_some());
+    }
+}
+
+#[test_case]
+fn test_threads_take_turns() {
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+
+    let log = Arc::new(Mutex::new(Vec::new()));
+    for &name in ["a", "b"].iter() {
+        let log = log.clone();
+        // Or a tick could put them out of turn.
+        spawn(name, move || {
+            without_preemption(|| {
+                for step in 0..3 {
+                    latency::without_interrupts(|| log.lock().push((name, step, current())));
+                    yield_now();
+                }
+            })
+        });
+    }
+    while ready_count() > 0 {
+        yield_now();
+    }
+    assert_eq!(current(), BOOT_THREAD);
+    let log = latency::without_interrupts(|| log.lock().clone());
+    let order: Vec<_> = log.iter().map(|&(name, step, _)| (name, step)).collect();
+    assert_eq!(
+        order,
+        [("a", 0), ("b", 0), ("a", 1), ("b", 1), ("a", 2), ("b", 2)]
+    );
+    // Each on its own thread.
+    assert_ne!(log[0].2, log[1].2);
+    assert_eq!(log[0].2, log[2].2);
+    // Everything's freed, and the boot thread is on its own again.
+    assert!(SCHEDULER.lock().is_none());
+}
+
+#[test_case]
+fn test_spinning_thread_is_preempted() {
+    use core::sync::atomic::{spin_loop_hint, AtomicBool};
+
+    static RAN: AtomicBool = AtomicBool::new(false);
+    static STOP: AtomicBool = AtomicBool::new(false);
+    // Spins for a few slices, without yielding either.
+    let spin = || {
+        let deadline = crate::time::ticks() + 4 * TIME_SLICE_TICKS;
+        while !RAN.load(Ordering::SeqCst) && crate::time::ticks() < deadline {
+            spin_loop_hint();
+        }
+        RAN.load(Ordering::SeqCst)
+    };
+    spawn("spinner", || {
+        RAN.store(true, Ordering::SeqCst);
+        while !STOP.load(Ordering::SeqCst) {
+            spin_loop_hint();
+        }
+    });
+    let ran_early = without_preemption(spin);
+    let ran = spin();
+    STOP.store(true, Ordering::SeqCst);
+    while ready_count() > 0 {
+        yield_now();
+    }
+    assert!(!ran_early, "preempted inside without_preemption");
+    assert!(ran, "never preempted");
+}
+
+#[test_case]
+fn test_block_and_unblock() {
+    use alloc::sync::Arc;
+
+    let done = Arc::new(AtomicBool::new(false));
+    let thread = {
+        let done = done.clone();
+        spawn("blocker", move || {
+            block();
+            done.store(true, Ordering::SeqCst);
+        })
+    };
+    without_preemption(|| {
+        yield_now();
+        // Blocked, so not back in the queue.
+        assert_eq!(ready_count(), 0);
+        assert!(!done.load(Ordering::SeqCst));
+        let mut states = Vec::new();
+        for_each_thread(|thread| states.push((thread.id, thread.state))).unwrap();
+        assert_eq!(
+            states,
+            [(BOOT_THREAD, State::Running), (thread, State::Blocked)]
+        );
+        unblock(thread);
+        assert_eq!(ready_count(), 1);
+    });
+    while ready_count() > 0 {
+        yield_now();
+    }
+    assert!(done.load(Ordering::SeqCst));
+
+    // Unblocked first, so blocking doesn't wait.
+    unblock(current());
+    block();
+    assert!(SCHEDULER.lock().is_none());
+}
@@ -0,0 +1,207 @@
+//! A quick check of the basics on whatever machine we booted on.
+//!
+//! The real test suite only runs under QEMU as it relies on the exit
+//! device, so it can't tell us anything about a physical machine.
+//! Building with `--features selftest` makes the kernel run these
+//! checks right after `init` and print a pass/fail report to the
+//! screen and serial instead.
+use crate::allocator::heap_free;
+use crate::boot_timing::read_tsc;
+use crate::interrupts::{BREAKPOINT_COUNTER, COUNTERS, TIMER_COUNTER};
+use x86_64::instructions::port::Port;
+use x86_64::structures::DescriptorTablePointer;
+
+/// How long we give hardware to respond, in TSC cycles. That's
+/// somewhere around a second on anything we'd boot on.
+const TIMEOUT_CYCLES: u64 = 1 << 31;
+
+/// How a single check went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    Fail(&'static str),
+    /// The check can't run on this kernel (yet), with why.
+    Skip(&'static str),
+}
+
+pub struct Check {
+    pub name: &'static str,
+    pub run: fn() -> Outcome,
+}
+
+pub const CHECKS: &[Check] = &[
+    Check {
+        name: "idt",
+        run: check_idt,
+    },
+    Check {
+        name: "heap",
+        run: check_heap,
+    },
+    Check {
+        name: "timer",
+        run: check_timer,
+    },
+    Check {
+        name: "keyboard controller",
+        run: check_keyboard_controller,
+    },
+];
+
+/// Print to the screen and serial, or just serial without a screen
+/// (`println!` goes there too then).
+macro_rules! report {
+    ($($arg:tt)*) => {{
+        #[cfg(not(feature = "no-vga"))]
+        crate::println!($($arg)*);
+        crate::serial_println!($($arg)*);
+    }};
+}
+
+/// Run every check and print the report. Returns whether none failed.
+pub fn run() -> bool {
+    report!("selftest:");
+
+    let mut failed = 0;
+    for check in CHECKS {
+        let outcome = (check.run)();
+        let (result, detail) = match outcome {
+            Outcome::Pass => ("pass", ""),
+            Outcome::Fail(why) => ("FAIL", why),
+            Outcome::Skip(why) => ("skip", why),
+        };
+        if let Outcome::Fail(_) = outcome {
+            failed += 1;
+        }
+        report!("  {:<20} {} {}", check.name, result, detail);
+    }
+
+    let verdict = if failed == 0 { "passed" } else { "FAILED" };
+    report!(
+        "selftest {}, {} of {} failed",
+        verdict,
+        failed,
+        CHECKS.len()
+    );
+    failed == 0
+}
+
+/// The loaded IDT has room for every vector and a breakpoint
+/// actually reaches our handler.
+fn check_idt() -> Outcome {
+    let mut pointer = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe {
+        asm!("sidt [{}]", in(reg) &mut pointer, options(nostack));
+    }
+    // 256 entries of 16 bytes
+    if pointer.limit != 256 * 16 - 1 {
+        return Outcome::Fail("IDT is not the full 256 entries");
+    }
+
+    let before = COUNTERS[BREAKPOINT_COUNTER].count();
+    x86_64::instructions::interrupts::int3();
+    if COUNTERS[BREAKPOINT_COUNTER].count() != before + 1 {
+        return Outcome::Fail("breakpoint handler did not run");
+    }
+    Outcome::Pass
+}
+
+/// What's written to memory from the heap reads back, and freeing it
+/// gives it all back.
+fn check_heap() -> Outcome {
+    use alloc::alloc::{alloc, dealloc, Layout};
+
+    const SIZE: usize = 4096;
+    let free = heap_free();
+    if free == 0 {
+        return Outcome::Skip("the heap didn't get mapped");
+    }
+    let layout = Layout::from_size_align(SIZE, 16).unwrap();
+    let intact = unsafe {
+        let block = alloc(layout);
+        if block.is_null() {
+            return Outcome::Fail("allocating 4 KiB failed");
+        }
+        for i in 0..SIZE {
+            block.add(i).write_volatile(i as u8);
+        }
+        let intact = (0..SIZE).all(|i| block.add(i).read_volatile() == i as u8);
+        dealloc(block, layout);
+        intact
+    };
+    if !intact {
+        return Outcome::Fail("heap memory didn't read back what was written");
+    }
+    if heap_free() != free {
+        return Outcome::Fail("freeing didn't give it all back");
+    }
+    Outcome::Pass
+}
+
+/// Timer interrupts are arriving, i.e. the PIC is set up and
+/// interrupts are on.
+fn check_timer() -> Outcome {
+    let before = COUNTERS[TIMER_COUNTER].count();
+    let start = read_tsc();
+    // Two ticks, as the first could have been on its way already.
+    while COUNTERS[TIMER_COUNTER].count() < before + 2 {
+        if read_tsc() - start > TIMEOUT_CYCLES {
+            return Outcome::Fail("no timer ticks");
+        }
+        core::sync::atomic::spin_loop_hint();
+    }
+    Outcome::Pass
+}
+
+/// There is an 8042 that answers commands.
+///
+/// Only reads the configuration byte, so it doesn't disturb whatever
+/// state the firmware left the controller in.
+fn check_keyboard_controller() -> Outcome {
+    const OUTPUT_FULL: u8 = 1;
+    const INPUT_FULL: u8 = 1 << 1;
+    const READ_CONFIGURATION: u8 = 0x20;
+
+    let mut data = Port::<u8>::new(0x60);
+    let mut status = Port::<u8>::new(0x64);
+
+    let wait = |status: &mut Port<u8>, ready: &dyn Fn(u8) -> bool| {
+        let start = read_tsc();
+        while !ready(unsafe { status.read() }) {
+            if read_tsc() - start > TIMEOUT_CYCLES {
+                return false;
+            }
+        }
+        true
+    };
+
+    // Nothing decodes the port, so the bus floats high.
+    if unsafe { status.read() } == 0xFF {
+        return Outcome::Fail("no controller at 0x64");
+    }
+    // Don't let the keyboard interrupt (if anything unmasks it) or a
+    // stale byte get mixed up with the answer.
+    crate::latency::without_interrupts(|| unsafe {
+        while status.read() & OUTPUT_FULL != 0 {
+            data.read();
+        }
+        if !wait(&mut status, &|s| s & INPUT_FULL == 0) {
+            return Outcome::Fail("controller never ready for commands");
+        }
+        status.write(READ_CONFIGURATION);
+        if !wait(&mut status, &|s| s & OUTPUT_FULL != 0) {
+            return Outcome::Fail("no answer to read configuration");
+        }
+        data.read();
+        Outcome::Pass
+    })
+}
+
+#[test_case]
+fn test_checks_pass_in_qemu() {
+    for check in CHECKS {
+        if let Outcome::Fail(why) = (check.run)() {
+            panic!("{} failed: {}", check.name, why);
+        }
+    }
+}
@@ -1,21 +1,334 @@
-use uart_16550::SerialPort; // Get serial port struct
+//! COM1, for printing to the host and reading what it sends.
+//!
+//! Sending can wait on the other end with flow control, for real
+//! serial links that can't keep up with a crash dump. It's off by
+//! default, QEMU takes whatever we send, and is set with `serial`:
+//!
+//! - `xon`: the other end sends XOFF to make us stop and XON to go on.
+//!   The interrupt handler picks those out of the input, or, with
+//!   interrupts off, sending polls for them and drops whatever else
+//!   comes in meanwhile.
+//! - `rts`: we only send while the other end holds CTS. We always
+//!   hold RTS, there's nothing reading fast enough to need to stop.
+//!
+//! If the other end doesn't let us go on within `FLOW_TIMEOUT_CYCLES`
+//! we send anyway, and keep sending without waiting until it does, so
+//! a pulled cable doesn't hang the kernel one byte at a time.
+//!
+//! `xfer` sends files over it with XMODEM or YMODEM.
+use crate::boot_timing::read_tsc;
+use crate::shell::{self, CommandFailed, CommandResult};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use lazy_static::lazy_static;
 use spin::Mutex; // used to make this thread safe.
-use lazy_static::lazy_static; // make sure we only make one serial port if we use it
+use uart_16550::SerialPort; // Get serial port struct // make sure we only make one serial port if we use it
+use x86_64::instructions::port::Port;
+
+pub mod xfer;
+
+/// IO port base of the first serial port (COM1).
+pub const COM1: u16 = 0x3F8;
+
+/// About a second, how long sending waits for the other end.
+pub const FLOW_TIMEOUT_CYCLES: u64 = 1 << 31;
+
+const XON: u8 = 0x11;
+const XOFF: u8 = 0x13;
+/// Line status register bits.
+const DATA_READY: u8 = 1;
+const BREAK_INTERRUPT: u8 = 1 << 4;
+const OUTPUT_EMPTY: u8 = 1 << 5;
+/// Modem status register bit.
+const CLEAR_TO_SEND: u8 = 1 << 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FlowControl {
+    None,
+    /// XON/XOFF.
+    Software,
+    /// RTS/CTS.
+    Hardware,
+}
+
+impl FlowControl {
+    const ALL: [FlowControl; 3] = [
+        FlowControl::None,
+        FlowControl::Software,
+        FlowControl::Hardware,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FlowControl::None => "none",
+            FlowControl::Software => "xon",
+            FlowControl::Hardware => "rts",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<FlowControl> {
+        FlowControl::ALL
+            .iter()
+            .copied()
+            .find(|flow| flow.name() == name)
+    }
+}
+
+static FLOW_CONTROL: AtomicU8 = AtomicU8::new(FlowControl::None as u8);
+/// The other end sent XOFF.
+static PAUSED: AtomicBool = AtomicBool::new(false);
+/// Gave up waiting, don't wait again until the other end is ready.
+static STALLED: AtomicBool = AtomicBool::new(false);
+static STALLS: AtomicU64 = AtomicU64::new(0);
 
 lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
         // Connect to the common serial port at 0x3F8
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
         Mutex::new(serial_port)
     };
 }
 
+/// Adds the `serial` shell command.
+pub fn init() {
+    shell::register(
+        "serial",
+        "serial [flow none|xon|rts]: flow control on COM1",
+        serial_command,
+    )
+    .expect("serial command");
+    xfer::init();
+}
+
+pub fn flow_control() -> FlowControl {
+    FlowControl::ALL[usize::from(FLOW_CONTROL.load(Ordering::Relaxed))]
+}
+
+pub fn set_flow_control(flow: FlowControl) {
+    PAUSED.store(false, Ordering::SeqCst);
+    STALLED.store(false, Ordering::SeqCst);
+    FLOW_CONTROL.store(flow as u8, Ordering::Relaxed);
+}
+
+/// Times sending gave up waiting for the other end.
+pub fn stalls() -> u64 {
+    STALLS.load(Ordering::Relaxed)
+}
+
+/// Whether `byte` that came in was XON or XOFF meant for us, rather
+/// than input.
+pub fn handle_flow_byte(byte: u8) -> bool {
+    if flow_control() != FlowControl::Software {
+        return false;
+    }
+    match byte {
+        XON => PAUSED.store(false, Ordering::SeqCst),
+        XOFF => PAUSED.store(true, Ordering::SeqCst),
+        _ => return false,
+    }
+    true
+}
+
+/// Whether the other end lets us send.
+fn ready_to_send() -> bool {
+    let ready = match flow_control() {
+        FlowControl::None => true,
+        FlowControl::Software => {
+            // Nothing else is going to see the XON.
+            if !x86_64::instructions::interrupts::are_enabled() {
+                while let Some(Received::Byte(byte)) = receive_raw() {
+                    handle_flow_byte(byte);
+                }
+            }
+            !PAUSED.load(Ordering::SeqCst)
+        }
+        FlowControl::Hardware => {
+            let mut modem_status = Port::<u8>::new(COM1 + 6);
+            let status = unsafe { modem_status.read() };
+            status & CLEAR_TO_SEND != 0
+        }
+    };
+    if ready {
+        STALLED.store(false, Ordering::SeqCst);
+    }
+    ready || STALLED.load(Ordering::SeqCst)
+}
+
+/// Send one byte once flow control and the UART let us.
+pub(crate) fn transmit(byte: u8) {
+    let start = read_tsc();
+    while !ready_to_send() {
+        if read_tsc() - start > FLOW_TIMEOUT_CYCLES {
+            STALLED.store(true, Ordering::SeqCst);
+            STALLS.fetch_add(1, Ordering::Relaxed);
+            break;
+        }
+        core::sync::atomic::spin_loop_hint();
+    }
+    let mut line_status = Port::<u8>::new(COM1 + 5);
+    let mut data = Port::<u8>::new(COM1);
+    unsafe {
+        while line_status.read() & OUTPUT_EMPTY == 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+        data.write(byte);
+    }
+}
+
+/// Sends through `transmit`, whoever holds the lock.
+struct Transmitter;
+
+impl fmt::Write for Transmitter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        s.bytes().for_each(transmit);
+        Ok(())
+    }
+}
+
 /// Spin lock print and the macros
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
+    let _port = SERIAL1.lock();
+    Transmitter
+        .write_fmt(args)
+        .expect("Printing to serial failed");
+}
+
+/// How loadable modules print to the host, see
+/// `vga_buffer::module_print`.
+pub(crate) extern "C" fn module_print(text: *const u8, len: usize) {
+    _print(format_args!("{}", unsafe {
+        crate::module::text(text, len)
+    }));
+}
+
+crate::export_symbol!("serial_print", module_print);
+// With no screen, modules printing to it end up here too.
+#[cfg(feature = "no-vga")]
+crate::export_symbol!("vga_print", module_print);
+
+// Without a VGA console `print!` goes to serial instead.
+#[cfg(feature = "no-vga")]
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::serial::_print(format_args!($($arg)*)));
+}
+
+#[cfg(feature = "no-vga")]
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+// Serial has no colors, these leave them out. The color arguments
+// aren't even looked at, as there's no `vga_buffer::Color` to name.
+#[cfg(feature = "no-vga")]
+#[macro_export]
+macro_rules! print_color {
+    ($foreground:expr, $background:expr, $($arg:tt)*) => ($crate::print!($($arg)*));
+}
+
+#[cfg(feature = "no-vga")]
+#[macro_export]
+macro_rules! println_color {
+    ($foreground:expr, $background:expr) => ($crate::println!());
+    ($foreground:expr, $background:expr, $($arg:tt)*) => ($crate::println!($($arg)*));
+}
+
+/// What the UART had waiting for us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Received {
+    Byte(u8),
+    /// The line was held low for longer than a character - a "serial break".
+    Break,
+}
+
+/// Read whatever COM1 has received, without waiting and without
+/// taking the `SERIAL1` lock. Meant for interrupt handlers and the
+/// kernel monitor, which can't rely on the lock being free.
+pub fn receive_raw() -> Option<Received> {
+    let mut data = Port::<u8>::new(COM1);
+    let mut line_status = Port::<u8>::new(COM1 + 5);
+    unsafe {
+        let status = line_status.read();
+        if status & BREAK_INTERRUPT != 0 {
+            // A break also shows up as a NUL byte, throw it away
+            data.read();
+            Some(Received::Break)
+        } else if status & DATA_READY != 0 {
+            Some(Received::Byte(data.read()))
+        } else {
+            None
+        }
+    }
+}
+
+/// Writes straight to COM1 without going through the `SERIAL1` lock.
+///
+/// Only for places that can't wait on the lock (panics, the kernel
+/// monitor) as output can interleave with whoever holds it.
+pub struct RawSerial(());
+
+impl RawSerial {
+    /// The port has to have been set up by `SERIAL1` already.
+    pub fn new() -> RawSerial {
+        RawSerial(())
+    }
+}
+
+impl Default for RawSerial {
+    fn default() -> RawSerial {
+        RawSerial::new()
+    }
+}
+
+impl core::fmt::Write for RawSerial {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        Transmitter.write_str(s)
+    }
+}
+
+/// Serial output for the panic path, see `panic_writer`.
+pub enum PanicWriter {
+    Locked(spin::MutexGuard<'static, SerialPort>),
+    Raw(RawSerial),
+}
+
+impl PanicWriter {
+    /// Whether we had to go around the lock.
+    pub fn is_raw(&self) -> bool {
+        match self {
+            PanicWriter::Locked(_) => false,
+            PanicWriter::Raw(_) => true,
+        }
+    }
+}
+
+impl core::fmt::Write for PanicWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        // Holding the lock only keeps others out, sending is the same.
+        Transmitter.write_str(s)
+    }
+}
+
+/// Something to print a panic to that can't deadlock.
+///
+/// Just calling `serial_println!` would spin forever if the panic came
+/// from inside `_print` (or from an interrupt handler that interrupted
+/// it), as the lock is never going to be released. So we only use the
+/// lock when it's free and we aren't in an interrupt handler, and write
+/// to the UART directly otherwise.
+pub fn panic_writer() -> PanicWriter {
+    if !crate::interrupts::in_interrupt() {
+        if let Some(port) = SERIAL1.try_lock() {
+            return PanicWriter::Locked(port);
+        }
+    }
+    PanicWriter::Raw(RawSerial::new())
 }
 
 /// Prints to the host through the serial interface.
@@ -34,3 +347,46 @@ macro_rules! serial_println {
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
         concat!($fmt, "\n"), $($arg)*));
 }
+
+fn serial_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    let mut words = args.split_whitespace();
+    match (words.next(), words.next()) {
+        (None, _) => {}
+        (Some("flow"), Some(name)) => match FlowControl::from_name(name) {
+            Some(flow) => set_flow_control(flow),
+            None => return usage(out),
+        },
+        _ => return usage(out),
+    }
+    let _ = writeln!(
+        out,
+        "flow control {}, {} stalls",
+        flow_control().name(),
+        stalls()
+    );
+    Ok(())
+}
+
+fn usage(out: &mut dyn fmt::Write) -> CommandResult {
+    let _ = writeln!(out, "usage: serial [flow none|xon|rts]");
+    Err(CommandFailed)
+}
+
+#[test_case]
+fn test_xon_xoff() {
+    assert!(!handle_flow_byte(XOFF));
+    set_flow_control(FlowControl::Software);
+    assert!(handle_flow_byte(XOFF));
+    assert!(!ready_to_send());
+    assert!(!handle_flow_byte(b'a'));
+    assert!(handle_flow_byte(XON));
+    assert!(ready_to_send());
+    set_flow_control(FlowControl::None);
+}
+
+#[test_case]
+fn test_panic_writer_avoids_held_lock() {
+    assert!(!panic_writer().is_raw());
+    let _held = SERIAL1.lock();
+    assert!(panic_writer().is_raw());
+}
@@ -0,0 +1,397 @@
+//! Sending files over COM1 with XMODEM or YMODEM, so crash dumps and
+//! traces can be pulled off real hardware with any terminal program
+//! (`rx`/`rb` from lrzsz, minicom, Tera Term...).
+//!
+//! Only the sending side. The receiver starts things by sending `C`
+//! for CRC-16 or NAK for the old checksum, and ACKs every block.
+//!
+//! - XMODEM sends 128 byte blocks, padded out with `^Z` at the end.
+//! - YMODEM sends 1K blocks, after a block 0 with the file name, and
+//!   gives the receiver an empty block 0 at the end to say there are no
+//!   more files. There's no length in the first block 0, what we send
+//!   is written as it goes and isn't counted up front, so the receiver
+//!   keeps the padding.
+//!
+//! The data comes from a function writing to a `fmt::Write`, so a
+//! dump doesn't need to fit in memory first. The transfer runs with
+//! interrupts off, or the serial interrupt would take the receiver's
+//! ACKs for typing, and with XON/XOFF off, those bytes can be part of
+//! the data and the ACKs pace things anyway.
+use super::{flow_control, receive_raw, set_flow_control, transmit, FlowControl, Received};
+use crate::boot_timing::read_tsc;
+use crate::error::KernelError;
+use crate::latency;
+use crate::shell::{self, CommandFailed, CommandResult};
+use crate::unwind::Backtrace;
+use crate::{crash_dump, trace};
+use core::fmt;
+use core::sync::atomic::spin_loop_hint;
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+/// Asks for CRC-16 instead of the checksum.
+const CRC: u8 = b'C';
+/// What the last block gets padded out with, `^Z`.
+const PAD: u8 = 0x1A;
+
+/// Tries at each block before giving up, what XMODEM always used.
+pub const MAX_RETRIES: usize = 10;
+/// About a second, the same rough TSC guess as elsewhere.
+const SECOND_CYCLES: u64 = 1 << 31;
+/// How long the receiver gets to start, they're usually started by
+/// hand after us.
+const START_TIMEOUT_CYCLES: u64 = 60 * SECOND_CYCLES;
+const ACK_TIMEOUT_CYCLES: u64 = 10 * SECOND_CYCLES;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Xmodem,
+    Ymodem,
+}
+
+impl Protocol {
+    fn block_size(self) -> usize {
+        match self {
+            Protocol::Xmodem => 128,
+            Protocol::Ymodem => 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XferError {
+    /// The receiver never started, or stopped answering.
+    Timeout,
+    /// It sent two CANs.
+    Cancelled,
+    /// A block got NAKed `MAX_RETRIES` times.
+    TooManyRetries,
+}
+
+impl From<XferError> for KernelError {
+    fn from(error: XferError) -> KernelError {
+        match error {
+            XferError::Timeout => KernelError::Timeout,
+            XferError::Cancelled => KernelError::PermissionDenied,
+            XferError::TooManyRetries => KernelError::DeviceError,
+        }
+    }
+}
+
+/// Both ends of the line, so the protocol can be tested without one.
+trait Link {
+    fn send(&mut self, byte: u8);
+    fn receive(&mut self, timeout_cycles: u64) -> Option<u8>;
+}
+
+struct Serial;
+
+impl Link for Serial {
+    fn send(&mut self, byte: u8) {
+        transmit(byte);
+    }
+
+    fn receive(&mut self, timeout_cycles: u64) -> Option<u8> {
+        let start = read_tsc();
+        while read_tsc() - start < timeout_cycles {
+            if let Some(Received::Byte(byte)) = receive_raw() {
+                return Some(byte);
+            }
+            spin_loop_hint();
+        }
+        None
+    }
+}
+
+/// CRC-16/XMODEM: polynomial 0x1021, starting at 0.
+pub fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |crc, &byte| crc16_update(crc, byte))
+}
+
+fn crc16_update(crc: u16, byte: u8) -> u16 {
+    let mut crc = crc ^ u16::from(byte) << 8;
+    for _ in 0..8 {
+        crc = if crc & 0x8000 != 0 {
+            crc << 1 ^ 0x1021
+        } else {
+            crc << 1
+        };
+    }
+    crc
+}
+
+struct Sender<'a, L: Link> {
+    link: &'a mut L,
+    protocol: Protocol,
+    /// The receiver asked for CRC-16 rather than the checksum.
+    crc: bool,
+    block: [u8; 1024],
+    len: usize,
+    /// Number of the next data block, wraps around.
+    number: u8,
+    sent: usize,
+    error: Option<XferError>,
+}
+
+impl<'a, L: Link> Sender<'a, L> {
+    fn new(link: &'a mut L, protocol: Protocol) -> Sender<'a, L> {
+        Sender {
+            link,
+            protocol,
+            crc: false,
+            block: [0; 1024],
+            len: 0,
+            number: 1,
+            sent: 0,
+            error: None,
+        }
+    }
+
+    /// Wait for the receiver to ask for the next file.
+    fn start(&mut self) -> Result<(), XferError> {
+        let mut cancel = false;
+        loop {
+            match self.link.receive(START_TIMEOUT_CYCLES) {
+                Some(CRC) => self.crc = true,
+                Some(NAK) => self.crc = false,
+                Some(CAN) if cancel => return Err(XferError::Cancelled),
+                Some(CAN) => {
+                    cancel = true;
+                    continue;
+                }
+                Some(_) => continue,
+                None => return Err(XferError::Timeout),
+            }
+            return Ok(());
+        }
+    }
+
+    /// Send `data` as block `number`, padded out to `size` with `pad`,
+    /// until it's ACKed.
+    fn send_block(
+        &mut self,
+        number: u8,
+        data: &[u8],
+        size: usize,
+        pad: u8,
+    ) -> Result<(), XferError> {
+        for _ in 0..MAX_RETRIES {
+            self.link.send(if size == 128 { SOH } else { STX });
+            self.link.send(number);
+            self.link.send(!number);
+            let mut crc = 0u16;
+            let mut sum = 0u8;
+            for index in 0..size {
+                let byte = data.get(index).copied().unwrap_or(pad);
+                self.link.send(byte);
+                crc = crc16_update(crc, byte);
+                sum = sum.wrapping_add(byte);
+            }
+            if self.crc {
+                self.link.send((crc >> 8) as u8);
+                self.link.send(crc as u8);
+            } else {
+                self.link.send(sum);
+            }
+            match self.link.receive(ACK_TIMEOUT_CYCLES) {
+                Some(ACK) => return Ok(()),
+                Some(CAN) if self.link.receive(SECOND_CYCLES) == Some(CAN) => {
+                    return Err(XferError::Cancelled)
+                }
+                // A NAK, noise or nothing, all mean try again.
+                _ => {}
+            }
+        }
+        Err(XferError::TooManyRetries)
+    }
+
+    /// Block 0 of YMODEM, the file name. An empty one ends the batch.
+    fn send_header(&mut self, name: &str) -> Result<(), XferError> {
+        let mut header = [0u8; 128];
+        let len = name.len().min(127);
+        header[..len].copy_from_slice(&name.as_bytes()[..len]);
+        self.send_block(0, &header, 128, 0)
+    }
+
+    fn flush(&mut self) -> Result<(), XferError> {
+        if self.len == 0 {
+            return Ok(());
+        }
+        let block = self.block;
+        let size = self.protocol.block_size();
+        self.send_block(self.number, &block[..self.len], size, PAD)?;
+        self.number = self.number.wrapping_add(1);
+        self.sent += self.len;
+        self.len = 0;
+        Ok(())
+    }
+
+    fn end(&mut self) -> Result<(), XferError> {
+        self.flush()?;
+        for _ in 0..MAX_RETRIES {
+            self.link.send(EOT);
+            // YMODEM receivers NAK the first one to make sure.
+            if self.link.receive(ACK_TIMEOUT_CYCLES) == Some(ACK) {
+                return Ok(());
+            }
+        }
+        Err(XferError::TooManyRetries)
+    }
+}
+
+impl<L: Link> fmt::Write for Sender<'_, L> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.len == self.protocol.block_size() {
+                if let Err(error) = self.flush() {
+                    self.error = Some(error);
+                    return Err(fmt::Error);
+                }
+            }
+            self.block[self.len] = byte;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+fn send_over<L: Link>(
+    link: &mut L,
+    protocol: Protocol,
+    name: &str,
+    write: impl FnOnce(&mut dyn fmt::Write) -> fmt::Result,
+) -> Result<usize, XferError> {
+    let mut sender = Sender::new(link, protocol);
+    sender.start()?;
+    if protocol == Protocol::Ymodem {
+        sender.send_header(name)?;
+        // It asks again for the data.
+        sender.start()?;
+    }
+    if write(&mut sender).is_err() {
+        // Whatever went wrong on the line, or the writer gave up.
+        return Err(sender.error.unwrap_or(XferError::Cancelled));
+    }
+    sender.end()?;
+    if protocol == Protocol::Ymodem {
+        sender.start()?;
+        sender.send_header("")?;
+    }
+    Ok(sender.sent)
+}
+
+/// Send what `write` writes as file `name`, the name only goes out
+/// with YMODEM. Returns the bytes sent, not counting padding.
+pub fn send(
+    protocol: Protocol,
+    name: &str,
+    write: impl FnOnce(&mut dyn fmt::Write) -> fmt::Result,
+) -> Result<usize, XferError> {
+    latency::without_interrupts(|| {
+        let flow = flow_control();
+        if flow == FlowControl::Software {
+            set_flow_control(FlowControl::None);
+        }
+        let result = send_over(&mut Serial, protocol, name, write);
+        set_flow_control(flow);
+        result
+    })
+}
+
+/// Adds the `sz` shell command.
+pub fn init() {
+    shell::register(
+        "sz",
+        "sz crashdump|trace [xmodem]: send over serial with YMODEM",
+        sz_command,
+    )
+    .expect("sz command");
+}
+
+fn sz_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    let mut words = args.split_whitespace();
+    let what = words.next();
+    let protocol = match words.next() {
+        None => Protocol::Ymodem,
+        Some("xmodem") => Protocol::Xmodem,
+        Some(_) => return usage(out),
+    };
+    let result = match what {
+        Some("crashdump") => send(protocol, "crashdump.txt", |out| {
+            crash_dump::write(out, None, &Backtrace::capture())
+        }),
+        Some("trace") => send(protocol, "trace.txt", trace::dump),
+        _ => return usage(out),
+    };
+    match result {
+        Ok(sent) => {
+            let _ = writeln!(out, "\nsent {} bytes", sent);
+            Ok(())
+        }
+        Err(error) => {
+            let _ = writeln!(out, "\nsz: {:?}", error);
+            Err(CommandFailed)
+        }
+    }
+}
+
+fn usage(out: &mut dyn fmt::Write) -> CommandResult {
+    let _ = writeln!(out, "usage: sz crashdump|trace [xmodem]");
+    Err(CommandFailed)
+}
+
+#[test_case]
+fn test_ymodem() {
+    /// A receiver that answers with `replies` and keeps what it got.
+    struct Receiver {
+        got: [u8; 2560],
+        len: usize,
+        replies: &'static [u8],
+    }
+
+    impl Link for Receiver {
+        fn send(&mut self, byte: u8) {
+            self.got[self.len] = byte;
+            self.len += 1;
+        }
+
+        fn receive(&mut self, _timeout_cycles: u64) -> Option<u8> {
+            let (&first, rest) = self.replies.split_first()?;
+            self.replies = rest;
+            Some(first)
+        }
+    }
+
+    assert_eq!(crc16(b"123456789"), 0x31C3);
+    let mut receiver = Receiver {
+        got: [0; 2560],
+        len: 0,
+        // Start, header, start again, a NAKed block and then it
+        // again, both EOTs, and the empty header.
+        replies: &[CRC, ACK, CRC, NAK, ACK, NAK, ACK, CRC, ACK],
+    };
+    let sent = send_over(&mut receiver, Protocol::Ymodem, "dump.txt", |out| {
+        out.write_str("hi")
+    });
+    assert_eq!(sent, Ok(2));
+
+    let got = &receiver.got[..receiver.len];
+    let header = &got[..133];
+    assert_eq!(&header[..11], b"\x01\x00\xFFdump.txt\x00");
+    assert_eq!(
+        u16::from_be_bytes([header[131], header[132]]),
+        crc16(&header[3..131])
+    );
+    // Sent twice, the receiver NAKed the first.
+    let block = &got[133..133 + 1029];
+    assert_eq!(&block[..6], b"\x02\x01\xFEhi\x1A");
+    assert_eq!(block, &got[133 + 1029..133 + 2 * 1029]);
+    let rest = &got[133 + 2 * 1029..];
+    assert_eq!(&rest[..2], &[EOT, EOT]);
+    assert_eq!(&rest[2..5], b"\x01\x00\xFF");
+    assert_eq!(rest.len(), 2 + 133);
+}
@@ -0,0 +1,360 @@
+//! Commands for the kernel monitor's shell.
+//!
+//! Subsystems add their own commands while they initialise, so the
+//! monitor doesn't need to know about every one of them:
+//!
+//! ```ignore
+//! shell::register("uptime", "time since boot", uptime_command).expect("uptime command");
+//! ```
+//!
+//! Handlers run inside the monitor, i.e. in an interrupt handler with
+//! interrupts off and the rest of the kernel stopped wherever it was,
+//! so they must not wait on locks. They also run on the `shell` thread
+//! `start_console` starts, for lines typed on the keyboard or serial
+//! outside the monitor.
+//!
+//! `run PATH` executes a file of commands, one per line. `#` starts a
+//! comment, and `onerror continue` (or `stop`, the default) decides
+//! whether the rest of the file still runs after a command fails.
+use crate::error::KernelError;
+use crate::latency;
+use crate::line_discipline::{self, Output, Source};
+use crate::line_editor::LINE_LENGTH;
+use crate::scheduler::{self, State, ThreadId};
+use alloc::string::String;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// How many commands can be registered.
+pub const MAX_COMMANDS: usize = 64;
+
+/// How deep `run` can nest, a script running itself shouldn't run
+/// the stack out.
+const MAX_SCRIPT_DEPTH: usize = 4;
+
+/// A command didn't do what it was asked to. It has already printed why.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandFailed;
+
+pub type CommandResult = Result<(), CommandFailed>;
+
+/// Gets where to print and everything on the line after the command name.
+pub type Handler = fn(out: &mut dyn fmt::Write, args: &str) -> CommandResult;
+
+/// Where `run` gets files from: their whole contents by path.
+pub type FileSource = fn(path: &str) -> Option<String>;
+
+#[derive(Clone, Copy)]
+pub struct Command {
+    pub name: &'static str,
+    /// Shown by `help`, should mention the arguments if there are any.
+    pub help: &'static str,
+    pub handler: Handler,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    /// All `MAX_COMMANDS` slots are taken.
+    Full,
+    /// There already is a command with this name.
+    Duplicate,
+}
+
+impl From<RegisterError> for KernelError {
+    fn from(error: RegisterError) -> KernelError {
+        match error {
+            RegisterError::Full => KernelError::NoSpace,
+            RegisterError::Duplicate => KernelError::AlreadyExists,
+        }
+    }
+}
+
+static COMMANDS: Mutex<[Option<Command>; MAX_COMMANDS]> = Mutex::new([None; MAX_COMMANDS]);
+static FILE_SOURCE: Mutex<Option<FileSource>> = Mutex::new(None);
+static SCRIPT_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Add the commands that don't belong to any subsystem (yet).
+pub fn init() {
+    register("ps", "list tasks", ps_command).expect("ps command");
+    register(
+        "run",
+        "run PATH: execute the commands in a file",
+        run_command,
+    )
+    .expect("run command");
+}
+
+/// Let `run` read files through `source`. `fs::init` has it read them
+/// from the file systems.
+pub fn set_file_source(source: FileSource) {
+    latency::without_interrupts(|| *FILE_SOURCE.lock() = Some(source));
+}
+
+fn ps_command(out: &mut dyn fmt::Write, _args: &str) -> CommandResult {
+    // Only the running thread is on a CPU.
+    let cpu = crate::trace::current_cpu();
+    let _ = writeln!(out, "  id  cpu  state    name");
+    let listed = crate::scheduler::for_each_thread(|thread| {
+        let cpu: &dyn fmt::Display = match thread.state {
+            State::Running => &cpu,
+            _ => &"-",
+        };
+        let _ = writeln!(
+            out,
+            "{:>4}  {:>3}  {:<7}  {}",
+            thread.id,
+            cpu,
+            thread.state.as_str(),
+            thread.name
+        );
+    });
+    listed.map_err(|_| {
+        let _ = writeln!(out, "ps: the scheduler is busy");
+        CommandFailed
+    })
+}
+
+/// Make `name` run `handler` from the monitor.
+pub fn register(
+    name: &'static str,
+    help: &'static str,
+    handler: Handler,
+) -> Result<(), RegisterError> {
+    // The monitor reads the table from the serial interrupt.
+    latency::without_interrupts(|| {
+        let mut commands = COMMANDS.lock();
+        if commands
+            .iter()
+            .flatten()
+            .any(|command| command.name == name)
+        {
+            return Err(RegisterError::Duplicate);
+        }
+        let slot = commands
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(RegisterError::Full)?;
+        *slot = Some(Command {
+            name,
+            help,
+            handler,
+        });
+        Ok(())
+    })
+}
+
+/// The command called `name`, if one is registered.
+///
+/// `None` as well if the table is being changed right now, which only
+/// happens while something is registering a command.
+pub fn find(name: &str) -> Option<Command> {
+    let commands = COMMANDS.try_lock()?;
+    commands
+        .iter()
+        .flatten()
+        .find(|command| command.name == name)
+        .copied()
+}
+
+/// Call `f` with every registered command, in the order they were
+/// registered in.
+pub fn for_each(mut f: impl FnMut(&Command)) {
+    if let Some(commands) = COMMANDS.try_lock() {
+        commands.iter().flatten().for_each(|command| f(command));
+    }
+}
+
+/// Run a single command line, printing about unknown commands.
+pub fn execute(out: &mut dyn fmt::Write, line: &str) -> CommandResult {
+    let line = line.trim();
+    let name = match line.split_whitespace().next() {
+        Some(name) => name,
+        None => return Ok(()),
+    };
+    match find(name) {
+        Some(command) => (command.handler)(out, line[name.len()..].trim()),
+        None => {
+            let _ = writeln!(out, "unknown command `{}`", name);
+            Err(CommandFailed)
+        }
+    }
+}
+
+/// What the console shell prints before each line.
+pub const PROMPT: &str = "> ";
+
+/// Start a thread that runs every line typed on the keyboard or serial
+/// as a command, with what it prints going back to where it was typed.
+/// It blocks until `line_discipline` has a line for it.
+pub fn start_console() -> ThreadId {
+    scheduler::spawn("shell", || {
+        line_discipline::set_reader(scheduler::current());
+        for &source in &SOURCES {
+            let _ = write!(Output(source), "{}", PROMPT);
+        }
+        let mut line = [0; LINE_LENGTH];
+        loop {
+            let mut idle = true;
+            for &source in &SOURCES {
+                while let Some(len) = line_discipline::read_line(source, &mut line) {
+                    idle = false;
+                    let mut out = Output(source);
+                    // Only printable ASCII makes it into a line.
+                    let _ = execute(&mut out, core::str::from_utf8(&line[..len]).unwrap_or(""));
+                    let _ = write!(out, "{}", PROMPT);
+                }
+            }
+            if idle {
+                // A line finished since the last look makes this come
+                // straight back.
+                scheduler::block();
+            }
+        }
+    })
+}
+
+const SOURCES: [Source; 2] = [Source::Keyboard, Source::Serial];
+
+/// Run every line of `script` as a command.
+///
+/// Comments are whole lines starting with `#`, or a `#` followed by a
+/// space later on. A `#` right before a number is decimal, see
+/// `parse_number`.
+pub fn run_script(out: &mut dyn fmt::Write, script: &str) -> CommandResult {
+    if SCRIPT_DEPTH.fetch_add(1, Ordering::Relaxed) >= MAX_SCRIPT_DEPTH {
+        SCRIPT_DEPTH.fetch_sub(1, Ordering::Relaxed);
+        let _ = writeln!(out, "scripts nested too deep");
+        return Err(CommandFailed);
+    }
+    let mut stop_on_error = true;
+    let mut result = Ok(());
+    for (number, line) in script.lines().enumerate() {
+        let line = strip_comment(line).trim();
+        match line {
+            "" => continue,
+            "onerror stop" => stop_on_error = true,
+            "onerror continue" => stop_on_error = false,
+            _ if line.starts_with("onerror") => {
+                let _ = writeln!(out, "line {}: usage: onerror stop|continue", number + 1);
+                result = Err(CommandFailed);
+                if stop_on_error {
+                    break;
+                }
+            }
+            _ => {
+                if execute(out, line).is_err() {
+                    let _ = writeln!(out, "line {}: `{}` failed", number + 1, line);
+                    result = Err(CommandFailed);
+                    if stop_on_error {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    SCRIPT_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    result
+}
+
+fn strip_comment(line: &str) -> &str {
+    if line.trim_start().starts_with('#') {
+        return "";
+    }
+    let bytes = line.as_bytes();
+    let comment = (1..bytes.len()).find(|&i| {
+        bytes[i] == b'#'
+            && bytes[i - 1].is_ascii_whitespace()
+            && bytes
+                .get(i + 1)
+                .map_or(true, |next| next.is_ascii_whitespace())
+    });
+    match comment {
+        Some(i) => &line[..i],
+        None => line,
+    }
+}
+
+fn run_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    if args.is_empty() {
+        let _ = writeln!(out, "usage: run PATH");
+        return Err(CommandFailed);
+    }
+    let source = match FILE_SOURCE.try_lock().and_then(|source| *source) {
+        Some(source) => source,
+        None => {
+            let _ = writeln!(out, "no file system to read `{}` from yet", args);
+            return Err(CommandFailed);
+        }
+    };
+    match source(args) {
+        Some(script) => run_script(out, &script),
+        None => {
+            let _ = writeln!(out, "{}: no such file", args);
+            Err(CommandFailed)
+        }
+    }
+}
+
+/// Hex with an optional `0x`, or decimal with a `#` in front.
+pub fn parse_number(word: &str) -> Option<u64> {
+    if let Some(decimal) = word.strip_prefix('#') {
+        decimal.parse().ok()
+    } else {
+        let hex = word.strip_prefix("0x").unwrap_or(word);
+        u64::from_str_radix(hex, 16).ok()
+    }
+}
+
+#[test_case]
+fn test_register_and_find() {
+    fn handler(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+        let _ = out.write_str(args);
+        Ok(())
+    }
+
+    assert_eq!(register("shell_test", "test command", handler), Ok(()));
+    assert_eq!(
+        register("shell_test", "test command", handler),
+        Err(RegisterError::Duplicate)
+    );
+    assert_eq!(
+        find("shell_test").map(|command| command.help),
+        Some("test command")
+    );
+    assert!(find("no_such_command").is_none());
+}
+
+#[test_case]
+fn test_parse_number() {
+    assert_eq!(parse_number("0x1000"), Some(0x1000));
+    assert_eq!(parse_number("b8000"), Some(0xb8000));
+    assert_eq!(parse_number("#64"), Some(64));
+    assert_eq!(parse_number("zz"), None);
+}
+
+#[test_case]
+fn test_run_script() {
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+    fn count(_out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+        RUNS.fetch_add(1, Ordering::Relaxed);
+        if args == "fail" {
+            Err(CommandFailed)
+        } else {
+            Ok(())
+        }
+    }
+    let _ = register("script_test", "test command", count);
+
+    let mut out = String::new();
+    let script = "# a comment\n\
+                  script_test #64 # the #64 is an argument\n\
+                  onerror continue\n\
+                  script_test fail\n\
+                  script_test\n\
+                  onerror stop\n\
+                  no_such_command\n\
+                  script_test";
+    assert_eq!(run_script(&mut out, script), Err(CommandFailed));
+    assert_eq!(RUNS.load(Ordering::Relaxed), 3);
+}
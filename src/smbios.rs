@@ -0,0 +1,280 @@
+//! SMBIOS (a.k.a. DMI) table parsing.
+//!
+//! The BIOS leaves an entry point structure somewhere in the
+//! `0xF0000..0x100000` range, aligned to 16 bytes. That entry point
+//! tells us where the actual structure table lives in physical memory.
+//!
+//! Each structure in the table is a small formatted header followed by
+//! a set of NUL terminated strings, with the whole set terminated by an
+//! extra NUL. Fields that hold text just store a 1-based index into
+//! that string set.
+use crate::println;
+use core::str;
+use x86_64::VirtAddr;
+
+/// Where the BIOS is allowed to put the entry point.
+const SCAN_START: u64 = 0xF0000;
+const SCAN_END: u64 = 0x10_0000;
+
+/// Structure types that we know how to report on.
+pub const TYPE_BIOS_INFORMATION: u8 = 0;
+pub const TYPE_SYSTEM_INFORMATION: u8 = 1;
+pub const TYPE_MEMORY_DEVICE: u8 = 17;
+pub const TYPE_END_OF_TABLE: u8 = 127;
+
+/// The structure table that the entry point pointed us at.
+#[derive(Debug, Clone, Copy)]
+pub struct Smbios<'a> {
+    pub major_version: u8,
+    pub minor_version: u8,
+    table: &'a [u8],
+}
+
+impl<'a> Smbios<'a> {
+    /// Wrap an already located structure table.
+    pub fn from_table(major_version: u8, minor_version: u8, table: &'a [u8]) -> Smbios<'a> {
+        Smbios {
+            major_version,
+            minor_version,
+            table,
+        }
+    }
+
+    /// Iterate over all structures until the end-of-table marker
+    /// or until we run out of table.
+    pub fn structures(&self) -> Structures<'a> {
+        Structures { rest: self.table }
+    }
+
+    /// First structure of the given type, if there is one.
+    pub fn find(&self, kind: u8) -> Option<Structure<'a>> {
+        self.structures().find(|structure| structure.kind() == kind)
+    }
+
+    /// Print the bits that are useful in a bug report.
+    pub fn print_summary(&self) {
+        println!("SMBIOS {}.{}", self.major_version, self.minor_version);
+
+        if let Some(bios) = self.find(TYPE_BIOS_INFORMATION) {
+            println!(
+                "  BIOS:   {} {} ({})",
+                bios.string_at(0x04).unwrap_or("?"),
+                bios.string_at(0x05).unwrap_or("?"),
+                bios.string_at(0x08).unwrap_or("?"),
+            );
+        }
+
+        if let Some(system) = self.find(TYPE_SYSTEM_INFORMATION) {
+            println!(
+                "  System: {} {} {}",
+                system.string_at(0x04).unwrap_or("?"),
+                system.string_at(0x05).unwrap_or("?"),
+                system.string_at(0x06).unwrap_or(""),
+            );
+        }
+
+        for device in self
+            .structures()
+            .filter(|structure| structure.kind() == TYPE_MEMORY_DEVICE)
+        {
+            let locator = device.string_at(0x10).unwrap_or("?");
+            match memory_device_size_mib(&device) {
+                Some(0) | None => println!("  Memory: {} empty", locator),
+                Some(size) => println!("  Memory: {} {} MiB", locator, size),
+            }
+        }
+    }
+}
+
+/// Size of a memory device (type 17) in MiB.
+///
+/// `Some(0)` means the slot is empty and `None` means the
+/// firmware doesn't know.
+pub fn memory_device_size_mib(device: &Structure) -> Option<u64> {
+    let size = device.word(0x0C)?;
+    match size {
+        0xFFFF => None,
+        // Real size lives in the extended size field (2.7+)
+        0x7FFF => device.dword(0x1C).map(|size| u64::from(size & 0x7FFF_FFFF)),
+        // Bit 15 set means the value is in KiB rather than MiB
+        size if size & 0x8000 != 0 => Some(u64::from(size & 0x7FFF) / 1024),
+        size => Some(u64::from(size)),
+    }
+}
+
+/// One structure out of the table.
+#[derive(Debug, Clone, Copy)]
+pub struct Structure<'a> {
+    /// The formatted area, including the 4 byte header.
+    formatted: &'a [u8],
+    /// The string set that follows the formatted area.
+    strings: &'a [u8],
+}
+
+impl<'a> Structure<'a> {
+    pub fn kind(&self) -> u8 {
+        self.formatted[0]
+    }
+
+    pub fn handle(&self) -> u16 {
+        u16::from_le_bytes([self.formatted[2], self.formatted[3]])
+    }
+
+    /// The raw formatted area, header included, so that offsets
+    /// match the ones in the specification.
+    pub fn data(&self) -> &'a [u8] {
+        self.formatted
+    }
+
+    pub fn byte(&self, offset: usize) -> Option<u8> {
+        self.formatted.get(offset).copied()
+    }
+
+    pub fn word(&self, offset: usize) -> Option<u16> {
+        let bytes = self.formatted.get(offset..offset + 2)?;
+        Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn dword(&self, offset: usize) -> Option<u32> {
+        let bytes = self.formatted.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Look up a string by its 1-based index. Index 0 means "no string".
+    pub fn string(&self, index: u8) -> Option<&'a str> {
+        if index == 0 {
+            return None;
+        }
+        self.strings
+            .split(|&byte| byte == 0)
+            .nth(usize::from(index) - 1)
+            .filter(|string| !string.is_empty())
+            .and_then(|string| str::from_utf8(string).ok())
+    }
+
+    /// Look up the string whose index is stored at `offset`.
+    pub fn string_at(&self, offset: usize) -> Option<&'a str> {
+        self.string(self.byte(offset)?)
+    }
+}
+
+/// Iterator over the structure table.
+pub struct Structures<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Iterator for Structures<'a> {
+    type Item = Structure<'a>;
+
+    fn next(&mut self) -> Option<Structure<'a>> {
+        let length = usize::from(*self.rest.get(1)?);
+        if length < 4 || length > self.rest.len() {
+            return None;
+        }
+        let (formatted, rest) = self.rest.split_at(length);
+
+        // The string set ends with two NULs in a row. A structure
+        // with no strings is just the two NULs.
+        let end = rest.windows(2).position(|pair| pair == [0, 0])?;
+        let strings = &rest[..end];
+        self.rest = &rest[end + 2..];
+
+        if formatted[0] == TYPE_END_OF_TABLE {
+            self.rest = &[];
+            return None;
+        }
+        Some(Structure { formatted, strings })
+    }
+}
+
+/// Bytes in an entry point have to add up to 0 (mod 256).
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// Try to parse an entry point at the start of `bytes`. Gives back
+/// the version and the physical address and length of the table.
+fn parse_entry_point(bytes: &[u8]) -> Option<(u8, u8, u64, usize)> {
+    if bytes.starts_with(b"_SM3_") {
+        // 64-bit entry point (SMBIOS 3.0+)
+        let length = usize::from(*bytes.get(0x06)?);
+        if length < 0x18 || !checksum_ok(bytes.get(..length)?) {
+            return None;
+        }
+        let size = u32::from_le_bytes([bytes[0x0C], bytes[0x0D], bytes[0x0E], bytes[0x0F]]);
+        let mut address = [0; 8];
+        address.copy_from_slice(&bytes[0x10..0x18]);
+        Some((
+            bytes[0x07],
+            bytes[0x08],
+            u64::from_le_bytes(address),
+            size as usize,
+        ))
+    } else if bytes.starts_with(b"_SM_") {
+        // 32-bit entry point (SMBIOS 2.x)
+        let length = usize::from(*bytes.get(0x05)?);
+        if length < 0x1F || !checksum_ok(bytes.get(..length)?) || bytes.get(0x10..0x15)? != b"_DMI_"
+        {
+            return None;
+        }
+        let size = u16::from_le_bytes([bytes[0x16], bytes[0x17]]);
+        let address = u32::from_le_bytes([bytes[0x18], bytes[0x19], bytes[0x1A], bytes[0x1B]]);
+        Some((
+            bytes[0x06],
+            bytes[0x07],
+            u64::from(address),
+            usize::from(size),
+        ))
+    } else {
+        None
+    }
+}
+
+/// Scan the BIOS area for the SMBIOS entry point and hand back the
+/// structure table it points to.
+///
+/// # Safety
+///
+/// The caller must guarantee that all of physical memory is
+/// mapped at `physical_memory_offset`.
+pub unsafe fn find(physical_memory_offset: VirtAddr) -> Option<Smbios<'static>> {
+    let phys = |address: u64, len: usize| -> &'static [u8] {
+        let virt = physical_memory_offset + address;
+        core::slice::from_raw_parts(virt.as_ptr(), len)
+    };
+
+    let area = phys(SCAN_START, (SCAN_END - SCAN_START) as usize);
+    let (major, minor, address, size) = (0..area.len())
+        .step_by(16)
+        .find_map(|offset| parse_entry_point(&area[offset..]))?;
+
+    Some(Smbios::from_table(major, minor, phys(address, size)))
+}
+
+#[test_case]
+fn test_parse_structure_table() {
+    #[rustfmt::skip]
+    let table = [
+        // BIOS information (type 0): vendor = 1, version = 2, date = 3
+        0, 9, 0x00, 0x00, 1, 2, 0, 0, 3,
+        b'A', 0, b'B', 0, b'C', 0, 0,
+        // Memory device (type 17), no strings, 512 MiB
+        17, 0x0E, 0x01, 0x00, 0, 0, 0, 0, 0, 0, 0, 0, 0x00, 0x02,
+        0, 0,
+        // End of table
+        127, 4, 0x02, 0x00,
+        0, 0,
+    ];
+    let smbios = Smbios::from_table(2, 8, &table);
+
+    let bios = smbios.find(TYPE_BIOS_INFORMATION).unwrap();
+    assert_eq!(bios.string_at(0x04), Some("A"));
+    assert_eq!(bios.string_at(0x05), Some("B"));
+    assert_eq!(bios.string_at(0x08), Some("C"));
+    assert_eq!(bios.string(4), None);
+
+    let memory = smbios.find(TYPE_MEMORY_DEVICE).unwrap();
+    assert_eq!(memory.handle(), 1);
+    assert_eq!(memory_device_size_mib(&memory), Some(512));
+    assert_eq!(smbios.structures().count(), 2);
+}
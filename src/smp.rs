@@ -0,0 +1,486 @@
+//! Which CPUs are online, and starting the others.
+//!
+//! The MADT lists a local APIC for every CPU, `apic_id` says which is
+//! which: the boot CPU is 0 and the rest are numbered in the MADT's
+//! order. `online` starts one the way Intel says to, with an INIT IPI,
+//! 10 ms, and a startup IPI with the page to start at, sent again if
+//! the first didn't take. It starts in real mode, so the trampoline it
+//! runs is copied to the frame `frame_allocator` keeps back below 1
+//! MiB and identity mapped. That gets it to long mode with our page
+//! tables and registers, on a stack of its own, and into `ap_main`,
+//! where it loads a GDT and TSS of its own and the IDT and turns its
+//! APIC on. `start_all`, at boot, starts all of them.
+//!
+//! Then they park, halted with interrupts on, and `running_mask`
+//! leaves them out. The scheduler only runs threads on the boot CPU, so
+//! there's nothing on them to move off when one goes offline.
+//!
+//! `offline` clears the CPU's bit in `WANTED` and sends it a
+//! `WAKE_VECTOR` IPI. It wakes up, sees it isn't wanted, drops out of
+//! `ONLINE` and halts again, which is what `offline` waits for. `online`
+//! sets the bit and does the same to bring it back, a CPU is only
+//! started from scratch the first time. That's enough to test races
+//! against a changing set of online CPUs. The boot CPU can't be taken
+//! offline at all, which is also what the real thing would say.
+//!
+//! `cpus` lists them.
+use crate::acpi;
+use crate::apic;
+use crate::error::{KernelError, KernelResult};
+use crate::gdt::{self, CpuTables};
+use crate::interrupts;
+use crate::memory::{self, frame_allocator, paging};
+use crate::shell::{self, CommandFailed, CommandResult};
+use crate::time;
+use crate::trace::MAX_CPUS;
+use alloc::boxed::Box;
+use alloc::vec;
+use core::arch::x86_64::__cpuid;
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::{Mutex, Once};
+use x86_64::registers::control::{Cr0, Cr3, Cr4};
+use x86_64::registers::model_specific::Efer;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// The CPU the bootloader handed us.
+pub const BOOT_CPU: u32 = 0;
+pub const AP_STACK_SIZE: usize = 16 * 1024;
+/// How long a CPU gets to show up after each startup IPI.
+const STARTUP_TIMEOUT_MS: u64 = 100;
+/// CR4.PCIDE can only be set in long mode.
+const CR4_PCIDE: u64 = 1 << 17;
+/// EFER.LMA, which the CPU sets itself.
+const EFER_LMA: u64 = 1 << 10;
+
+/// Bit `n` is set if CPU `n` is online.
+static ONLINE: AtomicU32 = AtomicU32::new(1 << BOOT_CPU);
+/// Bit `n` is set if CPU `n` is parked in `ap_main`.
+static PARKED: AtomicU32 = AtomicU32::new(0);
+/// Bit `n` is set if CPU `n` should be online, what a parked CPU checks
+/// when it's woken.
+static WANTED: AtomicU32 = AtomicU32::new(1 << BOOT_CPU);
+/// The APIC ID of each CPU, once the APIC and the MADT say.
+static APIC_IDS: Once<[Option<u8>; MAX_CPUS]> = Once::new();
+/// Held while a CPU is starting, there's only the one trampoline.
+static STARTING: Mutex<()> = Mutex::new(());
+
+/// What the trampoline loads, at `ap_trampoline_parameters`.
+#[repr(C)]
+struct Parameters {
+    cr0: u64,
+    cr3: u64,
+    cr4: u64,
+    efer: u64,
+    stack: u64,
+    entry: u64,
+    tables: u64,
+    cpu: u64,
+}
+
+// Copied somewhere below 1 MiB and run from there, so it's data here.
+// It only knows where it is from CS, and patches its GDT pointer and
+// far jumps with that before it needs them.
+global_asm!(
+    r#"
+    .section .rodata.ap_trampoline, "a", @progbits
+    .balign 16
+    .global ap_trampoline_start
+    .global ap_trampoline_parameters
+    .global ap_trampoline_end
+    .code16
+ap_trampoline_start:
+    cli
+    cld
+    mov %cs, %ax
+    mov %ax, %ds
+    xor %ebx, %ebx
+    mov %ax, %bx
+    shl $4, %ebx
+    mov %ebx, %eax
+    add $AP_GDT, %eax
+    mov %eax, AP_GDT_POINTER + 2
+    mov %ebx, %eax
+    add $AP_START32, %eax
+    mov %eax, AP_FAR32
+    mov %ebx, %eax
+    add $AP_START64, %eax
+    mov %eax, AP_FAR64
+    lgdtl AP_GDT_POINTER
+    mov %cr0, %eax
+    or $1, %eax
+    mov %eax, %cr0
+    ljmpl *AP_FAR32
+
+    .code32
+ap_trampoline_32:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    mov AP_CR4(%ebx), %eax
+    mov %eax, %cr4
+    mov AP_CR3(%ebx), %eax
+    mov %eax, %cr3
+    mov $0xC0000080, %ecx
+    mov AP_EFER(%ebx), %eax
+    xor %edx, %edx
+    wrmsr
+    # Paging on, and with LME set that's long mode.
+    mov AP_CR0(%ebx), %eax
+    mov %eax, %cr0
+    ljmpl *AP_FAR64(%ebx)
+
+    .code64
+ap_trampoline_64:
+    # The top halves of registers are undefined after the switch.
+    mov %ebx, %ebx
+    mov AP_STACK(%rbx), %rsp
+    mov AP_CPU(%rbx), %edi
+    mov AP_TABLES(%rbx), %rsi
+    mov AP_ENTRY(%rbx), %rax
+    call *%rax
+    ud2
+
+    .balign 8
+ap_trampoline_gdt:
+    .quad 0
+    .quad 0x00af9a000000ffff # 64 bit code
+    .quad 0x00cf92000000ffff # data
+    .quad 0x00cf9a000000ffff # 32 bit code
+ap_trampoline_gdt_pointer:
+    .word ap_trampoline_gdt_pointer - ap_trampoline_gdt - 1
+    .long 0
+ap_trampoline_far32:
+    .long 0
+    .word 0x18
+ap_trampoline_far64:
+    .long 0
+    .word 0x08
+    .balign 8
+ap_trampoline_parameters:
+    .fill 8, 8, 0
+ap_trampoline_end:
+
+    .set AP_GDT, ap_trampoline_gdt - ap_trampoline_start
+    .set AP_GDT_POINTER, ap_trampoline_gdt_pointer - ap_trampoline_start
+    .set AP_FAR32, ap_trampoline_far32 - ap_trampoline_start
+    .set AP_FAR64, ap_trampoline_far64 - ap_trampoline_start
+    .set AP_START32, ap_trampoline_32 - ap_trampoline_start
+    .set AP_START64, ap_trampoline_64 - ap_trampoline_start
+    .set AP_CR0, ap_trampoline_parameters - ap_trampoline_start
+    .set AP_CR3, AP_CR0 + 8
+    .set AP_CR4, AP_CR0 + 16
+    .set AP_EFER, AP_CR0 + 24
+    .set AP_STACK, AP_CR0 + 32
+    .set AP_ENTRY, AP_CR0 + 40
+    .set AP_TABLES, AP_CR0 + 48
+    .set AP_CPU, AP_CR0 + 56
+    .code64
+"#
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_parameters: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// Adds the `cpus` shell command.
+pub fn init() {
+    shell::register(
+        "cpus",
+        "cpus [online|offline N]: list CPUs or bring one up or down",
+        cpus_command,
+    )
+    .expect("cpus command");
+}
+
+/// How many CPUs there are: the ones in the MADT once the APIC is on,
+/// before that going by what CPUID says about the package we're on.
+pub fn possible() -> u32 {
+    if let Some(ids) = apic_ids() {
+        return ids.iter().flatten().count() as u32;
+    }
+    let features = unsafe { __cpuid(1) };
+    // HTT says the logical processor count is valid.
+    let count = if features.edx & 1 << 28 != 0 {
+        (features.ebx >> 16) & 0xFF
+    } else {
+        1
+    };
+    count.max(1).min(MAX_CPUS as u32)
+}
+
+/// `None` until `apic::enable`, which also means the ACPI tables can
+/// be read.
+fn apic_ids() -> Option<&'static [Option<u8>; MAX_CPUS]> {
+    if let Some(ids) = APIC_IDS.r#try() {
+        return Some(ids);
+    }
+    let boot = apic::id()?;
+    Some(APIC_IDS.call_once(|| {
+        let mut ids = [None; MAX_CPUS];
+        ids[BOOT_CPU as usize] = Some(boot);
+        // Without a MADT there's only us.
+        if let Ok(madt) = acpi::madt() {
+            let others = madt.local_apics.iter().flatten().filter(|&&id| id != boot);
+            for (slot, &id) in ids[1..].iter_mut().zip(others) {
+                *slot = Some(id);
+            }
+        }
+        ids
+    }))
+}
+
+/// The APIC ID of `cpu`, what IPIs and the IO APIC send to.
+pub fn apic_id(cpu: u32) -> Option<u8> {
+    *apic_ids()?.get(cpu as usize)?
+}
+
+pub fn is_online(cpu: u32) -> bool {
+    cpu < 32 && ONLINE.load(Ordering::SeqCst) & 1 << cpu != 0
+}
+
+/// Bit `n` is set if CPU `n` is online.
+pub fn online_mask() -> u32 {
+    ONLINE.load(Ordering::SeqCst)
+}
+
+pub fn online_count() -> u32 {
+    online_mask().count_ones()
+}
+
+/// The online CPUs that aren't parked, the ones running kernel code
+/// and taking timer ticks.
+pub fn running_mask() -> u32 {
+    online_mask() & !PARKED.load(Ordering::SeqCst)
+}
+
+fn check(cpu: u32) -> KernelResult<()> {
+    if cpu < possible() {
+        Ok(())
+    } else {
+        Err(KernelError::NotFound)
+    }
+}
+
+/// Bring `cpu` up. Fine if it already is.
+pub fn online(cpu: u32) -> KernelResult<()> {
+    check(cpu)?;
+    if is_online(cpu) {
+        return Ok(());
+    }
+    WANTED.fetch_or(1 << cpu, Ordering::SeqCst);
+    if PARKED.load(Ordering::SeqCst) & 1 << cpu == 0 {
+        return start(cpu).map_err(|error| {
+            WANTED.fetch_and(!(1 << cpu), Ordering::SeqCst);
+            error
+        });
+    }
+    nudge(cpu, true)
+}
+
+/// Start every CPU there is. Those that could be started are, even if
+/// one of them couldn't, and how many are online then.
+pub fn start_all() -> KernelResult<u32> {
+    let mut result = Ok(());
+    for cpu in 0..possible() {
+        result = result.and(online(cpu));
+    }
+    result.map(|()| online_count())
+}
+
+/// The trampoline, as it's copied.
+fn trampoline() -> &'static [u8] {
+    unsafe {
+        let start = &ap_trampoline_start as *const u8;
+        let end = &ap_trampoline_end as *const u8;
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    }
+}
+
+fn start(cpu: u32) -> KernelResult<()> {
+    let apic_id = apic_id(cpu).ok_or(KernelError::NotReady)?;
+    let _starting = STARTING.lock();
+    let (cr3, _) = Cr3::read();
+    let cr3 = cr3.start_address().as_u64();
+    if cr3 > u64::from(u32::MAX) {
+        // The trampoline loads it in 32 bit mode.
+        return Err(KernelError::Unsupported);
+    }
+    let frame = frame_allocator::low_frame()?;
+    let address = frame.start_address();
+    let code = memory::physical_to_virtual(address, frame_allocator::FRAME_SIZE)?;
+    let trampoline = trampoline();
+    let stack = Box::leak(vec![0u8; AP_STACK_SIZE].into_boxed_slice());
+    let parameters = Parameters {
+        cr0: Cr0::read_raw(),
+        cr3,
+        cr4: Cr4::read_raw() & !CR4_PCIDE,
+        efer: Efer::read_raw() & !EFER_LMA,
+        // Aligned the way a call wants it.
+        stack: (VirtAddr::from_ptr(stack.as_ptr()).as_u64() + AP_STACK_SIZE as u64) & !0xF,
+        entry: ap_main as usize as u64,
+        tables: gdt::cpu_tables() as *const CpuTables as u64,
+        cpu: u64::from(cpu),
+    };
+    unsafe {
+        let code = code.as_mut_ptr::<u8>();
+        ptr::copy_nonoverlapping(trampoline.as_ptr(), code, trampoline.len());
+        let offset = &ap_trampoline_parameters as *const u8 as usize - trampoline.as_ptr() as usize;
+        ptr::write(code.add(offset) as *mut Parameters, parameters);
+    }
+
+    // It's still running from there when paging comes on.
+    let page = Page::containing_address(VirtAddr::new(address.as_u64()));
+    let mapped = match unsafe { paging::map_to(page, frame, PageTableFlags::PRESENT) } {
+        Ok(()) => true,
+        Err(KernelError::AlreadyExists)
+            if paging::translate_addr(page.start_address()) == Some(address) =>
+        {
+            false
+        }
+        Err(error) => return Err(error),
+    };
+    let started = wake(cpu, apic_id, address);
+    if mapped {
+        unsafe { paging::unmap(page)? };
+    }
+    started
+}
+
+/// INIT, then startup IPIs until `cpu` is online.
+fn wake(cpu: u32, apic_id: u8, trampoline: PhysAddr) -> KernelResult<()> {
+    apic::send_ipi(apic_id, apic::IPI_INIT)?;
+    time::spin_ms(10)?;
+    let page = (trampoline.as_u64() >> 12) as u32;
+    for _ in 0..2 {
+        apic::send_ipi(apic_id, apic::IPI_STARTUP | page)?;
+        for _ in 0..STARTUP_TIMEOUT_MS / 10 {
+            if is_online(cpu) {
+                return Ok(());
+            }
+            time::spin_ms(10)?;
+        }
+    }
+    // Back to waiting for a startup IPI, so it can't turn up in the
+    // trampoline once that's gone.
+    let _ = apic::send_ipi(apic_id, apic::IPI_INIT);
+    Err(KernelError::Timeout)
+}
+
+/// Where the trampoline leaves a CPU, on its own stack.
+extern "C" fn ap_main(cpu: u32, tables: &'static CpuTables) -> ! {
+    unsafe { gdt::load_cpu_tables(tables) };
+    interrupts::load_idt();
+    apic::enable_on_this_cpu();
+    PARKED.fetch_or(1 << cpu, Ordering::SeqCst);
+    ONLINE.fetch_or(1 << cpu, Ordering::SeqCst);
+    // See the top of the file. `nudge`'s IPIs are the only interrupts
+    // sent here, one that comes in while we look is still pending at
+    // the next `hlt` and wakes it straight away.
+    loop {
+        x86_64::instructions::interrupts::enable_and_hlt();
+        x86_64::instructions::interrupts::disable();
+        if WANTED.load(Ordering::SeqCst) & 1 << cpu != 0 {
+            ONLINE.fetch_or(1 << cpu, Ordering::SeqCst);
+        } else {
+            ONLINE.fetch_and(!(1 << cpu), Ordering::SeqCst);
+        }
+    }
+}
+
+/// Take `cpu` down. Fine if it already is, but the boot CPU has to
+/// stay.
+pub fn offline(cpu: u32) -> KernelResult<()> {
+    check(cpu)?;
+    if cpu == BOOT_CPU {
+        return Err(KernelError::PermissionDenied);
+    }
+    if !is_online(cpu) {
+        return Ok(());
+    }
+    WANTED.fetch_and(!(1 << cpu), Ordering::SeqCst);
+    nudge(cpu, false)
+}
+
+/// Wake parked `cpu` to look at `WANTED`, and wait until it's `online`
+/// or not. `Timeout` if it didn't, with `WANTED` put back.
+fn nudge(cpu: u32, online: bool) -> KernelResult<()> {
+    let apic_id = apic_id(cpu).ok_or(KernelError::NotReady)?;
+    apic::send_ipi(apic_id, apic::IPI_FIXED | u32::from(apic::WAKE_VECTOR))?;
+    for _ in 0..STARTUP_TIMEOUT_MS / 10 {
+        if is_online(cpu) == online {
+            return Ok(());
+        }
+        time::spin_ms(10)?;
+    }
+    if online {
+        WANTED.fetch_and(!(1 << cpu), Ordering::SeqCst);
+    } else {
+        WANTED.fetch_or(1 << cpu, Ordering::SeqCst);
+    }
+    Err(KernelError::Timeout)
+}
+
+fn cpus_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    let mut words = args.split_whitespace();
+    let change: fn(u32) -> KernelResult<()> = match words.next() {
+        None => {
+            for cpu in 0..possible() {
+                let state = if running_mask() & 1 << cpu != 0 {
+                    "online"
+                } else if is_online(cpu) {
+                    "parked"
+                } else {
+                    "offline"
+                };
+                let boot = if cpu == BOOT_CPU { " (boot)" } else { "" };
+                let _ = write!(out, "cpu {}: {}{}", cpu, state, boot);
+                let _ = match apic_id(cpu) {
+                    Some(id) => writeln!(out, ", apic {}", id),
+                    None => writeln!(out),
+                };
+            }
+            return Ok(());
+        }
+        Some("online") => online,
+        Some("offline") => offline,
+        Some(_) => return usage(out),
+    };
+    let cpu = match words.next().map(str::parse) {
+        Some(Ok(cpu)) => cpu,
+        _ => return usage(out),
+    };
+    change(cpu).map_err(|error| {
+        let _ = writeln!(out, "cpu {}: {}", cpu, error);
+        CommandFailed
+    })
+}
+
+fn usage(out: &mut dyn fmt::Write) -> CommandResult {
+    let _ = writeln!(out, "usage: cpus [online|offline N]");
+    Err(CommandFailed)
+}
+
+#[test_case]
+fn test_boot_cpu_stays_online() {
+    assert!(is_online(BOOT_CPU));
+    assert_eq!(online(BOOT_CPU), Ok(()));
+    assert_eq!(offline(BOOT_CPU), Err(KernelError::PermissionDenied));
+    assert_eq!(online(MAX_CPUS as u32), Err(KernelError::NotFound));
+    assert_eq!(online_count(), 1);
+    assert!(trampoline().len() <= frame_allocator::FRAME_SIZE as usize);
+    // QEMU only gives us one unless it's told otherwise.
+    if possible() > 1 && online(1).is_ok() {
+        assert!(is_online(1));
+        assert_eq!(running_mask(), 1 << BOOT_CPU);
+        assert_eq!(offline(1), Ok(()));
+        assert!(!is_online(1));
+        assert_eq!(online(1), Ok(()));
+        assert!(is_online(1));
+    }
+}
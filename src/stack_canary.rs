@@ -0,0 +1,137 @@
+//! Canaries at the bottom of kernel stacks.
+//!
+//! A guard page only catches an overflow that touches it. Something
+//! like a big array on the stack can jump right over it and land in
+//! whatever is below, so every registered stack also gets a random
+//! value written at its lowest address. The timer interrupt checks
+//! them all and panics with the stack's name once one has changed.
+//! `scheduler` registers every thread's stack, and checks the one it
+//! switches away from too. Thread names needn't be unique, so a stack
+//! is known by its bottom.
+use crate::error::{KernelError, KernelResult};
+use crate::latency;
+use crate::memory;
+use crate::random;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+pub const MAX_STACKS: usize = 64;
+
+/// How far down `protect_boot_stack` looks for the bottom, in pages.
+const MAX_BOOT_STACK_PAGES: u64 = 1024;
+
+#[derive(Debug, Clone, Copy)]
+struct Stack {
+    /// Whoever uses the stack, for the panic message.
+    name: &'static str,
+    bottom: VirtAddr,
+}
+
+static STACKS: Mutex<[Option<Stack>; MAX_STACKS]> = Mutex::new([None; MAX_STACKS]);
+static CANARY: AtomicU64 = AtomicU64::new(0);
+
+/// Pick the canary and protect the double fault stack. Needs `random`
+/// set up.
+pub fn init() {
+    // Never 0, so it can't match a stack that was never written to.
+    CANARY.store(random::u64() | 1, Ordering::Relaxed);
+    unsafe {
+        register("double fault", crate::gdt::double_fault_stack_bottom())
+            .expect("double fault stack canary");
+    }
+}
+
+/// Protect the stack we were booted on. Its bottom is found by looking
+/// for the guard page, so `memory::init` has to be done.
+pub fn protect_boot_stack() -> KernelResult<()> {
+    let rsp: u64;
+    unsafe {
+        asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack));
+    }
+    let mut bottom = VirtAddr::new(rsp).align_down(4096u64);
+    for _ in 0..MAX_BOOT_STACK_PAGES {
+        let below = bottom - 4096u64;
+        match memory::translate(below) {
+            Some(mapping) if mapping.writable => bottom = below,
+            _ => return unsafe { register("boot", bottom) },
+        }
+    }
+    Err(KernelError::NotFound)
+}
+
+/// Write the canary at `bottom`, the lowest address of the stack, and
+/// check it from now on.
+///
+/// # Safety
+///
+/// `bottom` has to be the bottom of a stack that stays around until
+/// `unregister` is called.
+pub unsafe fn register(name: &'static str, bottom: VirtAddr) -> KernelResult<()> {
+    latency::without_interrupts(|| {
+        let mut stacks = STACKS.lock();
+        let slot = stacks
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(KernelError::NoSpace)?;
+        ptr::write_volatile(bottom.as_mut_ptr(), CANARY.load(Ordering::Relaxed));
+        *slot = Some(Stack { name, bottom });
+        Ok(())
+    })
+}
+
+/// Stop checking the stack at `bottom`, e.g. before it's freed.
+pub fn unregister(bottom: VirtAddr) -> KernelResult<()> {
+    latency::without_interrupts(|| {
+        let mut stacks = STACKS.lock();
+        let slot = stacks
+            .iter_mut()
+            .find(|slot| slot.map_or(false, |stack| stack.bottom == bottom))
+            .ok_or(KernelError::NotFound)?;
+        *slot = None;
+        Ok(())
+    })
+}
+
+/// Panic if the canary of the stack at `bottom` is gone.
+pub fn check(bottom: VirtAddr) {
+    if let Some(stacks) = STACKS.try_lock() {
+        stacks
+            .iter()
+            .flatten()
+            .filter(|stack| stack.bottom == bottom)
+            .for_each(check_stack);
+    }
+}
+
+/// Panic if any canary is gone. Called from the timer interrupt, skips
+/// the check if the table is being changed.
+pub fn check_all() {
+    if let Some(stacks) = STACKS.try_lock() {
+        stacks.iter().flatten().for_each(check_stack);
+    }
+}
+
+fn check_stack(stack: &Stack) {
+    let found = unsafe { ptr::read_volatile(stack.bottom.as_ptr::<u64>()) };
+    if found != CANARY.load(Ordering::Relaxed) {
+        panic!(
+            "stack canary of `{}` at {:#x} clobbered, found {:#x}",
+            stack.name,
+            stack.bottom.as_u64(),
+            found
+        );
+    }
+}
+
+#[test_case]
+fn test_canary_survives() {
+    static mut STACK: [u64; 4] = [0; 4];
+    let bottom = VirtAddr::from_ptr(unsafe { &STACK });
+    assert_eq!(unsafe { register("canary test", bottom) }, Ok(()));
+    assert_ne!(unsafe { STACK[0] }, 0);
+    check(bottom);
+    assert_eq!(unregister(bottom), Ok(()));
+    assert_eq!(unregister(bottom), Err(KernelError::NotFound));
+}
@@ -0,0 +1,222 @@
+//! Every counter the kernel keeps, in one snapshot.
+//!
+//! `Snapshot::take` reads them all at once, so a consumer doesn't need
+//! to know which subsystem keeps what. Shown with `{}` it comes out as
+//! one `name value` pair per line, which is what the `stats` command
+//! prints and what the crash dump's `@stats` section holds:
+//!
+//! ```text
+//! uptime_ms 51234
+//! interrupts.timer 931
+//! heap.live_bytes 0
+//! cpu0.online 1
+//! ```
+//!
+//! Names don't change once added, things reading the output can rely
+//! on them. The interrupt counts are for all CPUs together, the block
+//! counts for all devices, and each network interface has its own.
+//!
+//! Nothing here waits on a lock, the crash dump takes a snapshot with
+//! the rest of the kernel stopped wherever it was. What's behind a lock
+//! that's held, like the scheduler in the middle of a switch, comes out
+//! as `unavailable`: `scheduler.threads unavailable`, and `net
+//! unavailable` in place of every interface's lines.
+use crate::allocator::{self, HeapStats};
+use crate::block::{self, IoStats};
+use crate::interrupts::{COUNTERS, COUNTER_COUNT};
+use crate::net::{self, InterfaceStats, MAX_INTERFACES};
+use crate::shell::{self, CommandResult};
+use crate::trace::{self, MAX_CPUS};
+use crate::{apic, idle, latency, log, mbuf, rcu, scheduler, smp, time};
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CpuStats {
+    pub online: bool,
+    /// Records in its trace buffer.
+    pub trace_records: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Snapshot {
+    pub uptime_ms: u64,
+    pub ticks: u64,
+    /// In the order of `interrupts::COUNTERS`.
+    pub interrupts: [u64; COUNTER_COUNT],
+    pub interrupts_off_count: u64,
+    /// TSC cycles.
+    pub interrupts_off_max: u64,
+    pub heap: HeapStats,
+    pub mbuf_buffers: usize,
+    pub mbuf_bytes: usize,
+    pub idle_sleeps: u64,
+    pub idle_ms: u64,
+    pub rcu_grace_periods: u64,
+    pub throttle_events: u64,
+    /// `None`s are what was locked.
+    pub log_suppressed: Option<u64>,
+    pub scheduler_switches: u64,
+    pub scheduler_preemptions: u64,
+    pub scheduler_threads: Option<usize>,
+    /// In the order they were added.
+    pub interfaces: Option<[Option<(&'static str, InterfaceStats)>; MAX_INTERFACES]>,
+    pub block: IoStats,
+    pub cpus: [CpuStats; MAX_CPUS],
+}
+
+impl Snapshot {
+    pub fn take() -> Snapshot {
+        let mut interrupts = [0; COUNTER_COUNT];
+        for (count, counter) in interrupts.iter_mut().zip(COUNTERS.iter()) {
+            *count = counter.count();
+        }
+        let mut cpus = [CpuStats::default(); MAX_CPUS];
+        for (cpu, stats) in cpus.iter_mut().enumerate() {
+            stats.online = smp::is_online(cpu as u32);
+        }
+        trace::for_each_record(|record| {
+            if let Some(stats) = cpus.get_mut(record.cpu as usize) {
+                stats.trace_records += 1;
+            }
+        });
+        let mut scheduler_threads = 0;
+        let listed = scheduler::for_each_thread(|_| scheduler_threads += 1);
+        let mut interfaces = [None; MAX_INTERFACES];
+        let mut slots = interfaces.iter_mut();
+        let found = net::for_each(|interface| {
+            if let Some(slot) = slots.next() {
+                *slot = Some((interface.name, interface.stats));
+            }
+        });
+        Snapshot {
+            uptime_ms: time::uptime_ms(),
+            ticks: time::ticks(),
+            interrupts,
+            interrupts_off_count: latency::INTERRUPTS_OFF.count(),
+            interrupts_off_max: latency::INTERRUPTS_OFF.max(),
+            heap: allocator::heap_stats(),
+            mbuf_buffers: mbuf::live_buffers(),
+            mbuf_bytes: mbuf::live_bytes(),
+            idle_sleeps: idle::sleeps(),
+            idle_ms: idle::slept_ns() / 1_000_000,
+            rcu_grace_periods: rcu::grace_periods(),
+            throttle_events: apic::throttle_events(),
+            log_suppressed: log::suppressed().ok(),
+            scheduler_switches: scheduler::switches(),
+            scheduler_preemptions: scheduler::preemptions(),
+            scheduler_threads: listed.ok().map(|()| scheduler_threads),
+            interfaces: found.ok().map(|()| interfaces),
+            block: block::total_io(),
+            cpus,
+        }
+    }
+}
+
+/// An interrupt counter name as a stat name, `double fault` is
+/// `double_fault`.
+struct Name(&'static str);
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, word) in self.0.split(' ').enumerate() {
+            if index > 0 {
+                f.write_str("_")?;
+            }
+            f.write_str(word)?;
+        }
+        Ok(())
+    }
+}
+
+/// A value, or `unavailable` if its lock was held.
+struct Maybe<T>(Option<T>);
+
+impl<T: fmt::Display> fmt::Display for Maybe<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.0 {
+            Some(value) => value.fmt(f),
+            None => f.write_str("unavailable"),
+        }
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "uptime_ms {}", self.uptime_ms)?;
+        writeln!(f, "ticks {}", self.ticks)?;
+        for (count, counter) in self.interrupts.iter().zip(COUNTERS.iter()) {
+            writeln!(f, "interrupts.{} {}", Name(counter.name), count)?;
+        }
+        writeln!(f, "interrupts_off.count {}", self.interrupts_off_count)?;
+        writeln!(f, "interrupts_off.max_cycles {}", self.interrupts_off_max)?;
+        writeln!(f, "heap.live_blocks {}", self.heap.live_blocks)?;
+        writeln!(f, "heap.live_bytes {}", self.heap.live_bytes)?;
+        writeln!(f, "heap.allocations {}", self.heap.allocations)?;
+        writeln!(f, "heap.untracked_blocks {}", self.heap.untracked_blocks)?;
+        writeln!(f, "mbuf.live_buffers {}", self.mbuf_buffers)?;
+        writeln!(f, "mbuf.live_bytes {}", self.mbuf_bytes)?;
+        writeln!(f, "idle.sleeps {}", self.idle_sleeps)?;
+        writeln!(f, "idle.ms {}", self.idle_ms)?;
+        writeln!(f, "rcu.grace_periods {}", self.rcu_grace_periods)?;
+        writeln!(f, "apic.throttle_events {}", self.throttle_events)?;
+        writeln!(f, "log.suppressed {}", Maybe(self.log_suppressed))?;
+        writeln!(f, "scheduler.switches {}", self.scheduler_switches)?;
+        writeln!(f, "scheduler.preemptions {}", self.scheduler_preemptions)?;
+        writeln!(f, "scheduler.threads {}", Maybe(self.scheduler_threads))?;
+        let interfaces = match &self.interfaces {
+            Some(interfaces) => &interfaces[..],
+            None => {
+                writeln!(f, "net unavailable")?;
+                &[]
+            }
+        };
+        for (name, stats) in interfaces.iter().flatten() {
+            writeln!(f, "net.{}.rx_packets {}", name, stats.rx_packets)?;
+            writeln!(f, "net.{}.rx_bytes {}", name, stats.rx_bytes)?;
+            writeln!(f, "net.{}.rx_dropped {}", name, stats.rx_dropped)?;
+            writeln!(f, "net.{}.tx_packets {}", name, stats.tx_packets)?;
+            writeln!(f, "net.{}.tx_bytes {}", name, stats.tx_bytes)?;
+        }
+        writeln!(f, "block.reads {}", self.block.reads)?;
+        writeln!(f, "block.read_bytes {}", self.block.read_bytes)?;
+        writeln!(f, "block.writes {}", self.block.writes)?;
+        writeln!(f, "block.written_bytes {}", self.block.written_bytes)?;
+        for (cpu, stats) in self.cpus.iter().enumerate() {
+            writeln!(f, "cpu{}.online {}", cpu, stats.online as u8)?;
+            writeln!(f, "cpu{}.trace_records {}", cpu, stats.trace_records)?;
+        }
+        Ok(())
+    }
+}
+
+/// Adds the `stats` shell command.
+pub fn init() {
+    shell::register("stats", "every kernel counter", stats_command).expect("stats command");
+}
+
+fn stats_command(out: &mut dyn fmt::Write, _args: &str) -> CommandResult {
+    let _ = write!(out, "{}", Snapshot::take());
+    Ok(())
+}
+
+#[test_case]
+fn test_snapshot() {
+    use crate::interrupts::TIMER_COUNTER;
+    use alloc::string::String;
+    use core::fmt::Write;
+
+    let first = Snapshot::take();
+    let second = Snapshot::take();
+    assert!(second.interrupts[TIMER_COUNTER] >= first.interrupts[TIMER_COUNTER]);
+    assert!(second.cpus[0].online);
+    assert!(second.scheduler_switches >= first.scheduler_switches);
+    assert!(second.scheduler_threads.unwrap() >= 1);
+    assert!(second.interfaces.is_some());
+
+    let mut text = String::new();
+    write!(text, "{}", second).unwrap();
+    assert!(text.contains("\ninterrupts.double_fault "));
+    assert!(text.contains("\nscheduler.threads "));
+    assert!(text.contains("\nblock.read_bytes "));
+    assert!(text.ends_with('\n'));
+}
@@ -0,0 +1,216 @@
+//! Single-step tracing of a kernel function.
+//!
+//! `steptrace ADDR` puts a breakpoint on the function at `ADDR`. When
+//! it's called, the breakpoint sets the trap flag instead of entering
+//! the monitor, and from then on every instruction it runs (including
+//! in whatever it calls) puts its `rip` in the trace buffer under the
+//! `step` subsystem. Dump it with `trace`.
+//!
+//! Tracing a call stops once the function returns, or after `limit`
+//! instructions so a long loop can't flush everything else out of the
+//! buffer. The breakpoint stays, so every call gets traced until
+//! `steptrace off`.
+//!
+//! Interrupt handlers don't get traced, the CPU clears the trap flag on
+//! the way in. Don't trace anything that records to the trace buffer
+//! itself, the step would wait on its lock forever. User processes
+//! can't be traced as there aren't any yet.
+use crate::breakpoints::{self, Action};
+use crate::error::{KernelError, KernelResult};
+use crate::ksyms::{self, Demangle};
+use crate::shell::{self, parse_number, CommandFailed, CommandResult};
+use crate::trace::{self, Subsystem, Tracepoint};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+/// Instructions traced per call unless told otherwise.
+pub const DEFAULT_LIMIT: u64 = 1000;
+
+const TRAP_FLAG: u64 = 1 << 8;
+
+/// Function being traced, 0 for none.
+static FUNCTION: AtomicU64 = AtomicU64::new(0);
+static LIMIT: AtomicU64 = AtomicU64::new(DEFAULT_LIMIT);
+/// Set while a call is being stepped through.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+/// `rsp` on entry, pointing at the return address. Above it means the
+/// function returned.
+static ENTRY_RSP: AtomicU64 = AtomicU64::new(0);
+static STEPS: AtomicU64 = AtomicU64::new(0);
+static CALLS: AtomicU64 = AtomicU64::new(0);
+/// Calls that hit the limit before returning.
+static CUT_SHORT: AtomicU64 = AtomicU64::new(0);
+
+static STEP: Tracepoint = Tracepoint {
+    subsystem: Subsystem::Stepping,
+    file: file!(),
+    line: line!(),
+    format: |f, args| {
+        write!(f, "{:#x}", args[0])?;
+        match ksyms::lookup(args[0]) {
+            Some((name, offset)) => write!(f, " <{}+{:#x}>", Demangle(name), offset),
+            None => Ok(()),
+        }
+    },
+};
+
+/// Adds the `steptrace` shell command.
+pub fn init() {
+    shell::register(
+        "steptrace",
+        "steptrace [ADDR [LIMIT]|off]: trace every instruction of a function",
+        steptrace_command,
+    )
+    .expect("steptrace command");
+}
+
+/// Trace every call to the function at `address`, up to `limit`
+/// instructions each. Turns on the `step` trace subsystem.
+///
+/// # Safety
+///
+/// `address` has to be the start of a function.
+pub unsafe fn trace_function(address: VirtAddr, limit: u64) -> KernelResult<()> {
+    if FUNCTION.load(Ordering::SeqCst) != 0 {
+        return Err(KernelError::AlreadyExists);
+    }
+    LIMIT.store(limit.max(1), Ordering::SeqCst);
+    breakpoints::insert_with(address, Action::StepTrace)?;
+    FUNCTION.store(address.as_u64(), Ordering::SeqCst);
+    trace::enable(Subsystem::Stepping);
+    Ok(())
+}
+
+/// Stop tracing calls. One that's being traced right now runs on to
+/// its end or the limit.
+pub fn stop() -> KernelResult<()> {
+    let function = FUNCTION.swap(0, Ordering::SeqCst);
+    if function == 0 {
+        return Err(KernelError::NotFound);
+    }
+    breakpoints::remove(VirtAddr::new(function))
+}
+
+/// Called by `breakpoints` when the function is entered, with the trap
+/// flag already set.
+pub(crate) fn start(stack_frame: &InterruptStackFrame) {
+    // A recursive call is already covered by the outer one.
+    if ACTIVE.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    ENTRY_RSP.store(stack_frame.stack_pointer.as_u64(), Ordering::SeqCst);
+    STEPS.store(0, Ordering::SeqCst);
+    CALLS.fetch_add(1, Ordering::Relaxed);
+    record(stack_frame.instruction_pointer.as_u64());
+}
+
+/// Called by the debug exception handler after each step. Returns false
+/// if we're not tracing.
+pub fn step(stack_frame: &mut InterruptStackFrame) -> bool {
+    if !ACTIVE.load(Ordering::SeqCst) {
+        return false;
+    }
+    let returned = stack_frame.stack_pointer.as_u64() > ENTRY_RSP.load(Ordering::SeqCst);
+    let steps = STEPS.fetch_add(1, Ordering::SeqCst) + 1;
+    let over_limit = steps >= LIMIT.load(Ordering::SeqCst);
+    if !returned {
+        record(stack_frame.instruction_pointer.as_u64());
+    }
+    unsafe {
+        if returned || over_limit {
+            if over_limit && !returned {
+                CUT_SHORT.fetch_add(1, Ordering::Relaxed);
+            }
+            ACTIVE.store(false, Ordering::SeqCst);
+            stack_frame.as_mut().cpu_flags &= !TRAP_FLAG;
+        } else {
+            // `breakpoints` clears it after stepping over the entry.
+            stack_frame.as_mut().cpu_flags |= TRAP_FLAG;
+        }
+    }
+    true
+}
+
+fn record(rip: u64) {
+    if trace::is_enabled(Subsystem::Stepping) {
+        trace::record(&STEP, trace::args(&[rip]));
+    }
+}
+
+fn steptrace_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    let mut words = args.split_whitespace();
+    let result = match words.next() {
+        None => {
+            let function = FUNCTION.load(Ordering::SeqCst);
+            if function == 0 {
+                let _ = writeln!(out, "not tracing");
+            } else {
+                let _ = write!(out, "tracing {:#x}", function);
+                if let Some((name, _)) = ksyms::lookup(function) {
+                    let _ = write!(out, " <{}>", Demangle(name));
+                }
+                let _ = writeln!(out, ", limit {}", LIMIT.load(Ordering::SeqCst));
+            }
+            let _ = writeln!(
+                out,
+                "{} calls traced, {} cut short",
+                CALLS.load(Ordering::Relaxed),
+                CUT_SHORT.load(Ordering::Relaxed)
+            );
+            return Ok(());
+        }
+        Some("off") => stop(),
+        Some(address) => {
+            let address =
+                ksyms::parse_address(address).and_then(|address| VirtAddr::try_new(address).ok());
+            let limit = words.next().map(parse_number);
+            match (address, limit) {
+                (Some(address), None) => unsafe { trace_function(address, DEFAULT_LIMIT) },
+                (Some(address), Some(Some(limit))) => unsafe { trace_function(address, limit) },
+                _ => {
+                    let _ = writeln!(out, "usage: steptrace [ADDR [LIMIT]|off]");
+                    return Err(CommandFailed);
+                }
+            }
+        }
+    };
+    result.map_err(|error| {
+        let _ = writeln!(out, "{}", error);
+        CommandFailed
+    })
+}
+
+#[test_case]
+fn test_traces_a_call() {
+    #[inline(never)]
+    fn traced(n: u64) -> u64 {
+        n * 3 + 1
+    }
+    let count = || {
+        let mut count = 0;
+        trace::for_each_record(|record| {
+            if record.event.subsystem == Subsystem::Stepping {
+                count += 1;
+            }
+        });
+        count
+    };
+
+    trace::clear();
+    let calls = CALLS.load(Ordering::Relaxed);
+    let address = VirtAddr::new(traced as usize as u64);
+    assert_eq!(unsafe { trace_function(address, 100) }, Ok(()));
+    assert_eq!(
+        unsafe { trace_function(address, 100) },
+        Err(KernelError::AlreadyExists)
+    );
+    assert_eq!(traced(2), 7);
+    assert_eq!(stop(), Ok(()));
+    assert!(!ACTIVE.load(Ordering::SeqCst));
+    assert_eq!(CALLS.load(Ordering::Relaxed), calls + 1);
+    assert!(count() > 1);
+    trace::disable(Subsystem::Stepping);
+    trace::clear();
+}
@@ -0,0 +1,849 @@
+//! The `syscall` instruction's way into the kernel from ring 3.
+//!
+//! Numbers and calling convention are Linux's, so programs built with
+//! `blog_os_user` run the same here and there: the number in `rax`,
+//! arguments in `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`, the result
+//! back in `rax`, a negative errno if it failed. `rcx` and `r11` are
+//! lost, everything else is kept.
+//!
+//! `syscall` doesn't switch stacks, so the entry does: ring 3's `rsp`
+//! goes in a scratch slot just long enough to load the thread's kernel
+//! stack from the TSS, the same `rsp0` interrupts use, see `usermode`.
+//! Interrupts are off until that's done, `SFMask` sees to it, and back
+//! on while the call runs. Only one CPU ever goes to ring 3, so one
+//! scratch slot does.
+//!
+//! So far there's
+//!
+//! - `write`, to stdout and stderr, which both go to the console, or
+//!   to a string for `capture_output`,
+//! - `stat`, `mkdir`, `rmdir`, `unlink` and `rename`, see `fs`. Paths
+//!   have to be absolute, there's no working directory,
+//! - `mmap` of anonymous memory, and `munmap` of all of what one
+//!   mapped, see `fs::mmap`. Files can't be mapped without file
+//!   descriptors,
+//! - `sched_yield`, see `scheduler::yield_now`,
+//! - `futex`, `FUTEX_WAIT` and `FUTEX_WAKE`, see `futex`,
+//! - `socket`, of `AF_INET` and `SOCK_DGRAM` only, and `bind`,
+//!   `connect`, `sendto`, `recvfrom` and `close` on what it gave back,
+//!   see `net::socket`. `recvfrom` waits unless it's `MSG_DONTWAIT`.
+//!   Sockets are the only file descriptors there are, from 3 up,
+//! - `exit`, which ends `usermode::run` with `Exit::Exited`.
+//!
+//! Listing a directory is `getdents64` on an open one, which waits for
+//! files to have file descriptors.
+use crate::error::{KernelError, KernelResult};
+use crate::fs::{self, Kind};
+use crate::futex;
+use crate::gdt;
+use crate::net::socket::{self, Endpoint, SocketId};
+use crate::net::Ipv4Addr;
+use crate::scheduler;
+use crate::usermode::{self, Exit};
+use alloc::string::String;
+use alloc::vec;
+use core::mem::size_of;
+use spin::Mutex;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+pub const WRITE: u64 = 1;
+pub const CLOSE: u64 = 3;
+pub const STAT: u64 = 4;
+pub const MMAP: u64 = 9;
+pub const MUNMAP: u64 = 11;
+pub const SCHED_YIELD: u64 = 24;
+pub const SOCKET: u64 = 41;
+pub const CONNECT: u64 = 42;
+pub const SENDTO: u64 = 44;
+pub const RECVFROM: u64 = 45;
+pub const BIND: u64 = 49;
+pub const EXIT: u64 = 60;
+pub const RENAME: u64 = 82;
+pub const MKDIR: u64 = 83;
+pub const RMDIR: u64 = 84;
+pub const UNLINK: u64 = 87;
+pub const FUTEX: u64 = 202;
+
+const STDOUT: u64 = 1;
+const STDERR: u64 = 2;
+
+const PROT_WRITE: u64 = 0x2;
+const PROT_EXEC: u64 = 0x4;
+const MAP_SHARED: u64 = 0x01;
+const MAP_PRIVATE: u64 = 0x02;
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+const AF_INET: u64 = 2;
+const SOCK_DGRAM: u64 = 2;
+/// A flag `socket` takes in with the type, which doesn't mean anything
+/// without `exec`.
+const SOCK_CLOEXEC: u64 = 0o2000000;
+const IPPROTO_UDP: u64 = 17;
+const MSG_DONTWAIT: u64 = 0x40;
+/// `struct sockaddr_in`: the family, the port and the address, both
+/// big endian, and 8 bytes of padding.
+const SOCKADDR_IN_LEN: usize = 16;
+/// The file descriptor of socket 0, after stdin, stdout and stderr.
+const FIRST_SOCKET_FD: u64 = 3;
+
+/// Errnos, Linux's.
+pub const ENOENT: i64 = 2;
+pub const EBADF: i64 = 9;
+pub const EAGAIN: i64 = 11;
+pub const ENOMEM: i64 = 12;
+pub const EACCES: i64 = 13;
+pub const EFAULT: i64 = 14;
+pub const EBUSY: i64 = 16;
+pub const EEXIST: i64 = 17;
+pub const EXDEV: i64 = 18;
+pub const EINVAL: i64 = 22;
+pub const ENOSPC: i64 = 28;
+pub const ENAMETOOLONG: i64 = 36;
+pub const ENOSYS: i64 = 38;
+pub const EDESTADDRREQ: i64 = 89;
+pub const EMSGSIZE: i64 = 90;
+pub const EPROTONOSUPPORT: i64 = 93;
+pub const EAFNOSUPPORT: i64 = 97;
+pub const EADDRINUSE: i64 = 98;
+pub const EADDRNOTAVAIL: i64 = 99;
+pub const ENETUNREACH: i64 = 101;
+pub const ETIMEDOUT: i64 = 110;
+
+/// The longest path a syscall takes, with its NUL.
+pub const MAX_PATH: usize = 256;
+
+/// Bytes `write` copies in at a time.
+const WRITE_CHUNK: usize = 256;
+
+/// Linux's `struct stat`, as `stat` fills it in.
+#[repr(C)]
+#[derive(Default)]
+struct Stat {
+    dev: u64,
+    ino: u64,
+    nlink: u64,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    _pad: u32,
+    rdev: u64,
+    size: u64,
+    blksize: u64,
+    blocks: u64,
+    atime: u64,
+    atime_ns: u64,
+    mtime: u64,
+    mtime_ns: u64,
+    ctime: u64,
+    ctime_ns: u64,
+    _reserved: [u64; 3],
+}
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+/// What the entry pushed, lowest address first. `rax` is the result
+/// on the way out.
+#[repr(C)]
+struct Frame {
+    r9: u64,
+    r8: u64,
+    r10: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    rax: u64,
+    rflags: u64,
+    rip: u64,
+    rsp: u64,
+}
+
+/// Where `rsp0` is, set by `init`.
+#[no_mangle]
+static mut SYSCALL_RSP0: u64 = 0;
+/// Ring 3's `rsp`, for the few instructions it takes to switch.
+#[no_mangle]
+static mut SYSCALL_USER_RSP: u64 = 0;
+
+extern "C" {
+    fn syscall_entry();
+}
+
+global_asm!(
+    "
+    .intel_syntax noprefix
+    .global syscall_entry
+    syscall_entry:
+        mov [rip + SYSCALL_USER_RSP], rsp
+        mov rsp, [rip + SYSCALL_RSP0]
+        mov rsp, [rsp]
+        and rsp, -16
+        push qword ptr [rip + SYSCALL_USER_RSP]
+        push rcx
+        push r11
+        push rax
+        push rdi
+        push rsi
+        push rdx
+        push r10
+        push r8
+        push r9
+        sti
+        mov rdi, rsp
+        call syscall_dispatch
+        cli
+        pop r9
+        pop r8
+        pop r10
+        pop rdx
+        pop rsi
+        pop rdi
+        pop rax
+        pop r11
+        pop rcx
+        pop rsp
+        sysretq
+    .att_syntax
+    "
+);
+
+/// Turn on `syscall` and point it at the entry.
+pub fn init() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.code_selector,
+        selectors.data_selector,
+    )
+    .expect("segments laid out for sysret");
+    LStar::write(VirtAddr::new(syscall_entry as usize as u64));
+    // The direction flag too, Rust counts on it being clear.
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::TRAP_FLAG | RFlags::DIRECTION_FLAG);
+    unsafe {
+        SYSCALL_RSP0 = gdt::kernel_stack_slot() as u64;
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+}
+
+/// The errno for `error`.
+pub fn errno(error: KernelError) -> i64 {
+    match error {
+        KernelError::OutOfMemory => ENOMEM,
+        KernelError::InvalidAddress => EFAULT,
+        KernelError::PermissionDenied => EACCES,
+        KernelError::WouldBlock => EAGAIN,
+        KernelError::Timeout => ETIMEDOUT,
+        KernelError::Busy => EBUSY,
+        KernelError::NotFound => ENOENT,
+        KernelError::AlreadyExists => EEXIST,
+        KernelError::NoSpace => ENOSPC,
+        KernelError::Unsupported => ENOSYS,
+        _ => EINVAL,
+    }
+}
+
+#[no_mangle]
+extern "C" fn syscall_dispatch(frame: &mut Frame) {
+    let result = match frame.rax {
+        WRITE => write(frame.rdi, VirtAddr::try_new(frame.rsi).ok(), frame.rdx),
+        STAT => stat(frame.rdi, VirtAddr::try_new(frame.rsi).ok()),
+        MMAP => mmap(frame.rsi, frame.rdx, frame.r10),
+        MUNMAP => munmap(frame.rdi, frame.rsi),
+        // Modes don't mean anything yet.
+        MKDIR => on_path(frame.rdi, fs::mkdir),
+        RMDIR => on_path(frame.rdi, fs::rmdir),
+        UNLINK => on_path(frame.rdi, fs::unlink),
+        RENAME => rename(frame.rdi, frame.rsi),
+        SCHED_YIELD => {
+            scheduler::yield_now();
+            Ok(0)
+        }
+        FUTEX => futex(frame.rdi, frame.rsi, frame.rdx, frame.r10),
+        SOCKET => open_socket(frame.rdi, frame.rsi, frame.rdx),
+        BIND => bind(frame.rdi, frame.rsi, frame.rdx),
+        CONNECT => connect(frame.rdi, frame.rsi, frame.rdx),
+        SENDTO => send_to(frame.rdi, frame.rsi, frame.rdx, frame.r8, frame.r9),
+        RECVFROM => receive_from(
+            frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9,
+        ),
+        CLOSE => close(frame.rdi),
+        EXIT => usermode::leave(Exit::Exited(frame.rdi as i32)),
+        _ => Err(ENOSYS),
+    };
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(errno) => -errno as u64,
+    };
+}
+
+/// Who `capture_output` is capturing for, and what it's got.
+static CAPTURE: Mutex<Option<(scheduler::ThreadId, String)>> = Mutex::new(None);
+
+/// Run `f`, with what this thread's programs write to stdout and
+/// stderr kept, rather than printed, and handed back with what `f`
+/// returned. For one thread at a time, `Busy` if another's capturing.
+pub fn capture_output<R>(f: impl FnOnce() -> R) -> KernelResult<(R, String)> {
+    let thread = scheduler::current();
+    {
+        let mut capture = CAPTURE.lock();
+        if capture.is_some() {
+            return Err(KernelError::Busy);
+        }
+        *capture = Some((thread, String::new()));
+    }
+    let result = f();
+    let (_, output) = CAPTURE.lock().take().unwrap();
+    Ok((result, output))
+}
+
+fn write(fd: u64, buffer: Option<VirtAddr>, len: u64) -> Result<u64, i64> {
+    if fd != STDOUT && fd != STDERR {
+        return Err(EBADF);
+    }
+    let buffer = buffer.ok_or(EFAULT)?;
+    let mut chunk = [0u8; WRITE_CHUNK];
+    let mut written = 0;
+    while written < len {
+        let size = (len - written).min(WRITE_CHUNK as u64) as usize;
+        let from = buffer + written;
+        if let Err(error) = crate::user::copy_in(&mut chunk[..size], from) {
+            // Whatever made it out counts, like a short write.
+            return if written > 0 {
+                Ok(written)
+            } else {
+                Err(errno(error))
+            };
+        }
+        output(&chunk[..size]);
+        written += size as u64;
+    }
+    Ok(written)
+}
+
+/// The NUL terminated path at `address`.
+fn copy_in_path(address: u64) -> Result<String, i64> {
+    let mut address = VirtAddr::try_new(address).map_err(|_| EFAULT)?;
+    let mut path = [0u8; MAX_PATH];
+    let mut len = 0;
+    while len < MAX_PATH {
+        // Only up to the end of the page, the NUL can be right before
+        // one that isn't mapped.
+        let size = (4096 - address.as_u64() % 4096).min((MAX_PATH - len) as u64) as usize;
+        crate::user::copy_in(&mut path[len..len + size], address).map_err(errno)?;
+        if let Some(nul) = path[len..len + size].iter().position(|&byte| byte == 0) {
+            let path = core::str::from_utf8(&path[..len + nul]).map_err(|_| ENOENT)?;
+            return Ok(path.into());
+        }
+        len += size;
+        address += size as u64;
+    }
+    Err(ENAMETOOLONG)
+}
+
+/// For the calls that only take a path and return 0.
+fn on_path(address: u64, call: fn(&str) -> KernelResult<()>) -> Result<u64, i64> {
+    let path = copy_in_path(address)?;
+    call(&path).map(|()| 0).map_err(errno)
+}
+
+fn stat(path: u64, buffer: Option<VirtAddr>) -> Result<u64, i64> {
+    let path = copy_in_path(path)?;
+    let buffer = buffer.ok_or(EFAULT)?;
+    let metadata = fs::stat(&path).map_err(errno)?;
+    let seconds = |ns: u64| (ns / 1_000_000_000, ns % 1_000_000_000);
+    let (mtime, mtime_ns) = seconds(metadata.modified_ns);
+    let stat = Stat {
+        ino: metadata.inode,
+        nlink: 1,
+        mode: match metadata.kind {
+            Kind::File => S_IFREG | 0o644,
+            Kind::Directory => S_IFDIR | 0o755,
+        },
+        size: metadata.size,
+        blksize: 4096,
+        blocks: (metadata.size + 511) / 512,
+        // Nothing keeps track of reads, and nothing about a file
+        // changes but what's in it.
+        atime: mtime,
+        atime_ns: mtime_ns,
+        mtime,
+        mtime_ns,
+        ctime: mtime,
+        ctime_ns: mtime_ns,
+        ..Stat::default()
+    };
+    let bytes = unsafe {
+        core::slice::from_raw_parts(&stat as *const Stat as *const u8, size_of::<Stat>())
+    };
+    crate::user::copy_out(buffer, bytes).map_err(errno)?;
+    Ok(0)
+}
+
+fn rename(from: u64, to: u64) -> Result<u64, i64> {
+    let from = copy_in_path(from)?;
+    let to = copy_in_path(to)?;
+    match fs::rename(&from, &to) {
+        Ok(()) => Ok(0),
+        Err(KernelError::Unsupported) => Err(EXDEV),
+        Err(error) => Err(errno(error)),
+    }
+}
+
+/// `mmap`, of anonymous memory only. Where it goes is up to the
+/// kernel, `MAP_FIXED` isn't done.
+fn mmap(len: u64, prot: u64, flags: u64) -> Result<u64, i64> {
+    if flags & MAP_ANONYMOUS == 0 {
+        return Err(EBADF);
+    }
+    let sharing = flags & (MAP_SHARED | MAP_PRIVATE);
+    if len == 0 || flags & MAP_FIXED != 0 || sharing == 0 || sharing == MAP_SHARED | MAP_PRIVATE {
+        return Err(EINVAL);
+    }
+    // Readable whether it asked or not, page tables can't say otherwise.
+    let options = fs::mmap::MapOptions {
+        writable: prot & PROT_WRITE != 0,
+        executable: prot & PROT_EXEC != 0,
+        shared: sharing == MAP_SHARED,
+    };
+    let mapping = fs::mmap::map_anonymous(len, options).map_err(errno)?;
+    Ok(mapping.give_to(scheduler::current()).as_u64())
+}
+
+/// `munmap`, of all of something `mmap` mapped, nothing less.
+fn munmap(address: u64, len: u64) -> Result<u64, i64> {
+    let address = VirtAddr::try_new(address).map_err(|_| EINVAL)?;
+    match fs::mmap::unmap(address, len, scheduler::current()) {
+        Ok(()) => Ok(0),
+        Err(_) => Err(EINVAL),
+    }
+}
+
+/// `futex`, with Linux's timeout for `FUTEX_WAIT`: a `struct timespec`
+/// at `timeout` to wait at most, or null to wait for good.
+fn futex(address: u64, op: u64, value: u64, timeout: u64) -> Result<u64, i64> {
+    let address = VirtAddr::try_new(address).map_err(|_| EFAULT)?;
+    let timeout_ms = if op == futex::FUTEX_WAIT && timeout != 0 {
+        let mut timespec = [0u8; 16];
+        let from = VirtAddr::try_new(timeout).map_err(|_| EFAULT)?;
+        crate::user::copy_in(&mut timespec, from).map_err(errno)?;
+        let mut seconds = [0; 8];
+        let mut nanoseconds = [0; 8];
+        seconds.copy_from_slice(&timespec[..8]);
+        nanoseconds.copy_from_slice(&timespec[8..]);
+        let (seconds, nanoseconds) = (i64::from_ne_bytes(seconds), i64::from_ne_bytes(nanoseconds));
+        if seconds < 0 || !(0..1_000_000_000).contains(&nanoseconds) {
+            return Err(EINVAL);
+        }
+        // 0 is no timeout to `sys_futex`, so a zero one is a tick.
+        (seconds as u64)
+            .saturating_mul(1000)
+            .saturating_add((nanoseconds as u64 + 999_999) / 1_000_000)
+            .max(1)
+    } else {
+        0
+    };
+    futex::sys_futex(address, op, value as u32, timeout_ms)
+        .map(|woken| woken as u64)
+        .map_err(errno)
+}
+
+/// The errno for what `net::socket` said no with.
+fn socket_errno(error: KernelError) -> i64 {
+    match error {
+        KernelError::NotFound => EBADF,
+        KernelError::AlreadyExists => EADDRINUSE,
+        KernelError::InvalidAddress => EADDRNOTAVAIL,
+        KernelError::NotReady => EDESTADDRREQ,
+        error => errno(error),
+    }
+}
+
+/// The socket `fd` is, if it's this thread's.
+fn socket_of(fd: u64) -> Result<SocketId, i64> {
+    let id = fd
+        .checked_sub(FIRST_SOCKET_FD)
+        .filter(|&id| id <= u64::from(u32::MAX))
+        .map(|id| SocketId(id as u32))
+        .ok_or(EBADF)?;
+    match socket::owner(id) {
+        Ok(Some(owner)) if owner == scheduler::current() => Ok(id),
+        _ => Err(EBADF),
+    }
+}
+
+/// The `struct sockaddr_in` at `address`, `len` bytes long.
+fn copy_in_endpoint(address: u64, len: u64) -> Result<Endpoint, i64> {
+    if len < SOCKADDR_IN_LEN as u64 {
+        return Err(EINVAL);
+    }
+    let mut sockaddr = [0u8; SOCKADDR_IN_LEN];
+    let from = VirtAddr::try_new(address).map_err(|_| EFAULT)?;
+    crate::user::copy_in(&mut sockaddr, from).map_err(errno)?;
+    if u64::from(u16::from_ne_bytes([sockaddr[0], sockaddr[1]])) != AF_INET {
+        return Err(EAFNOSUPPORT);
+    }
+    Ok(Endpoint {
+        address: Ipv4Addr([sockaddr[4], sockaddr[5], sockaddr[6], sockaddr[7]]),
+        port: u16::from_be_bytes([sockaddr[2], sockaddr[3]]),
+    })
+}
+
+/// Fill in the `struct sockaddr_in` at `address` with `endpoint`, as
+/// much of it as the `socklen_t` at `len` says there's room for, and
+/// set that to how long it is.
+fn copy_out_endpoint(endpoint: Endpoint, address: u64, len: u64) -> Result<(), i64> {
+    let mut sockaddr = [0u8; SOCKADDR_IN_LEN];
+    sockaddr[..2].copy_from_slice(&(AF_INET as u16).to_ne_bytes());
+    sockaddr[2..4].copy_from_slice(&endpoint.port.to_be_bytes());
+    sockaddr[4..8].copy_from_slice(&endpoint.address.0);
+    let len = VirtAddr::try_new(len).map_err(|_| EFAULT)?;
+    let mut room = [0u8; 4];
+    crate::user::copy_in(&mut room, len).map_err(errno)?;
+    let room = (u32::from_ne_bytes(room) as usize).min(SOCKADDR_IN_LEN);
+    let to = VirtAddr::try_new(address).map_err(|_| EFAULT)?;
+    crate::user::copy_out(to, &sockaddr[..room]).map_err(errno)?;
+    crate::user::copy_out(len, &(SOCKADDR_IN_LEN as u32).to_ne_bytes()).map_err(errno)
+}
+
+fn open_socket(domain: u64, kind: u64, protocol: u64) -> Result<u64, i64> {
+    if domain != AF_INET {
+        return Err(EAFNOSUPPORT);
+    }
+    if kind & !SOCK_CLOEXEC != SOCK_DGRAM || (protocol != 0 && protocol != IPPROTO_UDP) {
+        return Err(EPROTONOSUPPORT);
+    }
+    let id = socket::open().map_err(errno)?;
+    socket::give_to(id, scheduler::current()).map_err(socket_errno)?;
+    Ok(FIRST_SOCKET_FD + u64::from(id.0))
+}
+
+fn bind(fd: u64, address: u64, len: u64) -> Result<u64, i64> {
+    let id = socket_of(fd)?;
+    let local = copy_in_endpoint(address, len)?;
+    socket::bind(id, local).map(|_| 0).map_err(socket_errno)
+}
+
+fn connect(fd: u64, address: u64, len: u64) -> Result<u64, i64> {
+    let id = socket_of(fd)?;
+    let peer = copy_in_endpoint(address, len)?;
+    socket::connect(id, peer).map(|()| 0).map_err(socket_errno)
+}
+
+/// `sendto`, to `address` or, if that's null, the connected peer. There
+/// are no flags worth taking for a datagram that's sent straight away.
+fn send_to(fd: u64, buffer: u64, len: u64, address: u64, address_len: u64) -> Result<u64, i64> {
+    let id = socket_of(fd)?;
+    if len > socket::MAX_DATAGRAM as u64 {
+        return Err(EMSGSIZE);
+    }
+    let to = match address {
+        0 => None,
+        address => Some(copy_in_endpoint(address, address_len)?),
+    };
+    let mut bytes = vec![0; len as usize];
+    let from = VirtAddr::try_new(buffer).map_err(|_| EFAULT)?;
+    crate::user::copy_in(&mut bytes, from).map_err(errno)?;
+    match socket::send_to(id, &bytes, to) {
+        Ok(sent) => Ok(sent as u64),
+        Err(KernelError::InvalidAddress) => Err(ENETUNREACH),
+        Err(error) => Err(socket_errno(error)),
+    }
+}
+
+/// `recvfrom`, which waits for a datagram unless it's `MSG_DONTWAIT`,
+/// and says who sent it if `address` isn't null.
+fn receive_from(
+    fd: u64,
+    buffer: u64,
+    len: u64,
+    flags: u64,
+    address: u64,
+    address_len: u64,
+) -> Result<u64, i64> {
+    let id = socket_of(fd)?;
+    let to = VirtAddr::try_new(buffer).map_err(|_| EFAULT)?;
+    let mut bytes = vec![0; len.min(socket::MAX_DATAGRAM as u64) as usize];
+    let (received, from) =
+        socket::receive_from(id, &mut bytes, flags & MSG_DONTWAIT == 0).map_err(socket_errno)?;
+    crate::user::copy_out(to, &bytes[..received]).map_err(errno)?;
+    if address != 0 {
+        copy_out_endpoint(from, address, address_len)?;
+    }
+    Ok(received as u64)
+}
+
+fn close(fd: u64) -> Result<u64, i64> {
+    let id = socket_of(fd)?;
+    socket::close(id).map(|()| 0).map_err(socket_errno)
+}
+
+/// Print `bytes`, or add them to what's captured if this thread's
+/// output is being.
+fn output(bytes: &[u8]) {
+    let thread = scheduler::current();
+    let mut capture = CAPTURE.lock();
+    match capture.as_mut() {
+        Some((capturing, captured)) if *capturing == thread => {
+            captured.push_str(&String::from_utf8_lossy(bytes))
+        }
+        _ => {
+            drop(capture);
+            print_bytes(bytes);
+        }
+    }
+}
+
+/// Print what's valid UTF-8, and a replacement character for each
+/// byte that isn't.
+fn print_bytes(mut bytes: &[u8]) {
+    while !bytes.is_empty() {
+        match core::str::from_utf8(bytes) {
+            Ok(text) => {
+                crate::print!("{}", text);
+                return;
+            }
+            Err(error) => {
+                let (valid, rest) = bytes.split_at(error.valid_up_to());
+                crate::print!("{}\u{fffd}", unsafe {
+                    core::str::from_utf8_unchecked(valid)
+                });
+                // A sequence cut off at the end of a chunk is lost too.
+                bytes = &rest[error.error_len().unwrap_or(rest.len())..];
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_write_yield_exit() {
+    use crate::usermode::TestProgram;
+
+    let program = TestProgram::new();
+    // An unknown one, then exit with what it returned.
+    //   mov eax, 1000; syscall; mov edi, eax; mov eax, 60; syscall
+    let unknown = program.run(&[
+        0xb8, 0xe8, 0x03, 0, 0, 0x0f, 0x05, 0x89, 0xc7, 0xb8, 60, 0, 0, 0, 0x0f, 0x05,
+    ]);
+    assert_eq!(unknown, Exit::Exited(-ENOSYS as i32));
+
+    // write(1, the message after the code, 6), yield, then exit with
+    // what write returned, as long as rdi was kept.
+    let code = [
+        0x48, 0x8d, 0x35, 38, 0, 0, 0, // lea rsi, [rip + 38]
+        0xbf, 1, 0, 0, 0, // mov edi, 1
+        0xba, 6, 0, 0, 0, // mov edx, 6
+        0xb8, 1, 0, 0, 0, // mov eax, 1
+        0x0f, 0x05, // syscall
+        0x83, 0xff, 0x01, // cmp edi, 1
+        0x75, 0x09, // jne out
+        0x89, 0xc7, // mov edi, eax
+        0xb8, 24, 0, 0, 0, // mov eax, 24
+        0x0f, 0x05, // syscall
+        0xb8, 60, 0, 0, 0, // out: mov eax, 60
+        0x0f, 0x05, // syscall
+        b'r', b'i', b'n', b'g', b'3', b'\n',
+    ];
+    assert_eq!(program.run(&code), Exit::Exited(6));
+
+    // A buffer that isn't mapped.
+    let mut bad = code;
+    bad[3..7].copy_from_slice(&(-0x1000_0000i32).to_le_bytes());
+    assert_eq!(program.run(&bad), Exit::Exited(-EFAULT as i32));
+}
+
+#[test_case]
+fn test_futex() {
+    use crate::usermode::TestProgram;
+
+    let program = TestProgram::new();
+    // futex(the word after the code, FUTEX_WAIT, 1, no timeout), then
+    // exit with what it returned.
+    let code = [
+        0x48, 0x8d, 0x3d, 33, 0, 0, 0, // lea rdi, [rip + 33]
+        0x31, 0xf6, // xor esi, esi
+        0xba, 1, 0, 0, 0, // mov edx, 1
+        0x49, 0xc7, 0xc2, 0, 0, 0, 0, // mov r10, 0
+        0xb8, 202, 0, 0, 0, // mov eax, 202
+        0x0f, 0x05, // syscall
+        0x89, 0xc7, // mov edi, eax
+        0xb8, 60, 0, 0, 0, // mov eax, 60
+        0x0f, 0x05, // syscall
+        0, 0, 0, // to line the word up
+        0, 0, 0, 0, // the word
+        0, 0, 0, 0, // to line the timeout up
+        0, 0, 0, 0, 0, 0, 0, 0, // 0 seconds
+        1, 0, 0, 0, 0, 0, 0, 0, // and a nanosecond
+    ];
+    // It's 0, so it doesn't wait.
+    assert_eq!(program.run(&code), Exit::Exited(-EAGAIN as i32));
+
+    // It is 0, and nothing wakes it.
+    let mut timed = code;
+    timed[10] = 0;
+    timed[14..21].copy_from_slice(&[0x4c, 0x8d, 0x15, 27, 0, 0, 0]); // lea r10, [rip + 27]
+    assert_eq!(program.run(&timed), Exit::Exited(-ETIMEDOUT as i32));
+
+    // FUTEX_WAKE, with nothing to wake.
+    let mut wake = code;
+    wake[7..9].copy_from_slice(&[0xff, 0xc6]); // inc esi
+    assert_eq!(program.run(&wake), Exit::Exited(0));
+}
+
+#[test_case]
+fn test_mmap_and_munmap() {
+    use crate::usermode::TestProgram;
+
+    let program = TestProgram::new();
+    // mmap a page, write to the end of it, munmap it and exit with
+    // what munmap returned.
+    let mut code = [
+        0x31, 0xff, // xor edi, edi
+        0xbe, 0, 0x10, 0, 0, // mov esi, 4096
+        0xba, 3, 0, 0, 0, // mov edx, PROT_READ | PROT_WRITE
+        0x41, 0xba, 0x22, 0, 0, 0, // mov r10d, MAP_PRIVATE | MAP_ANONYMOUS
+        0x49, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff, // mov r8, -1
+        0x45, 0x31, 0xc9, // xor r9d, r9d
+        0xb8, 9, 0, 0, 0, // mov eax, 9
+        0x0f, 0x05, // syscall
+        0x48, 0x89, 0xc7, // mov rdi, rax
+        0xc6, 0x87, 0xff, 0x0f, 0, 0, 5, // mov byte [rdi + 4095], 5
+        0xbe, 0, 0x10, 0, 0, // mov esi, 4096
+        0xb8, 11, 0, 0, 0, // mov eax, 11
+        0x0f, 0x05, // syscall
+        0x89, 0xc7, // mov edi, eax
+        0xb8, 60, 0, 0, 0, // mov eax, 60
+        0x0f, 0x05, // syscall
+    ];
+    assert_eq!(program.run(&code), Exit::Exited(0));
+
+    // Unmapping more than it got fails, and it's unmapped anyway once
+    // the program's done.
+    code[47] = 0x20;
+    assert_eq!(program.run(&code), Exit::Exited(-EINVAL as i32));
+    let start = VirtAddr::new(fs::mmap::MAP_START);
+    assert_eq!(crate::memory::translate(start), None);
+    assert_eq!(fs::mmap::fault(start, false), Err(KernelError::NotFound));
+}
+
+#[test_case]
+fn test_mkdir_and_stat() {
+    use crate::usermode::TestProgram;
+
+    let program = TestProgram::new();
+    // mkdir(the path after the code, 0o755), then exit with what it
+    // returned.
+    let code = [
+        0x48, 0x8d, 0x3d, 21, 0, 0, 0, // lea rdi, [rip + 21]
+        0xbe, 0xed, 0x01, 0, 0, // mov esi, 0o755
+        0xb8, 83, 0, 0, 0, // mov eax, 83
+        0x0f, 0x05, // syscall
+        0x89, 0xc7, // mov edi, eax
+        0xb8, 60, 0, 0, 0, // mov eax, 60
+        0x0f, 0x05, // syscall
+        b'/', b's', b'y', b's', b'c', b'a', b'l', b'l', 0,
+    ];
+    assert_eq!(program.run(&code), Exit::Exited(0));
+    assert_eq!(
+        fs::stat("/syscall").map(|stat| stat.kind),
+        Ok(Kind::Directory)
+    );
+    assert_eq!(program.run(&code), Exit::Exited(-EEXIST as i32));
+    fs::rmdir("/syscall").unwrap();
+    assert_eq!(size_of::<Stat>(), 144);
+}
+
+#[test_case]
+fn test_datagram_sockets() {
+    use crate::usermode::TestProgram;
+
+    let localhost = |port| Endpoint {
+        address: Ipv4Addr([127, 0, 0, 1]),
+        port,
+    };
+    let server = socket::open().unwrap();
+    socket::bind(server, localhost(5000)).unwrap();
+    let program = TestProgram::new();
+    // socket(AF_INET, SOCK_DGRAM, 0), sendto(it, the message after the
+    // code, 5, 0, 127.0.0.1:5000, 16), then exit with what that
+    // returned.
+    let code = [
+        0xbf, 2, 0, 0, 0, // mov edi, AF_INET
+        0xbe, 2, 0, 0, 0, // mov esi, SOCK_DGRAM
+        0x31, 0xd2, // xor edx, edx
+        0xb8, 41, 0, 0, 0, // mov eax, 41
+        0x0f, 0x05, // syscall
+        0x89, 0xc7, // mov edi, eax
+        0x48, 0x8d, 0x35, 37, 0, 0, 0, // lea rsi, [rip + 37]
+        0xba, 5, 0, 0, 0, // mov edx, 5
+        0x45, 0x31, 0xd2, // xor r10d, r10d
+        0x4c, 0x8d, 0x05, 27, 0, 0, 0, // lea r8, [rip + 27]
+        0x41, 0xb9, 16, 0, 0, 0, // mov r9d, 16
+        0xb8, 44, 0, 0, 0, // mov eax, 44
+        0x0f, 0x05, // syscall
+        0x89, 0xc7, // mov edi, eax
+        0xb8, 60, 0, 0, 0, // mov eax, 60
+        0x0f, 0x05, // syscall
+        b'h', b'e', b'l', b'l', b'o', // the message
+        2, 0, 0x13, 0x88, 127, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, // 127.0.0.1:5000
+    ];
+    assert_eq!(program.run(&code), Exit::Exited(5));
+    let mut buffer = [0; 16];
+    let (len, _) = socket::receive_from(server, &mut buffer, false).unwrap();
+    assert_eq!(&buffer[..len], b"hello");
+    socket::close(server).unwrap();
+
+    // Nobody's on it now, so it's dropped, but it was still sent.
+    assert_eq!(program.run(&code), Exit::Exited(5));
+
+    // socket, bind(it, 127.0.0.1:5001, 16), sendto that, and
+    // recvfrom(it, the stack, 16, MSG_DONTWAIT, null, null), then exit
+    // with what that returned.
+    let code = [
+        0xbf, 2, 0, 0, 0, // mov edi, AF_INET
+        0xbe, 2, 0, 0, 0, // mov esi, SOCK_DGRAM
+        0x31, 0xd2, // xor edx, edx
+        0xb8, 41, 0, 0, 0, // mov eax, 41
+        0x0f, 0x05, // syscall
+        0x89, 0xc3, // mov ebx, eax
+        0x89, 0xc7, // mov edi, eax
+        0x48, 0x8d, 0x35, 94, 0, 0, 0, // lea rsi, [rip + 94]
+        0xba, 16, 0, 0, 0, // mov edx, 16
+        0xb8, 49, 0, 0, 0, // mov eax, 49
+        0x0f, 0x05, // syscall
+        0x89, 0xdf, // mov edi, ebx
+        0x48, 0x8d, 0x35, 68, 0, 0, 0, // lea rsi, [rip + 68]
+        0xba, 5, 0, 0, 0, // mov edx, 5
+        0x45, 0x31, 0xd2, // xor r10d, r10d
+        0x4c, 0x8d, 0x05, 58, 0, 0, 0, // lea r8, [rip + 58]
+        0x41, 0xb9, 16, 0, 0, 0, // mov r9d, 16
+        0xb8, 44, 0, 0, 0, // mov eax, 44
+        0x0f, 0x05, // syscall
+        0x89, 0xdf, // mov edi, ebx
+        0x48, 0x8d, 0x74, 0x24, 0xf0, // lea rsi, [rsp - 16]
+        0xba, 16, 0, 0, 0, // mov edx, 16
+        0x41, 0xba, 0x40, 0, 0, 0, // mov r10d, MSG_DONTWAIT
+        0x4d, 0x31, 0xc0, // xor r8, r8
+        0x4d, 0x31, 0xc9, // xor r9, r9
+        0xb8, 45, 0, 0, 0, // mov eax, 45
+        0x0f, 0x05, // syscall
+        0x89, 0xc7, // mov edi, eax
+        0xb8, 60, 0, 0, 0, // mov eax, 60
+        0x0f, 0x05, // syscall
+        b'h', b'e', b'l', b'l', b'o', // the message
+        2, 0, 0x13, 0x89, 127, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, // 127.0.0.1:5001
+    ];
+    assert_eq!(program.run(&code), Exit::Exited(5));
+    // The program didn't close it, but it's closed now it's done.
+    let socket = socket::open().unwrap();
+    assert_eq!(socket::bind(socket, localhost(5001)), Ok(localhost(5001)));
+    socket::close(socket).unwrap();
+
+    // Nothing sent, it yields instead, so nothing to take.
+    let mut quiet = code;
+    quiet[73] = 24;
+    assert_eq!(program.run(&quiet), Exit::Exited(-EAGAIN as i32));
+}
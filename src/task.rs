@@ -0,0 +1,107 @@
+//! Futures the kernel runs, and what runs them.
+//!
+//! A `Task` is a boxed `Future` with an id. `SimpleExecutor` polls its
+//! tasks round and round until they're all done, which is enough for
+//! tests. `Executor` only polls a task again once its `Waker` has been
+//! called, and halts when nothing has been, so a driver can hand out a
+//! future that's woken from its interrupt handler instead of having
+//! `_start` poll it.
+//!
+//! `WakerSlot` is the driver's half of that: `register` the waker in
+//! `poll` before returning `Pending`, `wake` it from the handler.
+use crate::latency;
+use alloc::boxed::Box;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+
+pub mod executor;
+pub mod simple_executor;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> TaskId {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+pub struct Task {
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+impl Task {
+    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task {
+            id: TaskId::new(),
+            future: Box::pin(future),
+        }
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
+
+/// The waker of whoever is waiting on something, if anyone is. Fine to
+/// `wake` from an interrupt handler.
+pub struct WakerSlot {
+    waker: Mutex<Option<Waker>>,
+}
+
+impl WakerSlot {
+    pub const fn new() -> WakerSlot {
+        WakerSlot {
+            waker: Mutex::new(None),
+        }
+    }
+
+    /// Have the next `wake` wake `waker`, instead of whatever was
+    /// registered before.
+    pub fn register(&self, waker: &Waker) {
+        latency::without_interrupts(|| {
+            let mut slot = self.waker.lock();
+            if !slot.as_ref().map_or(false, |old| old.will_wake(waker)) {
+                *slot = Some(waker.clone());
+            }
+        });
+    }
+
+    /// Wake whoever registered last, once.
+    pub fn wake(&self) {
+        // Not with the lock held, waking could register again.
+        if let Some(waker) = latency::without_interrupts(|| self.waker.lock().take()) {
+            waker.wake();
+        }
+    }
+}
+
+/// A future that's pending once, and wakes itself so it gets polled
+/// again: lets the other tasks have a go.
+pub async fn yield_now() {
+    struct YieldNow(bool);
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            context.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    YieldNow(false).await
+}
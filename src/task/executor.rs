@@ -0,0 +1,197 @@
+//! Runs tasks when they've been woken, and halts when none have.
+//!
+//! Each task has one `TaskWaker`, made when it's spawned, which every
+//! clone of its `Waker` shares. Waking it puts the task's id on the
+//! queue, unless it's on there already, and `run_ready_tasks` polls
+//! whatever is on the queue. Wakers can be called from interrupt
+//! handlers, so the queue is only ever locked with interrupts off.
+//!
+//! `run` checks the queue with interrupts off before it halts, see
+//! `idle::idle_unless`: a wake between looking and halting would
+//! otherwise sit there until some other interrupt came along.
+use super::{Task, TaskId};
+use crate::error::{KernelError, KernelResult};
+use crate::{idle, latency};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+
+/// Tasks an executor runs at once.
+pub const MAX_TASKS: usize = 100;
+
+/// Ids of tasks that have been woken, oldest first.
+struct TaskQueue(Mutex<VecDeque<TaskId>>);
+
+impl TaskQueue {
+    fn push(&self, id: TaskId) {
+        // With every task on it at most once it doesn't grow past what
+        // it started with, but it could if wakers outlive their tasks.
+        // Allocating with interrupts off is fine.
+        latency::without_interrupts(|| self.0.lock().push_back(id))
+    }
+
+    fn pop(&self) -> Option<TaskId> {
+        latency::without_interrupts(|| self.0.lock().pop_front())
+    }
+
+    fn is_empty(&self) -> bool {
+        latency::without_interrupts(|| self.0.lock().is_empty())
+    }
+}
+
+struct TaskWaker {
+    id: TaskId,
+    /// On the queue already, waking it again changes nothing.
+    queued: AtomicBool,
+    queue: Arc<TaskQueue>,
+}
+
+impl TaskWaker {
+    fn wake_task(&self) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            self.queue.push(self.id);
+        }
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
+
+pub struct Executor {
+    tasks: BTreeMap<TaskId, (Task, Arc<TaskWaker>)>,
+    queue: Arc<TaskQueue>,
+}
+
+impl Default for Executor {
+    fn default() -> Executor {
+        Executor::new()
+    }
+}
+
+impl Executor {
+    pub fn new() -> Executor {
+        Executor {
+            tasks: BTreeMap::new(),
+            queue: Arc::new(TaskQueue(Mutex::new(VecDeque::with_capacity(MAX_TASKS)))),
+        }
+    }
+
+    /// Add `task`, to be polled for the first time on the next
+    /// `run_ready_tasks`. `NoSpace` if there are `MAX_TASKS` already.
+    pub fn spawn(&mut self, task: Task) -> KernelResult<()> {
+        if self.tasks.len() >= MAX_TASKS {
+            return Err(KernelError::NoSpace);
+        }
+        let id = task.id;
+        let waker = Arc::new(TaskWaker {
+            id,
+            queued: AtomicBool::new(false),
+            queue: self.queue.clone(),
+        });
+        waker.wake_task();
+        self.tasks.insert(id, (task, waker));
+        Ok(())
+    }
+
+    /// Tasks that haven't finished.
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// Poll every task that's been woken, including those woken while
+    /// this is going on.
+    pub fn run_ready_tasks(&mut self) {
+        while let Some(id) = self.queue.pop() {
+            // Its waker may be around still after it finished.
+            let (task, task_waker) = match self.tasks.get_mut(&id) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            // Before polling, any wake from here on has to poll it
+            // again.
+            task_waker.queued.store(false, Ordering::Release);
+            let waker = Waker::from(task_waker.clone());
+            let mut context = Context::from_waker(&waker);
+            if let Poll::Ready(()) = task.poll(&mut context) {
+                self.tasks.remove(&id);
+            }
+        }
+    }
+
+    /// Run tasks as they're woken, forever, halting in between.
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            let queue = &self.queue;
+            idle::idle_unless(|| !queue.is_empty());
+        }
+    }
+}
+
+#[test_case]
+fn test_polled_when_woken() {
+    use super::WakerSlot;
+    use alloc::rc::Rc;
+    use core::cell::Cell;
+    use core::future::Future;
+    use core::pin::Pin;
+
+    static SLOT: WakerSlot = WakerSlot::new();
+    static READY: AtomicBool = AtomicBool::new(false);
+
+    /// What a driver would hand out.
+    struct Event;
+
+    impl Future for Event {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+            SLOT.register(context.waker());
+            if READY.swap(false, Ordering::AcqRel) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }
+    }
+
+    let polls = Rc::new(Cell::new(0));
+    let mut executor = Executor::new();
+    let counted = polls.clone();
+    executor
+        .spawn(Task::new(async move {
+            counted.set(counted.get() + 1);
+            Event.await;
+            super::yield_now().await;
+            counted.set(counted.get() + 1);
+        }))
+        .unwrap();
+    executor.run_ready_tasks();
+    assert_eq!(polls.get(), 1);
+    assert_eq!(executor.len(), 1);
+
+    // Nothing to do until the event comes along.
+    executor.run_ready_tasks();
+    assert!(executor.queue.is_empty());
+    READY.store(true, Ordering::Release);
+    SLOT.wake();
+    executor.run_ready_tasks();
+    assert_eq!(polls.get(), 2);
+    assert!(executor.is_empty());
+    // Woken only once, however often it's woken.
+    assert!(executor.queue.is_empty());
+}
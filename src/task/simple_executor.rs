@@ -0,0 +1,70 @@
+//! Runs tasks by polling whichever is next until they're all done.
+//!
+//! The waker it hands out does nothing, a task that's pending is just
+//! polled again on the next round. It never sleeps, see `Executor` for
+//! one that does.
+use super::Task;
+use alloc::collections::VecDeque;
+use core::ptr;
+use core::task::{Context, RawWaker, RawWakerVTable, Waker};
+
+fn dummy_raw_waker() -> RawWaker {
+    fn no_op(_: *const ()) {}
+    fn clone(_: *const ()) -> RawWaker {
+        dummy_raw_waker()
+    }
+
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, no_op, no_op, no_op);
+    RawWaker::new(ptr::null(), &VTABLE)
+}
+
+fn dummy_waker() -> Waker {
+    // The vtable doesn't touch the data pointer at all.
+    unsafe { Waker::from_raw(dummy_raw_waker()) }
+}
+
+#[derive(Default)]
+pub struct SimpleExecutor {
+    task_queue: VecDeque<Task>,
+}
+
+impl SimpleExecutor {
+    pub fn new() -> SimpleExecutor {
+        SimpleExecutor::default()
+    }
+
+    pub fn spawn(&mut self, task: Task) {
+        self.task_queue.push_back(task)
+    }
+
+    /// Poll every task until it's done.
+    pub fn run(&mut self) {
+        let waker = dummy_waker();
+        let mut context = Context::from_waker(&waker);
+        while let Some(mut task) = self.task_queue.pop_front() {
+            if task.poll(&mut context).is_pending() {
+                self.task_queue.push_back(task);
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_runs_everything() {
+    use alloc::rc::Rc;
+    use core::cell::Cell;
+
+    let done = Rc::new(Cell::new(0));
+    let mut executor = SimpleExecutor::new();
+    for yields in 0..3 {
+        let done = done.clone();
+        executor.spawn(Task::new(async move {
+            for _ in 0..yields {
+                super::yield_now().await;
+            }
+            done.set(done.get() + 1);
+        }));
+    }
+    executor.run();
+    assert_eq!(done.get(), 3);
+}
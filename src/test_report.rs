@@ -0,0 +1,235 @@
+//! Test result reporting.
+//!
+//! Every test prints the usual human readable `name...\t[ok]` line and
+//! is followed by one line of JSON, so a script on the host can pick up
+//! the results by looking for lines starting with `{`:
+//!
+//! ```text
+//! {"event":"test","name":"blog_os::foo","result":"ok","cycles":1234}
+//! {"event":"test","name":"blog_os::bar","result":"failed","message":"..."}
+//! {"event":"summary","total":5,"passed":1,"failed":1,"filtered":0,"result":"failed"}
+//! ```
+//!
+//! As we abort on panic, the first failure ends the run. The summary
+//! then tells how many tests passed before it and how many never ran.
+//!
+//! Which tests run can be narrowed down on the kernel command line,
+//! see `cmdline`: `test=NAME` only runs tests with `NAME` somewhere in
+//! their name and `shard=I/N` splits the suite into `N` shards and
+//! only runs the `I`th, counting from 0. CI can run the shards in
+//! separate QEMUs at the same time.
+use crate::boot_timing::read_tsc;
+use crate::platform::{self, ExitStatus};
+use crate::{serial_print, serial_println};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+static TOTAL: AtomicUsize = AtomicUsize::new(0);
+static PASSED: AtomicUsize = AtomicUsize::new(0);
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+static FILTERED: AtomicUsize = AtomicUsize::new(0);
+
+/// The test that is running right now and when it started.
+static CURRENT: Mutex<Option<(&'static str, u64)>> = Mutex::new(None);
+
+/// Wraps anything printable so that it comes out as a JSON string.
+pub struct Json<T>(pub T);
+
+impl<T: fmt::Display> fmt::Display for Json<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_char('"')?;
+        write!(JsonEscaper(f), "{}", self.0)?;
+        f.write_char('"')
+    }
+}
+
+/// Escapes everything that isn't allowed in a JSON string on the way through.
+struct JsonEscaper<'a, 'b>(&'a mut fmt::Formatter<'b>);
+
+impl fmt::Write for JsonEscaper<'_, '_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '"' => self.0.write_str("\\\"")?,
+                '\\' => self.0.write_str("\\\\")?,
+                '\n' => self.0.write_str("\\n")?,
+                '\r' => self.0.write_str("\\r")?,
+                '\t' => self.0.write_str("\\t")?,
+                c if (c as u32) < 0x20 => write!(self.0, "\\u{:04x}", c as u32)?,
+                c => self.0.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Which tests to run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Filter<'a> {
+    /// Part of the names of the tests to run.
+    pub name: Option<&'a str>,
+    /// Which shard to run and how many there are.
+    pub shard: (usize, usize),
+}
+
+impl<'a> Filter<'a> {
+    /// Every test.
+    pub const ALL: Filter<'static> = Filter {
+        name: None,
+        shard: (0, 1),
+    };
+
+    /// The filter `line` asks for, a kernel command line.
+    pub fn parse(line: &'a str) -> Result<Filter<'a>, &'static str> {
+        let name = crate::cmdline::value_in(line, "test");
+        let shard = match crate::cmdline::value_in(line, "shard") {
+            None => (0, 1),
+            Some(shard) => {
+                let mut parts = shard.splitn(2, '/');
+                let index = parts.next().and_then(|part| part.parse().ok());
+                let count = parts.next().and_then(|part| part.parse().ok());
+                match (index, count) {
+                    (Some(index), Some(count)) if index < count => (index, count),
+                    _ => return Err("shard has to be I/N with I less than N"),
+                }
+            }
+        };
+        Ok(Filter { name, shard })
+    }
+
+    /// Whether to run the test `name`, the `index`th in the suite.
+    /// Shards go by where tests are in the suite, so they come out
+    /// about the same size whatever the names.
+    pub fn selects(&self, index: usize, name: &str) -> bool {
+        let (shard, count) = self.shard;
+        index % count == shard && self.name.map_or(true, |part| name.contains(part))
+    }
+}
+
+/// The filter on the kernel command line. One that doesn't parse
+/// fails the run, rather than quietly running the wrong tests.
+pub fn filter() -> Filter<'static> {
+    Filter::parse(crate::cmdline::get()).unwrap_or_else(|error| fail(&error))
+}
+
+/// Announce how many tests are about to run, and how many the filter
+/// left out.
+pub fn begin_suite(total: usize, filtered: usize) {
+    TOTAL.store(total, Ordering::SeqCst);
+    FILTERED.store(filtered, Ordering::SeqCst);
+    if filtered == 0 {
+        serial_println!("Running {} tests", total);
+    } else {
+        serial_println!("Running {} tests, {} filtered out", total, filtered);
+    }
+}
+
+pub fn begin_test(name: &'static str) {
+    serial_print!("{}...\t", name);
+    *CURRENT.lock() = Some((name, read_tsc()));
+}
+
+/// The current test finished successfully.
+pub fn pass() {
+    let (name, start) = CURRENT.lock().take().unwrap_or(("<unknown>", read_tsc()));
+    PASSED.fetch_add(1, Ordering::SeqCst);
+    serial_println!("[ok]");
+    serial_println!(
+        "{{\"event\":\"test\",\"name\":{},\"result\":\"ok\",\"cycles\":{}}}",
+        Json(name),
+        read_tsc().saturating_sub(start)
+    );
+}
+
+/// The current test failed. Reports it along with the summary and exits.
+pub fn fail(reason: &dyn fmt::Display) -> ! {
+    let name = CURRENT.try_lock().and_then(|mut current| current.take());
+    let name = name.map_or("<unknown>", |(name, _)| name);
+    FAILED.fetch_add(1, Ordering::SeqCst);
+    // This is the panic handler for tests, so the serial lock might
+    // well be held by whatever panicked.
+    let mut out = crate::serial::panic_writer();
+    let _ = writeln!(out, "[failed]\n");
+    let _ = writeln!(out, "Error: {}\n", reason);
+    let _ = writeln!(out, "{}", crate::unwind::Backtrace::capture());
+    let _ = writeln!(
+        out,
+        "{{\"event\":\"test\",\"name\":{},\"result\":\"failed\",\"message\":{}}}",
+        Json(name),
+        Json(reason)
+    );
+    drop(out);
+    finish()
+}
+
+/// The current test ran out of time. Reports it and exits.
+pub fn timed_out(name: &str, timeout_ms: u64) -> ! {
+    serial_println!("[timeout]\n");
+    serial_println!("Error: {} did not finish within {} ms\n", name, timeout_ms);
+    FAILED.fetch_add(1, Ordering::SeqCst);
+    serial_println!(
+        "{{\"event\":\"test\",\"name\":{},\"result\":\"timeout\",\"timeout_ms\":{}}}",
+        Json(name),
+        timeout_ms
+    );
+    print_summary();
+    platform::exit(ExitStatus::Timeout)
+}
+
+fn print_summary() {
+    let passed = PASSED.load(Ordering::SeqCst);
+    let failed = FAILED.load(Ordering::SeqCst);
+    let filtered = FILTERED.load(Ordering::SeqCst);
+    // Standalone test binaries never call `begin_suite`.
+    let total = TOTAL.load(Ordering::SeqCst).max(passed + failed);
+    let result = if failed == 0 { "ok" } else { "failed" };
+
+    // Also runs after failures, see `fail`.
+    let mut out = crate::serial::panic_writer();
+    let _ = writeln!(
+        out,
+        "{} passed, {} failed, {} not run",
+        passed,
+        failed,
+        total - passed - failed
+    );
+    let _ = writeln!(
+        out,
+        "{{\"event\":\"summary\",\"total\":{},\"passed\":{},\"failed\":{},\"filtered\":{},\"result\":{}}}",
+        total,
+        passed,
+        failed,
+        filtered,
+        Json(result)
+    );
+}
+
+/// Print the summary and exit QEMU with a code matching the results.
+pub fn finish() -> ! {
+    print_summary();
+    if FAILED.load(Ordering::SeqCst) == 0 {
+        platform::exit(ExitStatus::Success)
+    } else {
+        platform::exit(ExitStatus::Failed)
+    }
+}
+
+#[test_case]
+fn test_json_escaping() {
+    let mut buffer = alloc::string::String::new();
+    write!(buffer, "{}", Json("a \"b\"\\\n\u{1}")).unwrap();
+    assert_eq!(buffer.as_bytes(), &b"\"a \\\"b\\\"\\\\\\n\\u0001\""[..]);
+}
+
+#[test_case]
+fn test_filter() {
+    let filter = Filter::parse("quiet test=vga shard=1/3").unwrap();
+    assert_eq!(filter.name, Some("vga"));
+    assert!(filter.selects(4, "blog_os::vga_buffer::test_println"));
+    assert!(!filter.selects(3, "blog_os::vga_buffer::test_println"));
+    assert!(!filter.selects(4, "blog_os::ui::test_table"));
+    assert_eq!(Filter::parse("").unwrap(), Filter::ALL);
+    assert!(Filter::parse("shard=3/3").is_err());
+    assert!(Filter::parse("shard=1").is_err());
+}
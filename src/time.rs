@@ -0,0 +1,601 @@
+//! Time since boot and the time of day.
+//!
+//! There are a few counters that can tell the time, each described by
+//! a `ClockSource`. `init` probes them and picks the best one that
+//! works, everything else goes through `monotonic_ns` so it doesn't
+//! need to care which one that was:
+//!
+//! - the PIT, counted in timer interrupts. Always there but coarse.
+//! - the TSC, calibrated against PIT channel 2. Only trusted if the
+//!   CPU says it's invariant, an older one changes speed with the CPU.
+//! - the HPET's main counter. Like kvmclock it needs the physical
+//!   memory mapping, to find the ACPI table and reach the registers, so
+//!   it turns up with `hpet::enable`.
+//! - kvmclock, the time according to the host under KVM. It only
+//!   turns up with `kvm::enable`.
+//!
+//! `hpet::enable` and `kvm::enable` both call `probe_again`. The local
+//! APIC timer isn't one: it counts down, and `idle` rearms it to wake
+//! up for the next deadline.
+//!
+//! The time of day comes from the CMOS clock, read once at boot, until
+//! something that knows better, like an NTP server's answer that
+//! `net::sntp` parses, says what it is with `discipline`. That steps
+//! the clock to it and, given a few of those a while apart, works out
+//! how fast the clock source drifts against it, so the time of day
+//! keeps better in between.
+use crate::error::{KernelError, KernelResult};
+use crate::interrupts::{COUNTERS, TIMER_COUNTER};
+use crate::latency;
+use crate::shell::{self, CommandFailed, CommandResult};
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+/// We don't reprogram the PIT, so it runs at the BIOS default of
+/// 1193182 / 65536 Hz, which is one tick every ~54.9 ms.
+pub const MILLISECONDS_PER_TICK: u64 = 55;
+
+const PIT_FREQUENCY: u64 = 1_193_182;
+pub const NANOSECONDS_PER_TICK: u64 = 65536 * 1_000_000_000 / PIT_FREQUENCY;
+
+/// Drift is only worked out over at least this long, over less it's
+/// lost in how far off each time we're told is.
+pub const MIN_DRIFT_INTERVAL_NS: u64 = 60 * 1_000_000_000;
+/// The most a clock is taken to be off by, NTP's 500 ppm.
+pub const MAX_DRIFT_PPB: i64 = 500_000;
+
+/// A counter we can tell the time with.
+pub struct ClockSource {
+    pub name: &'static str,
+    /// Higher is better: finer resolution and steadier.
+    pub quality: u32,
+    /// Get the source ready, false if this machine doesn't have it.
+    probe: fn() -> bool,
+    /// Nanoseconds since some fixed point, which differs per source.
+    read_ns: fn() -> u64,
+}
+
+/// Everything we know how to use, worst first.
+static SOURCES: [ClockSource; 4] = [
+    ClockSource {
+        name: "pit",
+        quality: 10,
+        probe: pit_probe,
+        read_ns: pit_read_ns,
+    },
+    ClockSource {
+        name: "hpet",
+        quality: 250,
+        probe: crate::hpet::clock_probe,
+        read_ns: crate::hpet::clock_read_ns,
+    },
+    ClockSource {
+        name: "tsc",
+        quality: 300,
+        probe: tsc_probe,
+        read_ns: tsc_read_ns,
+    },
+    ClockSource {
+        name: "kvmclock",
+        quality: 350,
+        probe: crate::kvm::clock_probe,
+        read_ns: crate::kvm::clock_read_ns,
+    },
+];
+
+/// Index into `SOURCES` of the one in use.
+static SELECTED: AtomicUsize = AtomicUsize::new(0);
+/// Bit `i` is set if `SOURCES[i]` probed fine.
+static AVAILABLE: AtomicUsize = AtomicUsize::new(1);
+/// Added to the selected source's reading, so switching sources
+/// carries on from where the previous one was.
+static OFFSET_NS: AtomicU64 = AtomicU64::new(0);
+/// The latest time handed out, nothing earlier is returned after it.
+static LAST_NS: AtomicU64 = AtomicU64::new(0);
+/// Seconds since 1970 when `init` read the CMOS clock.
+static BOOT_UNIX_TIME: AtomicU64 = AtomicU64::new(0);
+
+/// The time of day since `discipline` first set it.
+static WALL_CLOCK: Mutex<Option<Discipline>> = Mutex::new(None);
+
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+/// Time `idle` kept the PIT masked for, counted in as ticks.
+static SKIPPED_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Pick a clock source, read the CMOS clock and add the `uptime` and
+/// `clocksource` shell commands.
+pub fn init() {
+    let best = probe(0);
+    select(best);
+    crate::klog!(Info, "using {} as clock source", SOURCES[best].name);
+    BOOT_UNIX_TIME.store(read_cmos().unix_time(), Ordering::Relaxed);
+
+    shell::register("uptime", "time since interrupts came on", uptime_command)
+        .expect("uptime command");
+    shell::register(
+        "clocksource",
+        "clocksource [NAME]: list clock sources or switch",
+        clocksource_command,
+    )
+    .expect("clocksource command");
+}
+
+/// Probe the sources not in `available` yet, and return the best one
+/// there is now.
+fn probe(mut available: usize) -> usize {
+    for (index, source) in SOURCES.iter().enumerate() {
+        if available & 1 << index == 0 && (source.probe)() {
+            available |= 1 << index;
+        }
+    }
+    AVAILABLE.store(available, Ordering::Relaxed);
+    (0..SOURCES.len())
+        .filter(|index| available & 1 << index != 0)
+        .max_by_key(|&index| SOURCES[index].quality)
+        .unwrap_or(0)
+}
+
+/// Probe the sources that weren't there at `init` again, and switch to
+/// the best one if it's new.
+pub fn probe_again() {
+    let best = probe(AVAILABLE.load(Ordering::Relaxed));
+    if best != SELECTED.load(Ordering::Relaxed) {
+        select(best);
+        crate::klog!(Info, "switched to {} as clock source", SOURCES[best].name);
+    }
+}
+
+fn select(index: usize) {
+    latency::without_interrupts(|| {
+        let now = monotonic_ns();
+        SELECTED.store(index, Ordering::Relaxed);
+        let reading = (SOURCES[index].read_ns)();
+        OFFSET_NS.store(now.wrapping_sub(reading), Ordering::Relaxed);
+    });
+}
+
+/// How fast the TSC runs, if it's the clock source.
+pub fn tsc_hz() -> Option<u64> {
+    match TSC_HZ.load(Ordering::Relaxed) {
+        0 => None,
+        hz if clock_source().name == "tsc" => Some(hz),
+        _ => None,
+    }
+}
+
+/// The clock source in use.
+pub fn clock_source() -> &'static ClockSource {
+    &SOURCES[SELECTED.load(Ordering::Relaxed)]
+}
+
+/// Nanoseconds since interrupts came on. Never goes backwards, even
+/// across a switch of clock source.
+pub fn monotonic_ns() -> u64 {
+    let source = clock_source();
+    let now = (source.read_ns)().wrapping_add(OFFSET_NS.load(Ordering::Relaxed));
+    let last = LAST_NS.fetch_max(now, Ordering::Relaxed);
+    now.max(last)
+}
+
+/// Timer interrupts since they were enabled, and the ones `idle`
+/// slept through.
+pub fn ticks() -> u64 {
+    COUNTERS[TIMER_COUNTER].count() + SKIPPED_NS.load(Ordering::Relaxed) / NANOSECONDS_PER_TICK
+}
+
+/// Count `ns` without timer interrupts in as ticks.
+pub(crate) fn skip_ticks(ns: u64) {
+    SKIPPED_NS.fetch_add(ns, Ordering::Relaxed);
+}
+
+/// How long we have been up.
+pub fn uptime_ms() -> u64 {
+    monotonic_ns() / 1_000_000
+}
+
+/// Seconds since 1970, going by the CMOS clock at boot and the clock
+/// source since, or what `discipline` was told last.
+pub fn unix_time() -> u64 {
+    unix_time_ns() / 1_000_000_000
+}
+
+pub fn unix_time_ns() -> u64 {
+    let now = monotonic_ns();
+    match latency::without_interrupts(|| *WALL_CLOCK.lock()) {
+        Some(wall_clock) => wall_clock.unix_ns(now),
+        None => BOOT_UNIX_TIME.load(Ordering::Relaxed) * 1_000_000_000 + now,
+    }
+}
+
+/// It was `unix_ns` at `monotonic_ns()` `mono_ns`. How far the time of
+/// day was off, positive if it was behind.
+pub fn discipline(mono_ns: u64, unix_ns: u64) -> i64 {
+    let was = unix_time_ns();
+    latency::without_interrupts(|| {
+        let mut wall_clock = WALL_CLOCK.lock();
+        match wall_clock.as_mut() {
+            Some(wall_clock) => wall_clock.update(mono_ns, unix_ns),
+            None => *wall_clock = Some(Discipline::new(mono_ns, unix_ns)),
+        }
+    });
+    unix_time_ns() as i64 - was as i64
+}
+
+/// How the time of day is kept, once `discipline` has set it.
+pub fn wall_clock() -> Option<Discipline> {
+    latency::without_interrupts(|| *WALL_CLOCK.lock())
+}
+
+/// The time of day as the time at one point and how fast the clock
+/// source runs against it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Discipline {
+    mono_ns: u64,
+    unix_ns: u64,
+    drift_ppb: i64,
+    /// How many times drift was worked out.
+    estimates: u32,
+}
+
+impl Discipline {
+    /// It was `unix_ns` at `mono_ns`, with no idea of the drift yet.
+    pub fn new(mono_ns: u64, unix_ns: u64) -> Discipline {
+        Discipline {
+            mono_ns,
+            unix_ns,
+            drift_ppb: 0,
+            estimates: 0,
+        }
+    }
+
+    /// Parts per billion the clock source runs slow, negative if it's
+    /// fast.
+    pub fn drift_ppb(&self) -> i64 {
+        self.drift_ppb
+    }
+
+    /// The time of day at `mono_ns`.
+    pub fn unix_ns(&self, mono_ns: u64) -> u64 {
+        let elapsed = i128::from(mono_ns.saturating_sub(self.mono_ns));
+        let correction = elapsed * i128::from(self.drift_ppb) / 1_000_000_000;
+        (i128::from(self.unix_ns) + elapsed + correction) as u64
+    }
+
+    /// It was `unix_ns` at `mono_ns`. Step to that, and if the last
+    /// time was long enough ago see how far the clock source got off
+    /// since then, averaged with what it was before.
+    pub fn update(&mut self, mono_ns: u64, unix_ns: u64) {
+        let elapsed = mono_ns.saturating_sub(self.mono_ns);
+        if elapsed >= MIN_DRIFT_INTERVAL_NS {
+            let gained = i128::from(unix_ns) - i128::from(self.unix_ns) - i128::from(elapsed);
+            let drift = (gained * 1_000_000_000 / i128::from(elapsed))
+                .max(-i128::from(MAX_DRIFT_PPB))
+                .min(i128::from(MAX_DRIFT_PPB)) as i64;
+            self.drift_ppb = if self.estimates == 0 {
+                drift
+            } else {
+                (self.drift_ppb + drift) / 2
+            };
+            self.estimates += 1;
+        }
+        self.mono_ns = mono_ns;
+        self.unix_ns = unix_ns;
+    }
+}
+
+fn pit_probe() -> bool {
+    true
+}
+
+fn pit_read_ns() -> u64 {
+    ticks() * NANOSECONDS_PER_TICK
+}
+
+/// Only use the TSC if it runs at the same rate whatever the CPU is
+/// doing, then see how fast that is.
+fn tsc_probe() -> bool {
+    let invariant = unsafe {
+        __cpuid(0x8000_0000).eax >= 0x8000_0007 && __cpuid(0x8000_0007).edx & 1 << 8 != 0
+    };
+    if !invariant {
+        return false;
+    }
+    match calibrate_tsc() {
+        Ok(hz) => {
+            TSC_HZ.store(hz, Ordering::Relaxed);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+fn tsc_read_ns() -> u64 {
+    let hz = TSC_HZ.load(Ordering::Relaxed);
+    let cycles = unsafe { _rdtsc() };
+    (u128::from(cycles) * 1_000_000_000 / u128::from(hz)) as u64
+}
+
+/// Count TSC cycles while PIT channel 2 counts down 10 ms.
+fn calibrate_tsc() -> KernelResult<u64> {
+    latency::without_interrupts(|| {
+        let start = unsafe { _rdtsc() };
+        pit_wait_10ms()?;
+        let end = unsafe { _rdtsc() };
+        Ok((end - start) * 100)
+    })
+}
+
+/// Wait for about `ms` milliseconds without needing interrupts or a
+/// calibrated TSC, e.g. while panicking. Rounded up to 10 ms.
+pub fn spin_ms(ms: u64) -> KernelResult<()> {
+    for _ in 0..(ms + 9) / 10 {
+        pit_wait_10ms()?;
+    }
+    Ok(())
+}
+
+/// Let PIT channel 2 count down 10 ms, and wait until it has.
+fn pit_wait_10ms() -> KernelResult<()> {
+    const COUNT: u16 = (PIT_FREQUENCY / 100) as u16;
+    let mut gate = Port::<u8>::new(0x61);
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel2 = Port::<u8>::new(0x42);
+    unsafe {
+        // Gate on, speaker off, then one-shot mode with the count.
+        let saved = gate.read();
+        gate.write(saved & !0x02 | 0x01);
+        command.write(0b1011_0000);
+        channel2.write(COUNT as u8);
+        channel2.write((COUNT >> 8) as u8);
+        // The output goes high when the count runs out. Don't spin
+        // forever if there is no PIT to do that.
+        let start = _rdtsc();
+        while gate.read() & 0x20 == 0 {
+            if _rdtsc() - start > 10_000_000_000 {
+                gate.write(saved);
+                return Err(KernelError::Timeout);
+            }
+        }
+        gate.write(saved);
+    }
+    Ok(())
+}
+
+/// What the CMOS clock says, taken to be UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// The date and time `unix_time` seconds after 1970.
+    pub fn from_unix(unix_time: u64) -> DateTime {
+        // `unix_time` backwards, with years from March again.
+        let seconds = unix_time % 86400;
+        let days = unix_time / 86400 + 719_468;
+        let era = days / 146_097;
+        let day_of_era = days % 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let (year, month) = if month < 10 {
+            (era * 400 + year_of_era, month + 3)
+        } else {
+            (era * 400 + year_of_era + 1, month - 9)
+        };
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+
+    pub fn unix_time(&self) -> u64 {
+        // Days from 1970-01-01, counting years from March so the
+        // leap day is at the end.
+        let (year, month) = if self.month <= 2 {
+            (u64::from(self.year) - 1, u64::from(self.month) + 9)
+        } else {
+            (u64::from(self.year), u64::from(self.month) - 3)
+        };
+        let era = year / 400;
+        let year_of_era = year % 400;
+        let day_of_year = (153 * month + 2) / 5 + u64::from(self.day) - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = (era * 146_097 + day_of_era).saturating_sub(719_468);
+        days * 86400
+            + u64::from(self.hour) * 3600
+            + u64::from(self.minute) * 60
+            + u64::from(self.second)
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn read_cmos_register(register: u8) -> u8 {
+    latency::without_interrupts(|| unsafe {
+        // Bit 7 would turn NMIs off, keep it clear.
+        Port::<u8>::new(0x70).write(register & 0x7F);
+        Port::<u8>::new(0x71).read()
+    })
+}
+
+fn read_cmos() -> DateTime {
+    let read_all = || {
+        // Don't read in the middle of the clock updating itself.
+        while read_cmos_register(0x0A) & 0x80 != 0 {}
+        let mut values = [0; 6];
+        for (value, &register) in values.iter_mut().zip(&[0x00, 0x02, 0x04, 0x07, 0x08, 0x09]) {
+            *value = read_cmos_register(register);
+        }
+        values
+    };
+    // It can still tick over between two registers, read until two
+    // goes in a row agree.
+    let mut values = read_all();
+    loop {
+        let again = read_all();
+        if again == values {
+            break;
+        }
+        values = again;
+    }
+
+    let status = read_cmos_register(0x0B);
+    let binary = status & 0x04 != 0;
+    let decode = |value: u8| {
+        if binary {
+            value
+        } else {
+            (value & 0x0F) + (value >> 4) * 10
+        }
+    };
+    let [second, minute, hour, day, month, year] = values;
+    // In 12 hour mode the top bit of the hour means PM.
+    let mut hours = decode(hour & 0x7F);
+    if status & 0x02 == 0 && hour & 0x80 != 0 {
+        hours = (hours % 12) + 12;
+    } else if status & 0x02 == 0 && hours == 12 {
+        hours = 0;
+    }
+    DateTime {
+        year: 2000 + u16::from(decode(year)),
+        month: decode(month),
+        day: decode(day),
+        hour: hours,
+        minute: decode(minute),
+        second: decode(second),
+    }
+}
+
+/// Shows a number of milliseconds as `1d 02:03:04.567`.
+pub struct Duration(pub u64);
+
+impl fmt::Display for Duration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let seconds = self.0 / 1000;
+        let (minutes, hours, days) = (seconds / 60, seconds / 3600, seconds / 86400);
+        if days > 0 {
+            write!(f, "{}d ", days)?;
+        }
+        write!(
+            f,
+            "{:02}:{:02}:{:02}.{:03}",
+            hours % 24,
+            minutes % 60,
+            seconds % 60,
+            self.0 % 1000
+        )
+    }
+}
+
+fn uptime_command(out: &mut dyn fmt::Write, _args: &str) -> CommandResult {
+    let _ = writeln!(
+        out,
+        "up {} ({} ticks, {})",
+        Duration(uptime_ms()),
+        ticks(),
+        clock_source().name
+    );
+    Ok(())
+}
+
+fn clocksource_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    let available = AVAILABLE.load(Ordering::Relaxed);
+    if args.is_empty() {
+        for (index, source) in SOURCES.iter().enumerate() {
+            let state = if index == SELECTED.load(Ordering::Relaxed) {
+                "in use"
+            } else if available & 1 << index != 0 {
+                "available"
+            } else {
+                "not found"
+            };
+            let _ = writeln!(
+                out,
+                "{:<8} quality {:>3}  {}",
+                source.name, source.quality, state
+            );
+        }
+        return Ok(());
+    }
+    match SOURCES.iter().position(|source| source.name == args) {
+        Some(index) if available & 1 << index != 0 => {
+            select(index);
+            Ok(())
+        }
+        Some(_) => {
+            let _ = writeln!(out, "{} isn't available on this machine", args);
+            Err(CommandFailed)
+        }
+        None => {
+            let _ = writeln!(out, "no clock source called `{}`", args);
+            Err(CommandFailed)
+        }
+    }
+}
+
+#[test_case]
+fn test_monotonic_and_unix_time() {
+    let first = monotonic_ns();
+    let second = monotonic_ns();
+    assert!(second >= first);
+    let date = DateTime {
+        year: 2020,
+        month: 7,
+        day: 15,
+        hour: 12,
+        minute: 30,
+        second: 5,
+    };
+    assert_eq!(date.unix_time(), 1_594_816_205);
+    assert_eq!(DateTime::from_unix(1_594_816_205), date);
+    // The day after a leap day.
+    use alloc::string::ToString;
+    assert_eq!(
+        DateTime::from_unix(951_868_800).to_string(),
+        "2000-03-01 00:00:00"
+    );
+}
+
+#[test_case]
+fn test_discipline_drift() {
+    const SECOND: u64 = 1_000_000_000;
+
+    let mut clock = Discipline::new(10 * SECOND, 1_600_000_000 * SECOND);
+    assert_eq!(clock.unix_ns(12 * SECOND), 1_600_000_002 * SECOND);
+    // Too soon to say anything about drift.
+    clock.update(20 * SECOND, 1_600_000_011 * SECOND);
+    assert_eq!(clock.drift_ppb(), 0);
+    // 100 seconds and 10 ms behind, 100 ppm.
+    clock.update(120 * SECOND, 1_600_000_111 * SECOND + 10_000_000);
+    assert_eq!(clock.drift_ppb(), 100_000);
+    assert_eq!(
+        clock.unix_ns(220 * SECOND),
+        1_600_000_211 * SECOND + 20_000_000
+    );
+    // Way off is taken as no more than 500 ppm, and averaged in.
+    clock.update(220 * SECOND, 1_600_000_221 * SECOND);
+    assert_eq!(clock.drift_ppb(), (100_000 + MAX_DRIFT_PPB) / 2);
+}
@@ -0,0 +1,320 @@
+//! Static tracepoints.
+//!
+//! `trace_event!` drops a fixed-size record into a ring buffer, so it is
+//! cheap enough to leave in interrupt handlers and other hot paths:
+//!
+//! ```ignore
+//! trace_event!(Interrupts, "timer tick {}", count);
+//! ```
+//!
+//! A record only holds the timestamp, the CPU, which tracepoint fired and
+//! up to `MAX_ARGS` integer arguments. Formatting into text happens when
+//! the buffer is dumped (`trace` in the kernel monitor), not when the
+//! event is recorded. Arguments are cast with `as u64`, so they need to
+//! be integers (or pointers cast to one).
+//!
+//! Tracing is off for every subsystem until `enable` turns it on, and
+//! while it is off a tracepoint is a single atomic load.
+use crate::latency;
+use crate::shell::{CommandFailed, CommandResult};
+use core::fmt;
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+
+/// Integer arguments a record can hold.
+pub const MAX_ARGS: usize = 4;
+/// Records kept per CPU before the oldest get overwritten.
+pub const RECORDS_PER_CPU: usize = 256;
+/// CPUs we keep buffers for, and the most `smp` starts.
+pub const MAX_CPUS: usize = 4;
+
+/// Groups of tracepoints that get switched on and off together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    Interrupts,
+    Scheduler,
+    Memory,
+    Serial,
+    /// `step_trace`, one record per instruction.
+    Stepping,
+    Tests,
+}
+
+impl Subsystem {
+    pub const ALL: [Subsystem; 6] = [
+        Subsystem::Interrupts,
+        Subsystem::Scheduler,
+        Subsystem::Memory,
+        Subsystem::Serial,
+        Subsystem::Stepping,
+        Subsystem::Tests,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Subsystem::Interrupts => "interrupts",
+            Subsystem::Scheduler => "scheduler",
+            Subsystem::Memory => "memory",
+            Subsystem::Serial => "serial",
+            Subsystem::Stepping => "step",
+            Subsystem::Tests => "tests",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Subsystem> {
+        Subsystem::ALL
+            .iter()
+            .copied()
+            .find(|subsystem| subsystem.name() == name)
+    }
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// One `trace_event!` call site. The macro makes a static one of these,
+/// its address is what identifies the event in a record.
+pub struct Tracepoint {
+    pub subsystem: Subsystem,
+    pub file: &'static str,
+    pub line: u32,
+    /// Formats the arguments of a record with the format string given
+    /// to `trace_event!`.
+    pub format: fn(&mut fmt::Formatter, &[u64; MAX_ARGS]) -> fmt::Result,
+}
+
+impl Tracepoint {
+    pub fn id(&'static self) -> usize {
+        self as *const Tracepoint as usize
+    }
+}
+
+/// A single event.
+#[derive(Clone, Copy)]
+pub struct Record {
+    pub tsc: u64,
+    pub cpu: u32,
+    pub event: &'static Tracepoint,
+    pub args: [u64; MAX_ARGS],
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{}] {:>16} {:<10} {}:{}  ",
+            self.cpu,
+            self.tsc,
+            self.event.subsystem.name(),
+            self.event.file,
+            self.event.line
+        )?;
+        (self.event.format)(f, &self.args)
+    }
+}
+
+struct RingBuffer {
+    records: [Option<Record>; RECORDS_PER_CPU],
+    /// Where the next record goes.
+    next: usize,
+}
+
+impl RingBuffer {
+    const fn new() -> RingBuffer {
+        RingBuffer {
+            records: [None; RECORDS_PER_CPU],
+            next: 0,
+        }
+    }
+
+    fn push(&mut self, record: Record) {
+        self.records[self.next] = Some(record);
+        self.next = (self.next + 1) % RECORDS_PER_CPU;
+    }
+
+    /// Oldest first.
+    fn iter(&self) -> impl Iterator<Item = &Record> {
+        let (newer, older) = self.records.split_at(self.next);
+        older.iter().chain(newer.iter()).flatten()
+    }
+}
+
+/// Only ever written by its own CPU with interrupts off, so the lock is
+/// never contended. It's there so a dump from elsewhere sees whole
+/// records.
+static BUFFERS: [Mutex<RingBuffer>; MAX_CPUS] = [
+    Mutex::new(RingBuffer::new()),
+    Mutex::new(RingBuffer::new()),
+    Mutex::new(RingBuffer::new()),
+    Mutex::new(RingBuffer::new()),
+];
+
+/// Add the `trace` shell command.
+pub fn init() {
+    crate::shell::register(
+        "trace",
+        "dump the trace, or `on|off SUBSYSTEM` to switch one",
+        trace_command,
+    )
+    .expect("trace command");
+}
+
+fn trace_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    let mut words = args.split_whitespace();
+    let action = words.next();
+    let subsystem = words.next().and_then(Subsystem::from_name);
+    match (action, subsystem) {
+        (None, _) => {
+            let _ = dump(out);
+        }
+        (Some("on"), Some(subsystem)) => enable(subsystem),
+        (Some("off"), Some(subsystem)) => disable(subsystem),
+        _ => {
+            let _ = write!(out, "usage: trace [on|off SUBSYSTEM], subsystems:");
+            for subsystem in Subsystem::ALL.iter() {
+                let on = if is_enabled(*subsystem) { "*" } else { "" };
+                let _ = write!(out, " {}{}", subsystem.name(), on);
+            }
+            let _ = writeln!(out);
+            return Err(CommandFailed);
+        }
+    }
+    Ok(())
+}
+
+/// A bit per `Subsystem`.
+static ENABLED: AtomicU32 = AtomicU32::new(0);
+
+pub fn enable(subsystem: Subsystem) {
+    ENABLED.fetch_or(subsystem.bit(), Ordering::Relaxed);
+}
+
+pub fn disable(subsystem: Subsystem) {
+    ENABLED.fetch_and(!subsystem.bit(), Ordering::Relaxed);
+}
+
+pub fn is_enabled(subsystem: Subsystem) -> bool {
+    ENABLED.load(Ordering::Relaxed) & subsystem.bit() != 0
+}
+
+/// The CPU we are running on. `smp` parks the others with interrupts
+/// off as soon as they're up, so it's always the boot CPU.
+pub fn current_cpu() -> u32 {
+    0
+}
+
+/// Store a record for `event`. Use `trace_event!` instead.
+#[doc(hidden)]
+pub fn record(event: &'static Tracepoint, args: [u64; MAX_ARGS]) {
+    let cpu = current_cpu();
+    let record = Record {
+        tsc: crate::boot_timing::read_tsc(),
+        cpu,
+        event,
+        args,
+    };
+    latency::without_interrupts(|| BUFFERS[cpu as usize].lock().push(record));
+}
+
+/// Pad the arguments of a `trace_event!` out to `MAX_ARGS`.
+#[doc(hidden)]
+pub fn args(values: &[u64]) -> [u64; MAX_ARGS] {
+    assert!(values.len() <= MAX_ARGS, "too many trace_event! arguments");
+    let mut args = [0; MAX_ARGS];
+    args[..values.len()].copy_from_slice(values);
+    args
+}
+
+/// Call `f` with every record of every CPU, oldest first per CPU.
+///
+/// Skips the buffers of CPUs that are in the middle of recording, so
+/// this is safe to call from the monitor with anything interrupted.
+pub fn for_each_record(mut f: impl FnMut(&Record)) {
+    for buffer in BUFFERS.iter() {
+        if let Some(buffer) = buffer.try_lock() {
+            buffer.iter().for_each(&mut f);
+        }
+    }
+}
+
+/// Throw away everything recorded so far.
+pub fn clear() {
+    latency::without_interrupts(|| {
+        for buffer in BUFFERS.iter() {
+            *buffer.lock() = RingBuffer::new();
+        }
+    });
+}
+
+/// Print every record, see `for_each_record`.
+pub fn dump(out: &mut dyn fmt::Write) -> fmt::Result {
+    let mut result = Ok(());
+    for_each_record(|record| {
+        result = result.and_then(|_| writeln!(out, "{}", record));
+    });
+    result
+}
+
+/// Record an event if tracing is on for `subsystem`. The format string
+/// and up to `MAX_ARGS` integer arguments work like `println!`, but
+/// only get formatted when the trace is dumped.
+#[macro_export]
+macro_rules! trace_event {
+    ($subsystem:ident, $fmt:literal $(, $arg:expr)* $(,)?) => {
+        if $crate::trace::is_enabled($crate::trace::Subsystem::$subsystem) {
+            static TRACEPOINT: $crate::trace::Tracepoint = $crate::trace::Tracepoint {
+                subsystem: $crate::trace::Subsystem::$subsystem,
+                file: file!(),
+                line: line!(),
+                format: |_f, _args| {
+                    let mut _index = 0;
+                    write!(
+                        _f,
+                        $fmt
+                        $(, {
+                            // Same order as the arguments were stored in.
+                            let _ = stringify!($arg);
+                            _index += 1;
+                            _args[_index - 1]
+                        })*
+                    )
+                },
+            };
+            let args = $crate::trace::args(&[$($arg as u64),*]);
+            $crate::trace::record(&TRACEPOINT, args);
+        }
+    };
+}
+
+#[test_case]
+fn test_trace_event() {
+    let count = |line: u32| {
+        let mut count = 0;
+        for_each_record(|record| {
+            if record.event.line == line {
+                count += 1;
+            }
+        });
+        count
+    };
+
+    clear();
+    let line = line!() + 1;
+    let traced = |n: u64| trace_event!(Tests, "n = {:#x}, twice = {}", n, n * 2);
+
+    traced(1);
+    assert_eq!(count(line), 0);
+
+    enable(Subsystem::Tests);
+    traced(2);
+    traced(3);
+    disable(Subsystem::Tests);
+    traced(4);
+    assert_eq!(count(line), 2);
+
+    let mut last = None;
+    for_each_record(|record| last = Some(record.args));
+    assert_eq!(last, Some([3, 6, 0, 0]));
+    clear();
+}
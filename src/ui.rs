@@ -0,0 +1,293 @@
+//! Tables and progress bars for anything that prints to a console.
+//!
+//! Both write to a `fmt::Write`, the same as shell commands get, so
+//! they come out the same on the screen and on serial. `Console` is
+//! one for wherever `print!` goes.
+//!
+//! Borders are the box drawing characters from code page 437, which
+//! the VGA font has and any terminal on the other end of the serial
+//! port shows from their UTF-8.
+//!
+//! ```ignore
+//! const COLUMNS: [Column; 2] = [Column::left("name", 8), Column::right("count", 6)];
+//! let table = Table::new(&COLUMNS);
+//! table.header(out)?;
+//! table.row(out, &[&"timer", &931])?;
+//! table.end(out)?;
+//! ```
+use core::fmt::{self, Write};
+
+/// Cells longer than this get cut, whatever their column's width.
+pub const MAX_CELL: usize = 64;
+/// Cells a progress bar is wide, not counting its label.
+pub const BAR_WIDTH: usize = 20;
+
+/// Writes to wherever `print!` goes.
+pub struct Console;
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        crate::print!("{}", s);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    /// For numbers.
+    Right,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Column {
+    pub title: &'static str,
+    /// In chars, not counting the border or padding.
+    pub width: usize,
+    pub align: Align,
+}
+
+impl Column {
+    pub const fn left(title: &'static str, width: usize) -> Column {
+        Column {
+            title,
+            width,
+            align: Align::Left,
+        }
+    }
+
+    pub const fn right(title: &'static str, width: usize) -> Column {
+        Column {
+            title,
+            width,
+            align: Align::Right,
+        }
+    }
+}
+
+/// What a cell formats to, so it can be padded whatever its `Display`
+/// does with a width.
+struct Cell {
+    bytes: [u8; MAX_CELL],
+    len: usize,
+}
+
+impl Cell {
+    fn new(value: &dyn fmt::Display) -> Cell {
+        let mut cell = Cell {
+            bytes: [0; MAX_CELL],
+            len: 0,
+        };
+        // Too long is fine, it's cut.
+        let _ = write!(cell, "{}", value);
+        cell
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+}
+
+impl fmt::Write for Cell {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let end = self.len + c.len_utf8();
+            if end > MAX_CELL {
+                return Err(fmt::Error);
+            }
+            c.encode_utf8(&mut self.bytes[self.len..end]);
+            self.len = end;
+        }
+        Ok(())
+    }
+}
+
+/// A table with a border around it and between its columns. Rows are
+/// written as they come, so the columns have fixed widths.
+pub struct Table<'a> {
+    columns: &'a [Column],
+}
+
+impl<'a> Table<'a> {
+    pub fn new(columns: &'a [Column]) -> Table<'a> {
+        Table { columns }
+    }
+
+    /// One line of border, with `left`, `middle` and `right` where it
+    /// meets the vertical ones.
+    fn rule(&self, out: &mut dyn fmt::Write, left: char, middle: char, right: char) -> fmt::Result {
+        out.write_char(left)?;
+        for (index, column) in self.columns.iter().enumerate() {
+            if index > 0 {
+                out.write_char(middle)?;
+            }
+            for _ in 0..column.width + 2 {
+                out.write_char('─')?;
+            }
+        }
+        out.write_char(right)?;
+        out.write_char('\n')
+    }
+
+    /// One row, a value for each column. Missing ones are left blank,
+    /// ones that don't fit are cut.
+    pub fn row(&self, out: &mut dyn fmt::Write, cells: &[&dyn fmt::Display]) -> fmt::Result {
+        for (index, column) in self.columns.iter().enumerate() {
+            let cell = cells.get(index).map(|&value| Cell::new(value));
+            let text = cell.as_ref().map_or("", Cell::as_str);
+            let shown = text.chars().take(column.width);
+            let padding = column.width - shown.clone().count();
+            let (before, after) = match column.align {
+                Align::Left => (0, padding),
+                Align::Right => (padding, 0),
+            };
+            out.write_str("│ ")?;
+            for _ in 0..before {
+                out.write_char(' ')?;
+            }
+            for c in shown {
+                out.write_char(c)?;
+            }
+            for _ in 0..after {
+                out.write_char(' ')?;
+            }
+            out.write_char(' ')?;
+        }
+        out.write_str("│\n")
+    }
+
+    /// The top border and the column titles.
+    pub fn header(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        self.rule(out, '┌', '┬', '┐')?;
+        for column in self.columns {
+            write!(out, "│ {:<1$} ", column.title, column.width)?;
+        }
+        out.write_str("│\n")?;
+        self.rule(out, '├', '┼', '┤')
+    }
+
+    /// The bottom border.
+    pub fn end(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        self.rule(out, '└', '┴', '┘')
+    }
+}
+
+/// A bar that fills up as something gets done, redrawn in place with
+/// a carriage return. Nothing else should print on its line until
+/// `finish`.
+pub struct ProgressBar {
+    label: &'static str,
+    total: u64,
+    done: u64,
+    /// The percentage last drawn, so updates that don't change it
+    /// don't cost a redraw.
+    drawn: Option<u64>,
+}
+
+impl ProgressBar {
+    pub fn new(label: &'static str, total: u64) -> ProgressBar {
+        ProgressBar {
+            label,
+            total: total.max(1),
+            done: 0,
+            drawn: None,
+        }
+    }
+
+    /// `done` out of the total are done.
+    pub fn set(&mut self, out: &mut dyn fmt::Write, done: u64) -> fmt::Result {
+        self.done = done.min(self.total);
+        let percent = self.done * 100 / self.total;
+        if self.drawn == Some(percent) {
+            return Ok(());
+        }
+        self.drawn = Some(percent);
+        let filled = (self.done * BAR_WIDTH as u64 / self.total) as usize;
+        write!(out, "\r{} [", self.label)?;
+        for cell in 0..BAR_WIDTH {
+            out.write_char(if cell < filled { '█' } else { '░' })?;
+        }
+        write!(out, "] {:>3}%", percent)
+    }
+
+    /// `count` more are done.
+    pub fn advance(&mut self, out: &mut dyn fmt::Write, count: u64) -> fmt::Result {
+        self.set(out, self.done + count)
+    }
+
+    /// Fill the bar and move on to the next line.
+    pub fn finish(&mut self, out: &mut dyn fmt::Write) -> fmt::Result {
+        self.set(out, self.total)?;
+        out.write_char('\n')
+    }
+}
+
+#[cfg(test)]
+struct Buffer {
+    bytes: [u8; 1024],
+    len: usize,
+}
+
+#[cfg(test)]
+impl Buffer {
+    fn new() -> Buffer {
+        Buffer {
+            bytes: [0; 1024],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl fmt::Write for Buffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        self.bytes
+            .get_mut(self.len..end)
+            .ok_or(fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_table() {
+    const COLUMNS: [Column; 2] = [Column::left("name", 5), Column::right("count", 5)];
+    let mut out = Buffer::new();
+    let table = Table::new(&COLUMNS);
+    table.header(&mut out).unwrap();
+    table.row(&mut out, &[&"timer", &931]).unwrap();
+    table.row(&mut out, &[&"too long"]).unwrap();
+    table.end(&mut out).unwrap();
+    assert_eq!(
+        out.as_str(),
+        "┌───────┬───────┐\n\
+         │ name  │ count │\n\
+         ├───────┼───────┤\n\
+         │ timer │   931 │\n\
+         │ too l │       │\n\
+         └───────┴───────┘\n"
+    );
+}
+
+#[test_case]
+fn test_progress_bar() {
+    let mut out = Buffer::new();
+    let mut bar = ProgressBar::new("boot", 4);
+    bar.advance(&mut out, 1).unwrap();
+    let once = out.len;
+    // Same percentage, nothing to redraw.
+    bar.set(&mut out, 1).unwrap();
+    assert_eq!(out.len, once);
+    bar.finish(&mut out).unwrap();
+    assert_eq!(
+        out.as_str(),
+        "\rboot [█████░░░░░░░░░░░░░░░]  25%\
+         \rboot [████████████████████] 100%\n"
+    );
+}
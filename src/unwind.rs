@@ -0,0 +1,118 @@
+//! Backtraces by walking the frame pointer chain.
+//!
+//! We build with `eliminate-frame-pointer: false` (see the target json)
+//! so every function starts with
+//!
+//! ```text
+//! push rbp
+//! mov rbp, rsp
+//! ```
+//!
+//! which leaves a linked list on the stack: `[rbp]` is the caller's
+//! saved `rbp` and `[rbp + 8]` is the return address into the caller.
+use crate::ksyms::{self, Demangle};
+use core::fmt;
+
+/// How deep we are willing to go. Anything past this is most
+/// likely a corrupted chain anyway.
+pub const MAX_FRAMES: usize = 32;
+
+/// Frames further apart than this are not on the same stack, so
+/// the chain is probably garbage from here on.
+const MAX_FRAME_SIZE: u64 = 1024 * 1024;
+
+/// A captured list of return addresses, innermost first.
+#[derive(Debug, Clone, Copy)]
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+}
+
+impl Backtrace {
+    /// Walk the stack from the caller of `capture` outwards.
+    #[inline(never)]
+    pub fn capture() -> Backtrace {
+        let mut backtrace = Backtrace {
+            frames: [0; MAX_FRAMES],
+            len: 0,
+        };
+        unsafe {
+            walk(current_frame_pointer(), |return_address| {
+                backtrace.frames[backtrace.len] = return_address;
+                backtrace.len += 1;
+                backtrace.len < MAX_FRAMES
+            });
+        }
+        backtrace
+    }
+
+    /// Return addresses, innermost frame first.
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Backtrace:")?;
+        for (index, &address) in self.frames().iter().enumerate() {
+            write!(f, "  {:>2}: {:#018x}", index, address)?;
+            // The return address points past the call, so look up the
+            // byte before it in case the call was the last instruction.
+            match ksyms::lookup(address - 1) {
+                Some((name, offset)) => writeln!(f, " {}+{:#x}", Demangle(name), offset + 1)?,
+                None => writeln!(f)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The `rbp` of whoever called us (we're always inlined).
+#[inline(always)]
+pub fn current_frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack));
+    }
+    rbp
+}
+
+/// Call `f` with each return address on the chain starting at `rbp`
+/// until the chain ends or `f` returns `false`.
+///
+/// # Safety
+///
+/// `rbp` has to be a frame pointer from a chain built by the
+/// prologues above, e.g. from `current_frame_pointer`.
+pub unsafe fn walk(mut rbp: u64, mut f: impl FnMut(u64) -> bool) {
+    // The checks are only sanity checks - they keep us from running
+    // off into the weeds, they can't make a smashed stack readable.
+    while rbp != 0 && rbp % 8 == 0 {
+        let frame = rbp as *const u64;
+        let next = *frame;
+        let return_address = *frame.add(1);
+        if return_address == 0 || !f(return_address) {
+            break;
+        }
+
+        // The stack grows down, so callers live at higher addresses.
+        if next <= rbp || next - rbp > MAX_FRAME_SIZE {
+            break;
+        }
+        rbp = next;
+    }
+}
+
+#[test_case]
+fn test_capture_finds_frames() {
+    #[inline(never)]
+    fn nested() -> Backtrace {
+        Backtrace::capture()
+    }
+
+    let backtrace = nested();
+    // At the very least `nested`, this test and the test runner.
+    assert!(backtrace.frames().len() >= 3);
+    assert!(backtrace.frames().iter().all(|&address| address != 0));
+}
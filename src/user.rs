@@ -0,0 +1,163 @@
+//! Copying to and from user memory.
+//!
+//! A syscall gets handed pointers it can't trust. `copy_in` and
+//! `copy_out` first check the whole range is in the lower half and
+//! mapped for user mode (writable too for `copy_out`), so a process
+//! can't get the kernel to read or write its own memory for it.
+//!
+//! The page tables can still change under a copy, so the copy itself
+//! can fault. The instruction doing it is listed in the exception
+//! table, the `ex_table` linker section, with where to go instead. The
+//! page fault handler looks the faulting `rip` up there and resumes at
+//! the fixup, and the copy returns an error rather than the kernel
+//! panicking.
+//!
+//! Pages of a mapped file that aren't in yet, or are but can't be
+//! written to yet, get a chance to be, see `fs::mmap`.
+//!
+//! `syscall` uses these on whatever a program hands it.
+use crate::error::{KernelError, KernelResult};
+use crate::memory;
+use core::{mem, slice};
+use x86_64::VirtAddr;
+
+/// Everything below this can be user memory.
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+/// An instruction that's allowed to fault, and where to carry on if it
+/// does.
+#[repr(C)]
+pub struct ExceptionTableEntry {
+    pub instruction: u64,
+    pub fixup: u64,
+}
+
+// Only ever used for their addresses.
+extern "C" {
+    static __start_ex_table: u8;
+    static __stop_ex_table: u8;
+}
+
+fn exception_table() -> &'static [ExceptionTableEntry] {
+    unsafe {
+        let start = &__start_ex_table as *const u8 as usize;
+        let end = &__stop_ex_table as *const u8 as usize;
+        let len = (end - start) / mem::size_of::<ExceptionTableEntry>();
+        slice::from_raw_parts(start as *const ExceptionTableEntry, len)
+    }
+}
+
+/// Where to resume if the instruction at `rip` faults, if it's one
+/// that may. Called by the page fault handler.
+pub fn fixup(rip: VirtAddr) -> Option<VirtAddr> {
+    exception_table()
+        .iter()
+        .find(|entry| entry.instruction == rip.as_u64())
+        .map(|entry| VirtAddr::new(entry.fixup))
+}
+
+/// Copy `len` bytes, stopping at a page fault. Returns how many bytes
+/// were left when it stopped, 0 if it got through.
+///
+/// # Safety
+///
+/// Anything in the destination range that is mapped gets overwritten.
+unsafe fn copy_with_fixup(to: *mut u8, from: *const u8, len: usize) -> usize {
+    let left: usize;
+    // `rep movsb` counts rcx down as it goes, so after a fault it
+    // holds what's left.
+    asm!(
+        "2:",
+        "rep movsb",
+        "3:",
+        ".pushsection ex_table, \"a\"",
+        ".balign 8",
+        ".quad 2b, 3b",
+        ".popsection",
+        inout("rcx") len => left,
+        inout("rdi") to => _,
+        inout("rsi") from => _,
+        options(nostack),
+    );
+    left
+}
+
+/// Check every page of `start..start + len` is user memory, giving
+/// the ones that aren't in yet a chance to be if `fault_in`.
+fn check_user_range(start: VirtAddr, len: usize, write: bool, fault_in: bool) -> KernelResult<()> {
+    let end = start
+        .as_u64()
+        .checked_add(len as u64)
+        .ok_or(KernelError::InvalidAddress)?;
+    if end > USER_END {
+        return Err(KernelError::InvalidAddress);
+    }
+    let mut page = start.align_down(4096u64).as_u64();
+    while page < end {
+        match memory::translate(VirtAddr::new(page)) {
+            Some(mapping) if mapping.user_accessible && (mapping.writable || !write) => {}
+            _ if !fault_in => return Err(KernelError::InvalidAddress),
+            _ => crate::fs::mmap::fault(VirtAddr::new(page), write)
+                .map_err(|_| KernelError::InvalidAddress)?,
+        }
+        page += 4096;
+    }
+    Ok(())
+}
+
+/// Copy `to.len()` bytes from user memory at `from`.
+pub fn copy_in(to: &mut [u8], from: VirtAddr) -> KernelResult<()> {
+    check_user_range(from, to.len(), false, true)?;
+    match unsafe { copy_with_fixup(to.as_mut_ptr(), from.as_ptr(), to.len()) } {
+        0 => Ok(()),
+        _ => Err(KernelError::InvalidAddress),
+    }
+}
+
+/// `copy_in`, for with a lock held or interrupts off: a page that isn't
+/// in is `InvalidAddress`, not faulted in. `copy_in` it first.
+pub fn copy_in_resident(to: &mut [u8], from: VirtAddr) -> KernelResult<()> {
+    check_user_range(from, to.len(), false, false)?;
+    match unsafe { copy_with_fixup(to.as_mut_ptr(), from.as_ptr(), to.len()) } {
+        0 => Ok(()),
+        _ => Err(KernelError::InvalidAddress),
+    }
+}
+
+/// Copy `from` to user memory at `to`.
+pub fn copy_out(to: VirtAddr, from: &[u8]) -> KernelResult<()> {
+    check_user_range(to, from.len(), true, true)?;
+    match unsafe { copy_with_fixup(to.as_mut_ptr(), from.as_ptr(), from.len()) } {
+        0 => Ok(()),
+        _ => Err(KernelError::InvalidAddress),
+    }
+}
+
+#[test_case]
+fn test_faults_are_recovered() {
+    let mut buffer = [0u8; 16];
+    // Kernel memory isn't user memory.
+    let kernel = VirtAddr::from_ptr(&buffer);
+    assert_eq!(
+        copy_in(&mut [0; 4], kernel),
+        Err(KernelError::InvalidAddress)
+    );
+    assert_eq!(
+        copy_out(VirtAddr::new(USER_END - 2), &[0; 4]),
+        Err(KernelError::InvalidAddress)
+    );
+
+    let from = [7u8; 16];
+    assert_eq!(
+        unsafe { copy_with_fixup(buffer.as_mut_ptr(), from.as_ptr(), 16) },
+        0
+    );
+    assert_eq!(buffer, from);
+    // Nothing is mapped there, the page fault handler has to get us
+    // out.
+    let unmapped = 0xdead_0000 as *const u8;
+    assert_eq!(
+        unsafe { copy_with_fixup(buffer.as_mut_ptr(), unmapped, 16) },
+        16
+    );
+}
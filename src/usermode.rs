@@ -0,0 +1,312 @@
+//! Running code in ring 3.
+//!
+//! `run` drops to ring 3 at some entry point on some stack, and comes
+//! back when the code there traps into the kernel for good, saying
+//! why. Interrupts stay on in ring 3. The ones just passing through,
+//! like the timer or the keyboard, run on the thread's kernel stack and
+//! `iretq` back into ring 3 as usual, preemption included. The ones
+//! that end the run:
+//!
+//! - `int 0x81`, `RETURN_VECTOR`, the only gate ring 3 is allowed to
+//!   use: it's done, and passes back what's in `rdi`.
+//! - the `exit` syscall, see `syscall`.
+//! - a fault: divide error, invalid opcode, general protection or page
+//!   fault. The kernel panics on those, but not on ring 3's.
+//!
+//! Getting back: `enter_user_mode` pushes the callee-saved registers
+//! and `rflags` on the kernel stack, and where its pushes end goes in
+//! the TSS as `rsp0`, which is where the CPU starts the stack for an
+//! interrupt from ring 3. Whatever ends the run finds them there from
+//! `rsp0` and pops them, so `enter_user_mode` returns like any other
+//! function, with whatever the interrupt left below forgotten. Every
+//! thread has its own `rsp0`, `scheduler` swaps them, so any number of
+//! threads can be in ring 3 at once.
+//!
+//! Ring 3 gets at whatever is mapped `USER_ACCESSIBLE`, and the kernel
+//! doesn't check what that is, the caller of `run` has to.
+use crate::error::{KernelError, KernelResult};
+use crate::gdt;
+use crate::memory;
+use crate::user::USER_END;
+use core::mem::{self, MaybeUninit};
+use x86_64::structures::idt::{HandlerFunc, InterruptStackFrame};
+use x86_64::VirtAddr;
+
+/// What ring 3 calls to say it's done.
+pub const RETURN_VECTOR: u8 = 0x81;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    DivideError,
+    InvalidOpcode,
+    GeneralProtection { error_code: u64 },
+    PageFault { address: u64, error_code: u64 },
+}
+
+/// Why a run ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// `int 0x81`, with what was in `rdi`.
+    Returned(u64),
+    /// The `exit` syscall, with its status.
+    Exited(i32),
+    Fault {
+        fault: Fault,
+        rip: u64,
+    },
+}
+
+extern "C" {
+    /// Save the kernel's registers, point `rsp0` just below them and
+    /// `iretq` to `rip` in ring 3. Comes back once `leave` has been
+    /// called, with `exit` filled in.
+    fn enter_user_mode(
+        rip: u64,
+        rsp: u64,
+        // Where the `Exit` goes, which isn't FFI-safe.
+        exit: *mut u8,
+        rsp0: *mut u64,
+        code_selector: u64,
+        data_selector: u64,
+    );
+    /// Pop what `enter_user_mode` pushed at `rsp0` and return from it.
+    fn leave_user_mode(rsp0: u64) -> !;
+    /// What the `RETURN_VECTOR` gate points at.
+    fn user_return_entry();
+}
+
+global_asm!(
+    "
+    .intel_syntax noprefix
+    .global enter_user_mode
+    enter_user_mode:
+        push rbp
+        push rbx
+        push r12
+        push r13
+        push r14
+        push r15
+        pushfq
+        cli
+        push rdx
+        mov [rcx], rsp
+        # What iretq pops: ss, rsp, rflags with interrupts on, cs, rip.
+        push r9
+        push rsi
+        push 0x202
+        push r8
+        push rdi
+        # Nothing of the kernel's for ring 3 to see.
+        xor eax, eax
+        xor ebx, ebx
+        xor ecx, ecx
+        xor edx, edx
+        xor esi, esi
+        xor edi, edi
+        xor ebp, ebp
+        xor r8d, r8d
+        xor r9d, r9d
+        xor r10d, r10d
+        xor r11d, r11d
+        xor r12d, r12d
+        xor r13d, r13d
+        xor r14d, r14d
+        xor r15d, r15d
+        iretq
+
+    .global leave_user_mode
+    leave_user_mode:
+        mov rsp, rdi
+        # The pointer to the exit, written already.
+        add rsp, 8
+        popfq
+        pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop rbx
+        pop rbp
+        ret
+
+    .global user_return_entry
+    user_return_entry:
+        # Never goes back, so there's nothing to save. rdi is still
+        # ring 3's.
+        mov rsi, rsp
+        and rsp, -16
+        call usermode_returned
+        ud2
+    .att_syntax
+    "
+);
+
+/// The gate for `RETURN_VECTOR`, for the IDT.
+pub(crate) fn return_gate() -> HandlerFunc {
+    // It's never returned from, so how it would return doesn't matter.
+    unsafe { mem::transmute(user_return_entry as unsafe extern "C" fn()) }
+}
+
+#[no_mangle]
+extern "C" fn usermode_returned(value: u64, frame: &InterruptStackFrame) -> ! {
+    assert!(
+        is_user(frame),
+        "int {:#x} from the kernel at {:#x}",
+        RETURN_VECTOR,
+        frame.instruction_pointer.as_u64()
+    );
+    leave(Exit::Returned(value))
+}
+
+/// Whether the interrupt came from ring 3.
+pub fn is_user(frame: &InterruptStackFrame) -> bool {
+    frame.code_segment & 3 == 3
+}
+
+/// End the run, going back to where `run` was called with `exit`. The
+/// interrupt has to have come from ring 3, and should have finished
+/// with anything it counts on being dropped, as it never returns.
+pub(crate) fn leave(exit: Exit) -> ! {
+    let rsp0 = gdt::kernel_stack().as_u64();
+    unsafe {
+        let slot = *(rsp0 as *const *mut Exit);
+        slot.write(exit);
+        leave_user_mode(rsp0)
+    }
+}
+
+/// Run ring 3 code at `entry` with its stack at `stack_top`, until it
+/// traps back, see above. `InvalidAddress` if either isn't mapped for
+/// ring 3. Memory it mapped with the `mmap` syscall is unmapped once
+/// it's done.
+///
+/// # Safety
+///
+/// The code gets to read and write everything mapped
+/// `USER_ACCESSIBLE`, none of which the kernel can be counting on.
+pub unsafe fn run(entry: VirtAddr, stack_top: VirtAddr) -> KernelResult<Exit> {
+    let user = |address: VirtAddr, write: bool| {
+        address.as_u64() < USER_END
+            && memory::translate(address).map_or(false, |mapping| {
+                mapping.user_accessible && (mapping.writable || !write)
+            })
+    };
+    if !user(entry, false) || !user(stack_top - 8u64, true) {
+        return Err(KernelError::InvalidAddress);
+    }
+    let selectors = gdt::selectors();
+    let mut exit = MaybeUninit::<Exit>::uninit();
+    enter_user_mode(
+        entry.as_u64(),
+        stack_top.as_u64(),
+        exit.as_mut_ptr() as *mut u8,
+        gdt::kernel_stack_slot(),
+        u64::from(selectors.user_code_selector.0),
+        u64::from(selectors.user_data_selector.0),
+    );
+    // Whatever it got with `mmap` or `socket` and didn't give back.
+    crate::fs::mmap::unmap_thread(crate::scheduler::current());
+    crate::net::socket::close_thread(crate::scheduler::current());
+    Ok(exit.assume_init())
+}
+
+/// A page of code and a page of stack mapped for ring 3, for tests to
+/// run a few instructions with. Unmapped again when dropped.
+#[cfg(test)]
+pub(crate) struct TestProgram {
+    code_frame: x86_64::structures::paging::PhysFrame,
+}
+
+#[cfg(test)]
+impl TestProgram {
+    pub const CODE: u64 = 0x2000_0000_0000;
+    const STACK: u64 = TestProgram::CODE + 0x10000;
+
+    fn pages() -> [x86_64::structures::paging::Page; 2] {
+        use x86_64::structures::paging::Page;
+        [
+            Page::containing_address(VirtAddr::new(TestProgram::CODE)),
+            Page::containing_address(VirtAddr::new(TestProgram::STACK)),
+        ]
+    }
+
+    pub fn new() -> TestProgram {
+        use crate::memory::{frame_allocator, paging};
+        use x86_64::structures::paging::PageTableFlags;
+
+        let [code, stack] = TestProgram::pages();
+        let code_frame = frame_allocator::allocate_frame().unwrap();
+        let stack_frame = frame_allocator::allocate_frame().unwrap();
+        let user = PageTableFlags::USER_ACCESSIBLE;
+        unsafe {
+            paging::map_to(code, code_frame, PageTableFlags::PRESENT | user).unwrap();
+            paging::map_to(stack, stack_frame, paging::data_flags(true) | user).unwrap();
+        }
+        TestProgram { code_frame }
+    }
+
+    /// The stack's top, for starting `run` with.
+    pub fn stack_top() -> VirtAddr {
+        VirtAddr::new(TestProgram::STACK + 4096)
+    }
+
+    /// Run `code`, which is machine code, from the start of the page.
+    pub fn run(&self, code: &[u8]) -> Exit {
+        unsafe {
+            let offset = memory::physical_memory_offset().unwrap();
+            let page = (offset + self.code_frame.start_address().as_u64()).as_mut_ptr::<u8>();
+            core::ptr::copy_nonoverlapping(code.as_ptr(), page, code.len());
+            run(VirtAddr::new(TestProgram::CODE), TestProgram::stack_top()).unwrap()
+        }
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestProgram {
+    fn drop(&mut self) {
+        use crate::memory::{frame_allocator, paging};
+
+        for &page in TestProgram::pages().iter() {
+            unsafe {
+                let frame = paging::unmap(page).unwrap();
+                frame_allocator::free_frame(frame).unwrap();
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_runs_until_it_traps() {
+    static KERNEL_BYTE: u8 = 0;
+
+    let program = TestProgram::new();
+    // mov edi, 42; push rdi; xor edi, edi; pop rdi; int 0x81
+    let returned = program.run(&[0xbf, 42, 0, 0, 0, 0x57, 0x31, 0xff, 0x5f, 0xcd, 0x81]);
+    assert_eq!(returned, Exit::Returned(42));
+    assert!(x86_64::instructions::interrupts::are_enabled());
+    // ud2
+    let fault = |fault| Exit::Fault {
+        fault,
+        rip: TestProgram::CODE,
+    };
+    assert_eq!(program.run(&[0x0f, 0x0b]), fault(Fault::InvalidOpcode));
+    // cli, which ring 3 isn't allowed
+    assert_eq!(
+        program.run(&[0xfa]),
+        fault(Fault::GeneralProtection { error_code: 0 })
+    );
+    // movabs rax, &KERNEL_BYTE; mov byte [rax], 1
+    let address = &KERNEL_BYTE as *const u8 as u64;
+    let mut write = [0x48, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0xc6, 0x00, 0x01];
+    write[2..10].copy_from_slice(&address.to_le_bytes());
+    // Present, a write, from ring 3.
+    let page_fault = Fault::PageFault {
+        address,
+        error_code: 0b111,
+    };
+    assert_eq!(program.run(&write), fault(page_fault));
+    // Not mapped for ring 3 at all.
+    assert_eq!(
+        unsafe { run(VirtAddr::from_ptr(&KERNEL_BYTE), TestProgram::stack_top()) },
+        Err(KernelError::InvalidAddress)
+    );
+}
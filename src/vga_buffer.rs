@@ -1,7 +1,25 @@
-use volatile::Volatile; // Required to avoid the compiler optimising stuff away
+//! The VGA text console.
+//!
+//! `Writer::write_string` understands some of the ANSI escape
+//! sequences a terminal does, so what's formatted for one comes out
+//! right here and can go to serial as it is:
+//!
+//! - SGR, `ESC [ ... m`: reset, bold, and the eight colors and their
+//!   bright versions for the foreground and the background,
+//! - cursor movement, `ESC [ n A` to `D` and `ESC [ row ; col H`,
+//! - clearing, `ESC [ n J` for the screen and `ESC [ n K` for the line.
+//!
+//! Anything else that starts with an escape is dropped.
+//!
+//! Lines scrolled off the top are kept, the last `SCROLLBACK_LINES` of
+//! them, and `scroll_up` and `scroll_down` look back through them.
+//! PageUp and PageDown do it half a screen at a time. Writing anything
+//! goes back to the bottom first.
 use core::fmt; // Required as we'll be using the write macros.
 use lazy_static::lazy_static; // see Cargo.toml
-use spin::Mutex; // see Cargo.toml;
+use spin::Mutex;
+use volatile::Volatile; // Required to avoid the compiler optimising stuff away // see Cargo.toml;
+use x86_64::instructions::port::Port;
 
 /// Allowed colors that VGA can handle
 #[allow(dead_code)]
@@ -26,7 +44,6 @@ pub enum Color {
     White = 15,
 }
 
-
 /// Transparent here lets us basically use
 /// the struct as a type checking thing
 /// and it would still be stored as a u8.
@@ -36,7 +53,6 @@ struct ColorCode(u8);
 
 /// Color code creation from a set of background and foreground colors.
 impl ColorCode {
-
     /// If each part of the foreground and background are u4 but we can't
     /// use the u4 as there is no rust primitive for that. We have to use
     /// u8 and shift it to the left and use a bitwise or.
@@ -70,9 +86,117 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+/// What everything is printed in unless asked otherwise.
+pub const DEFAULT_COLORS: (Color, Color) = (Color::Yellow, Color::Black);
+
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
+
+/// The CRT controller's index and data ports. Its registers say where
+/// the blinking cursor is and what it looks like.
+const CRTC_INDEX: u16 = 0x3D4;
+const CRTC_DATA: u16 = 0x3D5;
+/// First scanline of the cursor, with a bit that turns it off.
+const CURSOR_START: u8 = 0x0A;
+const CURSOR_END: u8 = 0x0B;
+/// The cell it's on, counted from the top left, high byte and low.
+const CURSOR_HIGH: u8 = 0x0E;
+const CURSOR_LOW: u8 = 0x0F;
+const CURSOR_DISABLE: u8 = 1 << 5;
+/// An underline, the bottom two of a cell's 16 scanlines.
+const CURSOR_SCANLINES: (u8, u8) = (14, 15);
+
+/// Lines kept after they've scrolled off the top of the screen.
+pub const SCROLLBACK_LINES: usize = 200;
+
+/// Parameters an escape sequence can have, ones past these are dropped.
+const MAX_ESCAPE_PARAMS: usize = 4;
+/// ANSI's order of colors, as VGA's. The bright ones are the same
+/// with the intensity bit.
+const ANSI_COLORS: [Color; 8] = [
+    Color::Black,
+    Color::Red,
+    Color::Green,
+    Color::Brown,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::LightGray,
+];
+const INTENSITY: u8 = 0x08;
+
+/// How far into an escape sequence `write_string` is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    /// Had the ESC.
+    Started,
+    /// Had `ESC [`, and these parameters so far. `count` is the one
+    /// being read.
+    Csi {
+        params: [u16; MAX_ESCAPE_PARAMS],
+        count: usize,
+    },
+}
+
+type Line = [ScreenChar; BUFFER_WIDTH];
+
+const BLANK_LINE: Line = [ScreenChar {
+    ascii_character: b' ',
+    color_code: ColorCode(0),
+}; BUFFER_WIDTH];
 
+/// What's scrolled off the top, oldest first from `next - len`. Only
+/// used with `WRITER` locked.
+struct Scrollback {
+    lines: [Line; SCROLLBACK_LINES],
+    /// Where the next one goes.
+    next: usize,
+    len: usize,
+    /// The screen as it was before scrolling up, to put back at the
+    /// bottom.
+    screen: [Line; BUFFER_HEIGHT],
+}
+
+static SCROLLBACK: Mutex<Scrollback> = Mutex::new(Scrollback {
+    lines: [BLANK_LINE; SCROLLBACK_LINES],
+    next: 0,
+    len: 0,
+    screen: [BLANK_LINE; BUFFER_HEIGHT],
+});
+
+impl Scrollback {
+    fn push(&mut self, line: Line) {
+        self.lines[self.next] = line;
+        self.next = (self.next + 1) % SCROLLBACK_LINES;
+        self.len = (self.len + 1).min(SCROLLBACK_LINES);
+    }
+
+    /// Line `index` of the history and then the saved screen, 0 being
+    /// the oldest one kept.
+    fn line(&self, index: usize) -> &Line {
+        match index.checked_sub(self.len) {
+            Some(row) => &self.screen[row],
+            None => {
+                &self.lines[(self.next + SCROLLBACK_LINES - self.len + index) % SCROLLBACK_LINES]
+            }
+        }
+    }
+}
+
+fn read_crtc(register: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(CRTC_INDEX).write(register);
+        Port::<u8>::new(CRTC_DATA).read()
+    }
+}
+
+fn write_crtc(register: u8, value: u8) {
+    unsafe {
+        Port::<u8>::new(CRTC_INDEX).write(register);
+        Port::<u8>::new(CRTC_DATA).write(value);
+    }
+}
 
 /// This is basically just saying - we have a bunch of transparent
 /// structs (newtypes) and we need to store them in a buffer.
@@ -100,7 +224,10 @@ struct Buffer {
 pub struct Writer {
     /// Where we are in the row
     column_position: usize,
-    /// One color code for the whole buffer
+    /// Which row we're on. The bottom one, unless an escape sequence
+    /// moved us.
+    row_position: usize,
+    /// What the next characters are written in
     color_code: ColorCode,
     /// A reference (static) to a mutable buffer.
     /// This is static as we'd expect as the VGA
@@ -109,27 +236,34 @@ pub struct Writer {
     /// in practice is through casting a pointer
     /// and dereferencing it (Unsafe)
     buffer: &'static mut Buffer,
+    /// Row and column of the mouse pointer, if it's shown.
+    pointer: Option<(usize, usize)>,
+    /// Whether the blinking cursor is shown. The firmware leaves it
+    /// on.
+    cursor: bool,
+    /// What `write_string` has of an escape sequence so far.
+    escape: Escape,
+    /// Whether SGR asked for bold, which is the bright colors.
+    bold: bool,
+    /// How many lines up from the bottom the screen is showing.
+    scrolled: usize,
 }
 
 impl Writer {
-
-    /// Turns strings into bytes and then writes them
-    /// one by one.
+    /// Writes a string one glyph at a time.
+    ///
+    /// Every char takes up one cell, however many bytes it is in
+    /// UTF-8, so a `µs` is two columns wide like `{:<8}` thinks it is
+    /// and tables still line up. Escape sequences take up none, see the
+    /// module docs, and can be split over several calls.
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                // There is a range of hex values
-                // that represent the possible
-                // characters that VGA can display.
-                //
-                // We check here for those.
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                // We place a character as a placeholder if
-                // we find a byte outside of the range.
-                _ => self.write_byte(0xfe),
+        self.scroll_down(self.scrolled);
+        for c in s.chars() {
+            if !self.escape(c) {
+                self.put_byte(glyph(c));
             }
-
         }
+        self.move_cursor();
     }
 
     /// Writes the bytes to the buffer in memory.
@@ -137,14 +271,27 @@ impl Writer {
     /// When it matches a `\n` character, it should
     /// know how to handle that - aka go to next row.
     pub fn write_byte(&mut self, byte: u8) {
+        self.scroll_down(self.scrolled);
+        self.put_byte(byte);
+        self.move_cursor();
+    }
+
+    /// `write_byte` without moving the cursor, which is a few port
+    /// writes and can wait for the end of a string.
+    fn put_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
+            // Back to the start of the line, to draw over it.
+            b'\r' => self.column_position = 0,
+            // Only moves back, writing a space over it is up to whoever
+            // sent it.
+            0x08 => self.column_position = self.column_position.saturating_sub(1),
             byte => {
                 if self.column_position >= BUFFER_WIDTH {
                     self.new_line();
                 }
 
-                let row = BUFFER_HEIGHT - 1;
+                let row = self.row_position;
                 let col = self.column_position;
 
                 let color_code = self.color_code;
@@ -159,7 +306,6 @@ impl Writer {
         }
     }
 
-
     /// Basically this implements how we shuffle the
     /// buffer around in order to add a new line.
     ///
@@ -168,7 +314,16 @@ impl Writer {
     /// the leftover characters.
     ///
     /// And finally reset the column position of the writer.
+    ///
+    /// Above the bottom row, it only moves down one. The top row goes
+    /// to the scrollback.
     fn new_line(&mut self) {
+        self.column_position = 0;
+        if self.row_position < BUFFER_HEIGHT - 1 {
+            self.row_position += 1;
+            return;
+        }
+        SCROLLBACK.lock().push(self.line(0));
         for row in 1..BUFFER_HEIGHT {
             for col in 0..BUFFER_WIDTH {
                 let character = self.buffer.chars[row][col].read();
@@ -176,7 +331,269 @@ impl Writer {
             }
         }
         self.clear_row(BUFFER_HEIGHT - 1);
-        self.column_position = 0;
+    }
+
+    /// Take `c` if it's part of an escape sequence, acting on the
+    /// sequence once it's complete. Whether it was.
+    fn escape(&mut self, c: char) -> bool {
+        match self.escape {
+            Escape::None if c == '\x1b' => self.escape = Escape::Started,
+            Escape::None => return false,
+            Escape::Started if c == '[' => {
+                self.escape = Escape::Csi {
+                    params: [0; MAX_ESCAPE_PARAMS],
+                    count: 0,
+                }
+            }
+            // Not a kind we know.
+            Escape::Started => self.escape = Escape::None,
+            Escape::Csi {
+                mut params,
+                mut count,
+            } => match c {
+                '0'..='9' => {
+                    if let Some(param) = params.get_mut(count) {
+                        let digit = c as u16 - '0' as u16;
+                        *param = param.saturating_mul(10).saturating_add(digit);
+                    }
+                    self.escape = Escape::Csi { params, count };
+                }
+                ';' => {
+                    count += 1;
+                    self.escape = Escape::Csi { params, count };
+                }
+                // The final byte, which says what to do.
+                '@'..='~' => {
+                    self.escape = Escape::None;
+                    let len = (count + 1).min(MAX_ESCAPE_PARAMS);
+                    self.control_sequence(c, &params[..len]);
+                }
+                // Private markers and the like, nothing we use.
+                _ => {}
+            },
+        }
+        true
+    }
+
+    /// Act on `ESC [ params command`. A missing parameter is a 0.
+    fn control_sequence(&mut self, command: char, params: &[u16]) {
+        // How far to move, where 0 means 1.
+        let count = usize::from(params[0].max(1));
+        let position = |index: usize, limit: usize| {
+            let param = params.get(index).copied().unwrap_or(0).max(1);
+            (usize::from(param) - 1).min(limit - 1)
+        };
+        let cell = self.row_position * BUFFER_WIDTH + self.column_position.min(BUFFER_WIDTH - 1);
+        let line = self.row_position * BUFFER_WIDTH;
+        match command {
+            'A' => self.row_position = self.row_position.saturating_sub(count),
+            'B' => self.row_position = (self.row_position + count).min(BUFFER_HEIGHT - 1),
+            'C' => self.column_position = (self.column_position + count).min(BUFFER_WIDTH - 1),
+            'D' => self.column_position = self.column_position.saturating_sub(count),
+            'H' | 'f' => {
+                self.row_position = position(0, BUFFER_HEIGHT);
+                self.column_position = position(1, BUFFER_WIDTH);
+            }
+            'J' => match params[0] {
+                0 => self.clear_cells(cell, BUFFER_HEIGHT * BUFFER_WIDTH),
+                1 => self.clear_cells(0, cell + 1),
+                _ => self.clear_cells(0, BUFFER_HEIGHT * BUFFER_WIDTH),
+            },
+            'K' => match params[0] {
+                0 => self.clear_cells(cell, line + BUFFER_WIDTH),
+                1 => self.clear_cells(line, cell + 1),
+                _ => self.clear_cells(line, line + BUFFER_WIDTH),
+            },
+            'm' => params.iter().for_each(|&param| self.select_graphic(param)),
+            _ => {}
+        }
+    }
+
+    /// One parameter of SGR, `ESC [ ... m`.
+    fn select_graphic(&mut self, param: u16) {
+        let ColorCode(code) = self.color_code;
+        let (mut foreground, mut background) = (code & 0x0F, code >> 4);
+        let intensity = if self.bold { INTENSITY } else { 0 };
+        match param {
+            0 => {
+                self.bold = false;
+                foreground = DEFAULT_COLORS.0 as u8;
+                background = DEFAULT_COLORS.1 as u8;
+            }
+            1 => {
+                self.bold = true;
+                foreground |= INTENSITY;
+            }
+            22 => {
+                self.bold = false;
+                foreground &= !INTENSITY;
+            }
+            30..=37 => foreground = ANSI_COLORS[usize::from(param - 30)] as u8 | intensity,
+            39 => foreground = DEFAULT_COLORS.0 as u8,
+            40..=47 => background = ANSI_COLORS[usize::from(param - 40)] as u8,
+            49 => background = DEFAULT_COLORS.1 as u8,
+            90..=97 => foreground = ANSI_COLORS[usize::from(param - 90)] as u8 | INTENSITY,
+            // A bright background is a blinking one to VGA, so it's
+            // the plain one.
+            100..=107 => background = ANSI_COLORS[usize::from(param - 100)] as u8,
+            _ => {}
+        }
+        self.color_code = ColorCode(background << 4 | foreground);
+    }
+
+    /// Blank the cells from `start` to before `end`, counted from the
+    /// top left a row at a time.
+    fn clear_cells(&mut self, start: usize, end: usize) {
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code: self.color_code,
+        };
+        for cell in start..end.min(BUFFER_HEIGHT * BUFFER_WIDTH) {
+            self.buffer.chars[cell / BUFFER_WIDTH][cell % BUFFER_WIDTH].write(blank);
+        }
+    }
+
+    /// Write whatever comes next in `foreground` on `background`. What's
+    /// already on the screen keeps its colors.
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.color_code = ColorCode::new(foreground, background);
+    }
+
+    /// Run `f` with the colors set to `foreground` on `background`, and
+    /// put back the ones from before after.
+    pub fn with_color<R>(
+        &mut self,
+        foreground: Color,
+        background: Color,
+        f: impl FnOnce(&mut Writer) -> R,
+    ) -> R {
+        let before = self.color_code;
+        self.set_color(foreground, background);
+        let result = f(self);
+        self.color_code = before;
+        result
+    }
+
+    /// Put the blinking cursor where the next character goes. At the
+    /// end of a full line that's the last column still, the line only
+    /// wraps once something's written.
+    fn move_cursor(&self) {
+        if !self.cursor {
+            return;
+        }
+        let col = self.column_position.min(BUFFER_WIDTH - 1);
+        let cell = (self.row_position * BUFFER_WIDTH + col) as u16;
+        write_crtc(CURSOR_LOW, cell as u8);
+        write_crtc(CURSOR_HIGH, (cell >> 8) as u8);
+    }
+
+    /// Show `lines` further back in the scrollback, as far as there is.
+    /// The cursor's hidden until it's back at the bottom.
+    pub fn scroll_up(&mut self, lines: usize) {
+        let mut scrollback = SCROLLBACK.lock();
+        let target = (self.scrolled + lines).min(scrollback.len);
+        if target == self.scrolled {
+            return;
+        }
+        let pointer = self.pointer;
+        self.set_pointer(None);
+        if self.scrolled == 0 {
+            for row in 0..BUFFER_HEIGHT {
+                scrollback.screen[row] = self.line(row);
+            }
+            write_crtc(CURSOR_START, read_crtc(CURSOR_START) | CURSOR_DISABLE);
+        }
+        self.scrolled = target;
+        self.show_scrollback(&scrollback);
+        self.set_pointer(pointer);
+    }
+
+    /// Show `lines` further forward, back to what was written last at
+    /// most.
+    pub fn scroll_down(&mut self, lines: usize) {
+        if self.scrolled == 0 || lines == 0 {
+            return;
+        }
+        let pointer = self.pointer;
+        self.set_pointer(None);
+        self.scrolled = self.scrolled.saturating_sub(lines);
+        self.show_scrollback(&SCROLLBACK.lock());
+        if self.scrolled == 0 && self.cursor {
+            self.show_cursor();
+        }
+        self.set_pointer(pointer);
+    }
+
+    /// How many lines up from the bottom the screen is, 0 unless it's
+    /// been scrolled up.
+    pub fn scrolled(&self) -> usize {
+        self.scrolled
+    }
+
+    /// Put the lines `scrolled` up from the bottom on the screen.
+    fn show_scrollback(&mut self, scrollback: &Scrollback) {
+        let first = scrollback.len - self.scrolled;
+        for row in 0..BUFFER_HEIGHT {
+            let line = scrollback.line(first + row);
+            for (col, &character) in line.iter().enumerate() {
+                self.buffer.chars[row][col].write(character);
+            }
+        }
+    }
+
+    /// What's on `row`, colors and all.
+    fn line(&self, row: usize) -> Line {
+        let mut line = BLANK_LINE;
+        for (col, character) in line.iter_mut().enumerate() {
+            *character = self.buffer.chars[row][col].read();
+        }
+        line
+    }
+
+    /// Turn the blinking cursor on, as an underline where the next
+    /// character goes.
+    pub fn show_cursor(&mut self) {
+        let (start, end) = CURSOR_SCANLINES;
+        // The top bits of both are something else, keep them.
+        write_crtc(CURSOR_START, (read_crtc(CURSOR_START) & 0xC0) | start);
+        write_crtc(CURSOR_END, (read_crtc(CURSOR_END) & 0xE0) | end);
+        self.cursor = true;
+        self.move_cursor();
+    }
+
+    pub fn hide_cursor(&mut self) {
+        write_crtc(CURSOR_START, read_crtc(CURSOR_START) | CURSOR_DISABLE);
+        self.cursor = false;
+    }
+
+    /// Show the mouse pointer at `(row, column)`, or hide it with `None`.
+    /// It's drawn by swapping the colours of that cell.
+    pub fn set_pointer(&mut self, position: Option<(usize, usize)>) {
+        if let Some((row, col)) = self.pointer {
+            self.invert(row, col);
+        }
+        let position = position.filter(|&(row, col)| row < BUFFER_HEIGHT && col < BUFFER_WIDTH);
+        if let Some((row, col)) = position {
+            self.invert(row, col);
+        }
+        self.pointer = position;
+    }
+
+    /// The characters on `row`, 0 being the top one.
+    pub fn read_row(&self, row: usize) -> [u8; BUFFER_WIDTH] {
+        let mut bytes = [b' '; BUFFER_WIDTH];
+        for (col, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.buffer.chars[row][col].read().ascii_character;
+        }
+        bytes
+    }
+
+    /// Swap the foreground and background of a cell. Doing it twice
+    /// gives back what was there.
+    fn invert(&mut self, row: usize, col: usize) {
+        let mut character = self.buffer.chars[row][col].read();
+        character.color_code = ColorCode(character.color_code.0.rotate_left(4));
+        self.buffer.chars[row][col].write(character);
     }
 
     /// This method replaces all the characters int the last row
@@ -192,9 +609,43 @@ impl Writer {
     }
 }
 
+/// The code page 437 byte to show `c` with.
+///
+/// There is a range of values that are plain ASCII and a handful of
+/// others the VGA font has something close enough for. Anything else
+/// gets a placeholder square.
+fn glyph(c: char) -> u8 {
+    match c {
+        ' '..='~' | '\n' | '\r' | '\x08' => c as u8,
+        '°' => 0xF8,
+        '±' => 0xF1,
+        'µ' => 0xE6,
+        '·' => 0xFA,
+        '░' => 0xB0,
+        '█' => 0xDB,
+        '─' => 0xC4,
+        '│' => 0xB3,
+        '┌' => 0xDA,
+        '┐' => 0xBF,
+        '└' => 0xC0,
+        '┘' => 0xD9,
+        '├' => 0xC3,
+        '┤' => 0xB4,
+        '┬' => 0xC2,
+        '┴' => 0xC1,
+        '┼' => 0xC5,
+        _ => 0xFE,
+    }
+}
+
 impl fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Take the pointer off while scrolling, or it would scroll up
+        // with the text and leave an inverted cell behind.
+        let pointer = self.pointer;
+        self.set_pointer(None);
         self.write_string(s);
+        self.set_pointer(pointer);
         Ok(())
     }
 }
@@ -206,8 +657,14 @@ impl fmt::Write for Writer {
 lazy_static! {
     pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
         column_position: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black),
+        row_position: BUFFER_HEIGHT - 1,
+        color_code: ColorCode::new(DEFAULT_COLORS.0, DEFAULT_COLORS.1),
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+        pointer: None,
+        cursor: true,
+        escape: Escape::None,
+        bold: false,
+        scrolled: 0,
     });
 }
 
@@ -223,8 +680,192 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/// `print!` in `foreground` on `background`, like
+/// `print_color!(Color::Red, Color::Black, "{} failed", name)`. The
+/// colors go back to what they were after.
+#[macro_export]
+macro_rules! print_color {
+    ($foreground:expr, $background:expr, $($arg:tt)*) => (
+        $crate::vga_buffer::_print_color($foreground, $background, format_args!($($arg)*))
+    );
+}
+
+#[macro_export]
+macro_rules! println_color {
+    ($foreground:expr, $background:expr) => (
+        $crate::print_color!($foreground, $background, "\n")
+    );
+    ($foreground:expr, $background:expr, $($arg:tt)*) => (
+        $crate::print_color!($foreground, $background, "{}\n", format_args!($($arg)*))
+    );
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
     WRITER.lock().write_fmt(args).unwrap();
 }
+
+#[doc(hidden)]
+pub fn _print_color(foreground: Color, background: Color, args: fmt::Arguments) {
+    use core::fmt::Write;
+    // One lock for the lot, or someone else's print could come out in
+    // these colors.
+    WRITER
+        .lock()
+        .with_color(foreground, background, |writer| writer.write_fmt(args))
+        .unwrap();
+}
+
+/// Scroll half a screen up or down, for PageUp and PageDown. Nothing
+/// if something's printing, the keyboard interrupt could have come in
+/// the middle of it.
+pub fn scroll_page(up: bool) {
+    if let Some(mut writer) = WRITER.try_lock() {
+        if up {
+            writer.scroll_up(BUFFER_HEIGHT / 2);
+        } else {
+            writer.scroll_down(BUFFER_HEIGHT / 2);
+        }
+    }
+}
+
+/// How loadable modules print to the screen: the C ABI, and `len`
+/// bytes of UTF-8 at `text`, as `_print`'s Rust ABI and `fmt::Arguments`
+/// are no use to them.
+pub(crate) extern "C" fn module_print(text: *const u8, len: usize) {
+    _print(format_args!("{}", unsafe {
+        crate::module::text(text, len)
+    }));
+}
+
+crate::export_symbol!("vga_print", module_print);
+
+#[test_case]
+fn test_columns_count_glyphs() {
+    let mut writer = WRITER.lock();
+    writer.write_byte(b'\n');
+    writer.write_string("12 µs ±3°");
+    assert_eq!(writer.column_position, 9);
+    let row = writer.read_row(BUFFER_HEIGHT - 1);
+    assert_eq!(&row[..9], b"12 \xE6s \xF13\xF8");
+    writer.write_byte(b'\n');
+}
+
+#[test_case]
+fn test_cursor_follows_writes() {
+    let cell = || u16::from(read_crtc(CURSOR_HIGH)) << 8 | u16::from(read_crtc(CURSOR_LOW));
+    let last_row = ((BUFFER_HEIGHT - 1) * BUFFER_WIDTH) as u16;
+    let mut writer = WRITER.lock();
+    writer.show_cursor();
+    writer.write_byte(b'\n');
+    assert_eq!(cell(), last_row);
+    writer.write_string("abc");
+    assert_eq!(cell(), last_row + 3);
+    assert_eq!(read_crtc(CURSOR_START) & CURSOR_DISABLE, 0);
+
+    // Hidden, it stays put.
+    writer.hide_cursor();
+    writer.write_string("de");
+    assert_eq!(cell(), last_row + 3);
+    assert_ne!(read_crtc(CURSOR_START) & CURSOR_DISABLE, 0);
+    writer.show_cursor();
+    assert_eq!(cell(), last_row + 5);
+    writer.write_byte(b'\n');
+}
+
+#[test_case]
+fn test_print_color() {
+    let color_at = |writer: &Writer, col: usize| {
+        writer.buffer.chars[BUFFER_HEIGHT - 1][col]
+            .read()
+            .color_code
+    };
+    crate::println!();
+    crate::print_color!(Color::Red, Color::Blue, "ab");
+    crate::print!("c");
+    let writer = WRITER.lock();
+    assert_eq!(
+        color_at(&writer, 0),
+        ColorCode::new(Color::Red, Color::Blue)
+    );
+    assert_eq!(
+        color_at(&writer, 1),
+        ColorCode::new(Color::Red, Color::Blue)
+    );
+    assert_eq!(
+        color_at(&writer, 2),
+        ColorCode::new(DEFAULT_COLORS.0, DEFAULT_COLORS.1)
+    );
+    drop(writer);
+    crate::println!();
+}
+
+#[test_case]
+fn test_ansi_escapes() {
+    let color_at =
+        |writer: &Writer, row: usize, col: usize| writer.buffer.chars[row][col].read().color_code;
+    let bottom = BUFFER_HEIGHT - 1;
+    let mut writer = WRITER.lock();
+    writer.write_byte(b'\n');
+    // Bold then red is bright red, reset is back to the default.
+    writer.write_string("\x1b[1;31mab\x1b[0mc");
+    assert_eq!(&writer.read_row(bottom)[..3], b"abc");
+    assert_eq!(
+        color_at(&writer, bottom, 0),
+        ColorCode::new(Color::LightRed, Color::Black)
+    );
+    assert_eq!(
+        color_at(&writer, bottom, 2),
+        ColorCode::new(DEFAULT_COLORS.0, DEFAULT_COLORS.1)
+    );
+
+    // Split over two writes.
+    writer.write_string("\x1b[");
+    writer.write_string("32;44md\x1b[m");
+    assert_eq!(
+        color_at(&writer, bottom, 3),
+        ColorCode::new(Color::Green, Color::Blue)
+    );
+
+    // Back two and over the top, then up a line.
+    writer.write_string("\x1b[2DX\x1b[AY");
+    assert_eq!(&writer.read_row(bottom)[..4], b"abXd");
+    assert_eq!(writer.read_row(bottom - 1)[3], b'Y');
+    writer.write_string("\x1b[2K");
+    assert_eq!(writer.read_row(bottom - 1)[3], b' ');
+
+    // To the bottom left, and clear to the end of the screen.
+    writer.write_string("\x1b[25;1H\x1b[J");
+    assert_eq!(writer.read_row(bottom), [b' '; BUFFER_WIDTH]);
+    assert_eq!((writer.row_position, writer.column_position), (bottom, 0));
+}
+
+#[test_case]
+fn test_scrollback() {
+    use core::fmt::Write;
+    let bottom = BUFFER_HEIGHT - 1;
+    let mut writer = WRITER.lock();
+    for line in 0..BUFFER_HEIGHT + 5 {
+        write!(writer, "\nline {:02}", line).unwrap();
+    }
+    assert_eq!(&writer.read_row(0)[..7], b"line 05");
+
+    writer.scroll_up(5);
+    assert_eq!(writer.scrolled(), 5);
+    assert_eq!(&writer.read_row(0)[..7], b"line 00");
+    assert_eq!(&writer.read_row(bottom)[..7], b"line 24");
+    // As far as it goes, whatever else has scrolled off before.
+    writer.scroll_up(2 * SCROLLBACK_LINES);
+    assert!(writer.scrolled() > 5 && writer.scrolled() <= SCROLLBACK_LINES);
+    writer.scroll_down(2 * SCROLLBACK_LINES);
+    assert_eq!(writer.scrolled(), 0);
+    assert_eq!(&writer.read_row(bottom)[..7], b"line 29");
+
+    // Writing goes back down first.
+    writer.scroll_up(3);
+    writer.write_string("!");
+    assert_eq!(writer.scrolled(), 0);
+    assert_eq!(&writer.read_row(bottom)[..8], b"line 29!");
+    writer.write_byte(b'\n');
+}
@@ -0,0 +1,72 @@
+//! A watchdog that is fed by the timer interrupt.
+//!
+//! The test runner arms it before every test and disarms it after.
+//! If a test doesn't finish in time, the timer interrupt notices and
+//! we bail out of QEMU with `Timeout` instead of waiting for the
+//! `test-timeout` in Cargo.toml to kill the whole run.
+use crate::latency;
+use crate::time::MILLISECONDS_PER_TICK;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+/// Ticks left before the watchdog fires. 0 means disarmed.
+static REMAINING_TICKS: AtomicU64 = AtomicU64::new(0);
+/// Timeout the watchdog was armed with, for the report.
+static TIMEOUT_MS: AtomicU64 = AtomicU64::new(0);
+/// What we're currently watching, usually the name of a test.
+static WATCHING: Mutex<&'static str> = Mutex::new("");
+
+/// Start watching `name`. If `disarm` isn't called within `timeout_ms`
+/// we report it and exit QEMU.
+pub fn arm(name: &'static str, timeout_ms: u64) {
+    // Round up so very short timeouts still get at least one tick.
+    let ticks = (timeout_ms + MILLISECONDS_PER_TICK - 1) / MILLISECONDS_PER_TICK;
+
+    // The timer interrupt reads these, so don't let it in half way.
+    latency::without_interrupts(|| {
+        *WATCHING.lock() = name;
+        TIMEOUT_MS.store(timeout_ms, Ordering::SeqCst);
+        REMAINING_TICKS.store(ticks.max(1), Ordering::SeqCst);
+    });
+}
+
+/// Stop watching.
+pub fn disarm() {
+    REMAINING_TICKS.store(0, Ordering::SeqCst);
+}
+
+/// Whether the watchdog is currently counting down.
+pub fn is_armed() -> bool {
+    REMAINING_TICKS.load(Ordering::SeqCst) != 0
+}
+
+/// Called from the timer interrupt handler on every tick.
+pub fn tick() {
+    let remaining = REMAINING_TICKS.load(Ordering::SeqCst);
+    if remaining == 0 {
+        return;
+    }
+    REMAINING_TICKS.store(remaining - 1, Ordering::SeqCst);
+    if remaining == 1 {
+        expired();
+    }
+}
+
+fn expired() -> ! {
+    // The hung code might have been in the middle of printing to
+    // serial when we interrupted it, in which case we'd spin on the
+    // lock forever. It is never going to resume, so just take it.
+    unsafe { crate::serial::SERIAL1.force_unlock() };
+
+    // `arm` writes this with interrupts off so it can't be held here.
+    let name = *WATCHING.lock();
+    crate::test_report::timed_out(name, TIMEOUT_MS.load(Ordering::SeqCst))
+}
+
+#[test_case]
+fn test_arm_and_disarm() {
+    arm("watchdog::test_arm_and_disarm", 1000);
+    assert!(is_armed());
+    disarm();
+    assert!(!is_armed());
+}
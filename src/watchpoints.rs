@@ -0,0 +1,332 @@
+//! Hardware watchpoints in the debug registers.
+//!
+//! There are four of them, DR0 to DR3, each an address with a length
+//! of 1, 2, 4 or 8 bytes and the kind of access to catch. DR7 turns
+//! them on and DR6 says which one went off. A data watchpoint traps
+//! after the access, so the `rip` reported is that of the instruction
+//! right after the one that did it.
+//!
+//! Good for memory that gets scribbled on in ways the allocator's
+//! poisoning doesn't notice, e.g. a stray write into a live object.
+use crate::error::{KernelError, KernelResult};
+use crate::ksyms;
+use crate::latency;
+use crate::shell::{self, parse_number, CommandFailed, CommandResult};
+use core::fmt::{self, Write};
+use spin::Mutex;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+pub const MAX_WATCHPOINTS: usize = 4;
+
+/// DR7's LE bit. Old CPUs need it to report data watchpoints on the
+/// exact instruction, newer ones ignore it.
+const DR7_LOCAL_EXACT: u64 = 1 << 8;
+/// DR6 bits B0 to B3, which watchpoint was hit.
+const DR6_HIT_MASK: u64 = 0b1111;
+/// Makes an instruction breakpoint not go off again when we return
+/// to it.
+const RESUME_FLAG: u64 = 1 << 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Executing the instruction at the address. Length has to be 1.
+    Execute,
+    Write,
+    /// Reads or writes, the CPU can't catch only reads.
+    ReadWrite,
+}
+
+impl Access {
+    fn bits(self) -> u64 {
+        match self {
+            Access::Execute => 0b00,
+            Access::Write => 0b01,
+            Access::ReadWrite => 0b11,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Access::Execute => "x",
+            Access::Write => "w",
+            Access::ReadWrite => "rw",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Access> {
+        match name {
+            "x" => Some(Access::Execute),
+            "w" => Some(Access::Write),
+            "rw" => Some(Access::ReadWrite),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub address: u64,
+    pub len: u64,
+    pub access: Access,
+    pub hits: u64,
+}
+
+static WATCHPOINTS: Mutex<[Option<Watchpoint>; MAX_WATCHPOINTS]> =
+    Mutex::new([None; MAX_WATCHPOINTS]);
+
+/// Adds the `watch` shell command.
+pub fn init() {
+    shell::register(
+        "watch",
+        "watch [ADDR [LEN] [w|rw|x]|-d ADDR]: list, set or delete watchpoints",
+        watch_command,
+    )
+    .expect("watch command");
+}
+
+fn read_dr6() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov {}, dr6", out(reg) value, options(nomem, nostack)) };
+    value
+}
+
+fn write_dr6(value: u64) {
+    unsafe { asm!("mov dr6, {}", in(reg) value, options(nomem, nostack)) };
+}
+
+fn read_dr7() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov {}, dr7", out(reg) value, options(nomem, nostack)) };
+    value
+}
+
+fn write_dr7(value: u64) {
+    unsafe { asm!("mov dr7, {}", in(reg) value, options(nomem, nostack)) };
+}
+
+/// Put `address` in DR`index`.
+fn write_address(index: usize, address: u64) {
+    unsafe {
+        match index {
+            0 => asm!("mov dr0, {}", in(reg) address, options(nomem, nostack)),
+            1 => asm!("mov dr1, {}", in(reg) address, options(nomem, nostack)),
+            2 => asm!("mov dr2, {}", in(reg) address, options(nomem, nostack)),
+            _ => asm!("mov dr3, {}", in(reg) address, options(nomem, nostack)),
+        }
+    }
+}
+
+/// DR7 with watchpoint `index` turned on (or off with `None`).
+fn dr7_with(dr7: u64, index: usize, watchpoint: Option<&Watchpoint>) -> u64 {
+    let enable = 1 << (2 * index);
+    let control = 0b1111 << (16 + 4 * index);
+    let dr7 = dr7 & !enable & !control;
+    match watchpoint {
+        Some(watchpoint) => {
+            let len = match watchpoint.len {
+                1 => 0b00,
+                2 => 0b01,
+                8 => 0b10,
+                _ => 0b11,
+            };
+            let bits = watchpoint.access.bits() | len << 2;
+            dr7 | enable | bits << (16 + 4 * index) | DR7_LOCAL_EXACT
+        }
+        None => dr7,
+    }
+}
+
+/// Watch `len` bytes at `address` for `access`. `len` is 1, 2, 4 or 8
+/// and `address` has to be aligned to it. Returns which debug register
+/// it went in.
+pub fn set(address: VirtAddr, len: u64, access: Access) -> KernelResult<usize> {
+    let len_ok = match access {
+        Access::Execute => len == 1,
+        _ => [1, 2, 4, 8].contains(&len),
+    };
+    if !len_ok || !address.is_aligned(len) {
+        return Err(KernelError::InvalidArgument);
+    }
+    latency::without_interrupts(|| {
+        let mut watchpoints = WATCHPOINTS.lock();
+        if watchpoints
+            .iter()
+            .flatten()
+            .any(|watchpoint| watchpoint.address == address.as_u64())
+        {
+            return Err(KernelError::AlreadyExists);
+        }
+        let index = watchpoints
+            .iter()
+            .position(|slot| slot.is_none())
+            .ok_or(KernelError::NoSpace)?;
+        let watchpoint = Watchpoint {
+            address: address.as_u64(),
+            len,
+            access,
+            hits: 0,
+        };
+        write_address(index, watchpoint.address);
+        write_dr7(dr7_with(read_dr7(), index, Some(&watchpoint)));
+        watchpoints[index] = Some(watchpoint);
+        Ok(index)
+    })
+}
+
+/// Stop watching `address`.
+pub fn remove(address: VirtAddr) -> KernelResult<()> {
+    latency::without_interrupts(|| {
+        let mut watchpoints = WATCHPOINTS.lock();
+        let index = watchpoints
+            .iter()
+            .position(|slot| {
+                slot.map_or(false, |watchpoint| watchpoint.address == address.as_u64())
+            })
+            .ok_or(KernelError::NotFound)?;
+        write_dr7(dr7_with(read_dr7(), index, None));
+        watchpoints[index] = None;
+        Ok(())
+    })
+}
+
+/// Call `f` with every watchpoint and the debug register it's in.
+pub fn for_each(mut f: impl FnMut(usize, &Watchpoint)) {
+    if let Some(watchpoints) = WATCHPOINTS.try_lock() {
+        for (index, slot) in watchpoints.iter().enumerate() {
+            if let Some(watchpoint) = slot {
+                f(index, watchpoint);
+            }
+        }
+    }
+}
+
+/// Called by the debug exception handler. Reports every watchpoint
+/// that went off and returns false if none did.
+pub fn hit(stack_frame: &mut InterruptStackFrame) -> bool {
+    let dr6 = read_dr6();
+    let hits = dr6 & DR6_HIT_MASK;
+    if hits == 0 {
+        return false;
+    }
+    // The CPU never clears these itself.
+    write_dr6(dr6 & !DR6_HIT_MASK);
+
+    let rip = stack_frame.instruction_pointer.as_u64();
+    let mut out = crate::serial::panic_writer();
+    let mut watchpoints = WATCHPOINTS.try_lock();
+    for index in (0..MAX_WATCHPOINTS).filter(|index| hits & 1 << index != 0) {
+        let watchpoint = match watchpoints.as_mut().and_then(|w| w[index].as_mut()) {
+            Some(watchpoint) => {
+                watchpoint.hits += 1;
+                *watchpoint
+            }
+            None => continue,
+        };
+        let _ = write!(
+            out,
+            "\nwatchpoint {} ({} {:#x}/{}) hit by rip {:#x}",
+            index,
+            watchpoint.access.name(),
+            watchpoint.address,
+            watchpoint.len,
+            rip
+        );
+        if let Some((name, offset)) = ksyms::lookup(rip) {
+            let _ = write!(out, " <{}+{:#x}>", ksyms::Demangle(name), offset);
+        }
+        let _ = writeln!(out, ", hit {} times", watchpoint.hits);
+        if watchpoint.access == Access::Execute {
+            // Faults before the instruction runs, so without this we'd
+            // come straight back here.
+            unsafe { stack_frame.as_mut().cpu_flags |= RESUME_FLAG };
+        }
+    }
+    true
+}
+
+fn watch_command(out: &mut dyn fmt::Write, args: &str) -> CommandResult {
+    let mut words = args.split_whitespace();
+    let first = match words.next() {
+        Some(first) => first,
+        None => {
+            for_each(|index, watchpoint| {
+                let _ = write!(
+                    out,
+                    "dr{} {:#018x}/{} {:<2}",
+                    index,
+                    watchpoint.address,
+                    watchpoint.len,
+                    watchpoint.access.name()
+                );
+                if let Some((name, offset)) = ksyms::lookup(watchpoint.address) {
+                    let _ = write!(out, " <{}+{:#x}>", ksyms::Demangle(name), offset);
+                }
+                let _ = writeln!(out, "  {} hits", watchpoint.hits);
+            });
+            return Ok(());
+        }
+    };
+    let usage = |out: &mut dyn fmt::Write| {
+        let _ = writeln!(out, "usage: watch [ADDR [LEN] [w|rw|x]|-d ADDR]");
+        CommandFailed
+    };
+    let address = |word: Option<&str>| {
+        word.and_then(ksyms::parse_address)
+            .and_then(|address| VirtAddr::try_new(address).ok())
+    };
+
+    let result = if first == "-d" {
+        match address(words.next()) {
+            Some(address) => remove(address),
+            None => return Err(usage(out)),
+        }
+    } else {
+        let address = address(Some(first)).ok_or_else(|| usage(out))?;
+        let mut len = 8;
+        let mut access = Access::Write;
+        for word in words {
+            match (Access::from_name(word), parse_number(word)) {
+                (Some(kind), _) => access = kind,
+                (None, Some(number)) => len = number,
+                (None, None) => return Err(usage(out)),
+            }
+        }
+        if access == Access::Execute {
+            len = 1;
+        }
+        set(address, len, access).map(|index| {
+            let _ = writeln!(out, "dr{}", index);
+        })
+    };
+    result.map_err(|error| {
+        let _ = writeln!(out, "{}", error);
+        CommandFailed
+    })
+}
+
+#[test_case]
+fn test_write_is_caught() {
+    static mut WATCHED: u64 = 0;
+    let address = VirtAddr::from_ptr(unsafe { &WATCHED });
+    let hits = || {
+        let mut hits = None;
+        for_each(|_, watchpoint| {
+            if watchpoint.address == address.as_u64() {
+                hits = Some(watchpoint.hits);
+            }
+        });
+        hits
+    };
+
+    assert_eq!(
+        set(address + 1u64, 8, Access::Write),
+        Err(KernelError::InvalidArgument)
+    );
+    assert!(set(address, 8, Access::Write).is_ok());
+    unsafe { core::ptr::write_volatile(&mut WATCHED, 1) };
+    assert_eq!(hits(), Some(1));
+    assert_eq!(remove(address), Ok(()));
+    unsafe { core::ptr::write_volatile(&mut WATCHED, 2) };
+    assert_eq!(hits(), None);
+}
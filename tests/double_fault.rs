@@ -0,0 +1,40 @@
+#![no_std]
+#![no_main]
+
+use blog_os::test_report;
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use x86_64::structures::idt::InterruptDescriptorTable;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    test_report::begin_test("double_fault::unhandled_page_fault");
+
+    blog_os::gdt::init();
+    init_test_idt();
+
+    // There is no page fault handler in the test IDT, so the
+    // page fault escalates into a double fault.
+    unsafe {
+        *(0xdead_beef as *mut u64) = 42;
+    }
+
+    panic!("Execution continued after page fault");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        blog_os::expect_double_fault(&mut idt);
+        idt
+    };
+}
+
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
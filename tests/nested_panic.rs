@@ -0,0 +1,40 @@
+#![no_std]
+#![no_main]
+
+use blog_os::serial::RawSerial;
+use blog_os::{run_should_panic, test_report};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    run_should_panic("nested_panic::panic_while_panicking", panic_while_panicking)
+}
+
+// Panics whenever it's printed, like the panic handler does with the
+// message.
+struct Bomb;
+
+impl fmt::Display for Bomb {
+    fn fmt(&self, _: &mut fmt::Formatter) -> fmt::Result {
+        panic!("printed a bomb")
+    }
+}
+
+fn panic_while_panicking() {
+    panic!("{}", Bomb);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if blog_os::panic_policy::enter() {
+        // The first one: printing it panics.
+        let _ = writeln!(RawSerial::new(), "{}", info);
+        test_report::fail(&"printing the panic didn't panic")
+    }
+    // The second one prints the message too and panics again, the
+    // third has to get here without printing it.
+    blog_os::panic_policy::report_nested(info);
+    test_report::pass();
+    test_report::finish()
+}
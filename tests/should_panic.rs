@@ -0,0 +1,19 @@
+#![no_std]
+#![no_main]
+
+use blog_os::run_should_panic;
+use core::panic::PanicInfo;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    run_should_panic("should_panic::should_fail", should_fail)
+}
+
+fn should_fail() {
+    assert_eq!(0, 1);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::should_panic_handler(info)
+}
@@ -2,18 +2,16 @@
 #![no_main]
 #![feature(abi_x86_interrupt)]
 
+use blog_os::test_report;
 use core::panic::PanicInfo;
-use blog_os::serial_print;
 use lazy_static::lazy_static;
+use x86_64::registers::control::Cr2;
 // We want a custom handler that won't panic but succeeds
-use x86_64::structures::idt::InterruptDescriptorTable;
-use blog_os::{exit_qemu, QemuExitCode, serial_println};
-use x86_64::structures::idt::InterruptStackFrame;
-
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
-    serial_print!("stack_overflow::stack_overflow...\t");
+    test_report::begin_test("stack_overflow::stack_overflow");
 
     blog_os::gdt::init();
     init_test_idt();
@@ -40,10 +38,9 @@ lazy_static! {
         let mut idt = InterruptDescriptorTable::new();
         unsafe {
             idt.double_fault
-                .set_handler_fn(test_double_fault_handler)
+                .set_handler_fn(overflow_handler)
                 .set_stack_index(blog_os::gdt::DOUBLE_FAULT_IST_INDEX);
         }
-
         idt
     };
 }
@@ -52,11 +49,15 @@ pub fn init_test_idt() {
     TEST_IDT.load();
 }
 
-extern "x86-interrupt" fn test_double_fault_handler(
-    _stack_frame: &mut InterruptStackFrame,
+// Passes only if the double fault handler would have called it an
+// overflow too.
+extern "x86-interrupt" fn overflow_handler(
+    stack_frame: &mut InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    serial_println!("[ok]");
-    exit_qemu(QemuExitCode::Success);
-    loop {}
+    if !blog_os::interrupts::looks_like_stack_overflow(Cr2::read(), stack_frame.stack_pointer) {
+        test_report::fail(&"double fault wasn't recognised as a stack overflow");
+    }
+    test_report::pass();
+    test_report::finish()
 }
@@ -0,0 +1,12 @@
+//! The smallest program worth running.
+#![no_std]
+#![no_main]
+
+use blog_os_user::{entry, println};
+
+entry!(main);
+
+fn main() -> i32 {
+    println!("hello from user space");
+    0
+}
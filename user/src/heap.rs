@@ -0,0 +1,103 @@
+//! A heap for user programs, on memory from `mmap`.
+//!
+//! Allocation bumps a pointer through a chunk, and a new chunk gets
+//! mapped when one runs out. Only allocations big enough to get a
+//! mapping of their own are ever given back, short lived programs
+//! don't need better. A program that uses `alloc` puts
+//!
+//! ```ignore
+//! #[global_allocator]
+//! static HEAP: blog_os_user::Heap = blog_os_user::Heap::new();
+//! ```
+//!
+//! somewhere.
+use crate::syscall;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+const PAGE_SIZE: usize = 4096;
+
+pub struct Heap {
+    /// Next free byte in the current chunk.
+    next: AtomicUsize,
+    end: AtomicUsize,
+}
+
+impl Heap {
+    pub const fn new() -> Heap {
+        Heap {
+            next: AtomicUsize::new(0),
+            end: AtomicUsize::new(0),
+        }
+    }
+
+    fn is_large(layout: Layout) -> bool {
+        layout.size() >= CHUNK_SIZE / 2 || layout.align() > PAGE_SIZE
+    }
+}
+
+fn page_align(len: usize) -> usize {
+    (len + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if Heap::is_large(layout) {
+            // Mappings are page aligned, bigger alignments aren't
+            // worth the trouble.
+            if layout.align() > PAGE_SIZE {
+                return ptr::null_mut();
+            }
+            return syscall::mmap_anonymous(page_align(layout.size())).unwrap_or(ptr::null_mut());
+        }
+        loop {
+            let next = self.next.load(Ordering::Relaxed);
+            let start = (next + layout.align() - 1) & !(layout.align() - 1);
+            let end = start + layout.size();
+            if next != 0 && end <= self.end.load(Ordering::Relaxed) {
+                if self
+                    .next
+                    .compare_exchange(next, end, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    return start as *mut u8;
+                }
+                continue;
+            }
+            // Whatever is left in the old chunk is lost. Two threads
+            // could both get here, there are no threads yet.
+            let chunk = match syscall::mmap_anonymous(CHUNK_SIZE) {
+                Ok(chunk) => chunk as usize,
+                Err(_) => return ptr::null_mut(),
+            };
+            self.end.store(chunk + CHUNK_SIZE, Ordering::Relaxed);
+            self.next.store(chunk, Ordering::Relaxed);
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if Heap::is_large(layout) {
+            let _ = syscall::munmap(ptr, page_align(layout.size()));
+        }
+    }
+}
+
+#[test_case]
+fn test_heap() {
+    let heap = Heap::new();
+    unsafe {
+        let small = Layout::from_size_align(24, 8).unwrap();
+        let first = heap.alloc(small);
+        let second = heap.alloc(small);
+        assert!(!first.is_null() && second as usize >= first as usize + 24);
+        assert_eq!(second as usize % 8, 0);
+
+        let large = Layout::from_size_align(CHUNK_SIZE, 16).unwrap();
+        let mapping = heap.alloc(large);
+        assert_eq!(mapping as usize % PAGE_SIZE, 0);
+        ptr::write_bytes(mapping, 0xAA, CHUNK_SIZE);
+        heap.dealloc(mapping, large);
+    }
+}
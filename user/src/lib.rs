@@ -0,0 +1,163 @@
+//! What a user program needs to run on the kernel: the `_start` it is
+//! entered at, `print!` and `println!` on its standard output, a heap
+//! and exiting when it panics.
+//!
+//! A program looks like
+//!
+//! ```ignore
+//! #![no_std]
+//! #![no_main]
+//!
+//! blog_os_user::entry!(main);
+//!
+//! fn main() -> i32 {
+//!     blog_os_user::println!("hello");
+//!     0
+//! }
+//! ```
+//!
+//! and is built for the kernel's target, see `examples/`. The syscall
+//! numbers are Linux's, so these programs run there too. The kernel
+//! has `write`, `stat`, `mkdir`, `rmdir`, `unlink`, `rename`, `mmap`
+//! and `munmap` of anonymous memory, `futex`, `sched_yield` and `exit`
+//! of them so far, see its `syscall` module. Its build builds the
+//! examples too, as static PIEs, and its tests run them.
+#![no_std]
+#![cfg_attr(test, no_main)]
+#![feature(asm, global_asm)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+// Provides memcpy and friends, which core expects to be there.
+extern crate rlibc;
+
+pub mod heap;
+pub mod syscall;
+
+use core::fmt;
+use core::panic::PanicInfo;
+
+pub use heap::Heap;
+
+/// File descriptors every program starts out with.
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+/// Exit status after a panic, same as Rust's.
+const PANIC_STATUS: i32 = 101;
+
+// The kernel enters a program with `rsp` 16 byte aligned and pointing
+// at `argc`, not as if it had called it, so it needs a stub to get to
+// Rust code.
+global_asm!(
+    "
+    .global _start
+    _start:
+        xor %rbp, %rbp
+        mov %rsp, %rdi
+        and $-16, %rsp
+        call __blog_os_user_start
+        ud2
+    "
+);
+
+#[no_mangle]
+extern "C" fn __blog_os_user_start(_stack: *const u64) -> ! {
+    extern "Rust" {
+        /// Defined by `entry!`.
+        fn __blog_os_user_main() -> i32;
+    }
+    exit(unsafe { __blog_os_user_main() })
+}
+
+/// Name the function `_start` ends up calling. It takes nothing and
+/// returns the exit status.
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[no_mangle]
+        fn __blog_os_user_main() -> i32 {
+            // Type checks `main`.
+            let main: fn() -> i32 = $main;
+            main()
+        }
+    };
+}
+
+pub fn exit(status: i32) -> ! {
+    unsafe { syscall::syscall1(syscall::EXIT, status as u64) };
+    // Nothing to go back to if the kernel did return.
+    loop {}
+}
+
+/// Writes to a file descriptor, all of it.
+pub struct Fd(pub u64);
+
+impl fmt::Write for Fd {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut bytes = s.as_bytes();
+        while !bytes.is_empty() {
+            match syscall::write(self.0, bytes) {
+                Ok(written) => bytes = &bytes[written..],
+                Err(_) => return Err(fmt::Error),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(fd: u64, args: fmt::Arguments) {
+    use core::fmt::Write;
+    let _ = Fd(fd).write_fmt(args);
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::_print($crate::STDOUT, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    ($($arg:tt)*) => ($crate::_print($crate::STDERR, format_args!("{}\n", format_args!($($arg)*))));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    eprintln!("{}", info);
+    exit(PANIC_STATUS)
+}
+
+// The tests are a program of their own, which only Linux can run for
+// now.
+#[cfg(test)]
+entry!(run_tests);
+
+#[cfg(test)]
+fn run_tests() -> i32 {
+    test_main();
+    0
+}
+
+#[cfg(test)]
+fn test_runner(tests: &[&dyn Fn()]) {
+    eprintln!("running {} tests", tests.len());
+    for test in tests {
+        test();
+    }
+    eprintln!("ok");
+}
+
+#[test_case]
+fn test_write() {
+    assert_eq!(syscall::write(STDERR, b""), Ok(0));
+    // EBADF
+    assert_eq!(syscall::write(1000, b"lost"), Err(syscall::Errno(9)));
+}
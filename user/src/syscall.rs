@@ -0,0 +1,208 @@
+//! Raw syscalls, numbered like Linux's on x86_64.
+//!
+//! The number goes in `rax`, arguments in `rdi`, `rsi`, `rdx`, `r10`,
+//! `r8` and `r9`, and the result comes back in `rax`, negative errno on
+//! failure. `syscall` itself trashes `rcx` and `r11`.
+
+pub const WRITE: u64 = 1;
+pub const STAT: u64 = 4;
+pub const MMAP: u64 = 9;
+pub const MUNMAP: u64 = 11;
+pub const SCHED_YIELD: u64 = 24;
+pub const EXIT: u64 = 60;
+pub const RENAME: u64 = 82;
+pub const MKDIR: u64 = 83;
+pub const RMDIR: u64 = 84;
+pub const UNLINK: u64 = 87;
+pub const FUTEX: u64 = 202;
+
+pub const PROT_READ: u64 = 0x1;
+pub const PROT_WRITE: u64 = 0x2;
+pub const MAP_PRIVATE: u64 = 0x02;
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+pub const FUTEX_WAIT: u64 = 0;
+pub const FUTEX_WAKE: u64 = 1;
+
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+
+/// The longest path the kernel takes, with its NUL.
+pub const PATH_MAX: usize = 256;
+const ENAMETOOLONG: i64 = 36;
+
+/// What `stat` says about a file, laid out like Linux's.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stat {
+    pub dev: u64,
+    pub ino: u64,
+    pub nlink: u64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    _pad: u32,
+    pub rdev: u64,
+    pub size: u64,
+    pub blksize: u64,
+    pub blocks: u64,
+    pub atime: u64,
+    pub atime_ns: u64,
+    pub mtime: u64,
+    pub mtime_ns: u64,
+    pub ctime: u64,
+    pub ctime_ns: u64,
+    _reserved: [u64; 3],
+}
+
+impl Stat {
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+}
+
+/// A failed syscall, with the errno it returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub i64);
+
+pub type SyscallResult = Result<u64, Errno>;
+
+fn result(value: u64) -> SyscallResult {
+    // -4095..-1 are errors, anything else is a value.
+    if value > -4096i64 as u64 {
+        Err(Errno(-(value as i64)))
+    } else {
+        Ok(value)
+    }
+}
+
+/// # Safety
+///
+/// Whatever the syscall does with its arguments, e.g. writing to
+/// memory they point to.
+pub unsafe fn syscall1(number: u64, arg1: u64) -> u64 {
+    let value;
+    asm!("syscall", inlateout("rax") number => value, in("rdi") arg1,
+         out("rcx") _, out("r11") _, options(nostack));
+    value
+}
+
+/// # Safety
+///
+/// See `syscall1`.
+pub unsafe fn syscall3(number: u64, arg1: u64, arg2: u64, arg3: u64) -> u64 {
+    let value;
+    asm!("syscall", inlateout("rax") number => value, in("rdi") arg1, in("rsi") arg2, in("rdx") arg3,
+         out("rcx") _, out("r11") _, options(nostack));
+    value
+}
+
+/// # Safety
+///
+/// See `syscall1`.
+pub unsafe fn syscall6(
+    number: u64,
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+    arg5: u64,
+    arg6: u64,
+) -> u64 {
+    let value;
+    asm!("syscall", inlateout("rax") number => value, in("rdi") arg1, in("rsi") arg2, in("rdx") arg3,
+         in("r10") arg4, in("r8") arg5, in("r9") arg6, out("rcx") _, out("r11") _, options(nostack));
+    value
+}
+
+/// Write some of `bytes` to `fd`, returns how many.
+pub fn write(fd: u64, bytes: &[u8]) -> Result<usize, Errno> {
+    let written = unsafe { syscall3(WRITE, fd, bytes.as_ptr() as u64, bytes.len() as u64) };
+    result(written).map(|written| written as usize)
+}
+
+/// Call `f` with `path` NUL terminated, the way the kernel wants it.
+fn with_path<T>(path: &str, f: impl FnOnce(u64) -> T) -> Result<T, Errno> {
+    let mut buffer = [0u8; PATH_MAX];
+    if path.len() >= PATH_MAX {
+        return Err(Errno(ENAMETOOLONG));
+    }
+    buffer[..path.len()].copy_from_slice(path.as_bytes());
+    Ok(f(buffer.as_ptr() as u64))
+}
+
+pub fn stat(path: &str) -> Result<Stat, Errno> {
+    let mut stat = Stat::default();
+    let buffer = &mut stat as *mut Stat as u64;
+    result(with_path(path, |path| unsafe {
+        syscall3(STAT, path, buffer, 0)
+    })?)?;
+    Ok(stat)
+}
+
+pub fn mkdir(path: &str, mode: u32) -> Result<(), Errno> {
+    result(with_path(path, |path| unsafe {
+        syscall3(MKDIR, path, u64::from(mode), 0)
+    })?)
+    .map(|_| ())
+}
+
+/// Remove an empty directory.
+pub fn rmdir(path: &str) -> Result<(), Errno> {
+    result(with_path(path, |path| unsafe { syscall1(RMDIR, path) })?).map(|_| ())
+}
+
+/// Remove a file.
+pub fn unlink(path: &str) -> Result<(), Errno> {
+    result(with_path(path, |path| unsafe { syscall1(UNLINK, path) })?).map(|_| ())
+}
+
+pub fn rename(from: &str, to: &str) -> Result<(), Errno> {
+    let renamed = with_path(from, |from| {
+        with_path(to, |to| unsafe { syscall3(RENAME, from, to, 0) })
+    })??;
+    result(renamed).map(|_| ())
+}
+
+/// Let something else run for a bit.
+pub fn yield_now() {
+    unsafe { syscall1(SCHED_YIELD, 0) };
+}
+
+/// Map `len` bytes of fresh zeroed memory, readable and writable.
+pub fn mmap_anonymous(len: usize) -> Result<*mut u8, Errno> {
+    let address = unsafe {
+        syscall6(
+            MMAP,
+            0,
+            len as u64,
+            PROT_READ | PROT_WRITE,
+            MAP_PRIVATE | MAP_ANONYMOUS,
+            -1i64 as u64,
+            0,
+        )
+    };
+    result(address).map(|address| address as *mut u8)
+}
+
+/// # Safety
+///
+/// Nothing may use the memory afterwards.
+pub unsafe fn munmap(address: *mut u8, len: usize) -> Result<(), Errno> {
+    result(syscall3(MUNMAP, address as u64, len as u64, 0)).map(|_| ())
+}
+
+/// Sleep while the word at `address` is `value`.
+pub fn futex_wait(address: &core::sync::atomic::AtomicU32, value: u32) -> Result<(), Errno> {
+    let address = address as *const _ as u64;
+    // A null timeout, to wait for as long as it takes.
+    let waited = unsafe { syscall6(FUTEX, address, FUTEX_WAIT, u64::from(value), 0, 0, 0) };
+    result(waited).map(|_| ())
+}
+
+/// Wake up to `count` threads waiting on `address`, returns how many.
+pub fn futex_wake(address: &core::sync::atomic::AtomicU32, count: u32) -> Result<u64, Errno> {
+    let address = address as *const _ as u64;
+    result(unsafe { syscall3(FUTEX, address, FUTEX_WAKE, u64::from(count)) })
+}